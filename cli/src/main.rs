@@ -5,19 +5,7 @@
 use anyhow::Result;
 
 async fn run() -> Result<()> {
-    // Don't include timestamps and such because they're not really useful and
-    // too verbose, and plus several log targets such as journald will already
-    // include timestamps.
-    let format = tracing_subscriber::fmt::format()
-        .without_time()
-        .with_target(false)
-        .compact();
-    // Log to stderr by default
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .event_format(format)
-        .with_writer(std::io::stderr)
-        .init();
+    bootc_lib::logging::initialize_tracing();
     tracing::trace!("starting");
     bootc_lib::cli::run_from_iter(std::env::args()).await
 }
@@ -25,6 +13,10 @@ async fn run() -> Result<()> {
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     if let Err(e) = run().await {
+        if let Some(code) = e.downcast_ref::<bootc_lib::cli::ExitCode>() {
+            let _ = std::io::Write::flush(&mut std::io::stdout());
+            std::process::exit(code.0);
+        }
         tracing::error!("{:#}", e);
         std::process::exit(1);
     }
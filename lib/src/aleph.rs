@@ -0,0 +1,297 @@
+//! # The install aleph
+//!
+//! `bootc install` writes `.bootc-aleph.json` to the root of the physical root
+//! filesystem, recording how the system was born: the image it was installed
+//! from, and the environment which performed the installation.  It is never
+//! changed afterwards.  Fields were added over time, so the ones not written by
+//! the first versions are optional.
+
+use anyhow::Result;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+#[cfg(feature = "install")]
+use ostree_ext::oci_spec::image::{ImageConfiguration, ImageManifest, ANNOTATION_REVISION};
+use serde::{Deserialize, Serialize};
+
+/// Path to initially deployed version information, relative to the physical root
+pub(crate) const BOOTC_ALEPH_PATH: &str = ".bootc-aleph.json";
+
+/// The label or manifest annotation referencing the SBOM of an image by digest.
+///
+/// The OCI referrers API isn't available via the image proxy, so an SBOM
+/// attached as a referrer must also be referenced here to be recorded.
+#[cfg(feature = "install")]
+pub(crate) const SBOM_ANNOTATION: &str = "containers.bootc.sbom";
+
+/// Labels holding the source revision of an image, in order of preference.
+#[cfg(feature = "install")]
+const VCS_REF_LABELS: &[&str] = &[ANNOTATION_REVISION, "vcs-ref"];
+
+/// The "aleph" version information contains the image that was initially used
+/// to install.  This can be used to trace things like the specific version of
+/// `mkfs.ext4` or kernel version that was used.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct InstallAleph {
+    /// Digested pull spec for installed image
+    pub(crate) image: String,
+    /// The transport of the installed image, e.g. `registry`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) transport: Option<String>,
+    /// The manifest digest of the installed image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) digest: Option<String>,
+    /// The version number
+    #[serde(default)]
+    pub(crate) version: Option<String>,
+    /// The timestamp
+    #[serde(default)]
+    pub(crate) timestamp: Option<DateTime<Utc>>,
+    /// The source revision the image was built from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) vcs_ref: Option<String>,
+    /// The digest of the SBOM of the image, if it references one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sbom: Option<String>,
+    /// The version of bootc performing the installation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) bootc_version: Option<String>,
+    /// The `uname -r` of the kernel doing the installation
+    pub(crate) kernel: String,
+    /// The state of SELinux at install time
+    pub(crate) selinux: String,
+    /// The user created at install time, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<AlephUser>,
+    /// Kernel arguments given via `--karg` and `--karg-file`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) kargs: Vec<String>,
+}
+
+/// The user configuration recorded in the aleph, without any secrets.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct AlephUser {
+    pub(crate) name: String,
+    pub(crate) uid: u32,
+    pub(crate) groups: Vec<String>,
+    pub(crate) ssh_key: bool,
+    pub(crate) password: bool,
+    pub(crate) sudo: bool,
+}
+
+/// The source revision of an image, from its labels.
+#[cfg(feature = "install")]
+pub(crate) fn vcs_ref(config: &ImageConfiguration) -> Option<String> {
+    let labels = crate::status::labels_of_config(config)?;
+    VCS_REF_LABELS.iter().find_map(|k| labels.get(*k)).cloned()
+}
+
+/// The digest of the SBOM referenced by an image, preferring the manifest
+/// annotation over the label.  Anything which isn't a digest is ignored.
+#[cfg(feature = "install")]
+pub(crate) fn sbom_digest(manifest: &ImageManifest, config: &ImageConfiguration) -> Option<String> {
+    let annotation = manifest
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(SBOM_ANNOTATION));
+    let label = crate::status::labels_of_config(config).and_then(|l| l.get(SBOM_ANNOTATION));
+    let digest = annotation.or(label)?;
+    let valid = digest.split_once(':').is_some_and(|(algorithm, encoded)| {
+        !algorithm.is_empty()
+            && !encoded.is_empty()
+            && encoded.chars().all(|c| c.is_ascii_hexdigit())
+    });
+    if !valid {
+        tracing::warn!("Ignoring invalid {SBOM_ANNOTATION}: {digest}");
+        return None;
+    }
+    Some(digest.clone())
+}
+
+/// Load the aleph from the physical root, if the system was installed by bootc.
+#[context("Reading {BOOTC_ALEPH_PATH}")]
+pub(crate) fn load(sysroot_dir: &Dir) -> Result<Option<InstallAleph>> {
+    let Some(f) = sysroot_dir.open_optional(BOOTC_ALEPH_PATH)? else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_reader(std::io::BufReader::new(f))?))
+}
+
+/// Write a summary of the aleph.
+pub(crate) fn write_human(out: &mut impl std::io::Write, aleph: &InstallAleph) -> Result<()> {
+    writeln!(out, "Installed from: {}", aleph.image)?;
+    if let Some(transport) = aleph.transport.as_deref() {
+        writeln!(out, "  Transport: {transport}")?;
+    }
+    if let Some(version) = aleph.version.as_deref() {
+        writeln!(out, "  Version: {version}")?;
+    }
+    if let Some(digest) = aleph.digest.as_deref() {
+        writeln!(out, "  Digest: {digest}")?;
+    }
+    if let Some(timestamp) = aleph.timestamp {
+        writeln!(out, "  Built: {}", timestamp.to_rfc3339())?;
+    }
+    if let Some(vcs_ref) = aleph.vcs_ref.as_deref() {
+        writeln!(out, "  Source revision: {vcs_ref}")?;
+    }
+    if let Some(sbom) = aleph.sbom.as_deref() {
+        writeln!(out, "  SBOM: {sbom}")?;
+    }
+    if let Some(version) = aleph.bootc_version.as_deref() {
+        writeln!(out, "  Installed by: bootc {version}")?;
+    }
+    writeln!(out, "  Install kernel: {}", aleph.kernel)?;
+    writeln!(out, "  SELinux: {}", aleph.selinux)?;
+    if let Some(user) = aleph.user.as_ref() {
+        writeln!(out, "  User: {} ({})", user.name, user.uid)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn fixture() -> InstallAleph {
+    InstallAleph {
+        image: "quay.io/example/os@sha256:0123abcd".into(),
+        transport: Some("registry".into()),
+        digest: Some("sha256:0123abcd".into()),
+        version: Some("42.20240502.0".into()),
+        timestamp: Some("2024-05-02T10:11:12Z".parse().unwrap()),
+        vcs_ref: Some("8f2c1a9".into()),
+        sbom: Some("sha256:5bd1".into()),
+        bootc_version: Some("0.1.11".into()),
+        kernel: "6.8.9-300.fc40.x86_64".into(),
+        selinux: "enabled".into(),
+        user: Some(AlephUser {
+            name: "alice".into(),
+            uid: 1001,
+            groups: vec!["wheel".into()],
+            ssh_key: true,
+            password: false,
+            sudo: true,
+        }),
+        kargs: vec!["console=ttyS0".into()],
+    }
+}
+
+#[test]
+fn test_roundtrip() -> Result<()> {
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    assert_eq!(load(&td)?, None);
+
+    let aleph = fixture();
+    td.atomic_write(BOOTC_ALEPH_PATH, serde_json::to_vec(&aleph)?)?;
+    assert_eq!(load(&td)?.as_ref(), Some(&aleph));
+    let v = serde_json::to_value(&aleph)?;
+    assert_eq!(v["bootc_version"], "0.1.11");
+    assert_eq!(v["vcs_ref"], "8f2c1a9");
+    assert_eq!(v["kargs"], serde_json::json!(["console=ttyS0"]));
+
+    // Unknown fields are absent, rather than null
+    let aleph = InstallAleph {
+        transport: None,
+        sbom: None,
+        user: None,
+        kargs: Vec::new(),
+        ..fixture()
+    };
+    let v = serde_json::to_value(&aleph)?;
+    for k in ["transport", "sbom", "user", "kargs"] {
+        assert!(v.get(k).is_none(), "{k}");
+    }
+
+    td.atomic_write(BOOTC_ALEPH_PATH, "{")?;
+    assert!(load(&td).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_old_format() -> Result<()> {
+    // As written by older versions
+    let old = r#"{"image":"quay.io/example/os:latest","version":"39.1","timestamp":null,"kernel":"6.5.6-300.fc39.x86_64","selinux":"enabled"}"#;
+    let aleph: InstallAleph = serde_json::from_str(old)?;
+    assert_eq!(aleph.image, "quay.io/example/os:latest");
+    assert_eq!(aleph.version.as_deref(), Some("39.1"));
+    assert_eq!(aleph.timestamp, None);
+    assert_eq!(aleph.digest, None);
+    assert_eq!(aleph.bootc_version, None);
+    assert_eq!(aleph.user, None);
+    assert!(aleph.kargs.is_empty());
+    let mut out = Vec::new();
+    write_human(&mut out, &aleph)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "Installed from: quay.io/example/os:latest\n  Version: 39.1\n  Install kernel: 6.5.6-300.fc39.x86_64\n  SELinux: enabled\n"
+    );
+    // Even older versions lacked the version and timestamp
+    let aleph: InstallAleph = serde_json::from_str(
+        r#"{"image":"quay.io/example/os:latest","kernel":"6.5.6","selinux":"disabled"}"#,
+    )?;
+    assert_eq!(aleph.version, None);
+
+    let mut out = Vec::new();
+    write_human(&mut out, &fixture())?;
+    let out = String::from_utf8(out)?;
+    for line in [
+        "  Transport: registry\n",
+        "  Digest: sha256:0123abcd\n",
+        "  Source revision: 8f2c1a9\n",
+        "  SBOM: sha256:5bd1\n",
+        "  Installed by: bootc 0.1.11\n",
+        "  User: alice (1001)\n",
+    ] {
+        assert!(out.contains(line), "{out}");
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "install")]
+fn test_image_identity() -> Result<()> {
+    use ostree_ext::oci_spec::image::{ConfigBuilder, ImageConfigurationBuilder};
+
+    let config = |labels: &[(&str, &str)]| {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<std::collections::HashMap<_, _>>();
+        let c = ConfigBuilder::default().labels(labels).build().unwrap();
+        ImageConfigurationBuilder::default()
+            .config(c)
+            .build()
+            .unwrap()
+    };
+    let manifest: ImageManifest = serde_json::from_value(serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "digest": "sha256:6d8f",
+            "size": 2
+        },
+        "layers": []
+    }))?;
+    let empty = ImageConfiguration::default();
+    assert_eq!(vcs_ref(&empty), None);
+    assert_eq!(sbom_digest(&manifest, &empty), None);
+
+    let c = config(&[("vcs-ref", "legacy"), (SBOM_ANNOTATION, "sha256:aa")]);
+    assert_eq!(vcs_ref(&c).as_deref(), Some("legacy"));
+    assert_eq!(sbom_digest(&manifest, &c).as_deref(), Some("sha256:aa"));
+    let c = config(&[(ANNOTATION_REVISION, "8f2c1a9"), ("vcs-ref", "legacy")]);
+    assert_eq!(vcs_ref(&c).as_deref(), Some("8f2c1a9"));
+
+    // The manifest annotation takes precedence
+    let mut annotated = manifest.clone();
+    annotated.set_annotations(Some(
+        [(SBOM_ANNOTATION.to_string(), "sha256:bb".to_string())].into(),
+    ));
+    let c = config(&[(SBOM_ANNOTATION, "sha256:aa")]);
+    assert_eq!(sbom_digest(&annotated, &c).as_deref(), Some("sha256:bb"));
+    for invalid in ["sbom.spdx.json", "sha256:", ":abc", "sha256:xyz"] {
+        let c = config(&[(SBOM_ANNOTATION, invalid)]);
+        assert_eq!(sbom_digest(&manifest, &c), None, "{invalid}");
+    }
+    Ok(())
+}
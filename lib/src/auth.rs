@@ -0,0 +1,227 @@
+//! # Registry credentials
+//!
+//! Images are fetched with the credentials in the first of `/run/ostree/auth.json`,
+//! `/etc/ostree/auth.json` and `/usr/lib/ostree/auth.json` which exists, unless
+//! an authentication file is given explicitly via `--authfile`; see
+//! `containers-auth.json(5)` for the format.  `bootc registry login` writes
+//! `/etc/ostree/auth.json`.
+//!
+//! Credentials are passed to the fetcher via a file descriptor; only the path
+//! of the file they were read from may ever be logged or shown.
+
+use std::fmt::Display;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use fn_error_context::context;
+use ostree_ext::container::store::ImageProxyConfig;
+
+/// The locations of the global authentication file, relative to the root, in
+/// the order in which they are consulted.
+const GLOBAL_AUTHFILES: &[&str] = &[
+    "run/ostree/auth.json",
+    "etc/ostree/auth.json",
+    "usr/lib/ostree/auth.json",
+];
+/// The file written by `bootc registry login`, relative to the root.
+const LOGIN_AUTHFILE: &str = "etc/ostree/auth.json";
+
+/// Substrings of the errors returned by registries when the credentials are
+/// missing or rejected.
+const AUTH_ERRORS: &[&str] = &[
+    "unauthorized",
+    "authentication required",
+    "access to the resource is denied",
+    "forbidden",
+    "invalid username/password",
+];
+
+/// Where the credentials for fetching images come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AuthSource {
+    /// Given via `--authfile`
+    Explicit(Utf8PathBuf),
+    /// The global authentication file at this (absolute) path
+    Global(Utf8PathBuf),
+    /// No authentication file exists; images are fetched anonymously
+    None,
+}
+
+impl Display for AuthSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Explicit(p) => write!(f, "credentials from --authfile {p}"),
+            Self::Global(p) => write!(f, "credentials from {p}"),
+            Self::None => {
+                let paths = GLOBAL_AUTHFILES
+                    .iter()
+                    .map(|p| format!("/{p}"))
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "no credentials (none of {} exist; see `bootc registry login`)",
+                    paths.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl AuthSource {
+    /// Configure the fetcher to use the credentials, if any.  Unlike the
+    /// default of the fetcher, this never falls back to the credentials of
+    /// the container runtime.
+    pub(crate) fn configure(&self, config: &mut ImageProxyConfig) -> Result<()> {
+        let path = match self {
+            Self::Explicit(p) | Self::Global(p) => p,
+            Self::None => {
+                config.auth_anonymous = true;
+                return Ok(());
+            }
+        };
+        let f = std::fs::File::open(path).with_context(|| format!("Opening {path}"))?;
+        config.auth_data = Some(f);
+        Ok(())
+    }
+
+    /// If the error is an authentication failure, add which credentials were
+    /// used to it.
+    pub(crate) fn annotate_error(&self, err: anyhow::Error) -> anyhow::Error {
+        let msg = format!("{err:#}").to_ascii_lowercase();
+        if AUTH_ERRORS.iter().any(|e| msg.contains(e)) {
+            err.context(format!("Authentication failed, using {self}"))
+        } else {
+            err
+        }
+    }
+}
+
+/// Determine the credentials to use for the system with the root filesystem
+/// `root`; `authfile` is given via `--authfile`, and must exist.
+#[context("Finding registry credentials")]
+pub(crate) fn resolve_in_root(root: &Dir, authfile: Option<&Utf8Path>) -> Result<AuthSource> {
+    if let Some(authfile) = authfile {
+        if !authfile.try_exists()? {
+            anyhow::bail!("Authentication file {authfile} does not exist");
+        }
+        return Ok(AuthSource::Explicit(authfile.to_owned()));
+    }
+    for path in GLOBAL_AUTHFILES {
+        if root.try_exists(path)? {
+            return Ok(AuthSource::Global(Utf8Path::new("/").join(path)));
+        }
+    }
+    Ok(AuthSource::None)
+}
+
+/// Determine the credentials to use for the running system.
+pub(crate) fn resolve(authfile: Option<&Utf8Path>) -> Result<AuthSource> {
+    let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    resolve_in_root(&root, authfile)
+}
+
+/// Log in to `registry`, storing the credentials in `authfile` (by default
+/// the global authentication file), which is only readable by root.  The
+/// password is prompted for or read from standard input, so it is never part
+/// of a command line; the credentials are verified by contacting the registry
+/// before they are stored.
+#[context("Logging in to {registry}")]
+pub(crate) fn login(
+    registry: &str,
+    username: Option<&str>,
+    authfile: Option<&Utf8Path>,
+    password_stdin: bool,
+) -> Result<()> {
+    let default_authfile = Utf8Path::new("/").join(LOGIN_AUTHFILE);
+    let authfile = authfile.unwrap_or(&default_authfile);
+    if let Some(parent) = authfile.parent().filter(|p| !p.as_str().is_empty()) {
+        std::fs::create_dir_all(parent).with_context(|| format!("Creating {parent}"))?;
+    }
+    let mut cmd = Command::new("skopeo");
+    cmd.args(["login", &format!("--authfile={authfile}")]);
+    if let Some(username) = username {
+        cmd.arg(format!("--username={username}"));
+    }
+    if password_stdin {
+        cmd.arg("--password-stdin");
+    }
+    let status = cmd.arg(registry).status().context("Running skopeo")?;
+    if !status.success() {
+        anyhow::bail!("skopeo login failed: {status}");
+    }
+    std::fs::set_permissions(authfile, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Setting permissions of {authfile}"))?;
+    println!("Stored credentials for {registry} in {authfile}");
+    Ok(())
+}
+
+#[test]
+fn test_resolve() -> Result<()> {
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let secret = r#"{"auths":{"quay.io":{"auth":"c2VjcmV0dXNlcjpodW50ZXIy"}}}"#;
+    assert_eq!(resolve_in_root(&td, None)?, AuthSource::None);
+
+    // The global files are consulted in order
+    for (i, path) in GLOBAL_AUTHFILES.iter().enumerate().rev() {
+        td.create_dir_all(Utf8Path::new(path).parent().unwrap())?;
+        td.write(path, secret)?;
+        assert_eq!(
+            resolve_in_root(&td, None)?,
+            AuthSource::Global(Utf8PathBuf::from(format!("/{}", GLOBAL_AUTHFILES[i])))
+        );
+    }
+    assert_eq!(
+        resolve_in_root(&td, None)?,
+        AuthSource::Global("/run/ostree/auth.json".into())
+    );
+
+    // An explicit file takes precedence, and must exist
+    let tmp = tempfile::tempdir()?;
+    let explicit = Utf8Path::from_path(tmp.path()).unwrap().join("auth.json");
+    assert!(resolve_in_root(&td, Some(&explicit)).is_err());
+    std::fs::write(&explicit, secret)?;
+    let source = resolve_in_root(&td, Some(&explicit))?;
+    assert_eq!(source, AuthSource::Explicit(explicit.clone()));
+    let mut config = ImageProxyConfig::default();
+    source.configure(&mut config)?;
+    assert!(config.auth_data.is_some());
+    assert!(!config.auth_anonymous);
+    let mut config = ImageProxyConfig::default();
+    AuthSource::None.configure(&mut config)?;
+    assert!(config.auth_data.is_none());
+    assert!(config.auth_anonymous);
+
+    // Errors say which credentials were used, but never include them
+    let denied = || {
+        anyhow::anyhow!("reading manifest latest in quay.io/example/os: unauthorized: access to the requested resource is not authorized")
+    };
+    let e = format!("{:#}", source.annotate_error(denied()));
+    assert!(
+        e.starts_with(&format!(
+            "Authentication failed, using credentials from --authfile {explicit}: "
+        )),
+        "{e}"
+    );
+    assert!(!e.contains("c2VjcmV0"), "{e}");
+    let e = format!(
+        "{:#}",
+        AuthSource::Global("/etc/ostree/auth.json".into()).annotate_error(denied())
+    );
+    assert!(
+        e.starts_with("Authentication failed, using credentials from /etc/ostree/auth.json: "),
+        "{e}"
+    );
+    let e = format!("{:#}", AuthSource::None.annotate_error(denied()));
+    assert!(
+        e.starts_with("Authentication failed, using no credentials (none of /run/ostree/auth.json, /etc/ostree/auth.json, /usr/lib/ostree/auth.json exist"),
+        "{e}"
+    );
+    // Other errors are unchanged
+    let e = AuthSource::None.annotate_error(anyhow::anyhow!("manifest unknown"));
+    assert_eq!(format!("{e:#}"), "manifest unknown");
+    Ok(())
+}
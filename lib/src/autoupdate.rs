@@ -0,0 +1,266 @@
+//! # Automatic updates
+//!
+//! `bootc-fetch-apply-updates.service` runs `bootc upgrade --auto`, which acts
+//! according to the `[updates]` section of the fetch configuration (see
+//! [`crate::fetchconfig`]); the policy can hence be changed without editing
+//! the unit.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::reboot::SoftRebootMode;
+use crate::spec::{RebootStrategy, UpdateMode, UpdatePolicy};
+use crate::utils::RetryPolicy;
+
+/// The default delay before the first retry of a failed update
+pub(crate) const DEFAULT_BACKOFF: Duration = Duration::from_secs(60);
+/// Retries are never delayed by more than this
+const MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// A delay in the configuration, such as `90s`, `30m`, `2h` or `1d`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Delay(Duration);
+
+impl Delay {
+    pub(crate) fn as_secs(&self) -> u64 {
+        self.0.as_secs()
+    }
+}
+
+impl FromStr for Delay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (n, unit) = s.split_at(split);
+        let n: u64 = n.parse().with_context(|| format!("Parsing delay {s:?}"))?;
+        let multiplier = match unit {
+            "" if n == 0 => 0,
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => anyhow::bail!("Invalid delay {s:?}; expected e.g. 90s, 30m, 2h or 1d"),
+        };
+        let secs = n
+            .checked_mul(multiplier)
+            .ok_or_else(|| anyhow::anyhow!("Delay {s:?} is too large"))?;
+        Ok(Self(Duration::from_secs(secs)))
+    }
+}
+
+impl TryFrom<String> for Delay {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// What `bootc upgrade --auto` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Nothing; automatic updates are disabled
+    Disabled,
+    /// Check for an update
+    Check,
+    /// Stage an update
+    Stage,
+    /// Stage an update and reboot into it
+    Apply {
+        soft_reboot: SoftRebootMode,
+        /// Whether to reboot outside of maintenance windows
+        now: bool,
+    },
+}
+
+/// Decide what to do per the policy.
+pub(crate) fn decide(policy: &UpdatePolicy) -> Action {
+    if !policy.enabled {
+        return Action::Disabled;
+    }
+    match (policy.mode, policy.reboot) {
+        (UpdateMode::Check, _) => Action::Check,
+        (UpdateMode::Stage, _) => Action::Stage,
+        (UpdateMode::Apply, RebootStrategy::Window) => Action::Apply {
+            soft_reboot: SoftRebootMode::Never,
+            now: false,
+        },
+        (UpdateMode::Apply, RebootStrategy::Immediate) => Action::Apply {
+            soft_reboot: SoftRebootMode::Never,
+            now: true,
+        },
+        (UpdateMode::Apply, RebootStrategy::SoftRebootIfPossible) => Action::Apply {
+            soft_reboot: SoftRebootMode::Auto,
+            now: true,
+        },
+    }
+}
+
+/// A random delay of up to `jitter_seconds` of the policy, so that a fleet of
+/// machines doesn't fetch updates at the same time.
+pub(crate) fn jitter(policy: &UpdatePolicy) -> Duration {
+    if policy.jitter_seconds == 0 {
+        return Duration::ZERO;
+    }
+    // As for retries, the randomly keyed std hasher suffices
+    use std::hash::{BuildHasher, Hasher};
+    let r = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    Duration::from_secs(r % (policy.jitter_seconds + 1))
+}
+
+/// How failed updates are retried.
+pub(crate) fn retry_policy(policy: &UpdatePolicy) -> RetryPolicy {
+    let initial_delay = Duration::from_secs(policy.backoff_seconds);
+    RetryPolicy {
+        max_attempts: policy.retries.saturating_add(1),
+        initial_delay,
+        factor: 2,
+        max_delay: MAX_BACKOFF.max(initial_delay),
+        ..Default::default()
+    }
+}
+
+/// A one-line summary of the policy for `bootc status`.
+pub(crate) fn describe(policy: &UpdatePolicy) -> String {
+    let action = match decide(policy) {
+        Action::Disabled => "disabled",
+        Action::Check => "check for updates",
+        Action::Stage => "stage updates",
+        Action::Apply { now: false, .. } => "apply updates within maintenance windows",
+        Action::Apply {
+            soft_reboot: SoftRebootMode::Never,
+            ..
+        } => "apply updates immediately",
+        Action::Apply {
+            soft_reboot: SoftRebootMode::Auto,
+            ..
+        } => "apply updates immediately, via a soft reboot if possible",
+        Action::Apply {
+            soft_reboot: SoftRebootMode::Required,
+            ..
+        } => "apply updates immediately, via a soft reboot",
+    };
+    if policy.sources.is_empty() {
+        action.to_string()
+    } else {
+        format!("{action} (from {})", policy.sources.join(", "))
+    }
+}
+
+#[cfg(test)]
+fn test_policy() -> UpdatePolicy {
+    UpdatePolicy {
+        enabled: true,
+        mode: Default::default(),
+        reboot: Default::default(),
+        jitter_seconds: 0,
+        retries: 0,
+        backoff_seconds: DEFAULT_BACKOFF.as_secs(),
+        sources: Vec::new(),
+    }
+}
+
+#[test]
+fn test_parse_delay() {
+    for (s, secs) in [
+        ("0", 0),
+        ("0s", 0),
+        ("90s", 90),
+        ("30m", 1800),
+        ("2h", 7200),
+        ("1d", 86400),
+    ] {
+        assert_eq!(s.parse::<Delay>().unwrap().as_secs(), secs, "{s}");
+    }
+    for invalid in [
+        "",
+        "10",
+        "1.5h",
+        "-1m",
+        "2 h",
+        "1w",
+        "99999999999999999999d",
+    ] {
+        assert!(invalid.parse::<Delay>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_decide() {
+    let policy = test_policy();
+    // The default matches the previous behavior of the service
+    assert_eq!(
+        decide(&policy),
+        Action::Apply {
+            soft_reboot: SoftRebootMode::Never,
+            now: false
+        }
+    );
+    assert_eq!(
+        describe(&policy),
+        "apply updates within maintenance windows"
+    );
+    for (mode, reboot, expected) in [
+        (UpdateMode::Check, RebootStrategy::Immediate, Action::Check),
+        (UpdateMode::Stage, RebootStrategy::Immediate, Action::Stage),
+        (
+            UpdateMode::Apply,
+            RebootStrategy::Immediate,
+            Action::Apply {
+                soft_reboot: SoftRebootMode::Never,
+                now: true,
+            },
+        ),
+        (
+            UpdateMode::Apply,
+            RebootStrategy::SoftRebootIfPossible,
+            Action::Apply {
+                soft_reboot: SoftRebootMode::Auto,
+                now: true,
+            },
+        ),
+    ] {
+        let policy = UpdatePolicy {
+            mode,
+            reboot,
+            ..test_policy()
+        };
+        assert_eq!(decide(&policy), expected);
+        // Disabling takes precedence over everything else
+        let policy = UpdatePolicy {
+            enabled: false,
+            sources: vec!["/etc/bootc/fetch/50-updates.toml".into()],
+            ..policy
+        };
+        assert_eq!(decide(&policy), Action::Disabled);
+        assert_eq!(
+            describe(&policy),
+            "disabled (from /etc/bootc/fetch/50-updates.toml)"
+        );
+    }
+}
+
+#[test]
+fn test_jitter_retry() {
+    let policy = UpdatePolicy {
+        jitter_seconds: 600,
+        retries: 3,
+        backoff_seconds: 30,
+        ..test_policy()
+    };
+    assert_eq!(jitter(&test_policy()), Duration::ZERO);
+    for _ in 0..10 {
+        assert!(jitter(&policy) <= Duration::from_secs(600));
+    }
+    let retry = retry_policy(&policy);
+    assert_eq!(retry.max_attempts, 4);
+    assert_eq!(retry.initial_delay, Duration::from_secs(30));
+    assert_eq!(retry_policy(&test_policy()).max_attempts, 1);
+}
@@ -0,0 +1,189 @@
+//! # Inspecting how the root filesystem is provided
+//!
+//! This gathers (at runtime, not from the image) whether the booted root is a
+//! composefs mount, how fs-verity is configured in the ostree repository, and
+//! how `/sysroot` is mounted.
+
+use anyhow::{anyhow, Context, Result};
+use fn_error_context::context;
+use ostree_ext::keyfileext::KeyFileExt;
+use ostree_ext::ostree;
+
+use crate::spec::{FsVerity, RootBackend};
+
+/// The kernel's view of the mounts of the current process.
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// A single entry in `/proc/self/mountinfo`; see `proc(5)`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct MountInfo {
+    pub(crate) mount_point: String,
+    /// The per-mount options, e.g. `ro`
+    pub(crate) options: String,
+    pub(crate) fstype: String,
+    pub(crate) source: String,
+}
+
+impl MountInfo {
+    /// Whether the mount itself is read-only.
+    pub(crate) fn is_readonly(&self) -> bool {
+        self.options.split(',').any(|o| o == "ro")
+    }
+}
+
+/// Decode the octal escapes (e.g. `\040` for a space) used in mountinfo paths.
+fn unescape(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        r.push_str(&rest[..i]);
+        let escaped = rest
+            .get(i + 1..i + 4)
+            .and_then(|o| u8::from_str_radix(o, 8).ok());
+        match escaped {
+            Some(c) => {
+                r.push(char::from(c));
+                rest = &rest[i + 4..];
+            }
+            None => {
+                r.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    r.push_str(rest);
+    r
+}
+
+fn parse_mountinfo_line(line: &str) -> Result<MountInfo> {
+    let (mount, fs) = line
+        .split_once(" - ")
+        .ok_or_else(|| anyhow!("Missing separator"))?;
+    // Skip the mount ID, parent ID, major:minor and root
+    let mut mount = mount.split(' ').skip(4);
+    let mut fs = fs.split(' ');
+    fn next<'a>(it: &mut impl Iterator<Item = &'a str>, name: &str) -> Result<String> {
+        it.next()
+            .map(unescape)
+            .ok_or_else(|| anyhow!("Missing {name}"))
+    }
+    let mount_point = next(&mut mount, "mount point")?;
+    let options = next(&mut mount, "mount options")?;
+    let fstype = next(&mut fs, "filesystem type")?;
+    let source = next(&mut fs, "mount source")?;
+    Ok(MountInfo {
+        mount_point,
+        options,
+        fstype,
+        source,
+    })
+}
+
+/// Parse the contents of a mountinfo file.
+pub(crate) fn parse_mountinfo(contents: &str) -> Result<Vec<MountInfo>> {
+    contents
+        .lines()
+        .filter(|l| !l.is_empty())
+        .enumerate()
+        .map(|(i, l)| parse_mountinfo_line(l).with_context(|| format!("Parsing line {}", i + 1)))
+        .collect()
+}
+
+/// Find the mount visible at `path`; if there are multiple mounts on top of
+/// each other, this is the last one.
+fn find_mount<'a>(mounts: &'a [MountInfo], path: &str) -> Option<&'a MountInfo> {
+    mounts.iter().rev().find(|m| m.mount_point == path)
+}
+
+/// Map the `ex-integrity.fsverity` value of an ostree repository config.
+fn fsverity_from_config(value: Option<&str>) -> FsVerity {
+    match value {
+        Some("yes" | "true" | "1") => FsVerity::Required,
+        Some("maybe") => FsVerity::Enabled,
+        _ => FsVerity::Disabled,
+    }
+}
+
+/// Describe the root backend given the mounts and repository configuration.
+fn backend_from(mounts: &[MountInfo], fsverity: Option<&str>) -> RootBackend {
+    let composefs = find_mount(mounts, "/")
+        .map(|m| m.source == "composefs" || m.fstype == "composefs")
+        .unwrap_or_default();
+    let sysroot = find_mount(mounts, "/sysroot");
+    RootBackend {
+        composefs,
+        fsverity: fsverity_from_config(fsverity),
+        sysroot_fstype: sysroot.map(|m| m.fstype.clone()),
+        sysroot_readonly: sysroot.map(MountInfo::is_readonly).unwrap_or_default(),
+    }
+}
+
+/// Inspect the root backend of the running system.
+#[context("Inspecting root backend")]
+pub(crate) fn inspect(repo: &ostree::Repo) -> Result<RootBackend> {
+    let mountinfo =
+        std::fs::read_to_string(MOUNTINFO).with_context(|| format!("Reading {MOUNTINFO}"))?;
+    let mounts = parse_mountinfo(&mountinfo)?;
+    let fsverity = repo.config().optional_string("ex-integrity", "fsverity")?;
+    Ok(backend_from(&mounts, fsverity.as_deref()))
+}
+
+#[test]
+fn test_parse_mountinfo() -> Result<()> {
+    let mountinfo = include_str!("fixtures/mountinfo-composefs");
+    let mounts = parse_mountinfo(mountinfo)?;
+    assert_eq!(mounts.len(), 8);
+    assert_eq!(
+        mounts[0],
+        MountInfo {
+            mount_point: "/".into(),
+            options: "ro,relatime".into(),
+            fstype: "overlay".into(),
+            source: "composefs".into(),
+        }
+    );
+    // Optional fields and escapes
+    let m = find_mount(&mounts, "/var/my data").unwrap();
+    assert_eq!(m.fstype, "xfs");
+    assert!(!m.is_readonly());
+    // The last mount wins
+    assert_eq!(find_mount(&mounts, "/boot").unwrap().fstype, "ext4");
+
+    assert!(parse_mountinfo("36 35 98:0 /mnt1 /mnt2 rw").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_backend_from() -> Result<()> {
+    let mounts = parse_mountinfo(include_str!("fixtures/mountinfo-composefs"))?;
+    assert_eq!(
+        backend_from(&mounts, Some("maybe")),
+        RootBackend {
+            composefs: true,
+            fsverity: FsVerity::Enabled,
+            sysroot_fstype: Some("xfs".into()),
+            sysroot_readonly: true,
+        }
+    );
+    assert_eq!(
+        backend_from(&mounts, Some("yes")).fsverity,
+        FsVerity::Required
+    );
+    assert_eq!(backend_from(&mounts, None).fsverity, FsVerity::Disabled);
+
+    // A classic ostree system, with a bind mount for the root
+    let mounts = parse_mountinfo(
+        "1 0 253:0 /ostree/deploy/default/deploy/abcd.0 / rw,relatime shared:1 - xfs /dev/vda4 rw\n\
+         2 1 253:0 / /sysroot rw,relatime shared:2 - xfs /dev/vda4 rw\n",
+    )?;
+    assert_eq!(
+        backend_from(&mounts, Some("no")),
+        RootBackend {
+            composefs: false,
+            fsverity: FsVerity::Disabled,
+            sysroot_fstype: Some("xfs".into()),
+            sysroot_readonly: false,
+        }
+    );
+    Ok(())
+}
@@ -12,7 +12,6 @@ use std::env;
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
-use std::process::Command;
 
 #[derive(Debug, Deserialize)]
 struct DevicesOutput {
@@ -27,6 +26,7 @@ pub(crate) struct Device {
     pub(crate) model: Option<String>,
     pub(crate) label: Option<String>,
     pub(crate) fstype: Option<String>,
+    pub(crate) mountpoint: Option<String>,
     pub(crate) children: Option<Vec<Device>>,
 }
 
@@ -40,6 +40,19 @@ impl Device {
     pub(crate) fn has_children(&self) -> bool {
         self.children.as_ref().map_or(false, |v| !v.is_empty())
     }
+
+    /// Whether this device, or any of its children, is mounted.
+    pub(crate) fn is_mounted(&self) -> bool {
+        self.mountpoint.is_some() || self.children.iter().flatten().any(|c| c.is_mounted())
+    }
+}
+
+/// The size of a block device (or file) in bytes.
+#[context("Querying size of {dev}")]
+pub(crate) fn device_size(dev: &Utf8Path) -> Result<u64> {
+    use std::io::{Seek, SeekFrom};
+    let mut f = File::open(dev)?;
+    Ok(f.seek(SeekFrom::End(0))?)
 }
 
 #[context("Failed to wipe {dev}")]
@@ -52,14 +65,11 @@ pub(crate) fn wipefs(dev: &Utf8Path) -> Result<()> {
 }
 
 fn list_impl(dev: Option<&Utf8Path>) -> Result<Vec<Device>> {
-    let o = Command::new("lsblk")
-        .args(["-J", "-o", "NAME,SERIAL,MODEL,LABEL,FSTYPE"])
+    let devs: DevicesOutput = Task::new("Listing block devices", "lsblk")
+        .args(["-J", "-o", "NAME,SERIAL,MODEL,LABEL,FSTYPE,MOUNTPOINT"])
         .args(dev)
-        .output()?;
-    if !o.status.success() {
-        return Err(anyhow::anyhow!("Failed to list block devices"));
-    }
-    let devs: DevicesOutput = serde_json::from_reader(&*o.stdout)?;
+        .quiet()
+        .read_json()?;
     Ok(devs.blockdevices)
 }
 
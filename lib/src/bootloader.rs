@@ -1,12 +1,24 @@
 use anyhow::Result;
 use camino::Utf8Path;
 use fn_error_context::context;
+use serde::{Deserialize, Serialize};
 
 use crate::task::Task;
 
 /// The name of the mountpoint for efi (as a subdirectory of /boot, or at the toplevel)
 pub(crate) const EFI_DIR: &str = "efi";
 
+/// The bootloader to install.
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Bootloader {
+    /// GRUB, installed via bootupd
+    #[default]
+    Grub,
+    /// No bootloader; the boot entries are still written to /boot
+    None,
+}
+
 #[context("Installing bootloader")]
 pub(crate) fn install_via_bootupd(
     device: &Utf8Path,
@@ -3,7 +3,7 @@
 //! Command line tool to manage bootable ostree-based containers.
 
 use anyhow::{Context, Result};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std;
 use cap_std_ext::cap_std::fs::Dir;
 use clap::Parser;
@@ -12,15 +12,25 @@ use ostree::gio;
 use ostree_container::store::PrepareResult;
 use ostree_ext::container as ostree_container;
 use ostree_ext::keyfileext::KeyFileExt;
+use ostree_ext::oci_spec::image::{ImageConfiguration, ImageManifest};
 use ostree_ext::ostree;
 use std::ffi::OsString;
-use std::io::Seek;
+use std::io::{Seek, Write};
+use std::os::fd::RawFd;
 use std::os::unix::process::CommandExt;
 use std::process::Command;
 
-use crate::deploy::RequiredHostSpec;
+use crate::deploy::{PullOptions, RequiredHostSpec};
+use crate::deployment::DeploymentTarget;
+use crate::image::CopySource;
+use crate::progress_jsonl::{Phase, ProgressWriter};
+use crate::ratelimit::Rate;
+use crate::reboot::{ApplyTarget, RebootKind, RebootPlan, SoftRebootMode, When};
+use crate::registries::PreferSource;
+use crate::schema::SchemaType;
 use crate::spec::Host;
-use crate::spec::ImageReference;
+use crate::spec::{AvailableUpdate, LayerDiff, UpdateCheck};
+use crate::spec::{ImageReference, ImageVerification};
 use crate::utils::sigpolicy_from_opts;
 
 include!(concat!(env!("OUT_DIR"), "/version.rs"));
@@ -36,16 +46,124 @@ pub(crate) struct UpgradeOpts {
     ///
     /// This only downloads an updated manifest and image configuration (i.e. typically kilobyte-sized metadata)
     /// as opposed to the image layers.
+    ///
+    /// The layers of the update are compared with those of the booted image, and
+    /// the number of changed, added and removed layers is shown along with the
+    /// size of the layers which need to be downloaded.
+    ///
+    /// The exit code is 0 if no update is available, and 77 if an update is available;
+    /// any other exit code indicates an error.  The result is cached and shown by
+    /// `bootc status`.
     #[clap(long, conflicts_with = "apply")]
     pub(crate) check: bool,
 
-    /// Restart or reboot into the new target image.
+    /// Fetch the update without staging it.
+    ///
+    /// The image is fetched (and its signature verified) as usual, and shown by
+    /// `bootc status`; a subsequent `bootc upgrade` stages it without fetching anything.
+    #[clap(long, conflicts_with_all = ["check", "apply", "stage_only"])]
+    pub(crate) download_only: bool,
+
+    /// Stage the update fetched via `--download-only`, without accessing the network.
     ///
-    /// Currently, this option always reboots.  In the future this command
-    /// will detect the case where no kernel changes are queued, and perform
-    /// a userspace-only restart.
+    /// This fails if no update was downloaded.
     #[clap(long, conflicts_with = "check")]
+    pub(crate) stage_only: bool,
+
+    #[clap(flatten)]
+    pub(crate) reboot: ApplyOpts,
+
+    /// The output format of `--check`; by default, a summary intended for humans.
+    #[clap(long, value_enum, requires = "check")]
+    pub(crate) format: Option<UpgradeCheckFormat>,
+
+    /// Write progress as JSON lines to the given file descriptor; see
+    /// `bootc-progress-fd(5)` for the format.
+    #[clap(long, value_parser = crate::progress_jsonl::parse_fd, conflicts_with = "check")]
+    pub(crate) progress_fd: Option<RawFd>,
+
+    /// Limit the aggregate download rate of image layers, e.g. `10MiB/s` or `500Kbps`.
+    ///
+    /// This overrides `bandwidth-limit` in the `[fetch]` section of the configuration
+    /// in `/etc/bootc/fetch/*.toml`.  The limit is enforced between layers.
+    #[clap(long, value_name = "RATE", conflicts_with = "check")]
+    pub(crate) bandwidth_limit: Option<Rate>,
+
+    /// The authentication file for the registry, in the format of `containers-auth.json(5)`.
+    ///
+    /// By default, the first of `/run/ostree/auth.json`, `/etc/ostree/auth.json` and
+    /// `/usr/lib/ostree/auth.json` which exists is used; see `bootc registry login`.
+    #[clap(long)]
+    pub(crate) authfile: Option<Utf8PathBuf>,
+
+    /// For images with mirrors configured in `registries.conf`, which source to try first.
+    ///
+    /// Each source is tried in turn; layers which a mirror lacks are fetched from the next source.
+    #[clap(long, value_enum, default_value_t, conflicts_with = "check")]
+    pub(crate) prefer_source: PreferSource,
+
+    /// Fail unless `/etc/containers/policy.json` requires signatures for the image.
+    ///
+    /// The image must have been switched to with `--enforce-container-sigpolicy`.
+    #[clap(long)]
+    pub(crate) enforce_container_sigpolicy: bool,
+
+    /// Act according to the automatic update policy.
+    ///
+    /// The policy is configured in the `[updates]` section of the configuration in
+    /// `/etc/bootc/fetch/*.toml`, and determines whether to check for, stage or apply
+    /// updates; it is shown by `bootc status`.  This is used by
+    /// `bootc-fetch-apply-updates.service`.
+    #[clap(long, conflicts_with_all = ["check", "apply", "progress_fd", "download_only", "stage_only"])]
+    pub(crate) auto: bool,
+}
+
+/// Options for rebooting into a newly staged deployment
+#[derive(clap::Args, Debug, PartialEq, Eq)]
+pub(crate) struct ApplyOpts {
+    /// Restart or reboot into the new target image once it is staged.
+    ///
+    /// By default, this performs a full reboot; see `--soft-reboot`.  If staging
+    /// fails, no reboot is performed.
+    #[clap(long)]
     pub(crate) apply: bool,
+
+    /// Whether `--apply` may restart only userspace via `systemctl soft-reboot`.
+    ///
+    /// With `auto` (the default if no value is given), a soft reboot is used if the
+    /// staged deployment has the same kernel, initramfs and kernel arguments as the
+    /// booted one, and a full reboot otherwise; see `bootc status`.  With `required`,
+    /// this fails instead of performing a full reboot.  For a soft reboot, the staged
+    /// deployment is mounted at `/run/nextroot`.
+    #[clap(
+        long,
+        value_enum,
+        requires = "apply",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "auto"
+    )]
+    pub(crate) soft_reboot: Option<SoftRebootMode>,
+
+    /// When to reboot: `now` (the default), `+MINUTES` or `HH:MM` (local time).
+    ///
+    /// A scheduled reboot can be cancelled with `systemctl stop bootc-apply.timer`.
+    #[clap(long, value_name = "TIME", requires = "apply")]
+    pub(crate) when: Option<When>,
+
+    /// Reboot even outside of the configured maintenance windows.
+    ///
+    /// By default, `--apply` only reboots within the windows configured in the
+    /// `[maintenance]` section of the configuration in `/etc/bootc/fetch/*.toml`;
+    /// the update is staged regardless.  The windows aren't consulted for `--when`.
+    #[clap(long, requires = "apply", conflicts_with = "when")]
+    pub(crate) now: bool,
+}
+
+/// The output format of `bootc upgrade --check`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UpgradeCheckFormat {
+    Json,
 }
 
 /// Perform an switch operation
@@ -56,9 +174,24 @@ pub(crate) struct SwitchOpts {
     pub(crate) quiet: bool,
 
     /// The transport; e.g. oci, oci-archive.  Defaults to `registry`.
+    ///
+    /// The transport may also be given as a prefix of the target, e.g.
+    /// `oci-archive:/mnt/usb/os.tar`.  Images named `localhost/...`, as built by
+    /// `podman build`, are fetched from the root container storage of the host
+    /// (`containers-storage`); the signature policy does not apply to such local images.
     #[clap(long, default_value = "registry")]
     pub(crate) transport: String,
 
+    /// The image to fetch for subsequent updates, e.g. `quay.io/example/os:latest`.
+    ///
+    /// This is used when switching to a copy of a registry image, e.g. in an OCI
+    /// archive on removable media: the image is recorded under this name, and
+    /// `bootc upgrade` fetches from the registry.  For the `oci` and `oci-archive`
+    /// transports, this defaults to the `org.opencontainers.image.ref.name`
+    /// annotation of the image, if it is a full image reference.
+    #[clap(long)]
+    pub(crate) target_imgref: Option<String>,
+
     /// This argument is deprecated and does nothing.
     #[clap(long, hide = true)]
     pub(crate) no_signature_verification: bool,
@@ -67,7 +200,8 @@ pub(crate) struct SwitchOpts {
     /// a no-op).
     ///
     /// Enabling this option enforces that `/etc/containers/policy.json` includes a
-    /// default policy which requires signatures.
+    /// default policy which requires signatures.  The switch fails before fetching
+    /// anything if the policy which applies to the image does not verify signatures.
     #[clap(long)]
     pub(crate) enforce_container_sigpolicy: bool,
 
@@ -78,20 +212,61 @@ pub(crate) struct SwitchOpts {
     /// Don't create a new deployment, but directly mutate the booted state.
     /// This is hidden because it's not something we generally expect to be done,
     /// but this can be used in e.g. Anaconda %post to fixup
-    #[clap(long, hide = true)]
+    #[clap(long, hide = true, conflicts_with = "apply")]
     pub(crate) mutate_in_place: bool,
 
-    /// Retain reference to currently booted image
+    /// Retain reference to currently booted image.
+    ///
+    /// The booted image is pinned, so that it is not pruned even once no deployment
+    /// uses it anymore; see `bootc image unpin`.  This can be enabled by default via
+    /// `retain` in the `[switch]` section of the configuration in `/etc/bootc/fetch/*.toml`.
     #[clap(long)]
     pub(crate) retain: bool,
 
+    /// Write progress as JSON lines to the given file descriptor; see
+    /// `bootc-progress-fd(5)` for the format.
+    #[clap(long, value_parser = crate::progress_jsonl::parse_fd, conflicts_with = "mutate_in_place")]
+    pub(crate) progress_fd: Option<RawFd>,
+
+    /// Limit the aggregate download rate of image layers, e.g. `10MiB/s` or `500Kbps`.
+    ///
+    /// This overrides `bandwidth-limit` in the `[fetch]` section of the configuration
+    /// in `/etc/bootc/fetch/*.toml`.  The limit is enforced between layers.
+    #[clap(long, value_name = "RATE", conflicts_with = "mutate_in_place")]
+    pub(crate) bandwidth_limit: Option<Rate>,
+
+    /// The authentication file for the registry, in the format of `containers-auth.json(5)`.
+    ///
+    /// By default, the first of `/run/ostree/auth.json`, `/etc/ostree/auth.json` and
+    /// `/usr/lib/ostree/auth.json` which exists is used; see `bootc registry login`.
+    #[clap(long, conflicts_with = "mutate_in_place")]
+    pub(crate) authfile: Option<Utf8PathBuf>,
+
+    /// For images with mirrors configured in `registries.conf`, which source to try first.
+    ///
+    /// Each source is tried in turn; layers which a mirror lacks are fetched from the next source.
+    #[clap(long, value_enum, default_value_t, conflicts_with = "mutate_in_place")]
+    pub(crate) prefer_source: PreferSource,
+
+    #[clap(flatten)]
+    pub(crate) reboot: ApplyOpts,
+
     /// Target image to use for the next boot.
     pub(crate) target: String,
 }
 
 /// Options controlling rollback
 #[derive(Debug, Parser, PartialEq, Eq)]
-pub(crate) struct RollbackOpts {}
+pub(crate) struct RollbackOpts {
+    /// Don't ask for confirmation.
+    ///
+    /// Confirmation is only requested when run interactively, i.e. on a terminal.
+    #[clap(long, short = 'y')]
+    pub(crate) assume_yes: bool,
+
+    #[clap(flatten)]
+    pub(crate) reboot: ApplyOpts,
+}
 
 /// Perform an edit operation
 #[derive(Debug, Parser, PartialEq, Eq)]
@@ -105,16 +280,340 @@ pub(crate) struct EditOpts {
     pub(crate) quiet: bool,
 }
 
+/// The output format of `bootc status`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StatusFormat {
+    /// A summary intended to be read by humans; not stable.
+    HumanReadable,
+    Yaml,
+    Json,
+}
+
 /// Perform an status operation
 #[derive(Debug, Parser, PartialEq, Eq)]
 pub(crate) struct StatusOpts {
-    /// Output in JSON format.
+    /// Output in JSON format; equivalent to `--format=json`.
     #[clap(long)]
     pub(crate) json: bool,
 
-    /// Only display status for the booted deployment.
+    /// The output format; the default is YAML.
+    #[clap(long, value_enum, conflicts_with = "json")]
+    pub(crate) format: Option<StatusFormat>,
+
+    /// The version of the YAML or JSON format.  Version 0 is the original format;
+    /// newer versions may add fields.  The default is the latest version.
     #[clap(long)]
+    pub(crate) format_version: Option<u32>,
+
+    /// Only display status for the booted deployment.
+    #[clap(long, conflicts_with_all = ["staged", "rollback"])]
     pub(crate) booted: bool,
+
+    /// Only display status for the staged deployment.
+    #[clap(long, conflicts_with = "rollback")]
+    pub(crate) staged: bool,
+
+    /// Only display status for the rollback deployment.
+    #[clap(long)]
+    pub(crate) rollback: bool,
+
+    /// Keep running, displaying the status again whenever it changes.  The status
+    /// is checked every INTERVAL seconds (by default 2).  With `--format=json`,
+    /// one JSON document is written per line.
+    #[clap(long, value_name = "INTERVAL", num_args = 0..=1, default_missing_value = "2")]
+    pub(crate) watch: Option<u64>,
+
+    /// Include more details, such as kernel arguments, in the human-readable format.
+    ///
+    /// This also shows how the system was installed, as recorded by `bootc install`.
+    #[clap(long, short = 'v')]
+    pub(crate) verbose: bool,
+}
+
+/// Operations on deployments
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum DeploymentOpts {
+    /// Pin a deployment, so that it is never garbage collected.
+    Pin {
+        /// The deployment: its index in the boot order (0 is the default), `booted` or `rollback`
+        #[clap(value_name = "INDEX|booted|rollback")]
+        target: DeploymentTarget,
+    },
+    /// Unpin a deployment, so that it is garbage collected as usual.
+    Unpin {
+        /// The deployment: its index in the boot order (0 is the default), `booted` or `rollback`
+        #[clap(value_name = "INDEX|booted|rollback")]
+        target: DeploymentTarget,
+    },
+}
+
+/// Operations on the container images stored by bootc
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum ImageOpts {
+    /// Copy the image of a deployment, or a stored image, to another location.
+    ///
+    /// The image is copied from its original location, pinned to the stored
+    /// manifest digest, as the bootc image store does not keep the original
+    /// compressed layers.  The digest of the copy is verified to match.
+    Copy {
+        /// The deployment (an index, `booted` or `rollback`) or stored image
+        /// (as shown by `bootc image list`) to copy
+        #[clap(default_value = "booted")]
+        source: CopySource,
+
+        /// The destination, e.g. `oci:/path/to/dir`, `oci-archive:/path/to/image.tar`,
+        /// `containers-storage:localhost/os` or `docker://registry.example.com/os`
+        #[clap(long, value_parser = parse_imgref)]
+        to: ostree_container::ImageReference,
+
+        /// The authentication file for the source and destination registries
+        #[clap(long)]
+        authfile: Option<Utf8PathBuf>,
+
+        /// Recompress the layers; this changes the digest of the image
+        #[clap(long, value_enum)]
+        compression_format: Option<CompressionFormat>,
+
+        /// Don't display progress
+        #[clap(long)]
+        quiet: bool,
+    },
+    /// List the stored images, along with the deployments and pins which use them.
+    List {
+        /// The output format
+        #[clap(long, value_enum, default_value_t)]
+        format: ImageListFormat,
+    },
+    /// List the images pinned via `bootc switch --retain`.
+    ListPinned,
+    /// Remove the stored images which are not used by any deployment and not pinned.
+    ///
+    /// This also happens automatically when a new deployment is staged.
+    Prune {
+        /// Only print the images which would be removed, along with their sizes.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Keep the given number of the most recently created unused images.
+        ///
+        /// These are still pruned when the next deployment is staged, unless pinned.
+        #[clap(long, value_name = "N", default_value_t = 0)]
+        keep: usize,
+    },
+    /// Unpin an image, so that it is pruned once no deployment uses it.
+    Unpin {
+        /// The transport; e.g. oci, oci-archive.  Defaults to `registry`.
+        #[clap(long, default_value = "registry")]
+        transport: String,
+
+        /// The pinned image
+        image: String,
+    },
+}
+
+/// Options for `bootc usroverlay`
+#[derive(Debug, clap::Args, PartialEq, Eq)]
+#[clap(args_conflicts_with_subcommands = true)]
+pub(crate) struct UsrOverlayOpts {
+    /// Keep the overlay across reboots, until another deployment is booted or staged.
+    ///
+    /// Its contents are stored in `/var/lib/bootc/usroverlay`.
+    #[clap(long)]
+    pub(crate) persist_until_update: bool,
+
+    #[clap(subcommand)]
+    pub(crate) cmd: Option<UsrOverlayCmd>,
+}
+
+/// Operations on a persistent overlay on `/usr`
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum UsrOverlayCmd {
+    /// Remove a persistent overlay.
+    ///
+    /// If it is mounted, it is discarded on the next boot.
+    Reset,
+}
+
+/// Operations on `/etc`
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum EtcOpts {
+    /// Show the local changes to `/etc`, relative to the defaults of the booted image.
+    ///
+    /// The defaults are stored in `/usr/etc`; any file added, removed or modified
+    /// in `/etc` relative to them is carried over to new deployments.  Added or
+    /// removed directories are shown without their contents.
+    Diff {
+        /// Also show unified diffs of modified text files.  Binary and large files
+        /// are shown by their SHA-256.
+        #[clap(long)]
+        contents: bool,
+
+        /// The output format
+        #[clap(long, value_enum, default_value_t)]
+        format: EtcDiffFormat,
+    },
+    /// Prepare for making `/etc` transient, by classifying its local changes.
+    ///
+    /// Each change is classified as already present in the image being deployed,
+    /// as configuration which should move into the image, as machine-local state
+    /// which needs a tmpfiles.d or confext strategy, or as unclassified.
+    /// With `--apply`, the changed files are staged into the configuration
+    /// extension `/var/lib/confexts/bootc-local-etc`, which is overlaid onto
+    /// a transient `/etc` on boot.
+    MigrateToTransient {
+        /// Only show the classification; this is the default.
+        #[clap(long, conflicts_with = "apply")]
+        dry_run: bool,
+
+        /// Stage the local changes into the configuration extension.
+        #[clap(long)]
+        apply: bool,
+
+        /// Apply even if there are unclassified changes; they are not staged.
+        #[clap(long, requires = "apply")]
+        force: bool,
+
+        /// Write a JSON report of the classification to this path.
+        #[clap(long)]
+        report: Option<Utf8PathBuf>,
+    },
+}
+
+/// Operations on container registries
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum RegistryOpts {
+    /// Log in to a registry, storing the credentials used to fetch images.
+    ///
+    /// The credentials are verified with the registry, and then stored in
+    /// `/etc/ostree/auth.json` (readable only by root), which is used by
+    /// `bootc upgrade` and `bootc switch`.  Unless `--password-stdin` is given,
+    /// the password is prompted for.
+    Login {
+        /// The registry, e.g. `quay.io`
+        registry: String,
+
+        /// The user name
+        #[clap(long, short = 'u')]
+        username: Option<String>,
+
+        /// Read the password from standard input.
+        #[clap(long)]
+        password_stdin: bool,
+
+        /// Store the credentials in this file instead.
+        #[clap(long)]
+        authfile: Option<Utf8PathBuf>,
+    },
+}
+
+/// The output format of `bootc etc diff`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum EtcDiffFormat {
+    #[default]
+    HumanReadable,
+    Json,
+}
+
+/// The output format of `bootc fsck`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum FsckFormat {
+    #[default]
+    HumanReadable,
+    Json,
+}
+
+/// The output format of `bootc image list`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum ImageListFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+/// Parse an image reference including its transport, e.g. `oci:/path/to/dir`.
+fn parse_imgref(s: &str) -> Result<ostree_container::ImageReference> {
+    ostree_container::ImageReference::try_from(s)
+}
+
+/// The compression of layers copied by `bootc image copy`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CompressionFormat {
+    Gzip,
+    Zstd,
+    #[clap(name = "zstd:chunked")]
+    ZstdChunked,
+}
+
+impl CompressionFormat {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::ZstdChunked => "zstd:chunked",
+        }
+    }
+}
+
+/// Options for displaying kernel arguments
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct KargsOpts {
+    /// Output in JSON format.
+    #[clap(long)]
+    pub(crate) json: bool,
+
+    #[clap(subcommand)]
+    pub(crate) cmd: Option<KargsCmd>,
+}
+
+/// Changes to the machine-local kernel arguments
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum KargsCmd {
+    /// Add a kernel argument to the next deployment, and persistently to all future
+    /// ones.  Adding an argument which is already present does nothing.
+    Append(KargsEditOpts),
+    /// Remove a kernel argument from the next deployment, and persistently from all
+    /// future ones.  A `KEY` removes the argument with any value, while `KEY=VALUE`
+    /// removes only that exact argument.
+    Delete(KargsEditOpts),
+}
+
+/// Options for changing a kernel argument
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct KargsEditOpts {
+    /// The kernel argument, of the form `KEY` or `KEY=VALUE`.
+    pub(crate) karg: String,
+
+    /// Also change the kernel arguments of the booted deployment, taking
+    /// effect on the next boot even without an update.
+    #[clap(long)]
+    pub(crate) apply_to_current: bool,
+}
+
+/// The output format of `bootc internals print-kargs`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PrintKargsFormat {
+    Json,
+}
+
+/// Options for printing the kargs.d configuration of a root filesystem
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct PrintKargsOpts {
+    /// The root filesystem containing the kargs.d directories
+    #[clap(long, default_value = "/")]
+    pub(crate) root: Utf8PathBuf,
+
+    /// Evaluate match-architectures for this architecture instead of the current one
+    #[clap(long, default_value = std::env::consts::ARCH)]
+    pub(crate) arch: String,
+
+    /// Evaluate match-firmware for this firmware type instead of that of the
+    /// current machine
+    #[clap(long, value_enum)]
+    pub(crate) firmware: Option<crate::kargs::Firmware>,
+
+    /// The output format
+    #[clap(long, value_enum, default_value = "json")]
+    pub(crate) format: PrintKargsFormat,
 }
 
 /// Options for internal testing
@@ -154,6 +653,15 @@ pub(crate) enum InternalsOpts {
         late_dir: Option<Utf8PathBuf>,
     },
     FixupEtcFstab,
+    /// Print the kernel arguments which the kargs.d files in a root filesystem
+    /// would apply, along with each file and its match conditions
+    PrintKargs(PrintKargsOpts),
+    /// Print the JSON schema of a format which bootc reads or writes
+    PrintJsonSchema {
+        /// The format
+        #[clap(long, value_enum, default_value_t)]
+        of: SchemaType,
+    },
 }
 
 impl InternalsOpts {
@@ -232,6 +740,10 @@ pub(crate) enum Opt {
     ///
     /// A systemd journal message will be logged with `MESSAGE_ID=26f3b1eb24464d12aa5e7b544a6b5468` in
     /// order to detect a rollback invocation.
+    ///
+    /// The image reference, version and digest of the deployment which will become the default,
+    /// and of the one which will be demoted, are printed first.  When run on a terminal,
+    /// confirmation is requested unless `--assume-yes` is passed.
     Rollback(RollbackOpts),
     /// Apply full changes to the host specification.
     ///
@@ -248,8 +760,53 @@ pub(crate) enum Opt {
     /// This will output a YAML-formatted object using a schema intended to match a Kubernetes resource
     /// that describes the state of the booted system.
     ///
-    /// The exact API format is not currently declared stable.
+    /// This also works on systems not managed by bootc; `status.type` is one of `bootcHost`,
+    /// `rpmOstreeHost`, `ostreeHost`, `nonOstreeHost` and `container`.  With `--format-version=0`,
+    /// only `bootcHost` is reported.
+    ///
+    /// The exact API format is not currently declared stable.  Its JSON schema, in the latest
+    /// format version, is printed by `bootc internals print-json-schema --of host-status`.
     Status(StatusOpts),
+    /// Display or change kernel arguments.
+    ///
+    /// Without a subcommand, this displays the kernel arguments of the booted and
+    /// staged deployments.  Each argument is annotated with its source: a kargs.d
+    /// file, `ostree` for the argument used to find the deployment, `install` for
+    /// `bootc install --karg`, `local` for `bootc kargs append`, or `inherited` for
+    /// anything else (e.g. the root filesystem set up at installation time).
+    ///
+    /// Changes made via `append` and `delete` are stored in `/etc/bootc/kargs-local.toml`
+    /// and applied whenever a new deployment is staged, so they persist across upgrades.
+    Kargs(KargsOpts),
+    /// Operations on the container images stored by bootc.
+    #[clap(subcommand)]
+    Image(ImageOpts),
+    /// Operations on deployments.
+    ///
+    /// Pinned deployments are shown by `bootc status`.
+    #[clap(subcommand)]
+    Deployment(DeploymentOpts),
+    /// Operations on the machine-local configuration in `/etc`.
+    #[clap(subcommand)]
+    Etc(EtcOpts),
+    /// Operations on container registries.
+    #[clap(subcommand)]
+    Registry(RegistryOpts),
+    /// Verify the integrity of all deployments.
+    ///
+    /// For each deployment, this verifies the objects of its ostree commit and the
+    /// fs-verity digest of its composefs image (if enabled), that its origin is valid
+    /// and its container image is still stored, and that its bootloader entry refers
+    /// to it.  The exit code is nonzero if any deployment fails verification.
+    Fsck {
+        /// Only check metadata; the content of files is not verified.
+        #[clap(long)]
+        fast: bool,
+
+        /// The output format
+        #[clap(long, value_enum, default_value_t)]
+        format: FsckFormat,
+    },
     /// Adds a transient writable overlayfs on `/usr` that will be discarded on reboot.
     ///
     /// ## Use cases
@@ -270,8 +827,13 @@ pub(crate) enum Opt {
     /// Almost always, a system process will hold a reference to the open mount point.
     /// You can however invoke `umount -l /usr` to perform a "lazy unmount".
     ///
+    /// ## Persistent overlays
+    ///
+    /// For emergency hotfixes, `--persist-until-update` keeps the overlay across
+    /// reboots, until another deployment is booted or staged; `bootc status` shows
+    /// when such an overlay is active.
     #[clap(alias = "usroverlay")]
-    UsrOverlay,
+    UsrOverlay(UsrOverlayOpts),
     /// Install the running container to a target.
     ///
     /// ## Understanding installations
@@ -346,6 +908,7 @@ pub(crate) async fn ensure_self_unshared_mount_namespace() -> Result<()> {
 /// TODO drain this and the above into SysrootLock
 #[context("Acquiring sysroot")]
 pub(crate) async fn get_locked_sysroot() -> Result<ostree_ext::sysroot::SysrootLock> {
+    crate::hosttype::require_sysroot(&crate::hosttype::detect_host()?)?;
     let sysroot = ostree::Sysroot::new_default();
     sysroot.set_mount_namespace_in_use();
     let sysroot = ostree_ext::sysroot::SysrootLock::new_from_sysroot(&sysroot).await?;
@@ -370,11 +933,7 @@ pub(crate) fn require_root() -> Result<()> {
 #[context("Preparing for write")]
 pub(crate) async fn prepare_for_write() -> Result<()> {
     crate::cli::require_root()?;
-    if ostree_ext::container_utils::is_ostree_container()? {
-        anyhow::bail!(
-            "Detected container (ostree base); this command requires a booted host system."
-        );
-    }
+    crate::hosttype::require_sysroot(&crate::hosttype::detect_host()?)?;
     ensure_self_unshared_mount_namespace().await?;
     if crate::lsm::selinux_enabled()? && !crate::lsm::selinux_ensure_install()? {
         tracing::warn!("Do not have install_t capabilities");
@@ -382,63 +941,246 @@ pub(crate) async fn prepare_for_write() -> Result<()> {
     Ok(())
 }
 
+/// The download rate limit; the command line overrides the configuration.
+fn bandwidth_limit(cli: Option<Rate>) -> Result<Option<Rate>> {
+    match cli {
+        Some(r) => Ok(Some(r)),
+        None => Ok(crate::fetchconfig::load_config()?.fetch.bandwidth_limit),
+    }
+}
+
+/// Reboot (or schedule a reboot) into the staged deployment, per `--apply`.
+fn apply_staged(
+    sysroot: &ostree_ext::sysroot::SysrootLock,
+    booted_deployment: &ostree::Deployment,
+    opts: &ApplyOpts,
+) -> Result<()> {
+    let (_, host) = crate::status::get_status(sysroot, Some(booted_deployment))?;
+    let target = ApplyTarget::Staged(host.status.soft_reboot.as_ref());
+    let staged = sysroot.staged_deployment();
+    apply_to(&host, target, opts, |plan| {
+        // systemd soft-reboots into the root at /run/nextroot
+        if let Some(staged) = staged.filter(|_| plan.kind == RebootKind::SoftReboot) {
+            let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+            let deployment = sysroot.deployment_dirpath(&staged);
+            let mounts = crate::reboot::nextroot_mounts(
+                &sysroot_dir,
+                Utf8Path::new("/sysroot"),
+                Utf8Path::new(deployment.as_str()),
+            )?;
+            crate::reboot::prepare_nextroot(crate::reboot::HOST_PID, &mounts)?;
+        }
+        crate::reboot::execute(plan)
+    })
+}
+
+/// Reboot (or schedule a reboot) into `target`, unless we're outside of the
+/// maintenance window.
+fn apply_to(
+    host: &Host,
+    target: ApplyTarget,
+    opts: &ApplyOpts,
+    exec: impl FnOnce(&RebootPlan) -> Result<()>,
+) -> Result<()> {
+    let window = host.status.maintenance_window.as_ref();
+    if let Some(window) = window.filter(|w| !w.active && !opts.now && opts.when.is_none()) {
+        println!(
+            "Not rebooting outside of the maintenance window; the next one starts at {}.",
+            window.start.to_rfc3339()
+        );
+        println!("The changes will take effect on the next reboot; use --now to reboot anyway.");
+        return Ok(());
+    }
+    crate::reboot::apply(
+        target,
+        opts.soft_reboot.unwrap_or_default(),
+        opts.when.unwrap_or_default(),
+        exec,
+    )
+}
+
+/// Implementation of `bootc upgrade --auto`.
+async fn auto_upgrade(mut opts: UpgradeOpts) -> Result<()> {
+    use crate::autoupdate::Action;
+    let policy = crate::fetchconfig::load_config()?.update_policy();
+    match crate::autoupdate::decide(&policy) {
+        Action::Disabled => {
+            println!("Automatic updates are disabled.");
+            return Ok(());
+        }
+        Action::Check => opts.check = true,
+        Action::Stage => {}
+        Action::Apply { soft_reboot, now } => {
+            opts.reboot.apply = true;
+            opts.reboot.soft_reboot = Some(soft_reboot);
+            opts.reboot.now = now;
+        }
+    }
+    let delay = crate::autoupdate::jitter(&policy);
+    if !delay.is_zero() {
+        println!("Waiting {}s before updating", delay.as_secs());
+        tokio::time::sleep(delay).await;
+    }
+    let retry = crate::autoupdate::retry_policy(&policy);
+    let opts = &opts;
+    // Only retry failures to reach the registry; anything else (e.g. a rejected
+    // signature) won't go away by trying again.
+    let is_retryable = crate::deploy::is_transient_fetch_error;
+    crate::utils::retry_async(&retry, is_retryable, || upgrade(opts)).await
+}
+
 /// Implementation of the `bootc upgrade` CLI command.
 #[context("Upgrading")]
-async fn upgrade(opts: UpgradeOpts) -> Result<()> {
+async fn upgrade(opts: &UpgradeOpts) -> Result<()> {
+    let mut progress = opts.progress_fd.map(ProgressWriter::from_fd).transpose()?;
     prepare_for_write().await?;
     let sysroot = &get_locked_sysroot().await?;
     let repo = &sysroot.repo();
-    let (booted_deployment, _deployments, host) =
-        crate::status::get_status_require_booted(sysroot)?;
+    let (booted_deployment, deployments, host) = crate::status::get_status_require_booted(sysroot)?;
     let imgref = host.spec.image.as_ref();
     // If there's no specified image, let's be nice and check if the booted system is using rpm-ostree
-    if imgref.is_none()
-        && host
-            .status
-            .booted
-            .as_ref()
-            .map_or(false, |b| b.incompatible)
-    {
-        return Err(anyhow::anyhow!(
-            "Booted deployment contains local rpm-ostree modifications; cannot upgrade via bootc"
-        ));
+    if let Some(ty) = host.status.ty.as_ref().filter(|_| imgref.is_none()) {
+        crate::hosttype::require_bootc(ty)?;
     }
     let spec = RequiredHostSpec::from_spec(&host.spec)?;
+    let booted_status = host.status.booted.as_ref().and_then(|b| b.image.as_ref());
     let booted_image = host
         .status
         .booted
+        .as_ref()
         .map(|b| b.query_image(repo))
         .transpose()?
         .flatten();
     let imgref = imgref.ok_or_else(|| anyhow::anyhow!("No image source specified"))?;
+    let root = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    crate::sigpolicy::check_policy_in_root(&root, imgref, opts.enforce_container_sigpolicy)?;
     // Find the currently queued digest, if any before we pull
     let staged = host.status.staged.as_ref();
     let staged_image = staged.as_ref().and_then(|s| s.image.as_ref());
+    let deployed = staged_image
+        .map(|s| s.image_digest.as_str())
+        .into_iter()
+        .chain(booted_image.as_ref().map(|b| b.manifest_digest.as_str()))
+        .collect::<Vec<_>>();
+    let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
     let mut changed = false;
     if opts.check {
-        let imgref = imgref.clone().into();
-        let mut imp = crate::deploy::new_importer(repo, &imgref).await?;
-        match imp.prepare().await? {
-            PrepareResult::AlreadyPresent(_) => {
-                println!("No changes in: {imgref:#}");
+        let json = opts.format == Some(UpgradeCheckFormat::Json);
+        let ostree_imgref = imgref.clone().into();
+        let auth = crate::auth::resolve(opts.authfile.as_deref())?;
+        let mut imp = crate::deploy::new_importer(repo, &ostree_imgref, &auth, None).await?;
+        let available_update = |digest: &str,
+                                config: &ImageConfiguration,
+                                manifest: &ImageManifest,
+                                is_stored: &dyn Fn(&str) -> bool| {
+            let status = crate::status::create_imagestatus(imgref.clone(), digest, config);
+            let layers = booted_image.as_ref().map(|booted| LayerDiff {
+                booted_version: booted_status.and_then(|s| s.version.clone()),
+                booted_timestamp: booted_status.and_then(|s| s.timestamp),
+                ..crate::updatecheck::layer_diff(&booted.manifest, manifest, is_stored)
+            });
+            AvailableUpdate {
+                image_digest: status.image_digest,
+                version: status.version,
+                timestamp: status.timestamp,
+                layers,
+            }
+        };
+        let available = match crate::deploy::prepare(&mut imp, &ostree_imgref)
+            .await
+            .map_err(|e| auth.annotate_error(e))?
+        {
+            PrepareResult::AlreadyPresent(state) => {
+                // The image is already stored if it was downloaded, but not staged
+                let downloaded = crate::download::load(&sysroot_dir, imgref, &deployed)?
+                    .filter(|d| d.image_digest == state.manifest_digest);
+                if downloaded.is_some() {
+                    let update = available_update(
+                        &state.manifest_digest,
+                        &state.configuration,
+                        &state.manifest,
+                        &|_| true,
+                    );
+                    if !json {
+                        println!("Update downloaded for: {ostree_imgref:#}");
+                        crate::updatecheck::write_available(std::io::stdout().lock(), &update)?;
+                        crate::kargs::print_kargs_diff_to_commit(
+                            sysroot,
+                            &booted_deployment,
+                            state.get_commit(),
+                        )?;
+                    }
+                    Some(update)
+                } else {
+                    if !json {
+                        println!("No changes in: {ostree_imgref:#}");
+                    }
+                    None
+                }
             }
             PrepareResult::Ready(r) => {
                 crate::deploy::check_bootc_label(&r.config);
-                println!("Update available for: {imgref:#}");
-                if let Some(version) = r.version() {
-                    println!("  Version: {version}");
-                }
-                println!("  Digest: {}", r.manifest_digest);
-                changed = true;
-                if let Some(previous_image) = booted_image.as_ref() {
-                    let diff =
-                        ostree_container::ManifestDiff::new(&previous_image.manifest, &r.manifest);
-                    diff.print();
+                let to_fetch = r
+                    .layers_to_fetch()
+                    .map(|l| l.map(|(l, _)| l.digest()))
+                    .collect::<Result<Vec<_>>>()?;
+                let update = available_update(&r.manifest_digest, &r.config, &r.manifest, &|d| {
+                    !to_fetch.contains(&d)
+                });
+                if !json {
+                    println!("Update available for: {ostree_imgref:#}");
+                    crate::updatecheck::write_available(std::io::stdout().lock(), &update)?;
+                    // The kargs.d files are only known once the layers are fetched
+                    println!("Changes in kernel arguments are shown once the update is downloaded via `bootc upgrade --download-only`.");
                 }
+                Some(update)
             }
+        };
+        let check = UpdateCheck {
+            image: imgref.clone(),
+            checked: chrono::Utc::now(),
+            available,
+        };
+        let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+        crate::updatecheck::write(root, &check)?;
+        if json {
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer(&mut stdout, &check)?;
+            writeln!(stdout)?;
+        } else if let Some(staged) = deployments
+            .staged
+            .as_ref()
+            .filter(|_| check.available.is_none())
+        {
+            crate::kargs::print_kargs_diff(sysroot, &booted_deployment, staged)?;
+        }
+        let code = crate::updatecheck::exit_code(&check);
+        // An available update isn't a failure of the automatic update service
+        if code != 0 && !opts.auto {
+            return Err(ExitCode(code).into());
         }
     } else {
-        let fetched = crate::deploy::pull(sysroot, imgref, opts.quiet).await?;
+        let downloaded = if opts.download_only {
+            None
+        } else {
+            crate::download::load_image(&sysroot_dir, repo, imgref, &deployed)?
+        };
+        let fetched = if let Some(downloaded) = downloaded {
+            println!("Using downloaded update: {}", downloaded.manifest_digest);
+            Box::new(downloaded)
+        } else if opts.stage_only {
+            anyhow::bail!("No downloaded update of {imgref}; see `bootc upgrade --download-only`");
+        } else {
+            let pull_opts = PullOptions {
+                quiet: opts.quiet,
+                progress: progress.as_mut(),
+                bandwidth_limit: bandwidth_limit(opts.bandwidth_limit)?,
+                authfile: opts.authfile.as_deref(),
+                target: None,
+                prefer_source: opts.prefer_source,
+            };
+            crate::deploy::pull(sysroot, imgref, pull_opts).await?
+        };
         let staged_digest = staged_image.as_ref().map(|s| s.image_digest.as_str());
         let fetched_digest = fetched.manifest_digest.as_str();
         tracing::debug!("staged: {staged_digest:?}");
@@ -453,14 +1195,26 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
         if staged_unchanged {
             println!("Staged update present, not changed.");
 
-            if opts.apply {
-                crate::reboot::reboot()?;
+            if opts.reboot.apply {
+                if let Some(p) = progress.take() {
+                    p.finish();
+                }
+                apply_staged(sysroot, &booted_deployment, &opts.reboot)?;
             }
         } else if booted_unchanged {
             println!("No update available.")
+        } else if opts.download_only {
+            let update =
+                crate::download::record(&sysroot_dir, imgref, &fetched, chrono::Utc::now())?;
+            let version = update.version.as_deref().unwrap_or(&update.image_digest);
+            println!("Downloaded update: {version}; stage it with `bootc upgrade`");
         } else {
             let osname = booted_deployment.osname();
+            if let Some(p) = progress.as_mut() {
+                p.phase(Phase::Finalizing);
+            }
             crate::deploy::stage(sysroot, &osname, &fetched, &spec).await?;
+            crate::download::clear(&sysroot_dir)?;
             changed = true;
             if let Some(prev) = booted_image.as_ref() {
                 if let Some(fetched_manifest) = fetched.get_manifest(repo)? {
@@ -469,11 +1223,17 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
                     diff.print();
                 }
             }
+            if let Some(staged) = sysroot.staged_deployment() {
+                crate::kargs::print_kargs_diff(sysroot, &booted_deployment, &staged)?;
+            }
         }
     }
+    if let Some(p) = progress {
+        p.finish();
+    }
     if changed {
-        if opts.apply {
-            crate::reboot::reboot()?;
+        if opts.reboot.apply {
+            apply_staged(sysroot, &booted_deployment, &opts.reboot)?;
         }
     } else {
         tracing::debug!("No changes");
@@ -485,17 +1245,54 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
 /// Implementation of the `bootc switch` CLI command.
 #[context("Switching")]
 async fn switch(opts: SwitchOpts) -> Result<()> {
-    let transport = ostree_container::Transport::try_from(opts.transport.as_str())?;
+    let source = crate::ocilayout::parse_target(&opts.transport, &opts.target)?;
+    let sudo_user = std::env::var("SUDO_USER").ok();
+    let (transport, changed) = crate::localimage::resolve_transport(
+        source.transport,
+        &source.name,
+        crate::localimage::image_exists,
+        sudo_user.as_deref(),
+    )?;
     let imgref = ostree_container::ImageReference {
         transport,
-        name: opts.target.to_string(),
+        name: source.name,
+    };
+    if changed {
+        println!("Using {imgref} from the host container storage");
+    }
+    let sigverify = if transport == ostree_container::Transport::ContainerStorage {
+        if opts.enforce_container_sigpolicy {
+            anyhow::bail!(
+                "Signatures cannot be verified for images in the local container storage: {imgref}"
+            );
+        }
+        println!("Note: Signature policy does not apply to the local image {imgref}");
+        ostree_container::SignatureSource::ContainerPolicyAllowInsecure
+    } else {
+        sigpolicy_from_opts(
+            !opts.enforce_container_sigpolicy,
+            opts.ostree_remote.as_deref(),
+        )
+    };
+    let logical = crate::ocilayout::target_of(&imgref, opts.target_imgref.as_deref())?;
+    let source = ImageReference::from(ostree_container::OstreeImageReference {
+        sigverify: sigverify.clone(),
+        imgref,
+    });
+    // The image recorded in the origin, and fetched by subsequent upgrades
+    let target = match logical {
+        Some(imgref) => {
+            let sigverify = sigpolicy_from_opts(
+                !opts.enforce_container_sigpolicy,
+                opts.ostree_remote.as_deref(),
+            );
+            let target =
+                ImageReference::from(ostree_container::OstreeImageReference { sigverify, imgref });
+            println!("Recording {source} as {target}");
+            target
+        }
+        None => source.clone(),
     };
-    let sigverify = sigpolicy_from_opts(
-        !opts.enforce_container_sigpolicy,
-        opts.ostree_remote.as_deref(),
-    );
-    let target = ostree_container::OstreeImageReference { sigverify, imgref };
-    let target = ImageReference::from(target);
 
     // If we're doing an in-place mutation, we shortcut most of the rest of the work here
     if opts.mutate_in_place {
@@ -511,7 +1308,18 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
         println!("Updated {deployid} to pull from {target}");
         return Ok(());
     }
+    let root = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    crate::sigpolicy::check_policy_in_root(&root, &target, opts.enforce_container_sigpolicy)?;
+    if source != target {
+        crate::sigpolicy::check_policy_in_root(&root, &source, opts.enforce_container_sigpolicy)?;
+    }
+    if crate::ocilayout::is_local(transport)
+        && crate::sigpolicy::verification_in_root(&root, &source)? == ImageVerification::Unverified
+    {
+        println!("Note: The signature of the local image {source} is not verified; see --enforce-container-sigpolicy");
+    }
 
+    let mut progress = opts.progress_fd.map(ProgressWriter::from_fd).transpose()?;
     prepare_for_write().await?;
     let cancellable = gio::Cancellable::NONE;
 
@@ -532,9 +1340,25 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
     }
     let new_spec = RequiredHostSpec::from_spec(&new_spec)?;
 
-    let fetched = crate::deploy::pull(sysroot, &target, opts.quiet).await?;
+    let pull_opts = PullOptions {
+        quiet: opts.quiet,
+        progress: progress.as_mut(),
+        bandwidth_limit: bandwidth_limit(opts.bandwidth_limit)?,
+        authfile: opts.authfile.as_deref(),
+        target: Some(&target),
+        prefer_source: opts.prefer_source,
+    };
+    let fetched = crate::deploy::pull(sysroot, &source, pull_opts).await?;
 
-    if !opts.retain {
+    let retain = opts.retain || crate::fetchconfig::load_config()?.retain_on_switch();
+    if retain {
+        if let Some(booted) = host.status.booted.as_ref().and_then(|b| b.image.as_ref()) {
+            let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+            if crate::image::pin(&sysroot_dir, &booted.image, chrono::Utc::now())? {
+                println!("Pinned {}", booted.image);
+            }
+        }
+    } else {
         // By default, we prune the previous ostree ref so it will go away after later upgrades
         if let Some(booted_origin) = booted_deployment.origin() {
             if let Some(ostree_ref) = booted_origin.optional_string("origin", "refspec")? {
@@ -546,17 +1370,46 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
     }
 
     let stateroot = booted_deployment.osname();
+    if let Some(p) = progress.as_mut() {
+        p.phase(Phase::Finalizing);
+    }
     crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec).await?;
+    if let Some(p) = progress {
+        p.finish();
+    }
+    if opts.reboot.apply {
+        apply_staged(sysroot, &booted_deployment, &opts.reboot)?;
+    }
 
     Ok(())
 }
 
 /// Implementation of the `bootc rollback` CLI command.
 #[context("Rollback")]
-async fn rollback(_opts: RollbackOpts) -> Result<()> {
+async fn rollback(opts: RollbackOpts) -> Result<()> {
+    use std::io::IsTerminal;
+
     prepare_for_write().await?;
     let sysroot = &get_locked_sysroot().await?;
-    crate::deploy::rollback(sysroot).await
+    let (_, _, host) = crate::status::get_status_require_booted(sysroot)?;
+    crate::deploy::write_rollback_plan(&mut std::io::stdout().lock(), &host)?;
+    let interactive = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    if crate::deploy::rollback_needs_confirmation(opts.assume_yes, interactive) {
+        let stdin = std::io::stdin().lock();
+        if !crate::utils::prompt_yes_no("Proceed?", stdin, std::io::stdout().lock())? {
+            anyhow::bail!("Rollback cancelled");
+        }
+    }
+    crate::deploy::rollback(sysroot).await?;
+    if opts.reboot.apply {
+        apply_to(
+            &host,
+            ApplyTarget::Rollback,
+            &opts.reboot,
+            crate::reboot::execute,
+        )?;
+    }
+    Ok(())
 }
 
 /// Implementation of the `bootc edit` CLI command.
@@ -590,7 +1443,12 @@ async fn edit(opts: EditOpts) -> Result<()> {
         return crate::deploy::rollback(sysroot).await;
     }
 
-    let fetched = crate::deploy::pull(sysroot, new_spec.image, opts.quiet).await?;
+    let pull_opts = PullOptions {
+        quiet: opts.quiet,
+        bandwidth_limit: bandwidth_limit(None)?,
+        ..Default::default()
+    };
+    let fetched = crate::deploy::pull(sysroot, new_spec.image, pull_opts).await?;
 
     // TODO gc old layers here
 
@@ -610,6 +1468,21 @@ async fn usroverlay() -> Result<()> {
         .into());
 }
 
+/// Returned (wrapped in an [`anyhow::Error`]) by a command which completed,
+/// but whose result should be conveyed by the given nonzero exit code, such as
+/// `bootc upgrade --check` when an update is available.  Callers should exit
+/// with this code without printing anything.
+#[derive(Debug)]
+pub struct ExitCode(pub i32);
+
+impl std::fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Exiting with code {}", self.0)
+    }
+}
+
+impl std::error::Error for ExitCode {}
+
 /// Parse the provided arguments and execute.
 /// Calls [`structopt::clap::Error::exit`] on failure, printing the error message and aborting the program.
 pub async fn run_from_iter<I>(args: I) -> Result<()>
@@ -651,16 +1524,27 @@ impl Opt {
 async fn run_from_opt(opt: Opt) -> Result<()> {
     let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
     match opt {
-        Opt::Upgrade(opts) => upgrade(opts).await,
+        Opt::Upgrade(opts) if opts.auto => auto_upgrade(opts).await,
+        Opt::Upgrade(opts) => upgrade(&opts).await,
         Opt::Switch(opts) => switch(opts).await,
         Opt::Rollback(opts) => rollback(opts).await,
         Opt::Edit(opts) => edit(opts).await,
-        Opt::UsrOverlay => usroverlay().await,
+        Opt::UsrOverlay(opts) => match opts {
+            UsrOverlayOpts {
+                cmd: Some(UsrOverlayCmd::Reset),
+                ..
+            } => crate::usroverlay::reset(),
+            UsrOverlayOpts {
+                persist_until_update: true,
+                ..
+            } => crate::usroverlay::persist().await,
+            _ => usroverlay().await,
+        },
         #[cfg(feature = "install")]
         Opt::Install(opts) => match opts {
             InstallOpts::ToDisk(opts) => crate::install::install_to_disk(opts).await,
             InstallOpts::ToFilesystem(opts) => {
-                crate::install::install_to_filesystem(opts, false).await
+                crate::install::install_to_filesystem(opts, false, &[]).await
             }
             InstallOpts::ToExistingRoot(opts) => {
                 crate::install::install_to_existing_root(opts).await
@@ -672,6 +1556,58 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
             crate::install::exec_in_host_mountns(args.as_slice())
         }
         Opt::Status(opts) => super::status::status(opts).await,
+        Opt::Kargs(opts) => crate::kargs::run(opts).await,
+        Opt::Deployment(opts) => match opts {
+            DeploymentOpts::Pin { target } => crate::deployment::set_pinned(target, true).await,
+            DeploymentOpts::Unpin { target } => crate::deployment::set_pinned(target, false).await,
+        },
+        Opt::Etc(opts) => match opts {
+            EtcOpts::Diff { contents, format } => crate::etcdiff::run_diff(contents, format),
+            EtcOpts::MigrateToTransient {
+                dry_run: _,
+                apply,
+                force,
+                report,
+            } => crate::etcmigrate::migrate_to_transient(apply, force, report.as_deref()).await,
+        },
+        Opt::Fsck { fast, format } => crate::fsck::fsck(fast, format).await,
+        Opt::Registry(RegistryOpts::Login {
+            registry,
+            username,
+            password_stdin,
+            authfile,
+        }) => crate::auth::login(
+            &registry,
+            username.as_deref(),
+            authfile.as_deref(),
+            password_stdin,
+        ),
+        Opt::Image(opts) => match opts {
+            ImageOpts::Copy {
+                source,
+                to,
+                authfile,
+                compression_format,
+                quiet,
+            } => {
+                crate::image::copy(source, &to, authfile.as_deref(), compression_format, quiet)
+                    .await
+            }
+            ImageOpts::List { format } => crate::image::list(format).await,
+            ImageOpts::ListPinned => crate::image::list_pinned().await,
+            ImageOpts::Prune { dry_run, keep } => crate::image::prune_cmd(dry_run, keep).await,
+            ImageOpts::Unpin { transport, image } => {
+                let transport = ostree_container::Transport::try_from(transport.as_str())?;
+                let imgref = ostree_container::OstreeImageReference {
+                    sigverify: ostree_container::SignatureSource::ContainerPolicyAllowInsecure,
+                    imgref: ostree_container::ImageReference {
+                        transport,
+                        name: image,
+                    },
+                };
+                crate::image::unpin_cmd(&imgref.into()).await
+            }
+        },
         Opt::Internals(opts) => match opts {
             InternalsOpts::SystemdGenerator {
                 normal_dir,
@@ -682,6 +1618,8 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 crate::generator::generator(root, unit_dir)
             }
             InternalsOpts::FixupEtcFstab => crate::deploy::fixup_etc_fstab(&root),
+            InternalsOpts::PrintKargs(opts) => crate::kargs::print_kargs(opts),
+            InternalsOpts::PrintJsonSchema { of } => crate::schema::print(of),
         },
         #[cfg(feature = "internal-testing-api")]
         Opt::InternalTests(opts) => crate::privtests::run(opts).await,
@@ -707,6 +1645,76 @@ fn test_parse_install_args() {
     };
     assert!(o.target_opts.target_no_signature_verification);
     assert_eq!(o.filesystem_opts.root_path.as_str(), "/target");
+
+    let to_filesystem = |args: &[&str]| {
+        let base = ["bootc", "install", "to-filesystem"];
+        let args = base.iter().chain(args).chain(&["/target"]);
+        match Opt::try_parse_from(args)? {
+            Opt::Install(InstallOpts::ToFilesystem(o)) => Ok::<_, clap::Error>(o.filesystem_opts),
+            o => panic!("Expected filesystem opts, not {o:?}"),
+        }
+    };
+    assert!(to_filesystem(&[]).unwrap().btrfs_subvolumes.is_none());
+    // The layout is optional, and must not consume the root path
+    let o = to_filesystem(&["--btrfs-subvolumes"]).unwrap();
+    assert_eq!(o.root_path.as_str(), "/target");
+    assert_eq!(
+        o.btrfs_subvolumes.unwrap().to_string(),
+        crate::install::btrfs::DEFAULT_LAYOUT
+    );
+    let o = to_filesystem(&["--btrfs-subvolumes=@=/,@home=/var/home"]).unwrap();
+    assert_eq!(
+        o.btrfs_subvolumes.unwrap().to_string(),
+        "@=/,@home=/var/home"
+    );
+    assert!(to_filesystem(&["--btrfs-subvolumes=var=/var"]).is_err());
+    let bootloader = |args: &[&str]| {
+        let base = ["bootc", "install", "to-filesystem"];
+        let args = base.iter().chain(args).chain(&["/target"]);
+        match Opt::try_parse_from(args)? {
+            Opt::Install(InstallOpts::ToFilesystem(o)) => {
+                Ok::<_, clap::Error>(o.config_opts.bootloader)
+            }
+            o => panic!("Expected filesystem opts, not {o:?}"),
+        }
+    };
+    use crate::bootloader::Bootloader;
+    assert_eq!(bootloader(&[]).unwrap(), Bootloader::Grub);
+    assert_eq!(
+        bootloader(&["--bootloader", "none"]).unwrap(),
+        Bootloader::None
+    );
+    assert!(bootloader(&["--bootloader=lilo"]).is_err());
+    assert!(bootloader(&["--bootloader=systemd-boot"]).is_err());
+
+    let to_disk = |args: &[&str]| {
+        let base = ["bootc", "install", "to-disk"];
+        let args = base.iter().chain(args).chain(&["/dev/vda"]);
+        match Opt::try_parse_from(args)? {
+            Opt::Install(InstallOpts::ToDisk(o)) => Ok::<_, clap::Error>(o.block_opts),
+            o => panic!("Expected to-disk opts, not {o:?}"),
+        }
+    };
+    let o = to_disk(&[
+        "--encrypt=luks2",
+        "--encrypt-tpm2",
+        "--encrypt-tpm2-pcrs=0+7",
+    ])
+    .unwrap()
+    .encryption;
+    assert!(o.encrypt_tpm2);
+    assert_eq!(o.encrypt_tpm2_pcrs.as_deref(), Some("0+7"));
+    to_disk(&["--encrypt=luks2", "--encrypt-var", "--root-size=10G"]).unwrap();
+    let o = to_disk(&["--block-setup=raid1", "--device", "/dev/vdb"]).unwrap();
+    assert_eq!(o.device, "/dev/vda");
+    assert_eq!(o.mirror_devices, ["/dev/vdb"]);
+    for invalid in [
+        &["--encrypt-tpm2"][..],
+        &["--encrypt=luks2", "--encrypt-var"],
+        &["--encrypt=luks2", "--encrypt-tpm2-pcrs=7"],
+    ] {
+        assert!(to_disk(invalid).is_err(), "{invalid:?}");
+    }
 }
 
 #[test]
@@ -715,9 +1723,329 @@ fn test_parse_opts() {
         Opt::parse_including_static(["bootc", "status"]),
         Opt::Status(StatusOpts {
             json: false,
-            booted: false
+            format: None,
+            format_version: None,
+            booted: false,
+            staged: false,
+            rollback: false,
+            watch: None,
+            verbose: false,
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "status", "--format", "yaml"]),
+        Opt::Status(StatusOpts {
+            format: Some(StatusFormat::Yaml),
+            ..
+        })
+    ));
+    let e = Opt::try_parse_from(["bootc", "status", "--format", "toml"]).unwrap_err();
+    assert!(
+        e.to_string()
+            .contains("[possible values: human-readable, yaml, json]"),
+        "{e}"
+    );
+    assert!(Opt::try_parse_from(["bootc", "status", "--json", "--format", "yaml"]).is_err());
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "status", "--json", "--format-version=0"]),
+        Opt::Status(StatusOpts {
+            json: true,
+            format_version: Some(0),
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "status", "--staged", "--format=json"]),
+        Opt::Status(StatusOpts {
+            staged: true,
+            booted: false,
+            format: Some(StatusFormat::Json),
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "upgrade", "--check", "--format=json"]),
+        Opt::Upgrade(UpgradeOpts {
+            check: true,
+            format: Some(UpgradeCheckFormat::Json),
+            ..
+        })
+    ));
+    // The format only applies to --check
+    assert!(Opt::try_parse_from(["bootc", "upgrade", "--format=json"]).is_err());
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "upgrade", "--auto", "--quiet"]),
+        Opt::Upgrade(UpgradeOpts {
+            auto: true,
+            quiet: true,
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "upgrade", "--apply", "--soft-reboot"]),
+        Opt::Upgrade(UpgradeOpts {
+            reboot: ApplyOpts {
+                soft_reboot: Some(SoftRebootMode::Auto),
+                ..
+            },
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "upgrade", "--apply", "--soft-reboot=required"]),
+        Opt::Upgrade(UpgradeOpts {
+            reboot: ApplyOpts {
+                soft_reboot: Some(SoftRebootMode::Required),
+                ..
+            },
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "usroverlay"]),
+        Opt::UsrOverlay(UsrOverlayOpts {
+            persist_until_update: false,
+            cmd: None,
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "usr-overlay", "--persist-until-update"]),
+        Opt::UsrOverlay(UsrOverlayOpts {
+            persist_until_update: true,
+            cmd: None,
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "usroverlay", "reset"]),
+        Opt::UsrOverlay(UsrOverlayOpts {
+            cmd: Some(UsrOverlayCmd::Reset),
+            ..
+        })
+    ));
+    assert!(
+        Opt::try_parse_from(["bootc", "usroverlay", "--persist-until-update", "reset"]).is_err()
+    );
+    // The policy determines whether to check or apply
+    for arg in ["--check", "--apply", "--progress-fd=3"] {
+        assert!(Opt::try_parse_from(["bootc", "upgrade", "--auto", arg]).is_err());
+    }
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "switch", "--progress-fd=3", "quay.io/example/os"]),
+        Opt::Switch(SwitchOpts {
+            progress_fd: Some(3),
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static([
+            "bootc",
+            "switch",
+            "--authfile=/run/auth.json",
+            "quay.io/example/os"
+        ]),
+        Opt::Switch(SwitchOpts {
+            authfile: Some(p),
+            ..
+        }) if p == "/run/auth.json"
+    ));
+    assert!(matches!(
+        Opt::parse_including_static([
+            "bootc",
+            "switch",
+            "--target-imgref=quay.io/example/os:latest",
+            "oci-archive:/mnt/usb/os.tar"
+        ]),
+        Opt::Switch(SwitchOpts {
+            target_imgref: Some(t),
+            target,
+            ..
+        }) if t == "quay.io/example/os:latest" && target == "oci-archive:/mnt/usb/os.tar"
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "upgrade", "--download-only"]),
+        Opt::Upgrade(UpgradeOpts {
+            download_only: true,
+            stage_only: false,
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "upgrade", "--stage-only", "--apply"]),
+        Opt::Upgrade(UpgradeOpts {
+            stage_only: true,
+            reboot: ApplyOpts { apply: true, .. },
+            ..
+        })
+    ));
+    for args in [
+        ["bootc", "upgrade", "--download-only", "--check"],
+        ["bootc", "upgrade", "--download-only", "--apply"],
+        ["bootc", "upgrade", "--download-only", "--stage-only"],
+        ["bootc", "upgrade", "--stage-only", "--check"],
+        ["bootc", "upgrade", "--auto", "--download-only"],
+    ] {
+        assert!(Opt::try_parse_from(args).is_err(), "{args:?}");
+    }
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "upgrade", "--prefer-source=upstream"]),
+        Opt::Upgrade(UpgradeOpts {
+            prefer_source: PreferSource::Upstream,
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "switch", "quay.io/example/os"]),
+        Opt::Switch(SwitchOpts {
+            prefer_source: PreferSource::Mirror,
+            ..
+        })
+    ));
+    assert!(
+        Opt::try_parse_from(["bootc", "upgrade", "--check", "--prefer-source=upstream"]).is_err()
+    );
+    assert!(Opt::try_parse_from([
+        "bootc",
+        "switch",
+        "--mutate-in-place",
+        "--authfile=/run/auth.json",
+        "quay.io/example/os"
+    ])
+    .is_err());
+    assert_eq!(
+        Opt::parse_including_static([
+            "bootc",
+            "registry",
+            "login",
+            "-u",
+            "someuser",
+            "--password-stdin",
+            "quay.io"
+        ]),
+        Opt::Registry(RegistryOpts::Login {
+            registry: "quay.io".into(),
+            username: Some("someuser".into()),
+            password_stdin: true,
+            authfile: None,
+        })
+    );
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "upgrade", "--bandwidth-limit", "10MiB/s"]),
+        Opt::Upgrade(UpgradeOpts {
+            bandwidth_limit: Some(r),
+            ..
+        }) if r.bytes_per_sec() == 10 << 20
+    ));
+    assert!(matches!(
+        Opt::parse_including_static([
+            "bootc",
+            "switch",
+            "--apply",
+            "--soft-reboot=auto",
+            "--when=+10",
+            "quay.io/example/os"
+        ]),
+        Opt::Switch(SwitchOpts {
+            reboot: ApplyOpts {
+                apply: true,
+                soft_reboot: Some(SoftRebootMode::Auto),
+                when: Some(When::In(_)),
+                now: false,
+            },
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "rollback", "-y", "--apply"]),
+        Opt::Rollback(RollbackOpts {
+            assume_yes: true,
+            reboot: ApplyOpts { apply: true, .. },
+        })
+    ));
+    assert_eq!(
+        Opt::parse_including_static(["bootc", "deployment", "pin", "rollback"]),
+        Opt::Deployment(DeploymentOpts::Pin {
+            target: DeploymentTarget::Rollback
+        })
+    );
+    assert_eq!(
+        Opt::parse_including_static(["bootc", "deployment", "unpin", "2"]),
+        Opt::Deployment(DeploymentOpts::Unpin {
+            target: DeploymentTarget::Index(2)
+        })
+    );
+    assert!(matches!(
+        Opt::parse_including_static([
+            "bootc",
+            "image",
+            "copy",
+            "--to=oci-archive:/srv/os.tar",
+            "--compression-format=zstd:chunked"
+        ]),
+        Opt::Image(ImageOpts::Copy {
+            source: CopySource::Deployment(DeploymentTarget::Booted),
+            to: ostree_container::ImageReference {
+                transport: ostree_container::Transport::OciArchive,
+                ..
+            },
+            compression_format: Some(CompressionFormat::ZstdChunked),
+            ..
         })
     ));
+    assert_eq!(
+        Opt::parse_including_static(["bootc", "image", "list", "--format=json"]),
+        Opt::Image(ImageOpts::List {
+            format: ImageListFormat::Json
+        })
+    );
+    assert_eq!(
+        Opt::parse_including_static(["bootc", "image", "prune", "--dry-run", "--keep=2"]),
+        Opt::Image(ImageOpts::Prune {
+            dry_run: true,
+            keep: 2
+        })
+    );
+    for args in [
+        &["upgrade", "--progress-fd=1"][..],
+        &["upgrade", "--soft-reboot=auto"],
+        &["upgrade", "--apply", "--when=soon"],
+        &["upgrade", "--check", "--apply"],
+        &["deployment", "pin", "staged"],
+        &["upgrade", "--now"],
+        &["upgrade", "--apply", "--now", "--when=+5"],
+        &["switch", "--when=+5", "quay.io/example/os"],
+        &[
+            "switch",
+            "--apply",
+            "--mutate-in-place",
+            "quay.io/example/os",
+        ],
+        &["upgrade", "--bandwidth-limit=fast"],
+        &["upgrade", "--check", "--bandwidth-limit=1M"],
+        &["upgrade", "--check", "--progress-fd=3"],
+    ] {
+        assert!(Opt::try_parse_from(["bootc"].iter().chain(args)).is_err());
+    }
+    for (args, expected) in [
+        (&["--watch"][..], Some(2)),
+        (&["--watch", "10"], Some(10)),
+        (&["--watch=5", "--json"], Some(5)),
+    ] {
+        let opts = Opt::parse_including_static(["bootc", "status"].iter().chain(args));
+        let Opt::Status(opts) = opts else { panic!() };
+        assert_eq!(opts.watch, expected, "{args:?}");
+    }
+    for (a, b) in [
+        ("--booted", "--staged"),
+        ("--booted", "--rollback"),
+        ("--staged", "--rollback"),
+    ] {
+        let e = Opt::try_parse_from(["bootc", "status", a, b]).unwrap_err();
+        assert_eq!(
+            e.kind(),
+            clap::error::ErrorKind::ArgumentConflict,
+            "{a} {b}"
+        );
+    }
 }
 
 #[test]
@@ -730,3 +2058,29 @@ fn test_parse_generator() {
         Opt::Internals(InternalsOpts::SystemdGenerator { .. })
     ));
 }
+
+#[test]
+fn test_parse_print_kargs() {
+    let o = Opt::parse_including_static([
+        "bootc",
+        "internals",
+        "print-kargs",
+        "--root",
+        "/rootfs",
+        "--arch",
+        "aarch64",
+    ]);
+    let Opt::Internals(InternalsOpts::PrintKargs(o)) = o else {
+        panic!("Expected print-kargs, not {o:?}");
+    };
+    assert_eq!(o.root, "/rootfs");
+    assert_eq!(o.arch, "aarch64");
+    assert_eq!(o.firmware, None);
+    assert_eq!(o.format, PrintKargsFormat::Json);
+}
+
+#[test]
+fn test_exit_code_through_context() {
+    let e = anyhow::Error::from(ExitCode(77)).context("Upgrading");
+    assert_eq!(e.downcast_ref::<ExitCode>().unwrap().0, 77);
+}
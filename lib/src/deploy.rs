@@ -3,13 +3,16 @@
 //! Create a merged filesystem tree with the image and mounted configmaps.
 
 use std::io::{BufRead, Write};
+use std::time::Instant;
 
 use anyhow::Ok;
 use anyhow::{anyhow, Context, Result};
+use camino::Utf8Path;
 
 use cap_std::fs::{Dir, MetadataExt};
 use cap_std_ext::cap_std;
 use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
 use fn_error_context::context;
 use ostree::{gio, glib};
 use ostree_container::OstreeImageReference;
@@ -18,9 +21,14 @@ use ostree_ext::container::store::PrepareResult;
 use ostree_ext::ostree;
 use ostree_ext::ostree::Deployment;
 use ostree_ext::sysroot::SysrootLock;
+use serde::{Deserialize, Serialize};
 
-use crate::spec::ImageReference;
+use crate::auth::AuthSource;
+use crate::progress_jsonl::{Phase, ProgressWriter};
+use crate::ratelimit::{Rate, TokenBucket};
+use crate::registries::{PreferSource, Source};
 use crate::spec::{BootOrder, HostSpec};
+use crate::spec::{ImageReference, ImageVerification};
 use crate::status::labels_of_config;
 
 // TODO use https://github.com/ostreedev/ostree-rs-ext/pull/493/commits/afc1837ff383681b947de30c0cefc70080a4f87a
@@ -29,6 +37,16 @@ const BASE_IMAGE_PREFIX: &str = "ostree/container/baseimage/bootc";
 /// Set on an ostree commit if this is a derived commit
 const BOOTC_DERIVED_KEY: &str = "bootc.derived";
 
+/// The group in an origin file holding metadata recorded by bootc
+pub(crate) const ORIGIN_BOOTC_GROUP: &str = "bootc";
+/// Key in [`ORIGIN_BOOTC_GROUP`] recording how the image was verified, as JSON
+pub(crate) const ORIGIN_VERIFICATION_KEY: &str = "image-verification";
+/// Key in [`ORIGIN_BOOTC_GROUP`] recording a [`PullInfo`], as JSON
+pub(crate) const ORIGIN_PULL_KEY: &str = "image-pull";
+/// Key in [`ORIGIN_BOOTC_GROUP`] recording the kernel arguments which kargs.d
+/// provided to the deployment, as a command line
+pub(crate) const ORIGIN_KARGS_D_KEY: &str = "kargs-d";
+
 /// Variant of HostSpec but required to be filled out
 pub(crate) struct RequiredHostSpec<'a> {
     pub(crate) image: &'a ImageReference,
@@ -39,6 +57,65 @@ pub(crate) struct ImageState {
     pub(crate) manifest_digest: String,
     pub(crate) version: Option<String>,
     pub(crate) ostree_commit: String,
+    pub(crate) pull_info: PullInfo,
+}
+
+/// Sizes and timing of fetching an image, recorded in the origin of the
+/// deployment so that status doesn't need to recompute them.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PullInfo {
+    /// The total size of the compressed layers
+    pub(crate) compressed_size: Option<u64>,
+    /// The size of the unpacked filesystem tree
+    pub(crate) unpacked_size: Option<u64>,
+    /// When the image was fetched; unset if it was already present
+    pub(crate) timestamp: Option<DateTime<Utc>>,
+    /// The size of the layers which were downloaded; the remainder of
+    /// [`Self::compressed_size`] was reused from locally stored layers
+    #[serde(default)]
+    pub(crate) downloaded_size: Option<u64>,
+    /// The image the content was fetched from, if it is not the image of the
+    /// deployment; e.g. an OCI archive on removable media
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) source: Option<ImageReference>,
+}
+
+/// How much of an image was downloaded, and how much was reused from
+/// layers which were already stored locally.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FetchStats {
+    /// The total (compressed) size of the image's layers
+    pub(crate) total: u64,
+    /// The size of the layers which were downloaded
+    pub(crate) downloaded: u64,
+}
+
+impl FetchStats {
+    pub(crate) fn new(
+        to_fetch: impl Iterator<Item = u64>,
+        stored: impl Iterator<Item = u64>,
+    ) -> Self {
+        let downloaded = to_fetch.sum();
+        let reused: u64 = stored.sum();
+        Self {
+            total: downloaded + reused,
+            downloaded,
+        }
+    }
+
+    /// The size of the layers which didn't need to be downloaded.
+    pub(crate) fn reused(&self) -> u64 {
+        self.total.saturating_sub(self.downloaded)
+    }
+
+    /// The percentage of the image which didn't need to be downloaded.
+    pub(crate) fn percent_saved(&self) -> u64 {
+        match self.total {
+            0 => 0,
+            total => self.reused() * 100 / total,
+        }
+    }
 }
 
 impl<'a> RequiredHostSpec<'a> {
@@ -57,14 +134,55 @@ impl From<ostree_container::store::LayeredImageState> for ImageState {
     fn from(value: ostree_container::store::LayeredImageState) -> Self {
         let version = value.version().map(|v| v.to_owned());
         let ostree_commit = value.get_commit().to_owned();
+        let pull_info = PullInfo {
+            compressed_size: Some(compressed_size(&value.manifest)),
+            ..Default::default()
+        };
         Self {
             manifest_digest: value.manifest_digest,
             version,
             ostree_commit,
+            pull_info,
         }
     }
 }
 
+/// The total size of the (compressed) layers of an image.
+pub(crate) fn compressed_size(manifest: &ostree_ext::oci_spec::image::ImageManifest) -> u64 {
+    manifest
+        .layers()
+        .iter()
+        .map(|l| u64::try_from(l.size()).unwrap_or_default())
+        .sum()
+}
+
+/// The total size of the regular files in a commit; content shared between
+/// files is counted for each of them.  This walks the whole tree, so it is
+/// only done once when an image is imported and recorded in [`PullInfo`].
+#[context("Computing size of {commit}")]
+pub(crate) fn commit_unpacked_size(repo: &ostree::Repo, commit: &str) -> Result<u64> {
+    fn tree_size(dir: &gio::File) -> Result<u64> {
+        use gio::prelude::{FileEnumeratorExt, FileExt};
+        let cancellable = gio::Cancellable::NONE;
+        let children = dir.enumerate_children(
+            "standard::name,standard::type,standard::size",
+            gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+            cancellable,
+        )?;
+        let mut size = 0;
+        while let Some(info) = children.next_file(cancellable)? {
+            match info.file_type() {
+                gio::FileType::Directory => size += tree_size(&dir.child(info.name()))?,
+                gio::FileType::Regular => size += u64::try_from(info.size()).unwrap_or_default(),
+                _ => {}
+            }
+        }
+        Ok(size)
+    }
+    let (root, _) = repo.read_commit(commit, gio::Cancellable::NONE)?;
+    tree_size(&root)
+}
+
 impl ImageState {
     /// Fetch the manifest corresponding to this image.  May not be available in all backends.
     pub(crate) fn get_manifest(
@@ -80,13 +198,101 @@ impl ImageState {
 pub(crate) async fn new_importer(
     repo: &ostree::Repo,
     imgref: &ostree_container::OstreeImageReference,
+    auth: &AuthSource,
+    source: Option<&SourceConfig>,
 ) -> Result<ostree_container::store::ImageImporter> {
-    let config = Default::default();
+    let mut config = ostree_container::store::ImageProxyConfig::default();
+    auth.configure(&mut config)?;
+    let sources = crate::registries::pull_sources(&imgref.imgref)?;
+    tracing::debug!("Fetching {} from: {}", imgref.imgref, sources.join(", "));
+    let no_proxy = std::env::var("NO_PROXY")
+        .or_else(|_| std::env::var("no_proxy"))
+        .ok();
+    let proxy_env =
+        crate::fetchconfig::load_config()?.image_proxy_env(&sources, no_proxy.as_deref());
+    if proxy_env.is_some() || source.is_some() {
+        let mut c = std::process::Command::new("setpriv");
+        c.args(["--pdeathsig", "SIGTERM", "--", "skopeo"]);
+        if let Some(env) = proxy_env {
+            // The proxy is only set for the fetcher, and not for us (or anything else)
+            tracing::debug!("Using the configured HTTPS proxy");
+            c.envs(env);
+        }
+        if let Some(source) = source {
+            c.env("HOME", source.home.path());
+        }
+        config.skopeo_cmd = Some(c);
+    }
     let mut imp = ostree_container::store::ImageImporter::new(repo, imgref, config).await?;
     imp.require_bootable();
     Ok(imp)
 }
 
+/// A configuration of the container stack which only fetches an image from
+/// one of its sources.  The container stack only reads the per-user
+/// `registries.conf` if it exists, and hence the fetcher is run with a
+/// temporary home directory containing it.
+#[derive(Debug)]
+pub(crate) struct SourceConfig {
+    home: tempfile::TempDir,
+}
+
+impl SourceConfig {
+    /// Only fetch the image `name` from `source`.
+    #[context("Configuring source {}", source.name)]
+    pub(crate) fn new(name: &str, source: &Source) -> Result<Self> {
+        let home = tempfile::tempdir()?;
+        let dir = home.path().join(".config/containers");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join("registries.conf"),
+            crate::registries::config_for_source(name, source),
+        )?;
+        Ok(Self { home })
+    }
+}
+
+/// Fetch the manifest and configuration of an image; on failure, the error
+/// lists each source (i.e. mirror) that was tried.
+pub(crate) async fn prepare(
+    imp: &mut ostree_container::store::ImageImporter,
+    imgref: &ostree_container::OstreeImageReference,
+) -> Result<PrepareResult> {
+    imp.prepare().await.or_else(|e| {
+        let sources = crate::registries::pull_sources(&imgref.imgref)?;
+        Err(crate::registries::annotate_error(e, &sources))
+    })
+}
+
+/// Substrings of errors (lowercased) from fetching an image which indicate a
+/// network problem or an overloaded registry, rather than e.g. a missing image,
+/// an authentication failure or a rejected signature.
+const TRANSIENT_FETCH_ERRORS: &[&str] = &[
+    "connection refused",
+    "connection reset",
+    "connection timed out",
+    "i/o timeout",
+    "tls handshake timeout",
+    "context deadline exceeded",
+    "temporary failure in name resolution",
+    "network is unreachable",
+    "no route to host",
+    "unexpected eof",
+    "toomanyrequests",
+    "429 too many requests",
+    "500 internal server error",
+    "502 bad gateway",
+    "503 service unavailable",
+    "504 gateway timeout",
+];
+
+/// Whether an error is a transient failure to fetch an image, for which
+/// retrying may succeed.
+pub(crate) fn is_transient_fetch_error(e: &anyhow::Error) -> bool {
+    let msg = format!("{e:#}").to_ascii_lowercase();
+    TRANSIENT_FETCH_ERRORS.iter().any(|p| msg.contains(p))
+}
+
 pub(crate) fn check_bootc_label(config: &ostree_ext::oci_spec::image::ImageConfiguration) {
     if let Some(label) =
         labels_of_config(config).and_then(|labels| labels.get(crate::metadata::BOOTC_COMPAT_LABEL))
@@ -113,20 +319,81 @@ pub(crate) fn check_bootc_label(config: &ostree_ext::oci_spec::image::ImageConfi
     }
 }
 
-/// Write container fetch progress to standard output.
-async fn handle_layer_progress_print(
+/// The descriptor of the layer an import progress message refers to.
+fn progress_layer(
+    p: &ostree_container::store::ImportProgress,
+) -> &ostree_ext::oci_spec::image::Descriptor {
+    use ostree_container::store::ImportProgress;
+    match p {
+        ImportProgress::OstreeChunkStarted(l)
+        | ImportProgress::OstreeChunkCompleted(l)
+        | ImportProgress::DerivedLayerStarted(l)
+        | ImportProgress::DerivedLayerCompleted(l) => l,
+    }
+}
+
+/// Options for [`pull`].
+#[derive(Debug, Default)]
+pub(crate) struct PullOptions<'a> {
+    /// Don't write progress to standard output
+    pub(crate) quiet: bool,
+    /// Emit events for the verifying, fetching and writing phases
+    pub(crate) progress: Option<&'a mut ProgressWriter>,
+    /// Limit the aggregate download rate of layers
+    pub(crate) bandwidth_limit: Option<Rate>,
+    /// The authentication file given via `--authfile`
+    pub(crate) authfile: Option<&'a Utf8Path>,
+    /// Store the image as if it was fetched from this image, which is recorded
+    /// in the origin; see [`crate::ocilayout`]
+    pub(crate) target: Option<&'a ImageReference>,
+    /// Which source of an image with mirrors to try first
+    pub(crate) prefer_source: PreferSource,
+}
+
+impl PullOptions<'_> {
+    /// The same options, for one of several attempts.
+    fn reborrow(&mut self) -> PullOptions<'_> {
+        PullOptions {
+            quiet: self.quiet,
+            progress: self.progress.as_deref_mut(),
+            bandwidth_limit: self.bandwidth_limit,
+            authfile: self.authfile,
+            target: self.target,
+            prefer_source: self.prefer_source,
+        }
+    }
+}
+
+/// Write container fetch progress to standard output (unless `quiet`) and
+/// to `progress`, if any.  If `bandwidth_limit` is set, processing further
+/// messages (and hence the import, which waits for us) is paused after each
+/// layer as needed.
+async fn handle_layer_progress(
     mut layers: tokio::sync::mpsc::Receiver<ostree_container::store::ImportProgress>,
     mut layer_bytes: tokio::sync::watch::Receiver<Option<ostree_container::store::LayerProgress>>,
     total_layers: usize,
-    n_layers_fetched: &mut usize,
+    opts: PullOptions<'_>,
 ) {
-    let style = indicatif::ProgressStyle::default_bar();
-    let pb = indicatif::ProgressBar::new(100);
-    pb.set_style(
-        style
-            .template("{prefix} {bytes} [{bar:20}] ({eta}) {msg}")
-            .unwrap(),
-    );
+    let PullOptions {
+        quiet,
+        mut progress,
+        bandwidth_limit,
+        authfile: _,
+        target: _,
+        prefer_source: _,
+    } = opts;
+    let mut bucket = bandwidth_limit.map(|r| TokenBucket::new(r, Instant::now()));
+    let pb = (!quiet).then(|| {
+        let style = indicatif::ProgressStyle::default_bar();
+        let pb = indicatif::ProgressBar::new(100);
+        pb.set_style(
+            style
+                .template("{prefix} {bytes} [{bar:20}] ({eta}) {msg}")
+                .unwrap(),
+        );
+        pb
+    });
+    let mut n_layers_fetched = 0usize;
     loop {
         tokio::select! {
             // Always handle layer changes first.
@@ -134,13 +401,37 @@ async fn handle_layer_progress_print(
             layer = layers.recv() => {
                 if let Some(l) = layer {
                     if l.is_starting() {
-                        pb.set_position(0);
+                        if let Some(pb) = pb.as_ref() {
+                            pb.set_position(0);
+                        }
+                        if let Some(p) = progress.as_deref_mut() {
+                            let desc = progress_layer(&l);
+                            p.layer_started(desc.digest(), desc.size().try_into().unwrap_or_default());
+                        }
                     } else {
-                        pb.finish();
-                        *n_layers_fetched += 1;
+                        if let Some(pb) = pb.as_ref() {
+                            pb.finish();
+                        }
+                        n_layers_fetched += 1;
+                        if let Some(bucket) = bucket.as_mut() {
+                            let size = progress_layer(&l).size().try_into().unwrap_or_default();
+                            let delay = bucket.consume(size, Instant::now());
+                            if !delay.is_zero() {
+                                tracing::debug!("Throttling for {delay:?}");
+                                tokio::time::sleep(delay).await;
+                            }
+                        }
+                        if let Some(p) = progress.as_deref_mut() {
+                            p.layer_completed();
+                            if n_layers_fetched == total_layers {
+                                p.phase(Phase::Writing);
+                            }
+                        }
+                    }
+                    if let Some(pb) = pb.as_ref() {
+                        pb.set_prefix(format!("[{}/{}]", n_layers_fetched, total_layers));
+                        pb.set_message(ostree_ext::cli::layer_progress_format(&l));
                     }
-                    pb.set_prefix(format!("[{}/{}]", *n_layers_fetched, total_layers));
-                    pb.set_message(ostree_ext::cli::layer_progress_format(&l));
                 } else {
                     // If the receiver is disconnected, then we're done
                     break
@@ -153,8 +444,13 @@ async fn handle_layer_progress_print(
                 }
                 let bytes = layer_bytes.borrow();
                 if let Some(bytes) = &*bytes {
-                    pb.set_length(bytes.total);
-                    pb.set_position(bytes.fetched);
+                    if let Some(pb) = pb.as_ref() {
+                        pb.set_length(bytes.total);
+                        pb.set_position(bytes.fetched);
+                    }
+                    if let Some(p) = progress.as_deref_mut() {
+                        p.layer_progress(bytes.fetched);
+                    }
                 }
             }
 
@@ -167,15 +463,136 @@ async fn handle_layer_progress_print(
 pub(crate) async fn pull(
     sysroot: &SysrootLock,
     imgref: &ImageReference,
-    quiet: bool,
+    mut opts: PullOptions<'_>,
 ) -> Result<Box<ImageState>> {
     let repo = &sysroot.repo();
     let ostree_imgref = &OstreeImageReference::from(imgref.clone());
-    let mut imp = new_importer(repo, ostree_imgref).await?;
-    let prep = match imp.prepare().await? {
+    if let Some(p) = opts.progress.as_deref_mut() {
+        p.phase(Phase::Verifying);
+    }
+    let auth = crate::auth::resolve(opts.authfile)?;
+    let target = opts.target.filter(|&t| t != imgref).cloned();
+    let sources = crate::registries::pull_endpoints(&ostree_imgref.imgref)?;
+    let state = if sources.len() > 1 {
+        let sources = crate::registries::order_sources(sources, opts.prefer_source);
+        pull_from_sources(repo, imgref, target.as_ref(), &sources, &auth, opts).await
+    } else {
+        let mut imp = new_importer(repo, ostree_imgref, &auth, None).await?;
+        if let Some(target) = target.as_ref() {
+            imp.set_target(&OstreeImageReference::from(target.clone()));
+        }
+        pull_with_importer(repo, imp, imgref, opts).await
+    };
+    let mut state = state.map_err(|e| auth.annotate_error(e))?;
+    if target.is_some() {
+        state.pull_info.source = Some(imgref.clone());
+    }
+    Ok(state)
+}
+
+/// Pull an image which has mirrors, trying each source in turn.  Once a
+/// source has served the manifest, the remaining sources are only asked for
+/// that manifest digest; as the layers fetched so far are kept, only the
+/// layers the previous source lacked are fetched from the next one.
+async fn pull_from_sources(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    target: Option<&ImageReference>,
+    sources: &[Source],
+    auth: &AuthSource,
+    mut opts: PullOptions<'_>,
+) -> Result<Box<ImageState>> {
+    let mut digest = None;
+    let mut errors = Vec::new();
+    for source in sources {
+        let kind = if source.mirror { "mirror" } else { "upstream" };
+        let attempt = match digest.as_deref() {
+            Some(digest) => ImageReference {
+                image: crate::registries::pin_digest(&imgref.image, digest),
+                ..imgref.clone()
+            },
+            None => imgref.clone(),
+        };
+        if !opts.quiet {
+            println!("Fetching {attempt:#} from {kind} {}", source.name);
+        }
+        // Pinned images are stored as if fetched from the original image
+        let target = target.or(digest.is_some().then_some(imgref));
+        let config = SourceConfig::new(&imgref.image, source)?;
+        let r = pull_from_source(
+            repo,
+            &attempt,
+            target,
+            auth,
+            &config,
+            &mut digest,
+            opts.reborrow(),
+        )
+        .await;
+        let e = match r {
+            Err(e) => format!("{e:#}"),
+            r => return r,
+        };
+        if !opts.quiet {
+            eprintln!("Failed to fetch from {kind} {}: {e}", source.name);
+        }
+        errors.push((source.name.as_str(), e));
+    }
+    let primary = sources.iter().find(|s| !s.mirror).unwrap_or(&sources[0]);
+    Err(crate::registries::aggregate_errors(&primary.name, errors))
+}
+
+/// Pull an image from one source; `digest` is set to the manifest digest once
+/// it has been fetched.
+async fn pull_from_source(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    target: Option<&ImageReference>,
+    auth: &AuthSource,
+    config: &SourceConfig,
+    digest: &mut Option<String>,
+    opts: PullOptions<'_>,
+) -> Result<Box<ImageState>> {
+    let ostree_imgref = &OstreeImageReference::from(imgref.clone());
+    let mut imp = new_importer(repo, ostree_imgref, auth, Some(config)).await?;
+    if let Some(target) = target {
+        imp.set_target(&OstreeImageReference::from(target.clone()));
+    }
+    let prep = imp.prepare().await?;
+    if let PrepareResult::Ready(p) = &prep {
+        digest.get_or_insert_with(|| p.manifest_digest.to_string());
+    }
+    import_prepared(repo, imp, prep, imgref, opts).await
+}
+
+/// Pull a container image using an already configured importer; the
+/// [`Phase::Verifying`] phase must already have been announced.
+pub(crate) async fn pull_with_importer(
+    repo: &ostree::Repo,
+    mut imp: ostree_container::store::ImageImporter,
+    imgref: &ImageReference,
+    opts: PullOptions<'_>,
+) -> Result<Box<ImageState>> {
+    let ostree_imgref = &OstreeImageReference::from(imgref.clone());
+    let prep = prepare(&mut imp, ostree_imgref).await?;
+    import_prepared(repo, imp, prep, imgref, opts).await
+}
+
+/// Import an image after fetching its manifest and configuration.
+async fn import_prepared(
+    repo: &ostree::Repo,
+    mut imp: ostree_container::store::ImageImporter,
+    prep: PrepareResult,
+    imgref: &ImageReference,
+    mut opts: PullOptions<'_>,
+) -> Result<Box<ImageState>> {
+    let ostree_imgref = &OstreeImageReference::from(imgref.clone());
+    let prep = match prep {
         PrepareResult::AlreadyPresent(c) => {
             println!("No changes in {imgref:#} => {}", c.manifest_digest);
-            return Ok(Box::new((*c).into()));
+            // The unpacked size is recorded when an image is first imported;
+            // status computes it for deployments lacking it.
+            return Ok(Box::new(ImageState::from(*c)));
         }
         PrepareResult::Ready(p) => p,
     };
@@ -184,32 +601,69 @@ pub(crate) async fn pull(
         ostree_ext::cli::print_deprecated_warning(warning).await;
     }
     ostree_ext::cli::print_layer_status(&prep);
-    let printer = (!quiet).then(|| {
+    // Layers fetched by a previous (possibly interrupted) pull are kept in the
+    // repository, so pulls are resumed at layer granularity.
+    let (to_fetch, stored): (Vec<_>, Vec<_>) = prep.all_layers().partition(|l| l.commit.is_none());
+    let n_to_fetch = to_fetch.len();
+    let stats = FetchStats::new(
+        to_fetch.iter().map(|l| l.size()),
+        stored.iter().map(|l| l.size()),
+    );
+    let quiet = opts.quiet;
+    // Reading from local files isn't subject to the limit for downloads
+    let local = crate::ocilayout::is_local(ostree_imgref.imgref.transport);
+    if local {
+        opts.bandwidth_limit = None;
+    }
+    if let Some(limit) = opts.bandwidth_limit.filter(|_| !opts.quiet) {
+        println!(
+            "Limiting download rate to {}/s",
+            glib::format_size(limit.bytes_per_sec())
+        );
+    }
+    if let Some(p) = opts.progress.as_deref_mut() {
+        p.start(
+            &imgref.image,
+            to_fetch.iter().map(|l| l.size()),
+            stored.iter().map(|l| l.size()),
+            opts.bandwidth_limit,
+        );
+        p.phase(Phase::Fetching);
+        if n_to_fetch == 0 {
+            p.phase(Phase::Writing);
+        }
+    }
+    let import = if !opts.quiet || opts.progress.is_some() || opts.bandwidth_limit.is_some() {
         let layer_progress = imp.request_progress();
         let layer_byte_progress = imp.request_layer_progress();
-        let total_layers = prep.layers_to_fetch().count();
-        let mut n_fetched = 0usize;
-        tokio::task::spawn(async move {
-            handle_layer_progress_print(
-                layer_progress,
-                layer_byte_progress,
-                total_layers,
-                &mut n_fetched,
-            )
-            .await
-        })
-    });
-    let import = imp.import(prep).await;
-    if let Some(printer) = printer {
-        let _ = printer.await;
-    }
+        let (import, ()) = tokio::join!(
+            imp.import(prep),
+            handle_layer_progress(layer_progress, layer_byte_progress, n_to_fetch, opts,)
+        );
+        import
+    } else {
+        imp.import(prep).await
+    };
     let import = import?;
     if let Some(msg) =
         ostree_container::store::image_filtered_content_warning(repo, &ostree_imgref.imgref)?
     {
         crate::journal::journal_print(libsystemd::logging::Priority::Notice, &msg);
     }
-    Ok(Box::new((*import).into()))
+    let mut state = ImageState::from(*import);
+    state.pull_info.unpacked_size = Some(commit_unpacked_size(repo, &state.ostree_commit)?);
+    state.pull_info.timestamp = Some(Utc::now());
+    state.pull_info.downloaded_size = Some(stats.downloaded);
+    if !quiet {
+        println!(
+            "{} {} of {} ({}% reused from locally stored layers)",
+            if local { "Imported" } else { "Fetched" },
+            glib::format_size(stats.downloaded),
+            glib::format_size(stats.total),
+            stats.percent_saved()
+        );
+    }
+    Ok(Box::new(state))
 }
 
 pub(crate) async fn cleanup(sysroot: &SysrootLock) -> Result<()> {
@@ -245,7 +699,7 @@ pub(crate) async fn cleanup(sysroot: &SysrootLock) -> Result<()> {
             }
         }
 
-        let pruned = ostree_container::deploy::prune(locked_sysroot).context("Pruning images")?;
+        let pruned = crate::image::prune(locked_sysroot)?;
         if !pruned.is_empty() {
             let size = glib::format_size(pruned.objsize);
             println!(
@@ -282,19 +736,48 @@ async fn deploy(
     let stateroot = Some(stateroot);
     // Copy to move into thread
     let cancellable = gio::Cancellable::NONE;
+    // The kernel arguments are inherited from the merge deployment, updated for
+    // the changes in the kargs.d files of the image (including the overrides in
+    // /etc and /run), with any machine-local changes applied on top.
+    let repo = &sysroot.repo();
+    let host = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let sys_arch = std::env::consts::ARCH;
+    let firmware = crate::kargs::Firmware::detect()?;
+    let commit = image.ostree_commit.as_str();
+    let kargsd = crate::kargs::get_kargs_in_commit(repo, commit, Some(host), sys_arch, firmware)?;
+    crate::kargs::record_kargs_d(origin, &kargsd);
+    let local_kargs = crate::kargs::LocalKargs::load(&host.open_dir("etc")?)?;
+    let kargs = merge_deployment
+        .map(|d| {
+            let merge = crate::kargs::kargs_of_deployment(d);
+            let old = crate::kargs::kargs_d_of_deployment(repo, d, sys_arch, firmware)?;
+            crate::kargs::kargs_for_new_deployment(&merge, &old, &kargsd, &local_kargs)
+        })
+        .transpose()?;
+    let kargs = kargs
+        .as_ref()
+        .map(|k| k.iter().map(|k| k.as_str()).collect::<Vec<_>>());
+    let opts = ostree::SysrootDeployTreeOpts {
+        override_kernel_argv: kargs.as_deref(),
+        ..Default::default()
+    };
     let _new_deployment = sysroot.stage_tree_with_options(
         stateroot,
         image.ostree_commit.as_str(),
         Some(origin),
         merge_deployment,
-        &Default::default(),
+        &opts,
         cancellable,
     )?;
     Ok(())
 }
 
 #[context("Generating origin")]
-fn origin_from_imageref(imgref: &ImageReference) -> Result<glib::KeyFile> {
+fn origin_from_imageref(
+    imgref: &ImageReference,
+    verification: &ImageVerification,
+    pull_info: &PullInfo,
+) -> Result<glib::KeyFile> {
     let origin = glib::KeyFile::new();
     let imgref = OstreeImageReference::from(imgref.clone());
     origin.set_string(
@@ -302,6 +785,16 @@ fn origin_from_imageref(imgref: &ImageReference) -> Result<glib::KeyFile> {
         ostree_container::deploy::ORIGIN_CONTAINER,
         imgref.to_string().as_str(),
     );
+    origin.set_string(
+        ORIGIN_BOOTC_GROUP,
+        ORIGIN_VERIFICATION_KEY,
+        &serde_json::to_string(verification)?,
+    );
+    origin.set_string(
+        ORIGIN_BOOTC_GROUP,
+        ORIGIN_PULL_KEY,
+        &serde_json::to_string(pull_info)?,
+    );
     Ok(origin)
 }
 
@@ -314,7 +807,11 @@ pub(crate) async fn stage(
     spec: &RequiredHostSpec<'_>,
 ) -> Result<()> {
     let merge_deployment = sysroot.merge_deployment(Some(stateroot));
-    let origin = origin_from_imageref(spec.image)?;
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    // The image which was actually fetched is what was verified
+    let fetched = image.pull_info.source.as_ref().unwrap_or(spec.image);
+    let verification = crate::sigpolicy::verification_in_root(root, fetched)?;
+    let origin = origin_from_imageref(spec.image, &verification, &image.pull_info)?;
     crate::deploy::deploy(
         sysroot,
         merge_deployment.as_ref(),
@@ -324,6 +821,7 @@ pub(crate) async fn stage(
     )
     .await?;
     crate::deploy::cleanup(sysroot).await?;
+    crate::usroverlay::discard(root)?;
     println!("Queued for next boot: {:#}", spec.image);
     if let Some(version) = image.version.as_deref() {
         println!("  Version: {version}");
@@ -333,6 +831,60 @@ pub(crate) async fn stage(
     Ok(())
 }
 
+/// Whether `bootc rollback` should ask for confirmation; this is only done
+/// interactively, so as to not break existing automation.
+pub(crate) fn rollback_needs_confirmation(assume_yes: bool, interactive: bool) -> bool {
+    interactive && !assume_yes
+}
+
+fn write_rollback_entry(out: &mut impl Write, entry: &crate::spec::BootEntry) -> Result<()> {
+    let Some(image) = entry.image.as_ref() else {
+        writeln!(out, "  (not a container image)")?;
+        return Ok(());
+    };
+    writeln!(out, "  Image: {}", image.image)?;
+    if let Some(version) = image.version.as_deref() {
+        writeln!(out, "  Version: {version}")?;
+    }
+    writeln!(out, "  Digest: {}", image.image_digest)?;
+    Ok(())
+}
+
+/// Print what `bootc rollback` will do: which deployment becomes the default,
+/// and which one is demoted.
+pub(crate) fn write_rollback_plan(out: &mut impl Write, host: &crate::spec::Host) -> Result<()> {
+    let status = &host.status;
+    let rollback = status
+        .rollback
+        .as_ref()
+        .ok_or_else(|| anyhow!("No rollback available"))?;
+    let booted = status
+        .booted
+        .as_ref()
+        .ok_or_else(|| anyhow!("Not booted into a deployment"))?;
+    // If a rollback is already queued, this reverts it
+    let (default, demoted) = if status.rollback_queued {
+        (booted, rollback)
+    } else {
+        (rollback, booted)
+    };
+    writeln!(out, "The following deployment will become the default:")?;
+    write_rollback_entry(out, default)?;
+    writeln!(out, "The following deployment will be demoted to rollback:")?;
+    write_rollback_entry(out, demoted)?;
+    if let Some(staged) = status.staged.as_ref() {
+        match staged.image.as_ref() {
+            Some(image) => writeln!(
+                out,
+                "The staged deployment will be discarded: {}",
+                image.image
+            )?,
+            None => writeln!(out, "The staged deployment will be discarded")?,
+        }
+    }
+    Ok(())
+}
+
 /// Implementation of rollback functionality
 pub(crate) async fn rollback(sysroot: &SysrootLock) -> Result<()> {
     const ROLLBACK_JOURNAL_ID: &str = "26f3b1eb24464d12aa5e7b544a6b5468";
@@ -416,7 +968,8 @@ fn find_newest_deployment_name(deploysdir: &Dir) -> Result<String> {
 // Implementation of `bootc switch --in-place`
 pub(crate) fn switch_origin_inplace(root: &Dir, imgref: &ImageReference) -> Result<String> {
     // First, just create the new origin file
-    let origin = origin_from_imageref(imgref)?;
+    let verification = crate::sigpolicy::verification_in_root(root, imgref)?;
+    let origin = origin_from_imageref(imgref, &verification, &Default::default())?;
     let serialized_origin = origin.to_data();
 
     // Now, we can't rely on being officially booted (e.g. with the `ostree=` karg)
@@ -476,7 +1029,11 @@ fn test_switch_inplace() -> Result<()> {
         signature: None,
     };
     {
-        let origin = origin_from_imageref(&orig_imgref)?;
+        let origin = origin_from_imageref(
+            &orig_imgref,
+            &ImageVerification::Unverified,
+            &Default::default(),
+        )?;
         deploydir.atomic_write(
             format!("{target_deployment}.origin"),
             origin.to_data().as_bytes(),
@@ -574,6 +1131,82 @@ pub(crate) fn fixup_etc_fstab(root: &Dir) -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_compressed_size() -> Result<()> {
+    let manifest: ostree_ext::oci_spec::image::ImageManifest = serde_json::from_str(
+        r#"{
+    "schemaVersion": 2,
+    "config": {"mediaType": "application/vnd.oci.image.config.v1+json", "size": 1000, "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000000"},
+    "layers": [
+        {"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "size": 2048, "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111"},
+        {"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "size": 512, "digest": "sha256:2222222222222222222222222222222222222222222222222222222222222222"}
+    ]
+}"#,
+    )?;
+    // The config isn't counted
+    assert_eq!(compressed_size(&manifest), 2560);
+    Ok(())
+}
+
+#[test]
+fn test_fetch_stats() -> Result<()> {
+    use ostree_ext::oci_spec::image::{Descriptor, ImageManifest};
+    let booted: ImageManifest =
+        serde_json::from_str(include_str!("fixtures/manifest-booted.json"))?;
+    let update: ImageManifest =
+        serde_json::from_str(include_str!("fixtures/manifest-update.json"))?;
+    // Pulling the update when the booted image's layers are stored
+    let (stored, to_fetch): (Vec<_>, Vec<_>) = update
+        .layers()
+        .iter()
+        .partition(|l| booted.layers().iter().any(|b| b.digest() == l.digest()));
+    let size = |l: &Descriptor| u64::try_from(l.size()).unwrap();
+    let stats = FetchStats::new(to_fetch.into_iter().map(size), stored.into_iter().map(size));
+    assert_eq!(stats.total, compressed_size(&update));
+    assert_eq!(stats.downloaded, 9000);
+    assert_eq!(stats.reused(), 3000);
+    assert_eq!(stats.percent_saved(), 25);
+
+    // Nothing is reused on an initial pull, and an empty image saves nothing
+    let initial = FetchStats::new(update.layers().iter().map(size), std::iter::empty());
+    assert_eq!(initial.percent_saved(), 0);
+    assert_eq!(FetchStats::default().percent_saved(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_is_transient_fetch_error() {
+    let transient = [
+        "Pulling: Creating importer: failed to invoke method OpenImage: pinging container registry quay.io: Get \"https://quay.io/v2/\": dial tcp 1.2.3.4:443: connect: connection refused",
+        "Pulling: reading manifest latest in quay.io/example/os: received unexpected HTTP status: 503 Service Unavailable",
+        "Pulling: reading blob sha256:0c8a: Get \"https://quay.io/v2/example/os/blobs/sha256:0c8a\": net/http: TLS handshake timeout",
+        "Pulling: reading manifest latest in docker.io/library/os: toomanyrequests: You have reached your pull rate limit",
+        "Fetching quay.io/example/os:latest failed from all sources:\n  mirror-a.internal:5000/example/os:latest: manifest unknown\n  quay.io/example/os:latest: dial tcp: lookup quay.io: Temporary failure in name resolution",
+    ];
+    for e in transient {
+        assert!(is_transient_fetch_error(&anyhow::anyhow!(e)), "{e}");
+    }
+    let permanent = [
+        "Pulling: reading manifest latest in quay.io/example/os: manifest unknown",
+        "Pulling: reading manifest latest in quay.io/example/os: unauthorized: access to the requested resource is not authorized",
+        "Pulling: Source image rejected: A signature was required, but no signature exists",
+        "Fetching quay.io/example/os:latest failed from all sources:\n  mirror-a.internal:5000/example/os:latest: manifest unknown",
+        "No image source specified",
+        "Upgrading: Acquiring sysroot lock: Resource temporarily unavailable",
+    ];
+    for e in permanent {
+        assert!(!is_transient_fetch_error(&anyhow::anyhow!(e)), "{e}");
+    }
+    // The cause is found through context
+    let e = anyhow::anyhow!("i/o timeout")
+        .context("Pulling")
+        .context("Upgrading");
+    assert!(is_transient_fetch_error(&e));
+    assert!(!is_transient_fetch_error(
+        &anyhow::Error::from(crate::cli::ExitCode(77)).context("Upgrading")
+    ));
+}
+
 #[test]
 fn test_fixup_etc_fstab_default() -> Result<()> {
     let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
@@ -627,3 +1260,45 @@ UUID=6907-17CA          /boot/efi               vfat    umask=0077,shortname=win
     assert_eq!(tempdir.read_to_string("etc/fstab")?, modified);
     Ok(())
 }
+
+#[test]
+fn test_rollback_plan() -> Result<()> {
+    use crate::spec::Host;
+
+    assert!(rollback_needs_confirmation(false, true));
+    assert!(!rollback_needs_confirmation(true, true));
+    assert!(!rollback_needs_confirmation(false, false));
+    assert!(!rollback_needs_confirmation(true, false));
+
+    let mut host: Host = serde_yaml::from_str(include_str!("fixtures/spec-rollback.yaml"))?;
+    let render = |host: &Host| -> Result<String> {
+        let mut out = Vec::new();
+        write_rollback_plan(&mut out, host)?;
+        Ok(String::from_utf8(out)?)
+    };
+    let expected = "\
+The following deployment will become the default:
+  Image: ostree-unverified-registry:quay.io/example/someimage:latest
+  Version: 20240501.0
+  Digest: sha256:36ff9ac6a3e5a2b6d2b5a7b2d9a0c3e1f8f4a1b0c7d6e5f4a3b2c1d0e9f8a7b6
+The following deployment will be demoted to rollback:
+  Image: ostree-unverified-registry:quay.io/example/someimage:latest
+  Version: 20240508.0
+  Digest: sha256:b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c
+The staged deployment will be discarded: ostree-unverified-registry:quay.io/example/someimage:latest
+";
+    assert_eq!(render(&host)?, expected);
+
+    // Reverting a queued rollback
+    host.status.rollback_queued = true;
+    host.status.staged = None;
+    let out = render(&host)?;
+    let (default, demoted) = out.split_once("demoted").unwrap();
+    assert!(default.contains("20240508.0"), "{out}");
+    assert!(demoted.contains("20240501.0"), "{out}");
+    assert!(!out.contains("staged"));
+
+    host.status.rollback = None;
+    assert!(render(&host).is_err());
+    Ok(())
+}
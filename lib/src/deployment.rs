@@ -0,0 +1,222 @@
+//! # Pinning deployments
+//!
+//! A pinned deployment is never garbage collected; neither by ostree when
+//! writing new deployments, nor its image by bootc when pruning images.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::ostree;
+use ostree_ext::sysroot::SysrootLock;
+
+/// The deployment targeted by `bootc deployment pin` and `unpin`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DeploymentTarget {
+    /// The index in the boot order, as shown by `ostree admin status`
+    Index(usize),
+    Booted,
+    Rollback,
+}
+
+impl FromStr for DeploymentTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "booted" => Ok(Self::Booted),
+            "rollback" => Ok(Self::Rollback),
+            o => o.parse().map(Self::Index).map_err(|_| {
+                anyhow!("Invalid deployment {o:?}; expected an index, booted or rollback")
+            }),
+        }
+    }
+}
+
+/// The state of a deployment relevant to pinning and garbage collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DeploymentState {
+    pub(crate) booted: bool,
+    pub(crate) staged: bool,
+    pub(crate) rollback: bool,
+    pub(crate) pinned: bool,
+    /// The container image, if this deployment was created from one
+    pub(crate) image: Option<ostree_container::ImageReference>,
+}
+
+impl DeploymentState {
+    /// The state of all deployments, in boot order.
+    pub(crate) fn all(sysroot: &SysrootLock) -> Result<Vec<Self>> {
+        let booted = sysroot.booted_deployment();
+        let is_booted = |d: &ostree::Deployment| booted.as_ref().is_some_and(|b| b.equal(d));
+        let deployments = sysroot.deployments();
+        // As in `get_status`, the rollback is the first other deployment of the booted stateroot
+        let stateroot = booted.as_ref().map(|b| b.osname());
+        let rollback = deployments
+            .iter()
+            .find(|d| Some(d.osname()) == stateroot && !d.is_staged() && !is_booted(d));
+        deployments
+            .iter()
+            .map(|d| {
+                let image = d
+                    .origin()
+                    .map(|o| crate::status::get_image_origin(&o))
+                    .transpose()?
+                    .flatten()
+                    .map(|i| i.imgref);
+                Ok(Self {
+                    booted: is_booted(d),
+                    staged: d.is_staged(),
+                    rollback: rollback.is_some_and(|r| r.equal(d)),
+                    pinned: d.is_pinned(),
+                    image,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Find the index of the targeted deployment.
+pub(crate) fn resolve(target: DeploymentTarget, deployments: &[DeploymentState]) -> Result<usize> {
+    let find = |f: fn(&DeploymentState) -> bool, name| {
+        deployments
+            .iter()
+            .position(f)
+            .ok_or_else(|| anyhow!("No {name} deployment"))
+    };
+    match target {
+        DeploymentTarget::Index(i) if i < deployments.len() => Ok(i),
+        DeploymentTarget::Index(i) => Err(anyhow!(
+            "Invalid deployment index {i}; there are {} deployments",
+            deployments.len()
+        )),
+        DeploymentTarget::Booted => find(|d| d.booted, "booted"),
+        DeploymentTarget::Rollback => find(|d| d.rollback, "rollback"),
+    }
+}
+
+/// Check whether pinning (or unpinning) the deployment at `index` is possible;
+/// returns `false` if it's already in the desired state.
+pub(crate) fn check_pin(deployments: &[DeploymentState], index: usize, pin: bool) -> Result<bool> {
+    let d = deployments
+        .get(index)
+        .ok_or_else(|| anyhow!("Invalid deployment index {index}"))?;
+    if d.pinned == pin {
+        return Ok(false);
+    }
+    if d.staged {
+        anyhow::bail!("Cannot change the pin of the staged deployment");
+    }
+    if !pin && deployments.iter().filter(|d| !d.staged).count() == 1 {
+        anyhow::bail!("Refusing to unpin the only remaining deployment");
+    }
+    Ok(true)
+}
+
+/// Implementation of `bootc deployment pin` and `unpin`.
+#[context("Setting deployment pin")]
+pub(crate) async fn set_pinned(target: DeploymentTarget, pin: bool) -> Result<()> {
+    crate::cli::prepare_for_write().await?;
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let states = DeploymentState::all(sysroot)?;
+    let index = resolve(target, &states)?;
+    let verb = if pin { "pinned" } else { "unpinned" };
+    if !check_pin(&states, index, pin)? {
+        println!("Deployment {index} is already {verb}");
+        return Ok(());
+    }
+    let deployments = sysroot.deployments();
+    let deployment: &ostree::Deployment = deployments.get(index).context("Deployment")?;
+    sysroot.deployment_set_pinned(deployment, pin)?;
+    let image = states[index]
+        .image
+        .as_ref()
+        .map(|i| format!(" ({i})"))
+        .unwrap_or_default();
+    println!("Deployment {index}{image} is now {verb}");
+    Ok(())
+}
+
+#[test]
+fn test_pin_deployments() -> Result<()> {
+    let image = |name: &str| {
+        Some(ostree_container::ImageReference {
+            transport: ostree_container::Transport::Registry,
+            name: format!("quay.io/example/os:{name}"),
+        })
+    };
+    let state = |image| DeploymentState {
+        booted: false,
+        staged: false,
+        rollback: false,
+        pinned: false,
+        image,
+    };
+    // A staged update, the booted deployment, the rollback and an older pinned one
+    let mut deployments = vec![
+        DeploymentState {
+            staged: true,
+            ..state(image("v4"))
+        },
+        DeploymentState {
+            booted: true,
+            ..state(image("v3"))
+        },
+        DeploymentState {
+            rollback: true,
+            ..state(image("v2"))
+        },
+        DeploymentState {
+            pinned: true,
+            ..state(image("v1"))
+        },
+    ];
+
+    for (s, expected) in [("booted", 1), ("rollback", 2), ("3", 3), ("0", 0)] {
+        assert_eq!(resolve(s.parse()?, &deployments)?, expected, "{s}");
+    }
+    assert!(resolve("4".parse()?, &deployments).is_err());
+    assert!("newest".parse::<DeploymentTarget>().is_err());
+
+    // Round-trip the pin on the rollback deployment
+    let rollback = resolve(DeploymentTarget::Rollback, &deployments)?;
+    assert!(check_pin(&deployments, rollback, true)?);
+    deployments[rollback].pinned = true;
+    assert!(!check_pin(&deployments, rollback, true)?);
+    assert!(check_pin(&deployments, rollback, false)?);
+    deployments[rollback].pinned = false;
+    assert!(!check_pin(&deployments, rollback, false)?);
+
+    // The staged deployment can't be pinned
+    assert!(check_pin(&deployments, 0, true).is_err());
+
+    // The images of all deployments are kept, including the pinned one
+    let stored = ["v1", "v2", "v3", "v4", "v0"].map(|v| crate::image::StoredImage {
+        imgref: image(v).unwrap(),
+        digest: String::new(),
+        created: None,
+        size: 0,
+    });
+    assert_eq!(
+        crate::image::unused(&stored, &deployments, &[], 0),
+        [&stored[4]]
+    );
+
+    // The only remaining deployment can't be unpinned
+    let mut single = vec![DeploymentState {
+        booted: true,
+        pinned: true,
+        ..state(image("v3"))
+    }];
+    assert!(check_pin(&single, 0, false).is_err());
+    single.insert(
+        0,
+        DeploymentState {
+            staged: true,
+            ..state(image("v4"))
+        },
+    );
+    assert!(check_pin(&single, 1, false).is_err());
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! # Downloaded updates
+//!
+//! `bootc upgrade --download-only` fetches an update without staging it, so
+//! that it can be staged later (e.g. in a maintenance window) without network
+//! access.  The image is stored like any other fetched image; the update is
+//! recorded in the sysroot alongside the ostree repository, and is ignored once
+//! it is staged or booted, or if the host was switched to a different image.
+
+use anyhow::Result;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::container::OstreeImageReference;
+use ostree_ext::ostree;
+use serde::{Deserialize, Serialize};
+
+use crate::deploy::{ImageState, PullInfo};
+use crate::spec::{DownloadedUpdate, ImageReference};
+
+/// The path to the downloaded update, relative to the sysroot.
+const DOWNLOADED_PATH: &str = "ostree/bootc/downloaded.json";
+
+/// The downloaded update as stored, along with how it was fetched, which is
+/// recorded in the origin once it is staged.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    #[serde(flatten)]
+    update: DownloadedUpdate,
+    #[serde(default)]
+    pull_info: PullInfo,
+}
+
+/// Record `image`, just fetched for `imgref`, as downloaded.
+#[context("Writing {DOWNLOADED_PATH}")]
+pub(crate) fn record(
+    sysroot_dir: &Dir,
+    imgref: &ImageReference,
+    image: &ImageState,
+    now: DateTime<Utc>,
+) -> Result<DownloadedUpdate> {
+    let update = DownloadedUpdate {
+        image: imgref.clone(),
+        image_digest: image.manifest_digest.clone(),
+        version: image.version.clone(),
+        downloaded: now,
+    };
+    let record = Record {
+        update,
+        pull_info: image.pull_info.clone(),
+    };
+    if let Some(parent) = std::path::Path::new(DOWNLOADED_PATH).parent() {
+        sysroot_dir.create_dir_all(parent)?;
+    }
+    sysroot_dir.atomic_write(DOWNLOADED_PATH, serde_json::to_vec_pretty(&record)?)?;
+    Ok(record.update)
+}
+
+/// Forget the downloaded update, e.g. as it was staged.
+#[context("Removing {DOWNLOADED_PATH}")]
+pub(crate) fn clear(sysroot_dir: &Dir) -> Result<()> {
+    sysroot_dir.remove_file_optional(DOWNLOADED_PATH)?;
+    Ok(())
+}
+
+#[context("Reading {DOWNLOADED_PATH}")]
+fn load_record(
+    sysroot_dir: &Dir,
+    imgref: &ImageReference,
+    deployed: &[&str],
+) -> Result<Option<Record>> {
+    let Some(f) = sysroot_dir.open_optional(DOWNLOADED_PATH)? else {
+        return Ok(None);
+    };
+    let record = match serde_json::from_reader::<_, Record>(std::io::BufReader::new(f)) {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::debug!("Ignoring invalid {DOWNLOADED_PATH}: {e}");
+            return Ok(None);
+        }
+    };
+    if &record.update.image != imgref {
+        tracing::debug!("Ignoring download of {}", record.update.image);
+        return Ok(None);
+    }
+    let is_deployed = deployed.contains(&record.update.image_digest.as_str());
+    Ok((!is_deployed).then_some(record))
+}
+
+/// Load the downloaded update of `imgref`, unless an image with one of the
+/// digests in `deployed` (i.e. the staged or booted one) was downloaded.
+pub(crate) fn load(
+    sysroot_dir: &Dir,
+    imgref: &ImageReference,
+    deployed: &[&str],
+) -> Result<Option<DownloadedUpdate>> {
+    Ok(load_record(sysroot_dir, imgref, deployed)?.map(|r| r.update))
+}
+
+/// Load the downloaded update of `imgref` (see [`load`]) from the repository,
+/// if it is still stored there.
+#[context("Loading downloaded update")]
+pub(crate) fn load_image(
+    sysroot_dir: &Dir,
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    deployed: &[&str],
+) -> Result<Option<ImageState>> {
+    let Some(record) = load_record(sysroot_dir, imgref, deployed)? else {
+        return Ok(None);
+    };
+    let stored = OstreeImageReference::from(imgref.clone()).imgref;
+    let state = ostree_container::store::query_image(repo, &stored)?
+        .filter(|s| s.manifest_digest == record.update.image_digest);
+    let Some(state) = state else {
+        tracing::debug!(
+            "Downloaded update {} is no longer stored",
+            record.update.image_digest
+        );
+        return Ok(None);
+    };
+    Ok(Some(ImageState {
+        pull_info: record.pull_info,
+        ..ImageState::from(*state)
+    }))
+}
+
+#[test]
+fn test_downloaded() -> Result<()> {
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    let imgref = ImageReference {
+        image: "quay.io/example/os:latest".into(),
+        transport: "registry".into(),
+        signature: None,
+    };
+    let booted = "sha256:booted";
+    assert_eq!(load(&td, &imgref, &[booted])?, None);
+
+    let image = ImageState {
+        manifest_digest: "sha256:new".into(),
+        version: Some("42.1".into()),
+        ostree_commit: "c".repeat(64),
+        pull_info: PullInfo {
+            compressed_size: Some(1234),
+            downloaded_size: Some(1000),
+            ..Default::default()
+        },
+    };
+    let now = "2024-05-02T10:11:12Z".parse()?;
+    let update = record(&td, &imgref, &image, now)?;
+    assert_eq!(
+        update,
+        DownloadedUpdate {
+            image: imgref.clone(),
+            image_digest: "sha256:new".into(),
+            version: Some("42.1".into()),
+            downloaded: now,
+        }
+    );
+    assert_eq!(load(&td, &imgref, &[booted])?.as_ref(), Some(&update));
+    let r = load_record(&td, &imgref, &[booted])?.unwrap();
+    assert_eq!(r.pull_info, image.pull_info);
+
+    // Once the update is staged, it's no longer pending
+    assert_eq!(load(&td, &imgref, &[booted, "sha256:new"])?, None);
+    // Switching to a different image invalidates it
+    let other = ImageReference {
+        image: "quay.io/example/other:latest".into(),
+        ..imgref.clone()
+    };
+    assert_eq!(load(&td, &other, &[booted])?, None);
+
+    clear(&td)?;
+    assert_eq!(load(&td, &imgref, &[booted])?, None);
+    clear(&td)?;
+    td.atomic_write(DOWNLOADED_PATH, "{")?;
+    assert_eq!(load(&td, &imgref, &[booted])?, None);
+    Ok(())
+}
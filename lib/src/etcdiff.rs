@@ -0,0 +1,493 @@
+//! # Local changes to /etc
+//!
+//! When deploying, ostree performs a three-way merge of `/etc`: whatever was
+//! changed locally relative to the defaults of the booted image (which are
+//! stored in `/usr/etc`) is carried over to the new deployment.  `bootc etc diff`
+//! shows these local changes, e.g. to help move configuration into the image.
+
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::{Dir, Metadata, MetadataExt, PermissionsExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::Serialize;
+
+use crate::cli::EtcDiffFormat;
+
+/// The defaults of the booted image
+const USR_ETC: &str = "usr/etc";
+const ETC: &str = "etc";
+/// Text files larger than this are only compared by hash
+const MAX_DIFF_SIZE: u64 = 128 * 1024;
+
+/// How a path differs from the image defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ChangeType {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum FileType {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+impl FileType {
+    pub(crate) fn of(meta: &Metadata) -> Self {
+        let ty = meta.file_type();
+        if ty.is_file() {
+            Self::File
+        } else if ty.is_dir() {
+            Self::Directory
+        } else if ty.is_symlink() {
+            Self::Symlink
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// What differs in a modified path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Difference {
+    /// E.g. a file was replaced by a symbolic link
+    Type,
+    Contents,
+    /// The target of a symbolic link
+    Target,
+    Mode,
+    Owner,
+}
+
+/// A locally changed path in `/etc`.  Added or removed directories are
+/// reported as a whole, not including their contents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EtcChange {
+    /// The absolute path, e.g. `/etc/hostname`
+    pub(crate) path: Utf8PathBuf,
+    pub(crate) change: ChangeType,
+    /// The current type, or the type in the image if removed
+    pub(crate) file_type: FileType,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) differences: Vec<Difference>,
+    /// The SHA-256 of the file in the image, if it is a regular file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) old_sha256: Option<String>,
+    /// The current SHA-256 of the file, if it is a regular file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) new_sha256: Option<String>,
+    /// A unified diff of modified text files, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) diff: Option<String>,
+}
+
+pub(crate) fn sha256(d: &Dir, name: &Utf8Path) -> Result<String> {
+    let mut f = d.open(name).with_context(|| format!("Opening {name}"))?;
+    let mut hasher = openssl::sha::Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = f
+            .read(&mut buf)
+            .with_context(|| format!("Reading {name}"))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finish()))
+}
+
+/// The target of a symbolic link; unlike [`Dir::read_link`], absolute targets
+/// are allowed.
+pub(crate) fn read_link(d: &Dir, name: &Utf8Path) -> Result<std::ffi::OsString> {
+    let target = rustix::fs::readlinkat(d, name.as_std_path(), Vec::new())
+        .with_context(|| format!("Reading link {name}"))?;
+    Ok(std::ffi::OsString::from_vec(target.into_bytes()))
+}
+
+/// The SHA-256 of a path, if it is a regular file.
+fn file_sha256(d: &Dir, name: &Utf8Path, meta: &Metadata) -> Result<Option<String>> {
+    meta.is_file().then(|| sha256(d, name)).transpose()
+}
+
+/// Whether a file is small enough to diff, and looks like text.
+#[context("Reading {name}")]
+fn is_small_text(d: &Dir, name: &Utf8Path, meta: &Metadata) -> Result<bool> {
+    if meta.len() > MAX_DIFF_SIZE {
+        return Ok(false);
+    }
+    let mut buf = Vec::new();
+    d.open(name)?.read_to_end(&mut buf)?;
+    Ok(!buf.contains(&0) && std::str::from_utf8(&buf).is_ok())
+}
+
+/// Generate a unified diff between the versions of `path` (relative to `/etc`).
+#[context("Comparing {path}")]
+fn unified_diff(root_path: &Utf8Path, path: &Utf8Path) -> Result<String> {
+    let old = root_path.join(USR_ETC).join(path);
+    let new = root_path.join(ETC).join(path);
+    let label = |base: &str| format!("/{base}/{path}");
+    let o = Command::new("diff")
+        .args(["-u", "--label", &label(USR_ETC), "--label", &label(ETC)])
+        .args([old.as_str(), new.as_str()])
+        .output()
+        .context("Failed to run diff")?;
+    // An exit status of 1 means the files differ
+    if !matches!(o.status.code(), Some(0 | 1)) {
+        anyhow::bail!(
+            "diff failed: {}: {}",
+            o.status,
+            String::from_utf8_lossy(&o.stderr)
+        );
+    }
+    Ok(String::from_utf8(o.stdout)?)
+}
+
+/// The differences between two versions of a path which both exist.
+fn compare(
+    old_dir: &Dir,
+    new_dir: &Dir,
+    name: &Utf8Path,
+    old: &Metadata,
+    new: &Metadata,
+) -> Result<Vec<Difference>> {
+    let mut differences = Vec::new();
+    let (old_type, new_type) = (FileType::of(old), FileType::of(new));
+    if old_type != new_type {
+        differences.push(Difference::Type);
+    } else {
+        match new_type {
+            FileType::File => {
+                if old.len() != new.len() || sha256(old_dir, name)? != sha256(new_dir, name)? {
+                    differences.push(Difference::Contents);
+                }
+            }
+            FileType::Symlink => {
+                if read_link(old_dir, name)? != read_link(new_dir, name)? {
+                    differences.push(Difference::Target);
+                }
+            }
+            FileType::Directory | FileType::Other => {}
+        }
+    }
+    // Symbolic links always have mode 0777; the type is compared above
+    let mode = |m: &Metadata| m.permissions().mode() & 0o7777;
+    if old_type == new_type && new_type != FileType::Symlink && mode(old) != mode(new) {
+        differences.push(Difference::Mode);
+    }
+    if (old.uid(), old.gid()) != (new.uid(), new.gid()) {
+        differences.push(Difference::Owner);
+    }
+    Ok(differences)
+}
+
+/// Recursively compare the directories `old_dir` and `new_dir`, which are
+/// at `prefix` relative to `/etc`.
+fn diff_dirs(
+    old_dir: &Dir,
+    new_dir: &Dir,
+    prefix: &Utf8Path,
+    out: &mut Vec<EtcChange>,
+) -> Result<()> {
+    let mut names = BTreeSet::new();
+    for d in [old_dir, new_dir] {
+        for ent in d.entries()? {
+            let name = ent?.file_name();
+            let name = name
+                .into_string()
+                .map_err(|n| anyhow::anyhow!("Invalid non-UTF8 filename: {n:?}"))?;
+            names.insert(name);
+        }
+    }
+    for name in names {
+        let name = Utf8Path::new(&name);
+        let path = prefix.join(name);
+        let old = old_dir.symlink_metadata_optional(name)?;
+        let new = new_dir.symlink_metadata_optional(name)?;
+        let change = |change, file_type| EtcChange {
+            path: Utf8Path::new("/etc").join(&path),
+            change,
+            file_type,
+            differences: Vec::new(),
+            old_sha256: None,
+            new_sha256: None,
+            diff: None,
+        };
+        match (old, new) {
+            (Some(old), None) => out.push(EtcChange {
+                old_sha256: file_sha256(old_dir, name, &old)?,
+                ..change(ChangeType::Removed, FileType::of(&old))
+            }),
+            (None, Some(new)) => out.push(EtcChange {
+                new_sha256: file_sha256(new_dir, name, &new)?,
+                ..change(ChangeType::Added, FileType::of(&new))
+            }),
+            (Some(old), Some(new)) => {
+                let differences = compare(old_dir, new_dir, name, &old, &new)?;
+                if !differences.is_empty() {
+                    out.push(EtcChange {
+                        differences,
+                        old_sha256: file_sha256(old_dir, name, &old)?,
+                        new_sha256: file_sha256(new_dir, name, &new)?,
+                        ..change(ChangeType::Modified, FileType::of(&new))
+                    });
+                }
+                if old.is_dir() && new.is_dir() {
+                    let old_child = old_dir.open_dir(name)?;
+                    let new_child = new_dir.open_dir(name)?;
+                    diff_dirs(&old_child, &new_child, &path, out)?;
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Compute the changes of `/etc` relative to `/usr/etc` in the root mounted at
+/// `root_path`; with `contents`, include unified diffs of modified text files.
+#[context("Comparing /etc with /usr/etc")]
+pub(crate) fn diff(root: &Dir, root_path: &Utf8Path, contents: bool) -> Result<Vec<EtcChange>> {
+    let old = root.open_dir_optional(USR_ETC)?.ok_or_else(|| {
+        anyhow::anyhow!("Missing /{USR_ETC}; this command must be run on a booted bootc system")
+    })?;
+    let new = root.open_dir(ETC)?;
+    let mut changes = Vec::new();
+    diff_dirs(&old, &new, Utf8Path::new(""), &mut changes)?;
+    if contents {
+        for change in changes.iter_mut() {
+            if !change.differences.contains(&Difference::Contents) {
+                continue;
+            }
+            // SAFETY: All paths are in /etc
+            let path = change.path.strip_prefix("/etc").unwrap();
+            let mut text = true;
+            for d in [&old, &new] {
+                text &= is_small_text(d, path, &d.symlink_metadata(path)?)?;
+            }
+            if text {
+                change.diff = Some(unified_diff(root_path, path)?);
+            }
+        }
+    }
+    Ok(changes)
+}
+
+fn write_changes(
+    out: &mut impl Write,
+    changes: &[EtcChange],
+    format: EtcDiffFormat,
+    contents: bool,
+) -> Result<()> {
+    if format == EtcDiffFormat::Json {
+        serde_json::to_writer_pretty(&mut *out, changes)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    if changes.is_empty() {
+        writeln!(out, "No local changes to /etc")?;
+        return Ok(());
+    }
+    for change in changes {
+        let c = match change.change {
+            ChangeType::Added => 'A',
+            ChangeType::Removed => 'D',
+            ChangeType::Modified => 'M',
+        };
+        let dir = if change.file_type == FileType::Directory {
+            "/"
+        } else {
+            ""
+        };
+        write!(out, "{c} {}{dir}", change.path)?;
+        if !change.differences.is_empty() {
+            let differences = change
+                .differences
+                .iter()
+                .map(|d| match d {
+                    Difference::Type => "type",
+                    Difference::Contents => "contents",
+                    Difference::Target => "target",
+                    Difference::Mode => "mode",
+                    Difference::Owner => "owner",
+                })
+                .collect::<Vec<_>>();
+            write!(out, " ({})", differences.join(", "))?;
+        }
+        writeln!(out)?;
+        if !contents || !change.differences.contains(&Difference::Contents) {
+            continue;
+        }
+        if let Some(diff) = change.diff.as_deref() {
+            write!(out, "{diff}")?;
+        } else if let (Some(old), Some(new)) = (&change.old_sha256, &change.new_sha256) {
+            writeln!(out, "  Binary or large file; sha256 {old} -> {new}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc etc diff`.
+pub(crate) fn run_diff(contents: bool, format: EtcDiffFormat) -> Result<()> {
+    let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let changes = diff(&root, Utf8Path::new("/"), contents)?;
+    let mut out = std::io::stdout().lock();
+    write_changes(&mut out, &changes, format, contents)
+}
+
+#[test]
+fn test_etc_diff() -> Result<()> {
+    use cap_std::fs::Permissions;
+
+    let tempdir = tempfile::tempdir()?;
+    let td_path = Utf8Path::from_path(tempdir.path()).unwrap();
+    let td = Dir::open_ambient_dir(td_path, cap_std::ambient_authority())?;
+    assert!(diff(&td, td_path, false).is_err());
+
+    td.create_dir_all("usr/etc/ssh")?;
+    td.create_dir_all("usr/etc/removed.d")?;
+    td.create_dir_all("etc/ssh")?;
+    for d in ["usr/etc", "etc"] {
+        let d = td.open_dir(d)?;
+        d.write("unchanged.conf", "foo\n")?;
+        d.write("ssh/sshd_config", "PermitRootLogin no\nPort 22\n")?;
+        d.write("mode.conf", "x\n")?;
+        d.symlink("unchanged.conf", "link")?;
+        d.write("binary", [0u8, 1, 2])?;
+    }
+    td.write("usr/etc/removed.d/foo.conf", "x\n")?;
+    td.write("usr/etc/motd", "hello\n")?;
+    td.write("etc/ssh/sshd_config", "PermitRootLogin no\nPort 2222\n")?;
+    td.write("etc/ssh/added.conf", "x\n")?;
+    td.set_permissions("etc/mode.conf", Permissions::from_mode(0o600))?;
+    td.remove_file("etc/link")?;
+    td.symlink("mode.conf", "etc/link")?;
+    td.write("etc/binary", [0u8, 1, 3])?;
+    td.create_dir("etc/motd")?;
+
+    let changes = diff(&td, td_path, true)?;
+    let summary = changes
+        .iter()
+        .map(|c| {
+            (
+                c.path.as_str(),
+                c.change,
+                c.file_type,
+                c.differences.as_slice(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        [
+            (
+                "/etc/binary",
+                ChangeType::Modified,
+                FileType::File,
+                &[Difference::Contents][..]
+            ),
+            (
+                "/etc/link",
+                ChangeType::Modified,
+                FileType::Symlink,
+                &[Difference::Target]
+            ),
+            (
+                "/etc/mode.conf",
+                ChangeType::Modified,
+                FileType::File,
+                &[Difference::Mode]
+            ),
+            (
+                "/etc/motd",
+                ChangeType::Modified,
+                FileType::Directory,
+                &[Difference::Type]
+            ),
+            (
+                "/etc/removed.d",
+                ChangeType::Removed,
+                FileType::Directory,
+                &[]
+            ),
+            (
+                "/etc/ssh/added.conf",
+                ChangeType::Added,
+                FileType::File,
+                &[]
+            ),
+            (
+                "/etc/ssh/sshd_config",
+                ChangeType::Modified,
+                FileType::File,
+                &[Difference::Contents]
+            ),
+        ]
+    );
+    // Binary files are only compared by hash
+    let binary = &changes[0];
+    assert!(binary.diff.is_none());
+    assert_eq!(
+        binary.old_sha256.as_deref(),
+        Some("ae4b3280e56e2faf83f414a6e3dabe9d5fbe18976544c05fed121accb85b53fc")
+    );
+    let added = &changes[5];
+    assert!(added.old_sha256.is_none());
+    assert!(added.new_sha256.is_some());
+    assert_eq!(
+        changes[6].diff.as_deref(),
+        Some(
+            "--- /usr/etc/ssh/sshd_config\n+++ /etc/ssh/sshd_config\n@@ -1,2 +1,2 @@\n PermitRootLogin no\n-Port 22\n+Port 2222\n"
+        )
+    );
+
+    let mut out = Vec::new();
+    write_changes(&mut out, &changes, EtcDiffFormat::HumanReadable, false)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        [
+            "M /etc/binary (contents)",
+            "M /etc/link (target)",
+            "M /etc/mode.conf (mode)",
+            "M /etc/motd/ (type)",
+            "D /etc/removed.d/",
+            "A /etc/ssh/added.conf",
+            "M /etc/ssh/sshd_config (contents)",
+            "",
+        ]
+        .join("\n")
+    );
+    let mut out = Vec::new();
+    write_changes(&mut out, &changes[..1], EtcDiffFormat::HumanReadable, true)?;
+    assert!(String::from_utf8(out)?.contains("Binary or large file; sha256 ae4b3280"));
+    let mut out = Vec::new();
+    write_changes(&mut out, &changes[4..6], EtcDiffFormat::Json, false)?;
+    let v: serde_json::Value = serde_json::from_slice(&out)?;
+    assert_eq!(
+        v,
+        serde_json::json!([
+            { "path": "/etc/removed.d", "change": "removed", "fileType": "directory" },
+            {
+                "path": "/etc/ssh/added.conf",
+                "change": "added",
+                "fileType": "file",
+                "newSha256": "73cb3858a687a8494ca3323053016282f3dad39d42cf62ca4e79dda2aac7d9ac",
+            },
+        ])
+    );
+    Ok(())
+}
@@ -0,0 +1,547 @@
+//! # Migrating to a transient /etc
+//!
+//! An image can make `/etc` transient (via `etc.transient` in ostree's
+//! `prepare-root.conf`), in which case local changes are discarded on reboot.
+//! Before doing so, `bootc etc migrate-to-transient` inventories the local
+//! changes (see [`crate::etcdiff`]) and classifies them by how they should be
+//! handled in the future.  With `--apply`, the changed files are staged into a
+//! directory-based configuration extension in `/var/lib/confexts`, which
+//! `systemd-confext` overlays onto `/etc` on boot.
+
+use std::io::Write;
+use std::os::fd::AsRawFd;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::{Dir, Metadata, MetadataExt, PermissionsExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::Serialize;
+
+use crate::etcdiff::{ChangeType, Difference, EtcChange, FileType};
+
+/// Where directory-based configuration extensions are found by systemd-confext
+const CONFEXTS: &str = "/var/lib/confexts";
+/// The name of the configuration extension holding the staged files
+const CONFEXT_NAME: &str = "bootc-local-etc";
+/// Regular users and groups may not exist in the image
+const FIRST_REGULAR_UID: u32 = 1000;
+const FIRST_REGULAR_GID: u32 = 1000;
+/// Paths (relative to `/etc`) which hold per-machine state rather than
+/// configuration; a trailing `*` matches any suffix.
+const MACHINE_LOCAL: &[&str] = &[
+    "adjtime",
+    "credstore*",
+    "crypttab",
+    "fstab",
+    "group",
+    "group-",
+    "gshadow",
+    "gshadow-",
+    "hostname",
+    "locale.conf",
+    "localtime",
+    "machine-id",
+    "machine-info",
+    "NetworkManager/system-connections/*",
+    "passwd",
+    "passwd-",
+    "shadow",
+    "shadow-",
+    "ssh/ssh_host_*",
+    "subgid",
+    "subgid-",
+    "subuid",
+    "subuid-",
+    "vconsole.conf",
+];
+
+/// How a local change should be handled with a transient `/etc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Classification {
+    /// The image being deployed already ships the change
+    AlreadyInImage,
+    /// Static configuration, which should be part of the image
+    MoveToImage,
+    /// Per-machine state, which needs to be provided at runtime (e.g. via
+    /// tmpfiles.d, credentials or a configuration extension)
+    MachineLocal,
+    /// Changes which can't be carried over automatically
+    Unclassified,
+}
+
+impl Classification {
+    fn description(&self) -> &'static str {
+        match self {
+            Self::AlreadyInImage => "Already in the image",
+            Self::MoveToImage => "Should move to the image",
+            Self::MachineLocal => "Needs a tmpfiles.d or confext strategy",
+            Self::Unclassified => "Unclassified",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReportEntry {
+    #[serde(flatten)]
+    pub(crate) change: EtcChange,
+    pub(crate) classification: Classification,
+    /// Why the change was classified this way
+    pub(crate) reason: String,
+}
+
+/// The report written by `bootc etc migrate-to-transient`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Report {
+    /// The image the changes were compared with
+    pub(crate) target_image: Option<String>,
+    pub(crate) entries: Vec<ReportEntry>,
+}
+
+fn is_machine_local(path: &Utf8Path) -> bool {
+    let Ok(path) = path.strip_prefix("/etc") else {
+        return false;
+    };
+    MACHINE_LOCAL.iter().any(|p| match p.strip_suffix('*') {
+        Some(prefix) => path.as_str().starts_with(prefix),
+        None => path == *p,
+    })
+}
+
+/// Whether `path` (relative to `/etc`) in `current` is identical in `image`,
+/// which is the `/usr/etc` of the image being deployed.
+fn matches_image(current: &Dir, image: &Dir, path: &Utf8Path) -> Result<bool> {
+    let Some(theirs) = image.symlink_metadata_optional(path)? else {
+        return Ok(false);
+    };
+    let Some(ours) = current.symlink_metadata_optional(path)? else {
+        return Ok(false);
+    };
+    let mode = |m: &Metadata| m.permissions().mode();
+    if mode(&ours) != mode(&theirs) || (ours.uid(), ours.gid()) != (theirs.uid(), theirs.gid()) {
+        return Ok(false);
+    }
+    let r = match FileType::of(&ours) {
+        FileType::File => {
+            ours.len() == theirs.len()
+                && crate::etcdiff::sha256(current, path)? == crate::etcdiff::sha256(image, path)?
+        }
+        FileType::Symlink => {
+            crate::etcdiff::read_link(current, path)? == crate::etcdiff::read_link(image, path)?
+        }
+        FileType::Directory => theirs.is_dir(),
+        FileType::Other => false,
+    };
+    Ok(r)
+}
+
+/// Classify a single change; `etc` is the current `/etc`, and `image` the
+/// `/usr/etc` of the image being deployed.
+fn classify(
+    etc: &Dir,
+    image: Option<&Dir>,
+    change: &EtcChange,
+) -> Result<(Classification, String)> {
+    // SAFETY: All paths are in /etc
+    let path = change.path.strip_prefix("/etc").unwrap();
+    if let Some(image) = image {
+        let in_image = if change.change == ChangeType::Removed {
+            image.symlink_metadata_optional(path)?.is_none()
+        } else {
+            matches_image(etc, image, path)?
+        };
+        if in_image {
+            return Ok((
+                Classification::AlreadyInImage,
+                "The image being deployed contains the same change".into(),
+            ));
+        }
+    }
+    if change.file_type == FileType::Other {
+        return Ok((
+            Classification::Unclassified,
+            "Special files can't be carried over".into(),
+        ));
+    }
+    if change.change != ChangeType::Removed {
+        let meta = etc.symlink_metadata(path)?;
+        if meta.uid() >= FIRST_REGULAR_UID || meta.gid() >= FIRST_REGULAR_GID {
+            return Ok((
+                Classification::Unclassified,
+                format!(
+                    "Owned by {}:{}, which may not exist in the image",
+                    meta.uid(),
+                    meta.gid()
+                ),
+            ));
+        }
+    }
+    if is_machine_local(&change.path) {
+        return Ok((
+            Classification::MachineLocal,
+            "Contains per-machine state".into(),
+        ));
+    }
+    let reason = match change.change {
+        ChangeType::Removed => "Remove it from the image",
+        ChangeType::Added => "Add it to the image",
+        ChangeType::Modified if change.differences == [Difference::Mode] => {
+            "Change its mode in the image"
+        }
+        ChangeType::Modified => "Change it in the image",
+    };
+    Ok((Classification::MoveToImage, reason.into()))
+}
+
+/// Classify all changes of `etc` relative to `usr_etc`.
+#[context("Classifying changes")]
+pub(crate) fn classify_changes(
+    etc: &Dir,
+    image: Option<&Dir>,
+    changes: Vec<EtcChange>,
+) -> Result<Vec<ReportEntry>> {
+    changes
+        .into_iter()
+        .map(|change| {
+            let (classification, reason) = classify(etc, image, &change)?;
+            Ok(ReportEntry {
+                change,
+                classification,
+                reason,
+            })
+        })
+        .collect()
+}
+
+/// Whether an entry is staged into the configuration extension.
+fn is_staged(e: &ReportEntry) -> bool {
+    matches!(
+        e.classification,
+        Classification::MoveToImage | Classification::MachineLocal
+    ) && e.change.change != ChangeType::Removed
+}
+
+/// Copy `path` (relative to `/etc`) from `etc` into `dest`, preserving its
+/// metadata.  Parent directories are created with the metadata of `etc`, and
+/// with `recurse` the contents of directories are copied too.
+fn stage_path(etc: &Dir, dest: &Dir, path: &Utf8Path, recurse: bool) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_str().is_empty()) {
+        if dest.symlink_metadata_optional(parent)?.is_none() {
+            stage_path(etc, dest, parent, false)?;
+        }
+    }
+    let meta = etc.symlink_metadata(path)?;
+    match FileType::of(&meta) {
+        FileType::File => {
+            etc.copy(path, dest, path)?;
+        }
+        FileType::Directory => {
+            if dest.symlink_metadata_optional(path)?.is_none() {
+                dest.create_dir(path)?;
+            }
+        }
+        FileType::Symlink => {
+            let target = crate::etcdiff::read_link(etc, path)?;
+            rustix::fs::symlinkat(target.as_os_str(), dest, path.as_std_path())?;
+        }
+        FileType::Other => anyhow::bail!("Unsupported file type"),
+    }
+    if !meta.is_symlink() {
+        dest.set_permissions(path, meta.permissions())?;
+    }
+    let fdpath = format!("/proc/self/fd/{}/{path}", dest.as_raw_fd());
+    std::os::unix::fs::lchown(fdpath, Some(meta.uid()), Some(meta.gid()))?;
+    if recurse && meta.is_dir() {
+        for ent in etc.read_dir(path)? {
+            let name = ent?.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+            stage_path(etc, dest, &path.join(name), true)?;
+        }
+    }
+    Ok(())
+}
+
+/// Stage the files of the entries into a new configuration extension in
+/// `confexts`, replacing any previous one.  Returns the number of paths staged.
+#[context("Staging local changes")]
+pub(crate) fn stage(etc: &Dir, confexts: &Dir, entries: &[ReportEntry]) -> Result<usize> {
+    let tmpname = format!("{CONFEXT_NAME}.tmp");
+    confexts.remove_all_optional(&tmpname)?;
+    confexts.create_dir(&tmpname)?;
+    let tmp = confexts.open_dir(&tmpname)?;
+    tmp.create_dir("etc")?;
+    let tmp_etc = tmp.open_dir("etc")?;
+    let mut n = 0;
+    for e in entries.iter().filter(|e| is_staged(e)) {
+        // SAFETY: All paths are in /etc
+        let path = e.change.path.strip_prefix("/etc").unwrap();
+        // Added directories aren't descended into when diffing
+        let recurse = e.change.change == ChangeType::Added;
+        stage_path(etc, &tmp_etc, path, recurse)
+            .with_context(|| format!("Staging {}", e.change.path))?;
+        n += 1;
+    }
+    // systemd-confext requires an extension-release file; `_any` matches every OS
+    tmp_etc.create_dir_all("extension-release.d")?;
+    tmp_etc.write(
+        format!("extension-release.d/extension-release.{CONFEXT_NAME}"),
+        "ID=_any\n",
+    )?;
+    confexts.remove_all_optional(CONFEXT_NAME)?;
+    confexts.rename(&tmpname, confexts, CONFEXT_NAME)?;
+    Ok(n)
+}
+
+fn write_summary(out: &mut impl Write, report: &Report) -> Result<()> {
+    if report.entries.is_empty() {
+        writeln!(out, "No local changes to /etc")?;
+        return Ok(());
+    }
+    let mut entries = report.entries.iter().collect::<Vec<_>>();
+    entries.sort_by_key(|e| e.classification);
+    let mut prev = None;
+    for e in entries {
+        if prev != Some(e.classification) {
+            if prev.is_some() {
+                writeln!(out)?;
+            }
+            writeln!(out, "{}:", e.classification.description())?;
+            prev = Some(e.classification);
+        }
+        writeln!(out, "  {}: {}", e.change.path, e.reason)?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc etc migrate-to-transient`.
+#[context("Migrating to a transient /etc")]
+pub(crate) async fn migrate_to_transient(
+    apply: bool,
+    force: bool,
+    report_path: Option<&Utf8Path>,
+) -> Result<()> {
+    if apply {
+        crate::cli::require_root()?;
+    }
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+    // Compare with the image which will be booted next
+    let target = sysroot
+        .staged_deployment()
+        .or_else(|| sysroot.booted_deployment());
+    let (target_image, image) = if let Some(target) = target.as_ref() {
+        let imgref = target
+            .origin()
+            .map(|o| crate::status::get_image_origin(&o))
+            .transpose()?
+            .flatten()
+            .map(|i| i.imgref.to_string());
+        let path = sysroot.deployment_dirpath(target);
+        let root = sysroot_dir.open_dir(path.as_str())?;
+        (imgref, root.open_dir_optional("usr/etc")?)
+    } else {
+        (None, None)
+    };
+
+    let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let changes = crate::etcdiff::diff(&root, Utf8Path::new("/"), false)?;
+    let etc = root.open_dir("etc")?;
+    let entries = classify_changes(&etc, image.as_ref(), changes)?;
+    let report = Report {
+        target_image,
+        entries,
+    };
+
+    let mut out = std::io::stdout().lock();
+    write_summary(&mut out, &report)?;
+    if let Some(path) = report_path {
+        let buf = serde_json::to_vec_pretty(&report)?;
+        std::fs::write(path, buf).with_context(|| format!("Writing {path}"))?;
+        writeln!(out, "Wrote report to {path}")?;
+    }
+    if !apply {
+        return Ok(());
+    }
+
+    let unclassified = report
+        .entries
+        .iter()
+        .filter(|e| e.classification == Classification::Unclassified)
+        .count();
+    if unclassified > 0 {
+        if !force {
+            anyhow::bail!("Refusing to apply with {unclassified} unclassified changes; use --force to skip them");
+        }
+        writeln!(out, "Skipping {unclassified} unclassified changes")?;
+    }
+    std::fs::create_dir_all(CONFEXTS).with_context(|| format!("Creating {CONFEXTS}"))?;
+    let confexts = Dir::open_ambient_dir(CONFEXTS, cap_std::ambient_authority())?;
+    let n = stage(&etc, &confexts, &report.entries)?;
+    let dest = Utf8PathBuf::from(CONFEXTS).join(CONFEXT_NAME);
+    writeln!(out, "Staged {n} paths into {dest}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn fixture() -> Result<(tempfile::TempDir, Dir)> {
+    let tempdir = tempfile::tempdir()?;
+    let td = Dir::open_ambient_dir(tempdir.path(), cap_std::ambient_authority())?;
+    for d in ["usr/etc", "etc", "image/usr/etc"] {
+        td.create_dir_all(format!("{d}/ssh"))?;
+        td.create_dir_all(format!("{d}/NetworkManager/system-connections"))?;
+        let d = td.open_dir(d)?;
+        d.write("hostname", "localhost\n")?;
+        d.write("motd", "hello\n")?;
+        d.write("ssh/sshd_config", "Port 22\n")?;
+    }
+    let etc = td.open_dir("etc")?;
+    // Shipped by the image being deployed
+    etc.write("motd", "welcome\n")?;
+    td.write("image/usr/etc/motd", "welcome\n")?;
+    etc.write("chrony.conf", "pool example.com\n")?;
+    td.write("image/usr/etc/chrony.conf", "pool example.com\n")?;
+    // Local configuration
+    etc.write("ssh/sshd_config", "Port 2222\n")?;
+    etc.create_dir("sudoers.d")?;
+    etc.write("sudoers.d/admins", "%admins ALL=(ALL) ALL\n")?;
+    etc.symlink("../usr/share/zoneinfo/UTC", "localtime")?;
+    // Machine state
+    etc.write("hostname", "myhost\n")?;
+    etc.write("ssh/ssh_host_ed25519_key", "secret\n")?;
+    etc.set_permissions(
+        "ssh/ssh_host_ed25519_key",
+        cap_std::fs::Permissions::from_mode(0o600),
+    )?;
+    etc.write("NetworkManager/system-connections/eth0.nmconnection", "x\n")?;
+    Ok((tempdir, td))
+}
+
+#[test]
+fn test_classify() -> Result<()> {
+    let (tempdir, td) = fixture()?;
+    let td_path = Utf8Path::from_path(tempdir.path()).unwrap();
+    let changes = crate::etcdiff::diff(&td, td_path, false)?;
+    let etc = td.open_dir("etc")?;
+    let image = td.open_dir("image/usr/etc")?;
+    let entries = classify_changes(&etc, Some(&image), changes.clone())?;
+    let summary = entries
+        .iter()
+        .map(|e| (e.change.path.as_str(), e.classification))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        [
+            (
+                "/etc/NetworkManager/system-connections/eth0.nmconnection",
+                Classification::MachineLocal
+            ),
+            ("/etc/chrony.conf", Classification::AlreadyInImage),
+            ("/etc/hostname", Classification::MachineLocal),
+            ("/etc/localtime", Classification::MachineLocal),
+            ("/etc/motd", Classification::AlreadyInImage),
+            (
+                "/etc/ssh/ssh_host_ed25519_key",
+                Classification::MachineLocal
+            ),
+            ("/etc/ssh/sshd_config", Classification::MoveToImage),
+            ("/etc/sudoers.d", Classification::MoveToImage),
+        ]
+    );
+    // Without the image being deployed, nothing can be in it
+    let entries = classify_changes(&etc, None, changes)?;
+    assert_eq!(entries[1].classification, Classification::MoveToImage);
+    assert_eq!(entries[1].reason, "Add it to the image");
+
+    // A removal which the image also has
+    td.remove_file("etc/motd")?;
+    td.remove_file("image/usr/etc/motd")?;
+    let changes = crate::etcdiff::diff(&td, td_path, false)?;
+    let entries = classify_changes(&etc, Some(&image), changes)?;
+    let motd = entries
+        .iter()
+        .find(|e| e.change.path == "/etc/motd")
+        .unwrap();
+    assert_eq!(motd.change.change, ChangeType::Removed);
+    assert_eq!(motd.classification, Classification::AlreadyInImage);
+    let entries = classify_changes(&etc, None, vec![motd.change.clone()])?;
+    assert_eq!(entries[0].classification, Classification::MoveToImage);
+    assert_eq!(entries[0].reason, "Remove it from the image");
+
+    let mut out = Vec::new();
+    let report = Report {
+        target_image: None,
+        entries,
+    };
+    write_summary(&mut out, &report)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "Should move to the image:\n  /etc/motd: Remove it from the image\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_classify_unclassified() -> Result<()> {
+    let (tempdir, td) = fixture()?;
+    let td_path = Utf8Path::from_path(tempdir.path()).unwrap();
+    let etc = td.open_dir("etc")?;
+    rustix::fs::mknodat(&etc, "initctl", rustix::fs::FileType::Fifo, 0o600.into(), 0)?;
+    let changes = crate::etcdiff::diff(&td, td_path, false)?;
+    let entries = classify_changes(&etc, None, changes)?;
+    let fifo = entries
+        .iter()
+        .find(|e| e.change.path == "/etc/initctl")
+        .unwrap();
+    assert_eq!(fifo.classification, Classification::Unclassified);
+    Ok(())
+}
+
+#[test]
+fn test_stage() -> Result<()> {
+    let (tempdir, td) = fixture()?;
+    let td_path = Utf8Path::from_path(tempdir.path()).unwrap();
+    let changes = crate::etcdiff::diff(&td, td_path, false)?;
+    let etc = td.open_dir("etc")?;
+    let image = td.open_dir("image/usr/etc")?;
+    let entries = classify_changes(&etc, Some(&image), changes)?;
+    td.create_dir("confexts")?;
+    let confexts = td.open_dir("confexts")?;
+    // A previous staging is replaced
+    confexts.create_dir_all("bootc-local-etc/etc")?;
+    confexts.write("bootc-local-etc/etc/stale", "")?;
+    assert_eq!(stage(&etc, &confexts, &entries)?, 6);
+    let staged = confexts.open_dir("bootc-local-etc/etc")?;
+    assert!(!staged.try_exists("stale")?);
+    assert!(!staged.try_exists("motd")?);
+    assert_eq!(staged.read_to_string("hostname")?, "myhost\n");
+    assert_eq!(staged.read_to_string("ssh/sshd_config")?, "Port 2222\n");
+    assert_eq!(
+        staged
+            .metadata("ssh/ssh_host_ed25519_key")?
+            .permissions()
+            .mode()
+            & 0o7777,
+        0o600
+    );
+    assert_eq!(
+        crate::etcdiff::read_link(&staged, "localtime".into())?.to_str(),
+        Some("../usr/share/zoneinfo/UTC")
+    );
+    // Added directories are staged with their contents
+    assert_eq!(
+        staged.read_to_string("sudoers.d/admins")?,
+        "%admins ALL=(ALL) ALL\n"
+    );
+    assert_eq!(
+        staged.read_to_string("extension-release.d/extension-release.bootc-local-etc")?,
+        "ID=_any\n"
+    );
+    assert!(!confexts.try_exists("bootc-local-etc.tmp")?);
+    Ok(())
+}
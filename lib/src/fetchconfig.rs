@@ -0,0 +1,424 @@
+//! # Configuration for fetching images
+//!
+//! This module handles the TOML configuration files for `bootc upgrade` and
+//! `bootc switch`, found in `bootc/fetch` (e.g. `/etc/bootc/fetch/10-limit.toml`).
+//! Despite the name, this also has `[switch]`, `[maintenance]` and `[updates]`
+//! sections.
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{Schema, SchemaObject, SubschemaValidation};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::autoupdate::Delay;
+use crate::maintenance::{Window, WindowTimezone};
+use crate::ratelimit::Rate;
+use crate::spec::{RebootStrategy, UpdateMode, UpdatePolicy};
+
+/// The toplevel config entry for fetch configs.
+#[derive(Debug, Deserialize, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(rename = "FetchConfiguration")]
+pub(crate) struct FetchConfigurationToplevel {
+    pub(crate) fetch: Option<FetchConfiguration>,
+    pub(crate) switch: Option<SwitchConfiguration>,
+    pub(crate) maintenance: Option<MaintenanceConfiguration>,
+    pub(crate) proxy: Option<ProxyConfiguration>,
+    pub(crate) updates: Option<UpdatesConfiguration>,
+}
+
+/// The serialized `[fetch]` section
+#[derive(Debug, Deserialize, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+#[schemars(rename = "FetchSection")]
+pub(crate) struct FetchConfiguration {
+    /// The maximum aggregate download rate of layers, e.g. `10MiB/s`
+    #[schemars(with = "Option<String>")]
+    pub(crate) bandwidth_limit: Option<Rate>,
+}
+
+/// The serialized `[switch]` section
+#[derive(Debug, Deserialize, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct SwitchConfiguration {
+    /// Pin the booted image when switching; see `bootc switch --retain`
+    pub(crate) retain: Option<bool>,
+}
+
+/// The serialized `[maintenance]` section
+#[derive(Debug, Deserialize, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct MaintenanceConfiguration {
+    /// The windows within which `--apply` may reboot, e.g. `Mon-Fri 22:00-02:00`; if
+    /// unset, it always may
+    #[schemars(with = "Option<Vec<String>>")]
+    pub(crate) windows: Option<Vec<Window>>,
+    /// The timezone of the windows; by default the system timezone
+    #[schemars(with = "Option<String>")]
+    pub(crate) timezone: Option<WindowTimezone>,
+}
+
+/// The serialized `[proxy]` section
+#[derive(Debug, Deserialize, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct ProxyConfiguration {
+    /// The HTTPS proxy used to fetch images; only the image fetcher uses it
+    pub(crate) https_proxy: Option<String>,
+    /// The registry hosts fetched via the proxy; if unset, all of them
+    pub(crate) registries: Option<Vec<String>>,
+}
+
+/// The serialized `[updates]` section
+#[derive(Debug, Deserialize, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct UpdatesConfiguration {
+    /// Whether `bootc upgrade --auto` does anything; by default it does
+    pub(crate) enabled: Option<bool>,
+    /// Whether to check for, stage or apply updates
+    pub(crate) mode: Option<UpdateMode>,
+    /// How to reboot into an update in the `apply` mode
+    #[schemars(schema_with = "reboot_strategy_schema")]
+    pub(crate) reboot: Option<RebootStrategy>,
+    /// The maximum random delay before checking for an update
+    #[schemars(with = "Option<String>")]
+    pub(crate) jitter: Option<Delay>,
+    /// How often a failed update is retried
+    pub(crate) retries: Option<u32>,
+    /// The delay before the first retry
+    #[schemars(with = "Option<String>")]
+    pub(crate) backoff: Option<Delay>,
+}
+
+/// The schema of [`UpdatesConfiguration::reboot`], which also accepts the
+/// kebab-case name documented for configuration files.
+fn reboot_strategy_schema(gen: &mut SchemaGenerator) -> Schema {
+    let alias = SchemaObject {
+        const_value: Some("soft-reboot-if-possible".into()),
+        ..Default::default()
+    };
+    SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![gen.subschema_for::<RebootStrategy>(), alias.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+/// The merged configuration.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Config {
+    pub(crate) fetch: FetchConfiguration,
+    pub(crate) switch: SwitchConfiguration,
+    pub(crate) maintenance: MaintenanceConfiguration,
+    pub(crate) proxy: ProxyConfiguration,
+    pub(crate) updates: UpdatesConfiguration,
+    /// The files which set any value of the `[updates]` section
+    pub(crate) updates_sources: Vec<String>,
+}
+
+fn merge_basic<T>(s: &mut Option<T>, o: Option<T>) {
+    if let Some(o) = o {
+        *s = Some(o);
+    }
+}
+
+impl Config {
+    /// Apply any values in other, overriding any existing values in `self`;
+    /// `name` is the file `other` was read from.
+    fn merge(&mut self, name: &str, other: FetchConfigurationToplevel) {
+        if let Some(fetch) = other.fetch {
+            merge_basic(&mut self.fetch.bandwidth_limit, fetch.bandwidth_limit);
+        }
+        if let Some(switch) = other.switch {
+            merge_basic(&mut self.switch.retain, switch.retain);
+        }
+        if let Some(maintenance) = other.maintenance {
+            merge_basic(&mut self.maintenance.windows, maintenance.windows);
+            merge_basic(&mut self.maintenance.timezone, maintenance.timezone);
+        }
+        if let Some(proxy) = other.proxy {
+            merge_basic(&mut self.proxy.https_proxy, proxy.https_proxy);
+            merge_basic(&mut self.proxy.registries, proxy.registries);
+        }
+        if let Some(updates) = other.updates.filter(|u| u != &Default::default()) {
+            self.updates_sources.push(name.to_owned());
+            merge_basic(&mut self.updates.enabled, updates.enabled);
+            merge_basic(&mut self.updates.mode, updates.mode);
+            merge_basic(&mut self.updates.reboot, updates.reboot);
+            merge_basic(&mut self.updates.jitter, updates.jitter);
+            merge_basic(&mut self.updates.retries, updates.retries);
+            merge_basic(&mut self.updates.backoff, updates.backoff);
+        }
+    }
+
+    /// The effective policy of `bootc upgrade --auto`.
+    pub(crate) fn update_policy(&self) -> UpdatePolicy {
+        let u = &self.updates;
+        UpdatePolicy {
+            enabled: u.enabled.unwrap_or(true),
+            mode: u.mode.unwrap_or_default(),
+            reboot: u.reboot.unwrap_or_default(),
+            jitter_seconds: u.jitter.map(|d| d.as_secs()).unwrap_or_default(),
+            retries: u.retries.unwrap_or_default(),
+            backoff_seconds: u
+                .backoff
+                .map(|d| d.as_secs())
+                .unwrap_or(crate::autoupdate::DEFAULT_BACKOFF.as_secs()),
+            sources: self.updates_sources.clone(),
+        }
+    }
+
+    /// Whether `bootc switch` should pin the booted image by default.
+    pub(crate) fn retain_on_switch(&self) -> bool {
+        self.switch.retain.unwrap_or_default()
+    }
+
+    /// The current or next maintenance window, if any are configured.
+    pub(crate) fn maintenance_window(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Option<crate::spec::MaintenanceWindow> {
+        let windows = self.maintenance.windows.as_deref().unwrap_or_default();
+        let tz = self.maintenance.timezone.unwrap_or_default();
+        crate::maintenance::current(windows, tz, now)
+    }
+
+    /// Whether images on `host` are fetched via the proxy.
+    fn use_proxy(&self, host: &str) -> bool {
+        let Some(registries) = self.proxy.registries.as_deref() else {
+            return true;
+        };
+        let hostname = host.rsplit_once(':').map_or(host, |(h, _)| h);
+        registries.iter().any(|r| {
+            r == host
+                || r == hostname
+                || r.strip_prefix("*.").is_some_and(|domain| {
+                    hostname
+                        .strip_suffix(domain)
+                        .is_some_and(|p| p.ends_with('.'))
+                })
+        })
+    }
+
+    /// The environment for the image fetcher when fetching from `sources`
+    /// (see [`crate::registries::pull_sources`]), if the proxy applies to
+    /// any of them.  Sources not using the proxy are added to `no_proxy`.
+    pub(crate) fn image_proxy_env(
+        &self,
+        sources: &[String],
+        no_proxy: Option<&str>,
+    ) -> Option<Vec<(&'static str, String)>> {
+        let proxy = self.proxy.https_proxy.as_deref()?;
+        let hosts = sources
+            .iter()
+            .filter_map(|s| s.split_once('/').map(|(h, _)| h));
+        let (proxied, direct): (Vec<_>, Vec<_>) = hosts.partition(|h| self.use_proxy(h));
+        if proxied.is_empty() {
+            return None;
+        }
+        let no_proxy = no_proxy
+            .into_iter()
+            .filter(|v| !v.is_empty())
+            .chain(direct)
+            .collect::<Vec<_>>();
+        let mut env = vec![("HTTPS_PROXY", proxy.to_string())];
+        if !no_proxy.is_empty() {
+            env.push(("NO_PROXY", no_proxy.join(",")));
+        }
+        Some(env)
+    }
+}
+
+fn parse_fragments<'a>(fragments: impl Iterator<Item = (&'a str, &'a str)>) -> Result<Config> {
+    let mut config = Config::default();
+    for (name, buf) in fragments {
+        let c: FetchConfigurationToplevel =
+            toml::from_str(buf).with_context(|| format!("Parsing {name}"))?;
+        config.merge(name, c);
+    }
+    Ok(config)
+}
+
+/// Load the fetch configuration, merging all found configuration files.
+#[context("Loading fetch configuration")]
+pub(crate) fn load_config() -> Result<Config> {
+    const SYSTEMD_CONVENTIONAL_BASES: &[&str] = &["/usr/lib", "/usr/local/lib", "/etc", "/run"];
+    let fragments = liboverdrop::scan(SYSTEMD_CONVENTIONAL_BASES, "bootc/fetch", &["toml"], true);
+    let fragments = fragments
+        .into_values()
+        .map(|path| {
+            let buf =
+                std::fs::read_to_string(&path).with_context(|| format!("Reading {path:?}"))?;
+            Ok((path.to_string_lossy().into_owned(), buf))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    parse_fragments(fragments.iter().map(|(p, b)| (p.as_str(), b.as_str())))
+}
+
+#[test]
+fn test_parse_fetch_config() {
+    let fragments = [
+        ("10-base.toml", "[fetch]\nbandwidth-limit = \"10MiB/s\"\n"),
+        ("20-empty.toml", ""),
+    ];
+    let c = parse_fragments(fragments.into_iter()).unwrap();
+    assert_eq!(c.fetch.bandwidth_limit, Some("10MiB/s".parse().unwrap()));
+    assert!(!c.retain_on_switch());
+    // Later fragments override
+    let fragments = [
+        fragments[0],
+        (
+            "30-override.toml",
+            "[fetch]\nbandwidth-limit = \"500Kbps\"\n",
+        ),
+    ];
+    let c = parse_fragments(fragments.into_iter()).unwrap();
+    assert_eq!(c.fetch.bandwidth_limit.unwrap().bytes_per_sec(), 62_500);
+
+    let fragments = [
+        fragments[0],
+        ("40-retain.toml", "[switch]\nretain = true\n"),
+    ];
+    let c = parse_fragments(fragments.into_iter()).unwrap();
+    assert!(c.retain_on_switch());
+    assert!(c.fetch.bandwidth_limit.is_some());
+
+    let fragments = [
+        (
+            "10-windows.toml",
+            "[maintenance]\nwindows = [\"Sat,Sun 02:00-05:00\"]\ntimezone = \"UTC\"\n",
+        ),
+        (
+            "20-windows.toml",
+            "[maintenance]\nwindows = [\"Mon-Fri 23:00-01:00\"]\n",
+        ),
+    ];
+    let c = parse_fragments(fragments.into_iter()).unwrap();
+    // Windows are replaced, not appended
+    assert_eq!(c.maintenance.windows.as_ref().unwrap().len(), 1);
+    let now = "2024-05-01T12:00:00Z".parse().unwrap();
+    let w = c.maintenance_window(now).unwrap();
+    assert_eq!(w.start.to_rfc3339(), "2024-05-01T23:00:00+00:00");
+    assert_eq!(Config::default().maintenance_window(now), None);
+
+    for invalid in [
+        "[proxy]\nhttp-proxy = \"http://proxy.example.com:3128\"\n",
+        "[fetch]\nbandwidth-limit = \"fast\"\n",
+        "[fetch]\nunknown = 1\n",
+        "[maintenance]\nwindows = [\"Mon 25:00-26:00\"]\n",
+        "[maintenance]\ntimezone = \"Mars/Olympus\"\n",
+        "[updates]\nmode = \"download\"\n",
+        "[updates]\njitter = \"soon\"\n",
+        "[updates]\nschedule = \"daily\"\n",
+    ] {
+        assert!(parse_fragments([("invalid.toml", invalid)].into_iter()).is_err());
+    }
+}
+
+#[test]
+fn test_parse_updates_config() {
+    let c = parse_fragments([("10-empty.toml", "[fetch]\n")].into_iter()).unwrap();
+    let policy = c.update_policy();
+    assert!(policy.enabled);
+    assert_eq!(policy.mode, UpdateMode::Apply);
+    assert_eq!(policy.reboot, RebootStrategy::Window);
+    assert_eq!(policy.backoff_seconds, 60);
+    assert!(policy.sources.is_empty());
+
+    let fragments = [
+        (
+            "/usr/lib/bootc/fetch/50-updates.toml",
+            "[updates]\nmode = \"stage\"\njitter = \"2h\"\nretries = 3\n",
+        ),
+        ("/etc/bootc/fetch/10-limit.toml", "[updates]\n"),
+        (
+            "/etc/bootc/fetch/60-updates.toml",
+            "[updates]\nmode = \"apply\"\nreboot = \"soft-reboot-if-possible\"\nbackoff = \"5m\"\n",
+        ),
+    ];
+    let c = parse_fragments(fragments.into_iter()).unwrap();
+    let policy = c.update_policy();
+    // Later files override earlier ones, per value
+    assert_eq!(policy.mode, UpdateMode::Apply);
+    assert_eq!(policy.reboot, RebootStrategy::SoftRebootIfPossible);
+    assert_eq!(policy.jitter_seconds, 7200);
+    assert_eq!(policy.retries, 3);
+    assert_eq!(policy.backoff_seconds, 300);
+    // Files with an empty section aren't sources
+    assert_eq!(
+        policy.sources,
+        [
+            "/usr/lib/bootc/fetch/50-updates.toml",
+            "/etc/bootc/fetch/60-updates.toml"
+        ]
+    );
+
+    let fragments = [
+        fragments[0],
+        (
+            "/etc/bootc/fetch/90-off.toml",
+            "[updates]\nenabled = false\n",
+        ),
+    ];
+    let policy = parse_fragments(fragments.into_iter())
+        .unwrap()
+        .update_policy();
+    assert!(!policy.enabled);
+    assert_eq!(policy.mode, UpdateMode::Stage);
+}
+
+#[test]
+fn test_image_proxy_env() {
+    let sources = [
+        "mirror.internal:5000/os:latest",
+        "quay.io/example/os:latest",
+    ]
+    .map(String::from);
+    assert_eq!(Config::default().image_proxy_env(&sources, None), None);
+
+    let fragments = [(
+        "10-proxy.toml",
+        "[proxy]\nhttps-proxy = \"http://proxy.example.com:3128\"\n",
+    )];
+    let c = parse_fragments(fragments.into_iter()).unwrap();
+    assert_eq!(
+        c.image_proxy_env(&sources, None).unwrap(),
+        [("HTTPS_PROXY", "http://proxy.example.com:3128".to_string())]
+    );
+
+    // Only some registries are fetched via the proxy
+    let fragments = [
+        fragments[0],
+        ("20-hosts.toml", "[proxy]\nregistries = [\"*.io\"]\n"),
+    ];
+    let c = parse_fragments(fragments.into_iter()).unwrap();
+    assert_eq!(
+        c.image_proxy_env(&sources, Some("localhost")).unwrap(),
+        [
+            ("HTTPS_PROXY", "http://proxy.example.com:3128".to_string()),
+            ("NO_PROXY", "localhost,mirror.internal:5000".to_string())
+        ]
+    );
+    assert_eq!(c.image_proxy_env(&sources[..1], None), None);
+    for (hosts, expected) in [
+        ("[\"mirror.internal\"]", true),
+        ("[\"mirror.internal:5000\"]", true),
+        ("[\"mirror.internal:443\"]", false),
+        ("[\"internal\"]", false),
+        ("[\"*.internal\"]", true),
+        ("[]", false),
+    ] {
+        let fragment = format!("[proxy]\nregistries = {hosts}\n");
+        let fragments = [fragments[0], ("20-hosts.toml", &fragment)];
+        let c = parse_fragments(fragments.into_iter()).unwrap();
+        assert_eq!(
+            c.image_proxy_env(&sources[..1], None).is_some(),
+            expected,
+            "{hosts}"
+        );
+    }
+}
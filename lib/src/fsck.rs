@@ -0,0 +1,608 @@
+//! # Verifying the integrity of deployments
+//!
+//! `bootc fsck` checks that what is on disk still matches what was deployed:
+//! the objects of each deployment's commit, its composefs image, its origin,
+//! the container image it was created from, and its bootloader entry.  With
+//! `--fast`, only metadata is checked, i.e. the content of files is not read.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::glib;
+use ostree_ext::ostree;
+use serde::Serialize;
+
+use crate::cli::FsckFormat;
+use crate::deployment::DeploymentState;
+use crate::task::Task;
+
+/// The composefs image of a deployment
+const COMPOSEFS_IMAGE: &str = ".ostree.cfs";
+/// The commit metadata key holding the fs-verity digest of the composefs image
+const COMPOSEFS_DIGEST_KEY: &str = "ostree.composefs.digest.v0";
+/// At most this many corrupted objects are listed per deployment
+const MAX_REPORTED_OBJECTS: usize = 10;
+
+/// The outcome of a single check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "status", content = "message")]
+pub(crate) enum Outcome {
+    Ok,
+    /// The check doesn't apply, or couldn't be performed
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Check {
+    pub(crate) name: &'static str,
+    #[serde(flatten)]
+    pub(crate) outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeploymentReport {
+    /// The index of the deployment in boot order
+    pub(crate) index: usize,
+    /// E.g. `booted`; empty for other deployments
+    pub(crate) role: &'static str,
+    pub(crate) checksum: String,
+    pub(crate) checks: Vec<Check>,
+}
+
+impl DeploymentReport {
+    fn ok(&self) -> bool {
+        !self
+            .checks
+            .iter()
+            .any(|c| matches!(c.outcome, Outcome::Failed(_)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Report {
+    /// Whether only metadata was checked
+    pub(crate) fast: bool,
+    pub(crate) ok: bool,
+    pub(crate) deployments: Vec<DeploymentReport>,
+}
+
+/// Collect the outcome of a check which fails with an error.
+fn outcome(r: Result<Outcome>) -> Outcome {
+    r.unwrap_or_else(|e| Outcome::Failed(format!("{e:#}")))
+}
+
+/// The path of a metadata object in a repository, or `None` for file objects.
+fn metadata_object_path(checksum: &str, objtype: ostree::ObjectType) -> Option<Utf8PathBuf> {
+    let ext = match objtype {
+        ostree::ObjectType::Commit => "commit",
+        ostree::ObjectType::DirTree => "dirtree",
+        ostree::ObjectType::DirMeta => "dirmeta",
+        _ => return None,
+    };
+    let (prefix, rest) = checksum.split_at(checksum.len().min(2));
+    Some(format!("objects/{prefix}/{rest}.{ext}").into())
+}
+
+/// Verify a metadata object, which is named by the SHA-256 of its contents.
+/// Returns a description of the problem, if any.
+fn verify_metadata_object(
+    repo: &Dir,
+    checksum: &str,
+    objtype: ostree::ObjectType,
+) -> Result<Option<String>> {
+    let path = metadata_object_path(checksum, objtype).expect("metadata object");
+    if !repo.try_exists(&path)? {
+        return Ok(Some(format!("{path}: missing")));
+    }
+    let actual = crate::etcdiff::sha256(repo, &path)?;
+    Ok((actual != checksum).then(|| format!("{path}: checksum mismatch (actual {actual})")))
+}
+
+/// Verify the objects of `commit`; in fast mode, only the commit object.
+fn check_commit(repo: &ostree::Repo, repo_dir: &Dir, commit: &str, fast: bool) -> Result<Outcome> {
+    if let Some(problem) = verify_metadata_object(repo_dir, commit, ostree::ObjectType::Commit)? {
+        return Ok(Outcome::Failed(problem));
+    }
+    if fast {
+        return Ok(Outcome::Ok);
+    }
+    let cancellable = ostree::gio::Cancellable::NONE;
+    let mut objects = repo
+        .traverse_commit(commit, 0, cancellable)
+        .context("Listing objects")?
+        .into_iter()
+        .collect::<Vec<_>>();
+    objects.sort_by(|a, b| a.checksum().cmp(b.checksum()));
+    let mut problems = Vec::new();
+    for obj in objects.iter() {
+        let (checksum, objtype) = (obj.checksum(), obj.object_type());
+        let problem = if metadata_object_path(checksum, objtype).is_some() {
+            verify_metadata_object(repo_dir, checksum, objtype)?
+        } else {
+            repo.fsck_object(objtype, checksum, cancellable)
+                .err()
+                .map(|e| format!("{checksum}.file: {e}"))
+        };
+        problems.extend(problem);
+    }
+    Ok(describe_problems(&problems, objects.len()))
+}
+
+fn describe_problems(problems: &[String], total: usize) -> Outcome {
+    if problems.is_empty() {
+        return Outcome::Ok;
+    }
+    let mut msg = format!("{} of {total} objects are corrupted", problems.len());
+    for p in problems.iter().take(MAX_REPORTED_OBJECTS) {
+        msg.push('\n');
+        msg.push_str(p);
+    }
+    Outcome::Failed(msg)
+}
+
+/// Parse the digest in the output of `fsverity measure`, e.g. `sha256:<hex> <path>`.
+fn parse_fsverity_measure(output: &str) -> Result<&str> {
+    output
+        .split_ascii_whitespace()
+        .next()
+        .and_then(|d| d.strip_prefix("sha256:"))
+        .ok_or_else(|| anyhow::anyhow!("Unexpected output of fsverity measure: {output:?}"))
+}
+
+/// Verify the fs-verity digest of the composefs image of the deployment, which
+/// also covers the content of all files via their own fs-verity digests.
+fn check_composefs(
+    repo: &ostree::Repo,
+    commit: &str,
+    deployment: &Dir,
+    deployment_path: &Utf8Path,
+) -> Result<Outcome> {
+    if !deployment.try_exists(COMPOSEFS_IMAGE)? {
+        return Ok(Outcome::Skipped("composefs is not enabled".into()));
+    }
+    let commitv = repo.load_commit(commit)?.0;
+    let commitmeta = glib::VariantDict::new(Some(&commitv.child_value(0)));
+    let Some(expected) = commitmeta.lookup_value(COMPOSEFS_DIGEST_KEY, None) else {
+        return Ok(Outcome::Skipped(
+            "the commit has no composefs digest".into(),
+        ));
+    };
+    let expected = hex::encode(expected.data_as_bytes());
+    let path = Utf8Path::new("/")
+        .join(deployment_path)
+        .join(COMPOSEFS_IMAGE);
+    // This fails if fs-verity isn't enabled on the image
+    let output = match Task::new_quiet("fsverity")
+        .args(["measure", path.as_str()])
+        .read()
+    {
+        Ok(o) => o,
+        Err(e) => return Ok(Outcome::Skipped(format!("measuring {path}: {e:#}"))),
+    };
+    let actual = parse_fsverity_measure(&output)?;
+    if actual != expected {
+        return Ok(Outcome::Failed(format!(
+            "{path}: fs-verity digest {actual}, expected {expected}"
+        )));
+    }
+    Ok(Outcome::Ok)
+}
+
+/// Check the origin of a deployment and, for deployments of container images,
+/// that the image is still present in storage.
+fn check_origin(
+    repo: &ostree::Repo,
+    deployment: &ostree::Deployment,
+) -> Result<(Outcome, Outcome)> {
+    let Some(origin) = deployment.origin() else {
+        return Ok((
+            Outcome::Failed("missing origin".into()),
+            Outcome::Skipped("no origin".into()),
+        ));
+    };
+    let image = match crate::status::get_image_origin(&origin) {
+        Ok(Some(image)) => image,
+        Ok(None) => {
+            return Ok((
+                Outcome::Ok,
+                Outcome::Skipped("not deployed from a container image".into()),
+            ))
+        }
+        Err(e) => {
+            return Ok((
+                Outcome::Failed(format!("{e:#}")),
+                Outcome::Skipped("invalid origin".into()),
+            ))
+        }
+    };
+    let image = match ostree_container::store::query_image_commit(repo, &deployment.csum()) {
+        Ok(_) => Outcome::Ok,
+        Err(e) => Outcome::Failed(format!("{}: {e:#}", image.imgref)),
+    };
+    Ok((Outcome::Ok, image))
+}
+
+/// Check that the bootloader entry of the deployment at `deployment_path` (relative
+/// to `sysroot`) refers to it, and that its kernel and initramfs exist.
+fn check_bootloader(
+    sysroot: &Dir,
+    deployment_path: &Utf8Path,
+    osname: &str,
+    bootcsum: &str,
+    entry: &[(&str, Option<&str>)],
+) -> Result<Outcome> {
+    let get = |k: &str| entry.iter().find(|e| e.0 == k).and_then(|e| e.1);
+    let mut problems = Vec::new();
+    let target = get("options").and_then(|o| {
+        o.split_ascii_whitespace()
+            .find_map(|a| a.strip_prefix("ostree="))
+    });
+    match target {
+        None => problems.push("missing ostree= kernel argument".to_owned()),
+        Some(target) => {
+            // The target is /ostree/boot.<N>/<osname>/<bootcsum>/<serial>
+            let parts = target.rsplit('/').collect::<Vec<_>>();
+            if parts.get(1) != Some(&bootcsum) || parts.get(2) != Some(&osname) {
+                problems.push(format!("ostree={target} doesn't match {osname}/{bootcsum}"));
+            }
+            // It is a chain of symbolic links which should lead to the deployment
+            let inode = |d: &Dir| -> Result<(u64, u64)> {
+                let m = d.dir_metadata()?;
+                Ok((m.dev(), m.ino()))
+            };
+            let expected = inode(&sysroot.open_dir(deployment_path)?)?;
+            match sysroot.open_dir(target.trim_start_matches('/')) {
+                Ok(d) if inode(&d)? == expected => {}
+                Ok(_) => problems.push(format!("ostree={target} refers to another deployment")),
+                Err(e) => problems.push(format!("ostree={target}: {e}")),
+            }
+        }
+    }
+    let boot = sysroot.open_dir_optional("boot")?;
+    for k in ["linux", "initrd"] {
+        let Some(path) = get(k) else {
+            problems.push(format!("missing {k}"));
+            continue;
+        };
+        // Relative to the boot partition, or the sysroot if /boot isn't separate
+        let relpath = path.trim_start_matches('/');
+        let exists = match boot.as_ref() {
+            Some(boot) if boot.try_exists(relpath)? => true,
+            _ => sysroot.try_exists(relpath)?,
+        };
+        if !exists {
+            problems.push(format!("{k} {path}: missing"));
+        }
+    }
+    Ok(if problems.is_empty() {
+        Outcome::Ok
+    } else {
+        Outcome::Failed(problems.join("; "))
+    })
+}
+
+/// Check a single deployment.
+fn check_deployment(
+    sysroot: &ostree::Sysroot,
+    sysroot_dir: &Dir,
+    repo_dir: &Dir,
+    deployment: &ostree::Deployment,
+    state: &DeploymentState,
+    index: usize,
+    fast: bool,
+) -> Result<DeploymentReport> {
+    let repo = &sysroot.repo();
+    let commit = deployment.csum().to_string();
+    let deployment_path = Utf8PathBuf::from(sysroot.deployment_dirpath(deployment).as_str());
+    let mut checks = Vec::new();
+    let mut check = |name, outcome| checks.push(Check { name, outcome });
+
+    check(
+        "commit",
+        outcome(check_commit(repo, repo_dir, &commit, fast)),
+    );
+    let composefs = match sysroot_dir.open_dir_optional(&deployment_path)? {
+        None => Outcome::Failed(format!("missing deployment directory {deployment_path}")),
+        Some(_) if fast => Outcome::Skipped("fast mode".into()),
+        Some(d) => outcome(check_composefs(repo, &commit, &d, &deployment_path)),
+    };
+    check("composefs", composefs);
+    let (origin, image) = check_origin(repo, deployment).unwrap_or_else(|e| {
+        (
+            Outcome::Failed(format!("{e:#}")),
+            Outcome::Skipped("invalid origin".into()),
+        )
+    });
+    check("origin", origin);
+    check("image", image);
+    let bootloader = if state.staged {
+        Outcome::Skipped("written when the deployment is finalized".into())
+    } else if let Some(bootconfig) = deployment.bootconfig() {
+        let entry = ["options", "linux", "initrd"].map(|k| (k, bootconfig.get(k)));
+        let entry = entry
+            .iter()
+            .map(|(k, v)| (*k, v.as_deref()))
+            .collect::<Vec<_>>();
+        outcome(check_bootloader(
+            sysroot_dir,
+            &deployment_path,
+            deployment.osname().as_str(),
+            deployment.bootcsum().as_str(),
+            &entry,
+        ))
+    } else {
+        Outcome::Failed("missing bootloader entry".into())
+    };
+    check("bootloader", bootloader);
+
+    let role = match state {
+        s if s.staged => "staged",
+        s if s.booted => "booted",
+        s if s.rollback => "rollback",
+        _ => "",
+    };
+    Ok(DeploymentReport {
+        index,
+        role,
+        checksum: commit,
+        checks,
+    })
+}
+
+fn write_human(out: &mut impl Write, report: &Report) -> Result<()> {
+    for d in report.deployments.iter() {
+        let role = if d.role.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", d.role)
+        };
+        let status = if d.ok() { "ok" } else { "FAILED" };
+        writeln!(out, "Deployment {}{role} {}: {status}", d.index, d.checksum)?;
+        for c in d.checks.iter() {
+            match &c.outcome {
+                Outcome::Ok => writeln!(out, "  {}: ok", c.name)?,
+                Outcome::Skipped(why) => writeln!(out, "  {}: skipped ({why})", c.name)?,
+                Outcome::Failed(msg) => {
+                    let mut lines = msg.lines();
+                    writeln!(
+                        out,
+                        "  {}: FAILED: {}",
+                        c.name,
+                        lines.next().unwrap_or_default()
+                    )?;
+                    for l in lines {
+                        writeln!(out, "    {l}")?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc fsck`.
+#[context("Verifying deployments")]
+pub(crate) async fn fsck(fast: bool, format: FsckFormat) -> Result<()> {
+    crate::cli::require_root()?;
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+    let repo_dir = sysroot_dir
+        .open_dir("ostree/repo")
+        .context("Opening repository")?;
+    let states = DeploymentState::all(sysroot)?;
+    let deployments = sysroot
+        .deployments()
+        .iter()
+        .zip(states.iter())
+        .enumerate()
+        .map(|(i, (d, s))| check_deployment(sysroot, &sysroot_dir, &repo_dir, d, s, i, fast))
+        .collect::<Result<Vec<_>>>()?;
+    let report = Report {
+        fast,
+        ok: deployments.iter().all(DeploymentReport::ok),
+        deployments,
+    };
+    let mut out = std::io::stdout().lock();
+    match format {
+        FsckFormat::HumanReadable => write_human(&mut out, &report)?,
+        FsckFormat::Json => {
+            serde_json::to_writer(&mut out, &report)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    let failed = report.deployments.iter().filter(|d| !d.ok()).count();
+    if failed > 0 {
+        anyhow::bail!(
+            "{failed} of {} deployments failed verification",
+            report.deployments.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn write_object(repo: &Dir, contents: &str, objtype: ostree::ObjectType) -> Result<String> {
+    let checksum = hex::encode(openssl::sha::sha256(contents.as_bytes()));
+    let path = metadata_object_path(&checksum, objtype).unwrap();
+    repo.create_dir_all(path.parent().unwrap())?;
+    repo.write(&path, contents)?;
+    Ok(checksum)
+}
+
+#[test]
+fn test_verify_metadata_object() -> Result<()> {
+    use ostree::ObjectType;
+
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    let commit = write_object(&td, "commit", ObjectType::Commit)?;
+    let dirtree = write_object(&td, "dirtree", ObjectType::DirTree)?;
+    let dirmeta = write_object(&td, "dirmeta", ObjectType::DirMeta)?;
+    assert_eq!(
+        metadata_object_path(&dirtree, ObjectType::DirTree).unwrap(),
+        format!("objects/{}/{}.dirtree", &dirtree[..2], &dirtree[2..])
+    );
+    assert!(metadata_object_path(&dirtree, ObjectType::File).is_none());
+    for (checksum, objtype) in [
+        (&commit, ObjectType::Commit),
+        (&dirtree, ObjectType::DirTree),
+        (&dirmeta, ObjectType::DirMeta),
+    ] {
+        assert_eq!(verify_metadata_object(&td, checksum, objtype)?, None);
+    }
+
+    // An intentionally corrupted object
+    let path = metadata_object_path(&dirtree, ObjectType::DirTree).unwrap();
+    td.write(&path, "corrupted")?;
+    let problem = verify_metadata_object(&td, &dirtree, ObjectType::DirTree)?.unwrap();
+    assert!(problem.starts_with(&format!("{path}: checksum mismatch")));
+    // The object type is part of the path
+    let problem = verify_metadata_object(&td, &dirtree, ObjectType::DirMeta)?.unwrap();
+    assert!(problem.ends_with(".dirmeta: missing"));
+    td.remove_file(metadata_object_path(&commit, ObjectType::Commit).unwrap())?;
+    assert!(verify_metadata_object(&td, &commit, ObjectType::Commit)?.is_some());
+
+    let problems = (0..12).map(|i| format!("problem {i}")).collect::<Vec<_>>();
+    assert_eq!(describe_problems(&[], 5), Outcome::Ok);
+    let Outcome::Failed(msg) = describe_problems(&problems, 100) else {
+        panic!("expected failure");
+    };
+    assert!(msg.starts_with("12 of 100 objects are corrupted\nproblem 0\n"));
+    assert_eq!(msg.lines().count(), 1 + MAX_REPORTED_OBJECTS);
+    Ok(())
+}
+
+#[test]
+fn test_check_bootloader() -> Result<()> {
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    let deployment = Utf8Path::new("ostree/deploy/default/deploy/abc.0");
+    td.create_dir_all(deployment)?;
+    td.create_dir_all("ostree/deploy/default/deploy/def.0")?;
+    td.create_dir_all("ostree/boot.1/default/123")?;
+    td.symlink(
+        "../../../deploy/default/deploy/abc.0",
+        "ostree/boot.1/default/123/0",
+    )?;
+    td.symlink(
+        "../../../deploy/default/deploy/def.0",
+        "ostree/boot.1/default/123/1",
+    )?;
+    td.create_dir_all("boot/ostree/default-123")?;
+    td.write("boot/ostree/default-123/vmlinuz-6.8.1", "")?;
+    td.write("boot/ostree/default-123/initramfs-6.8.1.img", "")?;
+
+    let check = |options: &str, initrd: Option<&str>| {
+        let entry = [
+            ("options", Some(options)),
+            ("linux", Some("/ostree/default-123/vmlinuz-6.8.1")),
+            ("initrd", initrd),
+        ];
+        check_bootloader(&td, deployment, "default", "123", &entry).unwrap()
+    };
+    let initrd = Some("/ostree/default-123/initramfs-6.8.1.img");
+    let options = "root=UUID=abc rw ostree=/ostree/boot.1/default/123/0";
+    assert_eq!(check(options, initrd), Outcome::Ok);
+
+    let failure = |outcome| match outcome {
+        Outcome::Failed(msg) => msg,
+        o => panic!("unexpected {o:?}"),
+    };
+    assert_eq!(
+        failure(check("root=UUID=abc rw", initrd)),
+        "missing ostree= kernel argument"
+    );
+    assert_eq!(
+        failure(check("ostree=/ostree/boot.1/default/123/1", initrd)),
+        "ostree=/ostree/boot.1/default/123/1 refers to another deployment"
+    );
+    assert!(failure(check("ostree=/ostree/boot.1/other/123/0", initrd))
+        .starts_with("ostree=/ostree/boot.1/other/123/0 doesn't match default/123"));
+    assert_eq!(failure(check(options, None)), "missing initrd");
+    td.remove_file("boot/ostree/default-123/initramfs-6.8.1.img")?;
+    assert_eq!(
+        failure(check(options, initrd)),
+        "initrd /ostree/default-123/initramfs-6.8.1.img: missing"
+    );
+    // /boot may be part of the root filesystem
+    td.create_dir_all("boot/boot/ostree/default-123")?;
+    td.write("boot/boot/ostree/default-123/initramfs-6.8.1.img", "")?;
+    assert_eq!(
+        check(
+            options,
+            Some("/boot/ostree/default-123/initramfs-6.8.1.img")
+        ),
+        Outcome::Ok
+    );
+    Ok(())
+}
+
+#[test]
+fn test_report() -> Result<()> {
+    assert_eq!(
+        parse_fsverity_measure(
+            "sha256:abcd0123 /sysroot/ostree/deploy/default/deploy/abc.0/.ostree.cfs\n"
+        )?,
+        "abcd0123"
+    );
+    assert!(parse_fsverity_measure("").is_err());
+
+    let report = Report {
+        fast: true,
+        ok: false,
+        deployments: vec![DeploymentReport {
+            index: 0,
+            role: "booted",
+            checksum: "abc".into(),
+            checks: vec![
+                Check {
+                    name: "commit",
+                    outcome: Outcome::Failed("2 of 10 objects are corrupted\na\nb".into()),
+                },
+                Check {
+                    name: "composefs",
+                    outcome: Outcome::Skipped("fast mode".into()),
+                },
+                Check {
+                    name: "origin",
+                    outcome: Outcome::Ok,
+                },
+            ],
+        }],
+    };
+    assert_eq!(
+        serde_json::to_value(&report)?,
+        serde_json::json!({
+            "fast": true,
+            "ok": false,
+            "deployments": [{
+                "index": 0,
+                "role": "booted",
+                "checksum": "abc",
+                "checks": [
+                    {"name": "commit", "status": "failed", "message": "2 of 10 objects are corrupted\na\nb"},
+                    {"name": "composefs", "status": "skipped", "message": "fast mode"},
+                    {"name": "origin", "status": "ok"},
+                ],
+            }],
+        })
+    );
+    let mut out = Vec::new();
+    write_human(&mut out, &report)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "Deployment 0 (booted) abc: FAILED\n  \
+         commit: FAILED: 2 of 10 objects are corrupted\n    a\n    b\n  \
+         composefs: skipped (fast mode)\n  \
+         origin: ok\n"
+    );
+    Ok(())
+}
@@ -48,6 +48,10 @@ pub(crate) fn fstab_generator_impl(root: &Dir, unit_dir: &Dir) -> Result<bool> {
 
 /// Main entrypoint for the generator
 pub(crate) fn generator(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    // This shouldn't prevent the fstab fixups below
+    if let Err(e) = crate::usroverlay::generator(root, unit_dir) {
+        tracing::warn!("{e:#}");
+    }
     // Right now we only do something if the root is a read-only overlayfs (a composefs really)
     let st = rustix::fs::fstatfs(root.as_fd())?;
     if st.f_type != libc::OVERLAYFS_SUPER_MAGIC {
@@ -0,0 +1,258 @@
+//! # Detecting the type of host system
+//!
+//! bootc commands are run on all kinds of systems: hosts installed via bootc,
+//! hosts managed by rpm-ostree, systems not using ostree at all, and container
+//! builds.  Detection only probes the filesystem, so that every command can
+//! fail early with a consistent message, and `bootc status` can report the
+//! type of any system.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::glib;
+
+use crate::spec::HostType;
+
+/// Created by ostree-prepare-root when booting a deployment
+const OSTREE_BOOTED: &str = "run/ostree-booted";
+/// Files created by container runtimes
+const CONTAINER_MARKERS: &[&str] = &["run/.containerenv", ".dockerenv"];
+/// The repository of an ostree-based container image
+const OSTREE_REPO: &str = "sysroot/ostree/repo";
+const RPM_OSTREE: &str = "usr/bin/rpm-ostree";
+
+/// Find the booted deployment from the `ostree=` kernel argument, as a path
+/// relative to the sysroot; e.g. `ostree/deploy/default/deploy/<checksum>.0`.
+pub(crate) fn booted_deployment_path(root: &Dir) -> Result<Option<Utf8PathBuf>> {
+    let cmdline = root
+        .read_to_string("proc/cmdline")
+        .context("Reading /proc/cmdline")?;
+    let Some(target) = cmdline
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix("ostree="))
+    else {
+        return Ok(None);
+    };
+    // The argument points to a chain of symbolic links in /sysroot
+    let path = Utf8Path::new("sysroot").join(target.trim_start_matches('/'));
+    let path = root
+        .canonicalize(&path)
+        .with_context(|| format!("Resolving /{path}"))?;
+    let path = Utf8PathBuf::try_from(path)?;
+    Ok(path.strip_prefix("sysroot").ok().map(ToOwned::to_owned))
+}
+
+/// The type of a booted ostree system, given the origin of its deployment.
+fn classify_origin(origin: Option<&glib::KeyFile>, has_rpm_ostree: bool) -> Result<HostType> {
+    let Some(origin) = origin else {
+        return Ok(HostType::OstreeHost);
+    };
+    // Local package changes can only be represented by rpm-ostree
+    if crate::utils::origin_has_rpmostree_stuff(origin) {
+        return Ok(HostType::RpmOstreeHost);
+    }
+    if crate::status::get_image_origin(origin)?.is_some() {
+        return Ok(HostType::BootcHost);
+    }
+    Ok(if has_rpm_ostree {
+        HostType::RpmOstreeHost
+    } else {
+        HostType::OstreeHost
+    })
+}
+
+/// The type of the system booted into the deployment with the given origin.
+pub(crate) fn of_deployment(root: &Dir, origin: Option<&glib::KeyFile>) -> Result<HostType> {
+    classify_origin(origin, root.try_exists(RPM_OSTREE)?)
+}
+
+/// Detect the type of the system with the root filesystem `root`.
+#[context("Detecting host type")]
+pub(crate) fn detect(root: &Dir) -> Result<HostType> {
+    for marker in CONTAINER_MARKERS {
+        if root.try_exists(marker)? {
+            return Ok(HostType::Container);
+        }
+    }
+    if !root.try_exists(OSTREE_BOOTED)? {
+        // An ostree-based container image run by something other than podman
+        // or docker still has its repository
+        if root.try_exists(OSTREE_REPO)? {
+            return Ok(HostType::Container);
+        }
+        return Ok(HostType::NonOstreeHost);
+    }
+    let Some(deployment) = booted_deployment_path(root)? else {
+        return Ok(HostType::OstreeHost);
+    };
+    let origin = Utf8Path::new("sysroot").join(format!("{deployment}.origin"));
+    let origin = match root.open_optional(&origin)? {
+        Some(mut f) => {
+            let mut data = String::new();
+            f.read_to_string(&mut data)
+                .with_context(|| format!("Reading /{origin}"))?;
+            let kf = glib::KeyFile::new();
+            kf.load_from_data(&data, glib::KeyFileFlags::NONE)
+                .with_context(|| format!("Parsing /{origin}"))?;
+            Some(kf)
+        }
+        None => None,
+    };
+    of_deployment(root, origin.as_ref())
+}
+
+/// Detect the type of the running system.
+pub(crate) fn detect_host() -> Result<HostType> {
+    let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    detect(&root)
+}
+
+/// Whether the system has an ostree sysroot which bootc can operate on.
+pub(crate) fn has_sysroot(ty: &HostType) -> bool {
+    !matches!(ty, HostType::NonOstreeHost | HostType::Container)
+}
+
+/// A description of the type of system for `bootc status`.
+pub(crate) fn describe(ty: &HostType) -> &'static str {
+    match ty {
+        HostType::BootcHost => "booted from a container image via bootc",
+        HostType::RpmOstreeHost => "managed by rpm-ostree",
+        HostType::OstreeHost => "booted via ostree, but not from a container image",
+        HostType::NonOstreeHost => "not booted via ostree",
+        HostType::Container => "running in a container",
+    }
+}
+
+/// Fail with an actionable message unless bootc can operate on the system.
+pub(crate) fn require_sysroot(ty: &HostType) -> Result<()> {
+    match ty {
+        HostType::NonOstreeHost => anyhow::bail!(
+            "This system is not booted via ostree (missing /{OSTREE_BOOTED}); \
+             to convert it into a bootc host, use `bootc install to-existing-root`"
+        ),
+        HostType::Container => anyhow::bail!(
+            "Detected container; this command requires a booted host system. \
+             To build derived images, use e.g. `podman build` instead"
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Fail with an actionable message unless the system is booted from a
+/// container image, e.g. when there is no image to update from.
+pub(crate) fn require_bootc(ty: &HostType) -> Result<()> {
+    require_sysroot(ty)?;
+    match ty {
+        HostType::RpmOstreeHost => anyhow::bail!(
+            "This system is managed by rpm-ostree and cannot be updated via bootc; \
+             use `rpm-ostree upgrade`, or remove any local package changes via \
+             `rpm-ostree reset` and use `bootc switch <image>`"
+        ),
+        HostType::OstreeHost => anyhow::bail!(
+            "The booted deployment was not created from a container image; \
+             use `bootc switch <image>` first"
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_detect() -> Result<()> {
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let root = |name: &str| -> Result<Dir> {
+        td.create_dir(name)?;
+        let root = td.open_dir(name)?;
+        root.create_dir_all("proc")?;
+        root.create_dir_all("run")?;
+        Ok(root)
+    };
+
+    // A traditional system
+    let nonostree = root("nonostree")?;
+    nonostree.write("proc/cmdline", "root=/dev/vda3 rw\n")?;
+    assert_eq!(detect(&nonostree)?, HostType::NonOstreeHost);
+
+    // Containers
+    let podman = root("podman")?;
+    podman.write("run/.containerenv", "")?;
+    assert_eq!(detect(&podman)?, HostType::Container);
+    let docker = root("docker")?;
+    docker.write(".dockerenv", "")?;
+    assert_eq!(detect(&docker)?, HostType::Container);
+    let ostree_container = root("ostree-container")?;
+    ostree_container.create_dir_all(OSTREE_REPO)?;
+    assert_eq!(detect(&ostree_container)?, HostType::Container);
+
+    // Booted ostree systems, with the origin of the deployment varying
+    let booted = |name: &str, origin: Option<&str>| -> Result<Dir> {
+        let root = root(name)?;
+        root.write(OSTREE_BOOTED, "")?;
+        root.write(
+            "proc/cmdline",
+            "root=UUID=abc rw ostree=/ostree/boot.1/default/123/0\n",
+        )?;
+        root.create_dir_all("sysroot/ostree/deploy/default/deploy/123abc.0")?;
+        root.create_dir_all("sysroot/ostree/boot.1/default/123")?;
+        root.symlink(
+            "../../../deploy/default/deploy/123abc.0",
+            "sysroot/ostree/boot.1/default/123/0",
+        )?;
+        if let Some(origin) = origin {
+            root.write(
+                "sysroot/ostree/deploy/default/deploy/123abc.0.origin",
+                origin,
+            )?;
+        }
+        Ok(root)
+    };
+    let image = "[origin]\ncontainer-image-reference=ostree-unverified-registry:quay.io/example/os:latest\n";
+    let bootc = booted("bootc", Some(image))?;
+    assert_eq!(
+        booted_deployment_path(&bootc)?.unwrap(),
+        "ostree/deploy/default/deploy/123abc.0"
+    );
+    assert_eq!(detect(&bootc)?, HostType::BootcHost);
+    // rpm-ostree is commonly installed on bootc hosts too
+    bootc.create_dir_all("usr/bin")?;
+    bootc.write(RPM_OSTREE, "")?;
+    assert_eq!(detect(&bootc)?, HostType::BootcHost);
+
+    let layered = booted(
+        "rpm-ostree-layered",
+        Some(&format!("{image}\n[packages]\nrequested=vim\n")),
+    )?;
+    assert_eq!(detect(&layered)?, HostType::RpmOstreeHost);
+    let refspec = "[origin]\nrefspec=fedora:fedora/40/x86_64/silverblue\n";
+    let rpmostree = booted("rpm-ostree", Some(refspec))?;
+    rpmostree.create_dir_all("usr/bin")?;
+    rpmostree.write(RPM_OSTREE, "")?;
+    assert_eq!(detect(&rpmostree)?, HostType::RpmOstreeHost);
+    let ostree = booted("ostree", Some(refspec))?;
+    assert_eq!(detect(&ostree)?, HostType::OstreeHost);
+    let no_origin = booted("no-origin", None)?;
+    assert_eq!(detect(&no_origin)?, HostType::OstreeHost);
+    let no_karg = booted("no-karg", Some(image))?;
+    no_karg.write("proc/cmdline", "root=UUID=abc rw\n")?;
+    assert_eq!(detect(&no_karg)?, HostType::OstreeHost);
+
+    // The messages
+    for (ty, ok) in [
+        (HostType::BootcHost, (true, true)),
+        (HostType::RpmOstreeHost, (true, false)),
+        (HostType::OstreeHost, (true, false)),
+        (HostType::NonOstreeHost, (false, false)),
+        (HostType::Container, (false, false)),
+    ] {
+        assert_eq!(has_sysroot(&ty), ok.0);
+        assert_eq!(
+            (require_sysroot(&ty).is_ok(), require_bootc(&ty).is_ok()),
+            ok
+        );
+    }
+    Ok(())
+}
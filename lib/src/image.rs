@@ -0,0 +1,783 @@
+//! # Managing the container images stored by bootc
+//!
+//! Images which are not used by any deployment are pruned whenever a new
+//! deployment is staged.  Images can be pinned (e.g. via `bootc switch --retain`)
+//! to keep them; pins are recorded in the sysroot alongside the ostree repository.
+//! Unused images can also be pruned explicitly via `bootc image prune`, and
+//! images can be copied elsewhere via `bootc image copy`.
+
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::container::deploy::Pruned;
+use ostree_ext::container::OstreeImageReference;
+use ostree_ext::glib;
+use ostree_ext::sysroot::SysrootLock;
+use serde::Serialize;
+
+use crate::task::Task;
+
+use crate::cli::{CompressionFormat, ImageListFormat};
+use crate::deployment::{DeploymentState, DeploymentTarget};
+use crate::spec::{ImageReference, PinnedImage};
+
+/// The path to the pinned images, relative to the sysroot.
+const PINS_PATH: &str = "ostree/bootc/pinned-images.json";
+
+/// The reference under which an image is stored.
+fn stored_imgref(image: &ImageReference) -> ostree_container::ImageReference {
+    OstreeImageReference::from(image.clone()).imgref
+}
+
+/// Load the pinned images.
+#[context("Loading {PINS_PATH}")]
+pub(crate) fn load_pins(sysroot_dir: &Dir) -> Result<Vec<PinnedImage>> {
+    let Some(f) = sysroot_dir.open_optional(PINS_PATH)? else {
+        return Ok(Vec::new());
+    };
+    Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+}
+
+#[context("Writing {PINS_PATH}")]
+fn write_pins(sysroot_dir: &Dir, pins: &[PinnedImage]) -> Result<()> {
+    if let Some(parent) = std::path::Path::new(PINS_PATH).parent() {
+        sysroot_dir.create_dir_all(parent)?;
+    }
+    sysroot_dir.atomic_write(PINS_PATH, serde_json::to_vec_pretty(pins)?)?;
+    Ok(())
+}
+
+/// Pin an image; returns `false` if it was already pinned.
+pub(crate) fn pin(sysroot_dir: &Dir, image: &ImageReference, now: DateTime<Utc>) -> Result<bool> {
+    let mut pins = load_pins(sysroot_dir)?;
+    let imgref = stored_imgref(image);
+    if pins.iter().any(|p| stored_imgref(&p.image) == imgref) {
+        return Ok(false);
+    }
+    pins.push(PinnedImage {
+        image: image.clone(),
+        pinned: now,
+    });
+    write_pins(sysroot_dir, &pins)?;
+    Ok(true)
+}
+
+/// Unpin an image; returns `false` if it wasn't pinned.
+pub(crate) fn unpin(sysroot_dir: &Dir, image: &ImageReference) -> Result<bool> {
+    let mut pins = load_pins(sysroot_dir)?;
+    let imgref = stored_imgref(image);
+    let n = pins.len();
+    pins.retain(|p| stored_imgref(&p.image) != imgref);
+    if pins.len() == n {
+        return Ok(false);
+    }
+    write_pins(sysroot_dir, &pins)?;
+    Ok(true)
+}
+
+/// An image in the ostree repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StoredImage {
+    pub(crate) imgref: ostree_container::ImageReference,
+    /// The digest of the image's manifest
+    pub(crate) digest: String,
+    /// When the image was created, if known
+    pub(crate) created: Option<DateTime<Utc>>,
+    /// The total size of the compressed layers; layers may be shared
+    /// with other images
+    pub(crate) size: u64,
+}
+
+/// What uses a stored image, and hence keeps it from being pruned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ImageUse {
+    Booted,
+    Staged,
+    Rollback,
+    PinnedDeployment,
+    /// Any other deployment, e.g. in another stateroot
+    Deployment,
+    /// The image itself is pinned
+    Pinned,
+}
+
+impl std::fmt::Display for ImageUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Booted => "booted",
+            Self::Staged => "staged",
+            Self::Rollback => "rollback",
+            Self::PinnedDeployment => "pinned deployment",
+            Self::Deployment => "deployment",
+            Self::Pinned => "pinned",
+        };
+        f.write_str(s)
+    }
+}
+
+/// What uses the stored image `imgref`; the image is unused if this is empty.
+pub(crate) fn uses(
+    imgref: &ostree_container::ImageReference,
+    deployments: &[DeploymentState],
+    pins: &[PinnedImage],
+) -> Vec<ImageUse> {
+    let mut uses = deployments
+        .iter()
+        .filter(|d| d.image.as_ref() == Some(imgref))
+        .flat_map(|d| {
+            let mut uses = [
+                (d.booted, ImageUse::Booted),
+                (d.staged, ImageUse::Staged),
+                (d.rollback, ImageUse::Rollback),
+            ]
+            .into_iter()
+            .filter_map(|(set, u)| set.then_some(u))
+            .collect::<Vec<_>>();
+            if d.pinned {
+                uses.push(ImageUse::PinnedDeployment);
+            } else if uses.is_empty() {
+                uses.push(ImageUse::Deployment);
+            }
+            uses
+        })
+        .collect::<Vec<_>>();
+    if pins.iter().any(|p| &stored_imgref(&p.image) == imgref) {
+        uses.push(ImageUse::Pinned);
+    }
+    uses.sort();
+    uses.dedup();
+    uses
+}
+
+/// The stored images which are unused, i.e. neither deployed (including by
+/// pinned deployments) nor pinned, apart from the `keep_recent` most recently
+/// created ones; images with an unknown creation time are considered the oldest.
+pub(crate) fn unused<'a>(
+    stored: &'a [StoredImage],
+    deployments: &[DeploymentState],
+    pins: &[PinnedImage],
+    keep_recent: usize,
+) -> Vec<&'a StoredImage> {
+    let mut unused = stored
+        .iter()
+        .filter(|i| uses(&i.imgref, deployments, pins).is_empty())
+        .collect::<Vec<_>>();
+    unused.sort_by_key(|i| std::cmp::Reverse(i.created));
+    unused.split_off(keep_recent.min(unused.len()))
+}
+
+/// Load the images stored in the repository.
+#[context("Listing stored images")]
+fn stored_images(repo: &ostree_ext::ostree::Repo) -> Result<Vec<StoredImage>> {
+    ostree_container::store::list_images(repo)?
+        .into_iter()
+        .filter_map(|img| ostree_container::ImageReference::try_from(img.as_str()).ok())
+        .filter_map(|imgref| {
+            let state = match ostree_container::store::query_image(repo, &imgref) {
+                Ok(Some(state)) => state,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            Some(Ok(StoredImage {
+                imgref,
+                created: crate::status::created_timestamp(&state.configuration),
+                size: crate::deploy::compressed_size(&state.manifest),
+                digest: state.manifest_digest,
+            }))
+        })
+        .collect()
+}
+
+/// The unused images in the repository; see [`unused`].
+fn unused_images(sysroot: &SysrootLock, keep_recent: usize) -> Result<Vec<StoredImage>> {
+    let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+    let deployments = DeploymentState::all(sysroot)?;
+    let pins = load_pins(&sysroot_dir)?;
+    let stored = stored_images(&sysroot.repo())?;
+    Ok(unused(&stored, &deployments, &pins, keep_recent)
+        .into_iter()
+        .cloned()
+        .collect())
+}
+
+/// Remove the given images, along with their layers and objects.
+fn remove_images(sysroot: &SysrootLock, images: &[StoredImage]) -> Result<Pruned> {
+    let repo = &sysroot.repo();
+    for image in images {
+        tracing::debug!("Pruning {}", image.imgref);
+        ostree_container::store::remove_image(repo, &image.imgref)?;
+    }
+    let n_layers = ostree_container::store::gc_image_layers(repo)?;
+    let (_, n_objects_pruned, objsize) = repo.prune(
+        ostree_ext::ostree::RepoPruneFlags::REFS_ONLY,
+        0,
+        ostree_ext::ostree::gio::Cancellable::NONE,
+    )?;
+    Ok(Pruned {
+        n_images: images.len().try_into()?,
+        n_layers,
+        n_objects_pruned: n_objects_pruned.try_into()?,
+        objsize,
+    })
+}
+
+/// Remove the images which are neither deployed (including by pinned
+/// deployments) nor pinned, along with their layers and objects.
+#[context("Pruning images")]
+pub(crate) fn prune(sysroot: &SysrootLock) -> Result<Pruned> {
+    let unused = unused_images(sysroot, 0)?;
+    remove_images(sysroot, &unused)
+}
+
+/// Implementation of `bootc image prune`.
+pub(crate) async fn prune_cmd(dry_run: bool, keep_recent: usize) -> Result<()> {
+    crate::cli::prepare_for_write().await?;
+    // Holding the sysroot lock ensures we don't race with an upgrade
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let unused = unused_images(sysroot, keep_recent)?;
+    if unused.is_empty() {
+        println!("No unused images.");
+        return Ok(());
+    }
+    let verb = if dry_run { "Would prune" } else { "Pruning" };
+    for image in unused.iter() {
+        println!(
+            "{verb}: {} ({})",
+            image.imgref,
+            glib::format_size(image.size)
+        );
+    }
+    if dry_run {
+        let total = unused.iter().map(|i| i.size).sum::<u64>();
+        println!(
+            "Would prune {} images; up to {} (shared layers are kept)",
+            unused.len(),
+            glib::format_size(total)
+        );
+        return Ok(());
+    }
+    let pruned = remove_images(sysroot, &unused)?;
+    println!(
+        "Pruned images: {} (layers: {}, objsize: {})",
+        pruned.n_images,
+        pruned.n_layers,
+        glib::format_size(pruned.objsize)
+    );
+    Ok(())
+}
+
+/// An entry of `bootc image list --format=json`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListEntry<'a> {
+    image: String,
+    digest: &'a str,
+    size: u64,
+    created: Option<DateTime<Utc>>,
+    used_by: Vec<ImageUse>,
+}
+
+/// Write the stored images, along with what uses them.
+fn write_list(
+    out: &mut impl Write,
+    stored: &[StoredImage],
+    deployments: &[DeploymentState],
+    pins: &[PinnedImage],
+    format: ImageListFormat,
+) -> Result<()> {
+    let entries = stored.iter().map(|i| ListEntry {
+        image: i.imgref.to_string(),
+        digest: &i.digest,
+        size: i.size,
+        created: i.created,
+        used_by: uses(&i.imgref, deployments, pins),
+    });
+    if format == ImageListFormat::Json {
+        serde_json::to_writer_pretty(&mut *out, &entries.collect::<Vec<_>>())?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    let rows = entries
+        .map(|e| {
+            let digest = e.digest.get(..19).unwrap_or(e.digest).to_owned();
+            let created = e.created.map(|c| c.to_rfc3339()).unwrap_or_default();
+            let used_by = e
+                .used_by
+                .iter()
+                .map(|u| u.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            [
+                e.image,
+                digest,
+                glib::format_size(e.size).into(),
+                created,
+                used_by,
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["IMAGE", "DIGEST", "SIZE", "CREATED", "USED BY"].map(ToOwned::to_owned);
+    let mut widths = [0; 5];
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        for (w, col) in widths.iter_mut().zip(row) {
+            *w = (*w).max(col.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(col, w)| format!("{col:<w$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc image list`.
+pub(crate) async fn list(format: ImageListFormat) -> Result<()> {
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+    let deployments = DeploymentState::all(sysroot)?;
+    let pins = load_pins(&sysroot_dir)?;
+    let stored = stored_images(&sysroot.repo())?;
+    let mut out = std::io::stdout().lock();
+    write_list(&mut out, &stored, &deployments, &pins, format)
+}
+
+/// What `bootc image copy` copies: the image of a deployment, or a stored image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CopySource {
+    Deployment(DeploymentTarget),
+    Image(ostree_container::ImageReference),
+}
+
+impl FromStr for CopySource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(target) = s.parse() {
+            return Ok(Self::Deployment(target));
+        }
+        let imgref = ostree_container::ImageReference::try_from(s)
+            .with_context(|| format!("Expected a deployment or an image reference: {s}"))?;
+        Ok(Self::Image(imgref))
+    }
+}
+
+/// The reference of an image at its source, pinned to `digest` where possible
+/// so that a changed image at the source can't be copied by accident.
+fn pinned_source(imgref: &ostree_container::ImageReference, digest: &str) -> String {
+    if imgref.transport != ostree_container::Transport::Registry {
+        return imgref.to_string();
+    }
+    let name = imgref
+        .name
+        .split_once('@')
+        .map_or(&*imgref.name, |(n, _)| n);
+    // A tag follows the last path component; a colon before that is a port
+    let base = match name.rsplit_once(':') {
+        Some((base, tag)) if !tag.contains('/') => base,
+        _ => name,
+    };
+    format!("docker://{base}@{digest}")
+}
+
+/// The arguments to `skopeo` for copying `source` to `dest`, writing the
+/// digest of the copied manifest to `digestfile`.  Unless a compression
+/// is requested, the manifest digest is preserved.
+pub(crate) fn copy_args(
+    source: &str,
+    dest: &ostree_container::ImageReference,
+    authfile: Option<&Utf8Path>,
+    compression: Option<CompressionFormat>,
+    digestfile: &Utf8Path,
+    quiet: bool,
+) -> Vec<String> {
+    let mut args = vec!["copy".to_owned()];
+    if quiet {
+        args.push("--quiet".into());
+    }
+    if let Some(authfile) = authfile {
+        args.push(format!("--authfile={authfile}"));
+    }
+    match compression {
+        Some(c) => args.extend([
+            "--dest-compress".to_owned(),
+            format!("--dest-compress-format={}", c.as_str()),
+        ]),
+        None => args.push("--preserve-digests".into()),
+    }
+    args.push(format!("--digestfile={digestfile}"));
+    args.extend([source.to_owned(), dest.to_string()]);
+    args
+}
+
+/// Check the digest written by `skopeo copy --digestfile` against the
+/// digest of the stored image, returning the digest of the copy.
+pub(crate) fn check_copied_digest(
+    digestfile: &str,
+    expected: &str,
+    recompressed: bool,
+) -> Result<String> {
+    let digest = digestfile.trim();
+    if digest.is_empty() {
+        anyhow::bail!("No digest was written for the copied image");
+    }
+    if !recompressed && digest != expected {
+        anyhow::bail!("The copied image has digest {digest}, but the stored image has {expected}");
+    }
+    Ok(digest.to_owned())
+}
+
+/// Implementation of `bootc image copy`.
+#[context("Copying image")]
+pub(crate) async fn copy(
+    source: CopySource,
+    dest: &ostree_container::ImageReference,
+    authfile: Option<&Utf8Path>,
+    compression: Option<CompressionFormat>,
+    quiet: bool,
+) -> Result<()> {
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let imgref = match source {
+        CopySource::Deployment(target) => {
+            let states = DeploymentState::all(sysroot)?;
+            let index = crate::deployment::resolve(target, &states)?;
+            states[index]
+                .image
+                .clone()
+                .ok_or_else(|| anyhow!("Deployment {index} is not using a container image"))?
+        }
+        CopySource::Image(imgref) => imgref,
+    };
+    let state = ostree_container::store::query_image(&sysroot.repo(), &imgref)?
+        .ok_or_else(|| anyhow!("Image not found in bootc storage: {imgref}"))?;
+    let digest = &state.manifest_digest;
+    let source = pinned_source(&imgref, digest);
+
+    let td = tempfile::tempdir()?;
+    let digestfile = Utf8Path::from_path(td.path())
+        .context("Non-UTF8 temporary directory")?
+        .join("digest");
+    let args = copy_args(&source, dest, authfile, compression, &digestfile, quiet);
+    Task::new(format!("Copying {imgref} to {dest}"), "skopeo")
+        .args(args)
+        .run()?;
+    let written = std::fs::read_to_string(&digestfile).context("Reading digest")?;
+    let copied = check_copied_digest(&written, digest, compression.is_some())?;
+    println!("Copied {imgref} to {dest}; digest: {copied}");
+    Ok(())
+}
+
+/// Implementation of `bootc image list-pinned`.
+pub(crate) async fn list_pinned() -> Result<()> {
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+    let pins = load_pins(&sysroot_dir)?;
+    if pins.is_empty() {
+        println!("No pinned images.");
+    }
+    for p in pins {
+        println!("{} (pinned: {})", p.image, p.pinned.to_rfc3339());
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc image unpin`.
+pub(crate) async fn unpin_cmd(image: &ImageReference) -> Result<()> {
+    crate::cli::prepare_for_write().await?;
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+    if !unpin(&sysroot_dir, image)? {
+        anyhow::bail!("Image is not pinned: {image}");
+    }
+    println!("Unpinned {image}; it will be pruned once no deployment uses it.");
+    Ok(())
+}
+
+#[test]
+fn test_pins() -> Result<()> {
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    let imgref = |image: &str| ImageReference {
+        image: image.into(),
+        transport: "registry".into(),
+        signature: None,
+    };
+    let now: DateTime<Utc> = "2024-05-02T10:11:12Z".parse()?;
+    let old = imgref("quay.io/example/os:v1");
+    let new = imgref("quay.io/example/os:v2");
+    let other = imgref("quay.io/example/other:latest");
+    assert!(load_pins(&td)?.is_empty());
+
+    // Pinning on switch
+    assert!(pin(&td, &old, now)?);
+    assert!(!pin(&td, &old, now)?);
+    // The signature verification doesn't matter
+    let signed = ImageReference {
+        signature: Some(crate::spec::ImageSignature::ContainerPolicy),
+        ..old.clone()
+    };
+    assert!(!pin(&td, &signed, now)?);
+    assert_eq!(
+        load_pins(&td)?,
+        [PinnedImage {
+            image: old.clone(),
+            pinned: now
+        }]
+    );
+
+    // A prune after switching keeps the pinned image, but not others
+    let stored = [&old, &new, &other].map(|i| StoredImage {
+        imgref: stored_imgref(i),
+        digest: String::new(),
+        created: None,
+        size: 0,
+    });
+    let deployments = [DeploymentState {
+        booted: true,
+        staged: false,
+        rollback: false,
+        pinned: false,
+        image: Some(stored_imgref(&new)),
+    }];
+    assert_eq!(
+        unused(&stored, &deployments, &load_pins(&td)?, 0),
+        [&stored[2]]
+    );
+
+    // Once unpinned, it's pruned too
+    assert!(!unpin(&td, &other)?);
+    assert!(unpin(&td, &old)?);
+    assert!(load_pins(&td)?.is_empty());
+    assert_eq!(
+        unused(&stored, &deployments, &load_pins(&td)?, 0),
+        [&stored[0], &stored[2]]
+    );
+    Ok(())
+}
+
+/// Stored images along with the deployments and pins which use some of them.
+#[cfg(test)]
+fn fixture_images() -> Result<(Vec<StoredImage>, Vec<DeploymentState>, Vec<PinnedImage>)> {
+    let imgref = |name: &str| ostree_container::ImageReference {
+        transport: ostree_container::Transport::Registry,
+        name: format!("quay.io/example/os:{name}"),
+    };
+    let image = |name, digest: char, created: Option<&str>| StoredImage {
+        imgref: imgref(name),
+        digest: format!("sha256:{}", digest.to_string().repeat(64)),
+        created: created.map(|c| c.parse().unwrap()),
+        size: 1 << 30,
+    };
+    let deployment = |name| DeploymentState {
+        booted: false,
+        staged: false,
+        rollback: false,
+        pinned: false,
+        image: Some(imgref(name)),
+    };
+    let stored = vec![
+        image("staged", '1', Some("2024-06-01T00:00:00Z")),
+        image("booted", '2', Some("2024-05-01T00:00:00Z")),
+        image("rollback", '3', Some("2024-04-01T00:00:00Z")),
+        image("pinned-deployment", '4', Some("2024-01-01T00:00:00Z")),
+        image("pinned", '5', Some("2023-12-01T00:00:00Z")),
+        image("orphan-1", '6', Some("2024-03-01T00:00:00Z")),
+        image("orphan-2", '7', Some("2024-02-01T00:00:00Z")),
+        image("orphan-unknown", '8', None),
+    ];
+    let deployments = vec![
+        DeploymentState {
+            staged: true,
+            ..deployment("staged")
+        },
+        DeploymentState {
+            booted: true,
+            pinned: true,
+            ..deployment("booted")
+        },
+        DeploymentState {
+            rollback: true,
+            ..deployment("rollback")
+        },
+        DeploymentState {
+            pinned: true,
+            ..deployment("pinned-deployment")
+        },
+        // Not a container image
+        DeploymentState {
+            image: None,
+            ..deployment("")
+        },
+    ];
+    let pins = vec![PinnedImage {
+        image: ostree_container::OstreeImageReference {
+            sigverify: ostree_container::SignatureSource::ContainerPolicy,
+            imgref: imgref("pinned"),
+        }
+        .into(),
+        pinned: "2024-05-02T10:11:12Z".parse()?,
+    }];
+    Ok((stored, deployments, pins))
+}
+
+#[test]
+fn test_prune() -> Result<()> {
+    let (stored, deployments, pins) = fixture_images()?;
+    let names = |keep_recent| {
+        unused(&stored, &deployments, &pins, keep_recent)
+            .iter()
+            .map(|i| i.imgref.name.rsplit_once(':').unwrap().1.to_owned())
+            .collect::<Vec<_>>()
+    };
+    // Newest first
+    assert_eq!(names(0), ["orphan-1", "orphan-2", "orphan-unknown"]);
+    assert_eq!(names(1), ["orphan-2", "orphan-unknown"]);
+    assert_eq!(names(2), ["orphan-unknown"]);
+    assert!(names(3).is_empty());
+    assert!(names(10).is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_list() -> Result<()> {
+    let (stored, deployments, pins) = fixture_images()?;
+    let render = |format| -> Result<String> {
+        let mut out = Vec::new();
+        write_list(&mut out, &stored[..6], &deployments, &pins, format)?;
+        Ok(String::from_utf8(out)?)
+    };
+    let expected = "\
+IMAGE                                          DIGEST               SIZE    CREATED                    USED BY
+docker://quay.io/example/os:staged             sha256:111111111111  1.1 GB  2024-06-01T00:00:00+00:00  staged
+docker://quay.io/example/os:booted             sha256:222222222222  1.1 GB  2024-05-01T00:00:00+00:00  booted, pinned deployment
+docker://quay.io/example/os:rollback           sha256:333333333333  1.1 GB  2024-04-01T00:00:00+00:00  rollback
+docker://quay.io/example/os:pinned-deployment  sha256:444444444444  1.1 GB  2024-01-01T00:00:00+00:00  pinned deployment
+docker://quay.io/example/os:pinned             sha256:555555555555  1.1 GB  2023-12-01T00:00:00+00:00  pinned
+docker://quay.io/example/os:orphan-1           sha256:666666666666  1.1 GB  2024-03-01T00:00:00+00:00
+";
+    assert_eq!(render(ImageListFormat::Table)?, expected);
+
+    // Unused images, which would be pruned, are used by nothing
+    let json: serde_json::Value = serde_json::from_str(&render(ImageListFormat::Json)?)?;
+    let used_by = json
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["usedBy"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        used_by,
+        [
+            serde_json::json!(["staged"]),
+            serde_json::json!(["booted", "pinned-deployment"]),
+            serde_json::json!(["rollback"]),
+            serde_json::json!(["pinned-deployment"]),
+            serde_json::json!(["pinned"]),
+            serde_json::json!([]),
+        ]
+    );
+    assert_eq!(unused(&stored[..6], &deployments, &pins, 0), [&stored[5]]);
+    assert_eq!(json[0]["image"], "docker://quay.io/example/os:staged");
+    assert_eq!(json[0]["size"], 1073741824);
+    assert_eq!(json[0]["created"], "2024-06-01T00:00:00Z");
+    assert_eq!(json[0]["digest"], format!("sha256:{}", "1".repeat(64)));
+    Ok(())
+}
+
+#[test]
+fn test_copy() -> Result<()> {
+    let digest = format!("sha256:{}", "1".repeat(64));
+    let imgref = |s: &str| ostree_container::ImageReference::try_from(s).unwrap();
+
+    // Registry sources are pinned to the stored digest
+    for (name, expected) in [
+        ("quay.io/example/os:latest", "quay.io/example/os"),
+        ("quay.io/example/os", "quay.io/example/os"),
+        ("localhost:5000/os:v1", "localhost:5000/os"),
+        ("localhost:5000/os", "localhost:5000/os"),
+        ("quay.io/example/os@sha256:0000", "quay.io/example/os"),
+    ] {
+        let source = ostree_container::ImageReference {
+            transport: ostree_container::Transport::Registry,
+            name: name.into(),
+        };
+        assert_eq!(
+            pinned_source(&source, &digest),
+            format!("docker://{expected}@{digest}")
+        );
+    }
+    assert_eq!(
+        pinned_source(&imgref("oci:/var/lib/images/os"), &digest),
+        "oci:/var/lib/images/os"
+    );
+
+    for (s, v) in [
+        ("booted", CopySource::Deployment(DeploymentTarget::Booted)),
+        ("1", CopySource::Deployment(DeploymentTarget::Index(1))),
+        (
+            "docker://quay.io/example/os:latest",
+            CopySource::Image(imgref("docker://quay.io/example/os:latest")),
+        ),
+    ] {
+        assert_eq!(s.parse::<CopySource>()?, v, "{s}");
+    }
+    assert!("quay.io/example/os".parse::<CopySource>().is_err());
+
+    // Copying to an OCI directory and archive preserves the digest
+    let td = tempfile::tempdir()?;
+    let td = Utf8Path::from_path(td.path()).unwrap();
+    let digestfile = td.join("digest");
+    let source = format!("docker://quay.io/example/os@{digest}");
+    for dest in ["oci:/srv/images/os", "oci-archive:/srv/os.tar"] {
+        let args = copy_args(
+            &source,
+            &imgref(dest),
+            Some(Utf8Path::new("/run/auth.json")),
+            None,
+            &digestfile,
+            false,
+        );
+        assert_eq!(
+            args.join(" "),
+            format!(
+                "copy --authfile=/run/auth.json --preserve-digests --digestfile={digestfile} {source} {dest}"
+            )
+        );
+        // As written by `skopeo copy --digestfile`
+        std::fs::write(&digestfile, &digest)?;
+        let written = std::fs::read_to_string(&digestfile)?;
+        assert_eq!(check_copied_digest(&written, &digest, false)?, digest);
+    }
+    let other = format!("sha256:{}", "2".repeat(64));
+    assert!(check_copied_digest(&other, &digest, false).is_err());
+    assert!(check_copied_digest("", &digest, false).is_err());
+
+    // Recompressing changes the digest
+    let args = copy_args(
+        &source,
+        &imgref("docker://registry.local/os:latest"),
+        None,
+        Some(CompressionFormat::ZstdChunked),
+        &digestfile,
+        true,
+    );
+    assert_eq!(
+        args.join(" "),
+        format!(
+            "copy --quiet --dest-compress --dest-compress-format=zstd:chunked --digestfile={digestfile} {source} docker://registry.local/os:latest"
+        )
+    );
+    assert_eq!(check_copied_digest(&other, &digest, true)?, other);
+    Ok(())
+}
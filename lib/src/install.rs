@@ -7,16 +7,25 @@
 // This sub-module is the "basic" installer that handles creating basic block device
 // and filesystem setup.
 pub(crate) mod baseline;
+pub(crate) mod btrfs;
 pub(crate) mod config;
+pub(crate) mod luks;
+pub(crate) mod network;
 pub(crate) mod osconfig;
+pub(crate) mod partitions;
+pub(crate) mod preserve;
+pub(crate) mod raid;
+pub(crate) mod secureboot;
+pub(crate) mod user;
 
 use std::io::Write;
 use std::os::fd::AsFd;
+use std::os::fd::RawFd;
 use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::process::Command;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::Ok;
@@ -26,7 +35,6 @@ use camino::Utf8PathBuf;
 use cap_std::fs::{Dir, MetadataExt};
 use cap_std_ext::cap_std;
 use cap_std_ext::prelude::CapStdExtDirExt;
-use chrono::prelude::*;
 use clap::ValueEnum;
 use ostree_ext::oci_spec;
 use rustix::fs::{FileTypeExt, MetadataExt as _};
@@ -39,8 +47,11 @@ use ostree_ext::prelude::Cast;
 use serde::{Deserialize, Serialize};
 
 use self::baseline::InstallBlockDeviceOpts;
+use crate::aleph::{InstallAleph, BOOTC_ALEPH_PATH};
+use crate::bootloader::Bootloader;
 use crate::containerenv::ContainerExecutionInfo;
 use crate::mount::Filesystem;
+use crate::progress_jsonl::{Phase, ProgressWriter};
 use crate::task::Task;
 use crate::utils::sigpolicy_from_opts;
 
@@ -139,6 +150,14 @@ pub(crate) struct InstallConfigOpts {
     #[clap(long)]
     karg: Option<Vec<String>>,
 
+    /// Read kernel arguments from a file, or standard input if `-`.
+    ///
+    /// The file holds one argument per line; blank lines and lines starting with
+    /// `#` are ignored.  These arguments are added before any given via `--karg`.
+    #[clap(long, value_name = "PATH")]
+    #[serde(default)]
+    karg_file: Option<Utf8PathBuf>,
+
     /// The path to an `authorized_keys` that will be injected into the `root` account.
     ///
     /// The implementation of this uses systemd `tmpfiles.d`, writing to a file named
@@ -149,6 +168,56 @@ pub(crate) struct InstallConfigOpts {
     #[clap(long)]
     root_ssh_authorized_keys: Option<Utf8PathBuf>,
 
+    /// Create a user in the installed system, optionally with a specific UID.
+    ///
+    /// The user is created on first boot via `systemd-sysusers`, with a home
+    /// directory in `/var/home`.
+    #[clap(long, value_name = "NAME[:UID]")]
+    user: Option<String>,
+
+    /// Read the password for `--user` from standard input.
+    #[clap(long, requires = "user", conflicts_with = "user_password_file")]
+    #[serde(default)]
+    user_password_stdin: bool,
+
+    /// Read the password for `--user` from a file.
+    #[clap(long, value_name = "PATH", requires = "user")]
+    user_password_file: Option<Utf8PathBuf>,
+
+    /// The path to an `authorized_keys` that will be injected for `--user`.
+    #[clap(long, value_name = "PATH", requires = "user")]
+    user_ssh_key: Option<Utf8PathBuf>,
+
+    /// Supplementary groups for `--user`, separated by commas.
+    ///
+    /// Example: --user-groups=wheel,systemd-journal
+    #[clap(long, value_name = "GROUPS", value_delimiter = ',', requires = "user")]
+    #[serde(default)]
+    user_groups: Vec<String>,
+
+    /// Allow `--user` to use `sudo` without a password.
+    #[clap(long, requires = "user")]
+    #[serde(default)]
+    user_sudo: bool,
+
+    /// Copy the NetworkManager connection profiles, hostname and static
+    /// `/etc/resolv.conf` of the host into the installed system.
+    #[clap(long)]
+    #[serde(default)]
+    copy_network: bool,
+
+    /// A directory of NetworkManager connection profiles (keyfiles) to install.
+    ///
+    /// These take precedence over profiles with the same name from `--copy-network`.
+    #[clap(long, value_name = "DIR")]
+    network_config: Option<Utf8PathBuf>,
+
+    /// Write progress as JSON lines to the given file descriptor; see
+    /// `bootc-progress-fd(5)` for the format.
+    #[clap(long, value_parser = crate::progress_jsonl::parse_fd)]
+    #[serde(skip)]
+    progress_fd: Option<RawFd>,
+
     /// Perform configuration changes suitable for a "generic" disk image.
     /// At the moment:
     ///
@@ -157,6 +226,21 @@ pub(crate) struct InstallConfigOpts {
     #[clap(long)]
     #[serde(default)]
     pub(crate) generic_image: bool,
+
+    /// When Secure Boot is enabled, the EFI binaries in the image are checked for a
+    /// signature and SBAT metadata before installing.  Specifying this option
+    /// suppresses the check.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) skip_secureboot_check: bool,
+
+    /// The bootloader to install.
+    ///
+    /// With `none`, no bootloader is installed, but the boot entries are still
+    /// written to `/boot`, e.g. for a bootloader managed externally.
+    #[clap(long, value_enum, default_value_t)]
+    #[serde(default)]
+    pub(crate) bootloader: Bootloader,
 }
 
 /// Perform an installation to a block device.
@@ -240,6 +324,15 @@ pub(crate) struct InstallTargetFilesystemOpts {
     /// is then the responsibility of the invoking code to perform those operations.
     #[clap(long)]
     pub(crate) skip_finalize: bool,
+
+    /// Create btrfs subvolumes for the root and `/var` in the target filesystem, instead of
+    /// installing to it directly.
+    ///
+    /// Optionally, the layout can be given as a comma-separated list of NAME=MOUNTPOINT,
+    /// e.g. `--btrfs-subvolumes=root=/,var=/var,home=/var/home`.  There must be a subvolume
+    /// for `/`, and all others must be mounted in `/var`.
+    #[clap(long, value_name = "LAYOUT", num_args = 0..=1, require_equals = true, default_missing_value = btrfs::DEFAULT_LAYOUT)]
+    pub(crate) btrfs_subvolumes: Option<btrfs::SubvolumeLayout>,
 }
 
 /// Perform an installation to a mounted filesystem.
@@ -278,6 +371,16 @@ pub(crate) struct InstallToExistingRootOpts {
     #[clap(long)]
     pub(crate) acknowledge_destructive: bool,
 
+    /// Move the existing /home into the installed system, i.e. to /var/home.
+    #[clap(long)]
+    pub(crate) preserve_home: bool,
+
+    /// Move an existing path into the installed system; it must be persistent there,
+    /// i.e. under /var or e.g. /home or /root, which link into /var.  May be given
+    /// multiple times.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) preserve: Vec<Utf8PathBuf>,
+
     /// Path to the mounted root; it's expected to invoke podman with
     /// `-v /:/target`, then supplying this argument is unnecessary.
     #[clap(default_value = "/target")]
@@ -310,6 +413,14 @@ pub(crate) struct State {
     pub(crate) install_config: Option<config::InstallConfiguration>,
     /// The parsed contents of the authorized_keys (not the file path)
     pub(crate) root_ssh_authorized_keys: Option<String>,
+    /// Kernel arguments from `--karg-file` followed by those from `--karg`
+    pub(crate) kargs: Vec<String>,
+    /// The user to create, with the password already hashed
+    pub(crate) user: Option<user::UserConfig>,
+    /// The validated network configuration to inject
+    pub(crate) network: Option<network::NetworkConfig>,
+    /// The writer for `--progress-fd`
+    progress: Mutex<Option<ProgressWriter>>,
 }
 
 impl State {
@@ -329,6 +440,21 @@ impl State {
         Ok(Some(r))
     }
 
+    /// Announce a phase of the installation via `--progress-fd`, if enabled.
+    pub(crate) fn progress_phase(&self, phase: Phase, bytes_total: Option<u64>) {
+        if let Some(p) = self.progress.lock().unwrap().as_mut() {
+            p.phase_with_size(phase, bytes_total);
+        }
+    }
+
+    /// Emit the final event via `--progress-fd`, if enabled.
+    fn progress_finish(&self, device: &str) {
+        if let Some(p) = self.progress.lock().unwrap().take() {
+            let digest = self.source.digest.as_deref();
+            p.finish_install(&self.target_imgref.imgref.name, digest, device);
+        }
+    }
+
     #[context("Finalizing state")]
     pub(crate) fn consume(self) -> Result<()> {
         // If we had invoked `setenforce 0`, then let's re-enable it.
@@ -339,27 +465,6 @@ impl State {
     }
 }
 
-/// Path to initially deployed version information
-const BOOTC_ALEPH_PATH: &str = ".bootc-aleph.json";
-
-/// The "aleph" version information is injected into /root/.bootc-aleph.json
-/// and contains the image ID that was initially used to install.  This can
-/// be used to trace things like the specific version of `mkfs.ext4` or
-/// kernel version that was used.
-#[derive(Debug, Serialize)]
-struct InstallAleph {
-    /// Digested pull spec for installed image
-    image: String,
-    /// The version number
-    version: Option<String>,
-    /// The timestamp
-    timestamp: Option<chrono::DateTime<Utc>>,
-    /// The `uname -r` of the kernel doing the installation
-    kernel: String,
-    /// The state of SELinux at install time
-    selinux: String,
-}
-
 /// A mount specification is a subset of a line in `/etc/fstab`.
 ///
 /// There are 3 (ASCII) whitespace separated values:
@@ -549,11 +654,10 @@ async fn initialize_ostree_root_from_self(
 
     // TODO: make configurable?
     let stateroot = STATEROOT_DEFAULT;
-    Task::new_and_run(
-        "Initializing ostree layout",
-        "ostree",
-        ["admin", "init-fs", "--modern", rootfs.as_str()],
-    )?;
+    Task::new("Initializing ostree layout", "ostree")
+        .args(["admin", "init-fs", "--modern", rootfs.as_str()])
+        .run_async()
+        .await?;
 
     // And also label /boot AKA xbootldr, if it exists
     let bootdir = rootfs.join("boot");
@@ -563,11 +667,12 @@ async fn initialize_ostree_root_from_self(
 
     // Default to avoiding grub2-mkconfig etc., but we need to use zipl on s390x.
     // TODO: Lower this logic into ostree proper.
-    let bootloader = if cfg!(target_arch = "s390x") {
-        "zipl"
-    } else {
-        "none"
-    };
+    let bootloader =
+        if cfg!(target_arch = "s390x") && state.config_opts.bootloader != Bootloader::None {
+            "zipl"
+        } else {
+            "none"
+        };
     for (k, v) in [
         ("sysroot.bootloader", bootloader),
         // Always flip this one on because we need to support alongside installs
@@ -579,12 +684,17 @@ async fn initialize_ostree_root_from_self(
             .args(["config", "--repo", "ostree/repo", "set", k, v])
             .cwd(rootfs_dir)?
             .quiet()
-            .run()?;
+            .run_async()
+            .await?;
     }
     Task::new("Initializing sysroot", "ostree")
         .args(["admin", "os-init", stateroot, "--sysroot", "."])
         .cwd(rootfs_dir)?
-        .run()?;
+        .run_async()
+        .await?;
+    if let Some(btrfs) = root_setup.btrfs.as_ref() {
+        btrfs.mount_stateroot(rootfs, rootfs_dir, stateroot, sepolicy)?;
+    }
 
     // Bootstrap the initial labeling of the /ostree directory as usr_t
     if let Some(policy) = sepolicy {
@@ -601,37 +711,36 @@ async fn initialize_ostree_root_from_self(
     let sysroot = ostree::Sysroot::new(Some(&gio::File::for_path(rootfs)));
     sysroot.load(cancellable)?;
 
-    let (src_imageref, proxy_cfg) = if !state.source.in_host_mountns {
-        (state.source.imageref.clone(), None)
+    let src_imageref = if !state.source.in_host_mountns {
+        state.source.imageref.clone()
     } else {
-        let src_imageref = {
-            // We always use exactly the digest of the running image to ensure predictability.
-            let digest = state
-                .source
-                .digest
-                .as_ref()
-                .ok_or_else(|| anyhow::anyhow!("Missing container image digest"))?;
-            let spec = crate::utils::digested_pullspec(&state.source.imageref.name, digest);
-            ostree_container::ImageReference {
-                transport: ostree_container::Transport::ContainerStorage,
-                name: spec,
+        // We always use exactly the digest of the running image to ensure predictability.
+        let digest = state
+            .source
+            .digest
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing container image digest"))?;
+        let spec = crate::utils::digested_pullspec(&state.source.imageref.name, digest);
+        ostree_container::ImageReference {
+            transport: ostree_container::Transport::ContainerStorage,
+            name: spec,
+        }
+    };
+    // We need to fetch the container image from the root mount namespace.  If
+    // we don't have /var/lib/containers mounted in this image, fork off skopeo
+    // in the host mountnfs.
+    let proxy_cfg = || {
+        state.source.in_host_mountns.then(|| {
+            let skopeo_cmd = if !state.source.have_host_container_storage {
+                Some(run_in_host_mountns("skopeo"))
+            } else {
+                None
+            };
+            ostree_container::store::ImageProxyConfig {
+                skopeo_cmd,
+                ..Default::default()
             }
-        };
-
-        // We need to fetch the container image from the root mount namespace.  If
-        // we don't have /var/lib/containers mounted in this image, fork off skopeo
-        // in the host mountnfs.
-        let skopeo_cmd = if !state.source.have_host_container_storage {
-            Some(run_in_host_mountns("skopeo"))
-        } else {
-            None
-        };
-        let proxy_cfg = ostree_container::store::ImageProxyConfig {
-            skopeo_cmd,
-            ..Default::default()
-        };
-
-        (src_imageref, Some(proxy_cfg))
+        })
     };
     let src_imageref = ostree_container::OstreeImageReference {
         // There are no signatures to verify since we're fetching the already
@@ -640,16 +749,53 @@ async fn initialize_ostree_root_from_self(
         imgref: src_imageref,
     };
 
+    // Load the kargs from the kargs.d directories in the running root, which
+    // should be the same as the filesystem we'll deploy.
+    let container_rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let kargsd = crate::kargs::get_kargs_in_root(
+        container_rootfs,
+        std::env::consts::ARCH,
+        crate::kargs::Firmware::detect()?,
+    )?;
     let kargs = root_setup
         .kargs
         .iter()
         .map(|v| v.as_str())
-        .chain(state.config_opts.karg.iter().flatten().map(|v| v.as_str()))
+        .chain(kargsd.iter().map(|v| v.as_str()))
+        .chain(state.kargs.iter().map(|v| v.as_str()))
         .collect::<Vec<_>>();
+
+    // With --progress-fd, fetch the image first so that its progress is reported
+    // with the same events as `bootc upgrade`; the deployment reuses its layers.
+    let progress = state.progress.lock().unwrap().take();
+    let unpacked_size = if let Some(mut progress) = progress {
+        let repo = &sysroot.repo();
+        progress.phase(Phase::Verifying);
+        let mut imp = ostree_container::store::ImageImporter::new(
+            repo,
+            &src_imageref,
+            proxy_cfg().unwrap_or_default(),
+        )
+        .await?;
+        imp.require_bootable();
+        imp.set_target(&state.target_imgref);
+        let opts = crate::deploy::PullOptions {
+            progress: Some(&mut progress),
+            ..Default::default()
+        };
+        let imgstate =
+            crate::deploy::pull_with_importer(repo, imp, &src_imageref.clone().into(), opts).await;
+        *state.progress.lock().unwrap() = Some(progress);
+        imgstate?.pull_info.unpacked_size
+    } else {
+        None
+    };
+    state.progress_phase(Phase::Deploying, unpacked_size);
+
     let mut options = ostree_container::deploy::DeployOpts::default();
     options.kargs = Some(kargs.as_slice());
     options.target_imgref = Some(&state.target_imgref);
-    options.proxy_cfg = proxy_cfg;
+    options.proxy_cfg = proxy_cfg();
     let imgstate = crate::utils::async_task_with_spinner(
         "Deploying container image",
         ostree_container::deploy::deploy(&sysroot, stateroot, &src_imageref, Some(options)),
@@ -674,29 +820,56 @@ async fn initialize_ostree_root_from_self(
     if let Some(policy) = sepolicy {
         let deployment_root_meta = root.dir_metadata()?;
         let deployment_root_devino = (deployment_root_meta.dev(), deployment_root_meta.ino());
+        let mut progress = crate::progress::ProgressReporter::tty_or_noop();
         for d in ["ostree", "boot"] {
             let mut pathbuf = Utf8PathBuf::from(d);
+            progress.set_phase(format!("Relabeling {d}"), None);
             crate::lsm::ensure_dir_labeled_recurse(
                 rootfs_dir,
                 &mut pathbuf,
                 policy,
                 Some(deployment_root_devino),
+                &mut progress,
             )
             .with_context(|| format!("Recursive SELinux relabeling of {d}"))?;
         }
+        progress.finish();
     }
 
     // Write the entry for /boot to /etc/fstab.  TODO: Encourage OSes to use the karg?
     // Or better bind this with the grub data.
-    if let Some(boot) = root_setup.boot.as_ref() {
+    let mounts = root_setup.boot.iter().chain(root_setup.mounts.iter());
+    let fstab = mounts.map(|m| m.to_fstab()).collect::<Vec<_>>();
+    if !fstab.is_empty() {
         crate::lsm::atomic_replace_labeled(&root, "etc/fstab", 0o644.into(), sepolicy, |w| {
-            writeln!(w, "{}", boot.to_fstab()).map_err(Into::into)
+            fstab
+                .iter()
+                .try_for_each(|l| writeln!(w, "{l}"))
+                .map_err(Into::into)
+        })?;
+    }
+    if let Some(luks) = root_setup.luks.as_ref() {
+        let crypttab = luks.crypttab();
+        crate::lsm::atomic_replace_labeled(&root, "etc/crypttab", 0o600.into(), sepolicy, |w| {
+            w.write_all(crypttab.as_bytes()).map_err(Into::into)
+        })?;
+    }
+    if let Some(raid) = root_setup.raid.as_ref() {
+        let conf = raid.mdadm_conf();
+        crate::lsm::atomic_replace_labeled(&root, "etc/mdadm.conf", 0o644.into(), sepolicy, |w| {
+            w.write_all(conf.as_bytes()).map_err(Into::into)
         })?;
     }
 
     if let Some(contents) = state.root_ssh_authorized_keys.as_deref() {
         osconfig::inject_root_ssh_authorized_keys(&root, sepolicy, contents)?;
     }
+    if let Some(user) = state.user.as_ref() {
+        user.write(&root, sepolicy)?;
+    }
+    if let Some(network) = state.network.as_ref() {
+        network.write(&root, sepolicy)?;
+    }
 
     let uname = rustix::system::uname();
 
@@ -709,10 +882,19 @@ async fn initialize_ostree_root_from_self(
         .and_then(crate::status::try_deserialize_timestamp);
     let aleph = InstallAleph {
         image: src_imageref.imgref.name.clone(),
+        transport: Some(crate::status::transport_to_string(
+            src_imageref.imgref.transport,
+        )),
+        digest: Some(imgstate.manifest_digest.clone()),
         version: imgstate.version().as_ref().map(|s| s.to_string()),
         timestamp,
+        vcs_ref: crate::aleph::vcs_ref(&imgstate.configuration),
+        sbom: crate::aleph::sbom_digest(&imgstate.manifest, &imgstate.configuration),
+        bootc_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
         kernel: uname.release().to_str()?.to_string(),
         selinux: state.selinux_state.to_aleph().to_string(),
+        user: state.user.as_ref().map(|u| u.aleph()),
+        kargs: state.kargs.clone(),
     };
 
     Ok(aleph)
@@ -774,7 +956,9 @@ fn require_skopeo_with_containers_storage() -> Result<()> {
 }
 
 pub(crate) struct RootSetup {
-    luks_device: Option<String>,
+    luks: Option<luks::LuksPlan>,
+    raid: Option<raid::RaidPlan>,
+    btrfs: Option<btrfs::SubvolumeSetup>,
     device: Utf8PathBuf,
     rootfs: Utf8PathBuf,
     rootfs_fd: Dir,
@@ -782,6 +966,8 @@ pub(crate) struct RootSetup {
     /// True if we should skip finalizing
     skip_finalize: bool,
     boot: Option<MountSpec>,
+    /// Other filesystems mounted by the installed system, e.g. a separate /var
+    mounts: Vec<MountSpec>,
     kargs: Vec<String>,
 }
 
@@ -797,9 +983,9 @@ impl RootSetup {
         self.boot.as_ref().map(require_boot_uuid).transpose()
     }
 
-    // Drop any open file descriptors and return just the mount path and backing luks device, if any
-    fn into_storage(self) -> (Utf8PathBuf, Option<String>) {
-        (self.rootfs, self.luks_device)
+    // Drop any open file descriptors and return just the mount path and backing luks and raid devices, if any
+    fn into_storage(self) -> (Utf8PathBuf, Option<luks::LuksPlan>, Option<raid::RaidPlan>) {
+        (self.rootfs, self.luks, self.raid)
     }
 }
 
@@ -1151,6 +1337,28 @@ async fn prepare_install(
         .as_ref()
         .map(|p| std::fs::read_to_string(p).with_context(|| format!("Reading {p}")))
         .transpose()?;
+    let kargs = user_kargs(&config_opts)?;
+    // The image being installed is the one we're running in
+    let image_root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    // A generic image may be booted on a system with different firmware settings
+    if config_opts.skip_secureboot_check
+        || config_opts.generic_image
+        || config_opts.bootloader == Bootloader::None
+    {
+        tracing::debug!("Skipping Secure Boot check");
+    } else {
+        let mode = install_config
+            .as_ref()
+            .and_then(|c| c.secureboot_check)
+            .unwrap_or_default();
+        secureboot::preflight(image_root, mode)?;
+    }
+    let user = user::UserConfig::from_opts(&config_opts, image_root)?;
+    let network = network::NetworkConfig::from_opts(&config_opts, image_root)?;
+    let progress = config_opts
+        .progress_fd
+        .map(ProgressWriter::from_fd)
+        .transpose()?;
 
     // Create our global (read-only) state which gets wrapped in an Arc
     // so we can pass it to worker threads too. Right now this just
@@ -1162,11 +1370,68 @@ async fn prepare_install(
         target_imgref,
         install_config,
         root_ssh_authorized_keys,
+        kargs,
+        user,
+        network,
+        progress: Mutex::new(progress),
     });
 
     Ok(state)
 }
 
+/// The options which read from standard input: a path of `-`, or
+/// `--user-password-stdin`.
+fn stdin_options(
+    config_opts: &InstallConfigOpts,
+    encryption: Option<&luks::EncryptionOpts>,
+) -> Vec<&'static str> {
+    let is_stdin = |p: Option<&Utf8Path>| p.is_some_and(|p| p.as_str() == "-");
+    [
+        (is_stdin(config_opts.karg_file.as_deref()), "--karg-file=-"),
+        (config_opts.user_password_stdin, "--user-password-stdin"),
+        (
+            is_stdin(config_opts.user_password_file.as_deref()),
+            "--user-password-file=-",
+        ),
+        (
+            is_stdin(encryption.and_then(|e| e.encrypt_key_from.as_deref())),
+            "--encrypt-key-from=-",
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(used, name)| used.then_some(name))
+    .collect()
+}
+
+/// Standard input can only be read once, so at most one option may use it.
+/// This is checked before anything is read.
+fn check_stdin_options(
+    config_opts: &InstallConfigOpts,
+    encryption: Option<&luks::EncryptionOpts>,
+) -> Result<()> {
+    let opts = stdin_options(config_opts, encryption);
+    if opts.len() > 1 {
+        anyhow::bail!(
+            "Only one option can read from standard input, but got: {}",
+            opts.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The kernel arguments provided on the command line; those read from
+/// `--karg-file` come first, followed by each `--karg` in order.
+fn user_kargs(config_opts: &InstallConfigOpts) -> Result<Vec<String>> {
+    let mut kargs = config_opts
+        .karg_file
+        .as_deref()
+        .map(crate::kargs::read_karg_file)
+        .transpose()?
+        .unwrap_or_default();
+    kargs.extend(config_opts.karg.iter().flatten().cloned());
+    Ok(kargs)
+}
+
 async fn install_to_filesystem_impl(state: &State, rootfs: &mut RootSetup) -> Result<()> {
     if matches!(state.selinux_state, SELinuxFinalState::ForceTargetDisabled) {
         rootfs.kargs.push("selinux=0".to_string());
@@ -1191,10 +1456,24 @@ async fn install_to_filesystem_impl(state: &State, rootfs: &mut RootSetup) -> Re
             .context("Writing aleph version")?;
     }
 
-    crate::bootloader::install_via_bootupd(&rootfs.device, &rootfs.rootfs, &state.config_opts)?;
+    state.progress_phase(Phase::Bootloader, None);
+    match state.config_opts.bootloader {
+        Bootloader::Grub => {
+            crate::bootloader::install_via_bootupd(
+                &rootfs.device,
+                &rootfs.rootfs,
+                &state.config_opts,
+            )?;
+            if let Some(raid) = rootfs.raid.as_ref() {
+                raid.install_bootloader(&rootfs.rootfs, &state.config_opts)?;
+            }
+        }
+        Bootloader::None => tracing::debug!("Skipping bootloader installation"),
+    }
     tracing::debug!("Installed bootloader");
 
     // Finalize mounted filesystems
+    state.progress_phase(Phase::Finalizing, None);
     if !rootfs.skip_finalize {
         let bootfs = rootfs.boot.as_ref().map(|_| rootfs.rootfs.join("boot"));
         let bootfs = bootfs.as_ref().map(|p| p.as_path());
@@ -1214,10 +1493,19 @@ fn installation_complete() {
 #[context("Installing to disk")]
 pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
     let mut block_opts = opts.block_opts;
-    let target_blockdev_meta = block_opts
-        .device
-        .metadata()
-        .with_context(|| format!("Querying {}", &block_opts.device))?;
+    for device in std::iter::once(&block_opts.device).chain(block_opts.mirror_devices.iter()) {
+        let target_blockdev_meta = device
+            .metadata()
+            .with_context(|| format!("Querying {device}"))?;
+        if opts.via_loopback {
+            if !target_blockdev_meta.file_type().is_file() {
+                anyhow::bail!("Not a regular file (to be used via loopback): {device}");
+            }
+        } else if !target_blockdev_meta.file_type().is_block_device() {
+            anyhow::bail!("Not a block device: {device}");
+        }
+    }
+    check_stdin_options(&opts.config_opts, Some(&block_opts.encryption))?;
     if opts.via_loopback {
         if !opts.config_opts.generic_image {
             crate::utils::medium_visibility_warning(
@@ -1225,56 +1513,64 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
             );
             opts.config_opts.generic_image = true;
         }
-        if !target_blockdev_meta.file_type().is_file() {
-            anyhow::bail!(
-                "Not a regular file (to be used via loopback): {}",
-                block_opts.device
-            );
-        }
         if !crate::mount::is_same_as_host(Utf8Path::new("/dev"))? {
             anyhow::bail!("Loopback mounts (--via-loopback) require host devices (-v /dev:/dev)");
         }
-    } else if !target_blockdev_meta.file_type().is_block_device() {
-        anyhow::bail!("Not a block device: {}", block_opts.device);
     }
     let state = prepare_install(opts.config_opts, opts.source_opts, opts.target_opts).await?;
+    let target_device = block_opts.device.clone();
 
     // This is all blocking stuff
     let (mut rootfs, loopback) = {
-        let loopback_dev = if opts.via_loopback {
+        let mut loopback_devs = Vec::new();
+        if opts.via_loopback {
             let loopback_dev =
                 crate::blockdev::LoopbackDevice::new(block_opts.device.as_std_path())?;
             block_opts.device = loopback_dev.path().into();
-            Some(loopback_dev)
-        } else {
-            None
-        };
+            loopback_devs.push(loopback_dev);
+            for device in block_opts.mirror_devices.iter_mut() {
+                let loopback_dev = crate::blockdev::LoopbackDevice::new(device.as_std_path())?;
+                *device = loopback_dev.path().into();
+                loopback_devs.push(loopback_dev);
+            }
+        }
 
         let state = state.clone();
         let rootfs = tokio::task::spawn_blocking(move || {
             baseline::install_create_rootfs(&state, block_opts)
         })
         .await??;
-        (rootfs, loopback_dev)
+        (rootfs, loopback_devs)
     };
 
     install_to_filesystem_impl(&state, &mut rootfs).await?;
 
     // Drop all data about the root except the bits we need to ensure any file descriptors etc. are closed.
-    let (root_path, luksdev) = rootfs.into_storage();
+    let (root_path, luks, raid) = rootfs.into_storage();
     Task::new_and_run(
         "Unmounting filesystems",
         "umount",
         ["-R", root_path.as_str()],
     )?;
-    if let Some(luksdev) = luksdev.as_deref() {
-        Task::new_and_run("Closing root LUKS device", "cryptsetup", ["close", luksdev])?;
+    if let Some(raid) = raid.as_ref() {
+        raid.stop()?;
+    }
+    if let Some(luks) = luks.as_ref() {
+        for vol in luks.volumes().collect::<Vec<_>>().into_iter().rev() {
+            Task::new_and_run(
+                format!("Closing {} LUKS device", vol.name),
+                "cryptsetup",
+                ["close", vol.name],
+            )?;
+        }
     }
 
-    if let Some(loopback_dev) = loopback {
+    for loopback_dev in loopback {
         loopback_dev.close()?;
     }
 
+    let user = state.user.clone();
+    state.progress_finish(target_device.as_str());
     // At this point, all other threads should be gone.
     if let Some(state) = Arc::into_inner(state) {
         state.consume()?;
@@ -1283,6 +1579,15 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
         tracing::warn!("Failed to consume state Arc");
     }
 
+    if let Some(luks) = luks.as_ref() {
+        luks.print_summary();
+    }
+    if let Some(raid) = raid.as_ref() {
+        raid.print_summary();
+    }
+    if let Some(user) = user.as_ref() {
+        user.print_summary();
+    }
     installation_complete();
 
     Ok(())
@@ -1417,6 +1722,7 @@ fn warn_on_host_root(rootfs_fd: &Dir) -> Result<()> {
 pub(crate) async fn install_to_filesystem(
     opts: InstallToFilesystemOpts,
     targeting_host_root: bool,
+    preserve: &[Utf8PathBuf],
 ) -> Result<()> {
     let fsopts = opts.filesystem_opts;
     let root_path = &fsopts.root_path;
@@ -1438,9 +1744,38 @@ pub(crate) async fn install_to_filesystem(
         warn_on_host_root(&rootfs_fd)?;
     }
 
+    // Gather data about the root filesystem
+    let inspect = crate::mount::inspect_filesystem(&fsopts.root_path)?;
+    if fsopts.btrfs_subvolumes.is_some() {
+        if inspect.fstype != "btrfs" {
+            anyhow::bail!(
+                "--btrfs-subvolumes requires a btrfs filesystem, but {root_path} is {}",
+                inspect.fstype
+            );
+        }
+        if fsopts.replace == Some(ReplaceMode::Alongside) {
+            anyhow::bail!("--btrfs-subvolumes is not supported with --replace=alongside");
+        }
+    }
+
+    check_stdin_options(&opts.config_opts, None)?;
+
     // Gather global state, destructuring the provided options
     let state = prepare_install(opts.config_opts, opts.source_opts, opts.target_opts).await?;
 
+    // Verify that the data to preserve can be moved before changing anything
+    let stateroot_path = Utf8PathBuf::from(format!("ostree/deploy/{STATEROOT_DEFAULT}"));
+    let mut preserved = if !preserve.is_empty() {
+        if fsopts.replace != Some(ReplaceMode::Alongside) {
+            anyhow::bail!("Preserving data requires --replace=alongside");
+        }
+        let target_root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+        let var_root = rootfs_fd.open_dir_optional(&stateroot_path)?;
+        preserve::plan(preserve, &rootfs_fd, &target_root, var_root.as_ref())?
+    } else {
+        Vec::new()
+    };
+
     match fsopts.replace {
         Some(ReplaceMode::Wipe) => {
             let rootfs_fd = rootfs_fd.try_clone()?;
@@ -1458,12 +1793,9 @@ pub(crate) async fn install_to_filesystem(
         None => require_empty_rootdir(&rootfs_fd)?,
     }
 
-    // Gather data about the root filesystem
-    let inspect = crate::mount::inspect_filesystem(&fsopts.root_path)?;
-
     // We support overriding the mount specification for root (i.e. LABEL vs UUID versus
     // raw paths).
-    let root_info = if let Some(s) = fsopts.root_mount_spec {
+    let mut root_info = if let Some(s) = fsopts.root_mount_spec {
         RootMountInfo {
             mount_spec: s.to_string(),
             kargs: Vec::new(),
@@ -1495,6 +1827,21 @@ pub(crate) async fn install_to_filesystem(
             kargs,
         }
     };
+
+    // With a subvolume layout, we install to the root subvolume instead
+    let mut mounts = Vec::new();
+    let (btrfs, root_path, rootfs_fd) = if let Some(layout) = fsopts.btrfs_subvolumes {
+        let base = crate::utils::find_mount_option(&inspect.options, "subvol");
+        root_info.kargs.retain(|k| !k.starts_with("rootflags="));
+        root_info.kargs.extend(layout.kargs(base));
+        mounts = layout.mounts(&root_info.mount_spec, base);
+        let target = Utf8Path::new(RUN_BOOTC).join("mounts/btrfs-root");
+        let setup = btrfs::SubvolumeSetup::create(layout, &fsopts.root_path, &rootfs_fd, &target)?;
+        let rootfs_fd = Dir::open_ambient_dir(&target, cap_std::ambient_authority())?;
+        (Some(setup), target, rootfs_fd)
+    } else {
+        (None, fsopts.root_path, rootfs_fd)
+    };
     tracing::debug!("Root mount: {} {:?}", root_info.mount_spec, root_info.kargs);
 
     let boot_is_mount = {
@@ -1510,7 +1857,7 @@ pub(crate) async fn install_to_filesystem(
     };
     // Find the UUID of /boot because we need it for GRUB.
     let boot_uuid = if boot_is_mount {
-        let boot_path = fsopts.root_path.join(BOOT);
+        let boot_path = root_path.join(BOOT);
         let u = crate::mount::inspect_filesystem(&boot_path)
             .context("Inspecting /{BOOT}")?
             .uuid
@@ -1570,9 +1917,12 @@ pub(crate) async fn install_to_filesystem(
     let skip_finalize =
         matches!(fsopts.replace, Some(ReplaceMode::Alongside)) || fsopts.skip_finalize;
     let mut rootfs = RootSetup {
-        luks_device: None,
+        luks: None,
+        raid: None,
+        btrfs,
+        mounts,
         device: backing_device.into(),
-        rootfs: fsopts.root_path,
+        rootfs: root_path,
         rootfs_fd,
         rootfs_uuid: inspect.uuid.clone(),
         boot,
@@ -1582,15 +1932,49 @@ pub(crate) async fn install_to_filesystem(
 
     install_to_filesystem_impl(&state, &mut rootfs).await?;
 
+    if !preserved.is_empty() {
+        let var_root = rootfs.rootfs_fd.open_dir(&stateroot_path)?;
+        let sepolicy = state.load_policy()?;
+        preserve::move_into(
+            &mut preserved,
+            &rootfs.rootfs_fd,
+            &var_root,
+            &stateroot_path,
+            sepolicy.as_ref(),
+        )?;
+    }
+
+    state.progress_finish(rootfs.device.as_str());
+    let btrfs_root = rootfs.btrfs.is_some().then(|| rootfs.rootfs.clone());
     // Drop all data about the root except the path to ensure any file descriptors etc. are closed.
     drop(rootfs);
 
+    if let Some(btrfs_root) = btrfs_root {
+        Task::new_and_run(
+            "Unmounting subvolumes",
+            "umount",
+            ["-R", btrfs_root.as_str()],
+        )?;
+    }
+
+    if !preserved.is_empty() {
+        preserve::print_summary(&preserved);
+    }
+    if let Some(user) = state.user.as_ref() {
+        user.print_summary();
+    }
     installation_complete();
 
     Ok(())
 }
 
 pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) -> Result<()> {
+    let preserve = opts
+        .preserve_home
+        .then(|| Utf8PathBuf::from("/home"))
+        .into_iter()
+        .chain(opts.preserve)
+        .collect::<Vec<_>>();
     let opts = InstallToFilesystemOpts {
         filesystem_opts: InstallTargetFilesystemOpts {
             root_path: opts.root_path,
@@ -1599,13 +1983,14 @@ pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) ->
             replace: opts.replace,
             skip_finalize: true,
             acknowledge_destructive: opts.acknowledge_destructive,
+            btrfs_subvolumes: None,
         },
         source_opts: opts.source_opts,
         target_opts: opts.target_opts,
         config_opts: opts.config_opts,
     };
 
-    install_to_filesystem(opts, true).await
+    install_to_filesystem(opts, true, &preserve).await
 }
 
 #[test]
@@ -1617,6 +2002,62 @@ fn install_opts_serializable() {
     assert_eq!(c.block_opts.device, "/dev/vda");
 }
 
+#[test]
+fn test_user_kargs() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let path = Utf8Path::from_path(td.path()).unwrap().join("kargs");
+    std::fs::write(&path, "# Console\nconsole=ttyS0\n\nnosmt\n")?;
+    let opts = |v| serde_json::from_value::<InstallConfigOpts>(v).unwrap();
+    let c = opts(serde_json::json!({
+        "karg": ["quiet", "console=tty0"],
+        "karg_file": path,
+    }));
+    assert_eq!(
+        user_kargs(&c)?,
+        ["console=ttyS0", "nosmt", "quiet", "console=tty0"]
+    );
+    assert!(user_kargs(&opts(serde_json::json!({})))?.is_empty());
+
+    std::fs::write(&path, "nosmt\nroot=/dev/vda4\n")?;
+    let err = user_kargs(&opts(serde_json::json!({ "karg_file": path }))).unwrap_err();
+    assert_eq!(
+        format!("{err:#}"),
+        format!("Reading kernel arguments from {path}: Line 2: Cannot change reserved kernel argument root=")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_stdin_options() -> Result<()> {
+    let opts = |v| serde_json::from_value::<InstallConfigOpts>(v).unwrap();
+    let encryption = |v| serde_json::from_value::<luks::EncryptionOpts>(v).unwrap();
+    let none = encryption(serde_json::json!({}));
+    let key_stdin = encryption(serde_json::json!({ "encrypt-key-from": "-" }));
+
+    let c = opts(serde_json::json!({ "karg_file": "/etc/kargs" }));
+    assert!(stdin_options(&c, Some(&none)).is_empty());
+    check_stdin_options(&c, Some(&key_stdin))?;
+    let c = opts(serde_json::json!({ "karg_file": "-" }));
+    assert_eq!(stdin_options(&c, None), ["--karg-file=-"]);
+    check_stdin_options(&c, Some(&none))?;
+
+    let c = opts(serde_json::json!({
+        "karg_file": "-",
+        "user_password_stdin": true,
+    }));
+    assert_eq!(
+        format!("{:#}", check_stdin_options(&c, None).unwrap_err()),
+        "Only one option can read from standard input, but got: --karg-file=-, --user-password-stdin"
+    );
+    let c = opts(serde_json::json!({ "user_password_file": "-" }));
+    assert_eq!(
+        stdin_options(&c, Some(&key_stdin)),
+        ["--user-password-file=-", "--encrypt-key-from=-"]
+    );
+    assert!(check_stdin_options(&c, Some(&key_stdin)).is_err());
+    Ok(())
+}
+
 #[test]
 fn test_mountspec() {
     let mut ms = MountSpec::new("/dev/vda4", "/boot");
@@ -1,13 +1,13 @@
 //! # The baseline installer
 //!
 //! This module handles creation of simple root filesystem setups.  At the current time
-//! it's very simple - just a direct filesystem (e.g. xfs, ext4, btrfs etc.).  It is
-//! intended to add opinionated handling of TPM2-bound LUKS too.  But that's about it;
-//! other more complex flows should set things up externally and use `bootc install to-filesystem`.
+//! it's very simple - just a direct filesystem (e.g. xfs, ext4, btrfs etc.), optionally
+//! on LUKS (see [`super::luks`]) or mirrored across disks (see [`super::raid`]).  But
+//! that's about it; other more complex flows should set things up externally and use
+//! `bootc install to-filesystem`.
 
 use std::borrow::Cow;
 use std::fmt::Display;
-use std::io::Write;
 use std::process::Command;
 use std::process::Stdio;
 
@@ -19,14 +19,19 @@ use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
 use clap::ValueEnum;
 use fn_error_context::context;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use super::luks::{EncryptionOpts, LuksPlan};
+use super::partitions::PartitionConfig;
+use super::raid::{RaidPlan, RAID_PARTITION_TYPE};
 use super::MountSpec;
 use super::RootSetup;
 use super::State;
 use super::RUN_BOOTC;
 use super::RW_KARG;
 use crate::mount;
+use crate::progress_jsonl::Phase;
 use crate::task::Task;
 
 pub(crate) const BOOTPN: u32 = 3;
@@ -34,8 +39,12 @@ pub(crate) const BOOTPN: u32 = 3;
 pub(crate) const BOOTPN_SIZE_MB: u32 = 510;
 pub(crate) const EFIPN: u32 = 2;
 pub(crate) const EFIPN_SIZE_MB: u32 = 512;
+/// The partition type of `/var`, per the Discoverable Partitions Specification
+pub(crate) const VAR_PARTITION_TYPE: &str = "4D21B016-B534-45C2-A9FB-5C16E091FD2D";
 
-#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(
+    clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema,
+)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) enum Filesystem {
     Xfs,
@@ -49,12 +58,15 @@ impl Display for Filesystem {
     }
 }
 
-#[derive(clap::ValueEnum, Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(
+    clap::ValueEnum, Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema,
+)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) enum BlockSetup {
     #[default]
     Direct,
     Tpm2Luks,
+    Raid1,
 }
 
 impl Display for BlockSetup {
@@ -79,9 +91,16 @@ pub(crate) struct InstallBlockDeviceOpts {
     ///
     /// direct: Filesystem written directly to block device
     /// tpm2-luks: Bind unlock of filesystem to presence of the default tpm2 device.
+    /// raid1: Mirror the root and /boot filesystems across this and the --device disks.
     #[clap(long, value_enum)]
     pub(crate) block_setup: Option<BlockSetup>,
 
+    /// An additional device for --block-setup raid1; may be given multiple times.
+    /// These devices are wiped as well.
+    #[clap(long = "device", value_name = "DEVICE")]
+    #[serde(default)]
+    pub(crate) mirror_devices: Vec<Utf8PathBuf>,
+
     /// Target root filesystem type.
     #[clap(long, value_enum)]
     pub(crate) filesystem: Option<Filesystem>,
@@ -91,6 +110,19 @@ pub(crate) struct InstallBlockDeviceOpts {
     /// By default, all remaining space on the disk will be used.
     #[clap(long)]
     pub(crate) root_size: Option<String>,
+
+    /// Create the partitions described in a TOML (or JSON, if it ends in `.json`)
+    /// file instead of the default layout; see the documentation for the format.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["block_setup", "filesystem", "root_size", "encrypt"]
+    )]
+    pub(crate) partition_config: Option<Utf8PathBuf>,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub(crate) encryption: EncryptionOpts,
 }
 
 impl BlockSetup {
@@ -98,7 +130,7 @@ impl BlockSetup {
     pub(crate) fn requires_bootpart(&self) -> bool {
         match self {
             BlockSetup::Direct => false,
-            BlockSetup::Tpm2Luks => true,
+            BlockSetup::Tpm2Luks | BlockSetup::Raid1 => true,
         }
     }
 }
@@ -157,59 +189,33 @@ pub(crate) fn install_create_rootfs(
     state: &State,
     opts: InstallBlockDeviceOpts,
 ) -> Result<RootSetup> {
-    let luks_name = "root";
+    // Validate the partition configuration before touching the disk
+    let layout = if let Some(path) = opts.partition_config.as_deref() {
+        let disk_size = crate::blockdev::device_size(&opts.device)?;
+        let layout = PartitionConfig::load(path)?
+            .plan(disk_size, super::ARCH_USES_EFI)
+            .with_context(|| format!("Invalid partition configuration {path}"))?;
+        println!("Using partition layout:\n{layout}");
+        Some(layout)
+    } else {
+        None
+    };
     // Ensure we have a root filesystem upfront
     let root_filesystem = opts
         .filesystem
+        .or(layout
+            .as_ref()
+            .and_then(|l| l.root().spec.filesystem?.linux()))
         .or(state
             .install_config
             .as_ref()
             .and_then(|c| c.filesystem_root())
             .and_then(|r| r.fstype))
         .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
-    // Verify that the target is empty (if not already wiped in particular, but it's
-    // also good to verify that the wipe worked)
-    let device = crate::blockdev::list_dev(&opts.device)?;
-
-    // Handle wiping any existing data
-    if opts.wipe {
-        let dev = &opts.device;
-        for child in device.children.iter().flatten() {
-            let child = child.path();
-            println!("Wiping {child}");
-            crate::blockdev::wipefs(Utf8Path::new(&child))?;
-        }
-        println!("Wiping {dev}");
-        crate::blockdev::wipefs(dev)?;
-    } else if device.has_children() {
-        anyhow::bail!(
-            "Detected existing partitions on {}; use e.g. `wipefs` if you intend to overwrite",
-            opts.device
-        );
-    }
-
-    let run_bootc = Utf8Path::new(RUN_BOOTC);
-    let mntdir = run_bootc.join("mounts");
-    if mntdir.exists() {
-        std::fs::remove_dir_all(&mntdir)?;
-    }
-    let devdir = mntdir.join("dev");
-    std::fs::create_dir_all(&devdir)?;
-    Task::new("Mounting devtmpfs", "mount")
-        .args(["devtmpfs", "-t", "devtmpfs", devdir.as_str()])
-        .quiet()
-        .run()?;
-
-    // Now at this point, our /dev is a stale snapshot because we don't have udev running.
-    // So from hereon after, we prefix devices with our temporary devtmpfs mount.
-    let reldevice = opts
-        .device
-        .strip_prefix("/dev/")
-        .context("Absolute device path in /dev/ required")?;
-    let device = devdir.join(reldevice);
-
     // Use the install configuration to find the block setup, if we have one
-    let block_setup = if let Some(config) = state.install_config.as_ref() {
+    let block_setup = if layout.is_some() {
+        BlockSetup::Direct
+    } else if let Some(config) = state.install_config.as_ref() {
         config.get_block_setup(opts.block_setup.as_ref().copied())?
     } else if opts.filesystem.is_some() {
         // Otherwise, if a filesystem is specified then we default to whatever was
@@ -229,6 +235,104 @@ pub(crate) fn install_create_rootfs(
         .transpose()
         .context("Parsing root size")?;
 
+    // Check the disks for a mirrored setup before touching any of them
+    if !opts.mirror_devices.is_empty() && block_setup != BlockSetup::Raid1 {
+        anyhow::bail!("Additional devices (--device) require --block-setup raid1");
+    }
+    let mut raid = if block_setup == BlockSetup::Raid1 {
+        if opts.encryption.encrypt.is_some() {
+            anyhow::bail!("Encryption is not supported with --block-setup raid1");
+        }
+        super::raid::require_mdadm("/".as_ref())?;
+        let devices = std::iter::once(&opts.device)
+            .chain(opts.mirror_devices.iter())
+            .map(|d| Ok((d.clone(), crate::blockdev::device_size(d)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let raid = RaidPlan::new(
+            &devices,
+            root_size,
+            super::ARCH_USES_EFI,
+            uuid::Uuid::new_v4,
+        )?;
+        raid.require_unused()?;
+        Some(raid)
+    } else {
+        None
+    };
+
+    // Check everything needed for encryption before touching the disk
+    let luks = LuksPlan::new(&opts.encryption, block_setup, || {
+        uuid::Uuid::new_v4().to_string()
+    })?;
+    let luks = if let Some(luks) = luks {
+        if luks.tpm2() {
+            super::luks::require_tpm2("/sys".as_ref())?;
+        }
+        if luks.var.is_some() && opts.root_size.is_none() {
+            anyhow::bail!("A separate /var partition requires --root-size");
+        }
+        // Without a passphrase, this is replaced when binding to the TPM
+        let key = match opts.encryption.encrypt_key_from.as_deref() {
+            Some(path) => super::luks::read_key(path)?,
+            None => uuid::Uuid::new_v4().to_string().into_bytes(),
+        };
+        println!("Using encryption: luks2");
+        Some((luks, key))
+    } else {
+        None
+    };
+
+    let devices_size = std::iter::once(&opts.device)
+        .chain(opts.mirror_devices.iter())
+        .map(|d| crate::blockdev::device_size(d))
+        .sum::<Result<u64>>()?;
+    state.progress_phase(Phase::Partitioning, Some(devices_size));
+    for dev in std::iter::once(&opts.device).chain(opts.mirror_devices.iter()) {
+        // Verify that the target is empty (if not already wiped in particular, but it's
+        // also good to verify that the wipe worked)
+        let device = crate::blockdev::list_dev(dev)?;
+        if raid.is_some() && device.is_mounted() {
+            anyhow::bail!("Device {dev} is in use (mounted)");
+        }
+
+        // Handle wiping any existing data
+        if opts.wipe {
+            for child in device.children.iter().flatten() {
+                let child = child.path();
+                println!("Wiping {child}");
+                crate::blockdev::wipefs(Utf8Path::new(&child))?;
+            }
+            println!("Wiping {dev}");
+            crate::blockdev::wipefs(dev)?;
+        } else if device.has_children() {
+            anyhow::bail!(
+                "Detected existing partitions on {dev}; use e.g. `wipefs` if you intend to overwrite"
+            );
+        }
+    }
+
+    let run_bootc = Utf8Path::new(RUN_BOOTC);
+    let mntdir = run_bootc.join("mounts");
+    if mntdir.exists() {
+        std::fs::remove_dir_all(&mntdir)?;
+    }
+    let devdir = mntdir.join("dev");
+    std::fs::create_dir_all(&devdir)?;
+    Task::new("Mounting devtmpfs", "mount")
+        .args(["devtmpfs", "-t", "devtmpfs", devdir.as_str()])
+        .quiet()
+        .run()?;
+
+    // Now at this point, our /dev is a stale snapshot because we don't have udev running.
+    // So from hereon after, we prefix devices with our temporary devtmpfs mount.
+    let in_devdir = |dev: &Utf8Path| -> Result<Utf8PathBuf> {
+        let reldevice = dev
+            .strip_prefix("/dev/")
+            .context("Absolute device path in /dev/ required")?;
+        Ok(devdir.join(reldevice))
+    };
+    let device = in_devdir(&opts.device)?;
+
     // Load the policy from the container root, which also must be our install root
     let sepolicy = state.load_policy()?;
     let sepolicy = sepolicy.as_ref();
@@ -270,53 +374,112 @@ pub(crate) fn install_create_rootfs(
         anyhow::bail!("Unsupported architecture: {}", std::env::consts::ARCH);
     }
 
-    let esp_partno = if super::ARCH_USES_EFI {
-        sgdisk_partition(
-            &mut sgdisk.cmd,
-            EFIPN,
-            format!("0:+{EFIPN_SIZE_MB}M"),
-            "EFI-SYSTEM",
-            Some("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
-        );
-        Some(EFIPN)
+    let (esp_partno, bootpn, rootpn, varpn) = if let Some(layout) = layout.as_ref() {
+        for p in layout.partitions.iter() {
+            sgdisk_partition(
+                &mut sgdisk.cmd,
+                p.number,
+                p.sgdisk_size(),
+                &p.spec.label,
+                p.typecode(),
+            );
+        }
+        (
+            layout.esp().map(|p| p.number),
+            layout.boot().map(|p| p.number),
+            layout.root().number,
+            None,
+        )
     } else {
-        None
-    };
-
-    // Initialize the /boot filesystem.  Note that in the future, we may match
-    // what systemd/uapi-group encourages and make /boot be FAT32 as well, as
-    // it would aid systemd-boot.
-    let use_xbootldr = block_setup.requires_bootpart();
-    let mut partno = EFIPN;
-    if use_xbootldr {
-        partno += 1;
+        let esp_partno = if super::ARCH_USES_EFI {
+            sgdisk_partition(
+                &mut sgdisk.cmd,
+                EFIPN,
+                format!("0:+{EFIPN_SIZE_MB}M"),
+                "EFI-SYSTEM",
+                Some("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
+            );
+            Some(EFIPN)
+        } else {
+            None
+        };
+
+        // Initialize the /boot filesystem.  Note that in the future, we may match
+        // what systemd/uapi-group encourages and make /boot be FAT32 as well, as
+        // it would aid systemd-boot.
+        let use_xbootldr = block_setup.requires_bootpart() || luks.is_some();
+        let mut partno = EFIPN;
+        let raid_typecode = raid.as_ref().map(|_| RAID_PARTITION_TYPE);
+        if use_xbootldr {
+            partno += 1;
+            sgdisk_partition(
+                &mut sgdisk.cmd,
+                partno,
+                format!("0:+{BOOTPN_SIZE_MB}M"),
+                "boot",
+                raid_typecode,
+            );
+        }
+        let rootpn = if use_xbootldr { BOOTPN + 1 } else { EFIPN + 1 };
+        let root_size = if let Some(raid) = raid.as_ref() {
+            // Every disk holds a member of the same size
+            Cow::Owned(raid.root_partition_size())
+        } else {
+            root_size
+                .map(|v| Cow::Owned(format!("0:{v}M")))
+                .unwrap_or_else(|| Cow::Borrowed("0:0"))
+        };
         sgdisk_partition(
             &mut sgdisk.cmd,
-            partno,
-            format!("0:+{BOOTPN_SIZE_MB}M"),
-            "boot",
-            None,
+            rootpn,
+            root_size,
+            "root",
+            Some(raid_typecode.unwrap_or("0FC63DAF-8483-4772-8E79-3D69D8477DE4")),
         );
-    }
-    let rootpn = if use_xbootldr { BOOTPN + 1 } else { EFIPN + 1 };
-    let root_size = root_size
-        .map(|v| Cow::Owned(format!("0:{v}M")))
-        .unwrap_or_else(|| Cow::Borrowed("0:0"));
-    sgdisk_partition(
-        &mut sgdisk.cmd,
-        rootpn,
-        root_size,
-        "root",
-        Some("0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
-    );
+        let varpn = rootpn + 1;
+        let use_varpart = luks.as_ref().is_some_and(|(l, _)| l.var.is_some());
+        if use_varpart {
+            sgdisk_partition(
+                &mut sgdisk.cmd,
+                varpn,
+                "0:0",
+                "var",
+                Some(VAR_PARTITION_TYPE),
+            );
+        }
+        (
+            esp_partno,
+            use_xbootldr.then_some(BOOTPN),
+            rootpn,
+            use_varpart.then_some(varpn),
+        )
+    };
     sgdisk.run().context("Failed to run sgdisk")?;
     tracing::debug!("Created partition table");
 
+    // Copy the partition table to the other disks of a mirror, with new GUIDs
+    let mut devices = vec![device.clone()];
+    for member in raid.iter().flat_map(|r| r.members.iter().skip(1)) {
+        let target = in_devdir(&member.device)?;
+        Task::new(
+            &format!("Copying partitions to {}", member.device),
+            "sgdisk",
+        )
+        .args([format!("--replicate={target}").as_str(), device.as_str()])
+        .quiet_output()
+        .run()?;
+        Task::new("Randomizing partition GUIDs", "sgdisk")
+            .args(["--randomize-guids", target.as_str()])
+            .quiet_output()
+            .run()?;
+        devices.push(target);
+    }
+
     // Reread the partition table
-    {
+    for device in devices.iter() {
         let mut f = std::fs::OpenOptions::new()
             .write(true)
-            .open(&device)
+            .open(device)
             .with_context(|| format!("opening {device}"))?;
         crate::blockdev::reread_partition_table(&mut f, true)
             .context("Rereading partition table")?;
@@ -339,59 +502,78 @@ pub(crate) fn install_create_rootfs(
         Ok(devdir.join(devname).to_string())
     };
 
-    let base_rootdev = findpart(rootpn)?;
-
-    let (rootdev, root_blockdev_kargs) = match block_setup {
-        BlockSetup::Direct => (base_rootdev, None),
-        BlockSetup::Tpm2Luks => {
-            let uuid = uuid::Uuid::new_v4().to_string();
-            // This will be replaced via --wipe-slot=all when binding to tpm below
-            let dummy_passphrase = uuid::Uuid::new_v4().to_string();
-            let mut tmp_keyfile = tempfile::NamedTempFile::new()?;
-            tmp_keyfile.write_all(dummy_passphrase.as_bytes())?;
-            tmp_keyfile.flush()?;
-            let tmp_keyfile = tmp_keyfile.path();
-            let dummy_passphrase_input = Some(dummy_passphrase.as_bytes());
-
-            Task::new("Initializing LUKS for root", "cryptsetup")
-                .args(["luksFormat", "--uuid", uuid.as_str(), "--key-file"])
-                .args([tmp_keyfile])
-                .args([base_rootdev.as_str()])
-                .run()?;
-            // The --wipe-slot=all removes our temporary passphrase, and binds to the local TPM device.
-            // We also use .verbose() here as the details are important/notable.
-            Task::new("Enrolling root device with TPM", "systemd-cryptenroll")
-                .args(["--wipe-slot=all", "--tpm2-device=auto", "--unlock-key-file"])
-                .args([tmp_keyfile])
-                .args([base_rootdev.as_str()])
-                .verbose()
-                .run_with_stdin_buf(dummy_passphrase_input)?;
-            Task::new("Opening root LUKS device", "cryptsetup")
-                .args(["luksOpen", base_rootdev.as_str(), luks_name])
-                .run()?;
-            let rootdev = format!("/dev/mapper/{luks_name}");
-            let kargs = vec![
-                format!("luks.uuid={uuid}"),
-                format!("luks.options=tpm2-device=auto,headless=true"),
-            ];
-            (rootdev, Some(kargs))
+    let (base_rootdev, bootdev) = if let Some(raid) = raid.as_mut() {
+        let mut boot_members = Vec::new();
+        let mut root_members = Vec::new();
+        for (member, device) in raid.members.iter_mut().zip(devices.iter()) {
+            let partitions = crate::blockdev::list_dev(device)?
+                .children
+                .ok_or_else(|| anyhow::anyhow!("Failed to find children of {device}"))?;
+            let findpart = |idx: u32| -> Result<String> {
+                let devname = partitions
+                    .get(idx.checked_sub(1).unwrap() as usize)
+                    .ok_or_else(|| anyhow::anyhow!("Missing partition {idx} of {device}"))?
+                    .name
+                    .as_str();
+                Ok(devdir.join(devname).to_string())
+            };
+            boot_members.push(findpart(raid.boot.partno)?);
+            root_members.push(findpart(raid.root.partno)?);
+            member.esp = esp_partno.map(findpart).transpose()?;
         }
+        let bootdev = raid.create(&raid.boot, &boot_members, &devdir)?;
+        let rootdev = raid.create(&raid.root, &root_members, &devdir)?;
+        crate::blockdev::udev_settle()?;
+        (rootdev, Some(bootdev))
+    } else {
+        (findpart(rootpn)?, bootpn.map(findpart).transpose()?)
     };
 
-    // Initialize the /boot filesystem
-    let bootdev = if use_xbootldr {
-        Some(findpart(BOOTPN)?)
+    let (rootdev, vardev) = if let Some((luks, key)) = luks.as_ref() {
+        let rootdev = luks.create(&luks.root, &base_rootdev, key)?;
+        let vardev = match (luks.var.as_ref(), varpn) {
+            (Some(var), Some(varpn)) => Some(luks.create(var, &findpart(varpn)?, key)?),
+            _ => None,
+        };
+        (rootdev, vardev)
     } else {
-        None
+        (base_rootdev, None)
     };
+    let root_blockdev_kargs = luks.as_ref().map(|(l, _)| l.kargs());
+
+    // Initialize the /boot filesystem
+    state.progress_phase(Phase::Formatting, None);
+    let layout_boot = layout.as_ref().and_then(|l| l.boot());
+    let boot_filesystem = layout_boot
+        .and_then(|p| p.spec.filesystem?.linux())
+        .unwrap_or(root_filesystem);
+    let boot_label = layout_boot.map_or("boot", |p| p.spec.label.as_str());
     let boot_uuid = if let Some(bootdev) = bootdev.as_deref() {
-        Some(mkfs(bootdev, root_filesystem, "boot", []).context("Initializing /boot")?)
+        Some(mkfs(bootdev, boot_filesystem, boot_label, []).context("Initializing /boot")?)
     } else {
         None
     };
 
     // Initialize rootfs
-    let root_uuid = mkfs(&rootdev, root_filesystem, "root", [])?;
+    let root_label = layout
+        .as_ref()
+        .map_or("root", |l| l.root().spec.label.as_str());
+    let root_uuid = mkfs(&rootdev, root_filesystem, root_label, [])?;
+    let mut mounts = Vec::new();
+    if let Some(vardev) = vardev.as_deref() {
+        let var_uuid = mkfs(vardev, root_filesystem, "var", [])?;
+        mounts.push(MountSpec::new_uuid_src(&var_uuid.to_string(), "/var"));
+    }
+    // Initialize the other partitions of a custom layout
+    for p in layout.iter().flat_map(|l| l.others()) {
+        let Some(fs) = p.spec.filesystem.and_then(|f| f.linux()) else {
+            continue;
+        };
+        let uuid = mkfs(&findpart(p.number)?, fs, &p.spec.label, [])?;
+        if let Some(mountpoint) = p.spec.mountpoint.as_deref() {
+            mounts.push(MountSpec::new_uuid_src(&uuid.to_string(), mountpoint));
+        }
+    }
     let rootarg = format!("root=UUID={root_uuid}");
     let bootsrc = boot_uuid.as_ref().map(|uuid| format!("UUID={uuid}"));
     let bootarg = bootsrc.as_deref().map(|bootsrc| format!("boot={bootsrc}"));
@@ -411,6 +593,7 @@ pub(crate) fn install_create_rootfs(
     let kargs = root_blockdev_kargs
         .into_iter()
         .flatten()
+        .chain(raid.iter().flat_map(|r| r.kargs()))
         .chain([rootarg, RW_KARG.to_string()].into_iter())
         .chain(bootarg)
         .chain(install_config_kargs)
@@ -432,8 +615,12 @@ pub(crate) fn install_create_rootfs(
     // Create the EFI system partition, if applicable
     if let Some(esp_partno) = esp_partno {
         let espdev = &findpart(esp_partno)?;
+        let esp_label = layout
+            .as_ref()
+            .and_then(|l| l.esp())
+            .map_or("EFI-SYSTEM", |p| p.spec.label.as_str());
         Task::new("Creating ESP filesystem", "mkfs.fat")
-            .args([espdev.as_str(), "-n", "EFI-SYSTEM"])
+            .args([espdev.as_str(), "-n", esp_label])
             .verbose()
             .quiet_output()
             .run()?;
@@ -441,13 +628,24 @@ pub(crate) fn install_create_rootfs(
         std::fs::create_dir(&efifs_path).context("Creating efi dir")?;
         mount::mount(espdev, &efifs_path)?;
     }
+    // The ESPs of the other disks of a mirror are mounted when installing the bootloader
+    for espdev in raid
+        .iter()
+        .flat_map(|r| r.members.iter().skip(1))
+        .filter_map(|m| m.esp.as_deref())
+    {
+        Task::new("Creating ESP filesystem", "mkfs.fat")
+            .args([espdev, "-n", "EFI-SYSTEM"])
+            .verbose()
+            .quiet_output()
+            .run()?;
+    }
 
-    let luks_device = match block_setup {
-        BlockSetup::Direct => None,
-        BlockSetup::Tpm2Luks => Some(luks_name.to_string()),
-    };
     Ok(RootSetup {
-        luks_device,
+        luks: luks.map(|(l, _)| l),
+        raid,
+        btrfs: None,
+        mounts,
         device,
         rootfs,
         rootfs_fd,
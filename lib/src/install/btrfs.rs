@@ -0,0 +1,312 @@
+//! # Btrfs subvolume layout for `bootc install to-filesystem`
+//!
+//! With `--btrfs-subvolumes`, the target btrfs filesystem is not used directly;
+//! instead a subvolume is created for the root, and further subvolumes for
+//! e.g. `/var`, so that they can be snapshotted independently.  The layout is
+//! a comma-separated list of `NAME=MOUNTPOINT`, defaulting to
+//! [`DEFAULT_LAYOUT`].
+//!
+//! The root subvolume holds the physical root (i.e. the ostree repository and
+//! deployments) and is selected at boot via `rootflags=subvol=`.  Since in an
+//! ostree system only `/var` is persistent and writable, all other subvolumes
+//! must be mounted in `/var`; they are mounted via `/etc/fstab`.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+
+use super::MountSpec;
+use crate::task::Task;
+
+/// The layout used if `--btrfs-subvolumes` is given without a value
+pub(crate) const DEFAULT_LAYOUT: &str = "root=/,var=/var";
+/// The only directory (besides the root) which may hold subvolumes
+const VAR: &str = "/var";
+
+/// A subvolume to create, and where it is mounted in the installed system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Subvolume {
+    pub(crate) name: String,
+    pub(crate) mountpoint: Utf8PathBuf,
+}
+
+/// The subvolumes to create; the root subvolume is always the first, and
+/// parents are ordered before their children.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SubvolumeLayout {
+    subvolumes: Vec<Subvolume>,
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if matches!(name, "" | "." | "..") {
+        anyhow::bail!("Invalid subvolume name {name:?}");
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '@' | '.' | '_' | '-')))
+    {
+        anyhow::bail!("Invalid character {c:?} in subvolume name {name:?}");
+    }
+    Ok(())
+}
+
+fn validate_mountpoint(mountpoint: &Utf8Path) -> Result<()> {
+    let mut components = mountpoint.components();
+    if components.next() != Some(Utf8Component::RootDir) {
+        anyhow::bail!("Mountpoint {mountpoint} is not absolute");
+    }
+    // Catches e.g. trailing or repeated slashes, as well as `.` and `..`
+    let mut normalized = Utf8PathBuf::from("/");
+    for c in components {
+        match c {
+            Utf8Component::Normal(c) => normalized.push(c),
+            _ => anyhow::bail!("Mountpoint {mountpoint} is not normalized"),
+        }
+    }
+    if normalized.as_str() != mountpoint.as_str() {
+        anyhow::bail!("Mountpoint {mountpoint} is not normalized");
+    }
+    if mountpoint != "/" && !mountpoint.starts_with(VAR) {
+        anyhow::bail!("Mountpoint {mountpoint} is not persistent; subvolumes can only be mounted at / or in {VAR}");
+    }
+    Ok(())
+}
+
+impl FromStr for SubvolumeLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut subvolumes = Vec::new();
+        for entry in s.split(',') {
+            let (name, mountpoint) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Expected NAME=MOUNTPOINT, found {entry:?}"))?;
+            validate_name(name)?;
+            let mountpoint = Utf8PathBuf::from(mountpoint);
+            validate_mountpoint(&mountpoint)?;
+            if let Some(other) = subvolumes.iter().find(|o: &&Subvolume| o.name == name) {
+                anyhow::bail!("Duplicate subvolume {name} for {}", other.mountpoint);
+            }
+            if let Some(other) = subvolumes.iter().find(|o| o.mountpoint == mountpoint) {
+                anyhow::bail!("Duplicate mountpoint {mountpoint} for {}", other.name);
+            }
+            subvolumes.push(Subvolume {
+                name: name.to_owned(),
+                mountpoint,
+            });
+        }
+        if !subvolumes.iter().any(|s| s.mountpoint == "/") {
+            anyhow::bail!("No subvolume for the root (/)");
+        }
+        subvolumes.sort_by_key(|s| s.mountpoint.components().count());
+        Ok(Self { subvolumes })
+    }
+}
+
+impl Display for SubvolumeLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, s) in self.subvolumes.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", s.name, s.mountpoint)?;
+        }
+        Ok(())
+    }
+}
+
+/// The path of a subvolume relative to the toplevel subvolume, given the
+/// subvolume which is mounted as the target (from the `subvol=` mount option).
+fn subvol_path(base: Option<&str>, name: &str) -> String {
+    match base.map(|b| b.trim_matches('/')) {
+        Some(b) if !b.is_empty() => format!("{b}/{name}"),
+        _ => name.to_owned(),
+    }
+}
+
+impl SubvolumeLayout {
+    pub(crate) fn root(&self) -> &Subvolume {
+        &self.subvolumes[0]
+    }
+
+    /// The subvolumes besides the root
+    pub(crate) fn others(&self) -> impl Iterator<Item = &Subvolume> {
+        self.subvolumes.iter().skip(1)
+    }
+
+    /// The kernel arguments to mount the root subvolume.
+    pub(crate) fn kargs(&self, base: Option<&str>) -> Vec<String> {
+        vec![format!(
+            "rootflags=subvol={}",
+            subvol_path(base, &self.root().name)
+        )]
+    }
+
+    /// The mounts of the other subvolumes in the installed system; `source`
+    /// is the mount specification of the filesystem.
+    pub(crate) fn mounts(&self, source: &str, base: Option<&str>) -> Vec<MountSpec> {
+        self.others()
+            .map(|s| MountSpec {
+                source: source.to_owned(),
+                target: s.mountpoint.to_string(),
+                fstype: "btrfs".into(),
+                options: Some(format!("subvol={}", subvol_path(base, &s.name))),
+            })
+            .collect()
+    }
+}
+
+/// The subvolumes created on the target filesystem.
+#[derive(Debug)]
+pub(crate) struct SubvolumeSetup {
+    layout: SubvolumeLayout,
+    /// Where the target filesystem is mounted
+    toplevel: Utf8PathBuf,
+}
+
+impl SubvolumeSetup {
+    /// Create the subvolumes in `toplevel`, and mount the root subvolume at
+    /// `target`, along with the `/boot` filesystem (and the ESP, if any) mounted
+    /// in `toplevel`.
+    #[context("Creating btrfs subvolumes")]
+    pub(crate) fn create(
+        layout: SubvolumeLayout,
+        toplevel: &Utf8Path,
+        toplevel_fd: &Dir,
+        target: &Utf8Path,
+    ) -> Result<Self> {
+        // The bootloader reads /boot relative to the toplevel subvolume, so it
+        // can't be part of the root subvolume.
+        if ostree_ext::mountutil::is_mountpoint(toplevel_fd, super::BOOT)? != Some(true) {
+            anyhow::bail!("--btrfs-subvolumes requires a separate /boot filesystem");
+        }
+        for s in layout.subvolumes.iter() {
+            if toplevel_fd.symlink_metadata_optional(&s.name)?.is_some() {
+                anyhow::bail!("{toplevel}/{} already exists", s.name);
+            }
+        }
+        for s in layout.subvolumes.iter() {
+            let path = toplevel.join(&s.name);
+            Task::new(
+                format!("Creating subvolume {} for {}", s.name, s.mountpoint),
+                "btrfs",
+            )
+            .args(["subvolume", "create", path.as_str()])
+            .quiet_output()
+            .run()?;
+        }
+        std::fs::create_dir_all(target).with_context(|| format!("Creating {target}"))?;
+        bind_mount(&toplevel.join(&layout.root().name), target, false)?;
+        let dest = target.join(super::BOOT);
+        std::fs::create_dir_all(&dest).with_context(|| format!("Creating {dest}"))?;
+        bind_mount(&toplevel.join(super::BOOT), &dest, true)?;
+        Ok(Self {
+            layout,
+            toplevel: toplevel.to_owned(),
+        })
+    }
+
+    /// Mount the other subvolumes in the `/var` of the stateroot, so that its
+    /// initial contents end up in them.
+    #[context("Mounting btrfs subvolumes")]
+    pub(crate) fn mount_stateroot(
+        &self,
+        rootfs: &Utf8Path,
+        rootfs_fd: &Dir,
+        stateroot: &str,
+        sepolicy: Option<&ostree::SePolicy>,
+    ) -> Result<()> {
+        let stateroot_path = Utf8PathBuf::from(format!("ostree/deploy/{stateroot}"));
+        for s in self.layout.others() {
+            // SAFETY: All mountpoints besides the root are absolute paths in /var
+            let relpath = stateroot_path.join(s.mountpoint.strip_prefix("/").unwrap());
+            let dest = rootfs.join(&relpath);
+            std::fs::create_dir_all(&dest).with_context(|| format!("Creating {dest}"))?;
+            bind_mount(&self.toplevel.join(&s.name), &dest, false)?;
+            crate::lsm::ensure_dir_labeled(
+                rootfs_fd,
+                &relpath,
+                Some(&s.mountpoint),
+                0o755.into(),
+                sepolicy,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn bind_mount(src: &Utf8Path, target: &Utf8Path, recursive: bool) -> Result<()> {
+    let flag = if recursive { "--rbind" } else { "--bind" };
+    Task::new_and_run(
+        format!("Mounting {target}"),
+        "mount",
+        [flag, src.as_str(), target.as_str()],
+    )
+}
+
+#[test]
+fn test_parse_layout() {
+    let layout: SubvolumeLayout = DEFAULT_LAYOUT.parse().unwrap();
+    assert_eq!(layout.root().name, "root");
+    assert_eq!(layout.to_string(), DEFAULT_LAYOUT);
+
+    // The root comes first, and parents before children
+    let layout: SubvolumeLayout = "@home=/var/home,@var=/var,@=/".parse().unwrap();
+    assert_eq!(layout.to_string(), "@=/,@var=/var,@home=/var/home");
+    assert_eq!(
+        layout.others().map(|s| s.name.as_str()).collect::<Vec<_>>(),
+        ["@var", "@home"]
+    );
+
+    for (spec, err) in [
+        ("var=/var", "No subvolume for the root (/)"),
+        ("root", "Expected NAME=MOUNTPOINT, found \"root\""),
+        ("root=/,", "Expected NAME=MOUNTPOINT, found \"\""),
+        ("=/", "Invalid subvolume name \"\""),
+        ("..=/", "Invalid subvolume name \"..\""),
+        ("a/b=/", "Invalid character '/' in subvolume name \"a/b\""),
+        ("root=/,home=/home", "Mountpoint /home is not persistent; subvolumes can only be mounted at / or in /var"),
+        ("root=/,var=/variable", "Mountpoint /variable is not persistent; subvolumes can only be mounted at / or in /var"),
+        ("root=/,var=var", "Mountpoint var is not absolute"),
+        ("root=/,var=/var/", "Mountpoint /var/ is not normalized"),
+        ("root=/,var=/var/../etc", "Mountpoint /var/../etc is not normalized"),
+        ("root=/,root=/var", "Duplicate subvolume root for /"),
+        ("root=/,var=/", "Duplicate mountpoint / for root"),
+    ] {
+        let e = spec.parse::<SubvolumeLayout>().unwrap_err();
+        assert_eq!(e.to_string(), err, "{spec}");
+    }
+}
+
+#[test]
+fn test_layout_kargs_mounts() {
+    let layout: SubvolumeLayout = "root=/,var=/var,home=/var/home".parse().unwrap();
+    let uuid = "UUID=965eb3c7-5a3f-470d-aaa2-1bcf04334bc6";
+    for base in [None, Some("/"), Some("")] {
+        assert_eq!(layout.kargs(base), ["rootflags=subvol=root"]);
+        let fstab = layout
+            .mounts(uuid, base)
+            .iter()
+            .map(|m| m.to_fstab())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fstab,
+            [
+                format!("{uuid} /var btrfs subvol=var 0 0"),
+                format!("{uuid} /var/home btrfs subvol=home 0 0"),
+            ]
+        );
+    }
+    // The target may itself be a subvolume
+    assert_eq!(layout.kargs(Some("/os")), ["rootflags=subvol=os/root"]);
+    assert_eq!(
+        layout.mounts(uuid, Some("/os"))[0].options.as_deref(),
+        Some("subvol=os/var")
+    );
+}
@@ -4,20 +4,22 @@
 
 use anyhow::{Context, Result};
 use fn_error_context::context;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use super::baseline::BlockSetup;
 
 /// The toplevel config entry for installation configs stored
 /// in bootc/install (e.g. /etc/bootc/install/05-custom.toml)
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
+#[schemars(rename = "InstallConfigurationFile")]
 pub(crate) struct InstallConfigurationToplevel {
     pub(crate) install: Option<InstallConfiguration>,
 }
 
 /// Configuration for a filesystem
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct RootFS {
     #[serde(rename = "type")]
@@ -26,7 +28,7 @@ pub(crate) struct RootFS {
 
 /// This structure should only define "system" or "basic" filesystems; we are
 /// not trying to generalize this into e.g. supporting `/var` or other ones.
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct BasicFilesystems {
     pub(crate) root: Option<RootFS>,
@@ -36,7 +38,7 @@ pub(crate) struct BasicFilesystems {
 }
 
 /// The serialized [install] section
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, JsonSchema)]
 #[serde(rename = "install", rename_all = "kebab-case", deny_unknown_fields)]
 pub(crate) struct InstallConfiguration {
     /// Root filesystem type
@@ -47,6 +49,9 @@ pub(crate) struct InstallConfiguration {
     /// Kernel arguments, applied at installation time
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) kargs: Option<Vec<String>>,
+    /// How to handle EFI binaries which would not boot with Secure Boot enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) secureboot_check: Option<super::secureboot::SecureBootCheck>,
 }
 
 fn merge_basic<T>(s: &mut Option<T>, o: Option<T>) {
@@ -99,6 +104,7 @@ impl Mergeable for InstallConfiguration {
         merge_basic(&mut self.root_fs_type, other.root_fs_type);
         merge_basic(&mut self.block, other.block);
         self.filesystem.merge(other.filesystem);
+        merge_basic(&mut self.secureboot_check, other.secureboot_check);
         if let Some(other_kargs) = other.kargs {
             self.kargs
                 .get_or_insert_with(Default::default)
@@ -319,3 +325,30 @@ block = ["tpm2-luks"]"##,
     // And verify passing a disallowed config is an error
     assert!(install.get_block_setup(Some(BlockSetup::Direct)).is_err());
 }
+
+#[test]
+fn test_parse_secureboot_check() {
+    use super::secureboot::SecureBootCheck;
+    let c: InstallConfigurationToplevel = toml::from_str(
+        r##"[install]
+secureboot-check = "warn"
+"##,
+    )
+    .unwrap();
+    let mut install = c.install.unwrap();
+    assert_eq!(install.secureboot_check, Some(SecureBootCheck::Warn));
+    // Unset values don't override
+    install.merge(InstallConfiguration::default());
+    assert_eq!(install.secureboot_check, Some(SecureBootCheck::Warn));
+    install.merge(InstallConfiguration {
+        secureboot_check: Some(SecureBootCheck::Enforce),
+        ..Default::default()
+    });
+    assert_eq!(install.secureboot_check, Some(SecureBootCheck::Enforce));
+    assert!(toml::from_str::<InstallConfigurationToplevel>(
+        r##"[install]
+secureboot-check = "maybe"
+"##,
+    )
+    .is_err());
+}
@@ -0,0 +1,389 @@
+//! # LUKS encryption for `bootc install to-disk`
+//!
+//! The root filesystem (and optionally a separate `/var`) can be created on
+//! LUKS2 devices, unlocked at boot via a TPM2 enrollment, a passphrase, or
+//! both.  The root device is unlocked by the initramfs via `rd.luks.*` kernel
+//! arguments, since the initramfs is part of the image and hence can't include
+//! the generated `/etc/crypttab`; that is used for `/var`.
+
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use super::baseline::BlockSetup;
+use crate::task::Task;
+
+/// The PCRs bound by default when enrolling the TPM2, i.e. the Secure Boot state.
+const DEFAULT_TPM2_PCRS: &[u32] = &[7];
+
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Encryption {
+    Luks2,
+}
+
+impl Display for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+/// Options for encrypting the installed filesystems
+#[derive(Debug, Clone, Default, clap::Args, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct EncryptionOpts {
+    /// Create the root filesystem on an encrypted block device.
+    #[clap(long, value_enum)]
+    pub(crate) encrypt: Option<Encryption>,
+
+    /// Also create a separate encrypted /var partition, using the space remaining
+    /// after the root partition (whose size must be given via --root-size).
+    #[clap(long, requires = "encrypt", requires = "root_size")]
+    #[serde(default)]
+    pub(crate) encrypt_var: bool,
+
+    /// Read an initial passphrase from FILE, or `-` for standard input.  It is kept
+    /// as a key slot, so it can be used to unlock the filesystems too.
+    #[clap(long, value_name = "FILE", requires = "encrypt")]
+    pub(crate) encrypt_key_from: Option<Utf8PathBuf>,
+
+    /// Enroll the TPM2 device, so that the filesystems are unlocked at boot
+    /// without interaction.
+    #[clap(long, requires = "encrypt")]
+    #[serde(default)]
+    pub(crate) encrypt_tpm2: bool,
+
+    /// The PCRs the TPM2 enrollment is bound to, e.g. `7` (the default) or `0+7`.
+    #[clap(long, requires = "encrypt_tpm2")]
+    pub(crate) encrypt_tpm2_pcrs: Option<String>,
+}
+
+/// A LUKS device to create.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LuksVolume {
+    /// The name of the unlocked device in `/dev/mapper`
+    pub(crate) name: &'static str,
+    /// The UUID of the LUKS header
+    pub(crate) uuid: String,
+}
+
+/// The LUKS devices to create, and how they're unlocked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LuksPlan {
+    pub(crate) root: LuksVolume,
+    pub(crate) var: Option<LuksVolume>,
+    /// The PCRs to bind to, if the TPM2 is enrolled
+    tpm2_pcrs: Option<Vec<u32>>,
+    /// Whether the initial passphrase is kept
+    passphrase: bool,
+}
+
+fn parse_pcrs(s: &str) -> Result<Vec<u32>> {
+    s.split(['+', ','])
+        .map(|p| {
+            p.parse::<u32>()
+                .ok()
+                .filter(|&p| p < 24)
+                .ok_or_else(|| anyhow::anyhow!("Invalid PCR: {p}"))
+        })
+        .collect()
+}
+
+impl LuksPlan {
+    /// Determine the devices to create, if any; `block_setup` is `tpm2-luks`
+    /// for the equivalent of `--encrypt luks2 --encrypt-tpm2`.
+    pub(crate) fn new(
+        opts: &EncryptionOpts,
+        block_setup: BlockSetup,
+        new_uuid: impl Fn() -> String,
+    ) -> Result<Option<Self>> {
+        let legacy_tpm2 = block_setup == BlockSetup::Tpm2Luks;
+        if opts.encrypt.is_none() && !legacy_tpm2 {
+            return Ok(None);
+        }
+        let tpm2_pcrs = if opts.encrypt_tpm2 || legacy_tpm2 {
+            let pcrs = opts
+                .encrypt_tpm2_pcrs
+                .as_deref()
+                .map(parse_pcrs)
+                .transpose()
+                .context("Parsing --encrypt-tpm2-pcrs")?;
+            Some(pcrs.unwrap_or_else(|| DEFAULT_TPM2_PCRS.to_vec()))
+        } else {
+            None
+        };
+        let passphrase = opts.encrypt_key_from.is_some();
+        if tpm2_pcrs.is_none() && !passphrase {
+            anyhow::bail!("--encrypt requires --encrypt-tpm2 and/or --encrypt-key-from");
+        }
+        let root = LuksVolume {
+            name: "root",
+            uuid: new_uuid(),
+        };
+        let var = opts.encrypt_var.then(|| LuksVolume {
+            name: "var",
+            uuid: new_uuid(),
+        });
+        Ok(Some(Self {
+            root,
+            var,
+            tpm2_pcrs,
+            passphrase,
+        }))
+    }
+
+    /// Whether the TPM2 is enrolled.
+    pub(crate) fn tpm2(&self) -> bool {
+        self.tpm2_pcrs.is_some()
+    }
+
+    /// The volumes, in the order they're created.
+    pub(crate) fn volumes(&self) -> impl Iterator<Item = &LuksVolume> {
+        std::iter::once(&self.root).chain(self.var.as_ref())
+    }
+
+    /// Options for unlocking the devices at boot, if any are needed.
+    fn unlock_options(&self) -> Option<String> {
+        self.tpm2_pcrs.as_ref().map(|_| {
+            // Without a passphrase, there's nothing to ask for
+            if self.passphrase {
+                "tpm2-device=auto".to_string()
+            } else {
+                "tpm2-device=auto,headless=true".to_string()
+            }
+        })
+    }
+
+    fn format_args(&self, vol: &LuksVolume, dev: &str, keyfile: &str) -> Vec<String> {
+        ["luksFormat", "--type", "luks2", "--batch-mode", "--uuid"]
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .chain([vol.uuid.clone(), "--key-file".into(), keyfile.into()])
+            .chain([dev.into()])
+            .collect()
+    }
+
+    fn enroll_args(&self, dev: &str, keyfile: &str) -> Option<Vec<String>> {
+        let pcrs = self.tpm2_pcrs.as_ref()?;
+        let pcrs = pcrs.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let mut r = Vec::new();
+        // Unless we keep the passphrase, this removes it after enrolling
+        if !self.passphrase {
+            r.push("--wipe-slot=all".to_string());
+        }
+        r.extend([
+            "--tpm2-device=auto".to_string(),
+            format!("--tpm2-pcrs={}", pcrs.join("+")),
+            "--unlock-key-file".into(),
+            keyfile.into(),
+            dev.into(),
+        ]);
+        Some(r)
+    }
+
+    /// The kernel arguments for the initramfs to unlock the root device.
+    pub(crate) fn kargs(&self) -> Vec<String> {
+        let uuid = &self.root.uuid;
+        let mut r = vec![format!("rd.luks.name={uuid}={}", self.root.name)];
+        if let Some(options) = self.unlock_options() {
+            r.push(format!("rd.luks.options={uuid}={options}"));
+        }
+        r
+    }
+
+    /// The contents of `/etc/crypttab`.
+    pub(crate) fn crypttab(&self) -> String {
+        let options = self.unlock_options().unwrap_or_else(|| "luks".into());
+        self.volumes()
+            .map(|v| format!("{} UUID={} none {options}\n", v.name, v.uuid))
+            .collect()
+    }
+
+    /// Create, enroll and open a volume on `dev`, returning the unlocked device.
+    #[context("Encrypting {dev}")]
+    pub(crate) fn create(&self, vol: &LuksVolume, dev: &str, key: &[u8]) -> Result<String> {
+        let mut keyfile = tempfile::NamedTempFile::new()?;
+        keyfile.write_all(key)?;
+        keyfile.flush()?;
+        let keyfile = keyfile.into_temp_path();
+        let keyfile = keyfile
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid temporary path"))?;
+        Task::new(&format!("Initializing LUKS for {}", vol.name), "cryptsetup")
+            .args(self.format_args(vol, dev, keyfile))
+            .run()?;
+        // We also use .verbose() here as the details are important/notable.
+        if let Some(args) = self.enroll_args(dev, keyfile) {
+            Task::new(
+                &format!("Enrolling {} device with TPM", vol.name),
+                "systemd-cryptenroll",
+            )
+            .args(args)
+            .verbose()
+            .run()?;
+        }
+        Task::new(&format!("Opening {} LUKS device", vol.name), "cryptsetup")
+            .args(["luksOpen", "--key-file", keyfile, dev, vol.name])
+            .run()?;
+        Ok(format!("/dev/mapper/{}", vol.name))
+    }
+
+    /// Print the generated configuration.
+    pub(crate) fn print_summary(&self) {
+        println!("Encrypted devices (/etc/crypttab):");
+        for line in self.crypttab().lines() {
+            println!("  {line}");
+        }
+        println!("Kernel arguments: {}", self.kargs().join(" "));
+    }
+}
+
+/// Read the initial passphrase from `path`, or standard input for `-`.
+#[context("Reading passphrase from {path}")]
+pub(crate) fn read_key(path: &Utf8Path) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if path.as_str() == "-" {
+        std::io::stdin().read_to_end(&mut buf)?;
+    } else {
+        buf = std::fs::read(path)?;
+    }
+    // A passphrase typed at boot doesn't include the trailing newline
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    if buf.is_empty() {
+        anyhow::bail!("Empty passphrase");
+    }
+    Ok(buf)
+}
+
+/// Verify that a TPM2 device is present, given the path to sysfs.
+#[context("Checking for TPM2")]
+pub(crate) fn require_tpm2(sysfs: &Path) -> Result<()> {
+    let tpmrm = sysfs.join("class/tpmrm");
+    let found = match std::fs::read_dir(&tpmrm) {
+        Ok(mut d) => d.next().is_some(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e).with_context(|| format!("Reading {tpmrm:?}")),
+    };
+    if !found {
+        anyhow::bail!("Binding to the TPM2 was requested, but no TPM2 device was found");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_luks_plan() -> Result<()> {
+    let uuids = std::cell::Cell::new(0);
+    let new_uuid = || {
+        uuids.set(uuids.get() + 1);
+        format!("uuid-{}", uuids.get())
+    };
+    let opts = EncryptionOpts::default();
+    assert_eq!(LuksPlan::new(&opts, BlockSetup::Direct, new_uuid)?, None);
+
+    // The legacy block setup binds to the TPM2, without a passphrase
+    let plan = LuksPlan::new(&opts, BlockSetup::Tpm2Luks, new_uuid)?.unwrap();
+    assert_eq!(
+        plan.kargs(),
+        [
+            "rd.luks.name=uuid-1=root",
+            "rd.luks.options=uuid-1=tpm2-device=auto,headless=true"
+        ]
+    );
+    assert_eq!(
+        plan.crypttab(),
+        "root UUID=uuid-1 none tpm2-device=auto,headless=true\n"
+    );
+    assert_eq!(
+        plan.format_args(&plan.root, "/dev/vda4", "/tmp/key")
+            .join(" "),
+        "luksFormat --type luks2 --batch-mode --uuid uuid-1 --key-file /tmp/key /dev/vda4"
+    );
+    assert_eq!(
+        plan.enroll_args("/dev/vda4", "/tmp/key").unwrap().join(" "),
+        "--wipe-slot=all --tpm2-device=auto --tpm2-pcrs=7 --unlock-key-file /tmp/key /dev/vda4"
+    );
+
+    // A passphrase and the TPM2, with /var
+    let opts = EncryptionOpts {
+        encrypt: Some(Encryption::Luks2),
+        encrypt_var: true,
+        encrypt_key_from: Some("-".into()),
+        encrypt_tpm2: true,
+        encrypt_tpm2_pcrs: Some("0+7".into()),
+    };
+    let plan = LuksPlan::new(&opts, BlockSetup::Direct, new_uuid)?.unwrap();
+    assert_eq!(
+        plan.volumes().map(|v| v.name).collect::<Vec<_>>(),
+        ["root", "var"]
+    );
+    assert_eq!(
+        plan.kargs(),
+        [
+            "rd.luks.name=uuid-2=root",
+            "rd.luks.options=uuid-2=tpm2-device=auto"
+        ]
+    );
+    assert_eq!(
+        plan.crypttab(),
+        "root UUID=uuid-2 none tpm2-device=auto\nvar UUID=uuid-3 none tpm2-device=auto\n"
+    );
+    assert_eq!(
+        plan.enroll_args("/dev/vda5", "/tmp/key").unwrap().join(" "),
+        "--tpm2-device=auto --tpm2-pcrs=0+7 --unlock-key-file /tmp/key /dev/vda5"
+    );
+
+    // Just a passphrase
+    let opts = EncryptionOpts {
+        encrypt_tpm2: false,
+        encrypt_var: false,
+        encrypt_tpm2_pcrs: None,
+        ..opts
+    };
+    let plan = LuksPlan::new(&opts, BlockSetup::Direct, new_uuid)?.unwrap();
+    assert_eq!(plan.kargs(), ["rd.luks.name=uuid-4=root"]);
+    assert_eq!(plan.crypttab(), "root UUID=uuid-4 none luks\n");
+    assert_eq!(plan.enroll_args("/dev/vda4", "/tmp/key"), None);
+
+    // But there must be some way to unlock it
+    let invalid = EncryptionOpts {
+        encrypt_key_from: None,
+        ..opts.clone()
+    };
+    let e = LuksPlan::new(&invalid, BlockSetup::Direct, new_uuid).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "--encrypt requires --encrypt-tpm2 and/or --encrypt-key-from"
+    );
+    let invalid = EncryptionOpts {
+        encrypt_tpm2: true,
+        encrypt_tpm2_pcrs: Some("7+24".into()),
+        ..opts
+    };
+    let e = LuksPlan::new(&invalid, BlockSetup::Direct, new_uuid).unwrap_err();
+    assert_eq!(
+        format!("{e:#}"),
+        "Parsing --encrypt-tpm2-pcrs: Invalid PCR: 24"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_require_tpm2() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let e = require_tpm2(td.path()).unwrap_err();
+    assert_eq!(
+        format!("{e:#}"),
+        "Checking for TPM2: Binding to the TPM2 was requested, but no TPM2 device was found"
+    );
+    std::fs::create_dir_all(td.path().join("class/tpmrm/tpmrm0"))?;
+    require_tpm2(td.path())?;
+    Ok(())
+}
@@ -0,0 +1,406 @@
+//! # Network configuration for the installed system
+//!
+//! With `--copy-network`, the NetworkManager connection profiles of the system
+//! doing the installation are copied into the installed system, along with its
+//! hostname and a static `/etc/resolv.conf`.  With `--network-config DIR`, the
+//! profiles in `DIR` are used instead, taking precedence over copied profiles
+//! of the same name.
+//!
+//! All profiles are validated as keyfiles before the installation starts, and
+//! are written as files owned by `root` with mode `0600`, as otherwise
+//! NetworkManager ignores them.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+
+use super::InstallConfigOpts;
+
+const NM_DIR: &str = "etc/NetworkManager";
+const CONNECTIONS_DIR: &str = "etc/NetworkManager/system-connections";
+const HOSTNAME: &str = "etc/hostname";
+const RESOLV_CONF: &str = "etc/resolv.conf";
+/// Suffixes of files which NetworkManager does not load as profiles
+const IGNORED_SUFFIXES: &[&str] = &["~", ".bak", ".orig", ".rej", ".swp", ".tmp"];
+
+/// A NetworkManager connection profile in keyfile format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Profile {
+    /// The file name
+    name: String,
+    /// The `connection.id`, which defaults to the file name
+    id: String,
+    /// The `connection.type`, e.g. `ethernet`
+    kind: String,
+    uuid: Option<String>,
+    contents: String,
+}
+
+/// The network configuration to write into the installed system.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct NetworkConfig {
+    profiles: Vec<Profile>,
+    hostname: Option<String>,
+    resolv_conf: Option<String>,
+}
+
+/// Parse a keyfile as used by NetworkManager (and `GKeyFile`) into its groups.
+fn parse_keyfile(buf: &str) -> Result<BTreeMap<&str, BTreeMap<&str, &str>>> {
+    let mut groups: BTreeMap<&str, BTreeMap<&str, &str>> = BTreeMap::new();
+    let mut group = None;
+    for (i, line) in buf.lines().enumerate() {
+        let n = i + 1;
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .trim_end()
+                .strip_suffix(']')
+                .filter(|name| !name.is_empty() && !name.contains(['[', ']']))
+                .ok_or_else(|| anyhow::anyhow!("Line {n}: Invalid group {line:?}"))?;
+            groups.entry(name).or_default();
+            group = Some(name);
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            anyhow::bail!("Line {n}: Expected a group or key=value, found {line:?}");
+        };
+        let key = key.trim_end();
+        if key.is_empty() {
+            anyhow::bail!("Line {n}: Empty key");
+        }
+        let Some(group) = group else {
+            anyhow::bail!("Line {n}: Key {key} is not in a group");
+        };
+        groups
+            .entry(group)
+            .or_default()
+            .insert(key, value.trim_start());
+    }
+    Ok(groups)
+}
+
+impl Profile {
+    /// Validate the profile in `contents`.
+    #[context("Parsing connection profile {name}")]
+    fn new(name: &str, contents: String) -> Result<Self> {
+        let groups = parse_keyfile(&contents)?;
+        let connection = groups
+            .get("connection")
+            .ok_or_else(|| anyhow::anyhow!("Missing [connection] group"))?;
+        let kind = connection
+            .get("type")
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Missing connection type"))?
+            .to_string();
+        let id = connection
+            .get("id")
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| name.strip_suffix(".nmconnection").unwrap_or(name).into());
+        let uuid = connection.get("uuid").map(|v| v.to_string());
+        Ok(Self {
+            name: name.to_owned(),
+            id,
+            kind,
+            uuid,
+            contents,
+        })
+    }
+}
+
+/// Read the connection profiles in `dir`, sorted by name.
+fn read_profiles(dir: &Dir) -> Result<Vec<Profile>> {
+    let mut r = Vec::new();
+    for ent in dir.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            anyhow::bail!("Invalid non-UTF8 filename: {name:?}");
+        };
+        if name.starts_with('.')
+            || IGNORED_SUFFIXES.iter().any(|s| name.ends_with(s))
+            || !ent.file_type()?.is_file()
+        {
+            continue;
+        }
+        let contents = dir.read_to_string(name)?;
+        r.push(Profile::new(name, contents)?);
+    }
+    r.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(r)
+}
+
+/// Read a static `/etc/resolv.conf`; one which is a symlink or generated by
+/// NetworkManager will be recreated in the installed system.
+fn read_static_resolv_conf(root: &Dir) -> Result<Option<String>> {
+    if !root
+        .symlink_metadata_optional(RESOLV_CONF)?
+        .is_some_and(|m| m.is_file())
+    {
+        return Ok(None);
+    }
+    let contents = root.read_to_string(RESOLV_CONF)?;
+    if contents.trim().is_empty() || contents.contains("# Generated by NetworkManager") {
+        return Ok(None);
+    }
+    Ok(Some(contents))
+}
+
+impl NetworkConfig {
+    /// Gather the configuration for `--copy-network` and `--network-config`, if
+    /// given.  `root` is the image being installed.
+    #[context("Gathering network configuration")]
+    pub(crate) fn from_opts(opts: &InstallConfigOpts, root: &Dir) -> Result<Option<Self>> {
+        if !opts.copy_network && opts.network_config.is_none() {
+            return Ok(None);
+        }
+        if !root.try_exists("usr/sbin/NetworkManager")? {
+            anyhow::bail!("Injecting network configuration requires NetworkManager in the image");
+        }
+        let host_root = opts
+            .copy_network
+            .then(|| Dir::open_ambient_dir("/proc/1/root", cap_std::ambient_authority()))
+            .transpose()
+            .context("Opening host root")?;
+        let config_dir = opts
+            .network_config
+            .as_ref()
+            .map(|p| {
+                Dir::open_ambient_dir(p, cap_std::ambient_authority())
+                    .with_context(|| format!("Opening {p}"))
+            })
+            .transpose()?;
+        Self::new(host_root.as_ref(), config_dir.as_ref()).map(Some)
+    }
+
+    /// Gather the configuration to copy from `host_root`, and the profiles in
+    /// `config_dir`.
+    fn new(host_root: Option<&Dir>, config_dir: Option<&Dir>) -> Result<Self> {
+        let mut profiles = BTreeMap::new();
+        let mut r = Self::default();
+        if let Some(host_root) = host_root {
+            if let Some(dir) = host_root.open_dir_optional(CONNECTIONS_DIR)? {
+                let copied = read_profiles(&dir).context("Reading host connection profiles")?;
+                profiles.extend(copied.into_iter().map(|p| (p.name.clone(), p)));
+            }
+            r.hostname = host_root
+                .open_optional(HOSTNAME)?
+                .map(std::io::read_to_string)
+                .transpose()?
+                .map(|s| s.trim().to_owned())
+                .filter(|s| !s.is_empty());
+            r.resolv_conf = read_static_resolv_conf(host_root)?;
+        }
+        if let Some(dir) = config_dir {
+            let provided = read_profiles(dir)?;
+            if provided.is_empty() {
+                anyhow::bail!("No connection profiles found in --network-config directory");
+            }
+            profiles.extend(provided.into_iter().map(|p| (p.name.clone(), p)));
+        }
+        r.profiles = profiles.into_values().collect();
+
+        let mut uuids = BTreeMap::new();
+        for p in r.profiles.iter() {
+            let Some(uuid) = p.uuid.as_deref() else {
+                continue;
+            };
+            if let Some(other) = uuids.insert(uuid, p.name.as_str()) {
+                anyhow::bail!(
+                    "Connection profiles {other} and {} have the same UUID {uuid}",
+                    p.name
+                );
+            }
+        }
+        Ok(r)
+    }
+
+    /// Write the configuration into the deployment root.
+    #[context("Injecting network configuration")]
+    pub(crate) fn write(&self, root: &Dir, sepolicy: Option<&ostree::SePolicy>) -> Result<()> {
+        use rustix::fs::{AtFlags, Gid, Uid};
+        let write = |path: &Utf8Path, mode: u32, contents: &str| -> Result<()> {
+            crate::lsm::atomic_replace_labeled(root, path, mode.into(), sepolicy, |w| {
+                w.write_all(contents.as_bytes()).map_err(Into::into)
+            })?;
+            rustix::fs::chownat(
+                root,
+                path.as_std_path(),
+                Some(Uid::ROOT),
+                Some(Gid::ROOT),
+                AtFlags::SYMLINK_NOFOLLOW,
+            )
+            .with_context(|| format!("Setting ownership of {path}"))
+        };
+
+        println!("Injecting network configuration:");
+        if !self.profiles.is_empty() {
+            crate::lsm::ensure_dir_labeled(root, NM_DIR, None, 0o755.into(), sepolicy)?;
+            crate::lsm::ensure_dir_labeled(root, CONNECTIONS_DIR, None, 0o700.into(), sepolicy)?;
+        }
+        for p in self.profiles.iter() {
+            let path = Utf8Path::new(CONNECTIONS_DIR).join(&p.name);
+            write(&path, 0o600, &p.contents)?;
+            println!("  /{path}: {} ({})", p.id, p.kind);
+        }
+        if let Some(hostname) = self.hostname.as_deref() {
+            write(Utf8Path::new(HOSTNAME), 0o644, &format!("{hostname}\n"))?;
+            println!("  /{HOSTNAME}: {hostname}");
+        }
+        if let Some(contents) = self.resolv_conf.as_deref() {
+            if root
+                .symlink_metadata_optional(RESOLV_CONF)?
+                .is_some_and(|m| m.is_symlink())
+            {
+                println!("  /{RESOLV_CONF}: skipped, as it is a symbolic link in the image");
+            } else {
+                write(Utf8Path::new(RESOLV_CONF), 0o644, contents)?;
+                println!("  /{RESOLV_CONF}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_keyfile() -> Result<()> {
+    let buf = "# A comment\n\n[connection]\nid = Wired\ntype=ethernet\n  [ipv4]\nmethod=auto\naddress1=10.0.0.2/24,10.0.0.1\n";
+    let groups = parse_keyfile(buf)?;
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups["connection"]["id"], "Wired");
+    assert_eq!(groups["connection"]["type"], "ethernet");
+    assert_eq!(groups["ipv4"]["address1"], "10.0.0.2/24,10.0.0.1");
+
+    for (buf, expected) in [
+        ("id=foo\n", "Line 1: Key id is not in a group"),
+        ("[connection\n", "Line 1: Invalid group \"[connection\""),
+        ("[]\n", "Line 1: Invalid group \"[]\""),
+        (
+            "[connection]\nnonsense\n",
+            "Line 2: Expected a group or key=value, found \"nonsense\"",
+        ),
+        ("[connection]\n=x\n", "Line 2: Empty key"),
+    ] {
+        assert_eq!(parse_keyfile(buf).unwrap_err().to_string(), expected);
+    }
+
+    let p = Profile::new("eth0.nmconnection", "[connection]\ntype=ethernet\n".into())?;
+    assert_eq!(p.id, "eth0");
+    assert_eq!(p.kind, "ethernet");
+    for (buf, expected) in [
+        ("[ipv4]\nmethod=auto\n", "Missing [connection] group"),
+        ("[connection]\nid=foo\n", "Missing connection type"),
+    ] {
+        let err = Profile::new("foo", buf.into()).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            format!("Parsing connection profile foo: {expected}")
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_network_config() -> Result<()> {
+    use cap_std::fs::{MetadataExt, PermissionsExt};
+
+    let profile = |id: &str, uuid: &str| {
+        format!("[connection]\nid={id}\nuuid={uuid}\ntype=ethernet\n\n[ipv4]\nmethod=auto\n")
+    };
+    let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("host/etc/NetworkManager/system-connections")?;
+    td.create_dir_all("config")?;
+    let host = &td.open_dir("host")?;
+    let config = &td.open_dir("config")?;
+    let host_connections = &host.open_dir(CONNECTIONS_DIR)?;
+    host_connections.write("eth0.nmconnection", profile("Wired", "1"))?;
+    host_connections.write("eth1.nmconnection", profile("Old", "2"))?;
+    host_connections.write("eth1.nmconnection~", "garbage")?;
+    host_connections.write(".hidden", "garbage")?;
+    host.write(HOSTNAME, "myhost\n")?;
+    host.write(
+        RESOLV_CONF,
+        "# Generated by NetworkManager\nnameserver 10.0.0.1\n",
+    )?;
+    config.write("eth1.nmconnection", profile("Static", "3"))?;
+
+    // Only copying
+    let c = NetworkConfig::new(Some(host), None)?;
+    assert_eq!(
+        c.profiles.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(),
+        ["Wired", "Old"]
+    );
+    assert_eq!(c.hostname.as_deref(), Some("myhost"));
+    assert_eq!(c.resolv_conf, None);
+
+    // The provided profiles take precedence
+    host.write(RESOLV_CONF, "nameserver 10.0.0.1\n")?;
+    let c = NetworkConfig::new(Some(host), Some(config))?;
+    assert_eq!(
+        c.profiles.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(),
+        ["Wired", "Static"]
+    );
+    assert_eq!(c.resolv_conf.as_deref(), Some("nameserver 10.0.0.1\n"));
+
+    // Only the provided profiles
+    let c = NetworkConfig::new(None, Some(config))?;
+    assert_eq!(c.profiles.len(), 1);
+    assert_eq!(c.hostname, None);
+
+    // Errors
+    config.write("dup.nmconnection", profile("Dup", "3"))?;
+    assert_eq!(
+        NetworkConfig::new(None, Some(config))
+            .unwrap_err()
+            .to_string(),
+        "Connection profiles dup.nmconnection and eth1.nmconnection have the same UUID 3"
+    );
+    config.write("dup.nmconnection", "[connection]\n")?;
+    assert_eq!(
+        format!("{:#}", NetworkConfig::new(None, Some(config)).unwrap_err()),
+        "Parsing connection profile dup.nmconnection: Missing connection type"
+    );
+    td.create_dir("empty")?;
+    assert_eq!(
+        NetworkConfig::new(None, Some(&td.open_dir("empty")?))
+            .unwrap_err()
+            .to_string(),
+        "No connection profiles found in --network-config directory"
+    );
+
+    td.create_dir_all("target/etc")?;
+    // Setting the ownership of the written files requires privileges
+    crate::testutils::require_capability!(rustix::thread::CapabilityFlags::CHOWN);
+    config.remove_file("dup.nmconnection")?;
+    let c = NetworkConfig::new(Some(host), Some(config))?;
+    let target = &td.open_dir("target")?;
+    target.symlink("../run/resolv.conf", RESOLV_CONF)?;
+    c.write(target, None)?;
+    let meta = target.metadata(CONNECTIONS_DIR)?;
+    assert_eq!(meta.permissions().mode() & 0o7777, 0o700);
+    for (name, id) in [
+        ("eth0.nmconnection", "Wired"),
+        ("eth1.nmconnection", "Static"),
+    ] {
+        let path = format!("{CONNECTIONS_DIR}/{name}");
+        let meta = target.metadata(&path)?;
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o600);
+        assert_eq!((meta.uid(), meta.gid()), (0, 0));
+        assert!(target
+            .read_to_string(&path)?
+            .contains(&format!("id={id}\n")));
+    }
+    assert_eq!(target.read_to_string(HOSTNAME)?, "myhost\n");
+    assert!(target.symlink_metadata(RESOLV_CONF)?.is_symlink());
+    Ok(())
+}
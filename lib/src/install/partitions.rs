@@ -0,0 +1,559 @@
+//! # Declarative partitioning for `bootc install to-disk`
+//!
+//! By default, `to-disk` creates a fixed layout (see [`super::baseline`]).  A
+//! partition configuration replaces that with a list of partitions, e.g.:
+//!
+//! ```toml
+//! [[partition]]
+//! label = "EFI-SYSTEM"
+//! size = "1G"
+//! filesystem = "vfat"
+//! mountpoint = "/boot/efi"
+//!
+//! [[partition]]
+//! label = "root"
+//! size = "20G"
+//! filesystem = "xfs"
+//! mountpoint = "/"
+//!
+//! [[partition]]
+//! label = "var"
+//! size = "grow"
+//! filesystem = "xfs"
+//! mountpoint = "/var"
+//! ```
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use clap::ValueEnum;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use super::baseline::{Filesystem, VAR_PARTITION_TYPE};
+
+/// The mountpoints a partition may have.
+const SUPPORTED_MOUNTPOINTS: &[&str] = &[
+    "/",
+    "/boot",
+    "/boot/efi",
+    "/var",
+    "/var/home",
+    "/var/lib/containers",
+    "/var/log",
+];
+/// The space used by the partition table, including the alignment of the
+/// first partition and the backup table at the end of the disk.
+pub(crate) const PARTITION_TABLE_MIB: u64 = 2;
+/// The size of the architecture-specific first partition, e.g. BIOS-BOOT.
+pub(crate) const RESERVED_PARTITION_MIB: u64 = 1;
+/// The minimum size of the ESP
+const MIN_ESP_MIB: u64 = 100;
+/// The maximum length of a GPT partition name
+const MAX_PARTITION_NAME: usize = 36;
+
+const ESP_PARTITION_TYPE: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+const ROOT_PARTITION_TYPE: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+const HOME_PARTITION_TYPE: &str = "933AC7E1-2EB4-4F13-B844-0E14E2AEF915";
+
+/// The filesystem of a partition.
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PartitionFilesystem {
+    Xfs,
+    Ext4,
+    Btrfs,
+    Vfat,
+}
+
+impl Display for PartitionFilesystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+impl PartitionFilesystem {
+    /// The filesystem for `mkfs`, unless this is the ESP.
+    pub(crate) fn linux(&self) -> Option<Filesystem> {
+        match self {
+            Self::Xfs => Some(Filesystem::Xfs),
+            Self::Ext4 => Some(Filesystem::Ext4),
+            Self::Btrfs => Some(Filesystem::Btrfs),
+            Self::Vfat => None,
+        }
+    }
+
+    fn max_label_len(&self) -> usize {
+        match self {
+            Self::Xfs => 12,
+            Self::Ext4 => 16,
+            Self::Btrfs => 255,
+            Self::Vfat => 11,
+        }
+    }
+}
+
+/// The size of a partition.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum PartitionSize {
+    /// A fixed size, in MiB
+    Fixed(u64),
+    /// All space not used by other partitions
+    Grow,
+}
+
+impl FromStr for PartitionSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "grow" {
+            return Ok(Self::Grow);
+        }
+        let v = crate::blockdev::parse_size_mib(s)
+            .with_context(|| format!("Invalid size {s:?}; expected e.g. \"10G\" or \"grow\""))?;
+        Ok(Self::Fixed(v))
+    }
+}
+
+impl<'de> Deserialize<'de> for PartitionSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A `[[partition]]` entry.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct PartitionSpec {
+    /// The name of the partition, also used as the filesystem label
+    pub(crate) label: String,
+    pub(crate) size: PartitionSize,
+    /// If unset, the partition is left unformatted
+    pub(crate) filesystem: Option<PartitionFilesystem>,
+    pub(crate) mountpoint: Option<String>,
+}
+
+/// The serialized partition configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PartitionConfig {
+    #[serde(rename = "partition")]
+    pub(crate) partitions: Vec<PartitionSpec>,
+}
+
+/// A partition in a [`Layout`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PlannedPartition {
+    /// The partition number
+    pub(crate) number: u32,
+    pub(crate) spec: PartitionSpec,
+    /// The size in MiB; for a `grow` partition, this is the remaining space
+    pub(crate) size_mib: u64,
+    /// Whether this takes all remaining space at the end of the disk
+    grow_to_end: bool,
+}
+
+/// The partitions to create, after the architecture-specific first partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub(crate) partitions: Vec<PlannedPartition>,
+}
+
+impl PartitionSpec {
+    fn is(&self, mountpoint: &str) -> bool {
+        self.mountpoint.as_deref() == Some(mountpoint)
+    }
+
+    fn validate(&self, uses_efi: bool) -> Result<()> {
+        let label = &self.label;
+        if label.is_empty() || label.len() > MAX_PARTITION_NAME {
+            anyhow::bail!("The label must be between 1 and {MAX_PARTITION_NAME} characters");
+        }
+        if let Some(fs) = self.filesystem {
+            if label.len() > fs.max_label_len() {
+                anyhow::bail!(
+                    "The label must be at most {} characters for {fs}",
+                    fs.max_label_len()
+                );
+            }
+        }
+        if self.size == PartitionSize::Fixed(0) {
+            anyhow::bail!("The size must not be zero");
+        }
+        let Some(mountpoint) = self.mountpoint.as_deref() else {
+            if self.filesystem == Some(PartitionFilesystem::Vfat) {
+                anyhow::bail!("vfat is only supported for the ESP (/boot/efi)");
+            }
+            return Ok(());
+        };
+        if !SUPPORTED_MOUNTPOINTS.contains(&mountpoint) {
+            anyhow::bail!(
+                "Unsupported mountpoint {mountpoint}; supported: {}",
+                SUPPORTED_MOUNTPOINTS.join(", ")
+            );
+        }
+        let Some(fs) = self.filesystem else {
+            anyhow::bail!("A filesystem is required for mountpoint {mountpoint}");
+        };
+        if mountpoint == "/boot/efi" {
+            if !uses_efi {
+                anyhow::bail!("An ESP is not supported on {}", std::env::consts::ARCH);
+            }
+            if fs != PartitionFilesystem::Vfat {
+                anyhow::bail!("The ESP must be vfat, not {fs}");
+            }
+            if matches!(self.size, PartitionSize::Fixed(s) if s < MIN_ESP_MIB) {
+                anyhow::bail!("The ESP must be at least {MIN_ESP_MIB} MiB");
+            }
+        } else if fs == PartitionFilesystem::Vfat {
+            anyhow::bail!("vfat is only supported for the ESP (/boot/efi)");
+        }
+        Ok(())
+    }
+
+    /// The GPT partition type.
+    fn typecode(&self) -> Option<&'static str> {
+        match self.mountpoint.as_deref()? {
+            "/" => Some(ROOT_PARTITION_TYPE),
+            "/boot/efi" => Some(ESP_PARTITION_TYPE),
+            "/var" => Some(VAR_PARTITION_TYPE),
+            "/var/home" => Some(HOME_PARTITION_TYPE),
+            _ => None,
+        }
+    }
+}
+
+impl PartitionConfig {
+    /// Load the configuration; it is JSON if the filename ends in `.json`, and
+    /// TOML otherwise.
+    #[context("Loading partition configuration {path}")]
+    pub(crate) fn load(path: &Utf8Path) -> Result<Self> {
+        let buf = std::fs::read_to_string(path)?;
+        if path.extension() == Some("json") {
+            Ok(serde_json::from_str(&buf)?)
+        } else {
+            Ok(toml::from_str(&buf)?)
+        }
+    }
+
+    /// Check the configuration, independently of the disk.
+    pub(crate) fn validate(&self, uses_efi: bool) -> Result<()> {
+        if self.partitions.is_empty() {
+            anyhow::bail!("No partitions specified");
+        }
+        let mut labels = HashSet::new();
+        let mut mountpoints = HashSet::new();
+        let mut grow = None;
+        for (i, p) in self.partitions.iter().enumerate() {
+            let name = || format!("Partition {} ({})", i + 1, p.label);
+            p.validate(uses_efi).with_context(name)?;
+            if !labels.insert(p.label.as_str()) {
+                anyhow::bail!("{}: Duplicate label", name());
+            }
+            if let Some(mountpoint) = p.mountpoint.as_deref() {
+                if !mountpoints.insert(mountpoint) {
+                    anyhow::bail!("{}: Duplicate mountpoint {mountpoint}", name());
+                }
+            }
+            if p.size == PartitionSize::Grow {
+                if let Some(other) = grow.replace(p.label.as_str()) {
+                    anyhow::bail!(
+                        "{}: Only one partition may grow, but {other} does too",
+                        name()
+                    );
+                }
+            }
+        }
+        if !mountpoints.contains("/") {
+            anyhow::bail!("No partition has mountpoint /");
+        }
+        if uses_efi && !mountpoints.contains("/boot/efi") {
+            anyhow::bail!("No partition has mountpoint /boot/efi (the ESP), which is required");
+        }
+        Ok(())
+    }
+
+    /// Validate the configuration and determine the partitions for a disk of
+    /// `disk_size` bytes.
+    pub(crate) fn plan(&self, disk_size: u64, uses_efi: bool) -> Result<Layout> {
+        self.validate(uses_efi)?;
+        let disk_mib = disk_size / (1024 * 1024);
+        let fixed: u64 = self
+            .partitions
+            .iter()
+            .filter_map(|p| match p.size {
+                PartitionSize::Fixed(s) => Some(s),
+                PartitionSize::Grow => None,
+            })
+            .sum();
+        let required = fixed + PARTITION_TABLE_MIB + RESERVED_PARTITION_MIB;
+        if required > disk_mib {
+            anyhow::bail!(
+                "The partitions require {required} MiB, but the disk has only {disk_mib} MiB"
+            );
+        }
+        let remaining = disk_mib - required;
+        let n = self.partitions.len();
+        let partitions = self
+            .partitions
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let size_mib = match p.size {
+                    PartitionSize::Fixed(s) => s,
+                    PartitionSize::Grow if remaining == 0 => anyhow::bail!(
+                        "Partition {} ({}): No space remains on the disk ({disk_mib} MiB) to grow into",
+                        i + 1,
+                        p.label
+                    ),
+                    PartitionSize::Grow => remaining,
+                };
+                Ok(PlannedPartition {
+                    // Partition 1 is architecture specific
+                    number: i as u32 + 2,
+                    spec: p.clone(),
+                    size_mib,
+                    grow_to_end: p.size == PartitionSize::Grow && i + 1 == n,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Layout { partitions })
+    }
+}
+
+impl PlannedPartition {
+    /// The `sgdisk` partition specification.
+    pub(crate) fn sgdisk_size(&self) -> String {
+        if self.grow_to_end {
+            "0:0".into()
+        } else {
+            format!("0:+{}M", self.size_mib)
+        }
+    }
+
+    pub(crate) fn typecode(&self) -> Option<&'static str> {
+        self.spec.typecode()
+    }
+}
+
+impl Layout {
+    fn find(&self, mountpoint: &str) -> Option<&PlannedPartition> {
+        self.partitions.iter().find(|p| p.spec.is(mountpoint))
+    }
+
+    pub(crate) fn root(&self) -> &PlannedPartition {
+        // SAFETY: Validated when planning
+        self.find("/").unwrap()
+    }
+
+    pub(crate) fn boot(&self) -> Option<&PlannedPartition> {
+        self.find("/boot")
+    }
+
+    pub(crate) fn esp(&self) -> Option<&PlannedPartition> {
+        self.find("/boot/efi")
+    }
+
+    /// Partitions other than the root, /boot and the ESP.
+    pub(crate) fn others(&self) -> impl Iterator<Item = &PlannedPartition> {
+        self.partitions
+            .iter()
+            .filter(|p| !["/", "/boot", "/boot/efi"].iter().any(|m| p.spec.is(m)))
+    }
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for p in &self.partitions {
+            let fs = p.spec.filesystem.map(|f| f.to_string());
+            write!(
+                f,
+                "  {} {}: {} MiB, {}",
+                p.number,
+                p.spec.label,
+                p.size_mib,
+                fs.as_deref().unwrap_or("unformatted")
+            )?;
+            if let Some(mountpoint) = p.spec.mountpoint.as_deref() {
+                write!(f, " on {mountpoint}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn fixture_config() -> PartitionConfig {
+    toml::from_str(
+        r#"
+[[partition]]
+label = "EFI-SYSTEM"
+size = "1G"
+filesystem = "vfat"
+mountpoint = "/boot/efi"
+
+[[partition]]
+label = "boot"
+size = "1G"
+filesystem = "ext4"
+mountpoint = "/boot"
+
+[[partition]]
+label = "root"
+size = "8G"
+filesystem = "xfs"
+mountpoint = "/"
+
+[[partition]]
+label = "var"
+size = "grow"
+filesystem = "xfs"
+mountpoint = "/var"
+
+[[partition]]
+label = "data"
+size = "2G"
+"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_validate() {
+    let valid = fixture_config();
+    valid.validate(true).unwrap();
+    let json = r#"{"partition": [{"label": "root", "size": "grow", "filesystem": "btrfs", "mountpoint": "/"}]}"#;
+    let c: PartitionConfig = serde_json::from_str(json).unwrap();
+    c.validate(false).unwrap();
+    assert_eq!(
+        c.validate(true).unwrap_err().to_string(),
+        "No partition has mountpoint /boot/efi (the ESP), which is required"
+    );
+
+    let modified = |i: usize, f: &dyn Fn(&mut PartitionSpec)| {
+        let mut c = valid.clone();
+        f(&mut c.partitions[i]);
+        format!("{:#}", c.validate(true).unwrap_err())
+    };
+    assert_eq!(
+        modified(3, &|p| p.mountpoint = Some("/srv".into())),
+        "Partition 4 (var): Unsupported mountpoint /srv; supported: /, /boot, /boot/efi, /var, /var/home, /var/lib/containers, /var/log"
+    );
+    assert_eq!(
+        modified(3, &|p| p.mountpoint = Some("/".into())),
+        "Partition 4 (var): Duplicate mountpoint /"
+    );
+    assert_eq!(
+        modified(2, &|p| p.mountpoint = None),
+        "No partition has mountpoint /"
+    );
+    assert_eq!(
+        modified(4, &|p| p.size = PartitionSize::Grow),
+        "Partition 5 (data): Only one partition may grow, but var does too"
+    );
+    assert_eq!(
+        modified(4, &|p| p.label = "boot".into()),
+        "Partition 5 (boot): Duplicate label"
+    );
+    assert_eq!(
+        modified(0, &|p| p.filesystem = Some(PartitionFilesystem::Ext4)),
+        "Partition 1 (EFI-SYSTEM): The ESP must be vfat, not ext4"
+    );
+    assert_eq!(
+        modified(0, &|p| p.size = PartitionSize::Fixed(32)),
+        "Partition 1 (EFI-SYSTEM): The ESP must be at least 100 MiB"
+    );
+    assert_eq!(
+        modified(2, &|p| p.filesystem = Some(PartitionFilesystem::Vfat)),
+        "Partition 3 (root): vfat is only supported for the ESP (/boot/efi)"
+    );
+    assert_eq!(
+        modified(3, &|p| p.filesystem = None),
+        "Partition 4 (var): A filesystem is required for mountpoint /var"
+    );
+    assert_eq!(
+        modified(3, &|p| p.label = "variable-data".into()),
+        "Partition 4 (variable-data): The label must be at most 12 characters for xfs"
+    );
+    assert_eq!(
+        modified(4, &|p| p.size = PartitionSize::Fixed(0)),
+        "Partition 5 (data): The size must not be zero"
+    );
+
+    for invalid in [
+        "[[partition]]\nlabel = \"root\"\nsize = \"big\"\n",
+        "[[partition]]\nlabel = \"root\"\nsize = \"1G\"\nfilesystem = \"zfs\"\n",
+        "[[partition]]\nlabel = \"root\"\nsize = \"1G\"\ntype = \"linux\"\n",
+    ] {
+        assert!(toml::from_str::<PartitionConfig>(invalid).is_err());
+    }
+}
+
+#[test]
+fn test_plan() {
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let c = fixture_config();
+    let layout = c.plan(20 * GIB, true).unwrap();
+    assert_eq!(
+        layout.to_string(),
+        "  2 EFI-SYSTEM: 1024 MiB, vfat on /boot/efi
+  3 boot: 1024 MiB, ext4 on /boot
+  4 root: 8192 MiB, xfs on /
+  5 var: 8189 MiB, xfs on /var
+  6 data: 2048 MiB, unformatted
+"
+    );
+    // The growing partition isn't last, so it gets an explicit size
+    let sizes = layout
+        .partitions
+        .iter()
+        .map(|p| p.sgdisk_size())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        sizes,
+        ["0:+1024M", "0:+1024M", "0:+8192M", "0:+8189M", "0:+2048M"]
+    );
+    assert_eq!(layout.root().number, 4);
+    assert_eq!(layout.boot().unwrap().number, 3);
+    assert_eq!(layout.esp().unwrap().typecode(), Some(ESP_PARTITION_TYPE));
+    assert_eq!(
+        layout
+            .others()
+            .map(|p| p.spec.label.as_str())
+            .collect::<Vec<_>>(),
+        ["var", "data"]
+    );
+
+    // A larger disk just makes /var larger
+    let layout = c.plan(100 * GIB, true).unwrap();
+    assert_eq!(layout.partitions[3].size_mib, 90109);
+    // Exactly enough for the fixed partitions, but nothing to grow into
+    let fixed_mib = 1024 + 1024 + 8192 + 2048 + 3;
+    assert_eq!(
+        c.plan(fixed_mib * 1024 * 1024, true)
+            .unwrap_err()
+            .to_string(),
+        "Partition 4 (var): No space remains on the disk (12291 MiB) to grow into"
+    );
+    assert_eq!(
+        c.plan(10 * GIB, true).unwrap_err().to_string(),
+        "The partitions require 12291 MiB, but the disk has only 10240 MiB"
+    );
+
+    // Without a growing partition, the rest of the disk is unused
+    let mut c = c;
+    c.partitions.truncate(3);
+    let layout = c.plan(20 * GIB, true).unwrap();
+    assert_eq!(layout.partitions[2].sgdisk_size(), "0:+8192M");
+    // And one growing last uses the rest of the disk
+    c.partitions[2].size = PartitionSize::Grow;
+    let layout = c.plan(20 * GIB, true).unwrap();
+    assert_eq!(layout.partitions[2].sgdisk_size(), "0:0");
+    assert_eq!(layout.partitions[2].size_mib, 18429);
+    assert_eq!(layout.others().count(), 0);
+}
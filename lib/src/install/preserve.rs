@@ -0,0 +1,397 @@
+//! # Preserving data from the previous system
+//!
+//! `bootc install to-existing-root` leaves the files of the previous system in
+//! place (see `--replace=alongside`), but none of them are visible in the new
+//! system.  With `--preserve-home` and `--preserve PATH`, data is instead moved
+//! into the persistent `/var` of the new system, e.g. `/home` to `/var/home`.
+//! As both are on the same filesystem, this is a rename; a symlink is left
+//! in the old location so that the running system keeps working until reboot.
+
+use std::os::fd::AsFd;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+/// The maximum number of symbolic links followed when resolving a path
+const MAX_SYMLINKS: u32 = 40;
+
+/// A path to move into the installed system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Preserved {
+    /// The path as requested
+    pub(crate) path: Utf8PathBuf,
+    /// The path in the previous root, with symlinks resolved
+    source: Utf8PathBuf,
+    /// The path in the installed system, which is under `var`
+    dest: Utf8PathBuf,
+    /// The size of the data, and number of files, once moved
+    usage: Option<(u64, u64)>,
+}
+
+/// Resolve the symlinks in `path` within `root`, interpreting absolute links
+/// relative to it too.  Resolution stops at the toplevel `stop` directory, if
+/// given.
+fn resolve(root: &Dir, path: &Utf8Path, stop: Option<&str>) -> Result<Utf8PathBuf> {
+    let mut pending = path
+        .components()
+        .rev()
+        .map(|c| c.as_str().to_owned())
+        .collect::<Vec<_>>();
+    let mut r = Utf8PathBuf::new();
+    let mut links = 0;
+    let mut stopped = false;
+    while let Some(c) = pending.pop() {
+        match c.as_str() {
+            "/" | "." => continue,
+            ".." => {
+                r.pop();
+                continue;
+            }
+            c if r.as_str().is_empty() && stop == Some(c) => stopped = true,
+            _ => {}
+        }
+        let candidate = r.join(&c);
+        let is_symlink = !stopped
+            && root
+                .symlink_metadata_optional(&candidate)?
+                .is_some_and(|m| m.is_symlink());
+        if !is_symlink {
+            r = candidate;
+            continue;
+        }
+        links += 1;
+        if links > MAX_SYMLINKS {
+            anyhow::bail!("Too many levels of symbolic links in {path}");
+        }
+        let target = rustix::fs::readlinkat(root.as_fd(), candidate.as_std_path(), Vec::new())
+            .with_context(|| format!("Reading link {candidate}"))?;
+        let target = target
+            .into_string()
+            .map_err(|_| anyhow::anyhow!("Invalid non-UTF-8 link {candidate}"))?;
+        let target = Utf8Path::new(&target);
+        if target.is_absolute() {
+            r = Utf8PathBuf::new();
+        }
+        pending.extend(target.components().rev().map(|c| c.as_str().to_owned()));
+    }
+    Ok(r)
+}
+
+/// Determine where `path` in the previous system is stored in the installed
+/// system, whose root (i.e. the image) is `target_root`.  Only data under
+/// `/var` persists, but e.g. `/home` is a symlink to `/var/home`.
+fn map_path(target_root: &Dir, path: &Utf8Path) -> Result<Utf8PathBuf> {
+    if !path.is_absolute() {
+        anyhow::bail!("Not an absolute path: {path}");
+    }
+    let dest = resolve(target_root, path, Some("var"))?;
+    match dest.strip_prefix("var") {
+        Ok(rest) if !rest.as_str().is_empty() => Ok(dest),
+        _ => anyhow::bail!(
+            "{path} is not persistent in the installed system, as it is not under /var"
+        ),
+    }
+}
+
+/// Whether `dir` is an empty directory.
+fn is_empty_dir(dir: &Dir, path: &Utf8Path) -> Result<bool> {
+    Ok(dir.symlink_metadata(path)?.is_dir() && dir.read_dir(path)?.next().is_none())
+}
+
+/// Verify that `dest` in the installed system doesn't have any content.
+fn require_no_collision(var_root: &Dir, p: &Preserved) -> Result<()> {
+    if var_root.symlink_metadata_optional(&p.dest)?.is_some() && !is_empty_dir(var_root, &p.dest)? {
+        anyhow::bail!(
+            "Cannot preserve {}: /{} already has content in the installed system; \
+             remove it first, or omit this path",
+            p.path,
+            p.dest
+        );
+    }
+    Ok(())
+}
+
+/// The total size of the files in `path`, and their number; other
+/// filesystems are not included.
+fn disk_usage(dir: &Dir, path: &Utf8Path) -> Result<(u64, u64)> {
+    let meta = dir.symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok((meta.len(), 1));
+    }
+    let dev = meta.dev();
+    let (mut size, mut n) = (0, 0);
+    for ent in dir.read_dir(path)? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF-8 filename: {name:?}"))?;
+        let child = path.join(name);
+        let meta = ent.metadata()?;
+        if meta.is_dir() {
+            if meta.dev() != dev {
+                continue;
+            }
+            let (s, c) = disk_usage(dir, &child)?;
+            size += s;
+            n += c;
+        } else {
+            size += meta.len();
+            n += 1;
+        }
+    }
+    Ok((size, n))
+}
+
+/// Determine the paths to move from `old_root`, verifying that it's possible
+/// before anything is installed.  `var_root` contains the `var` directory of
+/// the installed system, if it already exists.
+#[context("Preparing to preserve data")]
+pub(crate) fn plan(
+    paths: &[Utf8PathBuf],
+    old_root: &Dir,
+    target_root: &Dir,
+    var_root: Option<&Dir>,
+) -> Result<Vec<Preserved>> {
+    let root_dev = old_root.dir_metadata()?.dev();
+    let mut r: Vec<Preserved> = Vec::new();
+    for path in paths {
+        if !path.is_absolute() {
+            anyhow::bail!("Not an absolute path: {path}");
+        }
+        let source = resolve(old_root, path, None)?;
+        if source.as_str().is_empty() || source.starts_with("ostree") || source.starts_with("boot")
+        {
+            anyhow::bail!("Cannot preserve {path}: it is part of the installation");
+        }
+        // Symlinks in the previous root were resolved, so e.g. a /home linking
+        // to /var/home is stored there in the installed system as well
+        let dest = map_path(target_root, &Utf8Path::new("/").join(&source))
+            .with_context(|| format!("Cannot preserve {path}"))?;
+        let meta = old_root
+            .symlink_metadata_optional(&source)?
+            .ok_or_else(|| anyhow::anyhow!("Cannot preserve {path}: not found"))?;
+        if meta.dev() != root_dev {
+            anyhow::bail!(
+                "Cannot preserve {path}: it is a separate filesystem; \
+                 instead, mount it at /{dest} in the installed system (e.g. via /etc/fstab)"
+            );
+        }
+        for other in r.iter() {
+            if source.starts_with(&other.source) || other.source.starts_with(&source) {
+                anyhow::bail!("Cannot preserve {path}: it overlaps with {}", other.path);
+            }
+        }
+        let p = Preserved {
+            path: path.clone(),
+            source,
+            dest,
+            usage: None,
+        };
+        if let Some(var_root) = var_root {
+            require_no_collision(var_root, &p)?;
+        }
+        r.push(p);
+    }
+    Ok(r)
+}
+
+/// Move the data into `var_root`, the directory containing the `var` of the
+/// installed system, leaving a symlink to it in the old location; `var_path`
+/// is the path of `var_root` relative to the previous root.
+#[context("Preserving data")]
+pub(crate) fn move_into(
+    preserved: &mut [Preserved],
+    old_root: &Dir,
+    var_root: &Dir,
+    var_path: &Utf8Path,
+    sepolicy: Option<&ostree_ext::ostree::SePolicy>,
+) -> Result<()> {
+    for p in preserved.iter_mut() {
+        require_no_collision(var_root, p)?;
+        p.usage = Some(disk_usage(old_root, &p.source)?);
+        // Relabel from the topmost directory we create
+        let mut top = p.dest.clone();
+        while let Some(parent) = top
+            .parent()
+            .filter(|d| !var_root.try_exists(d).unwrap_or(true))
+        {
+            top = parent.to_owned();
+        }
+        if let Some(parent) = p.dest.parent() {
+            var_root.create_dir_all(parent)?;
+        }
+        if var_root.try_exists(&p.dest)? {
+            var_root.remove_dir(&p.dest)?;
+        }
+        old_root
+            .rename(&p.source, var_root, &p.dest)
+            .with_context(|| format!("Moving {} to /{}", p.source, p.dest))?;
+        // SAFETY: The source can't be the root
+        let depth = p.source.parent().unwrap().components().count();
+        let link = std::iter::repeat("..")
+            .take(depth)
+            .collect::<Utf8PathBuf>()
+            .join(var_path)
+            .join(&p.dest);
+        old_root
+            .symlink(&link, &p.source)
+            .with_context(|| format!("Linking {}", p.source))?;
+        if let Some(policy) = sepolicy {
+            let mut progress = crate::progress::ProgressReporter::tty_or_noop();
+            progress.set_phase(format!("Relabeling /{top}"), None);
+            crate::lsm::ensure_dir_labeled_recurse(var_root, &mut top, policy, None, &mut progress)
+                .with_context(|| format!("Recursive SELinux relabeling of /{top}"))?;
+            progress.finish();
+        }
+    }
+    Ok(())
+}
+
+/// Print what was preserved.
+pub(crate) fn print_summary(preserved: &[Preserved]) {
+    println!("Preserved data:");
+    for p in preserved {
+        let usage = p
+            .usage
+            .map(|(size, n)| format!(" ({} in {n} files)", ostree_ext::glib::format_size(size)))
+            .unwrap_or_default();
+        println!("  {} -> /{}{usage}", p.path, p.dest);
+    }
+}
+
+#[cfg(test)]
+fn fixture() -> Result<(cap_std_ext::cap_tempfile::TempDir, Dir, Dir, Dir)> {
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    // The previous system, where /home is a directory
+    td.create_dir_all("old/home/alice/.config")?;
+    td.write("old/home/alice/notes.txt", "hello")?;
+    td.write("old/home/alice/.config/app.conf", "key=value\n")?;
+    td.create_dir_all("old/srv/www")?;
+    td.write("old/srv/www/index.html", "<html/>")?;
+    td.create_dir_all("old/var/lib/app")?;
+    // An absolute link, which cap-std doesn't create
+    rustix::fs::symlinkat("/var/lib/app", td.as_fd(), "old/opt-app")?;
+    // The image, as usual for ostree systems
+    td.create_dir_all("image/var")?;
+    td.create_dir_all("image/usr/etc")?;
+    td.symlink("var/home", "image/home")?;
+    td.symlink("var/roothome", "image/root")?;
+    td.symlink("var/srv", "image/srv")?;
+    td.symlink("../var/usrlocal", "image/usr/local")?;
+    // The stateroot
+    td.create_dir_all("stateroot/var/lib")?;
+    let old = td.open_dir("old")?;
+    let image = td.open_dir("image")?;
+    let stateroot = td.open_dir("stateroot")?;
+    Ok((td, old, image, stateroot))
+}
+
+#[test]
+fn test_map_path() -> Result<()> {
+    let (_td, _, image, _) = fixture()?;
+    for (path, expected) in [
+        ("/home", "var/home"),
+        ("/home/alice/", "var/home/alice"),
+        ("/root", "var/roothome"),
+        ("/usr/local/bin", "var/usrlocal/bin"),
+        ("/root/.ssh", "var/roothome/.ssh"),
+        ("/home/alice/../bob", "var/home/bob"),
+        ("/var/lib/app", "var/lib/app"),
+    ] {
+        assert_eq!(map_path(&image, path.into())?, expected, "{path}");
+    }
+    for (path, expected) in [
+        (
+            "/usr/lib/app",
+            "/usr/lib/app is not persistent in the installed system, as it is not under /var",
+        ),
+        (
+            "/var",
+            "/var is not persistent in the installed system, as it is not under /var",
+        ),
+        ("home", "Not an absolute path: home"),
+    ] {
+        assert_eq!(
+            map_path(&image, path.into()).unwrap_err().to_string(),
+            expected
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_preserve() -> Result<()> {
+    let (td, old, image, stateroot) = fixture()?;
+
+    // Symlinks in the previous root are followed, too
+    let paths = ["/home", "/srv/www", "/opt-app"].map(Utf8PathBuf::from);
+    let mut preserved = plan(&paths, &old, &image, Some(&stateroot))?;
+    assert_eq!(
+        preserved
+            .iter()
+            .map(|p| (p.source.as_str(), p.dest.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            ("home", "var/home"),
+            ("srv/www", "var/srv/www"),
+            ("var/lib/app", "var/lib/app")
+        ]
+    );
+    move_into(
+        &mut preserved,
+        &old,
+        &stateroot,
+        "ostree/deploy/default".into(),
+        None,
+    )?;
+    assert_eq!(
+        stateroot.read_to_string("var/home/alice/.config/app.conf")?,
+        "key=value\n"
+    );
+    assert_eq!(
+        stateroot.read_to_string("var/srv/www/index.html")?,
+        "<html/>"
+    );
+    assert!(stateroot.try_exists("var/lib/app")?);
+    assert_eq!(
+        td.read_link("old/home")?.to_str(),
+        Some("ostree/deploy/default/var/home")
+    );
+    assert_eq!(
+        td.read_link("old/srv/www")?.to_str(),
+        Some("../ostree/deploy/default/var/srv/www")
+    );
+    assert_eq!(preserved[0].usage, Some((15, 2)));
+    assert_eq!(preserved[1].usage, Some((7, 1)));
+
+    // Overlapping and missing paths
+    let (_td, old, image, stateroot) = fixture()?;
+    for (paths, expected) in [
+        (
+            &["/home", "/home/alice"][..],
+            "Cannot preserve /home/alice: it overlaps with /home",
+        ),
+        (&["/home/bob"], "Cannot preserve /home/bob: not found"),
+    ] {
+        let paths = paths.iter().map(Utf8PathBuf::from).collect::<Vec<_>>();
+        let e = plan(&paths, &old, &image, Some(&stateroot)).unwrap_err();
+        assert_eq!(e.root_cause().to_string(), expected);
+    }
+
+    // Empty directories in the installed system are replaced, but not content
+    stateroot.create_dir_all("var/home")?;
+    let paths = [Utf8PathBuf::from("/home")];
+    plan(&paths, &old, &image, Some(&stateroot))?;
+    stateroot.create_dir_all("var/home/core")?;
+    let e = plan(&paths, &old, &image, Some(&stateroot)).unwrap_err();
+    assert_eq!(
+        e.root_cause().to_string(),
+        "Cannot preserve /home: /var/home already has content in the installed system; \
+         remove it first, or omit this path"
+    );
+    Ok(())
+}
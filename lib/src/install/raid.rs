@@ -0,0 +1,419 @@
+//! # Software RAID for `bootc install to-disk`
+//!
+//! With `--block-setup raid1`, `/boot` and the root filesystem are mirrored
+//! across two or more disks via mdraid.  Every disk gets the same partition
+//! table, including its own BIOS boot partition or ESP, and the bootloader is
+//! installed to each of them so that the system still boots if either fails.
+//! The `/boot` array uses metadata 1.0, which is stored at the end of the
+//! members, so that firmware and the bootloader can read each member as a
+//! plain filesystem.
+//!
+//! The arrays are assembled by the initramfs (which is part of the image, so
+//! it must include the mdraid dracut module) via `rd.md.uuid` kernel arguments.
+
+use std::fmt::Display;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use fn_error_context::context;
+
+use super::baseline::{BOOTPN, BOOTPN_SIZE_MB, EFIPN_SIZE_MB};
+use super::partitions::{PARTITION_TABLE_MIB, RESERVED_PARTITION_MIB};
+use crate::task::Task;
+
+/// The partition type of RAID members, per the Discoverable Partitions Specification
+pub(crate) const RAID_PARTITION_TYPE: &str = "A19D880F-05FC-4D3B-A006-743F0F84911E";
+/// The smallest disk we install to.
+const MIN_DISK_MIB: u64 = 4096;
+
+/// A disk the arrays are created on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RaidMember {
+    pub(crate) device: Utf8PathBuf,
+    size_mib: u64,
+    /// The ESP of this disk, once it has been created
+    pub(crate) esp: Option<String>,
+}
+
+/// An mdraid array to create.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RaidArray {
+    /// The name of the array, i.e. `/dev/md/<name>`
+    pub(crate) name: &'static str,
+    /// The array UUID, in the format used by mdadm
+    uuid: String,
+    metadata: &'static str,
+    /// The partition number of the members on each disk
+    pub(crate) partno: u32,
+}
+
+/// The arrays to create, and the disks they span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RaidPlan {
+    /// The disks; the first is the one given as the target device
+    pub(crate) members: Vec<RaidMember>,
+    pub(crate) boot: RaidArray,
+    pub(crate) root: RaidArray,
+    root_size_mib: u64,
+}
+
+/// Format a UUID as mdadm does, e.g. `0a1b2c3d:...`.
+fn mdadm_uuid(u: uuid::Uuid) -> String {
+    let s = u.simple().to_string();
+    [&s[0..8], &s[8..16], &s[16..24], &s[24..32]].join(":")
+}
+
+impl RaidArray {
+    fn path(&self) -> String {
+        format!("/dev/md/{}", self.name)
+    }
+}
+
+impl RaidPlan {
+    /// Plan the arrays given the disks and their sizes in bytes; the root
+    /// member uses the space remaining on the smallest disk by default.
+    pub(crate) fn new(
+        devices: &[(Utf8PathBuf, u64)],
+        root_size_mib: Option<u64>,
+        uses_efi: bool,
+        new_uuid: impl Fn() -> uuid::Uuid,
+    ) -> Result<Self> {
+        if devices.len() < 2 {
+            anyhow::bail!("RAID1 requires at least two devices; use --device to add more");
+        }
+        let mut members: Vec<RaidMember> = Vec::new();
+        for (device, size) in devices {
+            if members.iter().any(|m| &m.device == device) {
+                anyhow::bail!("Device {device} is given more than once");
+            }
+            let size_mib = size / (1024 * 1024);
+            if size_mib < MIN_DISK_MIB {
+                anyhow::bail!(
+                    "Device {device} is too small ({size_mib} MiB); at least {MIN_DISK_MIB} MiB is required"
+                );
+            }
+            members.push(RaidMember {
+                device: device.clone(),
+                size_mib,
+                esp: None,
+            });
+        }
+        let esp_mib = if uses_efi { EFIPN_SIZE_MB.into() } else { 0 };
+        let overhead =
+            PARTITION_TABLE_MIB + RESERVED_PARTITION_MIB + esp_mib + u64::from(BOOTPN_SIZE_MB);
+        // SAFETY: There are at least two members
+        let smallest = members.iter().min_by_key(|m| m.size_mib).unwrap();
+        let available = smallest.size_mib - overhead;
+        let root_size_mib = root_size_mib.unwrap_or(available);
+        if root_size_mib > available {
+            anyhow::bail!(
+                "The root size of {root_size_mib} MiB does not fit on {} ({available} MiB available)",
+                smallest.device
+            );
+        }
+        Ok(Self {
+            members,
+            boot: RaidArray {
+                name: "boot",
+                uuid: mdadm_uuid(new_uuid()),
+                metadata: "1.0",
+                partno: BOOTPN,
+            },
+            root: RaidArray {
+                name: "root",
+                uuid: mdadm_uuid(new_uuid()),
+                metadata: "1.2",
+                partno: BOOTPN + 1,
+            },
+            root_size_mib,
+        })
+    }
+
+    /// The arrays, in the order they're created.
+    pub(crate) fn arrays(&self) -> impl Iterator<Item = &RaidArray> {
+        [&self.boot, &self.root].into_iter()
+    }
+
+    /// The size of the root member partition, as passed to sgdisk.
+    pub(crate) fn root_partition_size(&self) -> String {
+        format!("0:+{}M", self.root_size_mib)
+    }
+
+    fn create_args(&self, array: &RaidArray, members: &[String]) -> Vec<String> {
+        [
+            "--create".to_string(),
+            array.path(),
+            "--run".into(),
+            "--level=1".into(),
+            format!("--raid-devices={}", members.len()),
+            format!("--metadata={}", array.metadata),
+            format!("--uuid={}", array.uuid),
+            format!("--name={}", array.name),
+            // Otherwise the array is named after the host we're installing from
+            "--homehost=any".into(),
+        ]
+        .into_iter()
+        .chain(members.iter().cloned())
+        .collect()
+    }
+
+    /// Verify that none of the arrays already exist on this host.
+    pub(crate) fn require_unused(&self) -> Result<()> {
+        for array in self.arrays() {
+            let path = array.path();
+            if Utf8Path::new(&path).try_exists()? {
+                anyhow::bail!("An array {path} already exists");
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the array from the given member partitions, returning the path
+    /// to the array device within `devdir`.
+    #[context("Creating {} array", array.name)]
+    pub(crate) fn create(
+        &self,
+        array: &RaidArray,
+        members: &[String],
+        devdir: &Utf8Path,
+    ) -> Result<String> {
+        Task::new(&format!("Creating {} RAID1 array", array.name), "mdadm")
+            .args(self.create_args(array, members))
+            .verbose()
+            .quiet_output()
+            .run()?;
+        // This is a symlink to the kernel name, e.g. md127
+        let path = array.path();
+        let dev = std::fs::canonicalize(&path).with_context(|| format!("Resolving {path}"))?;
+        let name = dev
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid device {dev:?}"))?;
+        Ok(devdir.join(name).to_string())
+    }
+
+    /// Install the bootloader to each disk but the first, temporarily
+    /// mounting its ESP in place of the first one, if applicable.
+    #[context("Installing bootloader to mirrors")]
+    pub(crate) fn install_bootloader(
+        &self,
+        rootfs: &Utf8Path,
+        configopts: &super::InstallConfigOpts,
+    ) -> Result<()> {
+        let efi = &rootfs.join("boot").join(crate::bootloader::EFI_DIR);
+        let primary_esp = self.members[0].esp.as_deref();
+        for member in self.members.iter().skip(1) {
+            if let Some(esp) = member.esp.as_deref() {
+                Task::new_and_run("Unmounting ESP", "umount", [efi.as_str()])?;
+                crate::mount::mount(esp, efi)?;
+            }
+            crate::bootloader::install_via_bootupd(&member.device, rootfs, configopts)?;
+        }
+        if let Some(esp) = primary_esp {
+            Task::new_and_run("Unmounting ESP", "umount", [efi.as_str()])?;
+            crate::mount::mount(esp, efi)?;
+        }
+        Ok(())
+    }
+
+    /// Stop the arrays, in the reverse order of their creation.
+    pub(crate) fn stop(&self) -> Result<()> {
+        for array in [&self.root, &self.boot] {
+            Task::new_and_run(
+                format!("Stopping {} RAID1 array", array.name),
+                "mdadm",
+                ["--stop", &array.path()],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The kernel arguments for the initramfs to assemble the arrays.
+    pub(crate) fn kargs(&self) -> Vec<String> {
+        self.arrays()
+            .map(|a| format!("rd.md.uuid={}", a.uuid))
+            .collect()
+    }
+
+    /// The contents of `/etc/mdadm.conf`.
+    pub(crate) fn mdadm_conf(&self) -> String {
+        self.arrays()
+            .map(|a| {
+                format!(
+                    "ARRAY {} metadata={} UUID={}\n",
+                    a.path(),
+                    a.metadata,
+                    a.uuid
+                )
+            })
+            .collect()
+    }
+
+    /// Print the resulting array layout.
+    pub(crate) fn print_summary(&self) {
+        print!("{self}");
+        println!("Kernel arguments: {}", self.kargs().join(" "));
+    }
+}
+
+impl Display for RaidPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let devices = self
+            .members
+            .iter()
+            .map(|m| m.device.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(f, "RAID1 arrays (/etc/mdadm.conf):")?;
+        for a in self.arrays() {
+            writeln!(
+                f,
+                "  {}: partition {} of {devices}, UUID={}",
+                a.path(),
+                a.partno,
+                a.uuid
+            )?;
+        }
+        let smallest = self.members.iter().map(|m| m.size_mib).min().unwrap_or(0);
+        for m in self.members.iter().filter(|m| m.size_mib > smallest) {
+            writeln!(
+                f,
+                "  {}: {} MiB unused, as the disks differ in size",
+                m.device,
+                m.size_mib - smallest
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Verify that the image can assemble arrays, given the path to its root.
+#[context("Checking for mdraid support")]
+pub(crate) fn require_mdadm(root: &Path) -> Result<()> {
+    for (path, what) in [
+        ("usr/sbin/mdadm", "mdadm"),
+        (
+            "usr/lib/dracut/modules.d/90mdraid",
+            "the mdraid dracut module",
+        ),
+    ] {
+        if !root.join(path).try_exists()? {
+            anyhow::bail!("RAID1 requires {what} in the image (and its initramfs)");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_raid_plan() -> Result<()> {
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let uuids = std::cell::Cell::new(0u128);
+    let new_uuid = || {
+        uuids.set(uuids.get() + 1);
+        uuid::Uuid::from_u128(uuids.get())
+    };
+    let dev = |d: &str, size| (Utf8PathBuf::from(d), size);
+
+    let plan = RaidPlan::new(
+        &[dev("/dev/vda", 20 * GIB), dev("/dev/vdb", 20 * GIB)],
+        None,
+        true,
+        new_uuid,
+    )?;
+    // The remainder after the partition table, BIOS boot, ESP and /boot
+    assert_eq!(plan.root_partition_size(), "0:+19455M");
+    assert_eq!((plan.boot.partno, plan.root.partno), (3, 4));
+    assert_eq!(
+        plan.kargs(),
+        [
+            "rd.md.uuid=00000000:00000000:00000000:00000001",
+            "rd.md.uuid=00000000:00000000:00000000:00000002"
+        ]
+    );
+    assert_eq!(
+        plan.mdadm_conf(),
+        "ARRAY /dev/md/boot metadata=1.0 UUID=00000000:00000000:00000000:00000001\n\
+ARRAY /dev/md/root metadata=1.2 UUID=00000000:00000000:00000000:00000002\n"
+    );
+    assert_eq!(
+        plan.create_args(&plan.boot, &["/dev/vda3".into(), "/dev/vdb3".into()])
+            .join(" "),
+        "--create /dev/md/boot --run --level=1 --raid-devices=2 --metadata=1.0 \
+--uuid=00000000:00000000:00000000:00000001 --name=boot --homehost=any /dev/vda3 /dev/vdb3"
+    );
+    assert_eq!(
+        plan.to_string(),
+        "RAID1 arrays (/etc/mdadm.conf):
+  /dev/md/boot: partition 3 of /dev/vda, /dev/vdb, UUID=00000000:00000000:00000000:00000001
+  /dev/md/root: partition 4 of /dev/vda, /dev/vdb, UUID=00000000:00000000:00000000:00000002
+"
+    );
+
+    // Disks of different sizes are limited by the smallest one
+    let plan = RaidPlan::new(
+        &[dev("/dev/vda", 30 * GIB), dev("/dev/vdb", 20 * GIB)],
+        None,
+        false,
+        new_uuid,
+    )?;
+    assert_eq!(plan.root_partition_size(), "0:+19967M");
+    assert!(plan
+        .to_string()
+        .ends_with("  /dev/vda: 10240 MiB unused, as the disks differ in size\n"));
+    let plan = RaidPlan::new(
+        &[dev("/dev/vda", 30 * GIB), dev("/dev/vdb", 20 * GIB)],
+        Some(8192),
+        false,
+        new_uuid,
+    )?;
+    assert_eq!(plan.root_partition_size(), "0:+8192M");
+
+    // Invalid setups
+    for (devices, root_size, expected) in [
+        (
+            vec![dev("/dev/vda", 20 * GIB)],
+            None,
+            "RAID1 requires at least two devices; use --device to add more",
+        ),
+        (
+            vec![dev("/dev/vda", 20 * GIB), dev("/dev/vda", 20 * GIB)],
+            None,
+            "Device /dev/vda is given more than once",
+        ),
+        (
+            vec![dev("/dev/vda", 20 * GIB), dev("/dev/vdb", 2 * GIB)],
+            None,
+            "Device /dev/vdb is too small (2048 MiB); at least 4096 MiB is required",
+        ),
+        (
+            vec![dev("/dev/vda", 30 * GIB), dev("/dev/vdb", 20 * GIB)],
+            Some(20480),
+            "The root size of 20480 MiB does not fit on /dev/vdb (19455 MiB available)",
+        ),
+    ] {
+        let e = RaidPlan::new(&devices, root_size, true, new_uuid).unwrap_err();
+        assert_eq!(e.to_string(), expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_require_mdadm() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let e = require_mdadm(td.path()).unwrap_err();
+    assert_eq!(
+        format!("{e:#}"),
+        "Checking for mdraid support: RAID1 requires mdadm in the image (and its initramfs)"
+    );
+    std::fs::create_dir_all(td.path().join("usr/sbin"))?;
+    std::fs::write(td.path().join("usr/sbin/mdadm"), "")?;
+    let e = require_mdadm(td.path()).unwrap_err();
+    assert_eq!(
+        e.root_cause().to_string(),
+        "RAID1 requires the mdraid dracut module in the image (and its initramfs)"
+    );
+    std::fs::create_dir_all(td.path().join("usr/lib/dracut/modules.d/90mdraid"))?;
+    require_mdadm(td.path())?;
+    Ok(())
+}
@@ -0,0 +1,439 @@
+//! # Secure Boot preflight check
+//!
+//! When the system doing the installation has Secure Boot enabled, a bootloader
+//! that is not signed (or whose signature will be revoked via SBAT) results in a
+//! system that installs fine but then fails to boot.  Before making any changes,
+//! we inspect the EFI binaries shipped in the image and either fail or warn,
+//! depending on the `secureboot-check` install configuration.
+//!
+//! This only checks for the *presence* of an Authenticode signature and of SBAT
+//! metadata; verifying signatures against the firmware databases is left to
+//! the firmware.
+
+use std::io::Read;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The `SecureBoot` variable in the EFI global variable namespace
+const SECUREBOOT_VAR: &str = "SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+/// Directories holding the bootloader payload, relative to the image root
+const BOOTLOADER_DIRS: &[&str] = &["usr/lib/bootupd/updates/EFI", "usr/lib/ostree-boot/efi/EFI"];
+/// Unified kernel images live alongside the kernel
+const MODULES_DIR: &str = "usr/lib/modules";
+/// We only need the headers and section table, which are at the start of the file
+const HEADER_READ_SIZE: u64 = 64 * 1024;
+/// Index of the certificate table in the optional header data directories
+const SECURITY_DIRECTORY: u32 = 4;
+/// Size of a `WIN_CERTIFICATE` header
+const WIN_CERTIFICATE_HEADER_SIZE: u32 = 8;
+/// Size of a section header
+const SECTION_HEADER_SIZE: usize = 40;
+
+/// How to handle EFI binaries which would fail to boot with Secure Boot enabled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SecureBootCheck {
+    /// Fail the installation
+    #[default]
+    Enforce,
+    /// Print a warning and continue
+    Warn,
+}
+
+/// The role of an EFI binary in the boot chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PayloadKind {
+    Shim,
+    Grub,
+    Uki,
+    /// Other binaries, e.g. MokManager or the fallback loader
+    Other,
+}
+
+impl PayloadKind {
+    fn from_path(path: &Utf8Path) -> Self {
+        let name = path.file_name().unwrap_or_default().to_ascii_lowercase();
+        if path.starts_with(MODULES_DIR) {
+            Self::Uki
+        } else if name.starts_with("shim") {
+            Self::Shim
+        } else if name.starts_with("grub") {
+            Self::Grub
+        } else {
+            Self::Other
+        }
+    }
+
+    /// shim revokes grub and UKIs (and is itself revoked) via SBAT, so these
+    /// must carry SBAT metadata to be accepted.
+    fn requires_sbat(&self) -> bool {
+        !matches!(self, Self::Other)
+    }
+}
+
+/// The parts of a PE binary relevant for Secure Boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PeInfo {
+    /// Whether the binary has an embedded Authenticode signature
+    signed: bool,
+    /// Whether the binary has a `.sbat` section
+    sbat: bool,
+}
+
+#[derive(Debug)]
+struct Payload {
+    path: Utf8PathBuf,
+    kind: PayloadKind,
+    /// The parsed PE headers, or why they could not be parsed
+    info: std::result::Result<PeInfo, String>,
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Result<u16> {
+    buf.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| anyhow::anyhow!("Truncated header at offset {offset}"))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| anyhow::anyhow!("Truncated header at offset {offset}"))
+}
+
+/// Parse the headers at the start of a PE binary of total size `len`.
+fn inspect_pe(head: &[u8], len: u64) -> Result<PeInfo> {
+    if head.get(0..2) != Some(b"MZ") {
+        anyhow::bail!("Missing MZ header");
+    }
+    let pe = read_u32(head, 0x3c)? as usize;
+    if head.get(pe..pe + 4) != Some(b"PE\0\0") {
+        anyhow::bail!("Missing PE signature");
+    }
+    let coff = pe + 4;
+    let n_sections = read_u16(head, coff + 2)? as usize;
+    let optional_header_size = read_u16(head, coff + 16)? as usize;
+    let opt = coff + 20;
+    // The offsets of NumberOfRvaAndSizes and the data directories
+    let (n_dirs_offset, dirs_offset) = match read_u16(head, opt)? {
+        0x10b => (92, 96),
+        0x20b => (108, 112),
+        o => anyhow::bail!("Unknown optional header magic {o:#x}"),
+    };
+    let n_dirs = read_u32(head, opt + n_dirs_offset)?;
+    let signed = if n_dirs > SECURITY_DIRECTORY {
+        let dir = opt + dirs_offset + SECURITY_DIRECTORY as usize * 8;
+        // Unlike the other directories, this is a file offset rather than an RVA
+        let cert_offset = read_u32(head, dir)?;
+        let cert_size = read_u32(head, dir + 4)?;
+        cert_offset != 0
+            && cert_size > WIN_CERTIFICATE_HEADER_SIZE
+            && u64::from(cert_offset) + u64::from(cert_size) <= len
+    } else {
+        false
+    };
+    let sections = opt + optional_header_size;
+    let mut sbat = false;
+    for i in 0..n_sections {
+        let start = sections + i * SECTION_HEADER_SIZE;
+        let name = head
+            .get(start..start + 8)
+            .ok_or_else(|| anyhow::anyhow!("Truncated section table"))?;
+        if name == b".sbat\0\0\0" {
+            sbat = true;
+        }
+    }
+    Ok(PeInfo { signed, sbat })
+}
+
+#[context("Inspecting {path}")]
+fn inspect_file(root: &Dir, path: &Utf8Path) -> Result<PeInfo> {
+    let f = root.open(path)?;
+    let len = f.metadata()?.len();
+    let mut head = Vec::new();
+    f.take(HEADER_READ_SIZE).read_to_end(&mut head)?;
+    inspect_pe(&head, len)
+}
+
+fn is_efi_binary(name: &str) -> bool {
+    Utf8Path::new(name)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("efi"))
+}
+
+/// Recursively find EFI binaries in `path`.
+fn find_efi_binaries(root: &Dir, path: &Utf8Path, out: &mut Vec<Utf8PathBuf>) -> Result<()> {
+    for ent in root.read_dir(path)? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let ty = ent.file_type()?;
+        if ty.is_dir() {
+            find_efi_binaries(root, &path.join(name), out)?;
+        } else if ty.is_file() && is_efi_binary(name) {
+            out.push(path.join(name));
+        }
+    }
+    Ok(())
+}
+
+/// Find and inspect the bootloader binaries and unified kernel images in the image.
+#[context("Finding EFI binaries")]
+fn find_payloads(root: &Dir) -> Result<Vec<Payload>> {
+    let mut paths = Vec::new();
+    for dir in BOOTLOADER_DIRS {
+        if root.try_exists(dir)? {
+            find_efi_binaries(root, Utf8Path::new(dir), &mut paths)?;
+        }
+    }
+    if root.try_exists(MODULES_DIR)? {
+        for ent in root.read_dir(MODULES_DIR)? {
+            let ent = ent?;
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+            let name = ent.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let kdir = Utf8Path::new(MODULES_DIR).join(name);
+            for ent in root.read_dir(&kdir)? {
+                let ent = ent?;
+                let name = ent.file_name();
+                if let Some(name) = name.to_str() {
+                    if ent.file_type()?.is_file() && is_efi_binary(name) {
+                        paths.push(kdir.join(name));
+                    }
+                }
+            }
+        }
+    }
+    paths.sort();
+    let payloads = paths
+        .into_iter()
+        .map(|path| {
+            let kind = PayloadKind::from_path(&path);
+            let info = inspect_file(root, &path).map_err(|e| format!("{e:#}"));
+            Payload { path, kind, info }
+        })
+        .collect();
+    Ok(payloads)
+}
+
+/// Parse the contents of the `SecureBoot` EFI variable; the first four bytes
+/// are the variable attributes.
+fn parse_secureboot_var(buf: &[u8]) -> Result<bool> {
+    match buf {
+        [_, _, _, _, v] => Ok(*v == 1),
+        _ => anyhow::bail!("Invalid SecureBoot variable of length {}", buf.len()),
+    }
+}
+
+/// Whether the firmware has Secure Boot enabled; `None` if this is not an EFI system.
+#[context("Querying Secure Boot state")]
+fn secureboot_enabled() -> Result<Option<bool>> {
+    let efivars = Utf8Path::new(super::EFIVARFS);
+    if !efivars.try_exists()? {
+        return Ok(None);
+    }
+    let efivars = Dir::open_ambient_dir(efivars, cap_std::ambient_authority())?;
+    let Some(mut f) = efivars.open_optional(SECUREBOOT_VAR)? else {
+        return Ok(Some(false));
+    };
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    parse_secureboot_var(&buf).map(Some)
+}
+
+/// Return a description of each problem which would stop the payloads from
+/// booting with Secure Boot enabled.
+fn find_problems(payloads: &[Payload]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for payload in payloads {
+        let path = &payload.path;
+        match &payload.info {
+            Ok(info) => {
+                if !info.signed {
+                    problems.push(format!("{path}: not signed"));
+                }
+                if payload.kind.requires_sbat() && !info.sbat {
+                    problems.push(format!("{path}: missing SBAT metadata"));
+                }
+            }
+            Err(e) => problems.push(format!("{path}: not a valid EFI binary: {e}")),
+        }
+    }
+    problems
+}
+
+/// Verify that the bootloader payload in `root` can boot with Secure Boot enabled,
+/// if it is enabled.
+#[context("Secure Boot preflight check")]
+pub(crate) fn preflight(root: &Dir, mode: SecureBootCheck) -> Result<()> {
+    match secureboot_enabled()? {
+        Some(true) => {}
+        Some(false) => {
+            tracing::debug!("Secure Boot is disabled");
+            return Ok(());
+        }
+        None => {
+            tracing::debug!("Not an EFI system, skipping Secure Boot check");
+            return Ok(());
+        }
+    }
+    let payloads = find_payloads(root)?;
+    if payloads.is_empty() {
+        eprintln!("warning: Secure Boot is enabled, but no EFI binaries were found to verify");
+        return Ok(());
+    }
+    let problems = find_problems(&payloads);
+    if problems.is_empty() {
+        tracing::debug!("Verified {} EFI binaries", payloads.len());
+        return Ok(());
+    }
+    let problems = problems.join("\n  ");
+    match mode {
+        SecureBootCheck::Enforce => {
+            anyhow::bail!(
+                "Secure Boot is enabled, but the installed system would fail to boot:\n  {problems}\n\
+                 Use --skip-secureboot-check or set secureboot-check = \"warn\" to proceed anyway"
+            )
+        }
+        SecureBootCheck::Warn => {
+            eprintln!("warning: Secure Boot is enabled, but the installed system may fail to boot:\n  {problems}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn fake_pe(
+    pe32plus: bool,
+    sections: &[&str],
+    signature: Option<(u32, u32)>,
+    len: usize,
+) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    buf[0..2].copy_from_slice(b"MZ");
+    let pe = 0x80usize;
+    buf[0x3c..0x40].copy_from_slice(&(pe as u32).to_le_bytes());
+    buf[pe..pe + 4].copy_from_slice(b"PE\0\0");
+    let coff = pe + 4;
+    let (magic, optional_header_size, n_dirs_offset, dirs_offset) = if pe32plus {
+        (0x20bu16, 240u16, 108, 112)
+    } else {
+        (0x10b, 224, 92, 96)
+    };
+    buf[coff + 2..coff + 4].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+    buf[coff + 16..coff + 18].copy_from_slice(&optional_header_size.to_le_bytes());
+    let opt = coff + 20;
+    buf[opt..opt + 2].copy_from_slice(&magic.to_le_bytes());
+    buf[opt + n_dirs_offset..opt + n_dirs_offset + 4].copy_from_slice(&16u32.to_le_bytes());
+    if let Some((offset, size)) = signature {
+        let dir = opt + dirs_offset + 4 * 8;
+        buf[dir..dir + 4].copy_from_slice(&offset.to_le_bytes());
+        buf[dir + 4..dir + 8].copy_from_slice(&size.to_le_bytes());
+    }
+    let mut section = opt + optional_header_size as usize;
+    for name in sections {
+        buf[section..section + name.len()].copy_from_slice(name.as_bytes());
+        section += SECTION_HEADER_SIZE;
+    }
+    buf
+}
+
+#[test]
+fn test_inspect_pe() {
+    for pe32plus in [false, true] {
+        let buf = fake_pe(pe32plus, &[".text", ".sbat"], Some((0x800, 0x100)), 0x1000);
+        let info = inspect_pe(&buf, buf.len() as u64).unwrap();
+        assert_eq!(
+            info,
+            PeInfo {
+                signed: true,
+                sbat: true
+            }
+        );
+        let buf = fake_pe(pe32plus, &[".text"], None, 0x1000);
+        let info = inspect_pe(&buf, buf.len() as u64).unwrap();
+        assert_eq!(
+            info,
+            PeInfo {
+                signed: false,
+                sbat: false
+            }
+        );
+    }
+    // A certificate table pointing past the end of the file
+    let buf = fake_pe(true, &[".sbat"], Some((0x800, 0x1000)), 0x1000);
+    assert!(!inspect_pe(&buf, buf.len() as u64).unwrap().signed);
+    // Only the headers were read, but the file is large enough
+    assert!(inspect_pe(&buf[..0x400], 0x2000).unwrap().signed);
+    // An empty certificate table
+    let buf = fake_pe(true, &[], Some((0x800, 0)), 0x1000);
+    assert!(!inspect_pe(&buf, buf.len() as u64).unwrap().signed);
+    // Not a PE binary at all
+    assert!(inspect_pe(b"#!/bin/sh\n", 10).is_err());
+    let mut buf = fake_pe(true, &[], None, 0x1000);
+    buf[0x80] = b'X';
+    assert!(inspect_pe(&buf, buf.len() as u64).is_err());
+    // Truncated section table
+    let buf = fake_pe(true, &[".text", ".sbat"], None, 0x1000);
+    assert!(inspect_pe(&buf[..0x190], 0x1000).is_err());
+}
+
+#[test]
+fn test_parse_secureboot_var() {
+    assert!(parse_secureboot_var(&[0x06, 0, 0, 0, 1]).unwrap());
+    assert!(!parse_secureboot_var(&[0x06, 0, 0, 0, 0]).unwrap());
+    assert!(parse_secureboot_var(&[0x06, 0, 0, 0]).is_err());
+}
+
+#[test]
+fn test_find_problems() -> Result<()> {
+    use cap_std_ext::cap_tempfile;
+
+    let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let good = fake_pe(true, &[".text", ".sbat"], Some((0x800, 0x100)), 0x1000);
+    let nosbat = fake_pe(true, &[".text"], Some((0x800, 0x100)), 0x1000);
+    let unsigned = fake_pe(true, &[".text", ".sbat"], None, 0x1000);
+    td.create_dir_all("usr/lib/bootupd/updates/EFI/fedora")?;
+    td.create_dir_all("usr/lib/modules/6.10.0/")?;
+    td.write("usr/lib/bootupd/updates/EFI/fedora/shimx64.efi", &good)?;
+    td.write("usr/lib/bootupd/updates/EFI/fedora/grubx64.efi", &good)?;
+    td.write("usr/lib/bootupd/updates/EFI/fedora/mmx64.efi", &nosbat)?;
+    td.write("usr/lib/bootupd/updates/EFI/fedora/grub.cfg", "")?;
+    td.write("usr/lib/modules/6.10.0/vmlinuz", "")?;
+    let payloads = find_payloads(&td)?;
+    assert_eq!(payloads.len(), 3);
+    assert_eq!(payloads[0].kind, PayloadKind::Grub);
+    assert_eq!(payloads[1].kind, PayloadKind::Other);
+    assert_eq!(payloads[2].kind, PayloadKind::Shim);
+    assert_eq!(find_problems(&payloads), Vec::<String>::new());
+
+    td.write("usr/lib/bootupd/updates/EFI/fedora/grubx64.efi", &nosbat)?;
+    td.write("usr/lib/modules/6.10.0/6.10.0.efi", &unsigned)?;
+    td.create_dir("usr/lib/bootupd/updates/EFI/BOOT")?;
+    td.write("usr/lib/bootupd/updates/EFI/BOOT/BOOTX64.EFI", "garbage")?;
+    let payloads = find_payloads(&td)?;
+    assert_eq!(payloads.len(), 5);
+    assert_eq!(payloads[4].kind, PayloadKind::Uki);
+    let problems = find_problems(&payloads);
+    assert_eq!(problems.len(), 3);
+    assert!(problems[0]
+        .starts_with("usr/lib/bootupd/updates/EFI/BOOT/BOOTX64.EFI: not a valid EFI binary: "));
+    assert_eq!(
+        problems[1],
+        "usr/lib/bootupd/updates/EFI/fedora/grubx64.efi: missing SBAT metadata"
+    );
+    assert_eq!(problems[2], "usr/lib/modules/6.10.0/6.10.0.efi: not signed");
+    Ok(())
+}
@@ -0,0 +1,483 @@
+//! # Creating an initial user
+//!
+//! `bootc install --user` creates a user in the installed system.  Rather than
+//! editing `/etc/passwd` directly, we generate configuration which is applied
+//! on first boot, in the same way as for the root SSH key:
+//!
+//! - A `sysusers.d` file creating the user and adding it to its groups
+//! - A `tmpfiles.d` file creating the home directory and `authorized_keys`
+//! - A hashed password in `/etc/credstore`, which `systemd-sysusers` uses
+//!   when it creates the user
+//! - A `sudoers.d` file for passwordless `sudo`
+//!
+//! The password is hashed as soon as it is read, and is never logged or
+//! written to the aleph.
+
+use std::fmt::Display;
+use std::io::{BufRead, Read, Write};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+
+use super::InstallConfigOpts;
+use crate::aleph::AlephUser;
+use crate::task::Task;
+
+const SYSUSERS_DIR: &str = "etc/sysusers.d";
+const TMPFILES_DIR: &str = "etc/tmpfiles.d";
+const CREDSTORE_DIR: &str = "etc/credstore";
+const SUDOERS_DIR: &str = "etc/sudoers.d";
+/// The name of the generated `sysusers.d` and `tmpfiles.d` files
+const USER_CONF: &str = "bootc-user.conf";
+/// The range of UIDs for regular users, as in the default `login.defs`
+const UID_MIN: u32 = 1000;
+const UID_MAX: u32 = 60000;
+/// The alphabet used for salts and the hash in `crypt(3)` output
+const CRYPT_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const SALT_LEN: usize = 16;
+/// The number of SHA-512 rounds used for password hashes, rather than the
+/// `crypt(3)` default of 5000.
+const PASSWORD_HASH_ROUNDS: u32 = 100_000;
+
+/// A user to create in the installed system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UserConfig {
+    pub(crate) name: String,
+    uid: u32,
+    groups: Vec<String>,
+    authorized_keys: Option<String>,
+    /// The password in `crypt(3)` format; never the plain text
+    password_hash: Option<String>,
+    sudo: bool,
+}
+
+/// Check that `name` is a valid user or group name, in the portable subset
+/// accepted by `useradd` and `systemd-sysusers`.
+fn validate_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = name.len() <= 32
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !valid {
+        anyhow::bail!(
+            "Invalid name {name:?}: expected at most 32 lowercase letters, digits, '_' or '-', starting with a letter or '_'"
+        );
+    }
+    Ok(())
+}
+
+/// Parse the `NAME[:UID]` argument of `--user`.
+fn parse_user(spec: &str) -> Result<(String, Option<u32>)> {
+    let (name, uid) = match spec.split_once(':') {
+        Some((name, uid)) => {
+            let uid: u32 = uid
+                .parse()
+                .with_context(|| format!("Invalid UID {uid:?} for user {name}"))?;
+            if !(UID_MIN..=UID_MAX).contains(&uid) {
+                anyhow::bail!(
+                    "UID {uid} for user {name} is outside the range for regular users ({UID_MIN}-{UID_MAX})"
+                );
+            }
+            (name, Some(uid))
+        }
+        None => (spec, None),
+    };
+    validate_name(name)?;
+    Ok((name.to_owned(), uid))
+}
+
+/// The users in the `passwd` files of the image, including `/usr/lib/passwd`
+/// as used by `nss-altfiles`.
+fn existing_users(root: &Dir) -> Result<Vec<(String, u32)>> {
+    let mut r = Vec::new();
+    for path in ["etc/passwd", "usr/lib/passwd"] {
+        let Some(f) = root.open_optional(path)? else {
+            continue;
+        };
+        for line in std::io::BufReader::new(f).lines() {
+            let line = line?;
+            let mut fields = line.split(':');
+            let (Some(name), Some(uid)) = (fields.next(), fields.nth(1)) else {
+                continue;
+            };
+            if let Ok(uid) = uid.parse() {
+                r.push((name.to_owned(), uid));
+            }
+        }
+    }
+    Ok(r)
+}
+
+/// Find the UID for the user, which is the lowest free one for regular users
+/// unless given.
+fn allocate_uid(name: &str, uid: Option<u32>, existing: &[(String, u32)]) -> Result<u32> {
+    if existing.iter().any(|(n, _)| n == name) {
+        anyhow::bail!("User {name} already exists in the image");
+    }
+    let in_use = |uid| existing.iter().find(|(_, u)| *u == uid);
+    match uid {
+        Some(uid) => {
+            if let Some((other, _)) = in_use(uid) {
+                anyhow::bail!("UID {uid} is already used by {other} in the image");
+            }
+            Ok(uid)
+        }
+        None => (UID_MIN..=UID_MAX)
+            .find(|&uid| in_use(uid).is_none())
+            .ok_or_else(|| anyhow::anyhow!("No free UID for user {name}")),
+    }
+}
+
+/// Hash a password with SHA-512 `crypt(3)` (`$6$`) via `openssl passwd`,
+/// which reads the password from standard input so it doesn't show up in the
+/// process list.
+fn sha512_crypt(password: &[u8], salt: &str, rounds: u32) -> Result<String> {
+    let hash = Task::new("Hashing password", "openssl")
+        .args(["passwd", "-6", "-stdin", "-salt"])
+        .arg(format!("rounds={rounds}${salt}"))
+        .quiet()
+        .read_with_stdin_buf(password)?;
+    let hash = hash.trim_end();
+    if !hash.starts_with("$6$") {
+        anyhow::bail!("Unexpected output from openssl passwd: {hash}");
+    }
+    Ok(hash.to_owned())
+}
+
+/// Hash a password with a random salt.
+fn hash_password(password: &[u8]) -> Result<String> {
+    let mut buf = [0u8; SALT_LEN];
+    openssl::rand::rand_bytes(&mut buf)?;
+    let salt = buf
+        .iter()
+        .map(|b| char::from(CRYPT_ALPHABET[usize::from(b & 0x3f)]))
+        .collect::<String>();
+    sha512_crypt(password, &salt, PASSWORD_HASH_ROUNDS)
+}
+
+/// Read the password from `path`, or standard input for `-`.
+#[context("Reading password from {path}")]
+fn read_password(path: &Utf8Path) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if path.as_str() == "-" {
+        std::io::stdin().read_to_end(&mut buf)?;
+    } else {
+        buf = std::fs::read(path)?;
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    if buf.is_empty() {
+        anyhow::bail!("Password is empty");
+    }
+    if buf.contains(&b'\n') {
+        anyhow::bail!("Password contains multiple lines");
+    }
+    Ok(buf)
+}
+
+impl UserConfig {
+    /// Gather the configuration for `--user`, if given.  `root` is the image
+    /// being installed, which is checked for existing users.
+    #[context("Configuring user")]
+    pub(crate) fn from_opts(opts: &InstallConfigOpts, root: &Dir) -> Result<Option<Self>> {
+        let Some(spec) = opts.user.as_deref() else {
+            return Ok(None);
+        };
+        let (name, uid) = parse_user(spec)?;
+        if !root.try_exists("usr/bin/systemd-sysusers")? {
+            anyhow::bail!("Creating a user requires systemd-sysusers in the image");
+        }
+        let uid = allocate_uid(&name, uid, &existing_users(root)?)?;
+        for group in opts.user_groups.iter() {
+            validate_name(group)?;
+        }
+        let authorized_keys = opts
+            .user_ssh_key
+            .as_ref()
+            .map(|p| std::fs::read_to_string(p).with_context(|| format!("Reading {p}")))
+            .transpose()?;
+        let password_path = if opts.user_password_stdin {
+            Some(Utf8Path::new("-"))
+        } else {
+            opts.user_password_file.as_deref()
+        };
+        let password_hash = password_path
+            .map(|p| read_password(p).and_then(|pw| hash_password(&pw)))
+            .transpose()?;
+        Ok(Some(Self {
+            name,
+            uid,
+            groups: opts.user_groups.clone(),
+            authorized_keys,
+            password_hash,
+            sudo: opts.user_sudo,
+        }))
+    }
+
+    /// The `sysusers.d` configuration creating the user.
+    fn sysusers(&self, shell: &str) -> String {
+        let Self { name, uid, .. } = self;
+        let mut r = format!("u {name} {uid} - /home/{name} {shell}\n");
+        for group in self.groups.iter() {
+            r.push_str(&format!("m {name} {group}\n"));
+        }
+        r
+    }
+
+    /// The `tmpfiles.d` configuration creating the home directory, with `home`
+    /// being the resolved path of `/home`.
+    fn tmpfiles(&self, home: &Utf8Path) -> String {
+        let name = &self.name;
+        let homedir = Utf8Path::new("/").join(home).join(name);
+        let mut r = format!("d {homedir} 0700 {name} {name} -\n");
+        if let Some(keys) = self.authorized_keys.as_deref() {
+            // See the example in https://systemd.io/CREDENTIALS/
+            let b64_encoded = ostree_ext::glib::base64_encode(keys.as_bytes());
+            r.push_str(&format!("d {homedir}/.ssh 0700 {name} {name} -\n"));
+            r.push_str(&format!(
+                "f~ {homedir}/.ssh/authorized_keys 0600 {name} {name} - {b64_encoded}\n"
+            ));
+        }
+        r
+    }
+
+    /// Write the configuration into the deployment root.
+    #[context("Creating user {}", self.name)]
+    pub(crate) fn write(&self, root: &Dir, sepolicy: Option<&ostree::SePolicy>) -> Result<()> {
+        let write = |dir: &str, dirmode: u32, name: &str, mode: u32, contents: &str| {
+            crate::lsm::ensure_dir_labeled(root, dir, None, dirmode.into(), sepolicy)?;
+            let path = Utf8Path::new(dir).join(name);
+            crate::lsm::atomic_replace_labeled(root, path, mode.into(), sepolicy, |w| {
+                w.write_all(contents.as_bytes()).map_err(Into::into)
+            })
+        };
+
+        let shell = if root.try_exists("usr/bin/bash")? {
+            "/bin/bash"
+        } else {
+            "/bin/sh"
+        };
+        write(SYSUSERS_DIR, 0o755, USER_CONF, 0o644, &self.sysusers(shell))?;
+
+        // As for /root, resolve /home now, as it's usually a symlink to /var/home
+        let home = if root
+            .symlink_metadata_optional("home")?
+            .is_some_and(|m| m.is_symlink())
+        {
+            Utf8PathBuf::try_from(root.read_link("home")?).context("Reading /home symlink")?
+        } else {
+            Utf8PathBuf::from("home")
+        };
+        write(TMPFILES_DIR, 0o755, USER_CONF, 0o644, &self.tmpfiles(&home))?;
+
+        if let Some(hash) = self.password_hash.as_deref() {
+            let cred = format!("passwd.hashed-password.{}", self.name);
+            write(CREDSTORE_DIR, 0o700, &cred, 0o600, hash)?;
+        }
+        if self.sudo {
+            let conf = format!("bootc-user-{}", self.name);
+            let contents = format!("{} ALL=(ALL) NOPASSWD: ALL\n", self.name);
+            write(SUDOERS_DIR, 0o750, &conf, 0o440, &contents)?;
+        }
+        println!("Configured user: {}", self.name);
+        Ok(())
+    }
+
+    /// The configuration to record in the aleph.
+    pub(crate) fn aleph(&self) -> AlephUser {
+        AlephUser {
+            name: self.name.clone(),
+            uid: self.uid,
+            groups: self.groups.clone(),
+            ssh_key: self.authorized_keys.is_some(),
+            password: self.password_hash.is_some(),
+            sudo: self.sudo,
+        }
+    }
+
+    /// Print the configuration, without secrets.
+    pub(crate) fn print_summary(&self) {
+        println!("{self}");
+    }
+}
+
+impl Display for UserConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let yes_no = |v: bool| if v { "yes" } else { "no" };
+        writeln!(f, "User: {} (uid {})", self.name, self.uid)?;
+        if !self.groups.is_empty() {
+            writeln!(f, "  Groups: {}", self.groups.join(","))?;
+        }
+        writeln!(f, "  SSH key: {}", yes_no(self.authorized_keys.is_some()))?;
+        writeln!(f, "  Password: {}", yes_no(self.password_hash.is_some()))?;
+        write!(f, "  Passwordless sudo: {}", yes_no(self.sudo))
+    }
+}
+
+#[test]
+fn test_parse_user() -> Result<()> {
+    assert_eq!(parse_user("alice")?, ("alice".to_owned(), None));
+    assert_eq!(
+        parse_user("_ci-bot2:1500")?,
+        ("_ci-bot2".to_owned(), Some(1500))
+    );
+    for (spec, expected) in [
+        ("Alice", "Invalid name \"Alice\""),
+        ("1user", "Invalid name \"1user\""),
+        ("", "Invalid name \"\""),
+        ("alice:", "Invalid UID \"\" for user alice"),
+        ("alice:x", "Invalid UID \"x\" for user alice"),
+        (
+            "alice:0",
+            "UID 0 for user alice is outside the range for regular users (1000-60000)",
+        ),
+    ] {
+        let err = parse_user(spec).unwrap_err().to_string();
+        assert!(err.starts_with(expected), "{spec}: {err}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_allocate_uid() -> Result<()> {
+    let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    root.create_dir_all("usr/lib")?;
+    root.create_dir("etc")?;
+    root.write("etc/passwd", "root:x:0:0:root:/root:/bin/bash\n")?;
+    root.write(
+        "usr/lib/passwd",
+        "bin:x:1:1:bin:/bin:/sbin/nologin\ncore:x:1000:1000::/var/home/core:/bin/bash\n\n",
+    )?;
+    let existing = existing_users(root)?;
+    assert_eq!(existing.len(), 3);
+    assert_eq!(allocate_uid("alice", None, &existing)?, 1001);
+    assert_eq!(allocate_uid("alice", Some(1234), &existing)?, 1234);
+    assert_eq!(
+        allocate_uid("core", None, &existing)
+            .unwrap_err()
+            .to_string(),
+        "User core already exists in the image"
+    );
+    assert_eq!(
+        allocate_uid("alice", Some(1000), &existing)
+            .unwrap_err()
+            .to_string(),
+        "UID 1000 is already used by core in the image"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_sha512_crypt() -> Result<()> {
+    // Test vectors from the specification
+    for (password, salt, rounds, expected) in [
+        (
+            "Hello world!",
+            "saltstringsaltstring",
+            10000,
+            "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.",
+        ),
+        (
+            "This is just a test",
+            "toolongsaltstring",
+            5000,
+            "$6$rounds=5000$toolongsaltstrin$lQ8jolhgVRVhY4b5pZKaysCLi0QBxGoNeKQzQ3glMhwllF7oGDZxUhx1yxdYcz/e1JSbq3y6JMxxl8audkUEm0",
+        ),
+    ] {
+        assert_eq!(sha512_crypt(password.as_bytes(), salt, rounds)?, expected);
+    }
+
+    let hash = hash_password(b"secret")?;
+    let prefix = format!("$6$rounds={PASSWORD_HASH_ROUNDS}$");
+    let salt = &hash[prefix.len()..prefix.len() + SALT_LEN];
+    assert_eq!(hash, sha512_crypt(b"secret", salt, PASSWORD_HASH_ROUNDS)?);
+    assert_ne!(hash, hash_password(b"secret")?);
+    Ok(())
+}
+
+#[test]
+fn test_write_user() -> Result<()> {
+    use cap_std::fs::PermissionsExt;
+    use cap_std_ext::cap_std;
+    let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    root.create_dir_all("etc")?;
+    root.create_dir_all("usr/bin")?;
+    root.write("usr/bin/bash", "")?;
+    root.symlink("var/home", "home")?;
+
+    let mut user = UserConfig {
+        name: "alice".into(),
+        uid: 1001,
+        groups: vec!["wheel".into(), "adm".into()],
+        authorized_keys: Some("ssh-ed25519 ABCDE example@demo\n".into()),
+        password_hash: Some("$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1".into()),
+        sudo: true,
+    };
+    user.write(root, None)?;
+    assert_eq!(
+        root.read_to_string("etc/sysusers.d/bootc-user.conf")?,
+        "u alice 1001 - /home/alice /bin/bash\nm alice wheel\nm alice adm\n"
+    );
+    assert_eq!(
+        root.read_to_string("etc/tmpfiles.d/bootc-user.conf")?,
+        "d /var/home/alice 0700 alice alice -\n\
+         d /var/home/alice/.ssh 0700 alice alice -\n\
+         f~ /var/home/alice/.ssh/authorized_keys 0600 alice alice - c3NoLWVkMjU1MTkgQUJDREUgZXhhbXBsZUBkZW1vCg==\n"
+    );
+    let cred = "etc/credstore/passwd.hashed-password.alice";
+    assert_eq!(
+        root.read_to_string(cred)?,
+        user.password_hash.as_deref().unwrap()
+    );
+    assert_eq!(root.metadata(cred)?.permissions().mode() & 0o777, 0o600);
+    assert_eq!(
+        root.read_to_string("etc/sudoers.d/bootc-user-alice")?,
+        "alice ALL=(ALL) NOPASSWD: ALL\n"
+    );
+
+    let aleph = serde_json::to_value(user.aleph())?;
+    assert_eq!(
+        aleph,
+        serde_json::json!({
+            "name": "alice",
+            "uid": 1001,
+            "groups": ["wheel", "adm"],
+            "ssh_key": true,
+            "password": true,
+            "sudo": true,
+        })
+    );
+    assert!(!user.to_string().contains("$6$"));
+
+    // Without the optional parts, and /home as a directory
+    let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    root.create_dir_all("etc")?;
+    root.create_dir("home")?;
+    user.groups.clear();
+    user.authorized_keys = None;
+    user.password_hash = None;
+    user.sudo = false;
+    user.write(root, None)?;
+    assert_eq!(
+        root.read_to_string("etc/sysusers.d/bootc-user.conf")?,
+        "u alice 1001 - /home/alice /bin/sh\n"
+    );
+    assert_eq!(
+        root.read_to_string("etc/tmpfiles.d/bootc-user.conf")?,
+        "d /home/alice 0700 alice alice -\n"
+    );
+    assert!(!root.try_exists("etc/credstore")?);
+    assert!(!root.try_exists("etc/sudoers.d")?);
+    assert_eq!(
+        user.to_string(),
+        "User: alice (uid 1001)\n  SSH key: no\n  Password: no\n  Passwordless sudo: no"
+    );
+    Ok(())
+}
@@ -0,0 +1,2031 @@
+//! # Kernel arguments from the image
+//!
+//! Container images can ship `/usr/lib/bootc/kargs.d/*.toml` files which
+//! specify kernel arguments to apply.  For example:
+//!
+//! ```toml
+//! kargs = ["console=ttyS0,114800n8", "nosmt"]
+//! match-architectures = ["x86_64"]
+//! kargs-remove = ["mitigations=off"]
+//! ```
+//!
+//! Files are processed in order of their filename.  Removals are applied only
+//! after the additions from all files have been accumulated, so a derived
+//! image can drop an argument set by its base image regardless of the order
+//! in which the files sort.
+//!
+//! Machine-local configuration may also be placed in `/etc/bootc/kargs.d`
+//! and `/run/bootc/kargs.d`.  As with systemd configuration, files from all
+//! three directories are processed together in order of their filename; a
+//! file in `/etc` replaces one of the same name in `/run` or `/usr/lib`, and
+//! one in `/run` replaces one in `/usr/lib`.  A file which is a symlink to
+//! `/dev/null` masks any file of the same name with lower priority.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use clap::ValueEnum;
+use fn_error_context::context;
+use ostree_ext::ostree;
+use ostree_ext::sysroot::SysrootLock;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{KargsCmd, KargsEditOpts, KargsOpts, PrintKargsFormat, PrintKargsOpts};
+use crate::deploy::{ORIGIN_BOOTC_GROUP, ORIGIN_KARGS_D_KEY};
+
+/// The directory (relative to the root) holding kernel argument configuration.
+const KARGS_D: &str = "usr/lib/bootc/kargs.d";
+/// The directory holding kernel argument overrides for the current boot.
+const RUN_KARGS_D: &str = "run/bootc/kargs.d";
+/// The directory holding machine-local kernel argument overrides.
+const ETC_KARGS_D: &str = "etc/bootc/kargs.d";
+/// All kargs.d directories, in increasing order of priority.
+const KARGS_D_DIRS: &[&str] = &[KARGS_D, RUN_KARGS_D, ETC_KARGS_D];
+
+/// The current (and only) version of the kargs.d file format.
+const KARGS_D_VERSION: u32 = 1;
+
+/// The contents of a single kargs.d file.  Unknown keys are rejected, so that
+/// typos don't cause e.g. an architecture restriction to be silently ignored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Config {
+    /// The version of the file format; if unset, [`KARGS_D_VERSION`] is assumed.
+    #[allow(dead_code)]
+    version: Option<u32>,
+    /// Kernel arguments to add
+    #[serde(default)]
+    kargs: Vec<String>,
+    /// Kernel arguments to remove.  An entry of the form `key=value` removes
+    /// exactly that argument; a bare `key` removes it with any (or no) value.
+    #[serde(default, alias = "kargs_remove")]
+    kargs_remove: Vec<String>,
+    /// If set, this file only applies on the matching architectures; see
+    /// [`Config::matches_arch`].
+    match_architectures: Option<Vec<String>>,
+    /// If set, this file only applies to machines with this type of firmware.
+    match_firmware: Option<Firmware>,
+}
+
+/// The type of firmware a machine booted with, for `match-firmware`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Firmware {
+    Efi,
+    Bios,
+}
+
+impl Firmware {
+    /// Detect the firmware type of the running machine.
+    pub(crate) fn detect() -> Result<Self> {
+        let r = if Utf8Path::new("/sys/firmware/efi").try_exists()? {
+            Self::Efi
+        } else {
+            Self::Bios
+        };
+        Ok(r)
+    }
+}
+
+impl Display for Firmware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+/// Map alternative architecture names used by other tooling (e.g. Debian
+/// or Go) to the names used by Rust's `std::env::consts::ARCH`.
+fn normalize_arch(arch: &str) -> &str {
+    match arch {
+        "amd64" => "x86_64",
+        "arm64" => "aarch64",
+        "ppc64le" | "ppc64el" => "powerpc64",
+        o => o,
+    }
+}
+
+impl Config {
+    /// Entries prefixed with `!` exclude an architecture.  If there are any
+    /// other entries, one of them must match and none of the exclusions; if
+    /// there are only exclusions, every other architecture matches.
+    fn matches_arch(&self, sys_arch: &str) -> Result<bool> {
+        let Some(arches) = self.match_architectures.as_ref() else {
+            return Ok(true);
+        };
+        let (excluded, included): (Vec<_>, Vec<_>) = arches
+            .iter()
+            .map(|a| match a.strip_prefix('!') {
+                Some(a) => (true, normalize_arch(a)),
+                None => (false, normalize_arch(a)),
+            })
+            .partition(|(negated, _)| *negated);
+        let excluded = excluded.iter().map(|(_, a)| *a).collect::<Vec<_>>();
+        let included = included.iter().map(|(_, a)| *a).collect::<Vec<_>>();
+        if excluded.is_empty() && included.is_empty() {
+            anyhow::bail!("match-architectures must not be empty");
+        }
+        if !included.is_empty() && included.iter().all(|a| excluded.contains(a)) {
+            anyhow::bail!("match-architectures excludes all of the included architectures");
+        }
+        let sys_arch = normalize_arch(sys_arch);
+        if excluded.contains(&sys_arch) {
+            return Ok(false);
+        }
+        Ok(included.is_empty() || included.contains(&sys_arch))
+    }
+
+    /// Returns true if this file applies to `sys_arch` and `firmware`.
+    fn matches(&self, sys_arch: &str, firmware: Firmware) -> Result<bool> {
+        let firmware_matches = self.match_firmware.unwrap_or(firmware) == firmware;
+        Ok(self.matches_arch(sys_arch)? && firmware_matches)
+    }
+}
+
+/// Parse a kargs.d file.
+fn parse_kargs_toml(contents: &str) -> Result<Config> {
+    // Check the version before anything else, as a newer version may well have
+    // keys we don't know about.
+    #[derive(Deserialize)]
+    struct Versioned {
+        version: Option<toml::Value>,
+    }
+    let Versioned { version } = toml::from_str(contents)?;
+    match version {
+        None => {}
+        Some(toml::Value::Integer(v)) if v == i64::from(KARGS_D_VERSION) => {}
+        Some(v) => anyhow::bail!(
+            "Unsupported kargs.d version {v} (this version of bootc supports {KARGS_D_VERSION})"
+        ),
+    }
+    toml::from_str(contents).map_err(Into::into)
+}
+
+/// A single kernel argument, such as `console=ttyS0` or `nosmt`.  The original
+/// text is preserved, so that formatting round-trips exactly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Karg {
+    raw: String,
+    /// Offset of the `=` separating the key from the value, if any
+    eq: Option<usize>,
+}
+
+/// Split off the first kernel argument of `s`, which ends at the first whitespace
+/// not within double quotes (as parsed by the kernel); returns it and the remainder.
+fn next_karg(s: &str) -> (&str, &str) {
+    let mut in_quote = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => in_quote = !in_quote,
+            c if c.is_ascii_whitespace() && !in_quote => return s.split_at(i),
+            _ => {}
+        }
+    }
+    (s, "")
+}
+
+impl Karg {
+    fn new(raw: &str) -> Self {
+        let mut in_quote = false;
+        let eq = raw.char_indices().find_map(|(i, c)| match c {
+            '"' => {
+                in_quote = !in_quote;
+                None
+            }
+            '=' if !in_quote => Some(i),
+            _ => None,
+        });
+        Self {
+            raw: raw.to_owned(),
+            eq,
+        }
+    }
+
+    /// Parse a single kernel argument.  Values containing whitespace must be quoted,
+    /// e.g. `dm-mod.create="a b"`.
+    pub(crate) fn parse(s: &str) -> Result<Self> {
+        let (karg, rest) = next_karg(s);
+        if karg.is_empty() || !rest.is_empty() {
+            anyhow::bail!("Expected a single kernel argument: {s:?}");
+        }
+        if karg.matches('"').count() % 2 != 0 {
+            anyhow::bail!("Unbalanced quotes in kernel argument: {s:?}");
+        }
+        Ok(Self::new(karg))
+    }
+
+    /// The part before the first unquoted `=`, or the whole argument.
+    pub(crate) fn key(&self) -> &str {
+        &self.raw[..self.eq.unwrap_or(self.raw.len())]
+    }
+
+    /// The part after the first unquoted `=` (if any), without surrounding quotes.
+    #[cfg(test)]
+    pub(crate) fn value(&self) -> Option<&str> {
+        let v = &self.raw[self.eq? + 1..];
+        let v = match v.strip_prefix('"') {
+            Some(v) => v.strip_suffix('"').unwrap_or(v),
+            None => v,
+        };
+        Some(v)
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// Returns true if matched by `pattern`: `key=value` matches exactly that
+    /// argument, while a bare `key` matches it with any (or no) value.
+    pub(crate) fn matches(&self, pattern: &str) -> bool {
+        if pattern.contains('=') {
+            self.raw == pattern
+        } else {
+            self.key() == pattern
+        }
+    }
+}
+
+impl Display for Karg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+/// An ordered list of kernel arguments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct KargSet(Vec<Karg>);
+
+/// The changes between two [`KargSet`]s.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct KargDiff {
+    pub(crate) added: Vec<Karg>,
+    pub(crate) removed: Vec<Karg>,
+    /// Arguments whose key occurs once in both sets, but with a different value;
+    /// as `(old, new)`.
+    pub(crate) changed: Vec<(Karg, Karg)>,
+}
+
+impl KargDiff {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl KargSet {
+    /// Parse a kernel command line, such as the `options` of a boot entry.
+    pub(crate) fn parse(cmdline: &str) -> Self {
+        let mut r = Vec::new();
+        let mut s = cmdline.trim_start_matches(|c: char| c.is_ascii_whitespace());
+        while !s.is_empty() {
+            let (karg, rest) = next_karg(s);
+            r.push(Karg::new(karg));
+            s = rest.trim_start_matches(|c: char| c.is_ascii_whitespace());
+        }
+        Self(r)
+    }
+
+    pub(crate) fn iter(&self) -> std::slice::Iter<'_, Karg> {
+        self.0.iter()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn contains(&self, karg: &Karg) -> bool {
+        self.0.contains(karg)
+    }
+
+    /// Append `karg` if it is not already present, returning whether it was added.
+    pub(crate) fn insert(&mut self, karg: Karg) -> bool {
+        if self.contains(&karg) {
+            return false;
+        }
+        self.0.push(karg);
+        true
+    }
+
+    /// Append all arguments of `other` which are not already present.
+    pub(crate) fn merge(&mut self, other: impl IntoIterator<Item = Karg>) {
+        for karg in other {
+            self.insert(karg);
+        }
+    }
+
+    /// Remove duplicate arguments, keeping the first occurrence.
+    #[cfg(test)]
+    pub(crate) fn dedup(&mut self) {
+        let kargs = std::mem::take(&mut self.0);
+        self.merge(kargs);
+    }
+
+    /// Remove all arguments matched by `pattern` (see [`Karg::matches`]), returning
+    /// how many were removed.
+    pub(crate) fn remove_matching(&mut self, pattern: &str) -> usize {
+        let n = self.0.len();
+        self.0.retain(|k| !k.matches(pattern));
+        n - self.0.len()
+    }
+
+    /// Compute the changes from `self` to `new`.
+    pub(crate) fn diff(&self, new: &KargSet) -> KargDiff {
+        let mut added = new
+            .iter()
+            .filter(|k| !self.contains(k))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut removed = self
+            .iter()
+            .filter(|k| !new.contains(k))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut changed = Vec::new();
+        let single = |s: &KargSet, key: &str| s.iter().filter(|k| k.key() == key).count() == 1;
+        removed.retain(|old| {
+            let key = old.key();
+            if !(single(self, key) && single(new, key)) {
+                return true;
+            }
+            match added.iter().position(|k| k.key() == key) {
+                Some(i) => {
+                    changed.push((old.clone(), added.remove(i)));
+                    false
+                }
+                None => true,
+            }
+        });
+        KargDiff {
+            added,
+            removed,
+            changed,
+        }
+    }
+}
+
+impl FromIterator<Karg> for KargSet {
+    fn from_iter<T: IntoIterator<Item = Karg>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl IntoIterator for KargSet {
+    type Item = Karg;
+    type IntoIter = std::vec::IntoIter<Karg>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl Display for KargSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, karg) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(karg.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// Load and parse all kargs.d files in the specified root which apply to
+/// `sys_arch` and `firmware`, returning the combined list of kernel arguments
+/// after removals.  See the module documentation for the precedence of the
+/// kargs.d directories.
+#[cfg(feature = "install")]
+pub(crate) fn get_kargs_in_root(d: &Dir, sys_arch: &str, firmware: Firmware) -> Result<KargSet> {
+    let loaded = get_kargs_with_files(d, sys_arch, firmware)?;
+    Ok(loaded.kargs.into_iter().map(|(_, karg)| karg).collect())
+}
+
+/// A kargs.d file as it was evaluated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct KargsFile {
+    /// The full path of the file
+    file: String,
+    /// Whether the file's match conditions (if any) were satisfied
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    match_architectures: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    match_firmware: Option<Firmware>,
+    /// The kernel arguments added by the file, as written
+    kargs: Vec<String>,
+    /// The kernel arguments removed by the file, as written
+    kargs_remove: Vec<String>,
+}
+
+impl KargsFile {
+    /// Returns true if this file only applies to some machines.
+    fn is_conditional(&self) -> bool {
+        self.match_architectures.is_some() || self.match_firmware.is_some()
+    }
+}
+
+/// The result of evaluating the kargs.d directories.
+#[derive(Debug, Default)]
+struct LoadedKargs {
+    /// The kernel arguments after removals, each paired with the file which provided it
+    kargs: Vec<(KargSource, Karg)>,
+    /// All files which were processed (i.e. not masked), in order
+    files: Vec<KargsFile>,
+}
+
+/// Returns true if `name` is a symlink to `/dev/null`, masking any file of the same
+/// name with lower priority.
+fn is_masked(d: &Dir, name: &str) -> Result<bool> {
+    if !d.symlink_metadata(name)?.is_symlink() {
+        return Ok(false);
+    }
+    let target = rustix::fs::readlinkat(d, name, Vec::new())?;
+    Ok(target.as_bytes() == b"/dev/null")
+}
+
+/// The `.toml` files of a kargs.d directory by name, along with their contents;
+/// `None` for a file which is masked.
+type KargsDir = BTreeMap<String, Option<String>>;
+
+/// Read the kargs.d directory `path` of `root`, if it exists.
+fn read_kargs_dir(root: &Dir, path: &str) -> Result<KargsDir> {
+    let mut r = KargsDir::new();
+    let Some(d) = root.open_dir_optional(path)? else {
+        return Ok(r);
+    };
+    for ent in d.entries()? {
+        let name = ent?.file_name();
+        let name = name
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("Invalid non-UTF8 filename in {path}: {name:?}"))?;
+        if Utf8Path::new(&name).extension() != Some("toml") {
+            continue;
+        }
+        let contents = if is_masked(&d, &name)? {
+            None
+        } else {
+            Some(d.read_to_string(&name)?)
+        };
+        r.insert(name, contents);
+    }
+    Ok(r)
+}
+
+/// Read the kargs.d directory of the image in `commit`.
+#[context("Reading kargs.d of {commit}")]
+fn read_kargs_dir_from_commit(repo: &ostree::Repo, commit: &str) -> Result<KargsDir> {
+    use ostree::gio;
+    use ostree_ext::prelude::{FileEnumeratorExt, FileExt};
+    let cancellable = gio::Cancellable::NONE;
+    let mut r = KargsDir::new();
+    let (root, _) = repo.read_commit(commit, cancellable)?;
+    let d = root.resolve_relative_path(KARGS_D);
+    if !d.query_exists(cancellable) {
+        return Ok(r);
+    }
+    let children = d.enumerate_children(
+        "standard::name,standard::type,standard::symlink-target",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        cancellable,
+    )?;
+    while let Some(info) = children.next_file(cancellable)? {
+        let name = info.name();
+        let Some(name) = name.to_str() else {
+            anyhow::bail!("Invalid non-UTF8 filename in {KARGS_D}: {name:?}");
+        };
+        if Utf8Path::new(name).extension() != Some("toml") {
+            continue;
+        }
+        let contents = match info.file_type() {
+            gio::FileType::SymbolicLink => {
+                let masked = info
+                    .symlink_target()
+                    .is_some_and(|t| t.as_os_str() == "/dev/null");
+                if !masked {
+                    anyhow::bail!("Unsupported symbolic link {KARGS_D}/{name}");
+                }
+                None
+            }
+            gio::FileType::Regular => {
+                let (buf, _) = d.child(name).load_contents(cancellable)?;
+                let buf = String::from_utf8(buf.to_vec())
+                    .with_context(|| format!("Reading {KARGS_D}/{name}"))?;
+                Some(buf)
+            }
+            _ => continue,
+        };
+        r.insert(name.to_owned(), contents);
+    }
+    Ok(r)
+}
+
+/// Add the overrides to the kargs.d directory of an image: those in `/run` are
+/// read from `run_root`, which is normally the running system, while those in
+/// `/etc` are read from `etc_root`.
+fn with_overrides(
+    image: KargsDir,
+    run_root: &Dir,
+    etc_root: &Dir,
+) -> Result<[(&'static str, KargsDir); 3]> {
+    Ok([
+        (KARGS_D, image),
+        (RUN_KARGS_D, read_kargs_dir(run_root, RUN_KARGS_D)?),
+        (ETC_KARGS_D, read_kargs_dir(etc_root, ETC_KARGS_D)?),
+    ])
+}
+
+/// Evaluate the provided kargs.d directories, given in increasing order of
+/// priority along with their paths; each kernel argument is paired with the
+/// file which provided it.
+fn evaluate_kargs_dirs(
+    dirs: &[(&str, KargsDir)],
+    sys_arch: &str,
+    firmware: Firmware,
+) -> Result<LoadedKargs> {
+    // Find the highest priority file for each name
+    let mut files = BTreeMap::new();
+    for (dir, contents) in dirs {
+        for (name, buf) in contents {
+            files.insert(name.as_str(), (*dir, buf.as_deref()));
+        }
+    }
+    let mut kargs = Vec::new();
+    let mut kargs_files = Vec::new();
+    let mut removals = Vec::new();
+    for (name, (dir, buf)) in files {
+        let path = format!("/{dir}/{name}");
+        let Some(buf) = buf else {
+            tracing::debug!("Skipping masked {path}");
+            continue;
+        };
+        let config = parse_kargs_toml(buf).with_context(|| format!("Parsing {path}"))?;
+        let active = config
+            .matches(sys_arch, firmware)
+            .with_context(|| format!("Parsing {path}"))?;
+        kargs_files.push(KargsFile {
+            file: path.clone(),
+            active,
+            match_architectures: config.match_architectures.clone(),
+            match_firmware: config.match_firmware,
+            kargs: config.kargs.clone(),
+            kargs_remove: config.kargs_remove.clone(),
+        });
+        if !active {
+            tracing::debug!("Skipping {path} which does not match {sys_arch} with {firmware}");
+            continue;
+        }
+        let source = if dir == KARGS_D {
+            KargSource::Image {
+                file: name.to_owned(),
+            }
+        } else {
+            KargSource::Override { file: path.clone() }
+        };
+        for karg in config.kargs {
+            let karg = Karg::parse(&karg).with_context(|| format!("Parsing {path}"))?;
+            kargs.push((source.clone(), karg));
+        }
+        removals.extend(config.kargs_remove.into_iter().map(|r| (path.clone(), r)));
+    }
+    for (path, remove) in removals {
+        let n = kargs.len();
+        kargs.retain(|(_, k)| !k.matches(&remove));
+        if kargs.len() == n {
+            tracing::debug!("{path}: No kernel argument matched removal of {remove}");
+        }
+    }
+    Ok(LoadedKargs {
+        kargs,
+        files: kargs_files,
+    })
+}
+
+/// Like [`get_kargs_in_root`], but each kernel argument is paired with the
+/// file which provided it.
+#[context("Loading kargs.d")]
+fn get_kargs_with_files(root: &Dir, sys_arch: &str, firmware: Firmware) -> Result<LoadedKargs> {
+    let dirs = KARGS_D_DIRS
+        .iter()
+        .map(|&path| Ok((path, read_kargs_dir(root, path)?)))
+        .collect::<Result<Vec<_>>>()?;
+    evaluate_kargs_dirs(&dirs, sys_arch, firmware)
+}
+
+/// Load and parse the kargs.d files of the image in `commit` which apply to
+/// `sys_arch` and `firmware`, like [`get_kargs_in_root`].  If provided, the
+/// overrides in `/etc` and `/run` are read from `host`.
+pub(crate) fn get_kargs_in_commit(
+    repo: &ostree::Repo,
+    commit: &str,
+    host: Option<&Dir>,
+    sys_arch: &str,
+    firmware: Firmware,
+) -> Result<KargSet> {
+    let loaded = get_kargs_with_files_in_commit(repo, commit, host, sys_arch, firmware)?;
+    Ok(loaded.kargs.into_iter().map(|(_, karg)| karg).collect())
+}
+
+/// Like [`get_kargs_in_commit`], but each kernel argument is paired with the
+/// file which provided it.
+#[context("Loading kargs.d")]
+fn get_kargs_with_files_in_commit(
+    repo: &ostree::Repo,
+    commit: &str,
+    host: Option<&Dir>,
+    sys_arch: &str,
+    firmware: Firmware,
+) -> Result<LoadedKargs> {
+    let image = read_kargs_dir_from_commit(repo, commit)?;
+    if let Some(host) = host {
+        evaluate_kargs_dirs(&with_overrides(image, host, host)?, sys_arch, firmware)
+    } else {
+        evaluate_kargs_dirs(&[(KARGS_D, image)], sys_arch, firmware)
+    }
+}
+
+/// The kernel arguments which kargs.d provided to `deployment` when it was staged,
+/// as recorded in its origin.  Deployments staged by older versions or created
+/// by `bootc install` lack this; for them, the kargs.d files of the image are
+/// evaluated again, without any overrides.
+pub(crate) fn kargs_d_of_deployment(
+    repo: &ostree::Repo,
+    deployment: &ostree::Deployment,
+    sys_arch: &str,
+    firmware: Firmware,
+) -> Result<KargSet> {
+    if let Some(kargs) = deployment
+        .origin()
+        .map(|o| recorded_kargs_d(&o))
+        .transpose()?
+        .flatten()
+    {
+        return Ok(kargs);
+    }
+    get_kargs_in_commit(repo, &deployment.csum(), None, sys_arch, firmware)
+}
+
+/// The kernel arguments from kargs.d recorded in an origin, if any.
+fn recorded_kargs_d(origin: &ostree::glib::KeyFile) -> Result<Option<KargSet>> {
+    use ostree_ext::keyfileext::KeyFileExt;
+    let v = origin.optional_string(ORIGIN_BOOTC_GROUP, ORIGIN_KARGS_D_KEY)?;
+    Ok(v.map(|v| KargSet::parse(&v)))
+}
+
+/// Record the kernel arguments `kargs` provided by kargs.d in an origin.
+pub(crate) fn record_kargs_d(origin: &ostree::glib::KeyFile, kargs: &KargSet) {
+    origin.set_string(ORIGIN_BOOTC_GROUP, ORIGIN_KARGS_D_KEY, &kargs.to_string());
+}
+
+/// Compute the kernel arguments of a new deployment from those of the merge
+/// deployment (`merge`): the arguments which kargs.d provided to the merge
+/// deployment (`old`) but no longer provides are removed, the ones it newly
+/// provides (`new`) are added, and then the machine-local changes are applied.
+/// Anything else, such as an argument from kargs.d which was since deleted by
+/// other means, is left as is.
+pub(crate) fn kargs_for_new_deployment(
+    merge: &KargSet,
+    old: &KargSet,
+    new: &KargSet,
+    local: &LocalKargs,
+) -> Result<KargSet> {
+    let mut kargs = merge
+        .iter()
+        .filter(|k| new.contains(k) || !old.contains(k))
+        .cloned()
+        .collect::<KargSet>();
+    kargs.merge(new.iter().filter(|k| !old.contains(k)).cloned());
+    local.apply(&mut kargs)?;
+    Ok(kargs)
+}
+
+/// Where a kernel argument of a deployment came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum KargSource {
+    /// A kargs.d file in the deployment's image
+    Image { file: String },
+    /// A kargs.d file in `/etc` or `/run`; this is the full path
+    Override { file: String },
+    /// Generated by ostree to find the deployment
+    Ostree,
+    /// Given to `bootc install` via `--karg` or `--karg-file`
+    Install,
+    /// Added via `bootc kargs append`
+    Local,
+    /// None of the above, e.g. set up by the installation for the root
+    /// filesystem, or changed by other tools
+    Inherited,
+}
+
+impl Display for KargSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Image { file } => write!(f, "kargs.d/{file}"),
+            Self::Override { file } => f.write_str(file),
+            Self::Ostree => f.write_str("ostree"),
+            Self::Install => f.write_str("install"),
+            Self::Local => f.write_str("local"),
+            Self::Inherited => f.write_str("inherited"),
+        }
+    }
+}
+
+/// A kernel argument along with its source.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SourcedKarg {
+    pub(crate) karg: String,
+    pub(crate) source: KargSource,
+}
+
+/// The kernel arguments of a deployment.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeploymentKargs {
+    /// Either `staged` or `booted`
+    deployment: &'static str,
+    checksum: String,
+    kargs: Vec<SourcedKarg>,
+    /// The kargs.d files in the deployment
+    files: Vec<KargsFile>,
+}
+
+/// Attribute each of `kargs` (as found in a boot entry) to its source, given the
+/// arguments provided by kargs.d files, the machine-local changes and those given
+/// at installation time.  An argument which is provided by multiple kargs.d files
+/// is attributed to the first of them.
+fn annotate_kargs(
+    kargs: &KargSet,
+    kargsd: &[(KargSource, Karg)],
+    local: &LocalKargs,
+    install: &[String],
+) -> Vec<SourcedKarg> {
+    kargs
+        .iter()
+        .map(|karg| {
+            let source = if karg.key() == "ostree" {
+                KargSource::Ostree
+            } else if let Some((source, _)) = kargsd.iter().find(|(_, k)| k == karg) {
+                source.clone()
+            } else if local.append.iter().any(|a| a == karg.as_str()) {
+                KargSource::Local
+            } else if install.iter().any(|a| a == karg.as_str()) {
+                KargSource::Install
+            } else {
+                KargSource::Inherited
+            };
+            SourcedKarg {
+                karg: karg.to_string(),
+                source,
+            }
+        })
+        .collect()
+}
+
+#[context("Reading kernel arguments of {name} deployment")]
+fn deployment_kargs(
+    sysroot: &SysrootLock,
+    sysroot_dir: &Dir,
+    name: &'static str,
+    deployment: &ostree::Deployment,
+) -> Result<DeploymentKargs> {
+    let root = sysroot_dir.open_dir(sysroot.deployment_dirpath(deployment).as_str())?;
+    // The deployment has its own /etc, but /run is that of the running system
+    let host = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let dirs = with_overrides(read_kargs_dir(&root, KARGS_D)?, host, &root)?;
+    let kargsd = evaluate_kargs_dirs(&dirs, std::env::consts::ARCH, Firmware::detect()?)?;
+    let local = LocalKargs::load(&root.open_dir("etc")?)?;
+    let install = install_kargs(sysroot_dir)?;
+    let kargs = kargs_of_deployment(deployment);
+    Ok(DeploymentKargs {
+        deployment: name,
+        checksum: deployment.csum().into(),
+        kargs: annotate_kargs(&kargs, &kargsd.kargs, &local, &install),
+        files: kargsd.files,
+    })
+}
+
+/// The kernel arguments given to `bootc install`, as recorded in the aleph.
+fn install_kargs(sysroot_dir: &Dir) -> Result<Vec<String>> {
+    Ok(crate::aleph::load(sysroot_dir)?
+        .map(|a| a.kargs)
+        .unwrap_or_default())
+}
+
+/// Return the kernel arguments in the boot entry of a deployment.
+pub(crate) fn kargs_of_deployment(deployment: &ostree::Deployment) -> KargSet {
+    let options = deployment
+        .bootconfig()
+        .and_then(|b| b.get("options"))
+        .unwrap_or_default();
+    KargSet::parse(&options)
+}
+
+/// Path (relative to `/etc`) of the machine-local kernel argument changes.
+const LOCAL_KARGS: &str = "bootc/kargs-local.toml";
+
+/// Kernel arguments which are managed by ostree or the installation, and
+/// cannot be changed via `bootc kargs`.
+const RESERVED_KARGS: &[&str] = &["root", "ostree"];
+
+/// Machine-local changes to the kernel arguments made via `bootc kargs`, which
+/// are applied on top of the arguments inherited from the previous deployment
+/// whenever a new one is staged.  Since these are stored in `/etc`, they are
+/// carried across upgrades.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct LocalKargs {
+    /// Kernel arguments to add, if not already present
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    append: Vec<String>,
+    /// Kernel arguments to remove, with the same matching as `kargs-remove`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    delete: Vec<String>,
+}
+
+impl LocalKargs {
+    /// Load the changes from the provided `/etc` directory.
+    #[context("Loading {LOCAL_KARGS}")]
+    pub(crate) fn load(etc: &Dir) -> Result<Self> {
+        let Some(f) = etc.open_optional(LOCAL_KARGS)? else {
+            return Ok(Default::default());
+        };
+        let buf = std::io::read_to_string(f)?;
+        toml::from_str(&buf).map_err(Into::into)
+    }
+
+    #[context("Writing {LOCAL_KARGS}")]
+    fn store(&self, etc: &Dir) -> Result<()> {
+        if self.is_empty() {
+            etc.remove_file_optional(LOCAL_KARGS)?;
+            return Ok(());
+        }
+        etc.create_dir_all(Utf8Path::new(LOCAL_KARGS).parent().unwrap())?;
+        etc.atomic_write(LOCAL_KARGS, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.append.is_empty() && self.delete.is_empty()
+    }
+
+    /// Record that `karg` should be added; this cancels an identical deletion.
+    fn append(&mut self, karg: &str) {
+        self.delete.retain(|d| d != karg);
+        if !self.append.iter().any(|a| a == karg) {
+            self.append.push(karg.to_owned());
+        }
+    }
+
+    /// Record that `karg` should be removed; this cancels any matching additions.
+    fn delete(&mut self, karg: &str) {
+        self.append.retain(|a| !Karg::new(a).matches(karg));
+        if !self.delete.iter().any(|d| d == karg) {
+            self.delete.push(karg.to_owned());
+        }
+    }
+
+    /// Apply the changes to `kargs`: first deletions, then additions of
+    /// arguments which are not already present.
+    pub(crate) fn apply(&self, kargs: &mut KargSet) -> Result<()> {
+        for d in self.delete.iter() {
+            kargs.remove_matching(d);
+        }
+        for a in self.append.iter() {
+            kargs.insert(Karg::parse(a).with_context(|| format!("Parsing {LOCAL_KARGS}"))?);
+        }
+        Ok(())
+    }
+}
+
+/// Validate a kernel argument provided to `bootc kargs append` or `delete`.
+fn validate_karg(karg: &str) -> Result<Karg> {
+    let parsed = Karg::parse(karg)?;
+    let key = parsed.key();
+    if key.is_empty() {
+        anyhow::bail!("Invalid empty kernel argument key: {karg:?}");
+    }
+    if karg.chars().any(|c| c.is_control()) {
+        anyhow::bail!("Invalid control character in kernel argument: {karg:?}");
+    }
+    if RESERVED_KARGS.contains(&key) {
+        anyhow::bail!("Cannot change reserved kernel argument {key}=");
+    }
+    Ok(parsed)
+}
+
+/// Parse a file of kernel arguments given to `bootc install --karg-file`, with
+/// one argument per line; blank lines and comments starting with `#` are ignored.
+#[cfg(feature = "install")]
+fn parse_karg_file(buf: &str) -> Result<Vec<String>> {
+    buf.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            validate_karg(line).with_context(|| format!("Line {n}"))?;
+            Ok(line.to_owned())
+        })
+        .collect()
+}
+
+/// Read a file of kernel arguments (see [`parse_karg_file`]), or standard input for `-`.
+#[cfg(feature = "install")]
+#[context("Reading kernel arguments from {path}")]
+pub(crate) fn read_karg_file(path: &Utf8Path) -> Result<Vec<String>> {
+    let buf = if path.as_str() == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path)?
+    };
+    parse_karg_file(&buf)
+}
+
+/// Implementation of `bootc kargs append` and `bootc kargs delete`.
+async fn edit(opts: KargsEditOpts, append: bool) -> Result<()> {
+    let karg = opts.karg.as_str();
+    let parsed = validate_karg(karg)?;
+    crate::cli::prepare_for_write().await?;
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let booted = sysroot.require_booted_deployment()?;
+    let staged = sysroot.staged_deployment();
+    let etc = &Dir::open_ambient_dir("/etc", cap_std::ambient_authority())?;
+    let mut local = LocalKargs::load(etc)?;
+
+    // The kernel arguments the next deployment will have, without this change
+    let next = staged.as_ref().unwrap_or(&booted);
+    let mut next_kargs = kargs_of_deployment(next);
+    local.apply(&mut next_kargs)?;
+    let present = if append {
+        next_kargs.contains(&parsed)
+    } else {
+        next_kargs.iter().any(|k| k.matches(karg))
+    };
+    if present == append {
+        let state = if append { "already" } else { "not" };
+        println!("Kernel argument {karg} is {state} present; nothing to do");
+        return Ok(());
+    }
+
+    if append {
+        local.append(karg);
+    } else {
+        local.delete(karg);
+    }
+    local.store(etc)?;
+
+    let cancellable = ostree::gio::Cancellable::NONE;
+    let mut targets = Vec::new();
+    targets.extend(staged.as_ref());
+    if opts.apply_to_current {
+        targets.push(&booted);
+    }
+    for d in targets {
+        let mut kargs = kargs_of_deployment(d);
+        local.apply(&mut kargs)?;
+        sysroot.deployment_set_kargs_in_place(d, Some(&kargs.to_string()), cancellable)?;
+    }
+    local.apply(&mut next_kargs)?;
+    println!("Kernel arguments for the next deployment: {next_kargs}");
+    if staged.is_none() && !opts.apply_to_current {
+        println!("These will apply once an update is staged; use --apply-to-current to also change the booted deployment.");
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc kargs`.
+pub(crate) async fn run(opts: KargsOpts) -> Result<()> {
+    match opts.cmd {
+        Some(KargsCmd::Append(opts)) => edit(opts, true).await,
+        Some(KargsCmd::Delete(opts)) => edit(opts, false).await,
+        None => show(opts.json).await,
+    }
+}
+
+/// The evaluated kargs.d configuration of a root filesystem, for use by build tooling.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KargsReport {
+    arch: String,
+    firmware: Firmware,
+    /// All kargs.d files which were processed (i.e. not masked), in order
+    files: Vec<KargsFile>,
+    /// The resulting kernel arguments, after removals
+    kargs: Vec<SourcedKarg>,
+}
+
+fn kargs_report(root: &Dir, sys_arch: &str, firmware: Firmware) -> Result<KargsReport> {
+    let loaded = get_kargs_with_files(root, sys_arch, firmware)?;
+    let kargs = loaded
+        .kargs
+        .into_iter()
+        .map(|(source, karg)| SourcedKarg {
+            karg: karg.to_string(),
+            source,
+        })
+        .collect();
+    Ok(KargsReport {
+        arch: sys_arch.to_owned(),
+        firmware,
+        files: loaded.files,
+        kargs,
+    })
+}
+
+/// Implementation of `bootc internals print-kargs`.
+pub(crate) fn print_kargs(opts: PrintKargsOpts) -> Result<()> {
+    use std::io::Write;
+    let root = &Dir::open_ambient_dir(&opts.root, cap_std::ambient_authority())
+        .with_context(|| format!("Opening {}", opts.root))?;
+    let firmware = opts.firmware.map(Ok).unwrap_or_else(Firmware::detect)?;
+    let report = kargs_report(root, &opts.arch, firmware)?;
+    let out = std::io::stdout();
+    let mut out = out.lock();
+    match opts.format {
+        PrintKargsFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &report).context("Writing to stdout")?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a summary of the changes in kernel arguments from `old` to `new`, along
+/// with their sources.  Nothing is written if there are no changes.  The `ostree=`
+/// argument is ignored, since it differs for every deployment.
+fn write_kargs_diff(
+    out: &mut impl std::io::Write,
+    old: &[SourcedKarg],
+    new: &[SourcedKarg],
+) -> Result<()> {
+    let to_set = |kargs: &[SourcedKarg]| {
+        kargs
+            .iter()
+            .filter(|k| k.source != KargSource::Ostree)
+            .map(|k| Karg::new(&k.karg))
+            .collect()
+    };
+    let diff = KargSet::diff(&to_set(old), &to_set(new));
+    if diff.is_empty() {
+        return Ok(());
+    }
+    let source = |kargs: &[SourcedKarg], karg: &Karg| {
+        kargs
+            .iter()
+            .find(|k| k.karg == karg.as_str())
+            .map(|k| k.source.to_string())
+            .unwrap_or_default()
+    };
+    writeln!(out, "Kernel arguments:")?;
+    for karg in diff.removed {
+        writeln!(out, "  - {karg} ({})", source(old, &karg))?;
+    }
+    for karg in diff.added {
+        writeln!(out, "  + {karg} ({})", source(new, &karg))?;
+    }
+    for (from, to) in diff.changed {
+        writeln!(out, "  ~ {from} -> {to} ({})", source(new, &to))?;
+    }
+    Ok(())
+}
+
+/// Print the changes in kernel arguments between two deployments, if any.
+pub(crate) fn print_kargs_diff(
+    sysroot: &SysrootLock,
+    from: &ostree::Deployment,
+    to: &ostree::Deployment,
+) -> Result<()> {
+    let sysroot_dir = &crate::utils::open_sysroot_dir(sysroot)?;
+    let from = deployment_kargs(sysroot, sysroot_dir, "booted", from)?;
+    let to = deployment_kargs(sysroot, sysroot_dir, "staged", to)?;
+    let out = std::io::stdout();
+    let mut out = out.lock();
+    write_kargs_diff(&mut out, &from.kargs, &to.kargs)
+}
+
+/// Print the changes in kernel arguments between the booted deployment and a
+/// new deployment of `commit` staged based on it, if any.
+pub(crate) fn print_kargs_diff_to_commit(
+    sysroot: &SysrootLock,
+    booted: &ostree::Deployment,
+    commit: &str,
+) -> Result<()> {
+    let repo = &sysroot.repo();
+    let sysroot_dir = &crate::utils::open_sysroot_dir(sysroot)?;
+    let host = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let sys_arch = std::env::consts::ARCH;
+    let firmware = Firmware::detect()?;
+    let from = deployment_kargs(sysroot, sysroot_dir, "booted", booted)?;
+    let kargsd = get_kargs_with_files_in_commit(repo, commit, Some(host), sys_arch, firmware)?;
+    let new = kargsd.kargs.iter().map(|(_, k)| k.clone()).collect();
+    let old = kargs_d_of_deployment(repo, booted, sys_arch, firmware)?;
+    let local = LocalKargs::load(&host.open_dir("etc")?)?;
+    let kargs = kargs_for_new_deployment(&kargs_of_deployment(booted), &old, &new, &local)?;
+    let install = install_kargs(sysroot_dir)?;
+    let to = annotate_kargs(&kargs, &kargsd.kargs, &local, &install);
+    let out = std::io::stdout();
+    let mut out = out.lock();
+    write_kargs_diff(&mut out, &from.kargs, &to)
+}
+
+async fn show(json: bool) -> Result<()> {
+    use std::io::Write;
+    crate::cli::require_root()?;
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let sysroot_dir = &crate::utils::open_sysroot_dir(sysroot)?;
+    let booted = sysroot.require_booted_deployment()?;
+    let mut deployments = Vec::new();
+    if let Some(staged) = sysroot.staged_deployment() {
+        deployments.push(deployment_kargs(sysroot, sysroot_dir, "staged", &staged)?);
+    }
+    deployments.push(deployment_kargs(sysroot, sysroot_dir, "booted", &booted)?);
+
+    let out = std::io::stdout();
+    let mut out = out.lock();
+    if json {
+        serde_json::to_writer(&mut out, &deployments).context("Writing to stdout")?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    for d in deployments {
+        writeln!(out, "{} ({}):", d.deployment, d.checksum)?;
+        for k in d.kargs {
+            writeln!(out, "  {} ({})", k.karg, k.source)?;
+        }
+        for f in d.files.iter().filter(|f| f.is_conditional()) {
+            let state = if f.active { "active" } else { "skipped" };
+            writeln!(out, "  # {}: {state}", f.file)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+impl KargSet {
+    fn to_strings(&self) -> Vec<&str> {
+        self.iter().map(|k| k.as_str()).collect()
+    }
+}
+
+#[test]
+fn test_parse_kargs_toml() {
+    let sys_arch = "x86_64";
+    let file = r#"kargs = ["console=tty0", "nosmt"]"#;
+    let config = parse_kargs_toml(file).unwrap();
+    assert_eq!(config.kargs, ["console=tty0", "nosmt"]);
+    assert!(config.kargs_remove.is_empty());
+
+    let file = r#"kargs = ["console=tty0", "nosmt"]
+match-architectures = ["x86_64", "aarch64"]
+"#;
+    let config = parse_kargs_toml(file).unwrap();
+    assert_eq!(config.kargs, ["console=tty0", "nosmt"]);
+    assert!(!config.matches_arch("s390x").unwrap());
+
+    // Both spellings of the removal key are accepted
+    for key in ["kargs-remove", "kargs_remove"] {
+        let file = format!(r#"{key} = ["mitigations=off"]"#);
+        let config = parse_kargs_toml(&file).unwrap();
+        assert!(config.kargs.is_empty());
+        assert_eq!(config.kargs_remove, ["mitigations=off"]);
+    }
+
+    // Invalid TOML
+    assert!(parse_kargs_toml("kargs = 42").is_err());
+
+    // The current version is accepted
+    let file = "version = 1\nkargs = [\"nosmt\"]";
+    let config = parse_kargs_toml(file).unwrap();
+    assert_eq!(config.kargs, ["nosmt"]);
+
+    // A typo or other unknown key is an error naming the key
+    for (file, key) in [
+        (
+            "kargs = [\"nosmt\"]\nmatch-architecture = [\"s390x\"]",
+            "match-architecture",
+        ),
+        ("karg = [\"nosmt\"]", "karg"),
+        ("kargs = [\"nosmt\"]\nfrobnicate = true", "frobnicate"),
+    ] {
+        let e = parse_kargs_toml(file).unwrap_err();
+        let e = format!("{e:#}");
+        assert!(e.contains(&format!("unknown field `{key}`")), "{e}");
+    }
+
+    // A future version, even with unknown keys, has a clear error
+    for file in [
+        "version = 2",
+        "version = 2\nmatch-kernel = \"6.*\"",
+        "version = \"1\"",
+    ] {
+        let e = parse_kargs_toml(file).unwrap_err();
+        let e = format!("{e:#}");
+        assert!(e.contains("Unsupported kargs.d version"), "{e}");
+    }
+
+    // Empty effective matches
+    for arches in [r#"[]"#, r#"["x86_64", "!amd64"]"#] {
+        let file = format!("kargs = [\"foo\"]\nmatch-architectures = {arches}");
+        assert!(
+            parse_kargs_toml(&file)
+                .unwrap()
+                .matches_arch(sys_arch)
+                .is_err(),
+            "{arches}"
+        );
+    }
+}
+
+#[test]
+fn test_match_architectures() {
+    let cases = [
+        (r#"["x86_64"]"#, &["x86_64"][..]),
+        (r#"["amd64", "arm64"]"#, &["x86_64", "aarch64"]),
+        (r#"["!s390x"]"#, &["x86_64", "aarch64", "powerpc64"]),
+        (r#"["!s390x", "!ppc64le"]"#, &["x86_64", "aarch64"]),
+        (r#"["x86_64", "aarch64", "!arm64"]"#, &["x86_64"]),
+    ];
+    for (arches, expected) in cases {
+        let file = format!("kargs = [\"foo\"]\nmatch-architectures = {arches}");
+        for arch in ["x86_64", "aarch64", "powerpc64", "s390x"] {
+            let matched = parse_kargs_toml(&file).unwrap().matches_arch(arch).unwrap();
+            assert_eq!(matched, expected.contains(&arch), "{arches} {arch}");
+        }
+    }
+    // Aliases are also accepted for the system architecture
+    let file = r#"kargs = ["foo"]
+match-architectures = ["x86_64"]"#;
+    assert!(parse_kargs_toml(file)
+        .unwrap()
+        .matches_arch("amd64")
+        .unwrap());
+}
+
+#[test]
+fn test_karg_matches() {
+    let matches = |karg: &str, pattern: &str| Karg::parse(karg).unwrap().matches(pattern);
+    assert!(matches("mitigations=off", "mitigations=off"));
+    assert!(!matches("mitigations=auto", "mitigations=off"));
+    assert!(matches("mitigations=auto", "mitigations"));
+    assert!(matches("nosmt", "nosmt"));
+    assert!(!matches("nosmt=force", "nosmt=off"));
+    assert!(!matches("nosmtx", "nosmt"));
+}
+
+#[test]
+fn test_karg_parse() {
+    let cases = [
+        ("quiet", "quiet", None),
+        ("console=ttyS0,115200n8", "console", Some("ttyS0,115200n8")),
+        ("root=UUID=1234", "root", Some("UUID=1234")),
+        ("foo=", "foo", Some("")),
+        (r#"foo="bar baz""#, "foo", Some("bar baz")),
+        (r#""a=b"=c"#, r#""a=b""#, Some("c")),
+        (
+            r#"dm-mod.create="lroot,,,rw, 0 4096 linear 98:16 0, 4096 4096 linear 98:32 0""#,
+            "dm-mod.create",
+            Some("lroot,,,rw, 0 4096 linear 98:16 0, 4096 4096 linear 98:32 0"),
+        ),
+    ];
+    for (s, key, value) in cases {
+        let k = Karg::parse(s).unwrap();
+        assert_eq!(k.key(), key, "{s}");
+        assert_eq!(k.value(), value, "{s}");
+        assert_eq!(k.to_string(), s);
+        assert_eq!(Karg::parse(&k.to_string()).unwrap(), k);
+    }
+    for s in ["", " quiet", "quiet ", "a b", r#"foo="bar"#] {
+        assert!(Karg::parse(s).is_err(), "{s:?}");
+    }
+}
+
+#[test]
+fn test_kargset() {
+    let cmdline = r#"root=UUID=1234 rw  dm-mod.create="a b, c d" quiet foo="x y"z"#;
+    let kargs = KargSet::parse(cmdline);
+    assert_eq!(
+        kargs.to_strings(),
+        [
+            "root=UUID=1234",
+            "rw",
+            r#"dm-mod.create="a b, c d""#,
+            "quiet",
+            r#"foo="x y"z"#
+        ]
+    );
+    // Display round-trips, normalizing only the separating whitespace
+    assert_eq!(
+        kargs.to_string(),
+        r#"root=UUID=1234 rw dm-mod.create="a b, c d" quiet foo="x y"z"#
+    );
+    assert_eq!(KargSet::parse(&kargs.to_string()), kargs);
+    assert!(KargSet::parse(" \t\n").is_empty());
+
+    let mut kargs = KargSet::parse("quiet rw quiet console=tty0 rw");
+    kargs.dedup();
+    assert_eq!(kargs.to_string(), "quiet rw console=tty0");
+    kargs.merge(KargSet::parse("console=ttyS0 rw nosmt"));
+    assert_eq!(
+        kargs.to_string(),
+        "quiet rw console=tty0 console=ttyS0 nosmt"
+    );
+    assert_eq!(kargs.remove_matching("console"), 2);
+    assert_eq!(kargs.to_string(), "quiet rw nosmt");
+
+    let old = KargSet::parse("root=UUID=1234 mitigations=auto console=tty0 console=ttyS0 quiet");
+    let new = KargSet::parse("root=UUID=1234 mitigations=off console=tty0 nosmt");
+    let diff = old.diff(&new);
+    assert_eq!(diff.added.len(), 1);
+    assert_eq!(diff.added[0].as_str(), "nosmt");
+    // console occurs twice in the old set, so it is not a simple change
+    let removed = diff.removed.iter().map(|k| k.as_str()).collect::<Vec<_>>();
+    assert_eq!(removed, ["console=ttyS0", "quiet"]);
+    let changed = diff
+        .changed
+        .iter()
+        .map(|(o, n)| (o.as_str(), n.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(changed, [("mitigations=auto", "mitigations=off")]);
+    assert!(old.diff(&old).is_empty());
+}
+
+#[test]
+fn test_get_kargs_in_root() -> Result<()> {
+    use cap_std_ext::cap_std;
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+
+    // No directory
+    assert!(get_kargs_in_root(&td, "x86_64", Firmware::Efi)?.is_empty());
+    td.create_dir_all(KARGS_D)?;
+    assert!(get_kargs_in_root(&td, "x86_64", Firmware::Efi)?.is_empty());
+
+    // Files are applied in name order, and ones without .toml are ignored
+    td.write(
+        format!("{KARGS_D}/20-extra.toml"),
+        r#"kargs = ["console=ttyS0", "quiet"]"#,
+    )?;
+    td.write(
+        format!("{KARGS_D}/10-base.toml"),
+        r#"kargs = ["mitigations=off", "console=tty0"]"#,
+    )?;
+    td.write(format!("{KARGS_D}/30-ignored.conf"), r#"kargs = ["foo"]"#)?;
+    assert_eq!(
+        get_kargs_in_root(&td, "x86_64", Firmware::Efi)?.to_strings(),
+        ["mitigations=off", "console=tty0", "console=ttyS0", "quiet"]
+    );
+
+    // Removals apply after all additions, even from an earlier file
+    td.write(
+        format!("{KARGS_D}/05-remove.toml"),
+        r#"kargs-remove = ["mitigations=off", "console", "nonexistent"]"#,
+    )?;
+    assert_eq!(
+        get_kargs_in_root(&td, "x86_64", Firmware::Efi)?.to_strings(),
+        ["quiet"]
+    );
+
+    // A removal restricted to another architecture doesn't apply
+    td.write(
+        format!("{KARGS_D}/05-remove.toml"),
+        r#"kargs-remove = ["quiet"]
+match-architectures = ["aarch64"]
+"#,
+    )?;
+    assert_eq!(
+        get_kargs_in_root(&td, "x86_64", Firmware::Efi)?.to_strings(),
+        ["mitigations=off", "console=tty0", "console=ttyS0", "quiet"]
+    );
+    assert_eq!(
+        get_kargs_in_root(&td, "aarch64", Firmware::Efi)?.to_strings(),
+        ["mitigations=off", "console=tty0", "console=ttyS0"]
+    );
+
+    // The same tree evaluated for several architectures
+    td.write(
+        format!("{KARGS_D}/05-remove.toml"),
+        r#"kargs = ["cio_ignore=all"]
+match-architectures = ["s390x"]
+"#,
+    )?;
+    td.write(
+        format!("{KARGS_D}/15-not-s390x.toml"),
+        r#"kargs = ["nosmt"]
+match-architectures = ["!s390x"]
+"#,
+    )?;
+    let x86_64 = [
+        "mitigations=off",
+        "console=tty0",
+        "nosmt",
+        "console=ttyS0",
+        "quiet",
+    ];
+    let s390x = [
+        "cio_ignore=all",
+        "mitigations=off",
+        "console=tty0",
+        "console=ttyS0",
+        "quiet",
+    ];
+    for (arch, expected) in [("x86_64", x86_64), ("amd64", x86_64), ("s390x", s390x)] {
+        assert_eq!(
+            get_kargs_in_root(&td, arch, Firmware::Efi)?.to_strings(),
+            expected,
+            "{arch}"
+        );
+    }
+
+    // Parse errors include the filename
+    td.write(format!("{KARGS_D}/99-broken.toml"), "kargs = 42")?;
+    let e = get_kargs_in_root(&td, "x86_64", Firmware::Efi).unwrap_err();
+    assert!(format!("{e:#}").contains("Parsing /usr/lib/bootc/kargs.d/99-broken.toml"));
+    Ok(())
+}
+
+#[test]
+fn test_kargs_d_overrides() -> Result<()> {
+    use cap_std_ext::cap_std;
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let etc = ETC_KARGS_D;
+    let run = RUN_KARGS_D;
+    for d in [KARGS_D, etc, run] {
+        td.create_dir_all(d)?;
+    }
+    td.write(
+        format!("{KARGS_D}/10-base.toml"),
+        r#"kargs = ["console=tty0"]"#,
+    )?;
+    td.write(format!("{KARGS_D}/20-quiet.toml"), r#"kargs = ["quiet"]"#)?;
+    td.write(format!("{KARGS_D}/30-nosmt.toml"), r#"kargs = ["nosmt"]"#)?;
+    // Files from all directories are merged in order of their name
+    td.write(format!("{etc}/15-local.toml"), r#"kargs = ["audit=1"]"#)?;
+    td.write(format!("{run}/25-runtime.toml"), r#"kargs = ["debug"]"#)?;
+    assert_eq!(
+        get_kargs_in_root(&td, "x86_64", Firmware::Efi)?.to_strings(),
+        ["console=tty0", "audit=1", "quiet", "debug", "nosmt"]
+    );
+
+    // A file of the same name replaces one with lower priority entirely
+    td.write(
+        format!("{run}/10-base.toml"),
+        r#"kargs = ["console=ttyS1"]"#,
+    )?;
+    td.write(
+        format!("{etc}/10-base.toml"),
+        r#"kargs = ["console=ttyS0"]"#,
+    )?;
+    td.write(format!("{run}/20-quiet.toml"), r#"kargs = ["loglevel=3"]"#)?;
+    // Removals from an override still apply to all files
+    td.write(
+        format!("{etc}/30-nosmt.toml"),
+        r#"kargs-remove = ["debug"]"#,
+    )?;
+    let r = get_kargs_with_files(&td, "x86_64", Firmware::Efi)?.kargs;
+    let r = r
+        .iter()
+        .map(|(s, k)| (s.to_string(), k.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        r,
+        [
+            ("/etc/bootc/kargs.d/10-base.toml", "console=ttyS0"),
+            ("/etc/bootc/kargs.d/15-local.toml", "audit=1"),
+            ("/run/bootc/kargs.d/20-quiet.toml", "loglevel=3"),
+        ]
+        .map(|(s, k)| (s.to_owned(), k))
+    );
+
+    // A symlink to /dev/null masks lower priority files
+    td.remove_file(format!("{etc}/30-nosmt.toml"))?;
+    td.remove_file(format!("{run}/20-quiet.toml"))?;
+    td.remove_file(format!("{run}/25-runtime.toml"))?;
+    rustix::fs::symlinkat("/dev/null", &*td, format!("{etc}/20-quiet.toml"))?;
+    rustix::fs::symlinkat("/dev/null", &*td, format!("{run}/25-runtime.toml"))?;
+    assert_eq!(
+        get_kargs_in_root(&td, "x86_64", Firmware::Efi)?.to_strings(),
+        ["console=ttyS0", "audit=1", "nosmt"]
+    );
+    // ...but not ones with higher priority
+    td.remove_file(format!("{etc}/20-quiet.toml"))?;
+    rustix::fs::symlinkat("/dev/null", &*td, format!("{KARGS_D}/40-masked.toml"))?;
+    td.write(format!("{etc}/40-masked.toml"), r#"kargs = ["rd.shell=0"]"#)?;
+    assert_eq!(
+        get_kargs_in_root(&td, "x86_64", Firmware::Efi)?.to_strings(),
+        ["console=ttyS0", "audit=1", "quiet", "nosmt", "rd.shell=0"]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_match_firmware() -> Result<()> {
+    use cap_std_ext::cap_std;
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all(KARGS_D)?;
+    td.write(format!("{KARGS_D}/10-base.toml"), r#"kargs = ["quiet"]"#)?;
+    td.write(
+        format!("{KARGS_D}/20-efi.toml"),
+        r#"kargs = ["console=ttyS0"]
+match-firmware = "efi"
+"#,
+    )?;
+    td.write(
+        format!("{KARGS_D}/20-bios.toml"),
+        r#"kargs = ["console=tty0"]
+match-firmware = "bios"
+"#,
+    )?;
+    td.write(
+        format!("{KARGS_D}/30-s390x.toml"),
+        r#"kargs = ["cio_ignore=all"]
+match-architectures = ["s390x"]
+"#,
+    )?;
+    assert_eq!(
+        get_kargs_in_root(&td, "x86_64", Firmware::Efi)?.to_strings(),
+        ["quiet", "console=ttyS0"]
+    );
+    assert_eq!(
+        get_kargs_in_root(&td, "x86_64", Firmware::Bios)?.to_strings(),
+        ["quiet", "console=tty0"]
+    );
+
+    // Conditional files are reported whether or not they matched
+    let r = get_kargs_with_files(&td, "x86_64", Firmware::Bios)?;
+    let conditional = r
+        .files
+        .iter()
+        .filter(|f| f.is_conditional())
+        .map(|f| (f.file.as_str(), f.active))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        conditional,
+        [
+            ("/usr/lib/bootc/kargs.d/20-bios.toml", true),
+            ("/usr/lib/bootc/kargs.d/20-efi.toml", false),
+            ("/usr/lib/bootc/kargs.d/30-s390x.toml", false),
+        ]
+    );
+
+    // Only known firmware types are accepted
+    td.write(
+        format!("{KARGS_D}/20-efi.toml"),
+        r#"kargs = ["console=ttyS0"]
+match-firmware = "uefi"
+"#,
+    )?;
+    let e = get_kargs_in_root(&td, "x86_64", Firmware::Efi).unwrap_err();
+    assert!(format!("{e:#}").contains("unknown variant `uefi`"), "{e:#}");
+    Ok(())
+}
+
+#[test]
+fn test_kargs_report() -> Result<()> {
+    use cap_std_ext::cap_std;
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all(KARGS_D)?;
+    td.create_dir_all("etc/bootc/kargs.d")?;
+    td.write(
+        format!("{KARGS_D}/10-base.toml"),
+        r#"kargs = ["console=tty0", "quiet"]"#,
+    )?;
+    td.write(
+        format!("{KARGS_D}/20-aarch64.toml"),
+        r#"kargs = ["console=ttyAMA0"]
+match-architectures = ["aarch64"]
+"#,
+    )?;
+    td.write(
+        "etc/bootc/kargs.d/30-local.toml",
+        r#"kargs = ["nosmt"]
+kargs-remove = ["quiet"]
+"#,
+    )?;
+    let file = |path: &str, active: bool, kargs: &[&str]| serde_json::json!({"file": format!("/{path}"), "active": active, "kargs": kargs});
+    let mut files = [
+        file(
+            &format!("{KARGS_D}/10-base.toml"),
+            true,
+            &["console=tty0", "quiet"],
+        ),
+        file(
+            &format!("{KARGS_D}/20-aarch64.toml"),
+            false,
+            &["console=ttyAMA0"],
+        ),
+        file("etc/bootc/kargs.d/30-local.toml", true, &["nosmt"]),
+    ];
+    files[0]["kargsRemove"] = serde_json::json!([]);
+    files[1]["kargsRemove"] = serde_json::json!([]);
+    files[1]["matchArchitectures"] = serde_json::json!(["aarch64"]);
+    files[2]["kargsRemove"] = serde_json::json!(["quiet"]);
+    let report = serde_json::to_value(kargs_report(&td, "x86_64", Firmware::Efi)?)?;
+    assert_eq!(
+        report,
+        serde_json::json!({
+            "arch": "x86_64",
+            "firmware": "efi",
+            "files": files,
+            "kargs": [
+                {"karg": "console=tty0", "source": {"type": "image", "file": "10-base.toml"}},
+                {"karg": "nosmt", "source": {"type": "override", "file": "/etc/bootc/kargs.d/30-local.toml"}},
+            ],
+        })
+    );
+
+    // The same tree evaluated for another architecture
+    let report = kargs_report(&td, "aarch64", Firmware::Bios)?;
+    assert!(report.files.iter().all(|f| f.active));
+    let kargs = report
+        .kargs
+        .iter()
+        .map(|k| k.karg.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(kargs, ["console=tty0", "console=ttyAMA0", "nosmt"]);
+    let report = serde_json::to_value(report)?;
+    assert_eq!(report["firmware"], "bios");
+    Ok(())
+}
+
+#[test]
+fn test_annotate_kargs() {
+    let image = [
+        ("10-base.toml", "console=tty0"),
+        ("10-base.toml", "quiet"),
+        ("20-extra.toml", "console=ttyS0"),
+        ("20-extra.toml", "quiet"),
+    ]
+    .map(|(f, k)| {
+        let file = f.to_owned();
+        (KargSource::Image { file }, Karg::parse(k).unwrap())
+    });
+    let options = "root=UUID=1234 rw console=tty0 console=ttyS0 quiet nosmt rd.debug ostree=/ostree/boot.1/default/abc/0";
+    let mut local = LocalKargs::default();
+    local.append("rd.debug");
+    // Both given at install time and added locally
+    local.append("nosmt");
+    let install = ["nosmt".to_owned(), "rw".to_owned()];
+    let r = annotate_kargs(&KargSet::parse(options), &image, &local, &install);
+    let r = r
+        .iter()
+        .map(|k| (k.karg.as_str(), k.source.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        r,
+        [
+            ("root=UUID=1234", "inherited"),
+            ("rw", "install"),
+            ("console=tty0", "kargs.d/10-base.toml"),
+            ("console=ttyS0", "kargs.d/20-extra.toml"),
+            // Provided by both; the first file wins
+            ("quiet", "kargs.d/10-base.toml"),
+            ("nosmt", "local"),
+            ("rd.debug", "local"),
+            ("ostree=/ostree/boot.1/default/abc/0", "ostree"),
+        ]
+        .map(|(k, s)| (k, s.to_owned()))
+    );
+    let v = serde_json::to_value(SourcedKarg {
+        karg: "quiet".into(),
+        source: KargSource::Image {
+            file: "10-base.toml".into(),
+        },
+    })
+    .unwrap();
+    assert_eq!(
+        v,
+        serde_json::json!({"karg": "quiet", "source": {"type": "image", "file": "10-base.toml"}})
+    );
+}
+
+#[test]
+fn test_write_kargs_diff() -> Result<()> {
+    let image = |file: &str| KargSource::Image { file: file.into() };
+    let sourced = |kargs: &[(&str, KargSource)]| {
+        kargs
+            .iter()
+            .map(|(karg, source)| SourcedKarg {
+                karg: (*karg).into(),
+                source: source.clone(),
+            })
+            .collect::<Vec<_>>()
+    };
+    let old = sourced(&[
+        ("root=UUID=1234", KargSource::Inherited),
+        ("mitigations=auto", image("10-base.toml")),
+        ("quiet", image("10-base.toml")),
+        ("ostree=/ostree/boot.1/default/abc/0", KargSource::Ostree),
+    ]);
+    let new = sourced(&[
+        ("root=UUID=1234", KargSource::Inherited),
+        ("mitigations=off", image("10-base.toml")),
+        ("nosmt", image("20-extra.toml")),
+        ("ostree=/ostree/boot.1/default/def/0", KargSource::Ostree),
+    ]);
+    let mut out = Vec::new();
+    write_kargs_diff(&mut out, &old, &new)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "Kernel arguments:
+  - quiet (kargs.d/10-base.toml)
+  + nosmt (kargs.d/20-extra.toml)
+  ~ mitigations=auto -> mitigations=off (kargs.d/10-base.toml)
+"
+    );
+
+    // No changes (other than ostree=), no output
+    let mut out = Vec::new();
+    let mut same = old.clone();
+    same[3].karg = "ostree=/ostree/boot.1/default/def/0".into();
+    write_kargs_diff(&mut out, &old, &same)?;
+    assert!(out.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_validate_karg() {
+    for karg in [
+        "quiet",
+        "console=ttyS0,115200n8",
+        "foo=",
+        "a=b=c",
+        r#"dm-mod.create="a b""#,
+    ] {
+        validate_karg(karg).unwrap();
+    }
+    for karg in [
+        "",
+        "=foo",
+        "foo bar",
+        "foo\tbar",
+        "foo=\"bar",
+        "foo=\x01",
+        "root=UUID=1234",
+        "ostree",
+        "ostree=/x",
+    ] {
+        assert!(validate_karg(karg).is_err(), "{karg}");
+    }
+}
+
+#[test]
+fn test_parse_karg_file() -> Result<()> {
+    let buf = "# Serial console\nconsole=ttyS0,115200n8\n\n  nosmt\t\n\
+               # An argument with a space\ndm-mod.create=\"a b\"\n";
+    assert_eq!(
+        parse_karg_file(buf)?,
+        ["console=ttyS0,115200n8", "nosmt", r#"dm-mod.create="a b""#]
+    );
+    assert!(parse_karg_file("\n# nothing\n")?.is_empty());
+    for (buf, expected) in [
+        (
+            "quiet\n\nroot=UUID=1234\n",
+            "Line 3: Cannot change reserved kernel argument root=",
+        ),
+        (
+            "# ostree is managed\nostree=/ostree/boot.1\n",
+            "Line 2: Cannot change reserved kernel argument ostree=",
+        ),
+        (
+            "console=ttyS0 quiet\n",
+            "Line 1: Expected a single kernel argument: \"console=ttyS0 quiet\"",
+        ),
+    ] {
+        assert_eq!(format!("{:#}", parse_karg_file(buf).unwrap_err()), expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_local_kargs() -> Result<()> {
+    use cap_std_ext::cap_std;
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let mut local = LocalKargs::load(&td)?;
+    assert!(local.is_empty());
+    let base = KargSet::parse("root=UUID=1234 rw console=tty0 quiet");
+
+    local.append("nosmt");
+    local.append("nosmt");
+    local.delete("console");
+    local.append("console=ttyS0");
+    local.store(&td)?;
+    let local = LocalKargs::load(&td)?;
+    assert_eq!(local.append, ["nosmt", "console=ttyS0"]);
+    assert_eq!(local.delete, ["console"]);
+    let mut kargs = base.clone();
+    local.apply(&mut kargs)?;
+    assert_eq!(
+        kargs.to_string(),
+        "root=UUID=1234 rw quiet nosmt console=ttyS0"
+    );
+    // Applying is idempotent, as it happens on every upgrade
+    local.apply(&mut kargs)?;
+    assert_eq!(
+        kargs.to_string(),
+        "root=UUID=1234 rw quiet nosmt console=ttyS0"
+    );
+
+    // Deleting cancels a previous append, and vice versa
+    let mut local = local;
+    local.delete("nosmt");
+    local.delete("console");
+    local.append("quiet");
+    assert_eq!(local.append, ["quiet"]);
+    assert_eq!(local.delete, ["console", "nosmt"]);
+    local.delete("quiet");
+    local.append("nosmt");
+    local.append("console");
+    local.delete("console");
+    assert_eq!(local.append, ["nosmt"]);
+    assert_eq!(local.delete, ["quiet", "console"]);
+
+    // Storing an empty set removes the file
+    let local = LocalKargs::default();
+    local.store(&td)?;
+    assert!(!td.try_exists(LOCAL_KARGS)?);
+    Ok(())
+}
+
+#[test]
+fn test_kargs_for_new_deployment() -> Result<()> {
+    let merge = KargSet::parse(
+        "root=UUID=1234 rw console=tty0 mitigations=auto quiet rd.debug ostree=/ostree/boot.1/default/abc/0",
+    );
+    // What kargs.d provided to the merge deployment; `nosmt` was since
+    // removed by other means.
+    let old = KargSet::parse("console=tty0 mitigations=auto quiet nosmt");
+    let new = KargSet::parse("console=tty0 mitigations=off nosmt splash");
+    let none = LocalKargs::default();
+    let r = kargs_for_new_deployment(&merge, &old, &new, &none)?;
+    assert_eq!(
+        r.to_strings(),
+        [
+            "root=UUID=1234",
+            "rw",
+            "console=tty0",
+            "rd.debug",
+            "ostree=/ostree/boot.1/default/abc/0",
+            "mitigations=off",
+            "splash"
+        ]
+    );
+
+    // No changes in kargs.d, no changes in the kernel arguments
+    let r = kargs_for_new_deployment(&merge, &old, &old, &none)?;
+    assert_eq!(r, merge);
+
+    // Machine-local changes are applied last
+    let mut local = LocalKargs::default();
+    local.delete("splash");
+    local.append("nosmt");
+    let r = kargs_for_new_deployment(&merge, &old, &new, &local)?;
+    assert!(!r.iter().any(|k| k.key() == "splash"));
+    assert_eq!(r.iter().last().unwrap().as_str(), "nosmt");
+    Ok(())
+}
+
+#[test]
+fn test_recorded_kargs_d() -> Result<()> {
+    let origin = ostree::glib::KeyFile::new();
+    assert_eq!(recorded_kargs_d(&origin)?, None);
+    let kargs = KargSet::parse(r#"console=ttyS0,115200n8 foo="a b" nosmt"#);
+    record_kargs_d(&origin, &kargs);
+    assert_eq!(recorded_kargs_d(&origin)?, Some(kargs));
+    // Nothing provided is distinct from not being recorded
+    record_kargs_d(&origin, &KargSet::default());
+    assert_eq!(recorded_kargs_d(&origin)?, Some(KargSet::default()));
+    Ok(())
+}
+
+#[test]
+fn test_kargs_d_run_of_host() -> Result<()> {
+    use cap_std_ext::cap_std;
+    let deployment = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let host = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    for d in [KARGS_D, ETC_KARGS_D, RUN_KARGS_D] {
+        deployment.create_dir_all(d)?;
+    }
+    host.create_dir_all(RUN_KARGS_D)?;
+    host.create_dir_all(ETC_KARGS_D)?;
+    deployment.write(format!("{KARGS_D}/10-base.toml"), r#"kargs = ["quiet"]"#)?;
+    deployment.write(
+        format!("{ETC_KARGS_D}/20-local.toml"),
+        r#"kargs = ["audit=1"]"#,
+    )?;
+    // Not used, since /run is that of the running system
+    deployment.write(
+        format!("{RUN_KARGS_D}/30-stale.toml"),
+        r#"kargs = ["stale"]"#,
+    )?;
+    host.write(
+        format!("{RUN_KARGS_D}/30-debug.toml"),
+        r#"kargs = ["debug"]"#,
+    )?;
+    host.write(
+        format!("{ETC_KARGS_D}/40-host.toml"),
+        r#"kargs = ["nosmt"]"#,
+    )?;
+
+    let dirs = with_overrides(read_kargs_dir(&deployment, KARGS_D)?, &host, &deployment)?;
+    let r = evaluate_kargs_dirs(&dirs, "x86_64", Firmware::Efi)?;
+    let r = r
+        .kargs
+        .iter()
+        .map(|(s, k)| (s.to_string(), k.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        r,
+        [
+            ("kargs.d/10-base.toml", "quiet"),
+            ("/etc/bootc/kargs.d/20-local.toml", "audit=1"),
+            ("/run/bootc/kargs.d/30-debug.toml", "debug"),
+        ]
+        .map(|(s, k)| (s.to_owned(), k))
+    );
+
+    // When staging, both /etc and /run are those of the running system
+    let dirs = with_overrides(read_kargs_dir(&deployment, KARGS_D)?, &host, &host)?;
+    let r = evaluate_kargs_dirs(&dirs, "x86_64", Firmware::Efi)?;
+    let r = r.kargs.into_iter().map(|(_, k)| k).collect::<KargSet>();
+    assert_eq!(r.to_strings(), ["quiet", "debug", "nosmt"]);
+    Ok(())
+}
+
+#[test]
+fn test_match_firmware_staging() -> Result<()> {
+    // The kargs.d of a new image, as evaluated when staging it
+    let image = [
+        ("10-base.toml", r#"kargs = ["quiet"]"#),
+        (
+            "20-efi.toml",
+            r#"kargs = ["console=ttyS0"]
+match-firmware = "efi""#,
+        ),
+        (
+            "20-bios.toml",
+            r#"kargs = ["console=tty0"]
+match-firmware = "bios""#,
+        ),
+    ]
+    .into_iter()
+    .map(|(name, contents)| (name.to_owned(), Some(contents.to_owned())))
+    .collect::<KargsDir>();
+    let merge = KargSet::parse("root=UUID=1234 quiet");
+    let old = KargSet::parse("quiet");
+    for (firmware, expected) in [
+        (Firmware::Efi, "console=ttyS0"),
+        (Firmware::Bios, "console=tty0"),
+    ] {
+        let dirs = [(KARGS_D, image.clone())];
+        let new = evaluate_kargs_dirs(&dirs, "x86_64", firmware)?;
+        let new = new.kargs.into_iter().map(|(_, k)| k).collect::<KargSet>();
+        let r = kargs_for_new_deployment(&merge, &old, &new, &LocalKargs::default())?;
+        assert_eq!(r.to_strings(), ["root=UUID=1234", "quiet", expected]);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_kargs_diff_for_update() -> Result<()> {
+    // As done by print_kargs_diff_to_commit()
+    let image = |file: &str, karg: &str| {
+        let file = file.to_owned();
+        (KargSource::Image { file }, Karg::new(karg))
+    };
+    let booted = KargSet::parse("root=UUID=1234 mitigations=auto quiet");
+    let old = [
+        image("10-base.toml", "mitigations=auto"),
+        image("10-base.toml", "quiet"),
+    ];
+    let new = [
+        image("10-base.toml", "mitigations=off"),
+        image("20-extra.toml", "nosmt"),
+    ];
+    let local = LocalKargs::default();
+    let set = |kargs: &[(KargSource, Karg)]| kargs.iter().map(|(_, k)| k.clone()).collect();
+    let from = annotate_kargs(&booted, &old, &local, &[]);
+    let kargs = kargs_for_new_deployment(&booted, &set(&old), &set(&new), &local)?;
+    let to = annotate_kargs(&kargs, &new, &local, &[]);
+    let mut out = Vec::new();
+    write_kargs_diff(&mut out, &from, &to)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "Kernel arguments:
+  - quiet (kargs.d/10-base.toml)
+  + nosmt (kargs.d/20-extra.toml)
+  ~ mitigations=auto -> mitigations=off (kargs.d/10-base.toml)
+"
+    );
+
+    // An update without kargs.d changes
+    let kargs = kargs_for_new_deployment(&booted, &set(&old), &set(&old), &local)?;
+    let to = annotate_kargs(&kargs, &old, &local, &[]);
+    let mut out = Vec::new();
+    write_kargs_diff(&mut out, &from, &to)?;
+    assert!(out.is_empty());
+    Ok(())
+}
@@ -1,4 +1,9 @@
+// The kernel command line helpers are only used by `bootc install`.
+#![cfg_attr(not(feature = "install"), allow(dead_code))]
+
 use anyhow::Result;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
 use fn_error_context::context;
 
 /// This is used by dracut.
@@ -37,6 +42,41 @@ pub(crate) fn find_first_cmdline_arg<'a>(
     .next()
 }
 
+/// Find the version of the kernel in a root filesystem, i.e. the name of the
+/// directory in `/usr/lib/modules` which contains a `vmlinuz`.
+#[context("Finding kernel version")]
+pub(crate) fn find_kernel_version(root: &Dir) -> Result<Option<String>> {
+    let Some(modules) = root.open_dir_optional("usr/lib/modules")? else {
+        return Ok(None);
+    };
+    for entry in modules.entries()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if modules.try_exists(format!("{name}/vmlinuz"))? {
+            return Ok(Some(name.to_owned()));
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn test_find_kernel_version() -> Result<()> {
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    assert_eq!(find_kernel_version(&td)?, None);
+    td.create_dir_all("usr/lib/modules/6.7.0-nokernel")?;
+    assert_eq!(find_kernel_version(&td)?, None);
+    td.create_dir_all("usr/lib/modules/6.8.9-300.fc40.x86_64")?;
+    td.write("usr/lib/modules/6.8.9-300.fc40.x86_64/vmlinuz", "kernel")?;
+    assert_eq!(
+        find_kernel_version(&td)?.as_deref(),
+        Some("6.8.9-300.fc40.x86_64")
+    );
+    Ok(())
+}
+
 #[test]
 fn test_find_first() {
     let kargs = &["foo=bar", "root=/dev/vda", "blah", "root=/dev/other"];
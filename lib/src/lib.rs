@@ -17,20 +17,45 @@
 #![allow(clippy::needless_borrow)]
 #![allow(clippy::needless_borrows_for_generic_args)]
 
+mod aleph;
+mod auth;
+mod autoupdate;
+mod backend;
 pub mod cli;
 pub(crate) mod deploy;
+mod deployment;
+mod download;
+mod etcdiff;
+mod etcmigrate;
+mod fetchconfig;
+mod fsck;
 pub(crate) mod generator;
+mod hosttype;
+mod image;
 pub(crate) mod journal;
+mod localimage;
+pub mod logging;
 mod lsm;
+mod maintenance;
 pub(crate) mod metadata;
+mod ocilayout;
+mod progress_jsonl;
+mod ratelimit;
 mod reboot;
 mod reexec;
+mod registries;
+mod schema;
+mod sigpolicy;
 mod status;
 mod task;
+mod updatecheck;
+mod usroverlay;
 mod utils;
 
 #[cfg(feature = "internal-testing-api")]
 mod privtests;
+#[cfg(test)]
+mod testutils;
 
 #[cfg(feature = "install")]
 mod blockdev;
@@ -41,12 +66,13 @@ mod containerenv;
 #[cfg(feature = "install")]
 mod install;
 mod k8sapitypes;
-#[cfg(feature = "install")]
+mod kargs;
 mod kernel;
-#[cfg(feature = "install")]
 pub(crate) mod mount;
 #[cfg(feature = "install")]
 mod podman;
+#[cfg(feature = "install")]
+mod progress;
 pub mod spec;
 
 #[cfg(feature = "docgen")]
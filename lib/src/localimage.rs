@@ -0,0 +1,135 @@
+//! # Images from the host's container storage
+//!
+//! Images built on the host via e.g. `podman build` can be deployed directly
+//! via the `containers-storage` transport.  bootc runs as root, and hence uses
+//! the root storage; images in a user's rootless storage must be copied there
+//! first.
+
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use ostree_ext::container::Transport;
+
+/// The root container storage.
+const ROOT_STORAGE: &str = "/var/lib/containers/storage";
+
+/// Returns true if the image `name` can only be in local storage; podman names
+/// locally built images `localhost/...`.  Note `localhost:5000/...` is a registry.
+pub(crate) fn is_local_name(name: &str) -> bool {
+    name.split_once('/')
+        .is_some_and(|(domain, _)| domain == "localhost")
+}
+
+/// The storage root of a `containers-storage` reference with an explicit
+/// `[driver@root+runroot:options]` storage specifier, if any.
+fn storage_root(name: &str) -> Option<&str> {
+    let spec = name.strip_prefix('[')?.split_once(']')?.0;
+    let root = spec.split_once('@').map_or(spec, |(_, root)| root);
+    root.split(['+', ':']).next()
+}
+
+/// Check whether `name` exists in the root container storage.
+#[context("Querying container storage for {name}")]
+pub(crate) fn image_exists(name: &str) -> Result<bool> {
+    let status = Command::new("podman")
+        .args(["image", "exists", name])
+        .status()
+        .context("Running podman")?;
+    Ok(status.success())
+}
+
+fn not_found(name: &str, sudo_user: Option<&str>) -> anyhow::Error {
+    let mut msg =
+        format!("Image {name} was not found in the root container storage ({ROOT_STORAGE})");
+    if let Some(user) = sudo_user {
+        msg.push_str(&format!(
+            "; if it was built by {user} without root privileges, copy it via: podman image scp {user}@localhost::{name} root@localhost::"
+        ));
+    }
+    anyhow::anyhow!(msg)
+}
+
+/// Determine the transport to fetch `name` with.  Images named `localhost/...`
+/// are only ever local, and hence fetched from the host's container storage
+/// rather than a registry.  Returns the transport, and whether it was changed.
+/// `exists` checks whether an image is in the root container storage, and
+/// `sudo_user` is the user invoking bootc via sudo, if any.
+pub(crate) fn resolve_transport(
+    transport: Transport,
+    name: &str,
+    exists: impl FnOnce(&str) -> Result<bool>,
+    sudo_user: Option<&str>,
+) -> Result<(Transport, bool)> {
+    match transport {
+        Transport::Registry if is_local_name(name) => {
+            if !exists(name)? {
+                return Err(not_found(name, sudo_user));
+            }
+            Ok((Transport::ContainerStorage, true))
+        }
+        Transport::ContainerStorage => {
+            if let Some(root) = storage_root(name) {
+                if root != ROOT_STORAGE {
+                    anyhow::bail!(
+                        "bootc requires images in the root container storage ({ROOT_STORAGE}), not {root}"
+                    );
+                }
+            } else if !exists(name)? {
+                return Err(not_found(name, sudo_user));
+            }
+            Ok((transport, false))
+        }
+        t => Ok((t, false)),
+    }
+}
+
+#[test]
+fn test_resolve_transport() -> Result<()> {
+    let present = |n: &str| Ok(n == "localhost/test" || n == "quay.io/example/os");
+    let resolve = |transport, name| resolve_transport(transport, name, present, Some("dev"));
+    use Transport::*;
+
+    // Locally built images are found in the host's storage
+    assert_eq!(
+        resolve(Registry, "localhost/test")?,
+        (ContainerStorage, true)
+    );
+    assert_eq!(
+        resolve(ContainerStorage, "localhost/test")?,
+        (ContainerStorage, false)
+    );
+    // But a registry on localhost isn't
+    assert_eq!(resolve(Registry, "localhost:5000/test")?, (Registry, false));
+    assert_eq!(resolve(Registry, "quay.io/example/os")?, (Registry, false));
+    assert_eq!(resolve(OciDir, "/srv/os")?, (OciDir, false));
+
+    // Images in rootless storage
+    assert_eq!(
+        resolve(Registry, "localhost/other").unwrap_err().to_string(),
+        "Image localhost/other was not found in the root container storage (/var/lib/containers/storage); \
+if it was built by dev without root privileges, copy it via: podman image scp dev@localhost::localhost/other root@localhost::"
+    );
+    let e = resolve_transport(ContainerStorage, "localhost/other", present, None).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Image localhost/other was not found in the root container storage (/var/lib/containers/storage)"
+    );
+    assert_eq!(
+        resolve(
+            ContainerStorage,
+            "[overlay@/home/dev/.local/share/containers/storage+/run/user/1000/containers]localhost/test"
+        )
+        .unwrap_err()
+        .to_string(),
+        "bootc requires images in the root container storage (/var/lib/containers/storage), not /home/dev/.local/share/containers/storage"
+    );
+    assert_eq!(
+        resolve(
+            ContainerStorage,
+            "[overlay@/var/lib/containers/storage]localhost/other"
+        )?,
+        (ContainerStorage, false)
+    );
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! # Logging setup
+//!
+//! Configures the global tracing subscriber.  Filtering is controlled
+//! by `RUST_LOG`; the output format is selected via the environment.
+
+use std::os::fd::AsFd;
+
+use anyhow::Result;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable which selects the log format; one of `plain`, `json` or `journald`.
+pub const LOG_FORMAT_ENV: &str = "BOOTC_LOG_FORMAT";
+
+/// Set by systemd to `<device>:<inode>` when stderr is connected to the journal.
+const JOURNAL_STREAM_ENV: &str = "JOURNAL_STREAM";
+
+/// The output format for log messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable text
+    Plain,
+    /// One JSON object per line
+    Json,
+    /// The native journal protocol, which includes fields such as `PRIORITY`
+    /// and `CODE_FILE`/`CODE_LINE`
+    Journald,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let r = match s {
+            "plain" => Self::Plain,
+            "json" => Self::Json,
+            "journald" => Self::Journald,
+            o => anyhow::bail!("Invalid {LOG_FORMAT_ENV}: {o}"),
+        };
+        Ok(r)
+    }
+}
+
+impl LogFormat {
+    /// Determine the log format from the environment.  An explicit [`LOG_FORMAT_ENV`]
+    /// takes precedence; otherwise use the journal if stderr is connected to it,
+    /// and plain text if not.
+    pub fn from_env() -> Result<Self> {
+        if let Some(v) = std::env::var_os(LOG_FORMAT_ENV) {
+            let v = v
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("Invalid UTF-8 in {LOG_FORMAT_ENV}"))?;
+            return v.parse();
+        }
+        let r = if stderr_is_journal() {
+            Self::Journald
+        } else {
+            Self::Plain
+        };
+        Ok(r)
+    }
+}
+
+/// Check whether our stderr is the stream referenced by `JOURNAL_STREAM`; see
+/// <https://www.freedesktop.org/software/systemd/man/latest/systemd.exec.html#%24JOURNAL_STREAM>
+fn stderr_is_journal() -> bool {
+    let Some(v) = std::env::var_os(JOURNAL_STREAM_ENV) else {
+        return false;
+    };
+    let Some((dev, ino)) = v.to_str().and_then(|v| v.split_once(':')) else {
+        return false;
+    };
+    let Ok(st) = rustix::fs::fstat(std::io::stderr().as_fd()) else {
+        return false;
+    };
+    dev.parse::<u64>().ok() == Some(st.st_dev) && ino.parse::<u64>().ok() == Some(st.st_ino)
+}
+
+/// Create a subscriber for the given format, writing to `writer`.  The journald
+/// format does not use the writer, unless connecting to the journal fails in which
+/// case we fall back to plain text.
+fn subscriber<W>(format: LogFormat, writer: W) -> Box<dyn tracing::Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let registry = tracing_subscriber::registry().with(EnvFilter::from_default_env());
+    match format {
+        LogFormat::Plain => {
+            // Don't include timestamps and such because they're not really useful and
+            // too verbose, and plus several log targets such as journald will already
+            // include timestamps.
+            let layer = tracing_subscriber::fmt::layer()
+                .without_time()
+                .with_target(false)
+                .compact()
+                .with_writer(writer);
+            Box::new(registry.with(layer))
+        }
+        LogFormat::Json => {
+            let layer = tracing_subscriber::fmt::layer().json().with_writer(writer);
+            Box::new(registry.with(layer))
+        }
+        LogFormat::Journald => match tracing_journald::layer() {
+            Ok(layer) => Box::new(registry.with(layer)),
+            Err(_) => subscriber(LogFormat::Plain, writer),
+        },
+    }
+}
+
+/// Initialize the global tracing subscriber, logging to stderr in the format
+/// selected by [`LogFormat::from_env`].
+pub fn initialize_tracing() {
+    let format = LogFormat::from_env().unwrap_or_else(|e| {
+        eprintln!("warning: {e:#}; using plain logs");
+        LogFormat::Plain
+    });
+    initialize_tracing_with(format)
+}
+
+/// Like [`initialize_tracing`], but with an explicitly provided format.
+pub fn initialize_tracing_with(format: LogFormat) {
+    subscriber(format, std::io::stderr).init()
+}
+
+#[test]
+fn test_log_format_parse() {
+    assert_eq!("plain".parse::<LogFormat>().unwrap(), LogFormat::Plain);
+    assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+    assert_eq!(
+        "journald".parse::<LogFormat>().unwrap(),
+        LogFormat::Journald
+    );
+    assert!("yaml".parse::<LogFormat>().is_err());
+}
+
+#[test]
+fn test_json_logs() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Buf {
+        fn write(&mut self, b: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(b)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buf = Buf::default();
+    let writer = buf.clone();
+    let s = subscriber(LogFormat::Json, move || writer.clone());
+    tracing::subscriber::with_default(s, || {
+        tracing::error!("first message");
+        tracing::error!(foo = 42, "second message");
+    });
+    let buf = buf.0.lock().unwrap();
+    let lines = std::str::from_utf8(&buf)?.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    for (line, msg) in lines.iter().zip(["first message", "second message"]) {
+        let v: serde_json::Value = serde_json::from_str(line)?;
+        assert_eq!(v["level"], "ERROR");
+        assert_eq!(v["fields"]["message"], msg);
+        assert_eq!(v["target"], "bootc_lib::logging");
+    }
+    Ok(())
+}
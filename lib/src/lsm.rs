@@ -19,6 +19,9 @@ use ostree_ext::ostree;
 use rustix::fd::AsFd;
 use std::os::fd::AsRawFd;
 
+#[cfg(feature = "install")]
+use crate::progress::ProgressReporter;
+
 /// The mount path for selinux
 #[cfg(feature = "install")]
 const SELINUXFS: &str = "/sys/fs/selinux";
@@ -261,12 +264,15 @@ pub(crate) fn ensure_labeled(
 
 /// A wrapper for creating a directory, also optionally setting a SELinux label.
 /// The provided `skip` parameter is a device/inode that we will ignore (and not traverse).
+/// Each visited object increments the counter of `progress`, and the directory
+/// being traversed is shown as its message.
 #[cfg(feature = "install")]
 pub(crate) fn ensure_dir_labeled_recurse(
     root: &Dir,
     path: &mut Utf8PathBuf,
     policy: &ostree::SePolicy,
     skip: Option<(libc::dev_t, libc::ino64_t)>,
+    progress: &mut ProgressReporter,
 ) -> Result<()> {
     // Juggle the cap-std requirement for relative paths vs the libselinux
     // requirement for absolute paths by special casing the empty string "" as "."
@@ -280,6 +286,8 @@ pub(crate) fn ensure_dir_labeled_recurse(
     let mut n = 0u64;
 
     let metadata = root.symlink_metadata(path_for_read)?;
+    progress.set_message(|| format!("/{path}"));
+    progress.inc(1);
     match ensure_labeled(root, path, &metadata, policy)? {
         SELinuxLabelState::Unlabeled => {
             n += 1;
@@ -304,8 +312,9 @@ pub(crate) fn ensure_dir_labeled_recurse(
         path.push(name);
 
         if metadata.is_dir() {
-            ensure_dir_labeled_recurse(root, path, policy, skip)?;
+            ensure_dir_labeled_recurse(root, path, policy, skip, progress)?;
         } else {
+            progress.inc(1);
             match ensure_labeled(root, path, &metadata, policy)? {
                 SELinuxLabelState::Unlabeled => {
                     n += 1;
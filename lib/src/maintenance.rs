@@ -0,0 +1,396 @@
+//! # Maintenance windows
+//!
+//! Updates can be staged at any time, but `bootc upgrade --apply` (and
+//! `bootc switch --apply`) only reboot within one of the maintenance windows
+//! configured in the `[maintenance]` section of `bootc-fetch-config(5)`.
+//!
+//! Windows are specified in wall-clock time, e.g. `Sat,Sun 02:00-05:00`; the
+//! logic here is generic over the timezone, so that DST transitions are
+//! handled by whatever [`TimeZone`] is in use.
+
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use chrono::{
+    DateTime, Datelike, Days, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+    Utc, Weekday,
+};
+use serde::Deserialize;
+
+use crate::spec::MaintenanceWindow;
+
+/// A set of weekdays, as a bitmask indexed by the number of days from Monday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Days7(u8);
+
+impl Days7 {
+    const ALL: Self = Self(0b111_1111);
+
+    fn contains(&self, day: Weekday) -> bool {
+        self.0 & (1 << day.num_days_from_monday()) != 0
+    }
+
+    fn insert(&mut self, day: Weekday) {
+        self.0 |= 1 << day.num_days_from_monday();
+    }
+}
+
+impl FromStr for Days7 {
+    type Err = anyhow::Error;
+
+    /// Parse e.g. `Mon-Fri`, `Sat,Sun` or `*`; ranges may wrap, e.g. `Fri-Mon`.
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "*" {
+            return Ok(Self::ALL);
+        }
+        let day = |d: &str| {
+            Weekday::from_str(d.trim()).map_err(|_| anyhow::anyhow!("Invalid weekday {d:?}"))
+        };
+        let mut days = Self(0);
+        for part in s.split(',') {
+            if let Some((first, last)) = part.split_once('-') {
+                let (mut d, last) = (day(first)?, day(last)?);
+                days.insert(d);
+                while d != last {
+                    d = d.succ();
+                    days.insert(d);
+                }
+            } else {
+                days.insert(day(part)?);
+            }
+        }
+        Ok(days)
+    }
+}
+
+/// A weekly maintenance window.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Window {
+    /// The days on which the window starts
+    days: Days7,
+    start: NaiveTime,
+    /// If not after `start`, the window extends into the following day
+    end: NaiveTime,
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    /// Parse a window such as `Mon-Fri 22:00-02:00`; without days, the window
+    /// applies to every day.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (days, times) = match s.rsplit_once(char::is_whitespace) {
+            Some((days, times)) => (days.trim().parse()?, times),
+            None => (Days7::ALL, s),
+        };
+        let (start, end) = times.split_once('-').ok_or_else(|| {
+            anyhow::anyhow!("Invalid window {s:?}; expected e.g. Sat 02:00-04:00")
+        })?;
+        let time = |t: &str| {
+            NaiveTime::parse_from_str(t, "%H:%M").with_context(|| format!("Parsing {t:?} in {s:?}"))
+        };
+        Ok(Self {
+            days,
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// The timezone in which windows are specified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum WindowTimezone {
+    /// The system timezone, i.e. `/etc/localtime`
+    #[default]
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl FromStr for WindowTimezone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "local" => Ok(Self::Local),
+            "UTC" | "utc" => Ok(Self::Fixed(FixedOffset::east_opt(0).unwrap())),
+            o => o.parse().map(Self::Fixed).map_err(|_| {
+                anyhow::anyhow!(
+                    "Invalid timezone {o:?}; expected local, UTC or an offset like +02:00"
+                )
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for WindowTimezone {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Resolve a wall-clock time; times skipped by a DST transition are moved
+/// forward to the first valid minute, and for repeated times the earliest
+/// (or `latest`) instant is used.
+fn resolve<Tz: TimeZone>(tz: &Tz, t: NaiveDateTime, latest: bool) -> Option<DateTime<Tz>> {
+    (0..=180).find_map(|m| {
+        let r = tz.from_local_datetime(&(t + chrono::Duration::minutes(m)));
+        if latest {
+            r.latest()
+        } else {
+            r.earliest()
+        }
+    })
+}
+
+impl Window {
+    /// The instance of this window starting on `date`.
+    fn on<Tz: TimeZone>(&self, tz: &Tz, date: NaiveDate) -> Option<(DateTime<Tz>, DateTime<Tz>)> {
+        let end_date = if self.end > self.start {
+            date
+        } else {
+            date.checked_add_days(Days::new(1))?
+        };
+        let start = resolve(tz, date.and_time(self.start), false)?;
+        let end = resolve(tz, end_date.and_time(self.end), true)?;
+        Some((start, end))
+    }
+}
+
+/// The start and end of the window we're currently in, or otherwise of the next one.
+pub(crate) fn next_window<Tz: TimeZone>(
+    windows: &[Window],
+    now: &DateTime<Tz>,
+) -> Option<(DateTime<Tz>, DateTime<Tz>)> {
+    let tz = now.timezone();
+    let today = now.naive_local().date();
+    // Start one day back, for windows extending past midnight
+    let yesterday = today.checked_sub_days(Days::new(1))?;
+    yesterday
+        .iter_days()
+        .take(9)
+        .flat_map(|date| {
+            windows
+                .iter()
+                .filter(move |w| w.days.contains(date.weekday()))
+                .filter_map(|w| w.on(&tz, date))
+                .collect::<Vec<_>>()
+        })
+        .filter(|(_, end)| end > now)
+        .min_by(|a, b| a.0.cmp(&b.0))
+}
+
+fn to_status<Tz: TimeZone>(
+    window: Option<(DateTime<Tz>, DateTime<Tz>)>,
+    now: &DateTime<Tz>,
+) -> Option<MaintenanceWindow> {
+    window.map(|(start, end)| MaintenanceWindow {
+        active: &start <= now,
+        start: start.fixed_offset(),
+        end: end.fixed_offset(),
+    })
+}
+
+/// The current or next maintenance window, if any are configured.
+pub(crate) fn current(
+    windows: &[Window],
+    tz: WindowTimezone,
+    now: DateTime<Utc>,
+) -> Option<MaintenanceWindow> {
+    if windows.is_empty() {
+        return None;
+    }
+    match tz {
+        WindowTimezone::Local => {
+            let now = now.with_timezone(&Local);
+            to_status(next_window(windows, &now), &now)
+        }
+        WindowTimezone::Fixed(o) => {
+            let now = now.with_timezone(&o);
+            to_status(next_window(windows, &now), &now)
+        }
+    }
+}
+
+#[test]
+fn test_parse_window() {
+    let hm = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    let w: Window = "Mon-Fri 22:00-02:30".parse().unwrap();
+    assert_eq!(w.days, Days7(0b001_1111));
+    assert_eq!((w.start, w.end), (hm(22, 0), hm(2, 30)));
+    let w: Window = "Sat,sunday 02:00-05:00".parse().unwrap();
+    assert_eq!(w.days, Days7(0b110_0000));
+    let w: Window = "Fri-Mon 01:00-02:00".parse().unwrap();
+    assert_eq!(w.days, Days7(0b111_0001));
+    for s in ["03:00-04:00", "* 03:00-04:00"] {
+        assert_eq!(s.parse::<Window>().unwrap().days, Days7::ALL);
+    }
+    for s in [
+        "",
+        "Mon",
+        "Mon 03:00",
+        "Mon 3-4",
+        "Someday 03:00-04:00",
+        "Mon 03:00-25:00",
+    ] {
+        assert!(s.parse::<Window>().is_err(), "{s}");
+    }
+
+    assert_eq!(
+        "local".parse::<WindowTimezone>().unwrap(),
+        WindowTimezone::Local
+    );
+    let utc = WindowTimezone::Fixed(FixedOffset::east_opt(0).unwrap());
+    assert_eq!("UTC".parse::<WindowTimezone>().unwrap(), utc);
+    let cest = WindowTimezone::Fixed(FixedOffset::east_opt(7200).unwrap());
+    assert_eq!("+02:00".parse::<WindowTimezone>().unwrap(), cest);
+    assert!("Europe/Berlin".parse::<WindowTimezone>().is_err());
+}
+
+/// Central European time in 2024, with DST from 2024-03-31 01:00 UTC to
+/// 2024-10-27 01:00 UTC.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+struct TestCet;
+
+#[cfg(test)]
+impl TimeZone for TestCet {
+    type Offset = FixedOffset;
+
+    fn from_offset(_: &FixedOffset) -> Self {
+        Self
+    }
+
+    fn offset_from_local_date(&self, local: &NaiveDate) -> chrono::LocalResult<FixedOffset> {
+        self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+    }
+
+    fn offset_from_local_datetime(
+        &self,
+        local: &NaiveDateTime,
+    ) -> chrono::LocalResult<FixedOffset> {
+        let [dst, std] = [7200, 3600].map(|s| FixedOffset::east_opt(s).unwrap());
+        let valid = |o: FixedOffset| {
+            let utc = *local - chrono::Duration::seconds(o.local_minus_utc().into());
+            self.offset_from_utc_datetime(&utc) == o
+        };
+        match (valid(dst), valid(std)) {
+            (true, true) => chrono::LocalResult::Ambiguous(dst, std),
+            (true, false) => chrono::LocalResult::Single(dst),
+            (false, true) => chrono::LocalResult::Single(std),
+            (false, false) => chrono::LocalResult::None,
+        }
+    }
+
+    fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+        self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+    }
+
+    fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+        let t = |s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        let dst = (t("2024-03-31 01:00")..t("2024-10-27 01:00")).contains(utc);
+        FixedOffset::east_opt(if dst { 7200 } else { 3600 }).unwrap()
+    }
+}
+
+#[test]
+fn test_next_window() {
+    let windows = |s: &[&str]| {
+        s.iter()
+            .map(|w| w.parse().unwrap())
+            .collect::<Vec<Window>>()
+    };
+    let utc = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+    let at = |s: &str| utc(s).with_timezone(&TestCet);
+    let next = |w: &[Window], now: &str| {
+        let now = at(now);
+        next_window(w, &now).map(|(s, e)| (s.to_rfc3339(), e.to_rfc3339()))
+    };
+    let expect = |s: &str, e: &str| Some((s.to_owned(), e.to_owned()));
+
+    // 2024-05-01 is a Wednesday
+    let weekend = windows(&["Sat,Sun 02:00-05:00"]);
+    assert_eq!(
+        next(&weekend, "2024-05-01T12:00:00+02:00"),
+        expect("2024-05-04T02:00:00+02:00", "2024-05-04T05:00:00+02:00")
+    );
+    // Within the window; also an instance starting on Sunday
+    assert_eq!(
+        next(&weekend, "2024-05-05T04:59:00+02:00"),
+        expect("2024-05-05T02:00:00+02:00", "2024-05-05T05:00:00+02:00")
+    );
+    // Just after the last window of the week
+    assert_eq!(
+        next(&weekend, "2024-05-05T05:00:00+02:00"),
+        expect("2024-05-11T02:00:00+02:00", "2024-05-11T05:00:00+02:00")
+    );
+    // The timezone matters, not the UTC time
+    assert_eq!(
+        next(&weekend, "2024-05-04T01:30:00Z"),
+        expect("2024-05-04T02:00:00+02:00", "2024-05-04T05:00:00+02:00")
+    );
+
+    // Windows across midnight, from the day before
+    let nightly = windows(&["Mon-Fri 23:00-01:00", "Sun 12:00-13:00"]);
+    assert_eq!(
+        next(&nightly, "2024-05-04T00:30:00+02:00"),
+        expect("2024-05-03T23:00:00+02:00", "2024-05-04T01:00:00+02:00")
+    );
+    assert_eq!(
+        next(&nightly, "2024-05-04T01:00:00+02:00"),
+        expect("2024-05-05T12:00:00+02:00", "2024-05-05T13:00:00+02:00")
+    );
+
+    // Spring forward: 02:00-03:00 doesn't exist on 2024-03-31
+    let dst = windows(&["Sun 02:30-03:30"]);
+    assert_eq!(
+        next(&dst, "2024-03-30T12:00:00+01:00"),
+        expect("2024-03-31T03:00:00+02:00", "2024-03-31T03:30:00+02:00")
+    );
+    // A window entirely within the gap starts and ends at the transition
+    let gap = windows(&["Sun 02:10-02:50"]);
+    let (s, e) = next(&gap, "2024-03-30T12:00:00+01:00").unwrap();
+    assert_eq!(s, e);
+    // The wall-clock time is kept across the transition
+    let daily = windows(&["04:00-05:00"]);
+    assert_eq!(
+        next(&daily, "2024-03-30T06:00:00+01:00"),
+        expect("2024-03-31T04:00:00+02:00", "2024-03-31T05:00:00+02:00")
+    );
+
+    // Fall back: 02:00-03:00 is repeated on 2024-10-27; the window covers both
+    assert_eq!(
+        next(&dst, "2024-10-26T12:00:00+02:00"),
+        expect("2024-10-27T02:30:00+02:00", "2024-10-27T03:30:00+01:00")
+    );
+    assert_eq!(
+        next(&dst, "2024-10-27T02:45:00+01:00"),
+        expect("2024-10-27T02:30:00+02:00", "2024-10-27T03:30:00+01:00")
+    );
+
+    // Status
+    let now = utc("2024-05-01T10:00:00Z");
+    let w = current(
+        &weekend,
+        WindowTimezone::Fixed(FixedOffset::east_opt(7200).unwrap()),
+        now,
+    )
+    .unwrap();
+    assert!(!w.active);
+    assert_eq!(w.start.to_rfc3339(), "2024-05-04T02:00:00+02:00");
+    let w = current(&daily, "UTC".parse().unwrap(), utc("2024-05-01T04:30:00Z")).unwrap();
+    assert!(w.active);
+    assert_eq!(current(&[], WindowTimezone::Local, now), None);
+}
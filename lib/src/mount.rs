@@ -1,6 +1,9 @@
 //! Helpers for interacting with mountpoints
 
-use anyhow::{anyhow, Context, Result};
+// Most of these are only used by `bootc install`.
+#![cfg_attr(not(feature = "install"), allow(dead_code))]
+
+use anyhow::{anyhow, Result};
 use camino::Utf8Path;
 use fn_error_context::context;
 use serde::Deserialize;
@@ -34,8 +37,7 @@ fn run_findmnt(args: &[&str], path: &str) -> Result<Filesystem> {
         .args(args)
         .arg(path)
         .quiet()
-        .read()?;
-    let o: Findmnt = serde_json::from_str(&o).context("Parsing findmnt output")?;
+        .read_json::<Findmnt>()?;
     o.filesystems
         .into_iter()
         .next()
@@ -0,0 +1,252 @@
+//! # Images in OCI layouts
+//!
+//! Air-gapped systems receive updates as files, e.g. on removable media:
+//! either an OCI image layout directory (the `oci` transport) or a tar archive
+//! of one (`oci-archive`).  Such an image is recorded in the origin under its
+//! logical image reference, i.e. the registry image it is a copy of, so that
+//! once connectivity exists `bootc upgrade` fetches from the registry.  The
+//! logical reference is given via `--target-imgref`, or found in the
+//! `org.opencontainers.image.ref.name` annotation of the layout, as written by
+//! e.g. `skopeo copy docker://quay.io/example/os oci-archive:os.tar:quay.io/example/os:latest`.
+
+use anyhow::Result;
+use camino::Utf8Path;
+use fn_error_context::context;
+use ostree_ext::container::{ImageReference, Transport};
+use ostree_ext::oci_spec::image::{ImageIndex, ANNOTATION_REF_NAME};
+
+use crate::task::Task;
+
+/// The index of an OCI image layout, relative to its root.
+const INDEX_JSON: &str = "index.json";
+
+/// Whether images fetched via `transport` are read from local files.
+pub(crate) fn is_local(transport: Transport) -> bool {
+    matches!(transport, Transport::OciDir | Transport::OciArchive)
+}
+
+/// Parse the target of `bootc switch`, which may include a transport prefix
+/// such as `oci-archive:` if `--transport` is the default.
+pub(crate) fn parse_target(transport: &str, target: &str) -> Result<ImageReference> {
+    let explicit = Transport::try_from(transport)?;
+    if explicit == Transport::Registry {
+        // Note this does not match e.g. `localhost:5000/os`, as `localhost`
+        // is not a transport.
+        if let Ok(imgref) = ImageReference::try_from(target) {
+            return Ok(imgref);
+        }
+    }
+    Ok(ImageReference {
+        transport: explicit,
+        name: target.to_string(),
+    })
+}
+
+/// Split the name of an `oci` or `oci-archive` image into the path of the
+/// layout, and the name of the image in it, if any.
+fn split_name(name: &str) -> (&Utf8Path, Option<&str>) {
+    match name.split_once(':') {
+        Some((path, refname)) if !refname.is_empty() => (Utf8Path::new(path), Some(refname)),
+        Some((path, _)) => (Utf8Path::new(path), None),
+        None => (Utf8Path::new(name), None),
+    }
+}
+
+/// Read the index of the layout at `path`.
+#[context("Reading index of {path}")]
+fn read_index(transport: Transport, path: &Utf8Path) -> Result<ImageIndex> {
+    let index = match transport {
+        Transport::OciDir => {
+            let f = std::fs::File::open(path.join(INDEX_JSON))?;
+            serde_json::from_reader(std::io::BufReader::new(f))?
+        }
+        Transport::OciArchive => {
+            // Archives may also have been created with `tar -C <dir> .`
+            let index = Task::new_quiet("tar")
+                .args(["-xOf", path.as_str(), "--no-anchored", INDEX_JSON])
+                .read()?;
+            serde_json::from_str(&index)?
+        }
+        o => anyhow::bail!("Not an OCI layout transport: {o}"),
+    };
+    Ok(index)
+}
+
+/// Find the logical image reference of the image `refname` (or the only
+/// image) in the index; annotations which are only a tag are ignored.
+fn logical_name(index: &ImageIndex, refname: Option<&str>) -> Option<String> {
+    let refnames = index
+        .manifests()
+        .iter()
+        .map(|m| {
+            m.annotations()
+                .as_ref()
+                .and_then(|a| a.get(ANNOTATION_REF_NAME))
+        })
+        .collect::<Vec<_>>();
+    let found = match (refname, refnames.as_slice()) {
+        (Some(refname), refnames) => refnames.iter().flatten().find(|&&n| n == refname).copied(),
+        (None, [only]) => *only,
+        (None, _) => None,
+    };
+    found.filter(|n| n.contains('/')).cloned()
+}
+
+/// Determine the image reference to record in the origin when fetching
+/// `source`: `target_imgref` if given, or the logical reference of an image in
+/// an OCI layout.
+#[context("Determining target image of {source}")]
+pub(crate) fn target_of(
+    source: &ImageReference,
+    target_imgref: Option<&str>,
+) -> Result<Option<ImageReference>> {
+    let name = if let Some(name) = target_imgref {
+        Some(name.to_owned())
+    } else if is_local(source.transport) {
+        let (path, refname) = split_name(&source.name);
+        logical_name(&read_index(source.transport, path)?, refname)
+    } else {
+        None
+    };
+    let target = name.map(|name| ImageReference {
+        transport: Transport::Registry,
+        name,
+    });
+    if let Some(target) = target.as_ref() {
+        if target.name.contains(':') && ImageReference::try_from(target.name.as_str()).is_ok() {
+            anyhow::bail!("The target image reference must not include a transport: {target}");
+        }
+    }
+    Ok(target)
+}
+
+#[test]
+fn test_parse_target() -> Result<()> {
+    let parse = |transport, target| parse_target(transport, target).unwrap().to_string();
+    assert_eq!(
+        parse("registry", "oci-archive:/mnt/usb/os-update.tar"),
+        "oci-archive:/mnt/usb/os-update.tar"
+    );
+    assert_eq!(
+        parse("registry", "oci:/mnt/usb/os-oci/"),
+        "oci:/mnt/usb/os-oci/"
+    );
+    assert_eq!(
+        parse("registry", "quay.io/example/os:latest"),
+        "docker://quay.io/example/os:latest"
+    );
+    assert_eq!(
+        parse("registry", "localhost:5000/os"),
+        "docker://localhost:5000/os"
+    );
+    assert_eq!(
+        parse("registry", "docker://quay.io/example/os"),
+        "docker://quay.io/example/os"
+    );
+    // An explicit transport is never overridden
+    assert_eq!(parse("oci", "/srv/os:latest"), "oci:/srv/os:latest");
+    assert!(parse_target("ftp", "/srv/os").is_err());
+
+    assert_eq!(
+        split_name("/mnt/usb/os.tar:quay.io/example/os:latest"),
+        (
+            Utf8Path::new("/mnt/usb/os.tar"),
+            Some("quay.io/example/os:latest")
+        )
+    );
+    assert_eq!(
+        split_name("/mnt/usb/os/"),
+        (Utf8Path::new("/mnt/usb/os/"), None)
+    );
+    assert_eq!(
+        split_name("/mnt/usb/os:"),
+        (Utf8Path::new("/mnt/usb/os"), None)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_target_of() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let td = Utf8Path::from_path(td.path()).unwrap();
+    let manifest = |refname: Option<&str>| {
+        let annotations = refname
+            .map(|n| format!(r#","annotations":{{"{ANNOTATION_REF_NAME}":"{n}"}}"#))
+            .unwrap_or_default();
+        format!(
+            r#"{{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:{}","size":1234{annotations}}}"#,
+            "a".repeat(64)
+        )
+    };
+    let layout = |name: &str, manifests: &[Option<&str>]| -> Result<camino::Utf8PathBuf> {
+        let dir = td.join(name);
+        std::fs::create_dir(&dir)?;
+        let manifests = manifests
+            .iter()
+            .map(|&n| manifest(n))
+            .collect::<Vec<_>>()
+            .join(",");
+        std::fs::write(
+            dir.join(INDEX_JSON),
+            format!(r#"{{"schemaVersion":2,"manifests":[{manifests}]}}"#),
+        )?;
+        std::fs::write(dir.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+        Ok(dir)
+    };
+    let target = |source: &str, target_imgref| {
+        target_of(&ImageReference::try_from(source).unwrap(), target_imgref)
+            .unwrap()
+            .map(|t| t.to_string())
+    };
+
+    // The annotation of the only image in the layout
+    let dir = layout("single", &[Some("quay.io/example/os:latest")])?;
+    assert_eq!(
+        target(&format!("oci:{dir}"), None).as_deref(),
+        Some("docker://quay.io/example/os:latest")
+    );
+    // An explicit target takes precedence
+    assert_eq!(
+        target(&format!("oci:{dir}"), Some("quay.io/example/other:stable")).as_deref(),
+        Some("docker://quay.io/example/other:stable")
+    );
+    assert!(target_of(
+        &ImageReference::try_from(format!("oci:{dir}").as_str())?,
+        Some("oci:/srv/os")
+    )
+    .is_err());
+
+    // The same layout, as an archive
+    let archive = td.join("os.tar");
+    Task::new_quiet("tar")
+        .args(["-cf", archive.as_str(), "-C", dir.as_str(), "."])
+        .run()?;
+    assert_eq!(
+        target(&format!("oci-archive:{archive}"), None).as_deref(),
+        Some("docker://quay.io/example/os:latest")
+    );
+
+    // With several images, the name selects one
+    let dir = layout(
+        "multi",
+        &[Some("quay.io/example/os:1"), Some("quay.io/example/os:2")],
+    )?;
+    assert_eq!(target(&format!("oci:{dir}"), None), None);
+    assert_eq!(
+        target(&format!("oci:{dir}:quay.io/example/os:2"), None).as_deref(),
+        Some("docker://quay.io/example/os:2")
+    );
+    // Tags and missing annotations aren't logical references
+    let dir = layout("tag", &[Some("latest")])?;
+    assert_eq!(target(&format!("oci:{dir}"), None), None);
+    let dir = layout("none", &[None])?;
+    assert_eq!(target(&format!("oci:{dir}"), None), None);
+    // Other transports are unchanged
+    assert_eq!(target("docker://quay.io/example/os", None), None);
+    assert!(target_of(
+        &ImageReference::try_from(format!("oci:{td}/missing").as_str())?,
+        None
+    )
+    .is_err());
+    Ok(())
+}
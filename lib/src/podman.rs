@@ -1,13 +1,21 @@
+use std::time::Duration;
+
 use anyhow::{anyhow, Result};
 use serde::Deserialize;
 
 use crate::install::run_in_host_mountns;
-use crate::task::Task;
+use crate::task::{Task, TaskTimeoutError};
+use crate::utils::{retry, RetryPolicy};
 
 /// Where we look inside our container to find our own image
 /// for use with `bootc install`.
 pub(crate) const CONTAINER_STORAGE: &str = "/var/lib/containers";
 
+/// Inspecting a local image should be fast; if it isn't, something is
+/// likely wedged (e.g. a stuck lock on the storage) and we'd rather fail.
+/// This is per attempt, so with retries we give up after about a minute.
+const INSPECT_TIMEOUT: Duration = Duration::from_secs(20);
+
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub(crate) struct Inspect {
@@ -16,11 +24,15 @@ pub(crate) struct Inspect {
 
 /// Given an image ID, return its manifest digest
 pub(crate) fn imageid_to_digest(imgid: &str) -> Result<String> {
-    let out = Task::new_cmd("podman inspect", run_in_host_mountns("podman"))
-        .args(["inspect", imgid])
-        .quiet()
-        .read()?;
-    let o: Vec<Inspect> = serde_json::from_str(&out)?;
+    // A wedged storage lock is often transient, so retry on timeout
+    let is_timeout = |e: &anyhow::Error| e.downcast_ref::<TaskTimeoutError>().is_some();
+    let o = retry(&RetryPolicy::default(), is_timeout, || {
+        Task::new_cmd("podman inspect", run_in_host_mountns("podman"))
+            .args(["inspect", imgid])
+            .quiet()
+            .timeout(INSPECT_TIMEOUT)
+            .read_json::<Vec<Inspect>>()
+    })?;
     let i = o
         .into_iter()
         .next()
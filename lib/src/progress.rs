@@ -0,0 +1,215 @@
+//! Lightweight progress reporting for long-running operations which
+//! don't have a natural byte count (e.g. walking a filesystem tree).
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Minimum interval between updates for the interactive renderer.
+const TTY_INTERVAL: Duration = Duration::from_millis(100);
+
+enum Backend {
+    Noop,
+    /// A single line which is updated in place
+    Tty(Box<dyn Write + Send>),
+}
+
+/// Reports progress of a task as a set of phases, each with a counter and
+/// optional total.  Output is rate limited; phase changes and [`Self::finish`]
+/// always emit an update.
+pub(crate) struct ProgressReporter {
+    backend: Backend,
+    interval: Duration,
+    clock: Box<dyn FnMut() -> Instant + Send>,
+    last_update: Option<Instant>,
+    phase: String,
+    current: u64,
+    total: Option<u64>,
+    message: Option<String>,
+}
+
+impl std::fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProgressReporter")
+            .field("phase", &self.phase)
+            .field("current", &self.current)
+            .field("total", &self.total)
+            .finish()
+    }
+}
+
+impl ProgressReporter {
+    fn new(backend: Backend, interval: Duration) -> Self {
+        Self {
+            backend,
+            interval,
+            clock: Box::new(Instant::now),
+            last_update: None,
+            phase: String::new(),
+            current: 0,
+            total: None,
+            message: None,
+        }
+    }
+
+    /// A reporter which outputs nothing.
+    pub(crate) fn noop() -> Self {
+        Self::new(Backend::Noop, Duration::ZERO)
+    }
+
+    /// An interactive reporter which renders a single updating line.
+    pub(crate) fn tty(out: impl Write + Send + 'static) -> Self {
+        Self::new(Backend::Tty(Box::new(out)), TTY_INTERVAL)
+    }
+
+    /// Use an interactive reporter on stderr if it is a terminal, otherwise output nothing.
+    pub(crate) fn tty_or_noop() -> Self {
+        use std::io::IsTerminal;
+        let stderr = std::io::stderr();
+        if stderr.is_terminal() {
+            Self::tty(stderr)
+        } else {
+            Self::noop()
+        }
+    }
+
+    /// Start a new phase, resetting the counter.
+    pub(crate) fn set_phase(&mut self, name: impl Into<String>, total: Option<u64>) {
+        if !self.phase.is_empty() {
+            self.end_line();
+        }
+        self.phase = name.into();
+        self.current = 0;
+        self.total = total;
+        self.message = None;
+        self.emit(true);
+    }
+
+    /// Increment the counter for the current phase.
+    pub(crate) fn inc(&mut self, n: u64) {
+        self.current += n;
+        self.emit(false);
+    }
+
+    /// Set a free-form message describing the current item.  Since the message
+    /// is only shown when an update is emitted, `f` is only invoked then.
+    pub(crate) fn set_message(&mut self, f: impl FnOnce() -> String) {
+        if self.due() {
+            self.message = Some(f());
+            self.emit(false);
+        }
+    }
+
+    /// Emit a final update for the current phase.
+    pub(crate) fn finish(&mut self) {
+        if self.phase.is_empty() {
+            return;
+        }
+        self.emit(true);
+        self.end_line();
+        self.phase.clear();
+    }
+
+    fn due(&mut self) -> bool {
+        let now = (self.clock)();
+        self.last_update.map_or(true, |last| {
+            now.saturating_duration_since(last) >= self.interval
+        })
+    }
+
+    fn emit(&mut self, force: bool) {
+        if matches!(self.backend, Backend::Noop) || !(force || self.due()) {
+            return;
+        }
+        self.last_update = Some((self.clock)());
+        // Progress output is best-effort; errors writing it are ignored.
+        let _ = match &mut self.backend {
+            Backend::Noop => Ok(()),
+            Backend::Tty(out) => {
+                let total = self.total.map(|t| format!("/{t}")).unwrap_or_default();
+                let message = self
+                    .message
+                    .as_deref()
+                    .map(|m| format!(" {m}"))
+                    .unwrap_or_default();
+                write!(
+                    out,
+                    "\r\x1b[K{}: {}{total}{message}",
+                    self.phase, self.current
+                )
+                .and_then(|_| out.flush())
+            }
+        };
+    }
+
+    fn end_line(&mut self) {
+        if let Backend::Tty(out) = &mut self.backend {
+            let _ = writeln!(out);
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_reporter(
+    backend: fn(SharedBuf) -> ProgressReporter,
+) -> (
+    ProgressReporter,
+    SharedBuf,
+    std::sync::Arc<std::sync::Mutex<Instant>>,
+) {
+    use std::sync::{Arc, Mutex};
+    let buf = SharedBuf::default();
+    let mut p = backend(buf.clone());
+    let now = Arc::new(Mutex::new(Instant::now()));
+    let clock = now.clone();
+    p.clock = Box::new(move || *clock.lock().unwrap());
+    (p, buf, now)
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuf {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuf {
+    fn write(&mut self, b: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(b)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_progress_rate_limit() {
+    let (mut p, buf, now) = test_reporter(ProgressReporter::tty);
+    p.set_phase("walk", None);
+    assert_eq!(buf.take(), "\r\x1b[Kwalk: 0");
+    for _ in 0..10 {
+        p.inc(1);
+    }
+    assert_eq!(buf.take(), "");
+    *now.lock().unwrap() += TTY_INTERVAL / 2;
+    p.inc(1);
+    assert_eq!(buf.take(), "");
+    *now.lock().unwrap() += TTY_INTERVAL / 2;
+    p.inc(1);
+    assert_eq!(buf.take(), "\r\x1b[Kwalk: 12");
+    p.inc(1);
+    // Not due, so the message isn't computed
+    p.set_message(|| unreachable!());
+    *now.lock().unwrap() += TTY_INTERVAL;
+    p.set_message(|| "foo".into());
+    assert_eq!(buf.take(), "\r\x1b[Kwalk: 13 foo");
+    p.finish();
+    assert_eq!(buf.take(), "\r\x1b[Kwalk: 13 foo\n");
+    // No phase, nothing to finish
+    p.finish();
+    assert_eq!(buf.take(), "");
+}
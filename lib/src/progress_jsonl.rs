@@ -0,0 +1,570 @@
+//! # Machine-readable progress for image pulls and installs
+//!
+//! With `--progress-fd`, a stream of JSON events (one per line) describing the
+//! progress of fetching and deploying an image, or of `bootc install`, is
+//! written to the given file descriptor.  The schema is defined by [`Event`]; every record also carries
+//! a `version` field, and new fields or event types may be added within a
+//! version.
+
+use std::io::Write;
+use std::os::fd::{BorrowedFd, FromRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::deploy::FetchStats;
+use crate::ratelimit::Rate;
+
+/// The version of the event schema.
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+/// Minimum interval between [`Event::LayerProgress`] events.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The phases of an update or install; each is announced via [`Event::Phase`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Phase {
+    /// Wiping and partitioning the target devices (install only)
+    #[cfg(feature = "install")]
+    Partitioning,
+    /// Creating filesystems (install only)
+    #[cfg(feature = "install")]
+    Formatting,
+    /// Fetching the manifest and configuration, and verifying signatures
+    Verifying,
+    /// Fetching and unpacking layers
+    Fetching,
+    /// Writing the merged image into the ostree repository
+    Writing,
+    /// Deploying the image into the target root (install only)
+    #[cfg(feature = "install")]
+    Deploying,
+    /// Installing the bootloader (install only)
+    #[cfg(feature = "install")]
+    Bootloader,
+    /// Creating the new deployment, or for installs, finalizing the filesystems
+    Finalizing,
+}
+
+/// A single progress event.
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum Event {
+    /// The layers which need to be fetched are known.
+    #[serde(rename_all = "camelCase")]
+    Start {
+        image: String,
+        layers: u64,
+        bytes_total: u64,
+        /// Layers which are already stored locally, e.g. from a previous
+        /// interrupted pull, and are not fetched again
+        layers_stored: u64,
+        bytes_stored: u64,
+        /// The configured limit of the download rate, in bytes per second
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bandwidth_limit: Option<u64>,
+    },
+    /// A new phase has started.
+    #[serde(rename_all = "camelCase")]
+    Phase {
+        phase: Phase,
+        /// The amount of data the phase handles, where known; e.g. the size
+        /// of the target devices for [`Phase::Partitioning`]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes_total: Option<u64>,
+    },
+    /// Fetching a layer has started.
+    #[serde(rename_all = "camelCase")]
+    LayerStarted { digest: String, bytes_total: u64 },
+    /// Progress fetching a layer; emitted at most every 500ms.
+    #[serde(rename_all = "camelCase")]
+    LayerProgress {
+        digest: String,
+        bytes: u64,
+        bytes_total: u64,
+        /// The download rate of this layer, in bytes per second
+        rate: u64,
+        /// The estimated time until all layers are fetched, in seconds
+        eta_secs: Option<u64>,
+    },
+    /// A layer has been fetched and stored.
+    #[serde(rename_all = "camelCase")]
+    LayerCompleted { digest: String, bytes: u64 },
+    /// The operation is complete; this is always the last event.
+    #[serde(rename_all = "camelCase")]
+    Summary {
+        layers: u64,
+        bytes: u64,
+        elapsed_secs: f64,
+        /// The total size of the image's layers, including those which
+        /// were already stored locally
+        image_bytes: u64,
+        /// The size of the layers reused from local storage
+        bytes_reused: u64,
+        /// The percentage of the image which didn't need to be downloaded
+        percent_saved: u64,
+    },
+    /// The installation is complete; this is always the last event of an install.
+    #[cfg(feature = "install")]
+    #[serde(rename_all = "camelCase")]
+    InstallSummary {
+        image: String,
+        /// The digest of the installed image
+        digest: Option<String>,
+        /// The target device, or the device backing the target filesystem
+        device: String,
+        elapsed_secs: f64,
+    },
+}
+
+/// An event as written, i.e. one line of the output.
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "ProgressRecord")]
+pub(crate) struct Record<'a> {
+    /// The version of the event schema
+    version: u32,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// The layer currently being fetched.
+#[derive(Debug)]
+struct CurrentLayer {
+    digest: String,
+    bytes_total: u64,
+    started: Instant,
+}
+
+/// Writes [`Event`]s as JSON lines.  Errors writing events are ignored.
+pub(crate) struct ProgressWriter {
+    out: Box<dyn Write + Send>,
+    clock: Box<dyn FnMut() -> Instant + Send>,
+    started: Instant,
+    fetch_started: Option<Instant>,
+    last_progress: Option<Instant>,
+    bytes_total: u64,
+    bytes_stored: u64,
+    bytes_completed: u64,
+    layers_completed: u64,
+    layer: Option<CurrentLayer>,
+}
+
+impl std::fmt::Debug for ProgressWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProgressWriter")
+            .field("bytes_total", &self.bytes_total)
+            .field("bytes_completed", &self.bytes_completed)
+            .field("layer", &self.layer)
+            .finish()
+    }
+}
+
+/// Parse the argument of `--progress-fd`.
+pub(crate) fn parse_fd(s: &str) -> Result<RawFd> {
+    let fd: RawFd = s.parse().context("Parsing file descriptor")?;
+    if fd <= libc::STDERR_FILENO {
+        anyhow::bail!("Cannot use standard input, output or error for progress");
+    }
+    Ok(fd)
+}
+
+impl ProgressWriter {
+    pub(crate) fn new(out: impl Write + Send + 'static) -> Self {
+        Self::with_clock(Box::new(out), Box::new(Instant::now))
+    }
+
+    fn with_clock(
+        out: Box<dyn Write + Send>,
+        mut clock: Box<dyn FnMut() -> Instant + Send>,
+    ) -> Self {
+        Self {
+            out,
+            started: clock(),
+            clock,
+            fetch_started: None,
+            last_progress: None,
+            bytes_total: 0,
+            bytes_stored: 0,
+            bytes_completed: 0,
+            layers_completed: 0,
+            layer: None,
+        }
+    }
+
+    /// Take ownership of the file descriptor `fd`, which must be open.
+    #[allow(unsafe_code)]
+    pub(crate) fn from_fd(fd: RawFd) -> Result<Self> {
+        // SAFETY: We only borrow the fd to check it is valid.
+        rustix::io::fcntl_getfd(unsafe { BorrowedFd::borrow_raw(fd) })
+            .with_context(|| format!("Invalid progress fd {fd}"))?;
+        // SAFETY: The fd is valid, and was passed to us to own.
+        let f = unsafe { std::fs::File::from_raw_fd(fd) };
+        Ok(Self::new(f))
+    }
+
+    fn emit(&mut self, event: &Event) {
+        let record = Record {
+            version: SCHEMA_VERSION,
+            event,
+        };
+        // Progress output is best-effort; errors writing it are ignored.
+        let _ = serde_json::to_writer(&mut self.out, &record)
+            .map_err(Into::into)
+            .and_then(|_| writeln!(self.out))
+            .and_then(|_| self.out.flush());
+    }
+
+    /// Announce the sizes of the layers which will be fetched, and of those
+    /// which are already stored.
+    pub(crate) fn start(
+        &mut self,
+        image: &str,
+        to_fetch: impl Iterator<Item = u64>,
+        stored: impl Iterator<Item = u64>,
+        bandwidth_limit: Option<Rate>,
+    ) {
+        let count = |sizes: &mut dyn Iterator<Item = u64>| {
+            sizes.fold((0, 0), |(n, total), size| (n + 1, total + size))
+        };
+        let (n, bytes_total) = count(&mut { to_fetch });
+        let (layers_stored, bytes_stored) = count(&mut { stored });
+        self.bytes_total = bytes_total;
+        self.bytes_stored = bytes_stored;
+        self.emit(&Event::Start {
+            image: image.to_owned(),
+            layers: n,
+            bytes_total,
+            layers_stored,
+            bytes_stored,
+            bandwidth_limit: bandwidth_limit.map(|r| r.bytes_per_sec()),
+        });
+    }
+
+    pub(crate) fn phase(&mut self, phase: Phase) {
+        self.phase_with_size(phase, None)
+    }
+
+    /// Announce a phase, along with the amount of data it handles.
+    pub(crate) fn phase_with_size(&mut self, phase: Phase, bytes_total: Option<u64>) {
+        if phase == Phase::Fetching {
+            self.fetch_started = Some((self.clock)());
+        }
+        self.emit(&Event::Phase { phase, bytes_total });
+    }
+
+    pub(crate) fn layer_started(&mut self, digest: &str, bytes_total: u64) {
+        self.layer = Some(CurrentLayer {
+            digest: digest.to_owned(),
+            bytes_total,
+            started: (self.clock)(),
+        });
+        // Always emit the first progress event of a layer
+        self.last_progress = None;
+        self.emit(&Event::LayerStarted {
+            digest: digest.to_owned(),
+            bytes_total,
+        });
+    }
+
+    /// Update the number of bytes fetched of the current layer; rate limited.
+    pub(crate) fn layer_progress(&mut self, bytes: u64) {
+        let now = (self.clock)();
+        let Some(layer) = self.layer.as_ref() else {
+            return;
+        };
+        let due = self.last_progress.map_or(true, |last| {
+            now.saturating_duration_since(last) >= PROGRESS_INTERVAL
+        });
+        if !due {
+            return;
+        }
+        self.last_progress = Some(now);
+        let rate = bytes_per_sec(bytes, now.saturating_duration_since(layer.started));
+        let fetched = self.bytes_completed + bytes;
+        let elapsed = now.saturating_duration_since(self.fetch_started.unwrap_or(self.started));
+        let eta_secs = match bytes_per_sec(fetched, elapsed) {
+            0 => None,
+            overall => Some(self.bytes_total.saturating_sub(fetched) / overall),
+        };
+        let event = Event::LayerProgress {
+            digest: layer.digest.clone(),
+            bytes,
+            bytes_total: layer.bytes_total,
+            rate,
+            eta_secs,
+        };
+        self.emit(&event);
+    }
+
+    pub(crate) fn layer_completed(&mut self) {
+        let Some(layer) = self.layer.take() else {
+            return;
+        };
+        self.bytes_completed += layer.bytes_total;
+        self.layers_completed += 1;
+        self.emit(&Event::LayerCompleted {
+            digest: layer.digest,
+            bytes: layer.bytes_total,
+        });
+    }
+
+    /// Emit the final summary.
+    pub(crate) fn finish(mut self) {
+        let elapsed = (self.clock)().saturating_duration_since(self.started);
+        let stats = FetchStats {
+            total: self.bytes_total + self.bytes_stored,
+            downloaded: self.bytes_completed,
+        };
+        self.emit(&Event::Summary {
+            layers: self.layers_completed,
+            bytes: self.bytes_completed,
+            elapsed_secs: elapsed.as_secs_f64(),
+            image_bytes: stats.total,
+            bytes_reused: stats.reused(),
+            percent_saved: stats.percent_saved(),
+        });
+    }
+
+    /// Emit the final summary of an install.
+    #[cfg(feature = "install")]
+    pub(crate) fn finish_install(mut self, image: &str, digest: Option<&str>, device: &str) {
+        let elapsed = (self.clock)().saturating_duration_since(self.started);
+        self.emit(&Event::InstallSummary {
+            image: image.to_owned(),
+            digest: digest.map(ToOwned::to_owned),
+            device: device.to_owned(),
+            elapsed_secs: elapsed.as_secs_f64(),
+        });
+    }
+}
+
+fn bytes_per_sec(bytes: u64, elapsed: Duration) -> u64 {
+    match elapsed.as_millis() {
+        0 => 0,
+        ms => (u128::from(bytes) * 1000 / ms)
+            .try_into()
+            .unwrap_or(u64::MAX),
+    }
+}
+
+#[test]
+fn test_parse_fd() {
+    assert_eq!(parse_fd("3").unwrap(), 3);
+    for v in ["0", "1", "2", "-1", "foo"] {
+        assert!(parse_fd(v).is_err(), "{v}");
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuf {
+    fn write(&mut self, b: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(b)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl SharedBuf {
+    fn events(&self) -> Vec<serde_json::Value> {
+        let out = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+        out.lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect()
+    }
+}
+
+/// A writer to a [`SharedBuf`], with a clock advanced by the returned function.
+#[cfg(test)]
+fn test_writer() -> (ProgressWriter, SharedBuf, impl Fn(u64)) {
+    use std::sync::{Arc, Mutex};
+
+    let buf = SharedBuf::default();
+    let now = Arc::new(Mutex::new(Instant::now()));
+    let clock = now.clone();
+    let p = ProgressWriter::with_clock(
+        Box::new(buf.clone()),
+        Box::new(move || *clock.lock().unwrap()),
+    );
+    let advance = move |ms| *now.lock().unwrap() += Duration::from_millis(ms);
+    (p, buf, advance)
+}
+
+#[test]
+fn test_progress_events() {
+    let (mut p, buf, advance) = test_writer();
+
+    // A synthetic pull of two layers
+    let layers = [("sha256:aaaa", 4000u64), ("sha256:bbbb", 2000)];
+    p.phase(Phase::Verifying);
+    advance(100);
+    p.start(
+        "quay.io/example/os:latest",
+        layers.iter().map(|l| l.1),
+        // One layer was fetched by a previous attempt
+        [3000].into_iter(),
+        Some("1MB/s".parse().unwrap()),
+    );
+    p.phase(Phase::Fetching);
+    for (digest, size) in layers {
+        p.layer_started(digest, size);
+        for i in 1..=4 {
+            advance(250);
+            p.layer_progress(size / 4 * i);
+        }
+        p.layer_progress(size);
+        p.layer_completed();
+    }
+    p.phase(Phase::Writing);
+    p.phase(Phase::Finalizing);
+    advance(900);
+    p.finish();
+
+    let events = buf.events();
+    for e in events.iter() {
+        crate::schema::validate(crate::schema::SchemaType::Progress, e);
+    }
+    assert!(events.iter().all(|e| e["version"] == SCHEMA_VERSION));
+    let types = events
+        .iter()
+        .map(|e| e["type"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        types,
+        [
+            "phase",
+            "start",
+            "phase",
+            "layerStarted",
+            // Rate limited to one event per 500ms
+            "layerProgress",
+            "layerProgress",
+            "layerCompleted",
+            "layerStarted",
+            "layerProgress",
+            "layerProgress",
+            "layerCompleted",
+            "phase",
+            "phase",
+            "summary"
+        ]
+    );
+    let phases = events
+        .iter()
+        .filter_map(|e| e["phase"].as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(phases, ["verifying", "fetching", "writing", "finalizing"]);
+    assert_eq!(events[1]["bytesTotal"], 6000);
+    assert_eq!(events[1]["layersStored"], 1);
+    assert_eq!(events[1]["bytesStored"], 3000);
+    assert_eq!(events[1]["bandwidthLimit"], 1_000_000);
+
+    // 1000 bytes after 250ms for the first layer
+    assert_eq!(events[4]["bytes"], 1000);
+    assert_eq!(events[4]["rate"], 4000);
+    // 5000 bytes remaining at 4000 bytes/s overall
+    assert_eq!(events[4]["etaSecs"], 1);
+    assert_eq!(events[5]["bytes"], 3000);
+    assert_eq!(events[5]["rate"], 4000);
+    assert_eq!(events[5]["etaSecs"], 0);
+
+    let summary = events.last().unwrap();
+    assert_eq!(summary["layers"], 2);
+    assert_eq!(summary["bytes"], 6000);
+    assert_eq!(summary["elapsedSecs"], 3.0);
+    assert_eq!(summary["imageBytes"], 9000);
+    assert_eq!(summary["bytesReused"], 3000);
+    assert_eq!(summary["percentSaved"], 33);
+}
+
+#[test]
+fn test_install_progress_events() {
+    let (mut p, buf, advance) = test_writer();
+
+    // A synthetic install to a disk, with an image of a single layer
+    p.phase_with_size(Phase::Partitioning, Some(20 << 30));
+    p.phase(Phase::Formatting);
+    advance(2000);
+    p.phase(Phase::Verifying);
+    p.start(
+        "quay.io/example/os:latest",
+        [1000u64].into_iter(),
+        [].into_iter(),
+        None,
+    );
+    p.phase(Phase::Fetching);
+    p.layer_started("sha256:aaaa", 1000);
+    advance(500);
+    p.layer_progress(500);
+    p.layer_completed();
+    p.phase(Phase::Writing);
+    p.phase_with_size(Phase::Deploying, Some(3000));
+    p.phase(Phase::Bootloader);
+    p.phase(Phase::Finalizing);
+    advance(500);
+    p.finish_install("quay.io/example/os:latest", Some("sha256:bbbb"), "/dev/vda");
+
+    let events = buf.events();
+    for e in events.iter() {
+        crate::schema::validate(crate::schema::SchemaType::Progress, e);
+    }
+    assert!(events.iter().all(|e| e["version"] == SCHEMA_VERSION));
+    let types = events
+        .iter()
+        .map(|e| e["type"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        types,
+        [
+            "phase",
+            "phase",
+            "phase",
+            "start",
+            "phase",
+            "layerStarted",
+            "layerProgress",
+            "layerCompleted",
+            "phase",
+            "phase",
+            "phase",
+            "phase",
+            "installSummary"
+        ]
+    );
+    let phases = events
+        .iter()
+        .filter_map(|e| Some((e["phase"].as_str()?, e.get("bytesTotal"))))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        phases,
+        [
+            ("partitioning", Some(&serde_json::json!(20u64 << 30))),
+            ("formatting", None),
+            ("verifying", None),
+            ("fetching", None),
+            ("writing", None),
+            ("deploying", Some(&serde_json::json!(3000))),
+            ("bootloader", None),
+            ("finalizing", None),
+        ]
+    );
+    assert_eq!(
+        events.last().unwrap(),
+        &serde_json::json!({
+            "version": SCHEMA_VERSION,
+            "type": "installSummary",
+            "image": "quay.io/example/os:latest",
+            "digest": "sha256:bbbb",
+            "device": "/dev/vda",
+            "elapsedSecs": 3.0,
+        })
+    );
+}
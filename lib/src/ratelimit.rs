@@ -0,0 +1,152 @@
+//! # Limiting the download rate of image pulls
+//!
+//! Layers are fetched by the container image proxy; we can't throttle the
+//! individual streams, but the importer waits for us to process its progress
+//! messages.  The limit is hence enforced at layer boundaries: after each
+//! layer, we pause until the average rate drops below the limit.
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+/// A transfer rate, in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Rate(u64);
+
+impl Rate {
+    pub(crate) fn bytes_per_sec(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    /// Parse a rate such as `10MiB/s`, `1.5MB/s` or `500Kbps`.  Decimal (`K`,
+    /// `M`, `G`) and binary (`Ki`, `Mi`, `Gi`) prefixes are supported; a `b`
+    /// denotes bits and a `B` (or no unit) bytes.
+    fn from_str(s: &str) -> Result<Self> {
+        let orig = s;
+        let s = s.trim();
+        let (s, bits) = if let Some(s) = s.strip_suffix("bps") {
+            (s, true)
+        } else if let Some(s) = s.strip_suffix("b/s") {
+            (s, true)
+        } else {
+            let s = s.strip_suffix("/s").unwrap_or(s);
+            (s.strip_suffix('B').unwrap_or(s), false)
+        };
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (n, prefix) = s.split_at(split);
+        let multiplier: u64 = match prefix.trim() {
+            "" => 1,
+            "K" | "k" => 1000,
+            "M" => 1000 * 1000,
+            "G" => 1000 * 1000 * 1000,
+            "Ki" => 1 << 10,
+            "Mi" => 1 << 20,
+            "Gi" => 1 << 30,
+            o => anyhow::bail!("Invalid unit prefix {o:?} in rate {orig:?}"),
+        };
+        let n: f64 = n
+            .parse()
+            .with_context(|| format!("Parsing rate {orig:?}"))?;
+        let divisor = if bits { 8.0 } else { 1.0 };
+        let bytes = (n * multiplier as f64 / divisor).round();
+        if !(bytes >= 1.0 && bytes < u64::MAX as f64) {
+            return Err(anyhow!("Invalid rate {orig:?}"));
+        }
+        Ok(Self(bytes as u64))
+    }
+}
+
+impl TryFrom<String> for Rate {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// A token bucket allowing bursts of up to one second worth of data.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: u64,
+    /// Available bytes; negative if we have transferred more than allowed
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate: rate.bytes_per_sec(),
+            tokens: rate.bytes_per_sec() as f64,
+            last: now,
+        }
+    }
+
+    /// Account for `n` bytes transferred at `now`, returning how long to wait
+    /// before transferring more.
+    pub(crate) fn consume(&mut self, n: u64, now: Instant) -> Duration {
+        let rate = self.rate as f64;
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - n as f64;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[test]
+fn test_parse_rate() {
+    const MI: u64 = 1 << 20;
+    let cases = [
+        ("10MiB/s", 10 * MI),
+        ("10 MiB/s", 10 * MI),
+        ("1.5MB/s", 1_500_000),
+        ("500Kbps", 62_500),
+        ("8Mb/s", 1_000_000),
+        ("2GiB", 2 << 30),
+        ("100k", 100_000),
+        ("4096", 4096),
+    ];
+    for (s, expected) in cases {
+        assert_eq!(s.parse::<Rate>().unwrap().bytes_per_sec(), expected, "{s}");
+    }
+    for s in ["", "0", "MiB/s", "10XB/s", "-5MB/s", "1..5M", "0.1b/s"] {
+        assert!(s.parse::<Rate>().is_err(), "{s}");
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let start = Instant::now();
+    let at = |ms| start + Duration::from_millis(ms);
+    let mut bucket = TokenBucket::new("1000B/s".parse().unwrap(), start);
+    // A burst of up to one second is allowed
+    assert_eq!(bucket.consume(1000, start), Duration::ZERO);
+    // Beyond that we need to wait for the tokens to refill
+    assert_eq!(bucket.consume(500, start), Duration::from_millis(500));
+    assert_eq!(bucket.consume(500, at(500)), Duration::from_millis(500));
+    // Idle time refills the bucket, but not beyond the burst size
+    assert_eq!(bucket.consume(0, at(10_000)), Duration::ZERO);
+    assert_eq!(bucket.consume(3000, at(10_000)), Duration::from_secs(2));
+
+    // Simulate transferring 10 chunks as fast as permitted; the total time
+    // taken converges on the limit.
+    let mut bucket = TokenBucket::new("1000B/s".parse().unwrap(), start);
+    let mut now = start;
+    for _ in 0..10 {
+        now += bucket.consume(1000, now);
+    }
+    assert_eq!(now, at(9000));
+}
@@ -1,11 +1,325 @@
 //! Handling of system restarts/reboot
 
 use std::io::Write;
+use std::str::FromStr;
+use std::time::Duration;
 
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::NaiveTime;
 use fn_error_context::context;
 
+use crate::spec::SoftRebootReadiness;
 use crate::task::Task;
 
+/// The unit name used for scheduled reboots via `--apply --when`.
+const SCHEDULED_UNIT: &str = "bootc-apply";
+/// `systemctl soft-reboot` switches into the root mounted here, if any.
+const NEXTROOT: &str = "run/nextroot";
+/// The process whose mount namespace [`NEXTROOT`] is set up in, i.e. systemd.
+/// We usually run in a private mount namespace (see `prepare_for_write()`),
+/// whose mounts systemd would not see.
+pub(crate) const HOST_PID: u32 = 1;
+
+/// Whether `--apply` may use a soft reboot
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum SoftRebootMode {
+    /// Use a soft reboot if the staged deployment has the same kernel and kernel arguments
+    Auto,
+    /// Always use a soft reboot; fail if the staged deployment requires a full reboot
+    Required,
+    /// Always perform a full reboot
+    #[default]
+    Never,
+}
+
+/// When to apply a staged deployment; parsed like the time argument
+/// of `shutdown(8)`, i.e. `now`, `+MINUTES` or `HH:MM`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum When {
+    #[default]
+    Now,
+    /// After the given delay
+    In(Duration),
+    /// At the next occurrence of the given (local) time
+    At(NaiveTime),
+}
+
+impl FromStr for When {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "now" {
+            return Ok(Self::Now);
+        }
+        if let Some(minutes) = s.strip_prefix('+') {
+            let minutes: u64 = minutes
+                .parse()
+                .with_context(|| format!("Parsing minutes in {s:?}"))?;
+            return Ok(if minutes == 0 {
+                Self::Now
+            } else {
+                Self::In(Duration::from_secs(minutes * 60))
+            });
+        }
+        let t = NaiveTime::parse_from_str(s, "%H:%M")
+            .with_context(|| format!("Invalid time {s:?}; expected now, +MINUTES or HH:MM"))?;
+        Ok(Self::At(t))
+    }
+}
+
+/// How the staged deployment is applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RebootKind {
+    /// A full reboot
+    Reboot,
+    /// `systemctl soft-reboot`, restarting only userspace
+    SoftReboot,
+}
+
+impl RebootKind {
+    fn verb(&self) -> &'static str {
+        match self {
+            Self::Reboot => "reboot",
+            Self::SoftReboot => "soft-reboot",
+        }
+    }
+}
+
+/// What `--apply` does after staging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RebootPlan {
+    pub(crate) kind: RebootKind,
+    pub(crate) when: When,
+}
+
+impl RebootPlan {
+    /// The command to initiate the reboot; scheduled reboots use a transient
+    /// timer unit, which can be cancelled via `systemctl stop`.
+    pub(crate) fn command(&self) -> Vec<String> {
+        let reboot = ["systemctl", self.kind.verb()].map(ToOwned::to_owned);
+        let timer = match self.when {
+            When::Now => return reboot.into(),
+            When::In(d) => format!("--on-active={}s", d.as_secs()),
+            When::At(t) => format!("--on-calendar={}", t.format("%H:%M")),
+        };
+        ["systemd-run", &format!("--unit={SCHEDULED_UNIT}"), &timer]
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .chain(reboot)
+            .collect()
+    }
+}
+
+/// The deployment `--apply` reboots into.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ApplyTarget<'a> {
+    /// The staged deployment, along with whether it can be soft rebooted into
+    Staged(Option<&'a SoftRebootReadiness>),
+    /// The rollback deployment, which has been made the default
+    Rollback,
+}
+
+/// Decide how to apply the target deployment.  This fails if nothing is staged,
+/// e.g. because staging failed.
+pub(crate) fn plan(target: ApplyTarget, mode: SoftRebootMode, when: When) -> Result<RebootPlan> {
+    let readiness = match target {
+        ApplyTarget::Staged(r) => r.filter(|r| r.staged),
+        ApplyTarget::Rollback => {
+            match mode {
+                SoftRebootMode::Auto => println!("Soft reboot not possible: rolling back"),
+                SoftRebootMode::Required => {
+                    anyhow::bail!("Soft reboot not possible: rolling back")
+                }
+                SoftRebootMode::Never => {}
+            }
+            let kind = RebootKind::Reboot;
+            return Ok(RebootPlan { kind, when });
+        }
+    };
+    let Some(readiness) = readiness else {
+        anyhow::bail!("No deployment is staged; refusing to reboot");
+    };
+    let kind = match mode {
+        SoftRebootMode::Auto | SoftRebootMode::Required if readiness.compatible => {
+            RebootKind::SoftReboot
+        }
+        SoftRebootMode::Required => {
+            let reasons = readiness
+                .blocking_reasons
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            anyhow::bail!("Soft reboot not possible: {}", reasons.join(", "));
+        }
+        SoftRebootMode::Auto => {
+            for reason in readiness.blocking_reasons.iter() {
+                println!("Soft reboot not possible: {reason}");
+            }
+            RebootKind::Reboot
+        }
+        SoftRebootMode::Never => RebootKind::Reboot,
+    };
+    Ok(RebootPlan { kind, when })
+}
+
+/// Apply the target deployment, using `exec` to initiate the reboot.
+/// For an immediate reboot, `exec` doesn't return.
+pub(crate) fn apply(
+    target: ApplyTarget,
+    mode: SoftRebootMode,
+    when: When,
+    exec: impl FnOnce(&RebootPlan) -> Result<()>,
+) -> Result<()> {
+    let plan = plan(target, mode, when)?;
+    exec(&plan)?;
+    match plan.when {
+        When::Now => {}
+        When::In(d) => println!(
+            "Scheduled {} in {} minutes; cancel with: systemctl stop {SCHEDULED_UNIT}.timer",
+            plan.kind.verb(),
+            d.as_secs() / 60
+        ),
+        When::At(t) => println!(
+            "Scheduled {} at {}; cancel with: systemctl stop {SCHEDULED_UNIT}.timer",
+            plan.kind.verb(),
+            t.format("%H:%M")
+        ),
+    }
+    Ok(())
+}
+
+/// A bind mount setting up [`NEXTROOT`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NextrootMount {
+    pub(crate) source: Utf8PathBuf,
+    pub(crate) target: Utf8PathBuf,
+    /// Whether to include the submounts of the source
+    pub(crate) recursive: bool,
+}
+
+/// Compute the mounts which make the deployment at `deployment` (relative to
+/// the sysroot at `sysroot_path`) the root of the next soft reboot.  As done by
+/// `ostree-prepare-root` on boot, the sysroot and the `/var` of the stateroot
+/// are mounted into the deployment.
+#[context("Preparing /{NEXTROOT}")]
+pub(crate) fn nextroot_mounts(
+    sysroot: &Dir,
+    sysroot_path: &Utf8Path,
+    deployment: &Utf8Path,
+) -> Result<Vec<NextrootMount>> {
+    let root = sysroot
+        .open_dir(deployment)
+        .with_context(|| format!("Opening deployment {deployment}"))?;
+    for d in ["usr", "etc", "sysroot", "var"] {
+        if !root
+            .symlink_metadata_optional(d)?
+            .is_some_and(|m| m.is_dir())
+        {
+            anyhow::bail!("Deployment {deployment} is missing /{d}");
+        }
+    }
+    // The deployment is in ostree/deploy/<stateroot>/deploy
+    let var = deployment
+        .parent()
+        .and_then(|p| p.parent())
+        .map(|p| p.join("var"))
+        .ok_or_else(|| anyhow::anyhow!("Invalid deployment path {deployment}"))?;
+    if !sysroot.try_exists(&var)? {
+        anyhow::bail!("Missing stateroot /var {var}");
+    }
+    let nextroot = Utf8Path::new("/").join(NEXTROOT);
+    let mount = |source: Utf8PathBuf, target: Utf8PathBuf, recursive| NextrootMount {
+        source,
+        target,
+        recursive,
+    };
+    Ok(vec![
+        mount(sysroot_path.join(deployment), nextroot.clone(), false),
+        mount(sysroot_path.to_owned(), nextroot.join("sysroot"), true),
+        mount(sysroot_path.join(var), nextroot.join("var"), false),
+    ])
+}
+
+/// The root directory as seen from the mount namespace of the process `pid`.
+pub(crate) fn root_of(pid: u32) -> Result<Dir> {
+    let path = format!("/proc/{pid}/root");
+    Dir::open_ambient_dir(&path, cap_std_ext::cap_std::ambient_authority())
+        .with_context(|| format!("Opening {path}"))
+}
+
+/// Whether `path` is a mount point in the mount namespace of the process `pid`.
+fn is_mountpoint_of(pid: u32, path: &Utf8Path) -> Result<bool> {
+    let mountinfo = format!("/proc/{pid}/mountinfo");
+    let mountinfo =
+        std::fs::read_to_string(&mountinfo).with_context(|| format!("Reading {mountinfo}"))?;
+    // The fifth field is the mount point
+    Ok(mountinfo
+        .lines()
+        .any(|l| l.split(' ').nth(4) == Some(path.as_str())))
+}
+
+/// A task running `exe` in the mount namespace of the process `pid`.
+fn task_in_mountns_of(pid: u32, description: impl AsRef<str>, exe: &str) -> Task {
+    Task::new(description, "nsenter").args(["-t", &pid.to_string(), "-m", "--", exe])
+}
+
+/// Set up [`NEXTROOT`] via `mounts` in the mount namespace of the process `pid`
+/// (normally [`HOST_PID`]), replacing any previous setup.
+#[context("Setting up /{NEXTROOT}")]
+pub(crate) fn prepare_nextroot(pid: u32, mounts: &[NextrootMount]) -> Result<()> {
+    let root = &root_of(pid)?;
+    let nextroot = Utf8Path::new("/").join(NEXTROOT);
+    if is_mountpoint_of(pid, &nextroot)? {
+        task_in_mountns_of(pid, "Unmounting previous soft reboot target", "umount")
+            .args(["-R", nextroot.as_str()])
+            .run()?;
+    }
+    root.create_dir_all(NEXTROOT)?;
+    for m in mounts {
+        let flag = if m.recursive { "--rbind" } else { "--bind" };
+        task_in_mountns_of(pid, format!("Mounting {}", m.target), "mount")
+            .args([flag, m.source.as_str(), m.target.as_str()])
+            .run()?;
+    }
+    Ok(())
+}
+
+/// Whether [`NEXTROOT`] in `root` (see [`root_of()`]) is the deployment
+/// `deployment`, i.e. a soft reboot into it has been prepared.
+pub(crate) fn nextroot_is(root: &Dir, deployment: &Dir) -> Result<bool> {
+    let Some(nextroot) = root.open_dir_optional(NEXTROOT)? else {
+        return Ok(false);
+    };
+    let (a, b) = (nextroot.dir_metadata()?, deployment.dir_metadata()?);
+    Ok((a.dev(), a.ino()) == (b.dev(), b.ino()))
+}
+
+/// Initiate or schedule the reboot described by `plan`.
+/// For an immediate reboot, this function will only return in case of error.
+#[context("Initiating {}", plan.kind.verb())]
+pub(crate) fn execute(plan: &RebootPlan) -> Result<()> {
+    let command = plan.command();
+    let (exe, args) = command.split_first().expect("command");
+    if plan.when != When::Now {
+        return Task::new(format!("Scheduling {}", plan.kind.verb()), exe)
+            .args(args)
+            .run();
+    }
+    if plan.kind == RebootKind::Reboot {
+        return reboot();
+    }
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+    Task::new("Soft rebooting system", exe).args(args).run()?;
+    tracing::debug!("Initiated soft reboot, sleeping forever...");
+    loop {
+        std::thread::park();
+    }
+}
+
 /// Initiate a system reboot.
 /// This function will only return in case of error.
 #[context("Initiating reboot")]
@@ -19,3 +333,207 @@ pub(crate) fn reboot() -> anyhow::Result<()> {
         std::thread::park();
     }
 }
+
+#[test]
+fn test_parse_when() {
+    let hm = |h, m| When::At(NaiveTime::from_hms_opt(h, m, 0).unwrap());
+    for (s, expected) in [
+        ("now", When::Now),
+        ("+0", When::Now),
+        ("+10", When::In(Duration::from_secs(600))),
+        ("03:30", hm(3, 30)),
+        ("23:05", hm(23, 5)),
+    ] {
+        assert_eq!(s.parse::<When>().unwrap(), expected, "{s}");
+    }
+    for s in ["", "soon", "+", "+-1", "25:00", "10m"] {
+        assert!(s.parse::<When>().is_err(), "{s}");
+    }
+}
+
+#[test]
+fn test_apply() {
+    use crate::spec::SoftRebootBlocker;
+
+    let compatible = SoftRebootReadiness {
+        staged: true,
+        compatible: true,
+        command: Some("systemctl soft-reboot".into()),
+        blocking_reasons: Vec::new(),
+        nextroot_prepared: false,
+    };
+    let incompatible = SoftRebootReadiness {
+        compatible: false,
+        command: Some("systemctl reboot".into()),
+        blocking_reasons: vec![SoftRebootBlocker::KernelChanged {
+            booted: Some("6.8.1".into()),
+            staged: Some("6.9.0".into()),
+        }],
+        ..compatible.clone()
+    };
+    let run = |readiness: Option<&SoftRebootReadiness>, mode, when| {
+        let mut executed = None;
+        apply(ApplyTarget::Staged(readiness), mode, when, |plan| {
+            executed = Some(plan.command().join(" "));
+            Ok(())
+        })
+        .map(|()| executed.unwrap())
+    };
+    use SoftRebootMode::*;
+
+    // Staging failed, or nothing is staged
+    assert!(run(None, Auto, When::Now).is_err());
+    let unstaged = SoftRebootReadiness::default();
+    assert!(run(Some(&unstaged), Never, When::Now).is_err());
+
+    // Soft reboot eligibility
+    assert_eq!(
+        run(Some(&compatible), Auto, When::Now).unwrap(),
+        "systemctl soft-reboot"
+    );
+    assert_eq!(
+        run(Some(&compatible), Never, When::Now).unwrap(),
+        "systemctl reboot"
+    );
+    assert_eq!(
+        run(Some(&incompatible), Auto, When::Now).unwrap(),
+        "systemctl reboot"
+    );
+    assert_eq!(
+        run(Some(&compatible), Required, When::Now).unwrap(),
+        "systemctl soft-reboot"
+    );
+    let e = run(Some(&incompatible), Required, When::Now).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Soft reboot not possible: kernel changed (6.8.1 -> 6.9.0)"
+    );
+
+    // Scheduling
+    assert_eq!(
+        run(Some(&compatible), Auto, "+5".parse().unwrap()).unwrap(),
+        "systemd-run --unit=bootc-apply --on-active=300s systemctl soft-reboot"
+    );
+    assert_eq!(
+        run(Some(&incompatible), Auto, "04:00".parse().unwrap()).unwrap(),
+        "systemd-run --unit=bootc-apply --on-calendar=04:00 systemctl reboot"
+    );
+
+    // Rolling back always requires a full reboot, and needs nothing staged
+    let plan = plan(ApplyTarget::Rollback, Auto, When::Now).unwrap();
+    assert_eq!(plan.command().join(" "), "systemctl reboot");
+    assert!(self::plan(ApplyTarget::Rollback, Required, When::Now).is_err());
+
+    // A failure to initiate the reboot is propagated
+    let r = apply(
+        ApplyTarget::Staged(Some(&compatible)),
+        Auto,
+        When::Now,
+        |_| anyhow::bail!("failed"),
+    );
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_nextroot() -> Result<()> {
+    use cap_std_ext::cap_std;
+
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let deployment = Utf8Path::new("ostree/deploy/default/deploy/abc.0");
+    let sysroot_path = Utf8Path::new("/sysroot");
+    td.create_dir_all(deployment)?;
+    for d in ["usr", "etc", "sysroot"] {
+        td.create_dir(deployment.join(d))?;
+    }
+    // Missing /var in the deployment, then in the stateroot
+    assert!(nextroot_mounts(&td, sysroot_path, deployment).is_err());
+    td.create_dir(deployment.join("var"))?;
+    let e = nextroot_mounts(&td, sysroot_path, deployment).unwrap_err();
+    assert!(format!("{e:#}").contains("Missing stateroot /var"));
+    td.create_dir("ostree/deploy/default/var")?;
+
+    let mounts = nextroot_mounts(&td, sysroot_path, deployment)?;
+    let mounts = mounts
+        .iter()
+        .map(|m| (m.source.as_str(), m.target.as_str(), m.recursive))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        mounts,
+        [
+            (
+                "/sysroot/ostree/deploy/default/deploy/abc.0",
+                "/run/nextroot",
+                false
+            ),
+            ("/sysroot", "/run/nextroot/sysroot", true),
+            (
+                "/sysroot/ostree/deploy/default/var",
+                "/run/nextroot/var",
+                false
+            ),
+        ]
+    );
+
+    // Detecting whether a soft reboot into the deployment is prepared
+    let staged = td.open_dir(deployment)?;
+    assert!(!nextroot_is(&td, &staged)?);
+    td.create_dir("run")?;
+    td.symlink("../ostree/deploy/default/deploy/abc.0", NEXTROOT)?;
+    assert!(nextroot_is(&td, &staged)?);
+    assert!(!nextroot_is(&td, &td.open_dir("ostree")?)?);
+    Ok(())
+}
+
+#[test]
+fn test_prepare_nextroot_mountns() -> Result<()> {
+    crate::testutils::require_capability!(rustix::thread::CapabilityFlags::SYS_ADMIN);
+    let nextroot = Utf8Path::new("/").join(NEXTROOT);
+    if nextroot.try_exists()? {
+        crate::testutils::skip_test!("{nextroot} exists");
+    }
+    // A process in a private mount namespace stands in for systemd
+    let mut child = std::process::Command::new("unshare")
+        .args(["-m", "--propagation", "private", "sleep", "infinity"])
+        .spawn()?;
+    let pid = child.id();
+    let r = (|| -> Result<()> {
+        let ours = std::fs::read_link("/proc/self/ns/mnt")?;
+        let mut tries = 0;
+        while std::fs::read_link(format!("/proc/{pid}/ns/mnt"))? == ours {
+            tries += 1;
+            assert!(tries < 500, "unshare didn't create a mount namespace");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let td = tempfile::tempdir()?;
+        std::fs::write(td.path().join("marker"), "")?;
+        let mounts = [NextrootMount {
+            source: Utf8PathBuf::try_from(td.path().to_owned())?,
+            target: nextroot.clone(),
+            recursive: false,
+        }];
+        let count = |pid: u32| -> Result<usize> {
+            let mountinfo = std::fs::read_to_string(format!("/proc/{pid}/mountinfo"))?;
+            Ok(mountinfo
+                .lines()
+                .filter(|l| l.split(' ').nth(4) == Some(nextroot.as_str()))
+                .count())
+        };
+        prepare_nextroot(pid, &mounts)?;
+        // The mount is only in the namespace of the target process
+        assert_eq!(count(pid)?, 1);
+        assert_eq!(count(std::process::id())?, 0);
+        let root = root_of(pid)?;
+        assert!(root.try_exists("run/nextroot/marker")?);
+        let source = Dir::open_ambient_dir(td.path(), cap_std_ext::cap_std::ambient_authority())?;
+        assert!(nextroot_is(&root, &source)?);
+        assert!(!nextroot_is(&root_of(std::process::id())?, &source)?);
+        // Preparing again replaces the previous setup
+        prepare_nextroot(pid, &mounts)?;
+        assert_eq!(count(pid)?, 1);
+        Ok(())
+    })();
+    child.kill()?;
+    child.wait()?;
+    std::fs::remove_dir(&nextroot)?;
+    r
+}
@@ -0,0 +1,518 @@
+//! # Registry mirrors
+//!
+//! Images are fetched via the container stack, which honors the mirrors
+//! configured in `/etc/containers/registries.conf` and its drop-ins; see
+//! `containers-registries.conf(5)`.  This module parses the same configuration
+//! so that we can determine (and report) which sources an image is fetched
+//! from, and in which order.
+//!
+//! For images with mirrors, bootc tries each source itself, so that it can
+//! log each attempt and fall back to the next source for layers which a
+//! (e.g. stale) mirror lacks; see [`crate::deploy::pull`].
+
+use std::fmt::Display;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::container::{ImageReference, Transport};
+use serde::Deserialize;
+
+use crate::sigpolicy::scope_matches;
+
+/// The path to the configuration, relative to the root.
+const REGISTRIES_PATH: &str = "etc/containers/registries.conf";
+/// The drop-in directory, relative to the root.
+const REGISTRIES_DROPIN_PATH: &str = "etc/containers/registries.conf.d";
+
+/// When a mirror may be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum PullFromMirror {
+    All,
+    DigestOnly,
+    TagOnly,
+}
+
+/// A `[[registry.mirror]]` entry.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Mirror {
+    location: String,
+    #[serde(default)]
+    insecure: bool,
+    pull_from_mirror: Option<PullFromMirror>,
+}
+
+/// A `[[registry]]` entry.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Registry {
+    prefix: Option<String>,
+    #[serde(default)]
+    location: String,
+    #[serde(default)]
+    insecure: bool,
+    #[serde(default)]
+    blocked: bool,
+    #[serde(default)]
+    mirror_by_digest_only: bool,
+    #[serde(default)]
+    mirror: Vec<Mirror>,
+}
+
+/// The toplevel of a configuration file; everything but the registries is
+/// irrelevant to us.
+#[derive(Debug, Deserialize)]
+struct RegistriesFile {
+    #[serde(default)]
+    registry: Vec<Registry>,
+}
+
+/// Which source of an image with mirrors is tried first.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum PreferSource {
+    /// The mirrors in their configured order, and then the primary location
+    #[default]
+    Mirror,
+    /// The primary location, and then the mirrors
+    Upstream,
+}
+
+/// A location an image is fetched from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Source {
+    /// The image at this location
+    pub(crate) name: String,
+    /// Whether the location is accessed without TLS verification
+    insecure: bool,
+    /// Whether this is a mirror, rather than the primary location
+    pub(crate) mirror: bool,
+}
+
+/// The merged registry configuration.
+#[derive(Debug, Default)]
+pub(crate) struct Registries {
+    registries: Vec<Registry>,
+}
+
+impl Registry {
+    fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(&self.location)
+    }
+
+    /// The length of the part of `name` matched by the prefix, if it matches.
+    fn matched_len(&self, name: &str) -> Option<usize> {
+        let prefix = self.prefix();
+        if prefix.is_empty() || !scope_matches(prefix, name) {
+            return None;
+        }
+        if prefix.starts_with("*.") {
+            // Wildcards match the whole host
+            Some(name.split('/').next().unwrap_or_default().len())
+        } else {
+            Some(prefix.len())
+        }
+    }
+}
+
+impl Mirror {
+    fn applies(&self, registry: &Registry, by_digest: bool) -> bool {
+        let default = if registry.mirror_by_digest_only {
+            PullFromMirror::DigestOnly
+        } else {
+            PullFromMirror::All
+        };
+        match self.pull_from_mirror.unwrap_or(default) {
+            PullFromMirror::All => true,
+            PullFromMirror::DigestOnly => by_digest,
+            PullFromMirror::TagOnly => !by_digest,
+        }
+    }
+}
+
+/// Fully qualify an image name as the container stack does, e.g. `fedora`
+/// is `docker.io/library/fedora`.
+fn qualify(name: &str) -> String {
+    match name.split_once('/') {
+        Some((domain, _)) if domain.contains(['.', ':']) || domain == "localhost" => {
+            name.to_string()
+        }
+        Some(_) => format!("docker.io/{name}"),
+        None => format!("docker.io/library/{name}"),
+    }
+}
+
+impl Registries {
+    fn parse_fragments<'a>(fragments: impl Iterator<Item = (&'a str, &'a str)>) -> Result<Self> {
+        let mut r = Self::default();
+        for (name, buf) in fragments {
+            let f: RegistriesFile =
+                toml::from_str(buf).with_context(|| format!("Parsing {name}"))?;
+            // Entries in later files replace those with the same prefix
+            for registry in f.registry {
+                r.registries.retain(|e| e.prefix() != registry.prefix());
+                r.registries.push(registry);
+            }
+        }
+        Ok(r)
+    }
+
+    /// Load the configuration from the given root.
+    #[context("Loading registries configuration")]
+    pub(crate) fn load(root: &Dir) -> Result<Self> {
+        let mut fragments = Vec::new();
+        if root.try_exists(REGISTRIES_PATH)? {
+            let buf = root.read_to_string(REGISTRIES_PATH)?;
+            fragments.push((REGISTRIES_PATH.to_string(), buf));
+        }
+        if let Some(d) = root.open_dir_optional(REGISTRIES_DROPIN_PATH)? {
+            let mut names = Vec::new();
+            for ent in d.entries()? {
+                let name = ent?.file_name();
+                if let Some(name) = name.to_str().filter(|n| n.ends_with(".conf")) {
+                    names.push(name.to_string());
+                }
+            }
+            names.sort();
+            for name in names {
+                let buf = d.read_to_string(&name)?;
+                fragments.push((format!("{REGISTRIES_DROPIN_PATH}/{name}"), buf));
+            }
+        }
+        Self::parse_fragments(fragments.iter().map(|(n, b)| (n.as_str(), b.as_str())))
+    }
+
+    /// The sources `name` is fetched from, in the order they are tried: the
+    /// applicable mirrors, and then the primary location, which is last.
+    pub(crate) fn sources(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self.endpoints(name)?.into_iter().map(|s| s.name).collect())
+    }
+
+    /// Like [`Self::sources`], but with the configuration of each source.
+    pub(crate) fn endpoints(&self, name: &str) -> Result<Vec<Source>> {
+        let name = qualify(name);
+        let Some((registry, len)) = self
+            .registries
+            .iter()
+            .filter_map(|r| r.matched_len(&name).map(|len| (r, len)))
+            .max_by_key(|(_, len)| *len)
+        else {
+            return Ok(vec![Source {
+                name,
+                insecure: false,
+                mirror: false,
+            }]);
+        };
+        if registry.blocked {
+            anyhow::bail!(
+                "Registry {} is blocked in registries.conf",
+                registry.prefix()
+            );
+        }
+        let rest = &name[len..];
+        let by_digest = rest.contains('@');
+        let mut sources = registry
+            .mirror
+            .iter()
+            .filter(|m| m.applies(registry, by_digest))
+            .map(|m| Source {
+                name: format!("{}{rest}", m.location),
+                insecure: m.insecure,
+                mirror: true,
+            })
+            .collect::<Vec<_>>();
+        let primary = if registry.location.is_empty() {
+            name
+        } else {
+            format!("{}{rest}", registry.location)
+        };
+        sources.push(Source {
+            name: primary,
+            insecure: registry.insecure,
+            mirror: false,
+        });
+        Ok(sources)
+    }
+}
+
+/// Split an image name into the repository and the tag (`:tag`) or
+/// digest (`@sha256:...`), if any.
+fn split_reference(name: &str) -> (&str, &str) {
+    let end = name.find('@').unwrap_or(name.len());
+    let last = name[..end].rfind('/').map_or(0, |i| i + 1);
+    let end = name[last..end].find(':').map_or(end, |i| last + i);
+    name.split_at(end)
+}
+
+/// The name of the image `name` with the given manifest digest; any tag is
+/// dropped, as the container stack doesn't support both.
+pub(crate) fn pin_digest(name: &str, digest: &str) -> String {
+    format!("{}@{digest}", split_reference(name).0)
+}
+
+/// Order the sources (see [`Registries::endpoints`]) as preferred.
+pub(crate) fn order_sources(mut sources: Vec<Source>, prefer: PreferSource) -> Vec<Source> {
+    if prefer == PreferSource::Upstream {
+        // The primary location is last
+        sources.rotate_right(1);
+    }
+    sources
+}
+
+/// A `registries.conf` which makes the container stack fetch the image
+/// `name` only from `source`, without any mirrors.
+pub(crate) fn config_for_source(name: &str, source: &Source) -> String {
+    let prefix = split_reference(&qualify(name)).0.to_owned();
+    let location = split_reference(&source.name).0.to_owned();
+    format!(
+        "[[registry]]\nprefix = {}\nlocation = {}\ninsecure = {}\n",
+        toml::Value::String(prefix),
+        toml::Value::String(location),
+        source.insecure
+    )
+}
+
+/// The sources an image is fetched from; for transports other than
+/// `registry`, this is just the image itself.
+pub(crate) fn pull_sources(imgref: &ImageReference) -> Result<Vec<String>> {
+    if imgref.transport != Transport::Registry {
+        return Ok(vec![imgref.name.clone()]);
+    }
+    let root = Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    Registries::load(&root)?.sources(&imgref.name)
+}
+
+/// Like [`pull_sources`], but with the configuration of each source.
+pub(crate) fn pull_endpoints(imgref: &ImageReference) -> Result<Vec<Source>> {
+    if imgref.transport != Transport::Registry {
+        return Ok(vec![Source {
+            name: imgref.name.clone(),
+            insecure: false,
+            mirror: false,
+        }]);
+    }
+    let root = Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    Registries::load(&root)?.endpoints(&imgref.name)
+}
+
+/// Split an error from the container stack after trying mirrors into the
+/// error for each source.  This looks like
+/// `(Mirrors also failed: [a: error]\n[b: error]): primary: error`.
+fn split_mirror_errors(msg: &str) -> Option<Vec<(&str, &str)>> {
+    const MARKER: &str = "(Mirrors also failed: [";
+    let rest = &msg[msg.find(MARKER)? + MARKER.len()..];
+    let (mirrors, primary) = rest.split_once("]): ")?;
+    let mut r = mirrors
+        .split("]\n[")
+        .map(|m| m.split_once(": "))
+        .collect::<Option<Vec<_>>>()?;
+    r.push(primary.split_once(": ")?);
+    Some(r)
+}
+
+/// Annotate an error fetching an image from `sources` (see [`pull_sources`])
+/// with each source that was tried, along with its error if known.
+pub(crate) fn annotate_error(err: anyhow::Error, sources: &[String]) -> anyhow::Error {
+    let Some(primary) = sources.last().filter(|_| sources.len() > 1) else {
+        return err;
+    };
+    let msg = format!("{err:#}");
+    let Some(errors) = split_mirror_errors(&msg) else {
+        return err.context(format!("Fetching {primary} (tried {})", sources.join(", ")));
+    };
+    aggregate_errors(primary, errors)
+}
+
+/// The error after fetching `primary` failed from each of the sources.
+pub(crate) fn aggregate_errors(
+    primary: &str,
+    errors: impl IntoIterator<Item = (impl Display, impl Display)>,
+) -> anyhow::Error {
+    let mut r = format!("Fetching {primary} failed from all sources:");
+    for (source, e) in errors {
+        r.push_str(&format!("\n  {source}: {e}"));
+    }
+    anyhow::anyhow!(r)
+}
+
+#[test]
+fn test_sources() -> Result<()> {
+    let fixture = include_str!("fixtures/registries.conf");
+    let r = Registries::parse_fragments([("registries.conf", fixture)].into_iter())?;
+    let digest = "sha256:0c8a0a8f1ad7a1d1cb3ab7d3f1c3a9d2c3ed4d0e0a5c6c9f5d6a0f3f7a4e2b1c";
+
+    // Mirrors are tried in order, filtered by whether the image is pinned
+    assert_eq!(
+        r.sources("quay.io/example/os:latest")?,
+        [
+            "mirror-a.internal:5000/example/os:latest",
+            "mirror-c.internal/example/os:latest",
+            "quay.io/example/os:latest"
+        ]
+    );
+    assert_eq!(
+        r.sources(&format!("quay.io/example/os@{digest}"))?,
+        [
+            format!("mirror-a.internal:5000/example/os@{digest}"),
+            format!("mirror-b.internal/example/os@{digest}"),
+            format!("quay.io/example/os@{digest}")
+        ]
+    );
+    // Prefixes only match whole path components
+    assert_eq!(
+        r.sources("quay.io/example-other/os")?,
+        ["quay.io/example-other/os"]
+    );
+    assert_eq!(
+        r.sources("registry.example.com/os:latest")?,
+        ["registry.example.com/os:latest"]
+    );
+    assert_eq!(
+        r.sources(&format!("registry.example.com/os@{digest}"))?,
+        [
+            format!("mirror-a.internal:5000/example-com/os@{digest}"),
+            format!("registry.example.com/os@{digest}")
+        ]
+    );
+    assert_eq!(
+        r.sources("build.corp.example/os:42")?,
+        [
+            "mirror-a.internal:5000/corp/os:42",
+            "build.corp.example/os:42"
+        ]
+    );
+    assert_eq!(
+        r.sources("fedora").unwrap_err().to_string(),
+        "Registry docker.io is blocked in registries.conf"
+    );
+
+    // Drop-ins replace entries with the same prefix
+    let dropin =
+        "[[registry]]\nprefix = \"quay.io/example\"\nlocation = \"registry.internal/example\"\n";
+    let r = Registries::parse_fragments(
+        [("registries.conf", fixture), ("50-internal.conf", dropin)].into_iter(),
+    )?;
+    assert_eq!(
+        r.sources("quay.io/example/os:latest")?,
+        ["registry.internal/example/os:latest"]
+    );
+    assert!(Registries::parse_fragments(
+        [("invalid.conf", "[[registry]]\nblocked = 1\n")].into_iter()
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_annotate_error() {
+    let sources = [
+        "mirror-a.internal:5000/example/os:latest",
+        "mirror-c.internal/example/os:latest",
+        "quay.io/example/os:latest",
+    ]
+    .map(String::from);
+    let err = anyhow::anyhow!(
+        "(Mirrors also failed: [mirror-a.internal:5000/example/os:latest: pinging container registry mirror-a.internal:5000: connection refused]\n\
+[mirror-c.internal/example/os:latest: reading manifest latest in mirror-c.internal/example/os: manifest unknown]): \
+quay.io/example/os:latest: pinging container registry quay.io: i/o timeout"
+    )
+    .context("Failed to invoke method OpenImage");
+    assert_eq!(
+        annotate_error(err, &sources).to_string(),
+        "Fetching quay.io/example/os:latest failed from all sources:
+  mirror-a.internal:5000/example/os:latest: pinging container registry mirror-a.internal:5000: connection refused
+  mirror-c.internal/example/os:latest: reading manifest latest in mirror-c.internal/example/os: manifest unknown
+  quay.io/example/os:latest: pinging container registry quay.io: i/o timeout"
+    );
+
+    // Errors we can't split still list the sources
+    let err = annotate_error(anyhow::anyhow!("unauthorized"), &sources);
+    assert_eq!(
+        format!("{err:#}"),
+        "Fetching quay.io/example/os:latest (tried mirror-a.internal:5000/example/os:latest, \
+mirror-c.internal/example/os:latest, quay.io/example/os:latest): unauthorized"
+    );
+    // With a single source, there's nothing to add
+    let err = annotate_error(anyhow::anyhow!("unauthorized"), &sources[2..]);
+    assert_eq!(format!("{err:#}"), "unauthorized");
+}
+
+#[test]
+fn test_failover() -> Result<()> {
+    let fixture = include_str!("fixtures/registries.conf");
+    let r = Registries::parse_fragments([("registries.conf", fixture)].into_iter())?;
+    let digest = "sha256:0c8a0a8f1ad7a1d1cb3ab7d3f1c3a9d2c3ed4d0e0a5c6c9f5d6a0f3f7a4e2b1c";
+
+    assert_eq!(
+        split_reference("quay.io/example/os:latest"),
+        ("quay.io/example/os", ":latest")
+    );
+    assert_eq!(
+        split_reference("localhost:5000/os"),
+        ("localhost:5000/os", "")
+    );
+    assert_eq!(
+        split_reference(&format!("localhost:5000/os@{digest}")),
+        ("localhost:5000/os", format!("@{digest}").as_str())
+    );
+    assert_eq!(
+        pin_digest("localhost:5000/os:latest", digest),
+        format!("localhost:5000/os@{digest}")
+    );
+    assert_eq!(
+        pin_digest(&format!("quay.io/example/os@{digest}"), digest),
+        format!("quay.io/example/os@{digest}")
+    );
+
+    let endpoints = r.endpoints("quay.io/example/os:latest")?;
+    let names = |sources: &[Source]| sources.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+    assert_eq!(
+        endpoints
+            .iter()
+            .map(|s| (s.insecure, s.mirror))
+            .collect::<Vec<_>>(),
+        [(true, true), (false, true), (false, false)]
+    );
+    assert_eq!(
+        names(&order_sources(endpoints.clone(), PreferSource::Mirror)),
+        r.sources("quay.io/example/os:latest")?
+    );
+    assert_eq!(
+        names(&order_sources(endpoints.clone(), PreferSource::Upstream)),
+        [
+            "quay.io/example/os:latest",
+            "mirror-a.internal:5000/example/os:latest",
+            "mirror-c.internal/example/os:latest",
+        ]
+    );
+
+    // Only the repository is rewritten, so pinned images use the same configuration
+    let config = config_for_source("quay.io/example/os:latest", &endpoints[0]);
+    assert_eq!(
+        config,
+        "[[registry]]\nprefix = \"quay.io/example/os\"\nlocation = \"mirror-a.internal:5000/example/os\"\ninsecure = true\n"
+    );
+    let parsed = Registries::parse_fragments([("registries.conf", config.as_str())].into_iter())?;
+    assert_eq!(
+        parsed.sources(&pin_digest("quay.io/example/os:latest", digest))?,
+        [format!("mirror-a.internal:5000/example/os@{digest}")]
+    );
+    // Unqualified names are qualified the same way as by the container stack
+    let config = config_for_source("os", &endpoints[2]);
+    assert!(
+        config.contains("prefix = \"docker.io/library/os\""),
+        "{config}"
+    );
+
+    let e = aggregate_errors(
+        "quay.io/example/os:latest",
+        [("mirror-a.internal:5000/example/os:latest", "blob unknown")],
+    );
+    assert_eq!(
+        e.to_string(),
+        "Fetching quay.io/example/os:latest failed from all sources:\n  mirror-a.internal:5000/example/os:latest: blob unknown"
+    );
+    Ok(())
+}
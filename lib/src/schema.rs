@@ -0,0 +1,177 @@
+//! # JSON schemas of the formats bootc reads and writes
+//!
+//! `bootc internals print-json-schema` prints a JSON schema generated from the
+//! serde types of e.g. `bootc status --json`, so that external tools can
+//! validate against it rather than maintaining schemas by hand.  Versioned
+//! formats carry their version in the `x-bootc-format-version` key of the schema.
+
+use std::io::Write;
+
+use anyhow::Result;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+
+use crate::fetchconfig::FetchConfigurationToplevel;
+use crate::progress_jsonl;
+use crate::spec::{HostV1, FORMAT_VERSION_LATEST};
+
+/// The key of the schema holding the version of the format.
+const FORMAT_VERSION_KEY: &str = "x-bootc-format-version";
+
+/// The formats which have a schema.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum SchemaType {
+    /// The host as shown by `bootc status --json`, in the latest format version
+    #[default]
+    HostStatus,
+    /// A line written via `--progress-fd`; see `bootc-progress-fd(5)`
+    Progress,
+    /// A configuration file in `bootc/fetch`
+    FetchConfig,
+    /// A configuration file in `bootc/install`
+    #[cfg(feature = "install")]
+    InstallConfig,
+}
+
+/// The schema of a format.
+pub(crate) fn schema_of(of: SchemaType) -> RootSchema {
+    let (mut schema, version) = match of {
+        SchemaType::HostStatus => (schema_for!(HostV1), Some(FORMAT_VERSION_LATEST)),
+        SchemaType::Progress => (
+            schema_for!(progress_jsonl::Record),
+            Some(progress_jsonl::SCHEMA_VERSION),
+        ),
+        SchemaType::FetchConfig => (schema_for!(FetchConfigurationToplevel), None),
+        #[cfg(feature = "install")]
+        SchemaType::InstallConfig => (
+            schema_for!(crate::install::config::InstallConfigurationToplevel),
+            None,
+        ),
+    };
+    if let Some(version) = version {
+        schema
+            .schema
+            .extensions
+            .insert(FORMAT_VERSION_KEY.to_owned(), version.into());
+    }
+    schema
+}
+
+/// Implementation of `bootc internals print-json-schema`.
+pub(crate) fn print(of: SchemaType) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &schema_of(of))?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+/// Validate `value` against the schema of a format.
+#[cfg(test)]
+pub(crate) fn validate(of: SchemaType, value: &serde_json::Value) {
+    let schema = serde_json::to_value(schema_of(of)).unwrap();
+    let validator = jsonschema::validator_for(&schema).unwrap();
+    let errors = validator
+        .iter_errors(value)
+        .map(|e| format!("{}: {e}", e.instance_path))
+        .collect::<Vec<_>>();
+    assert!(errors.is_empty(), "{of:?}: {errors:#?}\n{value:#}");
+}
+
+#[test]
+fn test_schema_stable() {
+    for of in <SchemaType as clap::ValueEnum>::value_variants() {
+        let a = serde_json::to_string_pretty(&schema_of(*of)).unwrap();
+        let b = serde_json::to_string_pretty(&schema_of(*of)).unwrap();
+        assert_eq!(a, b);
+    }
+    let host = serde_json::to_value(schema_of(SchemaType::HostStatus)).unwrap();
+    assert_eq!(host[FORMAT_VERSION_KEY], FORMAT_VERSION_LATEST);
+    assert_eq!(host["title"], "BootcHost");
+    let progress = serde_json::to_value(schema_of(SchemaType::Progress)).unwrap();
+    assert_eq!(progress[FORMAT_VERSION_KEY], progress_jsonl::SCHEMA_VERSION);
+    let fetch = serde_json::to_value(schema_of(SchemaType::FetchConfig)).unwrap();
+    assert!(fetch.get(FORMAT_VERSION_KEY).is_none());
+}
+
+#[test]
+fn test_validate_host() -> Result<()> {
+    use crate::spec::Host;
+
+    for fixture in [
+        include_str!("fixtures/spec.yaml"),
+        include_str!("fixtures/spec-v1.yaml"),
+        include_str!("fixtures/spec-rollback.yaml"),
+        include_str!("fixtures/spec-ostree-remote.yaml"),
+    ] {
+        let host: Host = serde_yaml::from_str(fixture)?;
+        let value = serde_json::to_value(host.versioned(FORMAT_VERSION_LATEST)?)?;
+        validate(SchemaType::HostStatus, &value);
+    }
+    let value = serde_json::to_value(Host::default().versioned(FORMAT_VERSION_LATEST)?)?;
+    validate(SchemaType::HostStatus, &value);
+
+    // The schema rejects what bootc would never write
+    let host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let mut value = serde_json::to_value(host.versioned(FORMAT_VERSION_LATEST)?)?;
+    value["status"]["booted"]["pinned"] = "yes".into();
+    let schema = serde_json::to_value(schema_of(SchemaType::HostStatus))?;
+    assert!(!jsonschema::is_valid(&schema, &value));
+    Ok(())
+}
+
+#[test]
+fn test_validate_configs() -> Result<()> {
+    let toml_value = |s: &str| -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(toml::from_str::<toml::Value>(s)?)?)
+    };
+    let fetch = r#"
+[fetch]
+bandwidth-limit = "10MiB/s"
+[switch]
+retain = true
+[maintenance]
+windows = ["Mon-Fri 22:00-02:00", "Sat 00:00-23:59"]
+timezone = "+02:00"
+[proxy]
+https-proxy = "http://proxy.example.com:3128"
+registries = ["quay.io"]
+[updates]
+mode = "apply"
+reboot = "soft-reboot-if-possible"
+jitter = "30m"
+retries = 3
+backoff = "5m"
+"#;
+    // The example is valid for bootc too
+    let _: FetchConfigurationToplevel = toml::from_str(fetch)?;
+    validate(SchemaType::FetchConfig, &toml_value(fetch)?);
+    let schema = serde_json::to_value(schema_of(SchemaType::FetchConfig))?;
+    assert!(!jsonschema::is_valid(
+        &schema,
+        &toml_value("[fetch]\nbandwidth = \"10MiB/s\"\n")?
+    ));
+    validate(
+        SchemaType::FetchConfig,
+        &toml_value("[updates]\nreboot = \"softRebootIfPossible\"\n")?,
+    );
+    assert!(!jsonschema::is_valid(
+        &schema,
+        &toml_value("[updates]\nreboot = \"later\"\n")?
+    ));
+
+    #[cfg(feature = "install")]
+    {
+        let install = r#"
+[install]
+root-fs-type = "xfs"
+block = ["direct", "tpm2-luks"]
+kargs = ["console=ttyS0"]
+secureboot-check = "enforce"
+[install.filesystem.root]
+type = "ext4"
+"#;
+        let _: crate::install::config::InstallConfigurationToplevel = toml::from_str(install)?;
+        validate(SchemaType::InstallConfig, &toml_value(install)?);
+    }
+    Ok(())
+}
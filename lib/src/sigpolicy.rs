@@ -0,0 +1,432 @@
+//! # Inspecting the container signature policy
+//!
+//! Container images fetched with [`ImageSignature::ContainerPolicy`] are verified
+//! by the container stack according to `/etc/containers/policy.json`; see
+//! `containers-policy.json(5)`.  This module parses that file so we can determine
+//! (and record) how a given image is verified, and to check before fetching an
+//! image that its policy can be satisfied at all.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::Deserialize;
+
+use crate::spec::{ImageReference, ImageSignature, ImageVerification};
+
+/// The path to the policy, relative to the root.
+const POLICY_PATH: &str = "etc/containers/policy.json";
+
+/// A single policy requirement; all requirements for an image must be satisfied.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum Requirement {
+    InsecureAcceptAnything,
+    Reject,
+    #[serde(rename_all = "camelCase")]
+    SignedBy {
+        key_path: Option<String>,
+        key_paths: Option<Vec<String>>,
+        key_data: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    SigstoreSigned {
+        key_path: Option<String>,
+        key_paths: Option<Vec<String>>,
+        key_data: Option<String>,
+        fulcio: Option<Fulcio>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// Fulcio (keyless) configuration of a `sigstoreSigned` requirement.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Fulcio {
+    subject_email: Option<String>,
+}
+
+/// The parsed contents of `policy.json`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Policy {
+    default: Vec<Requirement>,
+    /// Requirements per transport, and then per scope within the transport
+    #[serde(default)]
+    transports: BTreeMap<String, BTreeMap<String, Vec<Requirement>>>,
+}
+
+/// Returns true if the `docker` transport `scope` applies to the image `name`.
+pub(crate) fn scope_matches(scope: &str, name: &str) -> bool {
+    if let Some(domain) = scope.strip_prefix("*.") {
+        let host = name.split('/').next().unwrap_or_default();
+        return host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'));
+    }
+    match name.strip_prefix(scope) {
+        Some(rest) => rest.is_empty() || rest.starts_with(['/', ':', '@']),
+        None => false,
+    }
+}
+
+impl Policy {
+    /// Load the policy from the given root, if it exists.
+    #[context("Loading {POLICY_PATH}")]
+    pub(crate) fn load(root: &Dir) -> Result<Option<Self>> {
+        let Some(f) = root.open_optional(POLICY_PATH)? else {
+            return Ok(None);
+        };
+        let policy = serde_json::from_reader(std::io::BufReader::new(f))?;
+        Ok(Some(policy))
+    }
+
+    /// Find the requirements which apply to an image; the most specific scope wins.
+    pub(crate) fn requirements_for(&self, imgref: &ImageReference) -> &[Requirement] {
+        // We canonicalize the `docker` transport to `registry`
+        let transport = match imgref.transport.as_str() {
+            "registry" => "docker",
+            o => o,
+        };
+        let Some(scopes) = self.transports.get(transport) else {
+            return &self.default;
+        };
+        let best = scopes
+            .iter()
+            .filter(|(scope, _)| !scope.is_empty() && scope_matches(scope, &imgref.image))
+            // Prefer exact scopes over wildcards, and then longer ones
+            .max_by_key(|(scope, _)| (!scope.starts_with("*."), scope.len()));
+        best.or_else(|| scopes.get_key_value(""))
+            .map(|(_, r)| r.as_slice())
+            .unwrap_or(&self.default)
+    }
+}
+
+impl Requirement {
+    /// The key or identity which the requirement verifies against, if any.
+    fn identity(&self) -> Option<String> {
+        let keys = |key_path: &Option<String>, key_paths: &Option<Vec<String>>| {
+            key_path
+                .clone()
+                .or_else(|| key_paths.as_ref().map(|p| p.join(", ")))
+        };
+        match self {
+            Self::SignedBy {
+                key_path,
+                key_paths,
+                ..
+            } => keys(key_path, key_paths),
+            Self::SigstoreSigned {
+                key_path,
+                key_paths,
+                fulcio,
+                ..
+            } => keys(key_path, key_paths)
+                .or_else(|| fulcio.as_ref().and_then(|f| f.subject_email.clone())),
+            _ => None,
+        }
+    }
+
+    /// The `type` of the requirement, as written in the policy.
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::InsecureAcceptAnything => "insecureAcceptAnything",
+            Self::Reject => "reject",
+            Self::SignedBy { .. } => "signedBy",
+            Self::SigstoreSigned { .. } => "sigstoreSigned",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// The public key files the requirement verifies against; `None` if the
+    /// requirement doesn't verify signatures, or has no keys configured at all.
+    fn key_files(&self) -> Option<Vec<&str>> {
+        let (key_path, key_paths, has_other) = match self {
+            Self::SignedBy {
+                key_path,
+                key_paths,
+                key_data,
+            } => (key_path, key_paths, key_data.is_some()),
+            Self::SigstoreSigned {
+                key_path,
+                key_paths,
+                key_data,
+                fulcio,
+            } => (key_path, key_paths, key_data.is_some() || fulcio.is_some()),
+            _ => return None,
+        };
+        let files = key_path
+            .iter()
+            .chain(key_paths.iter().flatten())
+            .map(|p| p.as_str())
+            .collect::<Vec<_>>();
+        (has_other || !files.is_empty()).then_some(files)
+    }
+}
+
+/// Check that the signature policy for fetching `imgref` can be satisfied,
+/// so that obviously broken policies fail before anything is downloaded.
+/// With `enforce`, the policy must also actually verify signatures.
+/// `key_exists` checks whether a public key file exists.
+pub(crate) fn check_policy(
+    imgref: &ImageReference,
+    policy: Option<&Policy>,
+    enforce: bool,
+    key_exists: impl Fn(&str) -> Result<bool>,
+) -> Result<()> {
+    let path = format!("/{POLICY_PATH}");
+    let image = &imgref.image;
+    if imgref.signature != Some(ImageSignature::ContainerPolicy) {
+        if enforce {
+            anyhow::bail!(
+                "{image} is not verified via {path}; use `bootc switch --enforce-container-sigpolicy` to verify it"
+            );
+        }
+        return Ok(());
+    }
+    let policy =
+        policy.ok_or_else(|| anyhow!("{path} is missing; it is required to verify {image}"))?;
+    let requirements = policy.requirements_for(imgref);
+    if requirements.is_empty() {
+        anyhow::bail!("{path}: No requirements apply to {image}");
+    }
+    for r in requirements {
+        let kind = r.type_name();
+        match r {
+            Requirement::Reject => anyhow::bail!("{path}: Requirement {kind} rejects {image}"),
+            Requirement::SignedBy { .. } | Requirement::SigstoreSigned { .. } => {
+                let Some(files) = r.key_files() else {
+                    anyhow::bail!("{path}: Requirement {kind} for {image} has no public key");
+                };
+                for f in files {
+                    if !key_exists(f)? {
+                        anyhow::bail!(
+                            "{path}: Requirement {kind} for {image} uses missing public key {f}"
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if enforce && verification_for(imgref, Some(policy)) == ImageVerification::Unverified {
+        let kinds = requirements
+            .iter()
+            .map(|r| r.type_name())
+            .collect::<Vec<_>>()
+            .join(", ");
+        anyhow::bail!(
+            "{path}: Requirement {kinds} for {image} does not verify signatures, but --enforce-container-sigpolicy was given"
+        );
+    }
+    Ok(())
+}
+
+/// Check the signature policy of the system at `root` for `imgref`; see [`check_policy`].
+#[context("Checking signature policy")]
+pub(crate) fn check_policy_in_root(
+    root: &Dir,
+    imgref: &ImageReference,
+    enforce: bool,
+) -> Result<()> {
+    let policy = Policy::load(root)?;
+    check_policy(imgref, policy.as_ref(), enforce, |f| {
+        Ok(root.try_exists(f.trim_start_matches('/'))?)
+    })
+}
+
+/// Determine how an image fetched via `imgref` is verified, given the
+/// signature policy (if any).
+pub(crate) fn verification_for(
+    imgref: &ImageReference,
+    policy: Option<&Policy>,
+) -> ImageVerification {
+    match imgref.signature.as_ref() {
+        None | Some(ImageSignature::Insecure) => ImageVerification::Unverified,
+        Some(ImageSignature::OstreeRemote(remote)) => ImageVerification::OstreeRemote {
+            remote: remote.clone(),
+        },
+        Some(ImageSignature::ContainerPolicy) => {
+            let requirements = policy.map(|p| p.requirements_for(imgref)).unwrap_or(&[]);
+            // Pick the first requirement which actually verifies a signature
+            requirements
+                .iter()
+                .find_map(|r| match r {
+                    Requirement::SignedBy { .. } => Some(ImageVerification::SignedBy {
+                        identity: r.identity(),
+                    }),
+                    Requirement::SigstoreSigned { .. } => Some(ImageVerification::SigstoreSigned {
+                        identity: r.identity(),
+                    }),
+                    _ => None,
+                })
+                .unwrap_or(ImageVerification::Unverified)
+        }
+    }
+}
+
+/// Determine how an image fetched via `imgref` is verified on the system at `root`.
+pub(crate) fn verification_in_root(
+    root: &Dir,
+    imgref: &ImageReference,
+) -> Result<ImageVerification> {
+    let policy = Policy::load(root)?;
+    Ok(verification_for(imgref, policy.as_ref()))
+}
+
+#[test]
+fn test_scope_matches() {
+    let name = "quay.io/myorg/os:latest";
+    for scope in ["quay.io", "quay.io/myorg", "quay.io/myorg/os", name, "*.io"] {
+        assert!(scope_matches(scope, name), "{scope}");
+    }
+    for scope in ["quay", "quay.io/my", "quay.io/myorg/os:stable", "*.quay.io"] {
+        assert!(!scope_matches(scope, name), "{scope}");
+    }
+}
+
+#[test]
+fn test_verification_for() -> Result<()> {
+    let policy: Policy = serde_json::from_str(
+        r#"{
+    "default": [{"type": "reject"}],
+    "transports": {
+        "docker": {
+            "": [{"type": "insecureAcceptAnything"}],
+            "quay.io": [{"type": "signedBy", "keyType": "GPGKeys", "keyPath": "/etc/pki/quay.gpg"}],
+            "quay.io/myorg": [{"type": "sigstoreSigned", "keyPath": "/etc/pki/myorg.pub", "signedIdentity": {"type": "matchRepository"}}],
+            "*.example.com": [{"type": "sigstoreSigned", "fulcio": {"subjectEmail": "builder@example.com", "oidcIssuer": "https://example.com"}}]
+        }
+    }
+}"#,
+    )?;
+    let imgref = |image: &str, signature| ImageReference {
+        image: image.into(),
+        transport: "registry".into(),
+        signature,
+    };
+    let policy_ref = |image| imgref(image, Some(ImageSignature::ContainerPolicy));
+    let cases = [
+        (
+            policy_ref("quay.io/myorg/os:latest"),
+            ImageVerification::SigstoreSigned {
+                identity: Some("/etc/pki/myorg.pub".into()),
+            },
+        ),
+        (
+            policy_ref("quay.io/other/os:latest"),
+            ImageVerification::SignedBy {
+                identity: Some("/etc/pki/quay.gpg".into()),
+            },
+        ),
+        (
+            policy_ref("registry.example.com/os"),
+            ImageVerification::SigstoreSigned {
+                identity: Some("builder@example.com".into()),
+            },
+        ),
+        (
+            policy_ref("docker.io/library/os"),
+            ImageVerification::Unverified,
+        ),
+        (
+            imgref("quay.io/myorg/os:latest", None),
+            ImageVerification::Unverified,
+        ),
+        (
+            imgref(
+                "quay.io/myorg/os:latest",
+                Some(ImageSignature::OstreeRemote("myremote".into())),
+            ),
+            ImageVerification::OstreeRemote {
+                remote: "myremote".into(),
+            },
+        ),
+    ];
+    for (imgref, expected) in cases {
+        assert_eq!(
+            verification_for(&imgref, Some(&policy)),
+            expected,
+            "{}",
+            imgref.image
+        );
+    }
+    // No policy at all
+    assert_eq!(
+        verification_for(&policy_ref("quay.io/myorg/os"), None),
+        ImageVerification::Unverified
+    );
+    Ok(())
+}
+
+#[test]
+fn test_check_policy() -> Result<()> {
+    let imgref = |image: &str, signature| ImageReference {
+        image: image.into(),
+        transport: "registry".into(),
+        signature,
+    };
+    let policy_ref = |image| imgref(image, Some(ImageSignature::ContainerPolicy));
+    let err = |r: Result<()>| r.unwrap_err().to_string();
+    let present = ["/etc/pki/containers/example.gpg"];
+    let key_exists = |f: &str| Ok(present.contains(&f));
+
+    // A public key which doesn't exist
+    let policy: Policy = serde_json::from_str(include_str!("fixtures/policy-missing-key.json"))?;
+    let check =
+        |imgref: &ImageReference, enforce| check_policy(imgref, Some(&policy), enforce, key_exists);
+    assert_eq!(
+        err(check(&policy_ref("quay.io/myorg/os:latest"), false)),
+        "/etc/containers/policy.json: Requirement sigstoreSigned for quay.io/myorg/os:latest uses missing public key /etc/pki/containers/myorg.pub"
+    );
+    assert_eq!(
+        err(check(&policy_ref("registry.example.com/os"), false)),
+        "/etc/containers/policy.json: Requirement signedBy for registry.example.com/os uses missing public key /etc/pki/containers/example-old.gpg"
+    );
+    assert_eq!(
+        err(check(&policy_ref("quay.io/nokey/os"), false)),
+        "/etc/containers/policy.json: Requirement signedBy for quay.io/nokey/os has no public key"
+    );
+    assert_eq!(
+        err(check(&policy_ref("docker.io/library/os"), false)),
+        "/etc/containers/policy.json: Requirement reject rejects docker.io/library/os"
+    );
+    // Images which aren't verified via the policy aren't affected
+    check(&imgref("quay.io/myorg/os:latest", None), false)?;
+    check(
+        &imgref("quay.io/myorg/os:latest", Some(ImageSignature::Insecure)),
+        false,
+    )?;
+
+    // All keys are present
+    let key_exists = |_: &str| Ok(true);
+    check_policy(
+        &policy_ref("registry.example.com/os"),
+        Some(&policy),
+        true,
+        key_exists,
+    )?;
+
+    // The default policy accepts anything, which is only an error when enforcing
+    let policy: Policy =
+        serde_json::from_str(include_str!("fixtures/policy-insecure-default.json"))?;
+    let oci = ImageReference {
+        transport: "oci".into(),
+        ..policy_ref("/var/lib/images/os")
+    };
+    check_policy(&oci, Some(&policy), false, key_exists)?;
+    assert_eq!(
+        err(check_policy(&oci, Some(&policy), true, key_exists)),
+        "/etc/containers/policy.json: Requirement insecureAcceptAnything for /var/lib/images/os does not verify signatures, but --enforce-container-sigpolicy was given"
+    );
+    assert!(check_policy(&imgref("quay.io/os", None), Some(&policy), true, key_exists).is_err());
+
+    // A missing policy can't be satisfied
+    assert_eq!(
+        err(check_policy(&oci, None, false, key_exists)),
+        "/etc/containers/policy.json is missing; it is required to verify /var/lib/images/os"
+    );
+    Ok(())
+}
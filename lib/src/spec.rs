@@ -13,7 +13,7 @@ const KIND: &str = "BootcHost";
 /// The default object name we use; there's only one.
 pub(crate) const OBJECT_NAME: &str = "host";
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 /// The core host definition
 pub struct Host {
@@ -30,7 +30,7 @@ pub struct Host {
 
 /// Configuration for system boot ordering.
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub enum BootOrder {
     /// The staged or booted deployment will be booted next
@@ -40,7 +40,7 @@ pub enum BootOrder {
     Rollback,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 /// The host specification
 pub struct HostSpec {
@@ -76,6 +76,48 @@ pub struct ImageReference {
     pub signature: Option<ImageSignature>,
 }
 
+/// How an image was verified when it was fetched
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ImageVerification {
+    /// Not recorded; e.g. the deployment was created by an older version
+    #[default]
+    Unknown,
+    /// No signature verification was performed
+    Unverified,
+    /// The ostree commit was verified using the named ostree remote
+    OstreeRemote {
+        /// The ostree remote
+        remote: String,
+    },
+    /// The image was verified via a `signedBy` (GPG) requirement in `containers-policy.json`
+    SignedBy {
+        /// The key which the signature was verified against, if known
+        identity: Option<String>,
+    },
+    /// The image was verified via a `sigstoreSigned` requirement in `containers-policy.json`
+    SigstoreSigned {
+        /// The key or identity which the signature was verified against, if known
+        identity: Option<String>,
+    },
+}
+
+impl Display for ImageVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (kind, identity) = match self {
+            Self::Unknown => return f.write_str("unknown"),
+            Self::Unverified => return f.write_str("no"),
+            Self::OstreeRemote { remote } => ("ostree-remote", Some(remote)),
+            Self::SignedBy { identity } => ("gpg", identity.as_ref()),
+            Self::SigstoreSigned { identity } => ("sigstore", identity.as_ref()),
+        };
+        match identity {
+            Some(identity) => write!(f, "yes ({kind}: {identity})"),
+            None => write!(f, "yes ({kind})"),
+        }
+    }
+}
+
 /// The status of the booted image
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
@@ -88,6 +130,22 @@ pub struct ImageStatus {
     pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
     /// The digest of the fetched image (e.g. sha256:a0...);
     pub image_digest: String,
+    /// How the image was verified when it was fetched
+    #[serde(default)]
+    pub verification: ImageVerification,
+    /// The total size of the compressed image layers in bytes, if known
+    #[serde(default)]
+    pub compressed_size: Option<u64>,
+    /// The size of the image's unpacked filesystem tree in bytes, if known
+    #[serde(default)]
+    pub unpacked_size: Option<u64>,
+    /// When the image was pulled, if known
+    #[serde(default)]
+    pub pull_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    /// The size of the layers downloaded when the image was pulled, if known;
+    /// the remainder of the compressed size was reused from local layers
+    #[serde(default)]
+    pub downloaded_size: Option<u64>,
 }
 
 /// A bootable entry
@@ -110,10 +168,27 @@ pub struct BootEntry {
     pub cached_update: Option<ImageStatus>,
     /// Whether this boot entry is not compatible (has origin changes bootc does not understand)
     pub incompatible: bool,
-    /// Whether this entry will be subject to garbage collection
+    /// Whether this entry is pinned, i.e. will not be subject to garbage collection
     pub pinned: bool,
     /// If this boot entry is ostree based, the corresponding state
     pub ostree: Option<BootEntryOstree>,
+    /// The kernel arguments of this boot entry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kargs: Vec<String>,
+    /// The kernel of this boot entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<BootEntryKernel>,
+}
+
+/// The kernel of a boot entry
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BootEntryKernel {
+    /// The kernel version, i.e. its directory in `/usr/lib/modules`
+    pub version: String,
+    /// The SHA-256 of the initramfs, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initramfs_digest: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -124,6 +199,15 @@ pub struct BootEntry {
 pub enum HostType {
     /// The current system is deployed in a bootc compatible way.
     BootcHost,
+    /// The current system is managed by rpm-ostree, e.g. because it has local
+    /// package changes or wasn't deployed from a container image.
+    RpmOstreeHost,
+    /// The current system is booted via ostree, but not from a container image.
+    OstreeHost,
+    /// The current system is not booted via ostree.
+    NonOstreeHost,
+    /// The current system is a container, e.g. in a container build.
+    Container,
 }
 
 /// The status of the host system
@@ -143,6 +227,332 @@ pub struct HostStatus {
     /// The detected type of system
     #[serde(rename = "type")]
     pub ty: Option<HostType>,
+
+    /// Whether the staged deployment can be applied without a full reboot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft_reboot: Option<SoftRebootReadiness>,
+
+    /// How the root filesystem is provided
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<RootBackend>,
+
+    /// The result of the last `bootc upgrade --check`, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_check: Option<UpdateCheck>,
+
+    /// An update fetched via `bootc upgrade --download-only`, which is not yet staged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloaded: Option<DownloadedUpdate>,
+
+    /// Pinned deployments other than the staged, booted and rollback ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_deployments: Vec<PinnedDeployment>,
+
+    /// Images which are kept even if no deployment uses them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_images: Vec<PinnedImage>,
+
+    /// The current or next maintenance window, if any are configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance_window: Option<MaintenanceWindow>,
+
+    /// The policy for automatic updates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_policy: Option<UpdatePolicy>,
+
+    /// The persistent overlay on `/usr`, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usr_overlay: Option<PersistentUsrOverlay>,
+}
+
+/// A writable overlay on `/usr` created by `bootc usroverlay --persist-until-update`,
+/// which is kept across reboots until another deployment is booted or staged.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistentUsrOverlay {
+    /// Whether the overlay is discarded on the next boot
+    pub discard_pending: bool,
+}
+
+/// A maintenance window, within which `--apply` may reboot.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceWindow {
+    /// Whether we are currently within the window
+    pub active: bool,
+    /// The start of the window
+    pub start: chrono::DateTime<chrono::FixedOffset>,
+    /// The end of the window
+    pub end: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// The policy of `bootc upgrade --auto`, as run by `bootc-fetch-apply-updates.service`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePolicy {
+    /// Whether automatic updates are enabled
+    pub enabled: bool,
+    /// What to do when an update is available
+    pub mode: UpdateMode,
+    /// How to reboot into an update, in the `apply` mode
+    pub reboot: RebootStrategy,
+    /// The maximum random delay before checking for an update, in seconds
+    pub jitter_seconds: u64,
+    /// How often a failed update is retried
+    pub retries: u32,
+    /// The delay before the first retry in seconds, which doubles for each further retry
+    pub backoff_seconds: u64,
+    /// The configuration files setting the policy; later files take precedence
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+}
+
+/// What automatic updates do when an update is available.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum UpdateMode {
+    /// Only check for updates, as `bootc upgrade --check`
+    Check,
+    /// Download and stage updates, which are applied on the next reboot
+    Stage,
+    /// Stage updates and reboot into them
+    #[default]
+    Apply,
+}
+
+/// How automatic updates reboot into a staged update.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum RebootStrategy {
+    /// Reboot within the configured maintenance windows, if any
+    #[default]
+    Window,
+    /// Reboot immediately
+    Immediate,
+    /// Reboot immediately, via a soft reboot if possible
+    #[serde(alias = "soft-reboot-if-possible")]
+    SoftRebootIfPossible,
+}
+
+/// A deployment pinned via `bootc deployment pin`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PinnedDeployment {
+    /// The index of the deployment in the boot order
+    pub index: u32,
+    /// The deployment
+    pub deployment: BootEntry,
+}
+
+/// An image pinned via `bootc switch --retain`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PinnedImage {
+    /// The image reference
+    pub image: ImageReference,
+    /// When the image was pinned
+    pub pinned: chrono::DateTime<chrono::Utc>,
+}
+
+/// The result of checking for an update via `bootc upgrade --check`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateCheck {
+    /// The image which was checked
+    pub image: ImageReference,
+    /// When the check was performed
+    pub checked: chrono::DateTime<chrono::Utc>,
+    /// The update, if one is available
+    pub available: Option<AvailableUpdate>,
+}
+
+/// An update found via `bootc upgrade --check`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AvailableUpdate {
+    /// The digest of the image manifest
+    pub image_digest: String,
+    /// The version string, if any
+    pub version: Option<String>,
+    /// The build timestamp, if any
+    pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    /// How the layers differ from the booted image, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layers: Option<LayerDiff>,
+}
+
+/// An update fetched via `bootc upgrade --download-only`; a subsequent
+/// `bootc upgrade` stages it without fetching anything.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadedUpdate {
+    /// The image which was fetched
+    pub image: ImageReference,
+    /// The digest of the image manifest
+    pub image_digest: String,
+    /// The version string, if any
+    pub version: Option<String>,
+    /// When the update was downloaded
+    pub downloaded: chrono::DateTime<chrono::Utc>,
+}
+
+/// A layer of a container image.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Layer {
+    /// The digest of the (possibly compressed) layer
+    pub digest: String,
+    /// The (possibly compressed) size
+    pub size: u64,
+}
+
+/// How the layers of an update differ from the booted image.  Layers are
+/// compared by position: a layer is changed if its digest differs from the
+/// booted image's layer at the same position.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerDiff {
+    /// The version string of the booted image, if any
+    pub booted_version: Option<String>,
+    /// The build timestamp of the booted image, if any
+    pub booted_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    /// The total number of layers in the update
+    pub total: u64,
+    /// Layers of the update which replace a layer of the booted image
+    pub changed: Vec<Layer>,
+    /// Layers of the update beyond the layers of the booted image
+    pub added: Vec<Layer>,
+    /// Layers of the booted image beyond the layers of the update
+    pub removed: Vec<Layer>,
+    /// The total size of the layers which are not already stored locally
+    pub download_size: u64,
+}
+
+/// How the root filesystem of the running system is provided.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RootBackend {
+    /// Whether the booted root filesystem is a composefs mount
+    pub composefs: bool,
+    /// How fs-verity is configured for the ostree repository
+    pub fsverity: FsVerity,
+    /// The filesystem type of `/sysroot`
+    pub sysroot_fstype: Option<String>,
+    /// Whether `/sysroot` is mounted read-only
+    pub sysroot_readonly: bool,
+}
+
+/// The fs-verity configuration of the ostree repository.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum FsVerity {
+    /// fs-verity is not used
+    Disabled,
+    /// fs-verity is enabled for new objects if the filesystem supports it
+    Enabled,
+    /// fs-verity is required for all objects
+    Required,
+}
+
+impl Display for FsVerity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Disabled => "disabled",
+            Self::Enabled => "enabled",
+            Self::Required => "required",
+        };
+        f.write_str(s)
+    }
+}
+
+impl Display for RootBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let root = if self.composefs {
+            "composefs"
+        } else {
+            "ostree"
+        };
+        write!(f, "{root} (fs-verity: {})", self.fsverity)?;
+        if let Some(fstype) = self.sysroot_fstype.as_deref() {
+            let mode = if self.sysroot_readonly { "ro" } else { "rw" };
+            write!(f, ", sysroot: {fstype} ({mode})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the staged deployment can be activated via `systemctl soft-reboot`,
+/// which restarts userspace without going through the firmware and kernel.
+#[derive(Debug, Clone, Serialize, Default, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SoftRebootReadiness {
+    /// Whether there is a staged deployment
+    pub staged: bool,
+    /// Whether the staged deployment can be applied via a soft reboot
+    pub compatible: bool,
+    /// The command which would apply the staged deployment, if any
+    pub command: Option<String>,
+    /// Why the staged deployment requires a full reboot
+    #[serde(default)]
+    pub blocking_reasons: Vec<SoftRebootBlocker>,
+    /// Whether `/run/nextroot` is set up for a soft reboot into the staged
+    /// deployment, i.e. a soft reboot is pending
+    #[serde(default)]
+    pub nextroot_prepared: bool,
+}
+
+/// A reason a staged deployment can't be applied via a soft reboot.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "reason", rename_all = "camelCase")]
+pub enum SoftRebootBlocker {
+    /// The staged deployment has a different kernel
+    KernelChanged {
+        /// The version of the booted kernel
+        booted: Option<String>,
+        /// The version of the staged kernel
+        staged: Option<String>,
+    },
+    /// The staged deployment has different kernel arguments
+    KargsChanged {
+        /// Arguments only in the staged deployment
+        added: Vec<String>,
+        /// Arguments only in the booted deployment
+        removed: Vec<String>,
+    },
+    /// The staged deployment has the same kernel, but a different initramfs
+    /// (or other boot files)
+    InitramfsChanged,
+}
+
+impl Display for SoftRebootBlocker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KernelChanged { booted, staged } => {
+                let unknown = "unknown";
+                write!(
+                    f,
+                    "kernel changed ({} -> {})",
+                    booted.as_deref().unwrap_or(unknown),
+                    staged.as_deref().unwrap_or(unknown)
+                )
+            }
+            Self::KargsChanged { added, removed } => {
+                f.write_str("kernel arguments changed (")?;
+                let changes = removed
+                    .iter()
+                    .map(|k| format!("-{k}"))
+                    .chain(added.iter().map(|k| format!("+{k}")));
+                for (i, change) in changes.enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    f.write_str(&change)?;
+                }
+                f.write_str(")")
+            }
+            Self::InitramfsChanged => f.write_str("initramfs changed"),
+        }
+    }
 }
 
 impl Host {
@@ -204,6 +614,171 @@ impl Display for ImageReference {
     }
 }
 
+/// The supported versions of the serialized [`Host`]; version 0 is the
+/// original format, later versions may add fields.
+pub(crate) const FORMAT_VERSIONS: &[u32] = &[0, 1];
+/// The most recent format version, used by default.
+pub(crate) const FORMAT_VERSION_LATEST: u32 = 1;
+
+/// A [`Host`] in a particular serialization format version.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum VersionedHost<'a> {
+    V0(Box<v0::Host<'a>>),
+    V1(HostV1<'a>),
+}
+
+/// The current format, which includes the version after the `apiVersion`.
+#[derive(Serialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[schemars(rename = "BootcHost")]
+pub(crate) struct HostV1<'a> {
+    #[serde(flatten)]
+    resource: &'a k8sapitypes::Resource,
+    /// The version of this format
+    format_version: u32,
+    /// The spec
+    spec: &'a HostSpec,
+    /// The status
+    status: &'a HostStatus,
+}
+
+/// A [`BootEntry`] in a particular serialization format version.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum VersionedBootEntry<'a> {
+    V0(v0::BootEntry<'a>),
+    V1(&'a BootEntry),
+}
+
+fn unsupported_format_version(version: u32) -> anyhow::Error {
+    let supported = FORMAT_VERSIONS
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    anyhow::anyhow!(
+        "Unsupported format version {version}; supported versions: {}",
+        supported.join(", ")
+    )
+}
+
+impl Host {
+    /// Get a serializable view of the host in the given format version.
+    pub(crate) fn versioned(&self, version: u32) -> anyhow::Result<VersionedHost<'_>> {
+        match version {
+            0 => Ok(VersionedHost::V0(Box::new(v0::Host::from(self)))),
+            1 => Ok(VersionedHost::V1(HostV1 {
+                resource: &self.resource,
+                format_version: version,
+                spec: &self.spec,
+                status: &self.status,
+            })),
+            o => Err(unsupported_format_version(o)),
+        }
+    }
+}
+
+impl BootEntry {
+    /// Get a serializable view of the entry in the given format version.
+    pub(crate) fn versioned(&self, version: u32) -> anyhow::Result<VersionedBootEntry<'_>> {
+        match version {
+            0 => Ok(VersionedBootEntry::V0(v0::BootEntry::from(self))),
+            1 => Ok(VersionedBootEntry::V1(self)),
+            o => Err(unsupported_format_version(o)),
+        }
+    }
+}
+
+/// The original format of the status, which must not change.
+pub(crate) mod v0 {
+    use serde::Serialize;
+
+    use crate::k8sapitypes;
+
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub(crate) struct Host<'a> {
+        #[serde(flatten)]
+        resource: &'a k8sapitypes::Resource,
+        spec: &'a super::HostSpec,
+        status: HostStatus<'a>,
+    }
+
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct HostStatus<'a> {
+        staged: Option<BootEntry<'a>>,
+        booted: Option<BootEntry<'a>>,
+        rollback: Option<BootEntry<'a>>,
+        rollback_queued: bool,
+        #[serde(rename = "type")]
+        ty: Option<&'a super::HostType>,
+    }
+
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub(crate) struct BootEntry<'a> {
+        image: Option<ImageStatus<'a>>,
+        cached_update: Option<ImageStatus<'a>>,
+        incompatible: bool,
+        pinned: bool,
+        ostree: &'a Option<super::BootEntryOstree>,
+    }
+
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct ImageStatus<'a> {
+        image: &'a super::ImageReference,
+        version: &'a Option<String>,
+        timestamp: &'a Option<chrono::DateTime<chrono::Utc>>,
+        image_digest: &'a str,
+    }
+
+    impl<'a> From<&'a super::Host> for Host<'a> {
+        fn from(host: &'a super::Host) -> Self {
+            let status = &host.status;
+            Self {
+                resource: &host.resource,
+                spec: &host.spec,
+                status: HostStatus {
+                    staged: status.staged.as_ref().map(Into::into),
+                    booted: status.booted.as_ref().map(Into::into),
+                    rollback: status.rollback.as_ref().map(Into::into),
+                    rollback_queued: status.rollback_queued,
+                    // Other types were added later
+                    ty: status
+                        .ty
+                        .as_ref()
+                        .filter(|&ty| ty == &super::HostType::BootcHost),
+                },
+            }
+        }
+    }
+
+    impl<'a> From<&'a super::BootEntry> for BootEntry<'a> {
+        fn from(entry: &'a super::BootEntry) -> Self {
+            Self {
+                image: entry.image.as_ref().map(Into::into),
+                cached_update: entry.cached_update.as_ref().map(Into::into),
+                incompatible: entry.incompatible,
+                pinned: entry.pinned,
+                ostree: &entry.ostree,
+            }
+        }
+    }
+
+    impl<'a> From<&'a super::ImageStatus> for ImageStatus<'a> {
+        fn from(image: &'a super::ImageStatus) -> Self {
+            Self {
+                image: &image.image,
+                version: &image.version,
+                timestamp: &image.timestamp,
+                image_digest: &image.image_digest,
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -258,4 +833,66 @@ mod tests {
         assert_eq!(displayed.as_str(), src);
         assert_eq!(format!("{s:#}"), src);
     }
+
+    #[test]
+    fn test_display_backend() {
+        let mut backend = RootBackend {
+            composefs: true,
+            fsverity: FsVerity::Enabled,
+            sysroot_fstype: Some("xfs".into()),
+            sysroot_readonly: true,
+        };
+        assert_eq!(
+            backend.to_string(),
+            "composefs (fs-verity: enabled), sysroot: xfs (ro)"
+        );
+        backend.composefs = false;
+        backend.fsverity = FsVerity::Disabled;
+        backend.sysroot_fstype = None;
+        assert_eq!(backend.to_string(), "ostree (fs-verity: disabled)");
+    }
+
+    #[test]
+    fn test_format_versions() {
+        let mut host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml")).unwrap();
+        let v0 = serde_json::to_string_pretty(&host.versioned(0).unwrap()).unwrap();
+        // Version 0 is stable
+        assert_eq!(v0, include_str!("fixtures/status-v0.json").trim_end());
+
+        // Additions don't change version 0
+        let booted = host.status.booted.as_mut().unwrap();
+        booted.kargs = vec!["quiet".into()];
+        let image = booted.image.as_mut().unwrap();
+        image.verification = ImageVerification::Unverified;
+        image.compressed_size = Some(1024);
+        host.status.soft_reboot = Some(Default::default());
+        host.status.backend = Some(RootBackend {
+            composefs: true,
+            fsverity: FsVerity::Required,
+            sysroot_fstype: Some("xfs".into()),
+            sysroot_readonly: true,
+        });
+        let v0_again = serde_json::to_string_pretty(&host.versioned(0).unwrap()).unwrap();
+        assert_eq!(v0, v0_again);
+
+        let v1 = serde_json::to_value(host.versioned(1).unwrap()).unwrap();
+        assert_eq!(v1["formatVersion"], 1);
+        assert_eq!(v1["apiVersion"], API_VERSION);
+        let booted = &v1["status"]["booted"];
+        assert_eq!(booted["kargs"][0], "quiet");
+        assert_eq!(booted["image"]["compressedSize"], 1024);
+        assert_eq!(booted["image"]["verification"]["type"], "unverified");
+        assert!(v1["status"]["softReboot"].is_object());
+        assert_eq!(v1["status"]["backend"]["fsverity"], "required");
+        // And it can be parsed back
+        let parsed: Host = serde_json::from_value(v1).unwrap();
+        assert_eq!(parsed, host);
+
+        let e = host.versioned(42).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Unsupported format version 42; supported versions: 0, 1"
+        );
+        assert_eq!(FORMAT_VERSIONS.last().copied(), Some(FORMAT_VERSION_LATEST));
+    }
 }
@@ -1,11 +1,18 @@
 use std::collections::VecDeque;
+use std::io::Write;
 
-use crate::spec::{BootEntry, BootOrder, Host, HostSpec, HostStatus, HostType, ImageStatus};
-use crate::spec::{ImageReference, ImageSignature};
+use crate::cli::StatusFormat;
+use crate::deploy::{PullInfo, ORIGIN_BOOTC_GROUP, ORIGIN_PULL_KEY, ORIGIN_VERIFICATION_KEY};
+use crate::kargs::KargSet;
+use crate::spec::{
+    BootEntry, BootEntryKernel, BootOrder, Host, HostSpec, HostStatus, HostType, ImageStatus,
+};
+use crate::spec::{ImageReference, ImageSignature, ImageVerification, PinnedDeployment};
+use crate::spec::{SoftRebootBlocker, SoftRebootReadiness, FORMAT_VERSION_LATEST};
 use anyhow::{Context, Result};
-use camino::Utf8Path;
+use camino::Utf8PathBuf;
 use fn_error_context::context;
-use ostree::glib;
+use ostree::{gio, glib};
 use ostree_container::OstreeImageReference;
 use ostree_ext::container as ostree_container;
 use ostree_ext::keyfileext::KeyFileExt;
@@ -37,7 +44,7 @@ impl From<ImageSignature> for ostree_container::SignatureSource {
 }
 
 /// Fixme lower serializability into ostree-ext
-fn transport_to_string(transport: ostree_container::Transport) -> String {
+pub(crate) fn transport_to_string(transport: ostree_container::Transport) -> String {
     match transport {
         // Canonicalize to registry for our own use
         ostree_container::Transport::Registry => "registry".to_string(),
@@ -82,7 +89,7 @@ impl From<ImageReference> for OstreeImageReference {
 
 /// Parse an ostree origin file (a keyfile) and extract the targeted
 /// container image reference.
-fn get_image_origin(origin: &glib::KeyFile) -> Result<Option<OstreeImageReference>> {
+pub(crate) fn get_image_origin(origin: &glib::KeyFile) -> Result<Option<OstreeImageReference>> {
     origin
         .optional_string("origin", ostree_container::deploy::ORIGIN_CONTAINER)
         .context("Failed to load container image from origin")?
@@ -90,10 +97,43 @@ fn get_image_origin(origin: &glib::KeyFile) -> Result<Option<OstreeImageReferenc
         .transpose()
 }
 
+/// Read how the image of a deployment was verified, as recorded when it was
+/// staged.  Deployments which predate this being recorded are reported as unknown.
+fn get_image_verification(origin: &glib::KeyFile) -> Result<ImageVerification> {
+    let v = origin.optional_string(ORIGIN_BOOTC_GROUP, ORIGIN_VERIFICATION_KEY)?;
+    Ok(parse_image_verification(v.as_deref()))
+}
+
+fn parse_image_verification(v: Option<&str>) -> ImageVerification {
+    let Some(v) = v else {
+        return ImageVerification::Unknown;
+    };
+    // Be tolerant of values written by a newer version
+    serde_json::from_str(v).unwrap_or_else(|e| {
+        tracing::debug!("Failed to parse {ORIGIN_VERIFICATION_KEY}: {e}");
+        ImageVerification::Unknown
+    })
+}
+
+/// Read the sizes and timing of the image pull recorded when the deployment was
+/// staged; these are all unset for deployments which predate this being recorded.
+fn get_pull_info(origin: &glib::KeyFile) -> Result<PullInfo> {
+    let v = origin.optional_string(ORIGIN_BOOTC_GROUP, ORIGIN_PULL_KEY)?;
+    Ok(parse_pull_info(v.as_deref()))
+}
+
+fn parse_pull_info(v: Option<&str>) -> PullInfo {
+    v.and_then(|v| {
+        serde_json::from_str(v)
+            .map_err(|e| tracing::debug!("Failed to parse {ORIGIN_PULL_KEY}: {e}"))
+            .ok()
+    })
+    .unwrap_or_default()
+}
+
 pub(crate) struct Deployments {
     pub(crate) staged: Option<ostree::Deployment>,
     pub(crate) rollback: Option<ostree::Deployment>,
-    #[allow(dead_code)]
     pub(crate) other: VecDeque<ostree::Deployment>,
 }
 
@@ -113,19 +153,25 @@ pub(crate) fn labels_of_config(
     config.config().as_ref().and_then(|c| c.labels().as_ref())
 }
 
+/// The creation timestamp of an image, from its `org.opencontainers.image.created` label.
+pub(crate) fn created_timestamp(
+    config: &ImageConfiguration,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    labels_of_config(config)
+        .and_then(|l| {
+            l.get(oci_spec::image::ANNOTATION_CREATED)
+                .map(|s| s.as_str())
+        })
+        .and_then(try_deserialize_timestamp)
+}
+
 /// Convert between a subset of ostree-ext metadata and the exposed spec API.
 pub(crate) fn create_imagestatus(
     image: ImageReference,
     manifest_digest: &str,
     config: &ImageConfiguration,
 ) -> ImageStatus {
-    let labels = labels_of_config(config);
-    let timestamp = labels
-        .and_then(|l| {
-            l.get(oci_spec::image::ANNOTATION_CREATED)
-                .map(|s| s.as_str())
-        })
-        .and_then(try_deserialize_timestamp);
+    let timestamp = created_timestamp(config);
 
     let version = ostree_container::version_for_config(config).map(ToOwned::to_owned);
     ImageStatus {
@@ -133,6 +179,11 @@ pub(crate) fn create_imagestatus(
         version,
         timestamp,
         image_digest: manifest_digest.to_owned(),
+        verification: Default::default(),
+        compressed_size: None,
+        unpacked_size: None,
+        pull_timestamp: None,
+        downloaded_size: None,
     }
 }
 
@@ -155,8 +206,19 @@ fn boot_entry_from_deployment(
             let cached = imgstate.cached_update.map(|cached| {
                 create_imagestatus(image.clone(), &cached.manifest_digest, &cached.config)
             });
-            let imagestatus =
+            let mut imagestatus =
                 create_imagestatus(image, &imgstate.manifest_digest, &imgstate.configuration);
+            imagestatus.verification = get_image_verification(origin)?;
+            let pull_info = get_pull_info(origin)?;
+            imagestatus.compressed_size = pull_info.compressed_size;
+            // Deployments of an image which was already stored when it was
+            // pulled (and those from older versions) don't record this
+            imagestatus.unpacked_size = match pull_info.unpacked_size {
+                Some(size) => Some(size),
+                None => Some(crate::deploy::commit_unpacked_size(repo, &csum)?),
+            };
+            imagestatus.pull_timestamp = pull_info.timestamp;
+            imagestatus.downloaded_size = pull_info.downloaded_size;
             // We found a container-image based deployment
             (Some(imagestatus), cached)
         } else {
@@ -178,10 +240,41 @@ fn boot_entry_from_deployment(
             // SAFETY: The deployserial is really unsigned
             deploy_serial: deployment.deployserial().try_into().unwrap(),
         }),
+        kargs: crate::kargs::kargs_of_deployment(deployment)
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        kernel: {
+            let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+            let root = sysroot_dir.open_dir(sysroot.deployment_dirpath(deployment).as_str())?;
+            kernel_of_root(&root)?
+        },
     };
     Ok(r)
 }
 
+/// Find the kernel in the root filesystem of a deployment, along with the
+/// digest of its initramfs.
+#[context("Inspecting kernel")]
+fn kernel_of_root(root: &cap_std_ext::cap_std::fs::Dir) -> Result<Option<BootEntryKernel>> {
+    let Some(version) = crate::kernel::find_kernel_version(root)? else {
+        return Ok(None);
+    };
+    let initramfs = Utf8PathBuf::from(format!("usr/lib/modules/{version}/initramfs.img"));
+    let initramfs_digest = if root.try_exists(&initramfs)? {
+        Some(format!(
+            "sha256:{}",
+            crate::etcdiff::sha256(root, &initramfs)?
+        ))
+    } else {
+        None
+    };
+    Ok(Some(BootEntryKernel {
+        version,
+        initramfs_digest,
+    }))
+}
+
 impl BootEntry {
     /// Given a boot entry, find its underlying ostree container image
     pub(crate) fn query_image(
@@ -199,6 +292,74 @@ impl BootEntry {
     }
 }
 
+/// The parts of a deployment which determine whether it can be soft rebooted into.
+struct BootState {
+    kernel: Option<String>,
+    /// The checksum of the kernel and initramfs
+    bootcsum: String,
+    kargs: KargSet,
+}
+
+impl BootState {
+    #[context("Reading boot state")]
+    fn new(
+        sysroot: &SysrootLock,
+        sysroot_dir: &cap_std_ext::cap_std::fs::Dir,
+        deployment: &ostree::Deployment,
+    ) -> Result<Self> {
+        let root = sysroot_dir.open_dir(sysroot.deployment_dirpath(deployment).as_str())?;
+        Ok(Self {
+            kernel: crate::kernel::find_kernel_version(&root)?,
+            bootcsum: deployment.bootcsum().to_string(),
+            kargs: crate::kargs::kargs_of_deployment(deployment),
+        })
+    }
+}
+
+/// The command used to apply a staged deployment without a full reboot.
+const SOFT_REBOOT_COMMAND: &str = "systemctl soft-reboot";
+/// The command used to apply a staged deployment otherwise.
+const REBOOT_COMMAND: &str = "systemctl reboot";
+
+/// Determine whether the `staged` deployment can be switched to from `booted`
+/// via a soft reboot, which requires the same kernel, initramfs and kernel arguments.
+fn soft_reboot_readiness(booted: &BootState, staged: Option<&BootState>) -> SoftRebootReadiness {
+    let Some(staged) = staged else {
+        return SoftRebootReadiness::default();
+    };
+    let mut blocking_reasons = Vec::new();
+    if booted.kernel.is_none() || booted.kernel != staged.kernel {
+        blocking_reasons.push(SoftRebootBlocker::KernelChanged {
+            booted: booted.kernel.clone(),
+            staged: staged.kernel.clone(),
+        });
+    } else if booted.bootcsum != staged.bootcsum {
+        blocking_reasons.push(SoftRebootBlocker::InitramfsChanged);
+    }
+    let diff = booted.kargs.diff(&staged.kargs);
+    if !diff.is_empty() {
+        let (old, new): (Vec<_>, Vec<_>) = diff.changed.into_iter().unzip();
+        let strings = |v: Vec<crate::kargs::Karg>| v.iter().map(ToString::to_string).collect();
+        blocking_reasons.push(SoftRebootBlocker::KargsChanged {
+            added: strings(diff.added.into_iter().chain(new).collect()),
+            removed: strings(diff.removed.into_iter().chain(old).collect()),
+        });
+    }
+    let compatible = blocking_reasons.is_empty();
+    let command = if compatible {
+        SOFT_REBOOT_COMMAND
+    } else {
+        REBOOT_COMMAND
+    };
+    SoftRebootReadiness {
+        staged: true,
+        compatible,
+        command: Some(command.to_owned()),
+        blocking_reasons,
+        nextroot_prepared: false,
+    }
+}
+
 /// A variant of [`get_status`] that requires a booted deployment.
 pub(crate) fn get_status_require_booted(
     sysroot: &SysrootLock,
@@ -267,6 +428,34 @@ pub(crate) fn get_status(
         .map(|d| boot_entry_from_deployment(sysroot, d))
         .transpose()
         .context("Rollback deployment")?;
+    let soft_reboot = booted_deployment
+        .map(|booted| -> Result<_> {
+            let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+            let booted = BootState::new(sysroot, &sysroot_dir, booted)?;
+            let staged = deployments
+                .staged
+                .as_ref()
+                .map(|d| BootState::new(sysroot, &sysroot_dir, d))
+                .transpose()?;
+            let mut r = soft_reboot_readiness(&booted, staged.as_ref());
+            if let Some(staged) = deployments.staged.as_ref() {
+                let staged = sysroot_dir.open_dir(sysroot.deployment_dirpath(staged).as_str())?;
+                // Only root may look into the mount namespace of systemd
+                r.nextroot_prepared = match crate::reboot::root_of(crate::reboot::HOST_PID) {
+                    Ok(root) => crate::reboot::nextroot_is(&root, &staged)?,
+                    Err(e) => {
+                        tracing::debug!("{e:#}");
+                        false
+                    }
+                };
+            }
+            Ok(r)
+        })
+        .transpose()
+        .context("Computing soft reboot readiness")?;
+    let backend = booted_deployment
+        .map(|_| crate::backend::inspect(&sysroot.repo()))
+        .transpose()?;
     let spec = staged
         .as_ref()
         .or(booted.as_ref())
@@ -276,14 +465,60 @@ pub(crate) fn get_status(
             boot_order,
         })
         .unwrap_or_default();
+    let (update_check, downloaded) = match (booted_deployment, spec.image.as_ref()) {
+        (Some(_), Some(imgref)) => {
+            let deployed = [&staged, &booted]
+                .into_iter()
+                .flatten()
+                .filter_map(|e| e.image.as_ref())
+                .map(|i| i.image_digest.as_str())
+                .collect::<Vec<_>>();
+            let root = cap_std_ext::cap_std::fs::Dir::open_ambient_dir(
+                "/",
+                cap_std_ext::cap_std::ambient_authority(),
+            )?;
+            let sysroot_dir = crate::utils::open_sysroot_dir(sysroot)?;
+            (
+                crate::updatecheck::load(&root, imgref, &deployed)?,
+                crate::download::load(&sysroot_dir, imgref, &deployed)?,
+            )
+        }
+        _ => (None, None),
+    };
 
-    let ty = if booted
-        .as_ref()
-        .map(|b| b.image.is_some())
-        .unwrap_or_default()
-    {
-        // We're only of type BootcHost if we booted via container image
-        Some(HostType::BootcHost)
+    // We're only of type BootcHost if we booted via container image
+    let ty = booted_deployment
+        .map(|d| {
+            let root = cap_std_ext::cap_std::fs::Dir::open_ambient_dir(
+                "/",
+                cap_std_ext::cap_std::ambient_authority(),
+            )?;
+            crate::hosttype::of_deployment(&root, d.origin().as_ref())
+        })
+        .transpose()?;
+
+    let pinned_deployments = deployments
+        .other
+        .iter()
+        .filter(|d| d.is_pinned())
+        .map(|d| {
+            Ok(PinnedDeployment {
+                index: d.index().try_into()?,
+                deployment: boot_entry_from_deployment(sysroot, d)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .context("Pinned deployments")?;
+    let pinned_images = crate::image::load_pins(&crate::utils::open_sysroot_dir(sysroot)?)?;
+    let config = crate::fetchconfig::load_config()?;
+    let maintenance_window = config.maintenance_window(chrono::Utc::now());
+    let update_policy = Some(config.update_policy());
+    let usr_overlay = if let Some(booted) = booted_deployment {
+        let root = cap_std_ext::cap_std::fs::Dir::open_ambient_dir(
+            "/",
+            cap_std_ext::cap_std::ambient_authority(),
+        )?;
+        crate::usroverlay::status(&root, sysroot, booted)?
     } else {
         None
     };
@@ -295,6 +530,15 @@ pub(crate) fn get_status(
         rollback,
         rollback_queued,
         ty,
+        soft_reboot,
+        backend,
+        update_check,
+        downloaded,
+        pinned_deployments,
+        pinned_images,
+        maintenance_window,
+        update_policy,
+        usr_overlay,
     };
     Ok((deployments, host))
 }
@@ -302,27 +546,409 @@ pub(crate) fn get_status(
 /// Implementation of the `bootc status` CLI command.
 #[context("Status")]
 pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
-    let host = if !Utf8Path::new("/run/ostree-booted").try_exists()? {
-        Default::default()
+    let ty = crate::hosttype::detect_host()?;
+    // There's nothing to watch without a sysroot
+    if let Some(interval) = opts.watch.filter(|_| crate::hosttype::has_sysroot(&ty)) {
+        return watch(&opts, std::time::Duration::from_secs(interval)).await;
+    }
+    let format = opts.output_format();
+    // The aleph is only shown in the verbose summary
+    let show_aleph =
+        opts.verbose && format == StatusFormat::HumanReadable && Slot::from_opts(&opts).is_none();
+    let (host, aleph) = if !crate::hosttype::has_sysroot(&ty) {
+        let mut host = Host::default();
+        host.status.ty = Some(ty);
+        (host, None)
     } else {
         crate::cli::require_root()?;
         let sysroot = super::cli::get_locked_sysroot().await?;
         let booted_deployment = sysroot.booted_deployment();
         let (_deployments, host) = get_status(&sysroot, booted_deployment.as_ref())?;
-        host
+        let aleph = if show_aleph {
+            crate::aleph::load(&crate::utils::open_sysroot_dir(&sysroot)?)?
+        } else {
+            None
+        };
+        (host, aleph)
     };
 
-    // If we're in JSON mode, then convert the ostree data into Rust-native
-    // structures that can be serialized.
-    // Filter to just the serializable status structures.
     let out = std::io::stdout();
     let mut out = out.lock();
-    if opts.json {
-        serde_json::to_writer(&mut out, &host).context("Writing to stdout")?;
+    let version = opts.format_version.unwrap_or(FORMAT_VERSION_LATEST);
+    if let Some(slot) = Slot::from_opts(&opts) {
+        write_entry(&mut out, &host, slot, format, version, opts.verbose)
     } else {
-        serde_yaml::to_writer(&mut out, &host).context("Writing to stdout")?;
+        write_host(&mut out, &host, format, version, opts.verbose).context("Writing to stdout")?;
+        if let Some(aleph) = aleph.as_ref() {
+            writeln!(out)?;
+            crate::aleph::write_human(&mut out, aleph)?;
+        }
+        Ok(())
+    }
+}
+
+impl super::cli::StatusOpts {
+    fn output_format(&self) -> StatusFormat {
+        if self.json {
+            StatusFormat::Json
+        } else {
+            self.format.unwrap_or(StatusFormat::Yaml)
+        }
+    }
+}
+
+/// Tracks the most recently displayed status in `--watch` mode, so that it is
+/// only displayed again when it changes.
+#[derive(Debug, Default)]
+struct StatusWatcher {
+    last: Option<Host>,
+}
+
+impl StatusWatcher {
+    /// Returns the new state if it differs from the last one.
+    fn update(&mut self, host: Host) -> Option<&Host> {
+        if self.last.as_ref() == Some(&host) {
+            return None;
+        }
+        Some(self.last.insert(host))
+    }
+}
+
+/// Display the status in `--watch` mode.  On a terminal, the screen is cleared
+/// first; otherwise JSON is written as one document per line, and YAML as a
+/// stream of documents.  A missing selected deployment is not an error here.
+fn write_watched(
+    out: &mut impl std::io::Write,
+    host: &Host,
+    opts: &super::cli::StatusOpts,
+    tty: bool,
+) -> Result<()> {
+    let format = opts.output_format();
+    let version = opts.format_version.unwrap_or(FORMAT_VERSION_LATEST);
+    match format {
+        StatusFormat::Json => {}
+        _ if tty => write!(out, "\x1b[H\x1b[2J")?,
+        StatusFormat::Yaml => writeln!(out, "---")?,
+        StatusFormat::HumanReadable => {}
+    }
+    match Slot::from_opts(opts) {
+        Some(slot) if slot.entry(host).is_none() => match format {
+            StatusFormat::HumanReadable => {
+                writeln!(out, "No {} deployment", slot.label().to_lowercase())?
+            }
+            StatusFormat::Yaml => writeln!(out, "null")?,
+            StatusFormat::Json => write!(out, "null")?,
+        },
+        Some(slot) => write_entry(out, host, slot, format, version, opts.verbose)?,
+        None => write_host(out, host, format, version, opts.verbose)?,
+    }
+    if format == StatusFormat::Json {
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Implementation of `bootc status --watch`; this keeps the sysroot open and
+/// reloads it only when it has changed, until interrupted.
+async fn watch(opts: &super::cli::StatusOpts, interval: std::time::Duration) -> Result<()> {
+    use std::io::IsTerminal;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    crate::cli::require_root()?;
+    // Validate the format version up front
+    Host::default().versioned(opts.format_version.unwrap_or(FORMAT_VERSION_LATEST))?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let sysroot = ostree::Sysroot::new_default();
+    sysroot.set_mount_namespace_in_use();
+    let mut loaded = false;
+    let mut watcher = StatusWatcher::default();
+    let tty = std::io::stdout().is_terminal();
+    loop {
+        let host = {
+            // Only hold the lock while gathering the status, so we don't block changes
+            let sysroot = SysrootLock::new_from_sysroot(&sysroot).await?;
+            if !loaded {
+                sysroot.load(gio::Cancellable::NONE)?;
+                loaded = true;
+            } else if sysroot.load_if_changed(gio::Cancellable::NONE)? {
+                tracing::debug!("Sysroot changed, reloaded");
+            }
+            let booted_deployment = sysroot.booted_deployment();
+            get_status(&sysroot, booted_deployment.as_ref())?.1
+        };
+        if let Some(host) = watcher.update(host) {
+            let mut out = std::io::stdout().lock();
+            write_watched(&mut out, host, opts, tty).context("Writing to stdout")?;
+        }
+        tokio::select! {
+            _ = sigint.recv() => return Ok(()),
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
+
+/// One of the deployments in the status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Slot {
+    Staged,
+    Booted,
+    Rollback,
+}
+
+impl Slot {
+    /// The deployment selected via `--staged`, `--booted` or `--rollback`, if any.
+    fn from_opts(opts: &super::cli::StatusOpts) -> Option<Self> {
+        if opts.staged {
+            Some(Self::Staged)
+        } else if opts.booted {
+            Some(Self::Booted)
+        } else if opts.rollback {
+            Some(Self::Rollback)
+        } else {
+            None
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Staged => "Staged",
+            Self::Booted => "Booted",
+            Self::Rollback => "Rollback",
+        }
+    }
+
+    fn entry(self, host: &Host) -> Option<&BootEntry> {
+        let status = &host.status;
+        match self {
+            Self::Staged => status.staged.as_ref(),
+            Self::Booted => status.booted.as_ref(),
+            Self::Rollback => status.rollback.as_ref(),
+        }
+    }
+}
+
+/// Serialize just the selected deployment of `host`, which must exist.
+fn write_entry(
+    out: &mut impl std::io::Write,
+    host: &Host,
+    slot: Slot,
+    format: StatusFormat,
+    version: u32,
+    verbose: bool,
+) -> Result<()> {
+    let entry = slot
+        .entry(host)
+        .ok_or_else(|| anyhow::anyhow!("No {} deployment", slot.label().to_lowercase()))?;
+    let versioned = entry.versioned(version)?;
+    match format {
+        StatusFormat::HumanReadable => write_human_entry(out, slot.label(), entry, verbose)?,
+        StatusFormat::Yaml => serde_yaml::to_writer(out, &versioned)?,
+        StatusFormat::Json => serde_json::to_writer(out, &versioned)?,
+    }
+    Ok(())
+}
+
+/// Serialize `host` in the given format.  Both YAML and JSON serialize the same
+/// structures (in the given format version), and the order of fields is fixed
+/// by their definitions.  `verbose` only applies to the human-readable format.
+fn write_host(
+    out: &mut impl std::io::Write,
+    host: &Host,
+    format: StatusFormat,
+    version: u32,
+    verbose: bool,
+) -> Result<()> {
+    let versioned = host.versioned(version)?;
+    match format {
+        StatusFormat::HumanReadable => write_human(out, host, verbose)?,
+        StatusFormat::Yaml => serde_yaml::to_writer(out, &versioned)?,
+        StatusFormat::Json => serde_json::to_writer(out, &versioned)?,
+    }
+    Ok(())
+}
+
+/// Write a summary of each deployment of `host`.
+fn write_human(out: &mut impl std::io::Write, host: &Host, verbose: bool) -> Result<()> {
+    if let Some(ty) = host
+        .status
+        .ty
+        .as_ref()
+        .filter(|&ty| ty != &HostType::BootcHost)
+    {
+        writeln!(
+            out,
+            "This system is not managed by bootc: {}",
+            crate::hosttype::describe(ty)
+        )?;
+        writeln!(out)?;
+    }
+    if let Some(overlay) = host.status.usr_overlay.as_ref() {
+        if overlay.discard_pending {
+            writeln!(
+                out,
+                "Persistent hotfix overlay on /usr: discarded on the next boot"
+            )?;
+        } else {
+            writeln!(
+                out,
+                "WARNING: Persistent hotfix overlay on /usr is active until the next update"
+            )?;
+            writeln!(out, "  Remove it with: bootc usroverlay reset")?;
+        }
+        writeln!(out)?;
+    }
+    let mut first = true;
+    for slot in [Slot::Staged, Slot::Booted, Slot::Rollback] {
+        let Some(entry) = slot.entry(host) else {
+            continue;
+        };
+        if !std::mem::take(&mut first) {
+            writeln!(out)?;
+        }
+        write_human_entry(out, slot.label(), entry, verbose)?;
+    }
+    for pinned in host.status.pinned_deployments.iter() {
+        if !std::mem::take(&mut first) {
+            writeln!(out)?;
+        }
+        let label = format!("Pinned deployment {}", pinned.index);
+        write_human_entry(out, &label, &pinned.deployment, verbose)?;
+    }
+    if first {
+        writeln!(out, "No deployments found.")?;
+    }
+    if !host.status.pinned_images.is_empty() {
+        writeln!(out)?;
+        for p in host.status.pinned_images.iter() {
+            writeln!(out, "Pinned image: {}", p.image)?;
+        }
     }
+    if let Some(backend) = host.status.backend.as_ref() {
+        writeln!(out)?;
+        writeln!(out, "Root backend: {backend}")?;
+    }
+    if let Some(check) = host.status.update_check.as_ref() {
+        writeln!(out)?;
+        let checked = check.checked.to_rfc3339();
+        match check.available.as_ref() {
+            Some(update) => {
+                let version = update.version.as_deref().unwrap_or(&update.image_digest);
+                writeln!(out, "Update available as of {checked}: {version}")?
+            }
+            None => writeln!(out, "No update available as of {checked}")?,
+        }
+    }
+    if let Some(downloaded) = host.status.downloaded.as_ref() {
+        writeln!(out)?;
+        let version = downloaded
+            .version
+            .as_deref()
+            .unwrap_or(&downloaded.image_digest);
+        let when = downloaded.downloaded.to_rfc3339();
+        writeln!(
+            out,
+            "Update downloaded, not staged: {version} (as of {when}); stage it with `bootc upgrade`"
+        )?;
+    }
+    if let Some(readiness) = host.status.soft_reboot.as_ref().filter(|r| r.staged) {
+        writeln!(out)?;
+        if readiness.nextroot_prepared {
+            writeln!(out, "A soft reboot into the staged deployment is pending")?;
+        } else if readiness.compatible {
+            writeln!(
+                out,
+                "The staged deployment can be applied with: {SOFT_REBOOT_COMMAND}"
+            )?;
+        } else {
+            let reasons = readiness
+                .blocking_reasons
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            writeln!(
+                out,
+                "The staged deployment requires a full reboot: {}",
+                reasons.join(", ")
+            )?;
+        }
+    }
+    if let Some(window) = host.status.maintenance_window.as_ref() {
+        let end = window.end.to_rfc3339();
+        if window.active {
+            writeln!(out, "Within maintenance window until {end}")?;
+        } else {
+            let start = window.start.to_rfc3339();
+            writeln!(out, "Next maintenance window: {start} - {end}")?;
+        }
+    }
+    if let Some(policy) = host.status.update_policy.as_ref() {
+        let policy = crate::autoupdate::describe(policy);
+        writeln!(out, "Automatic updates: {policy}")?;
+    }
+    Ok(())
+}
+
+/// Write a summary of a single deployment.
+fn write_human_entry(
+    out: &mut impl std::io::Write,
+    label: &str,
+    entry: &BootEntry,
+    verbose: bool,
+) -> Result<()> {
+    let Some(image) = entry.image.as_ref() else {
+        writeln!(out, "{label}: (not a container image)")?;
+        return write_human_kernel(out, entry, verbose);
+    };
+    writeln!(out, "{label} image: {}", image.image)?;
+    if let Some(version) = image.version.as_deref() {
+        writeln!(out, "  Version: {version}")?;
+    }
+    writeln!(out, "  Digest: {}", image.image_digest)?;
+    match (image.unpacked_size, image.compressed_size) {
+        (Some(unpacked), Some(compressed)) => writeln!(
+            out,
+            "  Size: {} (compressed: {})",
+            glib::format_size(unpacked),
+            glib::format_size(compressed)
+        )?,
+        (Some(size), None) => writeln!(out, "  Size: {}", glib::format_size(size))?,
+        (None, Some(size)) => writeln!(out, "  Size (compressed): {}", glib::format_size(size))?,
+        (None, None) => {}
+    }
+    if let Some(timestamp) = image.pull_timestamp {
+        writeln!(out, "  Pulled: {}", timestamp.to_rfc3339())?;
+    }
+    if let (Some(downloaded), Some(total)) = (image.downloaded_size, image.compressed_size) {
+        writeln!(
+            out,
+            "  Downloaded: {} of {}",
+            glib::format_size(downloaded),
+            glib::format_size(total)
+        )?;
+    }
+    writeln!(out, "  Verified: {}", image.verification)?;
+    if entry.pinned {
+        writeln!(out, "  Pinned: yes")?;
+    }
+    write_human_kernel(out, entry, verbose)
+}
 
+/// Write the kernel of `entry`; its initramfs and kernel arguments only if `verbose`.
+fn write_human_kernel(
+    out: &mut impl std::io::Write,
+    entry: &BootEntry,
+    verbose: bool,
+) -> Result<()> {
+    if let Some(kernel) = entry.kernel.as_ref() {
+        writeln!(out, "  Kernel: {}", kernel.version)?;
+        if let Some(digest) = kernel.initramfs_digest.as_deref().filter(|_| verbose) {
+            writeln!(out, "  Initramfs: {digest}")?;
+        }
+    }
+    if verbose && !entry.kargs.is_empty() {
+        writeln!(out, "  Kernel arguments: {}", entry.kargs.join(" "))?;
+    }
     Ok(())
 }
 
@@ -349,3 +975,493 @@ fn test_convert_signatures() {
         Some(ImageSignature::OstreeRemote("fedora".into()))
     );
 }
+
+#[test]
+fn test_write_host() -> Result<()> {
+    let host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let mut yaml = Vec::new();
+    write_host(
+        &mut yaml,
+        &host,
+        StatusFormat::Yaml,
+        FORMAT_VERSION_LATEST,
+        false,
+    )?;
+    let mut json = Vec::new();
+    write_host(
+        &mut json,
+        &host,
+        StatusFormat::Json,
+        FORMAT_VERSION_LATEST,
+        false,
+    )?;
+    let from_yaml: Host = serde_yaml::from_slice(&yaml)?;
+    let from_json: Host = serde_json::from_slice(&json)?;
+    assert_eq!(from_yaml, host);
+    assert_eq!(from_yaml, from_json);
+    // The output is stable
+    let mut again = Vec::new();
+    write_host(
+        &mut again,
+        &from_yaml,
+        StatusFormat::Yaml,
+        FORMAT_VERSION_LATEST,
+        false,
+    )?;
+    assert_eq!(yaml, again);
+    Ok(())
+}
+
+#[test]
+fn test_image_verification() -> Result<()> {
+    // Deployments which predate recording verification are reported as unknown
+    let host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let booted = host.status.booted.as_ref().unwrap().image.as_ref().unwrap();
+    assert_eq!(booted.verification, ImageVerification::Unknown);
+
+    let cases = [
+        (
+            ImageVerification::Unknown,
+            r#"{"type":"unknown"}"#,
+            "unknown",
+        ),
+        (
+            ImageVerification::Unverified,
+            r#"{"type":"unverified"}"#,
+            "no",
+        ),
+        (
+            ImageVerification::OstreeRemote {
+                remote: "fedora".into(),
+            },
+            r#"{"type":"ostreeRemote","remote":"fedora"}"#,
+            "yes (ostree-remote: fedora)",
+        ),
+        (
+            ImageVerification::SigstoreSigned {
+                identity: Some("myorg".into()),
+            },
+            r#"{"type":"sigstoreSigned","identity":"myorg"}"#,
+            "yes (sigstore: myorg)",
+        ),
+        (
+            ImageVerification::SignedBy { identity: None },
+            r#"{"type":"signedBy","identity":null}"#,
+            "yes (gpg)",
+        ),
+    ];
+    for (v, json, human) in cases.iter() {
+        assert_eq!(serde_json::to_string(v)?, *json);
+        assert_eq!(&serde_json::from_str::<ImageVerification>(json)?, v);
+        assert_eq!(v.to_string(), *human);
+    }
+
+    assert_eq!(parse_image_verification(None), ImageVerification::Unknown);
+    assert_eq!(
+        parse_image_verification(Some(r#"{"type":"future"}"#)),
+        ImageVerification::Unknown
+    );
+    assert_eq!(parse_image_verification(Some(cases[3].1)), cases[3].0);
+    Ok(())
+}
+
+#[test]
+fn test_pull_info() -> Result<()> {
+    // Deployments which predate recording this have no values
+    let host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let booted = host.status.booted.as_ref().unwrap().image.as_ref().unwrap();
+    assert_eq!(booted.compressed_size, None);
+    assert_eq!(booted.pull_timestamp, None);
+    assert_eq!(parse_pull_info(None), PullInfo::default());
+    assert_eq!(parse_pull_info(Some("garbage")), PullInfo::default());
+
+    let info = PullInfo {
+        compressed_size: Some(512 << 20),
+        unpacked_size: Some(3 << 30),
+        timestamp: try_deserialize_timestamp("2024-05-02T10:11:12Z"),
+        downloaded_size: Some(112 << 20),
+        source: None,
+    };
+    let v = serde_json::to_string(&info)?;
+    assert_eq!(parse_pull_info(Some(&v)), info);
+    assert!(!v.contains("source"));
+    // An image fetched from an OCI archive, recorded as its registry image
+    let archive = PullInfo {
+        source: Some(ImageReference {
+            image: "/mnt/usb/os.tar".into(),
+            transport: "oci-archive".into(),
+            signature: Some(ImageSignature::ContainerPolicy),
+        }),
+        ..info.clone()
+    };
+    let v = serde_json::to_string(&archive)?;
+    assert_eq!(parse_pull_info(Some(&v)), archive);
+    // Records from older versions don't have the downloaded size
+    let old = parse_pull_info(Some(r#"{"compressedSize":1024}"#));
+    assert_eq!(old.compressed_size, Some(1024));
+    assert_eq!(old.downloaded_size, None);
+
+    let mut image = booted.clone();
+    image.compressed_size = info.compressed_size;
+    image.unpacked_size = info.unpacked_size;
+    image.pull_timestamp = info.timestamp;
+    image.downloaded_size = info.downloaded_size;
+    let v = serde_json::to_value(&image)?;
+    assert_eq!(v["downloadedSize"], 117440512);
+    assert_eq!(v["compressedSize"], 536870912);
+    assert_eq!(v["unpackedSize"], 3221225472u64);
+    assert_eq!(v["pullTimestamp"], "2024-05-02T10:11:12Z");
+    let v = serde_json::to_value(booted)?;
+    assert!(v["pullTimestamp"].is_null());
+    Ok(())
+}
+
+#[test]
+fn test_write_human() -> Result<()> {
+    let host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let mut out = Vec::new();
+    write_host(
+        &mut out,
+        &host,
+        StatusFormat::HumanReadable,
+        FORMAT_VERSION_LATEST,
+        false,
+    )?;
+    let out = String::from_utf8(out)?;
+    assert!(out.contains("Booted image: "), "{out}");
+    assert!(out.contains("  Verified: unknown\n"), "{out}");
+    let mut out = Vec::new();
+    write_host(
+        &mut out,
+        &Host::default(),
+        StatusFormat::HumanReadable,
+        FORMAT_VERSION_LATEST,
+        false,
+    )?;
+    assert_eq!(String::from_utf8(out)?, "No deployments found.\n");
+    Ok(())
+}
+
+#[test]
+fn test_soft_reboot_readiness() {
+    let state = |kernel: Option<&str>, kargs: &str| BootState {
+        kernel: kernel.map(ToOwned::to_owned),
+        bootcsum: format!("{kernel:?}"),
+        kargs: KargSet::parse(kargs),
+    };
+    let kver = Some("6.8.9-300.fc40.x86_64");
+    let booted = state(kver, "root=UUID=abcd rw quiet");
+
+    // Nothing staged
+    let r = soft_reboot_readiness(&booted, None);
+    assert_eq!(r, SoftRebootReadiness::default());
+
+    let r = soft_reboot_readiness(&booted, Some(&state(kver, "root=UUID=abcd rw quiet")));
+    assert!(r.staged && r.compatible);
+    assert_eq!(r.command.as_deref(), Some(SOFT_REBOOT_COMMAND));
+    assert!(r.blocking_reasons.is_empty());
+
+    let r = soft_reboot_readiness(
+        &booted,
+        Some(&state(
+            Some("6.9.1-100.fc40.x86_64"),
+            "root=UUID=abcd rw quiet",
+        )),
+    );
+    assert!(r.staged && !r.compatible);
+    assert_eq!(r.command.as_deref(), Some(REBOOT_COMMAND));
+    assert_eq!(
+        r.blocking_reasons,
+        [SoftRebootBlocker::KernelChanged {
+            booted: kver.map(Into::into),
+            staged: Some("6.9.1-100.fc40.x86_64".into())
+        }]
+    );
+    assert_eq!(
+        r.blocking_reasons[0].to_string(),
+        "kernel changed (6.8.9-300.fc40.x86_64 -> 6.9.1-100.fc40.x86_64)"
+    );
+
+    let r = soft_reboot_readiness(
+        &booted,
+        Some(&state(kver, "root=UUID=efgh rw console=ttyS0")),
+    );
+    assert!(!r.compatible);
+    assert_eq!(
+        r.blocking_reasons,
+        [SoftRebootBlocker::KargsChanged {
+            added: vec!["console=ttyS0".into(), "root=UUID=efgh".into()],
+            removed: vec!["quiet".into(), "root=UUID=abcd".into()],
+        }]
+    );
+    assert_eq!(
+        r.blocking_reasons[0].to_string(),
+        "kernel arguments changed (-quiet -root=UUID=abcd +console=ttyS0 +root=UUID=efgh)"
+    );
+
+    // The same kernel with a regenerated initramfs
+    let r = soft_reboot_readiness(
+        &booted,
+        Some(&BootState {
+            bootcsum: "other".into(),
+            ..state(kver, "root=UUID=abcd rw quiet")
+        }),
+    );
+    assert!(!r.compatible);
+    assert_eq!(r.blocking_reasons, [SoftRebootBlocker::InitramfsChanged]);
+    assert_eq!(r.blocking_reasons[0].to_string(), "initramfs changed");
+
+    // If we can't determine the kernel, be conservative
+    let r = soft_reboot_readiness(&state(None, "rw"), Some(&state(None, "rw")));
+    assert!(!r.compatible);
+
+    // The structured form
+    let v = serde_json::to_value(&r).unwrap();
+    assert_eq!(v["blockingReasons"][0]["reason"], "kernelChanged");
+}
+
+#[test]
+fn test_write_entry() -> Result<()> {
+    let mut host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let write = |host: &Host, slot, format| -> Result<String> {
+        let mut out = Vec::new();
+        write_entry(&mut out, host, slot, format, FORMAT_VERSION_LATEST, false)?;
+        Ok(String::from_utf8(out)?)
+    };
+
+    // There's no staged deployment
+    let e = write(&host, Slot::Staged, StatusFormat::Json).unwrap_err();
+    assert_eq!(e.to_string(), "No staged deployment");
+    let e = write(&host, Slot::Rollback, StatusFormat::Yaml).unwrap_err();
+    assert_eq!(e.to_string(), "No rollback deployment");
+
+    let booted = host.status.booted.clone().unwrap();
+    let with_digest = |digest: &str| {
+        let mut entry = booted.clone();
+        entry.image.as_mut().unwrap().image_digest = digest.into();
+        entry
+    };
+    host.status.staged = Some(with_digest("sha256:staged"));
+    host.status.rollback = Some(with_digest("sha256:rollback"));
+    for (slot, digest) in [
+        (Slot::Staged, "sha256:staged"),
+        (
+            Slot::Booted,
+            booted.image.as_ref().unwrap().image_digest.as_str(),
+        ),
+        (Slot::Rollback, "sha256:rollback"),
+    ] {
+        let json: serde_json::Value =
+            serde_json::from_str(&write(&host, slot, StatusFormat::Json)?)?;
+        assert_eq!(json["image"]["imageDigest"], digest);
+        let yaml: BootEntry = serde_yaml::from_str(&write(&host, slot, StatusFormat::Yaml)?)?;
+        assert_eq!(yaml.image.unwrap().image_digest, digest);
+        let human = write(&host, slot, StatusFormat::HumanReadable)?;
+        assert!(
+            human.starts_with(&format!("{} image: ", slot.label())),
+            "{human}"
+        );
+        assert!(human.contains(&format!("  Digest: {digest}\n")), "{human}");
+        assert_eq!(human.matches(" image: ").count(), 1);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_watch() -> Result<()> {
+    use clap::Parser;
+    let mut host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let mut watcher = StatusWatcher::default();
+    let opts =
+        crate::cli::StatusOpts::try_parse_from(["status", "--watch", "--format=json"]).unwrap();
+    let mut out = Vec::new();
+    let mut render = |host: &Host| {
+        if let Some(host) = watcher.update(host.clone()) {
+            write_watched(&mut out, host, &opts, false).unwrap();
+        }
+    };
+    render(&host);
+    // No changes
+    render(&host);
+    render(&host);
+    // A new staged deployment
+    host.status.staged = host.status.booted.clone();
+    render(&host);
+    render(&host);
+    // Which is then marked as a rollback
+    host.status.rollback = host.status.staged.take();
+    render(&host);
+    let out = String::from_utf8(out)?;
+    let docs = out
+        .lines()
+        .map(serde_json::from_str::<Host>)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    assert_eq!(docs.len(), 3);
+    assert!(docs[0].status.staged.is_none());
+    assert!(docs[1].status.staged.is_some());
+    assert!(docs[2].status.staged.is_none() && docs[2].status.rollback.is_some());
+    assert_eq!(docs[2], host);
+
+    // A missing selected deployment isn't an error
+    let opts = crate::cli::StatusOpts::try_parse_from(["status", "--watch", "--json", "--staged"])
+        .unwrap();
+    let mut out = Vec::new();
+    write_watched(&mut out, &host, &opts, false)?;
+    assert_eq!(String::from_utf8(out)?, "null\n");
+    let opts = crate::cli::StatusOpts::try_parse_from(["status", "--watch"]).unwrap();
+    let mut out = Vec::new();
+    write_watched(&mut out, &host, &opts, false)?;
+    assert!(String::from_utf8(out)?.starts_with("---\n"));
+    let mut out = Vec::new();
+    write_watched(&mut out, &host, &opts, true)?;
+    assert!(String::from_utf8(out)?.starts_with("\x1b[H\x1b[2J"));
+    Ok(())
+}
+
+#[test]
+fn test_write_pinned_deployments() -> Result<()> {
+    let mut host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let booted = host.status.booted.as_mut().unwrap();
+    booted.pinned = true;
+    let mut older = booted.clone();
+    older.image.as_mut().unwrap().version = Some("20231101.0".into());
+    host.status.pinned_deployments = vec![PinnedDeployment {
+        index: 2,
+        deployment: older,
+    }];
+    let mut out = Vec::new();
+    write_host(
+        &mut out,
+        &host,
+        StatusFormat::HumanReadable,
+        FORMAT_VERSION_LATEST,
+        false,
+    )?;
+    let out = String::from_utf8(out)?;
+    let (booted, pinned) = out.split_once("Pinned deployment 2 image: ").unwrap();
+    assert!(booted.contains("  Pinned: yes\n"), "{out}");
+    assert!(pinned.contains("  Version: 20231101.0\n"), "{out}");
+
+    let v = serde_json::to_value(&host)?;
+    assert_eq!(v["status"]["booted"]["pinned"], true);
+    assert_eq!(v["status"]["pinnedDeployments"][0]["index"], 2);
+    host.status.pinned_deployments.clear();
+    let v = serde_json::to_value(&host)?;
+    assert!(v["status"].get("pinnedDeployments").is_none());
+    Ok(())
+}
+
+#[test]
+fn test_write_downloaded() -> Result<()> {
+    let mut host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let image = host.spec.image.clone().unwrap();
+    host.status.downloaded = Some(crate::spec::DownloadedUpdate {
+        image,
+        image_digest: "sha256:new".into(),
+        version: Some("42.1".into()),
+        downloaded: "2024-05-02T10:11:12Z".parse()?,
+    });
+    let mut out = Vec::new();
+    write_host(
+        &mut out,
+        &host,
+        StatusFormat::HumanReadable,
+        FORMAT_VERSION_LATEST,
+        false,
+    )?;
+    let out = String::from_utf8(out)?;
+    assert!(
+        out.contains("Update downloaded, not staged: 42.1 (as of 2024-05-02T10:11:12+00:00)"),
+        "{out}"
+    );
+    let v = serde_json::to_value(&host)?;
+    assert_eq!(v["status"]["downloaded"]["imageDigest"], "sha256:new");
+    host.status.downloaded = None;
+    let v = serde_json::to_value(&host)?;
+    assert!(v["status"].get("downloaded").is_none());
+    Ok(())
+}
+
+#[test]
+fn test_kernel() -> Result<()> {
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    assert_eq!(kernel_of_root(&td)?, None);
+    td.create_dir_all("usr/lib/modules/6.8.9-300.fc40.x86_64")?;
+    td.write("usr/lib/modules/6.8.9-300.fc40.x86_64/vmlinuz", "kernel")?;
+    let kernel = kernel_of_root(&td)?.unwrap();
+    assert_eq!(kernel.version, "6.8.9-300.fc40.x86_64");
+    assert_eq!(kernel.initramfs_digest, None);
+    td.write(
+        "usr/lib/modules/6.8.9-300.fc40.x86_64/initramfs.img",
+        "initramfs",
+    )?;
+    let kernel = kernel_of_root(&td)?.unwrap();
+    assert_eq!(
+        kernel.initramfs_digest.as_deref().unwrap(),
+        format!("sha256:{}", hex::encode(openssl::sha::sha256(b"initramfs")))
+    );
+
+    // A staged deployment with a different kernel than the booted one
+    let mut host: Host = serde_yaml::from_str(include_str!("fixtures/spec-v1.yaml"))?;
+    let booted = host.status.booted.as_mut().unwrap();
+    booted.kernel = Some(kernel);
+    booted.kargs = vec!["root=UUID=abcd".into(), "rw".into()];
+    let mut staged = booted.clone();
+    staged.kernel = Some(BootEntryKernel {
+        version: "6.9.1-100.fc40.x86_64".into(),
+        initramfs_digest: None,
+    });
+    host.status.staged = Some(staged);
+
+    let mut json = Vec::new();
+    write_host(
+        &mut json,
+        &host,
+        StatusFormat::Json,
+        FORMAT_VERSION_LATEST,
+        false,
+    )?;
+    let v: serde_json::Value = serde_json::from_slice(&json)?;
+    assert_eq!(
+        v["status"]["booted"]["kernel"]["version"],
+        "6.8.9-300.fc40.x86_64"
+    );
+    assert_eq!(
+        v["status"]["staged"]["kernel"],
+        serde_json::json!({"version": "6.9.1-100.fc40.x86_64"})
+    );
+    assert_eq!(
+        v["status"]["booted"]["kargs"],
+        serde_json::json!(["root=UUID=abcd", "rw"])
+    );
+    assert_eq!(serde_json::from_slice::<Host>(&json)?, host);
+    // The original format is unchanged
+    let mut json = Vec::new();
+    write_host(&mut json, &host, StatusFormat::Json, 0, false)?;
+    let v: serde_json::Value = serde_json::from_slice(&json)?;
+    assert!(v["status"]["booted"].get("kernel").is_none());
+
+    let human = |verbose| -> Result<String> {
+        let mut out = Vec::new();
+        write_host(
+            &mut out,
+            &host,
+            StatusFormat::HumanReadable,
+            FORMAT_VERSION_LATEST,
+            verbose,
+        )?;
+        Ok(String::from_utf8(out)?)
+    };
+    let out = human(false)?;
+    assert!(out.contains("  Kernel: 6.9.1-100.fc40.x86_64\n"), "{out}");
+    assert!(out.contains("  Kernel: 6.8.9-300.fc40.x86_64\n"), "{out}");
+    assert!(!out.contains("Kernel arguments"), "{out}");
+    assert!(!out.contains("Initramfs"), "{out}");
+    let out = human(true)?;
+    assert!(
+        out.contains("  Kernel arguments: root=UUID=abcd rw\n"),
+        "{out}"
+    );
+    assert!(out.contains("  Initramfs: sha256:"), "{out}");
+    Ok(())
+}
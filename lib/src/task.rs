@@ -1,13 +1,21 @@
 use std::{
     ffi::OsStr,
-    io::{Seek, Write},
-    process::{Command, Stdio},
+    io::{IsTerminal, Read, Seek, Write},
+    os::unix::ffi::OsStrExt,
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
 use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
 use cap_std_ext::prelude::CapStdExtCommandExt;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+#[cfg(feature = "install")]
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 /// How much information we output
 #[derive(Debug, PartialEq, Eq, Default)]
@@ -25,9 +33,57 @@ pub(crate) struct Task {
     description: String,
     verbosity: CmdVerbosity,
     quiet_output: bool,
+    /// Whether stderr is passed through rather than teed (see [`Task::new_cmd()`]).
+    inherit_stderr: bool,
+    timeout: Option<Duration>,
     pub(crate) cmd: Command,
 }
 
+/// Maximum size of stdout captured by [`Task::read()`] and its variants, which
+/// fail with [`OutputLimitError`] (and close the pipe) once it is exceeded.  This
+/// is far more than any command we parse the output of should ever produce.
+pub(crate) const OUTPUT_LIMIT: u64 = 4 * 1024 * 1024;
+
+/// Returned (wrapped in an [`anyhow::Error`]) when a task did not complete
+/// within its configured timeout; use `downcast_ref` to detect this case.
+#[derive(Debug)]
+pub(crate) struct TaskTimeoutError {
+    pub(crate) description: String,
+    pub(crate) timeout: Duration,
+}
+
+impl std::fmt::Display for TaskTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Task {} timed out after {:?}",
+            self.description, self.timeout
+        )
+    }
+}
+
+impl std::error::Error for TaskTimeoutError {}
+
+/// Returned (wrapped in an [`anyhow::Error`]) when the captured output of a
+/// task exceeded its configured limit.
+#[derive(Debug)]
+pub(crate) struct OutputLimitError {
+    pub(crate) description: String,
+    pub(crate) limit: u64,
+}
+
+impl std::fmt::Display for OutputLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Output of task {} exceeded limit of {} bytes",
+            self.description, self.limit
+        )
+    }
+}
+
+impl std::error::Error for OutputLimitError {}
+
 impl Task {
     pub(crate) fn new(description: impl AsRef<str>, exe: impl AsRef<str>) -> Self {
         Self::new_cmd(description, Command::new(exe.as_ref()))
@@ -54,6 +110,13 @@ impl Task {
             description,
             verbosity: Default::default(),
             quiet_output: false,
+            // When our stderr is a terminal, let the child write to it directly
+            // so that it can still detect the TTY (for progress bars, colors and
+            // prompts).  The user sees the output there, so we don't need its tail
+            // in the error.  Otherwise (e.g. under journald) stderr is teed so that
+            // failures carry an excerpt.  Unit tests always tee for determinism.
+            inherit_stderr: !cfg!(test) && std::io::stderr().is_terminal(),
+            timeout: None,
             cmd,
         }
     }
@@ -76,6 +139,13 @@ impl Task {
         self
     }
 
+    /// Fail if the command does not exit within the provided duration.  On expiry,
+    /// the whole process group of the child is killed.
+    pub(crate) fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     pub(crate) fn args<S: AsRef<OsStr>>(mut self, args: impl IntoIterator<Item = S>) -> Self {
         self.cmd.args(args);
         self
@@ -100,26 +170,20 @@ impl Task {
             CmdVerbosity::Verbose => {
                 // Output the description first
                 println!("{}", self.description);
-
-                // Lock stdout so we buffer
-                let mut stdout = std::io::stdout().lock();
-                let cmd_args = std::iter::once(self.cmd.get_program())
-                    .chain(self.cmd.get_args())
-                    .map(|arg| arg.to_string_lossy());
-                // We unwrap() here to match the default for println!() even though
-                // arguably that's wrong
-                stdout.write_all(b">").unwrap();
-                for s in cmd_args {
-                    stdout.write_all(b" ").unwrap();
-                    stdout.write_all(s.as_bytes()).unwrap();
-                }
-                stdout.write_all(b"\n").unwrap();
+                println!("> {}", render_argv(&self.cmd));
             }
         }
     }
 
     /// Run the command with optional stdin buffer, returning an error if the command does not exit successfully.
     pub(crate) fn run_with_stdin_buf(self, stdin: Option<&[u8]>) -> Result<()> {
+        match stdin {
+            Some(mut buf) => self.run_impl(Some(&mut buf)),
+            None => self.run_impl(None),
+        }
+    }
+
+    fn run_impl(self, stdin: Option<&mut (dyn Read + Send)>) -> Result<()> {
         self.pre_run_output();
         let description = self.description;
         let mut cmd = self.cmd;
@@ -129,54 +193,237 @@ impl Task {
             cmd.stdout(Stdio::from(tmpf.try_clone()?));
             cmd.stderr(Stdio::from(tmpf.try_clone()?));
             output = Some(tmpf);
+        } else if !self.inherit_stderr {
+            // Tee stderr so that we can include its tail in the error
+            cmd.stderr(Stdio::piped());
+        }
+        if self.timeout.is_some() {
+            // Put the child in its own process group so that on timeout we
+            // can also kill anything it forked.
+            cmd.process_group(0);
         }
-        tracing::debug!("exec: {cmd:?}");
-        let st = if let Some(stdin_value) = stdin {
+        if stdin.is_some() {
             cmd.stdin(Stdio::piped());
-            let mut child = cmd.spawn()?;
-            // SAFETY: We used piped for stdin
-            let mut stdin = child.stdin.take().unwrap();
-            // If this was async, we could avoid spawning a thread here
-            std::thread::scope(|s| {
-                s.spawn(move || stdin.write_all(stdin_value))
-                    .join()
-                    .map_err(|e| anyhow::anyhow!("Failed to spawn thread: {e:?}"))?
-                    .context("Failed to write to cryptsetup stdin")
-            })?;
-            child.wait()?
-        } else {
-            cmd.status()?
-        };
-        tracing::trace!("{st:?}");
+        }
+        let mut trace = ExecTrace::new(&cmd, trace_commands());
+        let mut child = cmd.spawn()?;
+        trace.spawned(Some(child.id()));
+        let stderr = child.stderr.take();
+        let (st, stdin_result, stderr_tail) = std::thread::scope(|s| -> Result<_> {
+            let stderr_tee = stderr.map(|stderr| s.spawn(move || tee_tail(stderr)));
+            // Write stdin from a helper thread, so that a child which doesn't
+            // read all of its input before writing output can't deadlock us.
+            let stdin_writer = stdin.map(|input| {
+                // SAFETY: We used piped for stdin
+                let mut stdin = child.stdin.take().unwrap();
+                s.spawn(move || std::io::copy(input, &mut stdin).map(|_| ()))
+            });
+            let st = wait_child(&mut child, &description, self.timeout);
+            let stdin_result = stdin_writer
+                .map(|h| {
+                    h.join()
+                        .map_err(|e| anyhow::anyhow!("Failed to join stdin thread: {e:?}"))
+                })
+                .transpose()?;
+            let stderr_tail = stderr_tee.map(join_tee).transpose()?;
+            Ok((st, stdin_result, stderr_tail))
+        })?;
+        trace.finish(&st);
+        let st = st?;
+        // If the child failed, that's a more useful error than a failure
+        // to write its input, so only check this on success.
+        if st.success() {
+            if let Some(r) = stdin_result {
+                r.with_context(|| format!("Writing stdin of {description}"))?;
+            }
+        }
         if !st.success() {
-            if let Some(mut output) = output {
+            let tail = if let Some(mut output) = output {
                 output.seek(std::io::SeekFrom::Start(0))?;
                 let mut stderr = std::io::stderr().lock();
                 std::io::copy(&mut output, &mut stderr)?;
-            }
-            anyhow::bail!("Task {description} failed: {st:?}");
+                output.seek(std::io::SeekFrom::Start(0))?;
+                Some(tee_tail(output)?)
+            } else {
+                stderr_tail
+            };
+            return Err(task_failure(&description, &cmd, st, tail.as_ref()));
         }
         Ok(())
     }
 
     /// Like [`run()`], but return stdout.
     pub(crate) fn read(self) -> Result<String> {
+        self.read_with_stdin_impl(None)
+    }
+
+    /// Like [`read()`], but write `stdin` to the standard input of the command.
+    #[cfg(feature = "install")]
+    pub(crate) fn read_with_stdin_buf(self, stdin: &[u8]) -> Result<String> {
+        self.read_with_stdin_impl(Some(stdin))
+    }
+
+    fn read_with_stdin_impl(self, stdin: Option<&[u8]>) -> Result<String> {
+        let stdout = self.read_impl(stdin, |stdout| read_bounded(stdout, OUTPUT_LIMIT))?;
+        Ok(String::from_utf8(stdout)?)
+    }
+
+    /// Run the command with optional stdin buffer, capturing stdout via
+    /// `capture`; it returning `None` means the output limit was exceeded.
+    fn read_impl<T: Send>(
+        self,
+        stdin: Option<&[u8]>,
+        capture: impl FnOnce(std::process::ChildStdout) -> std::io::Result<Option<T>> + Send,
+    ) -> Result<T> {
         self.pre_run_output();
         let description = self.description;
         let mut cmd = self.cmd;
-        tracing::debug!("exec: {cmd:?}");
         cmd.stdout(Stdio::piped());
-        let child = cmd
+        if !self.inherit_stderr {
+            cmd.stderr(Stdio::piped());
+        }
+        if self.timeout.is_some() {
+            cmd.process_group(0);
+        }
+        if stdin.is_some() {
+            cmd.stdin(Stdio::piped());
+        }
+        let mut trace = ExecTrace::new(&cmd, trace_commands());
+        let mut child = cmd
             .spawn()
             .with_context(|| format!("Spawning {description} failed"))?;
-        let o = child
-            .wait_with_output()
-            .with_context(|| format!("Executing {description} failed"))?;
-        let st = o.status;
+        trace.spawned(Some(child.id()));
+        // SAFETY: We used piped for stdout
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take();
+        // Read both pipes from helper threads so that a child filling
+        // either of them can't block us.
+        let (st, stdout, stdin_result, stderr_tail) = std::thread::scope(|s| -> Result<_> {
+            // Note that the pipe is closed when this returns, so a child
+            // which exceeds the limit will get EPIPE or SIGPIPE.
+            let stdout = s.spawn(move || capture(stdout));
+            let stderr_tee = stderr.map(|stderr| s.spawn(move || tee_tail(stderr)));
+            let stdin_writer = stdin.map(|mut input| {
+                // SAFETY: We used piped for stdin
+                let mut stdin = child.stdin.take().unwrap();
+                s.spawn(move || std::io::copy(&mut input, &mut stdin).map(|_| ()))
+            });
+            let st = wait_child(&mut child, &description, self.timeout);
+            let stdout = stdout
+                .join()
+                .map_err(|e| anyhow::anyhow!("Failed to join reader thread: {e:?}"))?;
+            let stdin_result = stdin_writer
+                .map(|h| {
+                    h.join()
+                        .map_err(|e| anyhow::anyhow!("Failed to join stdin thread: {e:?}"))
+                })
+                .transpose()?;
+            let stderr_tail = stderr_tee.map(join_tee).transpose()?;
+            Ok((st, stdout?, stdin_result, stderr_tail))
+        })?;
+        trace.finish(&st);
+        let st = st.with_context(|| format!("Executing {description} failed"))?;
+        let Some(stdout) = stdout else {
+            return Err(OutputLimitError {
+                description,
+                limit: OUTPUT_LIMIT,
+            }
+            .into());
+        };
         if !st.success() {
-            anyhow::bail!("Task {description} failed: {st:?}");
+            return Err(task_failure(&description, &cmd, st, stderr_tail.as_ref()));
+        }
+        if let Some(r) = stdin_result {
+            r.with_context(|| format!("Writing stdin of {description}"))?;
         }
-        Ok(String::from_utf8(o.stdout)?)
+        Ok(stdout)
+    }
+
+    /// Like [`read()`], but parse stdout as JSON.  On failure to parse, the error
+    /// includes the path to the offending value and an excerpt of the output.
+    /// Output exceeding the limit is never parsed.
+    pub(crate) fn read_json<T: DeserializeOwned>(self) -> Result<T> {
+        let description = self.description.clone();
+        let argv = render_argv(&self.cmd);
+        let out = self.read()?;
+        parse_json_output(&out)
+            .with_context(|| format!("Parsing JSON output of {description} ({argv})"))
+    }
+
+    /// Like [`run()`], but asynchronous.  Dropping the returned future kills the child.
+    #[cfg(feature = "install")]
+    pub(crate) async fn run_async(self) -> Result<()> {
+        self.exec_async().await
+    }
+
+    #[cfg(feature = "install")]
+    async fn exec_async(mut self) -> Result<()> {
+        self.pre_run_output();
+        let description = self.description;
+        if self.timeout.is_some() {
+            self.cmd.process_group(0);
+        }
+        let mut cmd = tokio::process::Command::from(self.cmd);
+        cmd.kill_on_drop(true);
+        let mut output = None;
+        if self.quiet_output {
+            let tmpf = tempfile::tempfile()?;
+            cmd.stdout(Stdio::from(tmpf.try_clone()?));
+            cmd.stderr(Stdio::from(tmpf.try_clone()?));
+            output = Some(tmpf);
+        } else if !self.inherit_stderr {
+            cmd.stderr(Stdio::piped());
+        }
+        let mut trace = ExecTrace::new(cmd.as_std(), trace_commands());
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Spawning {description} failed"))?;
+        trace.spawned(child.id());
+        let stderr = child.stderr.take();
+        let read_stderr = async move {
+            match stderr {
+                Some(stderr) => tee_tail_async(stderr).await.map(Some),
+                None => Ok(None),
+            }
+        };
+        let timeout = self.timeout;
+        let wait = async {
+            let Some(timeout) = timeout else {
+                return Ok(child.wait().await?);
+            };
+            match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(st) => Ok(st?),
+                Err(_) => {
+                    if let Some(pid) = child.id() {
+                        // SAFETY: The pid of a spawned child is never zero
+                        let pid = rustix::process::Pid::from_raw(pid as i32).unwrap();
+                        kill_process_group(pid);
+                    }
+                    child.wait().await?;
+                    Err(anyhow::Error::from(TaskTimeoutError {
+                        description: description.clone(),
+                        timeout,
+                    }))
+                }
+            }
+        };
+        let (st, stderr_tail) = tokio::join!(wait, read_stderr);
+        trace.finish(&st);
+        let st = st.with_context(|| format!("Executing {description} failed"))?;
+        let stderr_tail = stderr_tail.context("Reading stderr")?;
+        if !st.success() {
+            let tail = if let Some(mut output) = output {
+                output.seek(std::io::SeekFrom::Start(0))?;
+                let mut stderr = std::io::stderr().lock();
+                std::io::copy(&mut output, &mut stderr)?;
+                output.seek(std::io::SeekFrom::Start(0))?;
+                Some(tee_tail(output)?)
+            } else {
+                stderr_tail
+            };
+            return Err(task_failure(&description, cmd.as_std(), st, tail.as_ref()));
+        }
+        Ok(())
     }
 
     pub(crate) fn new_and_run<'a>(
@@ -189,3 +436,675 @@ impl Task {
         t.run()
     }
 }
+
+/// If this environment variable is set, command execution is logged at
+/// info level instead of debug.
+const TRACE_COMMANDS_ENV: &str = "BOOTC_TRACE_COMMANDS";
+
+/// Whether command execution should be logged at info level.
+fn trace_commands() -> bool {
+    std::env::var_os(TRACE_COMMANDS_ENV).is_some()
+}
+
+/// Tracks a single command execution for logging: a span carrying the program
+/// and argv, and an event on completion with the pid, duration and exit status.
+/// Successful commands are logged at debug level (or info if `verbose`, see
+/// [`TRACE_COMMANDS_ENV`]), failures at warn.
+struct ExecTrace {
+    span: tracing::Span,
+    argv: String,
+    pid: Option<u32>,
+    start: Instant,
+    verbose: bool,
+}
+
+impl ExecTrace {
+    fn new(cmd: &Command, verbose: bool) -> Self {
+        let program = cmd.get_program().to_string_lossy();
+        let argv = render_argv(cmd);
+        let span = if verbose {
+            tracing::info_span!("exec", %program, %argv)
+        } else {
+            tracing::debug_span!("exec", %program, %argv)
+        };
+        Self {
+            span,
+            argv,
+            pid: None,
+            start: Instant::now(),
+            verbose,
+        }
+    }
+
+    fn spawned(&mut self, pid: Option<u32>) {
+        self.pid = pid;
+    }
+
+    fn finish(&self, r: &Result<ExitStatus>) {
+        let _g = self.span.enter();
+        let duration_ms = self.start.elapsed().as_millis() as u64;
+        let (argv, pid) = (&self.argv, self.pid);
+        match r {
+            Ok(st) if st.success() && self.verbose => {
+                tracing::info!(pid, duration_ms, status = %st, "exec: {argv}")
+            }
+            Ok(st) if st.success() => {
+                tracing::debug!(pid, duration_ms, status = %st, "exec: {argv}")
+            }
+            Ok(st) => tracing::warn!(pid, duration_ms, status = %st, "exec: {argv}"),
+            Err(e) => tracing::warn!(pid, duration_ms, status = %e, "exec: {argv}"),
+        }
+    }
+}
+
+/// Wait for a child process to exit.  If a timeout is provided and it expires,
+/// the process group of the child is killed and a [`TaskTimeoutError`] is returned.
+/// In all cases the child is reaped.
+fn wait_child(
+    child: &mut Child,
+    description: &str,
+    timeout: Option<Duration>,
+) -> Result<ExitStatus> {
+    let timeout = if let Some(timeout) = timeout {
+        timeout
+    } else {
+        return child.wait().map_err(Into::into);
+    };
+    let deadline = Instant::now() + timeout;
+    // Poll with an exponential backoff; this avoids busy waiting while still
+    // noticing fast exits promptly.
+    let mut interval = Duration::from_millis(1);
+    loop {
+        if let Some(st) = child.try_wait()? {
+            return Ok(st);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            kill_process_group(rustix::process::Pid::from_child(child));
+            // Reap the child so we don't leave a zombie
+            child.wait()?;
+            return Err(TaskTimeoutError {
+                description: description.to_owned(),
+                timeout,
+            }
+            .into());
+        }
+        std::thread::sleep(interval.min(deadline - now));
+        interval = (interval * 2).min(Duration::from_millis(100));
+    }
+}
+
+/// Forcibly kill a process group after a timeout.
+fn kill_process_group(pid: rustix::process::Pid) {
+    tracing::debug!("Killing process group {pid:?} after timeout");
+    if let Err(e) = rustix::process::kill_process_group(pid, rustix::process::Signal::Kill) {
+        tracing::warn!("Failed to kill process group {pid:?}: {e}");
+    }
+}
+
+/// The trailing portion of a stream (usually stderr), bounded to
+/// [`OUTPUT_TAIL_MAX`] bytes.
+#[derive(Debug, Default)]
+struct OutputTail {
+    buf: Vec<u8>,
+    /// Number of bytes dropped from the front of the stream
+    omitted: u64,
+}
+
+/// Maximum number of trailing bytes of output retained for error messages.
+const OUTPUT_TAIL_MAX: usize = 8192;
+
+impl OutputTail {
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        if let Some(excess) = self.buf.len().checked_sub(OUTPUT_TAIL_MAX) {
+            self.buf.drain(..excess);
+            self.omitted += excess as u64;
+        }
+    }
+}
+
+impl std::fmt::Display for OutputTail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.omitted > 0 {
+            writeln!(f, "... {} bytes omitted", self.omitted)?;
+        }
+        f.write_str(String::from_utf8_lossy(&self.buf).trim_end())
+    }
+}
+
+/// Read the provided stream to the end, or return `None` (without consuming
+/// the rest) if it is larger than `limit` bytes.
+fn read_bounded(src: impl Read, limit: u64) -> std::io::Result<Option<Vec<u8>>> {
+    let mut buf = Vec::new();
+    src.take(limit.saturating_add(1)).read_to_end(&mut buf)?;
+    Ok((buf.len() as u64 <= limit).then_some(buf))
+}
+
+/// Copy the provided stream to our stderr, retaining its tail.
+fn tee_tail(mut src: impl Read) -> std::io::Result<OutputTail> {
+    let mut tail = OutputTail::default();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let data = &buf[..n];
+        // Failing to forward output shouldn't fail the task
+        let _ = std::io::stderr().write_all(data);
+        tail.push(data);
+    }
+    Ok(tail)
+}
+
+/// Asynchronous version of [`tee_tail`].
+#[cfg(feature = "install")]
+async fn tee_tail_async(mut src: impl AsyncRead + Unpin) -> std::io::Result<OutputTail> {
+    let mut tail = OutputTail::default();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = src.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let data = &buf[..n];
+        let _ = std::io::stderr().write_all(data);
+        tail.push(data);
+    }
+    Ok(tail)
+}
+
+fn join_tee(
+    h: std::thread::ScopedJoinHandle<'_, std::io::Result<OutputTail>>,
+) -> Result<OutputTail> {
+    let tail = h
+        .join()
+        .map_err(|e| anyhow::anyhow!("Failed to join stderr thread: {e:?}"))?
+        .context("Reading stderr")?;
+    Ok(tail)
+}
+
+/// Render a command and its arguments for display to a human, as a
+/// string which can be copied and pasted into a shell.  Sensitive values
+/// are replaced; see [`render_args`].
+fn render_argv(cmd: &Command) -> String {
+    render_args(std::iter::once(cmd.get_program()).chain(cmd.get_args()))
+}
+
+/// Flags whose value (either the following argument, or after `=`) is redacted.
+const REDACTED_FLAGS: &[&str] = &["--creds", "--password", "--passphrase"];
+/// Replacement text for redacted values.
+const REDACTED: &str = "****";
+
+/// Shell-quote and join the provided arguments, replacing the values
+/// of [`REDACTED_FLAGS`] as well as `creds=` and `password=` options.
+fn render_args<'a>(args: impl IntoIterator<Item = &'a OsStr>) -> String {
+    static REDACT_OPTS: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"\b(creds|password)=[^,\s]*").unwrap());
+    let mut redact_next = false;
+    let mut r = Vec::new();
+    for arg in args {
+        if std::mem::take(&mut redact_next) {
+            r.push(shell_quote(REDACTED));
+            continue;
+        }
+        let Some(arg) = arg.to_str() else {
+            r.push(shell_quote_bytes(arg.as_bytes()));
+            continue;
+        };
+        if REDACTED_FLAGS.contains(&arg) {
+            redact_next = true;
+            r.push(arg.to_owned());
+            continue;
+        }
+        let arg = match REDACTED_FLAGS
+            .iter()
+            .find(|f| arg.strip_prefix(**f).is_some_and(|v| v.starts_with('=')))
+        {
+            Some(flag) => format!("{flag}={REDACTED}"),
+            None => REDACT_OPTS
+                .replace_all(arg, format!("${{1}}={REDACTED}"))
+                .into_owned(),
+        };
+        r.push(shell_quote(&arg));
+    }
+    r.join(" ")
+}
+
+/// Quote a string for a POSIX shell, if necessary.
+fn shell_quote(s: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        return s.to_owned();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Quote arbitrary (non-UTF-8) bytes using bash's `$'...'` syntax.
+fn shell_quote_bytes(b: &[u8]) -> String {
+    let mut r = String::from("$'");
+    for &c in b {
+        match c {
+            b'\'' | b'\\' => {
+                r.push('\\');
+                r.push(c as char);
+            }
+            0x20..=0x7e => r.push(c as char),
+            _ => r.push_str(&format!("\\x{c:02x}")),
+        }
+    }
+    r.push('\'');
+    r
+}
+
+/// How many characters of context to show on either side of a JSON parse error.
+const JSON_EXCERPT_CONTEXT: usize = 40;
+
+/// Parse JSON, reporting the path to the value that failed (e.g. `blockdevices[2].name`)
+/// along with a snippet of the input around the error location.
+pub(crate) fn parse_json_output<T: DeserializeOwned>(out: &str) -> Result<T> {
+    let de = &mut serde_json::Deserializer::from_str(out);
+    serde_path_to_error::deserialize(de).map_err(|e| {
+        let path = e.path().to_string();
+        let e = e.into_inner();
+        let excerpt = json_excerpt(out, e.line(), e.column());
+        anyhow::anyhow!("at {path}: {e}\nnear: {excerpt}")
+    })
+}
+
+/// Return the text around the given (1-based) line and column, truncated
+/// to [`JSON_EXCERPT_CONTEXT`] characters on either side.
+fn json_excerpt(out: &str, line: usize, column: usize) -> String {
+    let Some(line) = out.lines().nth(line.saturating_sub(1)) else {
+        return String::new();
+    };
+    let chars = line.chars().collect::<Vec<_>>();
+    let column = column.saturating_sub(1).min(chars.len());
+    let start = column.saturating_sub(JSON_EXCERPT_CONTEXT);
+    let end = (column + JSON_EXCERPT_CONTEXT).min(chars.len());
+    let mut r = String::new();
+    if start > 0 {
+        r.push_str("...");
+    }
+    r.extend(&chars[start..end]);
+    if end < chars.len() {
+        r.push_str("...");
+    }
+    r.trim().to_owned()
+}
+
+/// Build the error for a task which exited unsuccessfully, including the full
+/// command line and the tail of its stderr (if any).
+fn task_failure(
+    description: &str,
+    cmd: &Command,
+    st: ExitStatus,
+    stderr: Option<&OutputTail>,
+) -> anyhow::Error {
+    let argv = render_argv(cmd);
+    let mut msg = format!("Task {description} failed: {st}\ncommand: {argv}");
+    if let Some(stderr) = stderr.filter(|t| !t.buf.is_empty()) {
+        msg.push_str(&format!("\nstderr:\n{stderr}"));
+    }
+    anyhow::anyhow!(msg)
+}
+
+#[test]
+fn test_task_timeout() -> Result<()> {
+    let start = Instant::now();
+    // The backgrounded sleep verifies we kill the whole process group
+    let e = Task::new_quiet("sh")
+        .args(["-c", "sleep 30 & sleep 30"])
+        .timeout(Duration::from_millis(200))
+        .run()
+        .unwrap_err();
+    let e = e.downcast_ref::<TaskTimeoutError>().unwrap();
+    assert_eq!(e.timeout, Duration::from_millis(200));
+    assert_eq!(e.to_string(), "Task sh timed out after 200ms");
+
+    let e = Task::new_quiet("sh")
+        .args(["-c", "echo foo; sleep 30 & sleep 30"])
+        .timeout(Duration::from_millis(200))
+        .read()
+        .unwrap_err();
+    assert!(e.downcast_ref::<TaskTimeoutError>().is_some());
+    assert!(start.elapsed() < Duration::from_secs(20));
+    Ok(())
+}
+
+#[test]
+fn test_task_no_timeout() -> Result<()> {
+    let timeout = Duration::from_secs(60);
+    Task::new_quiet("true").timeout(timeout).run()?;
+    let out = Task::new_quiet("echo")
+        .arg("hello")
+        .timeout(timeout)
+        .read()?;
+    assert_eq!(out, "hello\n");
+    let e = Task::new_quiet("false").timeout(timeout).run().unwrap_err();
+    assert!(e.downcast_ref::<TaskTimeoutError>().is_none());
+    Ok(())
+}
+
+#[test]
+fn test_task_failure_stderr() -> Result<()> {
+    let script = "echo out; echo oops this broke >&2; exit 3";
+    let e = Task::new_quiet("sh")
+        .args(["-c", script])
+        .run()
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        e,
+        format!(
+            "Task sh failed: exit status: 3\ncommand: sh -c '{script}'\nstderr:\noops this broke"
+        )
+    );
+    let e = Task::new_quiet("sh")
+        .args(["-c", script])
+        .read()
+        .unwrap_err()
+        .to_string();
+    assert!(e.ends_with("\nstderr:\noops this broke"), "{e}");
+    // With quiet_output, stdout and stderr are merged
+    let e = Task::new_quiet("sh")
+        .args(["-c", script])
+        .quiet_output()
+        .run()
+        .unwrap_err()
+        .to_string();
+    assert!(e.ends_with("\nstderr:\nout\noops this broke"), "{e}");
+    // No stderr at all
+    let e = Task::new_quiet("false").read().unwrap_err().to_string();
+    assert_eq!(e, "Task false failed: exit status: 1\ncommand: false");
+    Ok(())
+}
+
+#[test]
+fn test_task_inherit_stderr() -> Result<()> {
+    let ours = std::fs::read_link("/proc/self/fd/2")?;
+    let child_stderr = |inherit_stderr| -> Result<_> {
+        let mut t = Task::new_quiet("readlink").arg("/proc/self/fd/2");
+        t.inherit_stderr = inherit_stderr;
+        Ok(std::path::PathBuf::from(t.read()?.trim_end()))
+    };
+    assert_eq!(child_stderr(true)?, ours);
+    assert_ne!(child_stderr(false)?, ours);
+    // With nothing captured, the error has no stderr excerpt
+    let mut t = Task::new_quiet("sh").args(["-c", "echo oops >&2; exit 3"]);
+    t.inherit_stderr = true;
+    let e = t.run().unwrap_err().to_string();
+    assert_eq!(
+        e,
+        "Task sh failed: exit status: 3\ncommand: sh -c 'echo oops >&2; exit 3'"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_task_failure_stderr_truncated() -> Result<()> {
+    let e = Task::new_quiet("sh")
+        .args([
+            "-c",
+            "head -c 10000 /dev/zero | tr '\\0' x >&2; echo >&2; echo last >&2; exit 1",
+        ])
+        .read()
+        .unwrap_err()
+        .to_string();
+    let (_, stderr) = e.split_once("\nstderr:\n").unwrap();
+    // 10000 + newline + "last\n", minus what we retain
+    assert!(
+        stderr.starts_with("... 1814 bytes omitted\nxxx"),
+        "{stderr}"
+    );
+    assert!(stderr.ends_with("x\nlast"));
+    Ok(())
+}
+
+#[test]
+fn test_task_async() -> Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        Task::new_quiet("true").run_async().await?;
+
+        // Errors should be formatted identically to the sync versions
+        let script = "echo out; echo oops this broke >&2; exit 3";
+        let sync_err = Task::new_quiet("sh")
+            .args(["-c", script])
+            .run()
+            .unwrap_err()
+            .to_string();
+        let e = Task::new_quiet("sh")
+            .args(["-c", script])
+            .run_async()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, sync_err);
+        let e = Task::new_quiet("sh")
+            .args(["-c", script])
+            .quiet_output()
+            .run_async()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(e.ends_with("\nstderr:\nout\noops this broke"), "{e}");
+
+        let e = Task::new_quiet("sh")
+            .args(["-c", "sleep 30 & sleep 30"])
+            .timeout(Duration::from_millis(200))
+            .run_async()
+            .await
+            .unwrap_err();
+        assert!(e.downcast_ref::<TaskTimeoutError>().is_some());
+
+        // Dropping the future should kill the child
+        let td = tempfile::tempdir()?;
+        let pidfile = td.path().join("pid");
+        let script = format!("echo $$ > {}; exec sleep 30", pidfile.display());
+        let r = tokio::time::timeout(
+            Duration::from_millis(500),
+            Task::new_quiet("sh").args(["-c", &script]).run_async(),
+        )
+        .await;
+        assert!(r.is_err());
+        let pid = std::fs::read_to_string(&pidfile)?;
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            // The process is gone, or at least a zombie
+            match std::fs::read_to_string(&stat) {
+                Ok(s) if !s.contains(") Z ") => {}
+                _ => break,
+            }
+            assert!(Instant::now() < deadline, "child was not killed");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        anyhow::Ok(())
+    })
+}
+
+#[test]
+fn test_read_json() -> Result<()> {
+    #[derive(serde::Deserialize, Debug)]
+    struct Entry {
+        #[allow(dead_code)]
+        name: String,
+    }
+    let v: Vec<Entry> = Task::new_quiet("echo")
+        .arg(r#"[{"name": "foo"}]"#)
+        .read_json()?;
+    assert_eq!(v.len(), 1);
+
+    let e = Task::new("Listing things", "echo")
+        .quiet()
+        .arg(r#"[{"name": "foo"}, {"name": 42}]"#)
+        .read_json::<Vec<Entry>>()
+        .unwrap_err();
+    let e = format!("{e:#}");
+    assert!(
+        e.starts_with(r#"Parsing JSON output of Listing things (echo '[{"name": "foo"}, {"name": 42}]'): at [1].name: invalid type: integer `42`"#),
+        "{e}"
+    );
+    assert!(
+        e.ends_with(r#"near: [{"name": "foo"}, {"name": 42}]"#),
+        "{e}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_json_excerpt() {
+    let long = format!("{}ERR{}", "a".repeat(100), "b".repeat(100));
+    let input = format!("first\n{long}\nlast");
+    let e = json_excerpt(&input, 2, 101);
+    assert_eq!(e, format!("...{}ERR{}...", "a".repeat(40), "b".repeat(37)));
+    assert_eq!(json_excerpt(&input, 1, 1), "first");
+    assert_eq!(json_excerpt(&input, 10, 1), "");
+}
+
+#[test]
+fn test_exec_trace() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    type Fields = Vec<(String, String)>;
+    /// Records the level and fields of each event
+    #[derive(Default, Clone)]
+    struct Capture(Arc<Mutex<Vec<(tracing::Level, Fields)>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Capture {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Visitor<'a>(&'a mut Fields);
+            impl tracing::field::Visit for Visitor<'_> {
+                fn record_debug(&mut self, f: &tracing::field::Field, v: &dyn std::fmt::Debug) {
+                    self.0.push((f.name().to_owned(), format!("{v:?}")));
+                }
+            }
+            let mut fields = Vec::new();
+            event.record(&mut Visitor(&mut fields));
+            self.0
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), fields));
+        }
+    }
+
+    let capture = Capture::default();
+    let subscriber = tracing_subscriber::registry().with(capture.clone());
+    tracing::subscriber::with_default(subscriber, || {
+        Task::new_quiet("echo").arg("hello world").read().unwrap();
+        Task::new_quiet("false").run().unwrap_err();
+        let trace = ExecTrace::new(&Command::new("true"), true);
+        trace.finish(&Ok(std::os::unix::process::ExitStatusExt::from_raw(0)));
+    });
+    let events = capture.0.lock().unwrap();
+    let [(l1, f1), (l2, f2), (l3, _)] = &events[..] else {
+        panic!("Unexpected events: {}", events.len());
+    };
+    assert_eq!(*l1, tracing::Level::DEBUG);
+    assert_eq!(*l2, tracing::Level::WARN);
+    assert_eq!(*l3, tracing::Level::INFO);
+    let names = |f: &Fields| f.iter().map(|v| v.0.clone()).collect::<Vec<_>>();
+    assert_eq!(
+        names(f1),
+        ["message", "pid", "duration_ms", "status"].map(ToOwned::to_owned)
+    );
+    assert_eq!(f1[0].1, "exec: echo 'hello world'");
+    assert_eq!(f1[3].1, "exit status: 0");
+    assert_eq!(f2[3].1, "exit status: 1");
+    Ok(())
+}
+
+#[test]
+fn test_render_args() {
+    let render = |args: &[&str]| render_args(args.iter().map(OsStr::new));
+    assert_eq!(render(&["ls", "-l", "/usr/lib"]), "ls -l /usr/lib");
+    assert_eq!(render(&["echo", "a b", ""]), "echo 'a b' ''");
+    assert_eq!(render(&["echo", "it's"]), r"echo 'it'\''s'");
+    assert_eq!(render(&["echo", "$HOME", "a;b"]), "echo '$HOME' 'a;b'");
+    let nonutf8 = OsStr::from_bytes(b"a\xff'b");
+    assert_eq!(
+        render_args([OsStr::new("touch"), nonutf8]),
+        r"touch $'a\xff\'b'"
+    );
+    // Redaction
+    assert_eq!(
+        render(&["skopeo", "copy", "--creds", "user:pass", "src", "dest"]),
+        "skopeo copy --creds '****' src dest"
+    );
+    assert_eq!(
+        render(&["skopeo", "--creds=user:pass", "--password", "a b"]),
+        "skopeo '--creds=****' --password '****'"
+    );
+    assert_eq!(
+        render(&["mount", "-o", "user=foo,password=secret,ro", "creds=x y"]),
+        "mount -o 'user=foo,password=****,ro' 'creds=**** y'"
+    );
+}
+
+#[test]
+fn test_task_stdin() -> Result<()> {
+    // Large enough to exceed the pipe buffer
+    const LEN: usize = 8 * 1024 * 1024;
+    let input = vec![b'x'; LEN];
+    let check_len = format!("test $(wc -c) = {LEN}");
+    Task::new_quiet("sh")
+        .args(["-c", &check_len])
+        .run_with_stdin_buf(Some(&input))?;
+
+    // The child exits successfully without reading its input
+    let e = Task::new_quiet("true")
+        .run_with_stdin_buf(Some(&input))
+        .unwrap_err();
+    assert_eq!(
+        format!("{e:#}"),
+        "Writing stdin of true: Broken pipe (os error 32)"
+    );
+    // If the child fails, that error takes precedence
+    let e = Task::new_quiet("false")
+        .run_with_stdin_buf(Some(&input))
+        .unwrap_err();
+    assert!(e
+        .to_string()
+        .starts_with("Task false failed: exit status: 1"));
+
+    let out = Task::new_quiet("wc")
+        .arg("-c")
+        .read_with_stdin_buf(&input)?;
+    assert_eq!(out.trim(), LEN.to_string());
+    Ok(())
+}
+
+#[test]
+fn test_task_output_limit() -> Result<()> {
+    // `yes` never exits on its own; the timeout ensures the test terminates
+    // even if closing the pipe doesn't stop it.
+    let unbounded = || Task::new_quiet("yes").timeout(Duration::from_secs(60));
+    let check = |e: anyhow::Error| {
+        let e = e.downcast::<OutputLimitError>().unwrap();
+        assert_eq!(e.limit, OUTPUT_LIMIT);
+        assert_eq!(
+            e.to_string(),
+            "Output of task yes exceeded limit of 4194304 bytes"
+        );
+    };
+    check(unbounded().read().unwrap_err());
+    check(unbounded().read_json::<serde_json::Value>().unwrap_err());
+
+    // Output of exactly the limit is fine
+    let out = Task::new_quiet("head")
+        .args(["-c", &OUTPUT_LIMIT.to_string(), "/dev/zero"])
+        .read()?;
+    assert_eq!(out.len() as u64, OUTPUT_LIMIT);
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! Helpers for unit tests which need privileges or filesystem features that
+//! the environment running `cargo test` may not provide.
+//!
+//! Rather than silently passing, such tests are skipped with a message on
+//! stderr (which, unlike `eprintln!`, isn't captured by the test harness).
+
+use std::io::Write;
+
+use anyhow::Result;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_tempfile::{self, TempDir};
+use rustix::thread::CapabilityFlags;
+
+/// The name of the xattr used to probe for support.
+const PROBE_XATTR: &str = "user.bootc.probe";
+
+/// Skip the current test: print `reason` and return.
+macro_rules! skip_test {
+    ($($arg:tt)*) => {{
+        $crate::testutils::report_skip(&format!($($arg)*));
+        return $crate::testutils::Skipped::skipped();
+    }};
+}
+pub(crate) use skip_test;
+
+/// Skip the current test unless running as root.
+macro_rules! require_root {
+    () => {
+        if !rustix::process::geteuid().is_root() {
+            $crate::testutils::skip_test!("requires root");
+        }
+    };
+}
+pub(crate) use require_root;
+
+/// Skip the current test unless all of the given capabilities are effective.
+macro_rules! require_capability {
+    ($caps:expr) => {
+        if let Some(reason) = $crate::testutils::missing_capabilities($caps) {
+            $crate::testutils::skip_test!("{reason}");
+        }
+    };
+}
+pub(crate) use require_capability;
+
+/// The return value of a skipped test.
+pub(crate) trait Skipped {
+    fn skipped() -> Self;
+}
+
+impl Skipped for () {
+    fn skipped() -> Self {}
+}
+
+impl<E> Skipped for std::result::Result<(), E> {
+    fn skipped() -> Self {
+        Ok(())
+    }
+}
+
+pub(crate) fn report_skip(reason: &str) {
+    // The test harness names the thread after the test.
+    let thread = std::thread::current();
+    let name = thread.name().unwrap_or("<unknown>");
+    let _ = writeln!(std::io::stderr(), "SKIPPED {name}: {reason}");
+}
+
+/// Describe which of `wanted` are not in `effective`, if any.
+fn describe_missing(effective: CapabilityFlags, wanted: CapabilityFlags) -> Option<String> {
+    let missing = wanted.difference(effective);
+    if missing.is_empty() {
+        return None;
+    }
+    let names = missing
+        .iter_names()
+        .map(|(name, _)| format!("CAP_{name}"))
+        .collect::<Vec<_>>();
+    Some(format!("requires {}", names.join(", ")))
+}
+
+/// Returns a reason to skip if any of `wanted` isn't in the effective
+/// capability set of this thread.
+pub(crate) fn missing_capabilities(wanted: CapabilityFlags) -> Option<String> {
+    match rustix::thread::capabilities(None) {
+        Ok(caps) => describe_missing(caps.effective, wanted),
+        Err(e) => Some(format!("failed to query capabilities: {e}")),
+    }
+}
+
+/// Whether the filesystem of `d` supports setting `user.` xattrs.
+pub(crate) fn xattrs_supported(d: &cap_std::fs::Dir) -> Result<bool> {
+    let f = cap_tempfile::TempFile::new(d)?;
+    let r = rustix::fs::fsetxattr(
+        f.as_file(),
+        PROBE_XATTR,
+        b"1",
+        rustix::fs::XattrFlags::empty(),
+    );
+    match r {
+        Ok(()) => Ok(true),
+        Err(rustix::io::Errno::NOTSUP | rustix::io::Errno::PERM) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Create a temporary directory which supports `user.` xattrs, trying the
+/// default location first and then `/var/tmp`, which unlike `/tmp` is rarely a
+/// tmpfs.  Returns `None` if neither does.
+pub(crate) fn tempdir_with_xattrs() -> Result<Option<TempDir>> {
+    let authority = cap_std::ambient_authority();
+    let td = TempDir::new(authority)?;
+    if xattrs_supported(&td)? {
+        return Ok(Some(td));
+    }
+    let vartmp = cap_std::fs::Dir::open_ambient_dir("/var/tmp", authority)?;
+    let td = TempDir::new_in(&vartmp)?;
+    Ok(xattrs_supported(&td)?.then_some(td))
+}
+
+#[test]
+fn test_describe_missing() {
+    let caps = CapabilityFlags::CHOWN | CapabilityFlags::SETUID;
+    assert_eq!(describe_missing(caps, CapabilityFlags::CHOWN), None);
+    assert_eq!(describe_missing(caps, CapabilityFlags::empty()), None);
+    assert_eq!(
+        describe_missing(caps, CapabilityFlags::SETUID | CapabilityFlags::SYS_CHROOT).as_deref(),
+        Some("requires CAP_SYS_CHROOT")
+    );
+    assert_eq!(
+        describe_missing(
+            CapabilityFlags::empty(),
+            CapabilityFlags::SETUID | CapabilityFlags::SYS_CHROOT
+        )
+        .as_deref(),
+        Some("requires CAP_SETUID, CAP_SYS_CHROOT")
+    );
+}
+
+#[test]
+fn test_missing_capabilities() {
+    // We can always query our own capabilities, and the empty set is never missing
+    assert_eq!(missing_capabilities(CapabilityFlags::empty()), None);
+    let caps = rustix::thread::capabilities(None).unwrap();
+    assert_eq!(missing_capabilities(caps.effective), None);
+}
+
+#[test]
+fn test_xattr_probe() -> Result<()> {
+    let Some(td) = tempdir_with_xattrs()? else {
+        skip_test!("no filesystem with xattr support");
+    };
+    assert!(xattrs_supported(&td)?);
+    // The probe doesn't leave anything behind
+    assert_eq!(td.entries()?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_skipped() -> Result<()> {
+    fn skip_unit() {
+        skip_test!("unit");
+    }
+    fn skip_result() -> Result<()> {
+        skip_test!("result");
+    }
+    skip_unit();
+    skip_result()?;
+
+    fn root_only(ran: &mut bool) {
+        crate::testutils::require_root!();
+        *ran = true;
+    }
+    let mut ran = false;
+    root_only(&mut ran);
+    assert_eq!(ran, rustix::process::geteuid().is_root());
+
+    // No capabilities are always present
+    fn no_caps(ran: &mut bool) {
+        crate::testutils::require_capability!(CapabilityFlags::empty());
+        *ran = true;
+    }
+    let mut ran = false;
+    no_caps(&mut ran);
+    assert!(ran);
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! # Caching the result of `bootc upgrade --check`
+//!
+//! The result of the last check is written to `/run` so that `bootc status`
+//! can show whether an update is available without fetching anything.
+
+use std::io::Write;
+
+use anyhow::Result;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::glib;
+use ostree_ext::oci_spec::image::{Descriptor, ImageManifest};
+
+use crate::spec::{AvailableUpdate, ImageReference, Layer, LayerDiff, UpdateCheck};
+
+/// The path to the cached result, relative to the root.
+const CACHE_PATH: &str = "run/bootc/update-check.json";
+
+/// The exit code of `bootc upgrade --check` if an update is available.
+pub(crate) const EXIT_UPDATE_AVAILABLE: i32 = 77;
+
+/// The exit code of `bootc upgrade --check` for the given result.
+pub(crate) fn exit_code(check: &UpdateCheck) -> i32 {
+    if check.available.is_some() {
+        EXIT_UPDATE_AVAILABLE
+    } else {
+        0
+    }
+}
+
+/// Cache the result of a check.
+#[context("Writing {CACHE_PATH}")]
+pub(crate) fn write(root: &Dir, check: &UpdateCheck) -> Result<()> {
+    if let Some(parent) = std::path::Path::new(CACHE_PATH).parent() {
+        root.create_dir_all(parent)?;
+    }
+    root.atomic_write(CACHE_PATH, serde_json::to_vec(check)?)?;
+    Ok(())
+}
+
+/// Load the cached result of the last check of `imgref`.  The result is
+/// ignored if a different image was checked, or if the available update has
+/// since been deployed (i.e. its digest is in `deployed`).
+#[context("Reading {CACHE_PATH}")]
+pub(crate) fn load(
+    root: &Dir,
+    imgref: &ImageReference,
+    deployed: &[&str],
+) -> Result<Option<UpdateCheck>> {
+    let Some(f) = root.open_optional(CACHE_PATH)? else {
+        return Ok(None);
+    };
+    // This is just a cache; if it can't be parsed (e.g. was written by a
+    // different version) ignore it.
+    let check = match serde_json::from_reader::<_, UpdateCheck>(std::io::BufReader::new(f)) {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::debug!("Ignoring invalid {CACHE_PATH}: {e}");
+            return Ok(None);
+        }
+    };
+    if &check.image != imgref {
+        tracing::debug!("Ignoring {CACHE_PATH} for {}", check.image);
+        return Ok(None);
+    }
+    let is_deployed = check
+        .available
+        .as_ref()
+        .is_some_and(|a| deployed.contains(&a.image_digest.as_str()));
+    Ok((!is_deployed).then_some(check))
+}
+
+impl From<&Descriptor> for Layer {
+    fn from(d: &Descriptor) -> Self {
+        Self {
+            digest: d.digest().to_string(),
+            size: d.size().try_into().unwrap_or_default(),
+        }
+    }
+}
+
+/// Compare the layers of the booted image with those of an update.
+/// `is_stored` returns true if a layer of the update (by digest) is already
+/// present locally, and hence doesn't need to be downloaded.
+pub(crate) fn layer_diff(
+    booted: &ImageManifest,
+    update: &ImageManifest,
+    is_stored: impl Fn(&str) -> bool,
+) -> LayerDiff {
+    let booted_layers = booted.layers();
+    let update_layers = update.layers();
+    let changed = update_layers
+        .iter()
+        .zip(booted_layers)
+        .filter(|(u, b)| u.digest() != b.digest())
+        .map(|(u, _)| Layer::from(u))
+        .collect();
+    let added = update_layers
+        .iter()
+        .skip(booted_layers.len())
+        .map(Layer::from)
+        .collect();
+    let removed = booted_layers
+        .iter()
+        .skip(update_layers.len())
+        .map(Layer::from)
+        .collect();
+    let download_size = update_layers
+        .iter()
+        .filter(|l| !is_stored(l.digest()))
+        .map(|l| Layer::from(l).size)
+        .sum();
+    LayerDiff {
+        booted_version: None,
+        booted_timestamp: None,
+        total: update_layers.len() as u64,
+        changed,
+        added,
+        removed,
+        download_size,
+    }
+}
+
+/// Print a summary of an available update intended for humans.
+pub(crate) fn write_available(mut out: impl Write, update: &AvailableUpdate) -> Result<()> {
+    let layers = update.layers.as_ref();
+    let booted_version = layers.and_then(|l| l.booted_version.as_deref());
+    match (booted_version, update.version.as_deref()) {
+        (Some(booted), Some(version)) if booted != version => {
+            writeln!(out, "  Version: {booted} -> {version}")?
+        }
+        (_, Some(version)) => writeln!(out, "  Version: {version}")?,
+        (_, None) => {}
+    }
+    let booted_timestamp = layers.and_then(|l| l.booted_timestamp);
+    match (booted_timestamp, update.timestamp) {
+        (Some(booted), Some(ts)) => writeln!(
+            out,
+            "  Created: {} -> {}",
+            booted.to_rfc3339(),
+            ts.to_rfc3339()
+        )?,
+        (None, Some(ts)) => writeln!(out, "  Created: {}", ts.to_rfc3339())?,
+        (_, None) => {}
+    }
+    writeln!(out, "  Digest: {}", update.image_digest)?;
+    if let Some(layers) = layers {
+        writeln!(
+            out,
+            "  Layers: {} changed, {} added, {} removed (total: {})",
+            layers.changed.len(),
+            layers.added.len(),
+            layers.removed.len(),
+            layers.total
+        )?;
+        writeln!(
+            out,
+            "  Download size: {}",
+            glib::format_size(layers.download_size)
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_update_check_cache() -> Result<()> {
+    use crate::spec::AvailableUpdate;
+
+    let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+    let imgref = ImageReference {
+        image: "quay.io/example/os:latest".into(),
+        transport: "registry".into(),
+        signature: None,
+    };
+    let booted = "sha256:booted";
+    assert_eq!(load(&td, &imgref, &[booted])?, None);
+
+    let mut check = UpdateCheck {
+        image: imgref.clone(),
+        checked: "2024-05-02T10:11:12Z".parse()?,
+        available: None,
+    };
+    assert_eq!(exit_code(&check), 0);
+    write(&td, &check)?;
+    assert_eq!(load(&td, &imgref, &[booted])?.as_ref(), Some(&check));
+
+    check.available = Some(AvailableUpdate {
+        image_digest: "sha256:new".into(),
+        version: Some("42.1".into()),
+        timestamp: None,
+        layers: None,
+    });
+    assert_eq!(exit_code(&check), EXIT_UPDATE_AVAILABLE);
+    write(&td, &check)?;
+    assert_eq!(load(&td, &imgref, &[booted])?.as_ref(), Some(&check));
+    // Once the update is staged, it's no longer available
+    assert_eq!(load(&td, &imgref, &[booted, "sha256:new"])?, None);
+
+    // Switching to a different image invalidates the cache
+    let other = ImageReference {
+        image: "quay.io/example/other:latest".into(),
+        ..imgref.clone()
+    };
+    assert_eq!(load(&td, &other, &[booted])?, None);
+
+    // As does garbage
+    td.atomic_write(CACHE_PATH, "{")?;
+    assert_eq!(load(&td, &imgref, &[booted])?, None);
+    Ok(())
+}
+
+#[test]
+fn test_layer_diff() -> Result<()> {
+    let booted: ImageManifest =
+        serde_json::from_str(include_str!("fixtures/manifest-booted.json"))?;
+    let update: ImageManifest =
+        serde_json::from_str(include_str!("fixtures/manifest-update.json"))?;
+    let rebuilt: ImageManifest =
+        serde_json::from_str(include_str!("fixtures/manifest-rebuilt.json"))?;
+    let stored_in = |m: &ImageManifest| {
+        m.layers()
+            .iter()
+            .map(|l| l.digest().to_string())
+            .collect::<Vec<_>>()
+    };
+    let booted_stored = stored_in(&booted);
+    let is_stored = |d: &str| booted_stored.iter().any(|s| s == d);
+    let layer = |n: char, size| Layer {
+        digest: format!("sha256:{}", n.to_string().repeat(64)),
+        size,
+    };
+
+    // Identical
+    let diff = layer_diff(&booted, &booted, is_stored);
+    assert_eq!(diff.total, 3);
+    assert!(diff.changed.is_empty());
+    assert!(diff.added.is_empty());
+    assert!(diff.removed.is_empty());
+    assert_eq!(diff.download_size, 0);
+
+    // Partially shared
+    let diff = layer_diff(&booted, &update, is_stored);
+    assert_eq!(diff.total, 4);
+    assert_eq!(diff.changed, [layer('4', 4000)]);
+    assert_eq!(diff.added, [layer('5', 5000)]);
+    assert!(diff.removed.is_empty());
+    assert_eq!(diff.download_size, 9000);
+
+    // Entirely new
+    let diff = layer_diff(&booted, &rebuilt, is_stored);
+    assert_eq!(diff.total, 2);
+    assert_eq!(diff.changed, [layer('6', 6000), layer('7', 7000)]);
+    assert!(diff.added.is_empty());
+    assert_eq!(diff.removed, [layer('3', 3000)]);
+    assert_eq!(diff.download_size, 13000);
+
+    // Going back to the booted image, with the rebuilt layers stored too
+    let stored = [booted_stored.clone(), stored_in(&rebuilt)].concat();
+    let diff = layer_diff(&rebuilt, &booted, |d| stored.iter().any(|s| s == d));
+    assert_eq!(diff.changed.len(), 2);
+    assert_eq!(diff.added, [layer('3', 3000)]);
+    assert_eq!(diff.download_size, 0);
+
+    // The layers are only serialized if present
+    let mut update = AvailableUpdate {
+        image_digest: "sha256:new".into(),
+        version: None,
+        timestamp: None,
+        layers: None,
+    };
+    assert!(!serde_json::to_string(&update)?.contains("layers"));
+    update.layers = Some(diff);
+    let v = serde_json::to_value(&update)?;
+    assert_eq!(v["layers"]["added"][0]["size"], 3000);
+    assert_eq!(v["layers"]["downloadSize"], 0);
+    Ok(())
+}
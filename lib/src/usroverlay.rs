@@ -0,0 +1,289 @@
+//! # Persistent overlays on `/usr`
+//!
+//! `bootc usroverlay` is a pass-through to `ostree admin unlock`, which mounts
+//! a transient overlay on `/usr`.  With `--persist-until-update`, we instead
+//! keep the upper and work directories in `/var/lib/bootc/usroverlay`, along
+//! with the deployment they apply to.  On boot, the systemd generator mounts
+//! the overlay again if that deployment is booted, and otherwise discards it.
+//! Staging a new deployment discards it too, although the overlay stays
+//! mounted until the reboot.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+use rustix::fs::StatVfsMountFlags;
+
+use crate::spec::PersistentUsrOverlay;
+use crate::task::Task;
+
+/// The state of a persistent overlay, relative to `/var`
+const STATE_DIR: &str = "lib/bootc/usroverlay";
+/// Holds the path of the deployment the overlay applies to, relative to the sysroot
+const DEPLOYMENT: &str = "deployment";
+const MOUNT_UNIT: &str = "bootc-usroverlay.service";
+const DISCARD_UNIT: &str = "bootc-usroverlay-discard.service";
+
+/// What to do with a persistent overlay on boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BootAction {
+    /// There is no overlay
+    None,
+    /// Mount the overlay on `/usr`
+    Mount,
+    /// The overlay was created for another deployment, or discarded when staging
+    Discard,
+}
+
+/// Decide what to do with the overlay, given the deployment it was created
+/// for (if not discarded) and the booted deployment.
+pub(crate) fn boot_action(exists: bool, recorded: Option<&str>, booted: &str) -> BootAction {
+    match (exists, recorded) {
+        (false, _) => BootAction::None,
+        (true, Some(recorded)) if recorded == booted => BootAction::Mount,
+        (true, _) => BootAction::Discard,
+    }
+}
+
+/// Read the deployment the overlay in `state` applies to, if any.
+fn read_deployment(state: &Dir) -> Result<Option<String>> {
+    let Some(mut f) = state.open_optional(DEPLOYMENT)? else {
+        return Ok(None);
+    };
+    let mut buf = String::new();
+    f.read_to_string(&mut buf)?;
+    Ok(Some(buf.trim().to_owned()))
+}
+
+/// Write the units for `action` into the generator output.
+fn generate_units(unit_dir: &Dir, action: BootAction) -> Result<()> {
+    let state = Utf8Path::new("/var").join(STATE_DIR);
+    let (unit, contents) = match action {
+        BootAction::None => return Ok(()),
+        BootAction::Mount => (
+            MOUNT_UNIT,
+            format!(
+                "[Unit]\n\
+Description=Persistent bootc overlay on /usr\n\
+Documentation=man:bootc-usr-overlay(8)\n\
+DefaultDependencies=no\n\
+RequiresMountsFor={state}\n\
+Before=local-fs.target\n\
+\n\
+[Service]\n\
+Type=oneshot\n\
+RemainAfterExit=yes\n\
+ExecStart=mount -t overlay -o lowerdir=/usr,upperdir={state}/upper,workdir={state}/work overlay /usr\n"
+            ),
+        ),
+        BootAction::Discard => (
+            DISCARD_UNIT,
+            format!(
+                "[Unit]\n\
+Description=Discard persistent bootc overlay on /usr\n\
+Documentation=man:bootc-usr-overlay(8)\n\
+DefaultDependencies=no\n\
+RequiresMountsFor={state}\n\
+Before=local-fs.target\n\
+\n\
+[Service]\n\
+Type=oneshot\n\
+ExecStart=rm -rf --one-file-system {state}\n"
+            ),
+        ),
+    };
+    unit_dir.atomic_write(unit, contents)?;
+    let target = "local-fs.target.wants";
+    unit_dir.create_dir_all(target)?;
+    unit_dir.symlink(format!("../{unit}"), format!("{target}/{unit}"))?;
+    Ok(())
+}
+
+/// Called by the systemd generator to mount or discard a persistent overlay.
+#[context("Generating units for a persistent /usr overlay")]
+pub(crate) fn generator(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    let Some(booted) = crate::hosttype::booted_deployment_path(root)? else {
+        return Ok(());
+    };
+    // The deployment path is ostree/deploy/<stateroot>/deploy/<checksum>.<serial>;
+    // /var isn't mounted yet, so use it via the sysroot.
+    let Some(stateroot_dir) = booted.parent().and_then(|p| p.parent()) else {
+        return Ok(());
+    };
+    let var = Utf8Path::new("sysroot").join(stateroot_dir).join("var");
+    let state = root.open_dir_optional(var.join(STATE_DIR))?;
+    let recorded = state.as_ref().map(read_deployment).transpose()?.flatten();
+    let action = boot_action(state.is_some(), recorded.as_deref(), booted.as_str());
+    tracing::debug!("Persistent /usr overlay: {action:?}");
+    generate_units(unit_dir, action)
+}
+
+/// The path of a deployment, relative to the sysroot.
+fn deployment_path(sysroot: &ostree::Sysroot, deployment: &ostree::Deployment) -> String {
+    sysroot.deployment_dirpath(deployment).to_string()
+}
+
+/// The state of the persistent overlay in `root`, for `bootc status`.
+pub(crate) fn status(
+    root: &Dir,
+    sysroot: &ostree::Sysroot,
+    booted: &ostree::Deployment,
+) -> Result<Option<PersistentUsrOverlay>> {
+    let Some(state) = root.open_dir_optional(Utf8Path::new("var").join(STATE_DIR))? else {
+        return Ok(None);
+    };
+    let recorded = read_deployment(&state)?;
+    let booted = deployment_path(sysroot, booted);
+    let discard_pending = boot_action(true, recorded.as_deref(), &booted) != BootAction::Mount;
+    Ok(Some(PersistentUsrOverlay { discard_pending }))
+}
+
+/// Mark the persistent overlay in `root`, if any, to be discarded on the next
+/// boot; this is done when staging a deployment.
+#[context("Discarding persistent /usr overlay")]
+pub(crate) fn discard(root: &Dir) -> Result<()> {
+    let Some(state) = root.open_dir_optional(Utf8Path::new("var").join(STATE_DIR))? else {
+        return Ok(());
+    };
+    if state.remove_file_optional(DEPLOYMENT)? {
+        println!("The persistent overlay on /usr will be discarded on the next boot.");
+    }
+    Ok(())
+}
+
+/// Whether `/usr` is writable, e.g. because an overlay is mounted on it.
+fn usr_is_writable() -> Result<bool> {
+    let st = rustix::fs::statvfs("/usr").context("Querying /usr")?;
+    Ok(!st.f_flag.contains(StatVfsMountFlags::RDONLY))
+}
+
+/// Implementation of `bootc usroverlay --persist-until-update`.
+#[context("Adding a persistent overlay on /usr")]
+pub(crate) async fn persist() -> Result<()> {
+    crate::cli::require_root()?;
+    let sysroot = &crate::cli::get_locked_sysroot().await?;
+    let booted = sysroot.require_booted_deployment()?;
+    if sysroot.staged_deployment().is_some() {
+        anyhow::bail!("A deployment is staged; the overlay would be discarded on the next boot");
+    }
+    if usr_is_writable()? {
+        anyhow::bail!("/usr is already writable");
+    }
+    // The generator finds the state via the sysroot
+    let var = rustix::fs::stat("/var")?;
+    let sysroot_st = rustix::fs::stat("/sysroot")?;
+    if var.st_dev != sysroot_st.st_dev {
+        anyhow::bail!("A persistent overlay is not supported with a separate /var filesystem");
+    }
+
+    let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let state_path = Utf8Path::new("var").join(STATE_DIR);
+    root.remove_all_optional(&state_path)?;
+    root.create_dir_all(&state_path)?;
+    let state = root.open_dir(&state_path)?;
+    state.create_dir("upper")?;
+    state.create_dir("work")?;
+    let abs = Utf8Path::new("/").join(&state_path);
+    Task::new("Mounting overlay on /usr", "mount")
+        .args([
+            "-t",
+            "overlay",
+            "-o",
+            &format!("lowerdir=/usr,upperdir={abs}/upper,workdir={abs}/work"),
+            "overlay",
+            "/usr",
+        ])
+        .run()?;
+    // Only record the overlay once it is mounted
+    state.atomic_write(DEPLOYMENT, deployment_path(sysroot, &booted))?;
+    println!("A writable overlay is now mounted on /usr, and will be kept until the next update.");
+    println!("Use `bootc usroverlay reset` to remove it.");
+    Ok(())
+}
+
+/// Implementation of `bootc usroverlay reset`.
+#[context("Removing persistent overlay on /usr")]
+pub(crate) fn reset() -> Result<()> {
+    crate::cli::require_root()?;
+    let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let state_path = Utf8Path::new("var").join(STATE_DIR);
+    let Some(state) = root.open_dir_optional(&state_path)? else {
+        println!("No persistent overlay on /usr.");
+        return Ok(());
+    };
+    state.remove_file_optional(DEPLOYMENT)?;
+    if usr_is_writable()? {
+        // The mounted overlay still uses the directories
+        println!("The persistent overlay on /usr will be discarded on the next boot.");
+    } else {
+        root.remove_all_optional(&state_path)?;
+        println!("Removed persistent overlay on /usr.");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_boot_action() {
+    let booted = "ostree/deploy/default/deploy/abc.0";
+    assert_eq!(boot_action(false, None, booted), BootAction::None);
+    assert_eq!(boot_action(true, Some(booted), booted), BootAction::Mount);
+    // A different deployment was booted, e.g. after a rollback
+    assert_eq!(
+        boot_action(true, Some("ostree/deploy/default/deploy/def.0"), booted),
+        BootAction::Discard
+    );
+    // Discarded when staging
+    assert_eq!(boot_action(true, None, booted), BootAction::Discard);
+}
+
+#[test]
+fn test_generate_lifecycle() -> Result<()> {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+    let booted = "ostree/deploy/default/deploy/abc.0";
+    let generate = |booted: &str| -> Result<Vec<String>> {
+        td.remove_all_optional("units")?;
+        td.create_dir("units")?;
+        let units = td.open_dir("units")?;
+        let state = td.open_dir_optional(Utf8Path::new("var").join(STATE_DIR))?;
+        let recorded = state.as_ref().map(read_deployment).transpose()?.flatten();
+        generate_units(
+            &units,
+            boot_action(state.is_some(), recorded.as_deref(), booted),
+        )?;
+        let mut r = units
+            .entries()?
+            .map(|e| Ok(e?.file_name().into_string().unwrap()))
+            .collect::<Result<Vec<_>>>()?;
+        r.sort();
+        Ok(r)
+    };
+    assert!(generate(booted)?.is_empty());
+
+    // Created for the booted deployment
+    let state_path = Utf8Path::new("var").join(STATE_DIR);
+    td.create_dir_all(&state_path)?;
+    td.write(state_path.join(DEPLOYMENT), format!("{booted}\n"))?;
+    assert_eq!(generate(booted)?, [MOUNT_UNIT, "local-fs.target.wants"]);
+    let unit = td.read_to_string(format!("units/{MOUNT_UNIT}"))?;
+    assert!(unit.contains("upperdir=/var/lib/bootc/usroverlay/upper,"));
+    assert!(td.try_exists(format!("units/local-fs.target.wants/{MOUNT_UNIT}"))?);
+
+    // Another deployment is booted
+    let other = "ostree/deploy/default/deploy/def.0";
+    assert_eq!(generate(other)?, [DISCARD_UNIT, "local-fs.target.wants"]);
+
+    // A deployment was staged
+    discard(&td)?;
+    assert!(td.try_exists(&state_path)?);
+    assert_eq!(generate(booted)?, [DISCARD_UNIT, "local-fs.target.wants"]);
+    // Nothing to do once the state is removed
+    td.remove_all_optional(&state_path)?;
+    discard(&td)?;
+    assert!(generate(booted)?.is_empty());
+    Ok(())
+}
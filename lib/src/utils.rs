@@ -4,9 +4,13 @@ use std::process::Command;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
 use ostree::glib;
 use ostree_ext::container::SignatureSource;
 use ostree_ext::ostree;
+use ostree_ext::prelude::FileExt;
+use ostree_ext::sysroot::SysrootLock;
 
 /// Try to look for keys injected by e.g. rpm-ostree requesting machine-local
 /// changes; if any are present, return `true`.
@@ -21,6 +25,15 @@ pub(crate) fn origin_has_rpmostree_stuff(kf: &glib::KeyFile) -> bool {
     false
 }
 
+/// Open the physical root of `sysroot`.
+pub(crate) fn open_sysroot_dir(sysroot: &SysrootLock) -> Result<Dir> {
+    let sysroot_path = sysroot
+        .path()
+        .path()
+        .ok_or_else(|| anyhow::anyhow!("Sysroot has no path"))?;
+    Dir::open_ambient_dir(sysroot_path, cap_std::ambient_authority()).map_err(Into::into)
+}
+
 /// Given an mount option string list like foo,bar=baz,something=else,ro parse it and find
 /// the first entry like $optname=
 /// This will not match a bare `optname` without an equals.
@@ -119,6 +132,148 @@ where
     r
 }
 
+/// Parameters controlling how [`retry`] and [`retry_async`] handle failures.
+#[derive(Debug, Clone)]
+pub(crate) struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub(crate) max_attempts: u32,
+    /// Delay before the first retry
+    pub(crate) initial_delay: Duration,
+    /// Multiplier applied to the delay after each retry
+    pub(crate) factor: u32,
+    /// Upper bound on the delay between attempts
+    pub(crate) max_delay: Duration,
+    /// Randomly adjust each delay by up to this fraction of its value (0.0 disables)
+    pub(crate) jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_secs(1),
+            factor: 2,
+            max_delay: Duration::from_secs(30),
+            jitter: 0.1,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry` (starting at 1), without jitter.
+    fn base_delay(&self, retry: u32) -> Duration {
+        let factor = self.factor.saturating_pow(retry.saturating_sub(1));
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.base_delay(retry);
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        // We don't need a real RNG here; the randomly keyed std hasher suffices.
+        use std::hash::{BuildHasher, Hasher};
+        let r = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish() as f64
+            / u64::MAX as f64;
+        let jitter = self.jitter.min(1.0);
+        delay.mul_f64(1.0 - jitter + 2.0 * jitter * r)
+    }
+
+    /// Given a failed attempt, return how long to wait before the next one, or
+    /// `None` if the error should be returned.
+    fn next_delay(
+        &self,
+        attempt: u32,
+        e: &anyhow::Error,
+        is_retryable: impl Fn(&anyhow::Error) -> bool,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts || !is_retryable(e) {
+            return None;
+        }
+        let delay = self.delay(attempt);
+        tracing::warn!(
+            "Attempt {attempt}/{} failed, retrying in {delay:?}: {e:#}",
+            self.max_attempts
+        );
+        Some(delay)
+    }
+}
+
+/// Invoke `f` until it succeeds, returns an error for which `is_retryable` is false,
+/// or the maximum number of attempts in `policy` is reached.
+#[cfg(feature = "install")]
+pub(crate) fn retry<T>(
+    policy: &RetryPolicy,
+    is_retryable: impl Fn(&anyhow::Error) -> bool,
+    f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    retry_with_sleep(policy, is_retryable, std::thread::sleep, f)
+}
+
+#[cfg(feature = "install")]
+fn retry_with_sleep<T>(
+    policy: &RetryPolicy,
+    is_retryable: impl Fn(&anyhow::Error) -> bool,
+    mut sleep: impl FnMut(Duration),
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(e) => match policy.next_delay(attempt, &e, &is_retryable) {
+                Some(delay) => sleep(delay),
+                None => return Err(e),
+            },
+        }
+        attempt += 1;
+    }
+}
+
+/// Asynchronous version of [`retry`].
+pub(crate) async fn retry_async<T, F, Fut>(
+    policy: &RetryPolicy,
+    is_retryable: impl Fn(&anyhow::Error) -> bool,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(e) => match policy.next_delay(attempt, &e, &is_retryable) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(e),
+            },
+        }
+        attempt += 1;
+    }
+}
+
+/// Ask a yes/no question, returning true only for `y` or `yes`.
+pub(crate) fn prompt_yes_no(
+    prompt: &str,
+    mut input: impl std::io::BufRead,
+    mut out: impl Write,
+) -> Result<bool> {
+    write!(out, "{prompt} [y/N] ")?;
+    out.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
 /// Given a possibly tagged image like quay.io/foo/bar:latest and a digest 0ab32..., return
 /// the digested form quay.io/foo/bar:latest@sha256:0ab32...
 /// If the image already has a digest, it will be replaced.
@@ -172,3 +327,139 @@ fn test_sigpolicy_from_opts() {
         SignatureSource::ContainerPolicyAllowInsecure
     );
 }
+
+#[test]
+#[cfg(feature = "install")]
+fn test_retry() {
+    let policy = RetryPolicy {
+        max_attempts: 5,
+        initial_delay: Duration::from_millis(10),
+        factor: 3,
+        max_delay: Duration::from_millis(50),
+        jitter: 0.0,
+    };
+    let retryable = |e: &anyhow::Error| e.to_string() != "fatal";
+
+    // Succeeds on the third attempt
+    let mut delays = Vec::new();
+    let mut n = 0;
+    let r = retry_with_sleep(
+        &policy,
+        retryable,
+        |d| delays.push(d),
+        || {
+            n += 1;
+            if n < 3 {
+                anyhow::bail!("flaky")
+            }
+            Ok(n)
+        },
+    );
+    assert_eq!(r.unwrap(), 3);
+    assert_eq!(
+        delays,
+        [Duration::from_millis(10), Duration::from_millis(30)]
+    );
+
+    // Attempts are exhausted, and the delay is capped
+    let mut delays = Vec::new();
+    let mut n = 0;
+    let r: Result<()> = retry_with_sleep(
+        &policy,
+        retryable,
+        |d| delays.push(d),
+        || {
+            n += 1;
+            anyhow::bail!("flaky {n}")
+        },
+    );
+    assert_eq!(r.unwrap_err().to_string(), "flaky 5");
+    assert_eq!(delays, [10, 30, 50, 50].map(Duration::from_millis));
+
+    // Fatal errors are returned immediately
+    let mut n = 0;
+    let r: Result<()> = retry_with_sleep(
+        &policy,
+        retryable,
+        |_| unreachable!(),
+        || {
+            n += 1;
+            anyhow::bail!("fatal")
+        },
+    );
+    assert_eq!(r.unwrap_err().to_string(), "fatal");
+    assert_eq!(n, 1);
+}
+
+#[test]
+fn test_retry_jitter() {
+    let policy = RetryPolicy {
+        initial_delay: Duration::from_millis(100),
+        jitter: 0.5,
+        ..Default::default()
+    };
+    for _ in 0..100 {
+        let d = policy.delay(1);
+        assert!(d >= Duration::from_millis(50) && d <= Duration::from_millis(150));
+    }
+}
+
+#[test]
+fn test_retry_async() -> Result<()> {
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        initial_delay: Duration::from_millis(1),
+        jitter: 0.0,
+        ..Default::default()
+    };
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        let n = std::cell::Cell::new(0);
+        let r = retry_async(
+            &policy,
+            |_| true,
+            || async {
+                n.set(n.get() + 1);
+                if n.get() < 3 {
+                    anyhow::bail!("flaky")
+                }
+                Ok(n.get())
+            },
+        )
+        .await;
+        assert_eq!(r.unwrap(), 3);
+        n.set(0);
+        let r: Result<()> = retry_async(
+            &policy,
+            |_| false,
+            || async {
+                n.set(n.get() + 1);
+                anyhow::bail!("fatal")
+            },
+        )
+        .await;
+        assert!(r.is_err());
+        assert_eq!(n.get(), 1);
+    });
+    Ok(())
+}
+
+#[test]
+fn test_prompt_yes_no() {
+    for (input, expected) in [
+        ("y\n", true),
+        ("Yes\n", true),
+        (" yes ", true),
+        ("\n", false),
+        ("n\n", false),
+        ("yep\n", false),
+        ("", false),
+    ] {
+        let mut out = Vec::new();
+        let r = prompt_yes_no("Proceed?", input.as_bytes(), &mut out).unwrap();
+        assert_eq!(r, expected, "{input:?}");
+        assert_eq!(out, b"Proceed? [y/N] ");
+    }
+}
@@ -77,6 +77,102 @@ pub(crate) fn run_alongside(image: &str, mut testargs: libtest_mimic::Arguments)
             cmd!(sh, "sudo {BASE_ARGS...} -v {tmpdisk}:/disk {image} bootc install to-disk --via-loopback {generic_inst_args...} /disk").run()?;
             Ok(())
         }),
+        Trial::test("loopback install with a partition config", move || {
+            let sh = &xshell::Shell::new()?;
+            reset_root(sh)?;
+            let size = 10 * 1000 * 1000 * 1000;
+            let mut tmpdisk = tempfile::NamedTempFile::new_in("/var/tmp")?;
+            tmpdisk.as_file_mut().set_len(size)?;
+            let tmpdisk = tmpdisk.into_temp_path();
+            let tmpdisk = tmpdisk.to_str().unwrap();
+            let tmpd = &sh.create_temp_dir()?;
+            let config = tmpd.path().join("partitions.toml");
+            let partitions = [
+                ("EFI-SYSTEM", "1G", "vfat", "/boot/efi"),
+                ("root", "6G", "xfs", "/"),
+                ("var", "grow", "xfs", "/var"),
+            ];
+            let buf = partitions
+                .iter()
+                .map(|(label, size, fs, mountpoint)| {
+                    format!("[[partition]]\nlabel = \"{label}\"\nsize = \"{size}\"\nfilesystem = \"{fs}\"\nmountpoint = \"{mountpoint}\"\n")
+                })
+                .collect::<String>();
+            std::fs::write(&config, buf)?;
+            let config = config.to_str().unwrap();
+            cmd!(sh, "sudo {BASE_ARGS...} -v {tmpdisk}:/disk -v {config}:/partitions.toml {image} bootc install to-disk --via-loopback {generic_inst_args...} --partition-config /partitions.toml /disk").run()?;
+            let table = cmd!(sh, "sudo sgdisk -p {tmpdisk}").read()?;
+            for (label, ..) in partitions {
+                assert!(table.contains(label), "Missing partition {label}:\n{table}");
+            }
+            Ok(())
+        }),
+        // This requires the md driver on the host
+        Trial::test("loopback install with RAID1", move || {
+            let sh = &xshell::Shell::new()?;
+            reset_root(sh)?;
+            let size = 10 * 1000 * 1000 * 1000;
+            let mut disks = Vec::new();
+            for _ in 0..2 {
+                let mut tmpdisk = tempfile::NamedTempFile::new_in("/var/tmp")?;
+                tmpdisk.as_file_mut().set_len(size)?;
+                disks.push(tmpdisk.into_temp_path());
+            }
+            let [disk0, disk1] = [&disks[0], &disks[1]].map(|d| d.to_str().unwrap());
+            // The block setup must be enabled by the install configuration
+            let tmpd = &sh.create_temp_dir()?;
+            let config = tmpd.path().join("50-raid1.toml");
+            std::fs::write(&config, "[install]\nblock = [\"direct\", \"raid1\"]\n")?;
+            let config = config.to_str().unwrap();
+            cmd!(sh, "sudo {BASE_ARGS...} -v {disk0}:/disk0 -v {disk1}:/disk1 -v {config}:/usr/lib/bootc/install/50-raid1.toml {image} bootc install to-disk --via-loopback {generic_inst_args...} --block-setup raid1 --device /disk1 /disk0").run()?;
+            for disk in [disk0, disk1] {
+                let table = cmd!(sh, "sudo sgdisk -p {disk}").read()?;
+                // Linux RAID members for /boot and the root
+                assert_eq!(table.matches("FD00").count(), 2, "{disk}:\n{table}");
+            }
+            Ok(())
+        })
+        .with_ignored_flag(!Path::new("/proc/mdstat").exists()),
+        Trial::test("to-filesystem with btrfs subvolumes", move || {
+            let sh = &xshell::Shell::new()?;
+            reset_root(sh)?;
+            let size = 10 * 1000 * 1000 * 1000;
+            let mut tmpdisk = tempfile::NamedTempFile::new_in("/var/tmp")?;
+            tmpdisk.as_file_mut().set_len(size)?;
+            let tmpdisk = tmpdisk.into_temp_path();
+            let tmpdisk = tmpdisk.to_str().unwrap();
+            // BIOS boot, ESP, /boot and the root
+            let table = "label: gpt\nsize=1MiB, type=21686148-6449-6E6F-744E-656564454649\nsize=512MiB, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B\nsize=1GiB\ntype=0FC63DAF-8483-4772-8E79-3D69E4C47DE4\n";
+            cmd!(sh, "sudo sfdisk {tmpdisk}").stdin(table).run()?;
+            let dev = cmd!(sh, "sudo losetup -P -f --show {tmpdisk}").read()?;
+            let tmpd = &sh.create_temp_dir()?;
+            let mnt = tmpd.path().to_str().unwrap();
+            let r = (|| -> Result<()> {
+                cmd!(sh, "sudo mkfs.fat {dev}p2").run()?;
+                cmd!(sh, "sudo mkfs.ext4 -q {dev}p3").run()?;
+                cmd!(sh, "sudo mkfs.btrfs -q {dev}p4").run()?;
+                cmd!(sh, "sudo mount {dev}p4 {mnt}").run()?;
+                cmd!(sh, "sudo mkdir {mnt}/boot").run()?;
+                cmd!(sh, "sudo mount {dev}p3 {mnt}/boot").run()?;
+                cmd!(sh, "sudo mkdir {mnt}/boot/efi").run()?;
+                cmd!(sh, "sudo mount {dev}p2 {mnt}/boot/efi").run()?;
+                cmd!(sh, "sudo {BASE_ARGS...} -v {mnt}:/target {image} bootc install to-filesystem {generic_inst_args...} --btrfs-subvolumes=root=/,var=/var,home=/var/home /target").run()?;
+                let subvols = cmd!(sh, "sudo btrfs subvolume list {mnt}").read()?;
+                for name in ["root", "var", "home"] {
+                    let found = subvols.lines().any(|l| l.ends_with(&format!(" path {name}")));
+                    assert!(found, "Missing subvolume {name}:\n{subvols}");
+                }
+                cmd!(sh, "sudo /bin/sh -c 'grep rootflags=subvol=root {mnt}/boot/loader/entries/*.conf'").run()?;
+                let fstab = cmd!(sh, "sudo /bin/sh -c 'cat {mnt}/root/ostree/deploy/default/deploy/*/etc/fstab'").read()?;
+                assert!(fstab.contains(" /var btrfs subvol=var "), "{fstab}");
+                assert!(fstab.contains(" /var/home btrfs subvol=home "), "{fstab}");
+                Ok(())
+            })();
+            let _ = cmd!(sh, "sudo umount -R {mnt}").run();
+            cmd!(sh, "sudo losetup -d {dev}").run()?;
+            r?;
+            Ok(())
+        }),
         Trial::test(
             "replace=alongside with ssh keys and a karg, and SELinux disabled",
             move || {